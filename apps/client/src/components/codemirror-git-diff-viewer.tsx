@@ -45,6 +45,8 @@ type FileGroup = {
   newContent: string;
   patch?: string;
   isBinary: boolean;
+  oldSubmoduleSha?: string;
+  newSubmoduleSha?: string;
 };
 
 function debugGitDiffViewerLog(
@@ -90,6 +92,8 @@ export function GitDiffViewer({
         newContent: diff.newContent || "",
         patch: diff.patch,
         isBinary: diff.isBinary,
+        oldSubmoduleSha: diff.oldSubmoduleSha,
+        newSubmoduleSha: diff.newSubmoduleSha,
       })),
     [diffs],
   );
@@ -212,7 +216,8 @@ function FileDiffRow({
     isExpanded &&
     !file.isBinary &&
     file.status !== "deleted" &&
-    file.status !== "renamed";
+    file.status !== "renamed" &&
+    file.status !== "submodule";
 
   const baseExtensions = useMemo(
     () => createMergeBaseExtensions(theme),
@@ -296,6 +301,22 @@ function FileDiffRow({
                 </p>
               ) : null}
             </div>
+          ) : file.status === "submodule" ? (
+            <div className="px-3 py-6 text-center text-neutral-500 dark:text-neutral-400 text-xs bg-neutral-50 dark:bg-neutral-900/50 space-y-2">
+              <p className="select-none">
+                {file.oldSubmoduleSha && file.newSubmoduleSha
+                  ? "Submodule updated."
+                  : file.newSubmoduleSha
+                    ? "Submodule added."
+                    : "Submodule removed."}
+              </p>
+              <p className="select-none font-mono text-[11px] text-neutral-600 dark:text-neutral-300">
+                {[file.oldSubmoduleSha, file.newSubmoduleSha]
+                  .filter(Boolean)
+                  .map((sha) => sha?.slice(0, 7))
+                  .join(" → ")}
+              </p>
+            </div>
           ) : file.isBinary ? (
             <div className="px-3 py-6 text-center text-neutral-500 dark:text-neutral-400 text-xs bg-neutral-50 dark:bg-neutral-900/50">
               Binary file not shown
@@ -327,6 +348,8 @@ const MemoFileDiffRow = memo(FileDiffRow, (prev, next) => {
     a.additions === b.additions &&
     a.deletions === b.deletions &&
     a.isBinary === b.isBinary &&
+    a.oldSubmoduleSha === b.oldSubmoduleSha &&
+    a.newSubmoduleSha === b.newSubmoduleSha &&
     (a.patch || "") === (b.patch || "") &&
     a.oldContent === b.oldContent &&
     a.newContent === b.newContent
@@ -76,6 +76,8 @@ type MonacoFileGroup = {
   patch?: string;
   isBinary: boolean;
   contentOmitted: boolean;
+  oldSubmoduleSha?: string;
+  newSubmoduleSha?: string;
   language: string;
   editorMetrics: EditorLayoutMetrics | null;
 };
@@ -859,7 +861,8 @@ function MonacoFileDiffRow({
     !file.isBinary &&
     !file.contentOmitted &&
     file.status !== "deleted" &&
-    file.status !== "renamed";
+    file.status !== "renamed" &&
+    file.status !== "submodule";
 
   const editorMinHeight = Math.max(
     file.editorMetrics?.editorMinHeight ?? DEFAULT_EDITOR_MIN_HEIGHT,
@@ -927,6 +930,22 @@ function MonacoFileDiffRow({
               </p>
             ) : null}
           </div>
+        ) : file.status === "submodule" ? (
+          <div className="grow space-y-2 bg-neutral-50 px-3 py-6 text-center text-xs text-neutral-500 dark:bg-neutral-900/50 dark:text-neutral-400 grid place-content-center">
+            <p className="select-none">
+              {file.oldSubmoduleSha && file.newSubmoduleSha
+                ? "Submodule updated."
+                : file.newSubmoduleSha
+                  ? "Submodule added."
+                  : "Submodule removed."}
+            </p>
+            <p className="select-none font-mono text-[11px] text-neutral-600 dark:text-neutral-300">
+              {[file.oldSubmoduleSha, file.newSubmoduleSha]
+                .filter(Boolean)
+                .map((sha) => sha?.slice(0, 7))
+                .join(" → ")}
+            </p>
+          </div>
         ) : file.isBinary ? (
           <div className="grow bg-neutral-50 px-3 py-6 text-center text-xs text-neutral-500 dark:bg-neutral-900/50 dark:text-neutral-400 grid place-content-center">
             Binary file not shown
@@ -971,6 +990,8 @@ const MemoMonacoFileDiffRow = memo(MonacoFileDiffRow, (prev, next) => {
     a.deletions === b.deletions &&
     a.isBinary === b.isBinary &&
     a.contentOmitted === b.contentOmitted &&
+    a.oldSubmoduleSha === b.oldSubmoduleSha &&
+    a.newSubmoduleSha === b.newSubmoduleSha &&
     a.language === b.language &&
     a.oldContent === b.oldContent &&
     a.newContent === b.newContent
@@ -1002,7 +1023,8 @@ export function MonacoGitDiffViewer({
           !diff.isBinary &&
           !diff.contentOmitted &&
           diff.status !== "deleted" &&
-          diff.status !== "renamed";
+          diff.status !== "renamed" &&
+          diff.status !== "submodule";
 
         const editorMetrics = shouldMeasure
           ? computeEditorLayoutMetrics(oldContent, newContent)
@@ -1019,6 +1041,8 @@ export function MonacoGitDiffViewer({
           patch: diff.patch,
           isBinary: diff.isBinary,
           contentOmitted: diff.contentOmitted ?? false,
+          oldSubmoduleSha: diff.oldSubmoduleSha,
+          newSubmoduleSha: diff.newSubmoduleSha,
           language: guessMonacoLanguage(diff.filePath),
           editorMetrics,
         };
@@ -0,0 +1,376 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use http::{Method, Request, Response, StatusCode, header};
+use hyper::{Body, body::HttpBody};
+use serde_json::{Value, json};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::{HttpClient, metrics::RouteLabel};
+
+/// Entries waiting for the exporter; past this, new entries are dropped rather than queued.
+const QUEUE_CAPACITY: usize = 8192;
+/// Entries sent to the collector in one request.
+const MAX_BATCH: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Structured per-request access logs, written to the `global_proxy::access` tracing target
+/// and optionally exported to an OpenTelemetry collector.
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    /// Fraction of requests logged, from `0.0` to `1.0`. `5xx` responses are always logged.
+    pub sample_rate: f64,
+    /// OTLP/HTTP logs endpoint, e.g. `http://otel-collector:4318/v1/logs`. Entries are sent as
+    /// JSON in batches.
+    pub otlp_endpoint: Option<String>,
+    /// Extra headers sent to the collector, such as an API key.
+    pub otlp_headers: Vec<(String, String)>,
+    /// The `service.name` resource attribute on exported entries.
+    pub service_name: String,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 1.0,
+            otlp_endpoint: None,
+            otlp_headers: Vec::new(),
+            service_name: "global-proxy".to_string(),
+        }
+    }
+}
+
+/// What is known about a request when it arrives.
+pub(crate) struct AccessEntry {
+    timestamp: SystemTime,
+    started: Instant,
+    route: RouteLabel,
+    host: String,
+    slug: Option<String>,
+    port: Option<u16>,
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+}
+
+impl AccessEntry {
+    pub(crate) fn new(
+        req: &Request<Body>,
+        started: Instant,
+        route: RouteLabel,
+        host: Option<String>,
+        target: Option<(String, u16)>,
+    ) -> Self {
+        let (slug, port) = match target {
+            Some((slug, port)) => (Some(slug), Some(port)),
+            None => (None, None),
+        };
+        Self {
+            timestamp: SystemTime::now(),
+            started,
+            route,
+            host: host.unwrap_or_default(),
+            slug,
+            port,
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// A finished request.
+pub(crate) struct AccessRecord {
+    entry: AccessEntry,
+    status: StatusCode,
+    duration: Duration,
+    bytes: u64,
+}
+
+pub(crate) struct AccessLog {
+    sample_rate: f64,
+    seen: AtomicU64,
+    exporter: Option<mpsc::Sender<AccessRecord>>,
+}
+
+impl AccessLog {
+    /// The log and, when an OTLP endpoint is configured, the queue to pass to [`export`].
+    pub(crate) fn new(config: &AccessLogConfig) -> (Self, Option<mpsc::Receiver<AccessRecord>>) {
+        let (exporter, queue) = match config.otlp_endpoint {
+            Some(_) => {
+                let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let log = Self {
+            sample_rate: config.sample_rate.clamp(0.0, 1.0),
+            seen: AtomicU64::new(0),
+            exporter,
+        };
+        (log, queue)
+    }
+
+    /// Whether to log a request that got `status`. Sampling is spread evenly over requests
+    /// rather than random, so a rate of `0.1` logs every tenth one.
+    fn sampled(&self, status: StatusCode) -> bool {
+        if status.is_server_error() {
+            return true;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).floor() > (n * self.sample_rate).floor()
+    }
+
+    /// Logs `entry` once `response`'s body has been sent, counting its bytes.
+    pub(crate) fn finish(
+        self: &Arc<Self>,
+        entry: AccessEntry,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if !self.sampled(response.status()) {
+            return response;
+        }
+        let status = response.status();
+        if status == StatusCode::SWITCHING_PROTOCOLS || response.body().is_end_stream() {
+            self.record(entry, status, 0);
+            return response;
+        }
+        response.map(|body| {
+            Body::wrap_stream(CountingBody {
+                inner: body,
+                bytes: 0,
+                pending: Some((self.clone(), entry, status)),
+            })
+        })
+    }
+
+    fn record(&self, entry: AccessEntry, status: StatusCode, bytes: u64) {
+        let duration = entry.started.elapsed();
+        info!(
+            target: "global_proxy::access",
+            host = %entry.host,
+            route = entry.route.as_str(),
+            slug = entry.slug.as_deref().unwrap_or("-"),
+            port = entry.port,
+            method = %entry.method,
+            path = %entry.path,
+            status = status.as_u16(),
+            duration_ms = duration.as_secs_f64() * 1000.0,
+            bytes,
+            user_agent = entry.user_agent.as_deref().unwrap_or("-"),
+            "request"
+        );
+        if let Some(exporter) = &self.exporter
+            && let Err(mpsc::error::TrySendError::Full(_)) = exporter.try_send(AccessRecord {
+                entry,
+                status,
+                duration,
+                bytes,
+            })
+        {
+            warn!("access log export queue full; dropping entry");
+        }
+    }
+}
+
+/// Passes a response body through, logging the request with its size when the body is done
+/// or dropped.
+struct CountingBody {
+    inner: Body,
+    bytes: u64,
+    pending: Option<(Arc<AccessLog>, AccessEntry, StatusCode)>,
+}
+
+impl Stream for CountingBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        if let Some((log, entry, status)) = self.pending.take() {
+            log.record(entry, status, self.bytes);
+        }
+    }
+}
+
+struct OtlpExporter {
+    client: HttpClient,
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    service_name: String,
+}
+
+/// Sends queued entries to the OTLP endpoint in batches until `shutdown` fires, then flushes
+/// what is left.
+pub(crate) async fn export(
+    config: AccessLogConfig,
+    client: HttpClient,
+    mut queue: mpsc::Receiver<AccessRecord>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let Some(endpoint) = config.otlp_endpoint else {
+        return;
+    };
+    let exporter = OtlpExporter {
+        client,
+        endpoint,
+        headers: config.otlp_headers,
+        service_name: config.service_name,
+    };
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            record = queue.recv() => match record {
+                Some(record) => {
+                    batch.push(record);
+                    if batch.len() >= MAX_BATCH {
+                        exporter.send(&batch).await;
+                        batch.clear();
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick() => {
+                if !batch.is_empty() {
+                    exporter.send(&batch).await;
+                    batch.clear();
+                }
+            }
+        }
+    }
+    while let Ok(record) = queue.try_recv() {
+        batch.push(record);
+    }
+    for chunk in batch.chunks(MAX_BATCH) {
+        exporter.send(chunk).await;
+    }
+}
+
+impl OtlpExporter {
+    async fn send(&self, records: &[AccessRecord]) {
+        let payload = self.payload(records);
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.as_str())
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let request = match request.body(Body::from(payload.to_string())) {
+            Ok(request) => request,
+            Err(err) => {
+                warn!(%err, "invalid access log export request");
+                return;
+            }
+        };
+        match tokio::time::timeout(EXPORT_TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => {}
+            Ok(Ok(response)) => {
+                warn!(status = %response.status(), count = records.len(), "access log export rejected");
+            }
+            Ok(Err(err)) => warn!(%err, count = records.len(), "access log export failed"),
+            Err(_) => warn!(count = records.len(), "access log export timed out"),
+        }
+    }
+
+    /// An OTLP `ExportLogsServiceRequest` in its JSON encoding.
+    fn payload(&self, records: &[AccessRecord]) -> Value {
+        let log_records: Vec<Value> = records
+            .iter()
+            .map(|record| {
+                let entry = &record.entry;
+                let nanos = entry
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let mut attributes = vec![
+                    string_attr("server.address", &entry.host),
+                    string_attr("cmux.route", entry.route.as_str()),
+                    string_attr("http.request.method", entry.method.as_str()),
+                    string_attr("url.path", &entry.path),
+                    int_attr("http.response.status_code", record.status.as_u16().into()),
+                    json!({
+                        "key": "http.server.request.duration",
+                        "value": { "doubleValue": record.duration.as_secs_f64() },
+                    }),
+                    int_attr("http.response.body.size", record.bytes),
+                ];
+                if let Some(slug) = &entry.slug {
+                    attributes.push(string_attr("cmux.slug", slug));
+                }
+                if let Some(port) = entry.port {
+                    attributes.push(int_attr("cmux.port", port.into()));
+                }
+                if let Some(user_agent) = &entry.user_agent {
+                    attributes.push(string_attr("user_agent.original", user_agent));
+                }
+                let (severity_number, severity_text) = if record.status.is_server_error() {
+                    (17, "ERROR")
+                } else {
+                    (9, "INFO")
+                };
+                json!({
+                    "timeUnixNano": nanos.to_string(),
+                    "severityNumber": severity_number,
+                    "severityText": severity_text,
+                    "body": {
+                        "stringValue": format!(
+                            "{} {}{} {}",
+                            entry.method,
+                            entry.host,
+                            entry.path,
+                            record.status.as_u16()
+                        ),
+                    },
+                    "attributes": attributes,
+                })
+            })
+            .collect();
+        json!({
+            "resourceLogs": [{
+                "resource": {
+                    "attributes": [string_attr("service.name", &self.service_name)],
+                },
+                "scopeLogs": [{
+                    "scope": { "name": "global_proxy::access" },
+                    "logRecords": log_records,
+                }],
+            }],
+        })
+    }
+}
+
+fn string_attr(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// OTLP JSON encodes 64-bit integers as strings.
+fn int_attr(key: &str, value: u64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
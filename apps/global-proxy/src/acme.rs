@@ -0,0 +1,951 @@
+//! Automatic certificates over ACME (RFC 8555), e.g. from Let's Encrypt.
+//!
+//! Certificates live in `storage_dir` (`account.key`, `certs/<domain>.pem` and `.key`; a
+//! wildcard is stored as `_.<domain>`) so restarts reuse them, and a background task renews
+//! them ahead of expiry. HTTP-01 answers challenges on the plain listener and can issue per
+//! host during the first TLS handshake, for hosts whose VM resolves; DNS-01 publishes TXT records through a
+//! [`DnsProvider`] and is the only way to get wildcard certificates.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::future::BoxFuture;
+use http::{HeaderMap, Method, Request, header};
+use hyper::{Body, body};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, KeyPair},
+};
+use serde_json::{Value, json};
+use tokio_rustls::rustls::{Certificate, PrivateKey, sign::CertifiedKey};
+use tracing::{info, warn};
+
+use crate::{
+    BackendResolver, HttpClient, Route,
+    rate_limit::{Buckets, RateLimit},
+};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETS_ENCRYPT_STAGING_DIRECTORY: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// How often stored certificates are checked for renewal.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Longest we wait for an authorization or order to settle.
+const POLL_TIMEOUT: Duration = Duration::from_secs(180);
+/// After a failed on-demand issuance, handshakes for that host fail fast for this long.
+const ON_DEMAND_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Publishes and removes the `_acme-challenge` TXT records used by DNS-01.
+pub trait DnsProvider: Send + Sync {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<(), String>>;
+    fn clear_txt<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// [`DnsProvider`] that runs `<program> set|clear <record name> <value>` and expects it to
+/// exit successfully once the change is made.
+pub struct CommandDnsProvider {
+    program: PathBuf,
+}
+
+impl CommandDnsProvider {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+
+    async fn run(&self, action: &str, name: &str, value: &str) -> Result<(), String> {
+        let status = tokio::process::Command::new(&self.program)
+            .args([action, name, value])
+            .status()
+            .await
+            .map_err(|err| format!("failed to run {}: {}", self.program.display(), err))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} {} {} exited with {}",
+                self.program.display(),
+                action,
+                name,
+                status
+            ))
+        }
+    }
+}
+
+impl DnsProvider for CommandDnsProvider {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.run("set", name, value))
+    }
+
+    fn clear_txt<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.run("clear", name, value))
+    }
+}
+
+#[derive(Clone)]
+pub enum AcmeChallenge {
+    /// Serve `/.well-known/acme-challenge/` on the plain listener, which must be reachable on
+    /// port 80 of every name being issued.
+    Http01,
+    /// Publish TXT records through `provider`, then wait `propagation_delay` before asking the
+    /// CA to validate.
+    Dns01 {
+        provider: Arc<dyn DnsProvider>,
+        propagation_delay: Duration,
+    },
+}
+
+impl fmt::Debug for AcmeChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcmeChallenge::Http01 => f.write_str("Http01"),
+            AcmeChallenge::Dns01 {
+                propagation_delay, ..
+            } => f
+                .debug_struct("Dns01")
+                .field("propagation_delay", propagation_delay)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AcmeConfig {
+    /// Where the TLS listener binds.
+    pub tls_bind_addr: SocketAddr,
+    pub directory_url: String,
+    pub contact_email: Option<String>,
+    pub challenge: AcmeChallenge,
+    /// Certificates obtained at startup and kept renewed, one per entry (`*.cmux.sh`,
+    /// `cmux.sh`, ...).
+    pub domains: Vec<String>,
+    /// With HTTP-01, obtain a certificate during the first TLS handshake for a host under the
+    /// base domains that has none yet. Preview hosts qualify only once their VM slug resolves
+    /// through the proxy's [`BackendResolver`], so without one only the base domains do. Off
+    /// by default: every order counts against the CA's rate limits.
+    pub on_demand: bool,
+    /// Orders started on demand, across all hosts.
+    pub on_demand_limit: RateLimit,
+    pub storage_dir: PathBuf,
+    /// Renew certificates that expire within this window.
+    pub renew_before: Duration,
+}
+
+impl AcmeConfig {
+    pub fn new(tls_bind_addr: SocketAddr, storage_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            tls_bind_addr,
+            directory_url: LETS_ENCRYPT_DIRECTORY.to_string(),
+            contact_email: None,
+            challenge: AcmeChallenge::Http01,
+            domains: Vec::new(),
+            on_demand: false,
+            on_demand_limit: RateLimit {
+                requests: 10,
+                window: Duration::from_secs(60 * 60),
+            },
+            storage_dir: storage_dir.into(),
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum AcmeError {
+    #[error("http error: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Protocol(String),
+}
+
+type AcmeResult<T> = std::result::Result<T, AcmeError>;
+
+fn protocol(msg: impl Into<String>) -> AcmeError {
+    AcmeError::Protocol(msg.into())
+}
+
+#[derive(Clone)]
+struct StoredCert {
+    key: Arc<CertifiedKey>,
+    not_after: DateTime<Utc>,
+}
+
+/// Owns the certificates served by the TLS listener and keeps them issued and renewed.
+pub(crate) struct AcmeManager {
+    config: AcmeConfig,
+    http: HttpClient,
+    base_domains: Vec<String>,
+    backend_resolver: Option<Arc<dyn BackendResolver>>,
+    certs: RwLock<HashMap<String, StoredCert>>,
+    /// HTTP-01 token -> key authorization, while a challenge is pending.
+    http_tokens: Mutex<HashMap<String, String>>,
+    on_demand_failures: Mutex<HashMap<String, Instant>>,
+    on_demand_orders: Buckets,
+    account: tokio::sync::Mutex<Option<Arc<Account>>>,
+    /// Issuance is serialized; a waiter re-checks the store before ordering.
+    issuing: tokio::sync::Mutex<()>,
+}
+
+impl AcmeManager {
+    pub(crate) fn new(
+        config: AcmeConfig,
+        http: HttpClient,
+        base_domains: Vec<String>,
+        backend_resolver: Option<Arc<dyn BackendResolver>>,
+    ) -> Self {
+        if config.on_demand
+            && matches!(config.challenge, AcmeChallenge::Http01)
+            && backend_resolver.is_none()
+        {
+            warn!("on-demand issuance cannot check preview hosts without a backend resolver");
+        }
+        let manager = Self {
+            on_demand_orders: Buckets::new(config.on_demand_limit),
+            config,
+            http,
+            base_domains,
+            backend_resolver,
+            certs: RwLock::new(HashMap::new()),
+            http_tokens: Mutex::new(HashMap::new()),
+            on_demand_failures: Mutex::new(HashMap::new()),
+            account: tokio::sync::Mutex::new(None),
+            issuing: tokio::sync::Mutex::new(()),
+        };
+        manager.load_stored();
+        manager
+    }
+
+    fn certs_dir(&self) -> PathBuf {
+        self.config.storage_dir.join("certs")
+    }
+
+    fn load_stored(&self) {
+        let Ok(entries) = std::fs::read_dir(self.certs_dir()) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("pem") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let domain = match stem.strip_prefix("_.") {
+                Some(rest) => format!("*.{}", rest),
+                None => stem.to_string(),
+            };
+            let loaded = std::fs::read(&path)
+                .and_then(|cert| std::fs::read(path.with_extension("key")).map(|key| (cert, key)));
+            match loaded
+                .map_err(AcmeError::from)
+                .and_then(|(cert, key)| parse_stored(&cert, &key))
+            {
+                Ok(stored) => {
+                    info!(%domain, not_after = %stored.not_after, "loaded stored certificate");
+                    self.certs.write().unwrap().insert(domain, stored);
+                }
+                Err(err) => warn!(%domain, %err, "ignoring unreadable stored certificate"),
+            }
+        }
+    }
+
+    /// Key authorization for a pending HTTP-01 challenge.
+    pub(crate) fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_tokens.lock().unwrap().get(token).cloned()
+    }
+
+    /// A stored certificate covering `host`, exact names first, then a wildcard.
+    fn lookup(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        if let Some(stored) = certs.get(host) {
+            return Some(stored.key.clone());
+        }
+        let (_, parent) = host.split_once('.')?;
+        certs
+            .get(&format!("*.{}", parent))
+            .map(|stored| stored.key.clone())
+    }
+
+    /// The certificate to present for a handshake with SNI `host`, issuing one on demand when
+    /// that is enabled, the host is ours and has a backend, and the order limit allows.
+    pub(crate) async fn certificate_for(&self, host: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = host?;
+        if let Some(key) = self.lookup(host) {
+            return Some(key);
+        }
+        if !self.config.on_demand || !matches!(self.config.challenge, AcmeChallenge::Http01) {
+            return None;
+        }
+        let (subdomain, _) = crate::parse_cmux_host(host, &self.base_domains)?;
+        if let Some(failed) = self.on_demand_failures.lock().unwrap().get(host)
+            && failed.elapsed() < ON_DEMAND_RETRY_AFTER
+        {
+            return None;
+        }
+        if let Some(subdomain) = subdomain
+            && !self.has_backend(subdomain).await
+        {
+            return None;
+        }
+        if let Err(retry_after) = self.on_demand_orders.take("") {
+            warn!(%host, ?retry_after, "on-demand certificate order limit reached");
+            return None;
+        }
+        match self.ensure(host).await {
+            Ok(()) => self.lookup(host),
+            Err(err) => {
+                warn!(%host, %err, "on-demand certificate issuance failed");
+                self.on_demand_failures
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_string(), Instant::now());
+                None
+            }
+        }
+    }
+
+    /// Whether a preview subdomain routes to a VM the backend resolver knows. Anyone can send
+    /// any SNI, so names nothing serves must not reach the CA.
+    async fn has_backend(&self, subdomain: String) -> bool {
+        let Some(resolver) = &self.backend_resolver else {
+            return false;
+        };
+        let (slug, port) = match crate::parse_route(subdomain) {
+            Route::Port(route) => (route.morph_id, route.port),
+            Route::Cmux(route) => (route.morph_id, route.port),
+            Route::Workspace(route) => (route.vm_slug, route.port),
+            Route::Invalid(_) => return false,
+        };
+        resolver.resolve(&slug, port).await.is_ok()
+    }
+
+    fn needs_issuance(&self, domain: &str) -> bool {
+        match self.certs.read().unwrap().get(domain) {
+            Some(stored) => {
+                let renew_before = chrono::Duration::from_std(self.config.renew_before)
+                    .unwrap_or_else(|_| chrono::Duration::days(30));
+                stored.not_after - Utc::now() < renew_before
+            }
+            None => true,
+        }
+    }
+
+    /// Issue a certificate for `domain` unless a fresh one is already stored.
+    async fn ensure(&self, domain: &str) -> AcmeResult<()> {
+        let _issuing = self.issuing.lock().await;
+        if !self.needs_issuance(domain) {
+            return Ok(());
+        }
+        let account = self.account().await?;
+        info!(%domain, "requesting certificate");
+        let (cert_pem, key_pem) = account.issue(domain, self).await?;
+        let stored = parse_stored(cert_pem.as_bytes(), key_pem.as_bytes())?;
+        let dir = self.certs_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+        let stem = domain.replace('*', "_");
+        write_private(&dir.join(format!("{}.key", stem)), key_pem.as_bytes()).await?;
+        tokio::fs::write(dir.join(format!("{}.pem", stem)), cert_pem).await?;
+        info!(%domain, not_after = %stored.not_after, "certificate issued");
+        self.certs
+            .write()
+            .unwrap()
+            .insert(domain.to_string(), stored);
+        Ok(())
+    }
+
+    async fn account(&self) -> AcmeResult<Arc<Account>> {
+        let mut account = self.account.lock().await;
+        if let Some(account) = account.as_ref() {
+            return Ok(account.clone());
+        }
+        let created = Arc::new(Account::load_or_register(self.http.clone(), &self.config).await?);
+        *account = Some(created.clone());
+        Ok(created)
+    }
+
+    /// Issue the configured certificates, then keep every stored one renewed.
+    pub(crate) async fn maintain(self: Arc<Self>) {
+        loop {
+            let mut domains = self.config.domains.clone();
+            for domain in self.certs.read().unwrap().keys() {
+                if !domains.contains(domain) {
+                    domains.push(domain.clone());
+                }
+            }
+            for domain in domains {
+                if self.needs_issuance(&domain)
+                    && let Err(err) = self.ensure(&domain).await
+                {
+                    warn!(%domain, %err, "certificate issuance failed");
+                }
+            }
+            tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Make a challenge answerable until [`Self::unpublish`].
+    async fn publish(&self, identifier: &str, token: &str, key_auth: &str) -> AcmeResult<()> {
+        match &self.config.challenge {
+            AcmeChallenge::Http01 => {
+                self.http_tokens
+                    .lock()
+                    .unwrap()
+                    .insert(token.to_string(), key_auth.to_string());
+            }
+            AcmeChallenge::Dns01 {
+                provider,
+                propagation_delay,
+            } => {
+                provider
+                    .set_txt(&dns_record_name(identifier), &dns_record_value(key_auth))
+                    .await
+                    .map_err(protocol)?;
+                tokio::time::sleep(*propagation_delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unpublish(&self, identifier: &str, token: &str, key_auth: &str) {
+        match &self.config.challenge {
+            AcmeChallenge::Http01 => {
+                self.http_tokens.lock().unwrap().remove(token);
+            }
+            AcmeChallenge::Dns01 { provider, .. } => {
+                if let Err(err) = provider
+                    .clear_txt(&dns_record_name(identifier), &dns_record_value(key_auth))
+                    .await
+                {
+                    warn!(%identifier, %err, "failed to remove DNS challenge record");
+                }
+            }
+        }
+    }
+
+    fn challenge_type(&self) -> &'static str {
+        match self.config.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::Dns01 { .. } => "dns-01",
+        }
+    }
+}
+
+fn dns_record_name(identifier: &str) -> String {
+    format!("_acme-challenge.{}", identifier.trim_start_matches("*."))
+}
+
+fn dns_record_value(key_auth: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, key_auth.as_bytes()))
+}
+
+async fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    tokio::fs::write(path, contents).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+}
+
+fn parse_stored(cert_pem: &[u8], key_pem: &[u8]) -> AcmeResult<StoredCert> {
+    let chain = rustls_pemfile::certs(&mut &cert_pem[..])?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| protocol("certificate file has no certificates"))?;
+    let not_after =
+        cert_not_after(leaf).ok_or_else(|| protocol("cannot read certificate expiry"))?;
+    let key = rustls_pemfile::pkcs8_private_keys(&mut &key_pem[..])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| protocol("key file has no PKCS#8 private key"))?;
+    let signing_key = tokio_rustls::rustls::sign::any_supported_type(&PrivateKey(key))
+        .map_err(|err| protocol(format!("unsupported private key: {}", err)))?;
+    Ok(StoredCert {
+        key: Arc::new(CertifiedKey::new(
+            chain.into_iter().map(Certificate).collect(),
+            signing_key,
+        )),
+        not_after,
+    })
+}
+
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+struct AcmeResponse {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> AcmeResult<Value> {
+        serde_json::from_slice(&self.body)
+            .map_err(|err| protocol(format!("invalid ACME response: {}", err)))
+    }
+
+    fn location(&self) -> AcmeResult<String> {
+        self.headers
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| protocol("ACME response is missing Location"))
+    }
+}
+
+/// A registered ACME account and the JWS signing that goes with it.
+struct Account {
+    http: HttpClient,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Mutex<Option<String>>,
+    rng: SystemRandom,
+}
+
+impl Account {
+    async fn load_or_register(http: HttpClient, config: &AcmeConfig) -> AcmeResult<Self> {
+        let rng = SystemRandom::new();
+        let key_path = config.storage_dir.join("account.key");
+        let pkcs8 = match tokio::fs::read(&key_path).await {
+            Ok(pem) => rustls_pemfile::pkcs8_private_keys(&mut &pem[..])?
+                .into_iter()
+                .next()
+                .ok_or_else(|| protocol("account.key has no PKCS#8 private key"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 =
+                    EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                        .map_err(|_| protocol("failed to generate account key"))?;
+                tokio::fs::create_dir_all(&config.storage_dir).await?;
+                write_private(&key_path, pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes()).await?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let key =
+            EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+                .map_err(|_| protocol("account.key is not a P-256 key"))?;
+
+        // Uncompressed point: 0x04 || x || y.
+        let point = key.public_key().as_ref();
+        let x = URL_SAFE_NO_PAD.encode(&point[1..33]);
+        let y = URL_SAFE_NO_PAD.encode(&point[33..65]);
+        // RFC 7638 thumbprint: required members in lexicographic order, no whitespace.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint =
+            URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, canonical.as_bytes()));
+        let jwk = json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y });
+
+        let directory = fetch_directory(&http, &config.directory_url).await?;
+        let mut account = Self {
+            http,
+            directory,
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: Mutex::new(None),
+            rng,
+        };
+
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &config.contact_email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = account.directory.new_account.clone();
+        let response = account.post(&new_account, Some(&payload)).await?;
+        account.kid = Some(response.location()?);
+        Ok(account)
+    }
+
+    async fn fresh_nonce(&self) -> AcmeResult<String> {
+        if let Some(nonce) = self.nonce.lock().unwrap().take() {
+            return Ok(nonce);
+        }
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())
+            .map_err(|err| protocol(err.to_string()))?;
+        let response = self.http.request(request).await?;
+        replay_nonce(response.headers()).ok_or_else(|| protocol("ACME server sent no nonce"))
+    }
+
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> AcmeResult<Value> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        // POST-as-GET carries an empty payload.
+        let payload = payload
+            .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| protocol("failed to sign ACME request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
+    async fn post(&self, url: &str, payload: Option<&Value>) -> AcmeResult<AcmeResponse> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let nonce = self.fresh_nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body.to_string()))
+                .map_err(|err| protocol(err.to_string()))?;
+            let response = self.http.request(request).await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            if let Some(nonce) = replay_nonce(&headers) {
+                *self.nonce.lock().unwrap() = Some(nonce);
+            }
+            let body = body::to_bytes(response.into_body()).await?;
+            if status.is_success() {
+                return Ok(AcmeResponse { headers, body });
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            let kind = problem["type"].as_str().unwrap_or_default();
+            if kind == "urn:ietf:params:acme:error:badNonce" && attempts < 3 {
+                continue;
+            }
+            return Err(protocol(format!(
+                "ACME request to {} failed with {}: {} {}",
+                url,
+                status,
+                kind,
+                problem["detail"].as_str().unwrap_or_default()
+            )));
+        }
+    }
+
+    /// POST-as-GET `url` until `status` leaves the pending states.
+    async fn poll(&self, url: &str, pending: &[&str]) -> AcmeResult<Value> {
+        let deadline = Instant::now() + POLL_TIMEOUT;
+        let mut delay = Duration::from_millis(250);
+        loop {
+            let object = self.post(url, None).await?.json()?;
+            let status = object["status"].as_str().unwrap_or_default();
+            if !pending.contains(&status) {
+                return Ok(object);
+            }
+            if Instant::now() >= deadline {
+                return Err(protocol(format!("timed out waiting on {}", url)));
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(5));
+        }
+    }
+
+    /// Run one order for `domain`; returns the PEM chain and PEM private key.
+    async fn issue(&self, domain: &str, manager: &AcmeManager) -> AcmeResult<(String, String)> {
+        let response = self
+            .post(
+                &self.directory.new_order,
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url = response.location()?;
+        let order = response.json()?;
+
+        let authorizations: Vec<String> = order["authorizations"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for authz_url in authorizations {
+            self.authorize(&authz_url, manager).await?;
+        }
+
+        let order = self.poll(&order_url, &["pending"]).await?;
+        if order["status"] != "ready" {
+            return Err(protocol(format!(
+                "order for {} is {}, not ready",
+                domain, order["status"]
+            )));
+        }
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| protocol("order has no finalize URL"))?;
+
+        let cert_pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, &self.rng)
+                .map_err(|_| protocol("failed to generate certificate key"))?;
+        let cert_key = EcdsaKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            cert_pkcs8.as_ref(),
+            &self.rng,
+        )
+        .map_err(|_| protocol("failed to load certificate key"))?;
+        let csr = build_csr(domain, &cert_key, &self.rng)?;
+        self.post(
+            finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )
+        .await?;
+
+        let order = self
+            .poll(&order_url, &["pending", "ready", "processing"])
+            .await?;
+        let cert_url = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(url)) => url.to_string(),
+            _ => {
+                return Err(protocol(format!(
+                    "order for {} ended as {}",
+                    domain, order["status"]
+                )));
+            }
+        };
+        let chain = self.post(&cert_url, None).await?;
+        let chain = String::from_utf8(chain.body.to_vec())
+            .map_err(|_| protocol("certificate chain is not PEM"))?;
+        Ok((chain, pem("PRIVATE KEY", cert_pkcs8.as_ref())))
+    }
+
+    async fn authorize(&self, authz_url: &str, manager: &AcmeManager) -> AcmeResult<()> {
+        let authz = self.post(authz_url, None).await?.json()?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let identifier = authz["identifier"]["value"]
+            .as_str()
+            .ok_or_else(|| protocol("authorization has no identifier"))?
+            .to_string();
+        let kind = manager.challenge_type();
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == kind))
+            .ok_or_else(|| protocol(format!("no {} challenge offered for {}", kind, identifier)))?;
+        let (Some(token), Some(url)) = (challenge["token"].as_str(), challenge["url"].as_str())
+        else {
+            return Err(protocol("challenge is missing its token or URL"));
+        };
+        let key_auth = format!("{}.{}", token, self.thumbprint);
+
+        manager.publish(&identifier, token, &key_auth).await?;
+        let result: AcmeResult<Value> = async {
+            self.post(url, Some(&json!({}))).await?;
+            self.poll(authz_url, &["pending"]).await
+        }
+        .await;
+        manager.unpublish(&identifier, token, &key_auth).await;
+
+        let authz = result?;
+        if authz["status"] != "valid" {
+            let detail = authz["challenges"]
+                .as_array()
+                .and_then(|c| c.iter().find_map(|c| c["error"]["detail"].as_str()))
+                .unwrap_or("no detail");
+            return Err(protocol(format!(
+                "authorization for {} failed: {}",
+                identifier, detail
+            )));
+        }
+        Ok(())
+    }
+}
+
+async fn fetch_directory(http: &HttpClient, url: &str) -> AcmeResult<Directory> {
+    let request = Request::builder()
+        .uri(url)
+        .body(Body::empty())
+        .map_err(|err| protocol(err.to_string()))?;
+    let response = http.request(request).await?;
+    let status = response.status();
+    let body = body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(protocol(format!(
+            "ACME directory {} returned {}",
+            url, status
+        )));
+    }
+    let directory: Value = serde_json::from_slice(&body)
+        .map_err(|err| protocol(format!("invalid ACME directory: {}", err)))?;
+    let field = |name: &str| {
+        directory[name]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| protocol(format!("ACME directory has no {}", name)))
+    };
+    Ok(Directory {
+        new_nonce: field("newNonce")?,
+        new_account: field("newAccount")?,
+        new_order: field("newOrder")?,
+    })
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+// Just enough DER to write a PKCS#10 request and read a certificate's expiry.
+
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EXTENSION_REQUEST: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e,
+];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn seq(parts: &[&[u8]]) -> Vec<u8> {
+    tlv(0x30, &parts.concat())
+}
+
+fn set(parts: &[&[u8]]) -> Vec<u8> {
+    tlv(0x31, &parts.concat())
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(0x03, &[&[0u8][..], bytes].concat())
+}
+
+/// PKCS#10 request for `domain` (as CN, when it fits, and as the only SAN).
+fn build_csr(domain: &str, key: &EcdsaKeyPair, rng: &SystemRandom) -> AcmeResult<Vec<u8>> {
+    let subject = if domain.len() <= 64 {
+        seq(&[&set(&[&seq(&[
+            OID_COMMON_NAME,
+            &tlv(0x0c, domain.as_bytes()),
+        ])])])
+    } else {
+        seq(&[])
+    };
+    let spki = seq(&[
+        &seq(&[OID_EC_PUBLIC_KEY, OID_PRIME256V1]),
+        &bit_string(key.public_key().as_ref()),
+    ]);
+    let san = seq(&[&tlv(0x82, domain.as_bytes())]);
+    let extensions = seq(&[&seq(&[OID_SUBJECT_ALT_NAME, &tlv(0x04, &san)])]);
+    let attributes = tlv(0xa0, &seq(&[OID_EXTENSION_REQUEST, &set(&[&extensions])]));
+    let info = seq(&[&[0x02, 0x01, 0x00], &subject, &spki, &attributes]);
+    let signature = key
+        .sign(rng, &info)
+        .map_err(|_| protocol("failed to sign certificate request"))?;
+    Ok(seq(&[
+        &info,
+        &seq(&[OID_ECDSA_WITH_SHA256]),
+        &bit_string(signature.as_ref()),
+    ]))
+}
+
+/// Splits one DER element off `input`: (tag, content, rest).
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        rest = &rest[count..];
+        len
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// `notAfter` from an X.509 certificate.
+fn cert_not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, cert, _) = read_tlv(der)?;
+    let (_, tbs, _) = read_tlv(cert)?;
+    let (tag, _, mut rest) = read_tlv(tbs)?;
+    if tag == 0xa0 {
+        // Explicit version; the serial number follows.
+        (_, _, rest) = read_tlv(rest)?;
+    }
+    let (_, _, rest) = read_tlv(rest)?; // signature algorithm
+    let (_, _, rest) = read_tlv(rest)?; // issuer
+    let (_, validity, _) = read_tlv(rest)?;
+    let (_, _, validity) = read_tlv(validity)?; // notBefore
+    let (tag, time, _) = read_tlv(validity)?;
+    parse_asn1_time(tag, std::str::from_utf8(time).ok()?)
+}
+
+fn parse_asn1_time(tag: u8, time: &str) -> Option<DateTime<Utc>> {
+    let time = time.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        // UTCTime: two-digit year, 50-99 meaning 19xx.
+        0x17 => {
+            let yy: i32 = time.get(..2)?.parse().ok()?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, time.get(2..)?)
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, time.get(4..)?),
+        _ => return None,
+    };
+    let field = |i: usize| rest.get(i..i + 2)?.parse::<u32>().ok();
+    NaiveDate::from_ymd_opt(year, field(0)?, field(2)?)?
+        .and_hms_opt(field(4)?, field(6)?, field(8)?)
+        .map(|t| t.and_utc())
+}
@@ -0,0 +1,276 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header};
+use hyper::Body;
+use tracing::warn;
+
+/// What a base domain itself (e.g. `cmux.sh`) serves.
+#[derive(Clone, Debug, Default)]
+pub enum ApexSite {
+    /// A plain-text `cmux!`.
+    #[default]
+    Greeting,
+    /// Files from a directory, e.g. an exported marketing site.
+    Static(StaticSite),
+    /// Redirect every request to `to`, keeping the path and query. `307`, or `308` when
+    /// `permanent`.
+    Redirect { to: String, permanent: bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct StaticSite {
+    pub root: PathBuf,
+    /// `Cache-Control: max-age` for everything but HTML, which is always revalidated.
+    pub max_age: Duration,
+}
+
+impl StaticSite {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl ApexSite {
+    /// Resolves the static root once so request paths can be checked against it.
+    pub(crate) fn prepare(self) -> Result<Self, String> {
+        match self {
+            ApexSite::Static(site) => {
+                let root = site
+                    .root
+                    .canonicalize()
+                    .map_err(|err| format!("apex root {}: {}", site.root.display(), err))?;
+                if !root.is_dir() {
+                    return Err(format!("apex root {} is not a directory", root.display()));
+                }
+                Ok(ApexSite::Static(StaticSite { root, ..site }))
+            }
+            ApexSite::Redirect { to, .. }
+                if !to.starts_with("http://") && !to.starts_with("https://") =>
+            {
+                Err(format!(
+                    "apex redirect '{}' must be an absolute http(s) URL",
+                    to
+                ))
+            }
+            other => Ok(other),
+        }
+    }
+
+    pub(crate) async fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        match self {
+            ApexSite::Greeting => Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from("cmux!"))
+                .unwrap(),
+            ApexSite::Static(site) => site.respond(req).await,
+            ApexSite::Redirect { to, permanent } => {
+                let path_and_query = req
+                    .uri()
+                    .path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or("/");
+                let location = format!("{}{}", to.trim_end_matches('/'), path_and_query);
+                let status = if *permanent {
+                    StatusCode::PERMANENT_REDIRECT
+                } else {
+                    StatusCode::TEMPORARY_REDIRECT
+                };
+                match HeaderValue::from_str(&location) {
+                    Ok(location) => Response::builder()
+                        .status(status)
+                        .header(header::LOCATION, location)
+                        .body(Body::empty())
+                        .unwrap(),
+                    Err(_) => status_response(StatusCode::BAD_REQUEST),
+                }
+            }
+        }
+    }
+}
+
+impl StaticSite {
+    async fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return response;
+        }
+        let Some(relative) = relative_path(req.uri().path()) else {
+            return status_response(StatusCode::NOT_FOUND);
+        };
+        match self.find(&relative).await {
+            Some(path) => self.file_response(req, &path, StatusCode::OK).await,
+            None => match self.find(Path::new("404.html")).await {
+                Some(path) => self.file_response(req, &path, StatusCode::NOT_FOUND).await,
+                None => status_response(StatusCode::NOT_FOUND),
+            },
+        }
+    }
+
+    /// The file for `relative`: the path itself, its `index.html` when it is a directory, or
+    /// `<path>.html` for extensionless URLs. Paths resolving outside the root are ignored.
+    async fn find(&self, relative: &Path) -> Option<PathBuf> {
+        let candidate = self.root.join(relative);
+        let mut candidates = vec![candidate.join("index.html"), candidate.clone()];
+        if relative.extension().is_none()
+            && let Some(name) = relative.file_name()
+        {
+            let mut html = name.to_os_string();
+            html.push(".html");
+            candidates.push(candidate.with_file_name(html));
+        }
+        for candidate in candidates {
+            let Ok(path) = tokio::fs::canonicalize(&candidate).await else {
+                continue;
+            };
+            if path.starts_with(&self.root)
+                && tokio::fs::metadata(&path)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_file())
+            {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    async fn file_response(
+        &self,
+        req: &Request<Body>,
+        path: &Path,
+        status: StatusCode,
+    ) -> Response<Body> {
+        let (metadata, contents) =
+            match tokio::try_join!(tokio::fs::metadata(path), tokio::fs::read(path)) {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!(path = %path.display(), %err, "failed to read apex file");
+                    return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            };
+        let content_type = content_type(path);
+        let modified = metadata.modified().ok();
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            metadata.len(),
+            modified
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|age| age.as_secs())
+                .unwrap_or_default()
+        );
+        let cache_control = if content_type.starts_with("text/html") {
+            "no-cache".to_string()
+        } else {
+            format!("public, max-age={}", self.max_age.as_secs())
+        };
+
+        let mut builder = Response::builder()
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::ETAG, &etag);
+        if let Some(modified) = modified {
+            builder = builder.header(
+                header::LAST_MODIFIED,
+                DateTime::<Utc>::from(modified)
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            );
+        }
+        if status == StatusCode::OK && etag_matches(req.headers(), &etag) {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+        }
+        let builder = builder
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, contents.len());
+        if req.method() == Method::HEAD {
+            builder.body(Body::empty()).unwrap()
+        } else {
+            builder.body(Body::from(contents)).unwrap()
+        }
+    }
+}
+
+/// The request path as a relative filesystem path, or `None` when it tries to leave the root.
+fn relative_path(request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(request_path)
+        .decode_utf8()
+        .ok()?;
+    if decoded.contains('\0') || decoded.contains('\\') {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(
+            status.canonical_reason().unwrap_or_default().to_string(),
+        ))
+        .unwrap()
+}
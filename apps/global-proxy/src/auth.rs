@@ -0,0 +1,303 @@
+use std::{collections::HashSet, fmt};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use http::{
+    Method, Request, Response, StatusCode,
+    header::{self, HeaderValue},
+};
+use hyper::Body;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::json_response;
+
+/// Cookie (and callback query parameter) carrying the preview token when none is configured.
+pub const DEFAULT_AUTH_COOKIE: &str = "cmux_preview_token";
+/// Header non-browser clients can send the preview token in.
+const TOKEN_HEADER: &str = "x-cmux-preview-token";
+
+/// Requires a token from the cmux control plane on `port-…` and workspace previews. Tokens are
+/// HS256 JWTs; a `vms` claim, when present, limits the token to those VM slugs.
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// Key shared with the control plane for signing tokens.
+    pub secret: Vec<u8>,
+    /// Cookie the token is stored in, also read from the query string on the way back from
+    /// the login page.
+    pub cookie_name: String,
+    /// Required `iss` claim.
+    pub issuer: Option<String>,
+    /// Where browsers without a valid token are sent, with the preview URL appended as
+    /// `redirect`. Without it they get `401`.
+    pub login_url: Option<String>,
+    /// Preview hosts or VM slugs reachable without a token.
+    pub public: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            cookie_name: DEFAULT_AUTH_COOKIE.to_string(),
+            issuer: None,
+            login_url: None,
+            public: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("cookie_name", &self.cookie_name)
+            .field("issuer", &self.issuer)
+            .field("login_url", &self.login_url)
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: i64,
+    #[serde(default)]
+    nbf: Option<i64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    vms: Option<Vec<String>>,
+}
+
+pub(crate) struct PreviewAuth {
+    key: hmac::Key,
+    cookie_name: String,
+    issuer: Option<String>,
+    login_url: Option<String>,
+    public: HashSet<String>,
+}
+
+impl PreviewAuth {
+    pub(crate) fn new(config: AuthConfig) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &config.secret),
+            cookie_name: config.cookie_name,
+            issuer: config.issuer,
+            login_url: config.login_url,
+            public: config
+                .public
+                .iter()
+                .map(|entry| entry.trim().to_ascii_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Returns `None` to let the request through, with the token removed so it never reaches
+    /// the VM. Otherwise returns the response to send instead: the login redirect, a `401`, or,
+    /// when the token arrives in the query string, a redirect that moves it into a cookie.
+    pub(crate) fn check(
+        &self,
+        req: &mut Request<Body>,
+        host: &str,
+        slug: &str,
+    ) -> Option<Response<Body>> {
+        if self.public.contains(host) || self.public.contains(&slug.to_ascii_lowercase()) {
+            return None;
+        }
+
+        if let Some((token, rest)) = self.take_query_token(req) {
+            if let Some(exp) = self.verify(&token, slug) {
+                return Some(self.store_token(req, host, &token, exp, rest));
+            }
+            return Some(self.unauthorized(req, host));
+        }
+
+        let token = req
+            .headers()
+            .get(TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| self.cookie_token(req));
+        match token {
+            Some(token) if self.verify(&token, slug).is_some() => {
+                self.strip_credentials(req);
+                None
+            }
+            _ => Some(self.unauthorized(req, host)),
+        }
+    }
+
+    /// Returns the token's expiry when it is signed with our key, current, and covers `slug`.
+    fn verify(&self, token: &str, slug: &str) -> Option<i64> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header.alg != "HS256" {
+            return None;
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.key, signed.as_bytes(), &signature).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        let now = Utc::now().timestamp();
+        if claims.exp <= now || claims.nbf.is_some_and(|nbf| nbf > now) {
+            return None;
+        }
+        if self.issuer.is_some() && claims.iss != self.issuer {
+            return None;
+        }
+        if let Some(vms) = &claims.vms
+            && !vms
+                .iter()
+                .any(|vm| vm == "*" || vm.eq_ignore_ascii_case(slug))
+        {
+            return None;
+        }
+        Some(claims.exp)
+    }
+
+    /// Splits `<cookie name>=<token>` out of the query string, returning the token and the
+    /// remaining query.
+    fn take_query_token(&self, req: &Request<Body>) -> Option<(String, String)> {
+        let query = req.uri().query()?;
+        let mut token = None;
+        let rest: Vec<&str> = query
+            .split('&')
+            .filter(|pair| match pair.split_once('=') {
+                Some((name, value)) if name == self.cookie_name => {
+                    token = Some(value.to_string());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        Some((token?, rest.join("&")))
+    }
+
+    fn cookie_token(&self, req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                (name == self.cookie_name).then(|| value.to_string())
+            })
+    }
+
+    fn strip_credentials(&self, req: &mut Request<Body>) {
+        let headers = req.headers_mut();
+        headers.remove(TOKEN_HEADER);
+        let remaining: Vec<String> = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .map(str::trim)
+            .filter(|pair| {
+                !pair.is_empty()
+                    && pair
+                        .split_once('=')
+                        .is_none_or(|(name, _)| name != self.cookie_name)
+            })
+            .map(str::to_string)
+            .collect();
+        headers.remove(header::COOKIE);
+        if !remaining.is_empty()
+            && let Ok(value) = HeaderValue::from_str(&remaining.join("; "))
+        {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+
+    fn store_token(
+        &self,
+        req: &Request<Body>,
+        host: &str,
+        token: &str,
+        exp: i64,
+        query: String,
+    ) -> Response<Body> {
+        let scheme = request_scheme(req);
+        let mut location = format!("{}://{}{}", scheme, host, req.uri().path());
+        if !query.is_empty() {
+            location.push('?');
+            location.push_str(&query);
+        }
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.cookie_name,
+            token,
+            (exp - Utc::now().timestamp()).max(0)
+        );
+        if scheme == "https" {
+            cookie.push_str("; Secure");
+        }
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, location)
+            .header(header::SET_COOKIE, cookie)
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn unauthorized(&self, req: &Request<Body>, host: &str) -> Response<Body> {
+        let wants_html = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+        if let Some(login_url) = &self.login_url
+            && *req.method() == Method::GET
+            && wants_html
+        {
+            let path_and_query = req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            let preview_url = format!("{}://{}{}", request_scheme(req), host, path_and_query);
+            let separator = if login_url.contains('?') { '&' } else { '?' };
+            let location = format!(
+                "{}{}redirect={}",
+                login_url,
+                separator,
+                utf8_percent_encode(&preview_url, NON_ALPHANUMERIC)
+            );
+            return Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, location)
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::empty())
+                .unwrap();
+        }
+        json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({
+                "error": "unauthorized",
+                "message": "This preview requires signing in to cmux.",
+            }),
+        )
+    }
+}
+
+/// The scheme the browser used, as reported by the load balancer. Requests arriving on the
+/// proxy's own TLS listener are tagged `https` before they get here.
+fn request_scheme(req: &Request<Body>) -> &str {
+    req.headers()
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .map(|proto| proto.split(',').next().unwrap_or(proto).trim())
+        .filter(|proto| matches!(*proto, "http" | "https"))
+        .unwrap_or("http")
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use http::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    header::{self, HeaderName, HeaderValue},
+};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+
+/// Caches upstream responses that are marked immutable or fresh for a long time, such as the
+/// hashed JS and CSS bundles Vite and Next emit, so repeat preview loads skip the WAN.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Memory budget for cached bodies and headers.
+    pub max_bytes: u64,
+    /// Responses larger than this (by `Content-Length`) are never cached. Responses without a
+    /// `Content-Length` are not cached either.
+    pub max_entry_bytes: u64,
+    /// Shortest `max-age` worth caching when the response is not marked `immutable`.
+    pub min_max_age: Duration,
+    /// Also keep entries on disk here, so they survive restarts and memory eviction.
+    pub disk_dir: Option<PathBuf>,
+    /// Disk budget when `disk_dir` is set.
+    pub disk_max_bytes: u64,
+}
+
+impl CacheConfig {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            max_entry_bytes: 8 * 1024 * 1024,
+            min_max_age: Duration::from_secs(24 * 60 * 60),
+            disk_dir: None,
+            disk_max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// An upstream response as it arrived, before per-request rewriting.
+#[derive(Debug)]
+pub(crate) struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: SystemTime,
+    expires: SystemTime,
+}
+
+impl CachedResponse {
+    fn size(&self) -> u64 {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        (self.body.len() + headers) as u64
+    }
+
+    fn is_fresh(&self) -> bool {
+        SystemTime::now() < self.expires
+    }
+
+    pub(crate) fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = SystemTime::now()
+            .duration_since(self.stored)
+            .unwrap_or_default()
+            .as_secs();
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(age));
+        response
+    }
+}
+
+/// Size-bounded least-recently-used map.
+struct Lru<V> {
+    entries: HashMap<String, (V, u64, u64)>,
+    order: BTreeMap<u64, String>,
+    bytes: u64,
+    tick: u64,
+}
+
+impl<V> Lru<V> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            bytes: 0,
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let (_, _, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        self.entries.get(key).map(|(value, _, _)| value)
+    }
+
+    /// Inserts `value` and returns whatever had to be evicted to stay within `capacity`.
+    fn insert(&mut self, key: String, value: V, size: u64, capacity: u64) -> Vec<(String, V)> {
+        let mut evicted = Vec::new();
+        if let Some(old) = self.remove(&key) {
+            evicted.push((key.clone(), old));
+        }
+        while self.bytes + size > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((value, size, _)) = self.entries.remove(&oldest) {
+                self.bytes -= size;
+                evicted.push((oldest, value));
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.bytes += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, size, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        self.bytes -= size;
+        Some(value)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskMeta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    stored: u64,
+    expires: u64,
+}
+
+pub(crate) struct ResponseCache {
+    config: CacheConfig,
+    memory: Mutex<Lru<Arc<CachedResponse>>>,
+    /// File names on disk, sized by file length.
+    disk: Mutex<Lru<()>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: CacheConfig, metrics: Arc<Metrics>) -> Self {
+        let cache = Self {
+            memory: Mutex::new(Lru::new()),
+            disk: Mutex::new(Lru::new()),
+            metrics,
+            config,
+        };
+        cache.load_disk_index();
+        cache.report_bytes();
+        cache
+    }
+
+    /// The cache key for `req`, or `None` when the request must go upstream: anything but a
+    /// plain `GET`, range requests, and requests carrying credentials.
+    pub(crate) fn key(req: &Request<Body>, host: &str) -> Option<String> {
+        if req.method() != Method::GET
+            || req.headers().contains_key(header::RANGE)
+            || req.headers().contains_key(header::AUTHORIZATION)
+        {
+            return None;
+        }
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/");
+        let encoding = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        Some(format!("{}{}\n{}", host, path_and_query, encoding))
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let cached = self.memory.lock().unwrap().get(key).cloned();
+        let cached = match cached {
+            Some(cached) if cached.is_fresh() => Some(cached),
+            Some(_) => {
+                self.memory.lock().unwrap().remove(key);
+                None
+            }
+            None => self.read_disk(key).await,
+        };
+        match &cached {
+            Some(_) => self.metrics.cache_hit(),
+            None => self.metrics.cache_miss(),
+        }
+        cached
+    }
+
+    /// When `response` may be cached, returns how long it stays fresh.
+    pub(crate) fn lifetime(&self, response: &Response<Body>) -> Option<Duration> {
+        let headers = response.headers();
+        if response.status() != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())?;
+        if content_length > self.config.max_entry_bytes {
+            return None;
+        }
+        // HTML is rewritten per route, and is rarely immutable anyway.
+        if crate::is_html(headers) {
+            return None;
+        }
+        // Only the encoding is part of the key.
+        for vary in headers.get_all(header::VARY) {
+            let vary = vary.to_str().ok()?;
+            if vary
+                .split(',')
+                .any(|name| !name.trim().eq_ignore_ascii_case("accept-encoding"))
+            {
+                return None;
+            }
+        }
+
+        let mut immutable = false;
+        let mut max_age = None;
+        let mut shared_max_age = None;
+        for directive in headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let directive = directive.trim().to_ascii_lowercase();
+            let (name, value) = directive
+                .split_once('=')
+                .map(|(name, value)| (name.trim(), Some(value.trim().trim_matches('"'))))
+                .unwrap_or((directive.as_str(), None));
+            let seconds = value.and_then(|value| value.parse::<u64>().ok());
+            match name {
+                "no-store" | "no-cache" | "private" => return None,
+                "immutable" => immutable = true,
+                "max-age" => max_age = seconds,
+                "s-maxage" => shared_max_age = seconds,
+                _ => {}
+            }
+        }
+        let lifetime = shared_max_age.or(max_age).map(Duration::from_secs);
+        match lifetime {
+            Some(lifetime) if lifetime.is_zero() => None,
+            Some(lifetime) if immutable || lifetime >= self.config.min_max_age => Some(lifetime),
+            None if immutable => Some(self.config.min_max_age),
+            _ => None,
+        }
+    }
+
+    pub(crate) async fn store(
+        &self,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        lifetime: Duration,
+    ) {
+        let stored = SystemTime::now();
+        let entry = Arc::new(CachedResponse {
+            status,
+            headers,
+            body,
+            stored,
+            expires: stored + lifetime,
+        });
+        let size = entry.size();
+        if size > self.config.max_bytes {
+            return;
+        }
+        let evicted = self.memory.lock().unwrap().insert(
+            key.clone(),
+            entry.clone(),
+            size,
+            self.config.max_bytes,
+        );
+        self.metrics.cache_store();
+        self.metrics.cache_evictions(evicted.len() as u64);
+        self.write_disk(&key, &entry).await;
+        self.report_bytes();
+    }
+
+    fn report_bytes(&self) {
+        let memory = self.memory.lock().unwrap().bytes;
+        let disk = self.disk.lock().unwrap().bytes;
+        self.metrics.set_cache_bytes(memory, disk);
+    }
+
+    fn file_name(key: &str) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        let hex: String = digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}.entry", hex)
+    }
+
+    /// Indexes entries left by a previous run, oldest first.
+    fn load_disk_index(&self) {
+        let Some(dir) = &self.config.disk_dir else {
+            return;
+        };
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            warn!(%err, dir = %dir.display(), "cache directory unavailable");
+            return;
+        }
+        let Ok(read_dir) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut files: Vec<(SystemTime, String, u64)> = read_dir
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if !name.ends_with(".entry") {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, name, metadata.len()))
+            })
+            .collect();
+        files.sort();
+        let mut disk = self.disk.lock().unwrap();
+        for (_, name, size) in files {
+            for (evicted, ()) in disk.insert(name, (), size, self.config.disk_max_bytes) {
+                let _ = std::fs::remove_file(dir.join(evicted));
+            }
+        }
+    }
+
+    async fn read_disk(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let dir = self.config.disk_dir.as_ref()?;
+        let name = Self::file_name(key);
+        self.disk.lock().unwrap().get(&name)?;
+        let path = dir.join(&name);
+        let parsed = tokio::fs::read(&path)
+            .await
+            .ok()
+            .and_then(|contents| parse_disk_entry(key, &contents));
+        let Some(entry) = parsed.filter(|entry| entry.is_fresh()) else {
+            self.disk.lock().unwrap().remove(&name);
+            let _ = tokio::fs::remove_file(&path).await;
+            self.report_bytes();
+            return None;
+        };
+        let entry = Arc::new(entry);
+        let size = entry.size();
+        if size <= self.config.max_bytes {
+            let evicted = self.memory.lock().unwrap().insert(
+                key.to_string(),
+                entry.clone(),
+                size,
+                self.config.max_bytes,
+            );
+            self.metrics.cache_evictions(evicted.len() as u64);
+            self.report_bytes();
+        }
+        Some(entry)
+    }
+
+    async fn write_disk(&self, key: &str, entry: &CachedResponse) {
+        let Some(dir) = &self.config.disk_dir else {
+            return;
+        };
+        let meta = DiskMeta {
+            key: key.to_string(),
+            status: entry.status.as_u16(),
+            headers: entry
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            stored: unix_secs(entry.stored),
+            expires: unix_secs(entry.expires),
+        };
+        let Ok(mut contents) = serde_json::to_vec(&meta) else {
+            return;
+        };
+        contents.push(b'\n');
+        contents.extend_from_slice(&entry.body);
+        let size = contents.len() as u64;
+        if size > self.config.disk_max_bytes {
+            return;
+        }
+
+        let name = Self::file_name(key);
+        let tmp = dir.join(format!("{}.tmp", name));
+        let written = async {
+            tokio::fs::write(&tmp, &contents).await?;
+            tokio::fs::rename(&tmp, dir.join(&name)).await
+        }
+        .await;
+        if let Err(err) = written {
+            debug!(%err, "failed to write cache entry");
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return;
+        }
+        let evicted =
+            self.disk
+                .lock()
+                .unwrap()
+                .insert(name.clone(), (), size, self.config.disk_max_bytes);
+        for (evicted, ()) in evicted {
+            if evicted != name {
+                let _ = tokio::fs::remove_file(dir.join(evicted)).await;
+            }
+        }
+    }
+}
+
+fn parse_disk_entry(key: &str, contents: &[u8]) -> Option<CachedResponse> {
+    let split = contents.iter().position(|byte| *byte == b'\n')?;
+    let meta: DiskMeta = serde_json::from_slice(&contents[..split]).ok()?;
+    if meta.key != key {
+        return None;
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in meta.headers {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes()).ok()?,
+            HeaderValue::from_str(&value).ok()?,
+        );
+    }
+    Some(CachedResponse {
+        status: StatusCode::from_u16(meta.status).ok()?,
+        headers,
+        body: Bytes::copy_from_slice(&contents[split + 1..]),
+        stored: UNIX_EPOCH + Duration::from_secs(meta.stored),
+        expires: UNIX_EPOCH + Duration::from_secs(meta.expires),
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
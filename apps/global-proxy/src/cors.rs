@@ -0,0 +1,66 @@
+use http::{HeaderMap, header::HeaderValue};
+
+/// Origins allowed to embed the VS Code route (port 39378) when no list is configured.
+pub const DEFAULT_FRAME_ANCESTORS: &[&str] = &[
+    "https://cmux.local",
+    "http://cmux.local",
+    "https://www.cmux.sh",
+    "https://cmux.sh",
+    "https://www.cmux.dev",
+    "https://cmux.dev",
+    "http://localhost:5173",
+];
+
+/// CORS and CSP policy built once from the config.
+#[derive(Debug)]
+pub(crate) struct CorsPolicy {
+    /// Empty means any origin (`Access-Control-Allow-Origin: *`).
+    allowed_origins: Vec<String>,
+    frame_ancestors: HeaderValue,
+}
+
+impl CorsPolicy {
+    pub(crate) fn new(allowed_origins: &[String], frame_ancestors: &[String]) -> Self {
+        let allowed_origins = allowed_origins
+            .iter()
+            .map(|origin| normalize_origin(origin))
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        let mut csp = String::from("frame-ancestors 'self'");
+        for ancestor in frame_ancestors {
+            let ancestor = ancestor.trim();
+            if !ancestor.is_empty() {
+                csp.push(' ');
+                csp.push_str(ancestor);
+            }
+        }
+        csp.push(';');
+        Self {
+            allowed_origins,
+            frame_ancestors: HeaderValue::from_str(&csp)
+                .unwrap_or_else(|_| HeaderValue::from_static("frame-ancestors 'self';")),
+        }
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request, or `None` when its origin is not
+    /// allowed. With an allowlist the request's own `Origin` is echoed back.
+    pub(crate) fn allow_origin(&self, request_headers: &HeaderMap) -> Option<HeaderValue> {
+        if self.allowed_origins.is_empty() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = request_headers.get(http::header::ORIGIN)?;
+        let normalized = normalize_origin(origin.to_str().ok()?);
+        self.allowed_origins
+            .contains(&normalized)
+            .then(|| origin.clone())
+    }
+
+    /// `Content-Security-Policy` value for routes that may be framed.
+    pub(crate) fn frame_ancestors(&self) -> HeaderValue {
+        self.frame_ancestors.clone()
+    }
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
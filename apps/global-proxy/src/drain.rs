@@ -0,0 +1,118 @@
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::{Notify, watch};
+
+/// How long [`ProxyHandle::shutdown`](crate::ProxyHandle::shutdown) waits for open
+/// connections and tunnels before closing them, when none is configured.
+pub const DEFAULT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// What happened to the connections open when shutdown began.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Client connections and WebSocket tunnels open when shutdown began.
+    pub open: usize,
+    /// Of those, how many were still open at the drain timeout and were closed.
+    pub force_closed: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Serving,
+    /// Listeners have stopped accepting; open connections finish what they are doing.
+    Draining,
+    /// The drain timeout passed; whatever is still open is dropped.
+    Closing,
+}
+
+/// Tracks open client connections and WebSocket tunnels so shutdown can wait for them.
+pub(crate) struct Drain {
+    phase: watch::Sender<Phase>,
+    active: AtomicUsize,
+    force_closed: AtomicUsize,
+    idle: Notify,
+}
+
+impl Drain {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            phase: watch::Sender::new(Phase::Serving),
+            active: AtomicUsize::new(0),
+            force_closed: AtomicUsize::new(0),
+            idle: Notify::new(),
+        })
+    }
+
+    /// Runs a connection or tunnel on its own task, counted as open until it finishes and
+    /// dropped if it is still running at the drain timeout.
+    pub(crate) fn spawn<F>(self: &Arc<Self>, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = DrainGuard(self.clone());
+        tokio::spawn(async move {
+            let drain = &guard.0;
+            tokio::select! {
+                _ = work => {}
+                _ = drain.reached(Phase::Closing) => {
+                    drain.force_closed.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+
+    /// Resolves once shutdown has begun: listeners stop accepting and connections should
+    /// finish their in-flight requests.
+    pub(crate) async fn draining(&self) {
+        self.reached(Phase::Draining).await;
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut rx = self.phase.subscribe();
+        let _ = rx.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Stops new work, waits up to `timeout` for open connections and tunnels, then closes
+    /// the rest.
+    pub(crate) async fn shutdown(&self, timeout: std::time::Duration) -> ShutdownReport {
+        self.phase.send_replace(Phase::Draining);
+        let open = self.active.load(Ordering::SeqCst);
+        if tokio::time::timeout(timeout, self.wait_idle())
+            .await
+            .is_err()
+        {
+            self.phase.send_replace(Phase::Closing);
+            self.wait_idle().await;
+        }
+        ShutdownReport {
+            open,
+            force_closed: self.force_closed.load(Ordering::SeqCst),
+        }
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+struct DrainGuard(Arc<Drain>);
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use http::{HeaderValue, Response, StatusCode, header};
+use hyper::Body;
+use serde_json::json;
+
+/// Placeholders replaced in the error page template, HTML-escaped.
+const STATUS_VAR: &str = "__CMUX_ERROR_STATUS__";
+const TITLE_VAR: &str = "__CMUX_ERROR_TITLE__";
+const MESSAGE_VAR: &str = "__CMUX_ERROR_MESSAGE__";
+const HOST_VAR: &str = "__CMUX_ERROR_HOST__";
+const RETRY_VAR: &str = "__CMUX_RETRY_SECONDS__";
+
+/// The page browsers get when a preview's backend cannot be reached.
+#[derive(Clone, Debug)]
+pub struct ErrorPageConfig {
+    /// Replaces the built-in page. `__CMUX_ERROR_STATUS__`, `__CMUX_ERROR_TITLE__`,
+    /// `__CMUX_ERROR_MESSAGE__`, `__CMUX_ERROR_HOST__` and `__CMUX_RETRY_SECONDS__` are
+    /// replaced with the response status, a short title and explanation, the preview host and
+    /// the retry delay.
+    pub template: Option<String>,
+    /// Sent as `Retry-After`; the built-in page waits this long before its first retry.
+    pub retry_after: Duration,
+}
+
+impl Default for ErrorPageConfig {
+    fn default() -> Self {
+        Self {
+            template: None,
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Why a request could not be proxied to its backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpstreamFailure {
+    /// The VM slug did not resolve to a backend.
+    NotRunning,
+    /// The backend refused or dropped the connection, e.g. while the VM resumes.
+    Unreachable,
+}
+
+impl UpstreamFailure {
+    fn code(self) -> &'static str {
+        match self {
+            UpstreamFailure::NotRunning => "workspace_not_running",
+            UpstreamFailure::Unreachable => "workspace_unreachable",
+        }
+    }
+
+    /// The plain-text body kept for clients that accept neither HTML nor JSON.
+    fn text(self) -> &'static str {
+        match self {
+            UpstreamFailure::NotRunning => "Failed to resolve backend",
+            UpstreamFailure::Unreachable => "Upstream fetch failed",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            UpstreamFailure::NotRunning => "Workspace is not running",
+            UpstreamFailure::Unreachable => "Workspace is waking up",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            UpstreamFailure::NotRunning => {
+                "This workspace is not running. It may have been paused or stopped."
+            }
+            UpstreamFailure::Unreachable => {
+                "The workspace is not answering yet. This page reloads once it is ready."
+            }
+        }
+    }
+}
+
+pub(crate) struct ErrorPages {
+    template: String,
+    retry_after: u64,
+}
+
+impl ErrorPages {
+    pub(crate) fn new(config: ErrorPageConfig) -> Self {
+        Self {
+            template: config
+                .template
+                .unwrap_or_else(|| ERROR_PAGE_HTML.to_string()),
+            retry_after: config.retry_after.as_secs().max(1),
+        }
+    }
+
+    /// A `502` for `failure`: HTML for browsers, JSON for API clients and plain text otherwise.
+    pub(crate) fn response(
+        &self,
+        accept: Option<&HeaderValue>,
+        host: &str,
+        failure: UpstreamFailure,
+    ) -> Response<Body> {
+        let status = StatusCode::BAD_GATEWAY;
+        let builder = Response::builder()
+            .status(status)
+            .header(header::CACHE_CONTROL, "no-store")
+            .header(header::RETRY_AFTER, self.retry_after);
+        let (content_type, body) = match preferred_format(accept) {
+            Format::Html => (
+                "text/html; charset=utf-8",
+                self.template
+                    .replace(STATUS_VAR, status.as_str())
+                    .replace(TITLE_VAR, &escape_html(failure.title()))
+                    .replace(MESSAGE_VAR, &escape_html(failure.message()))
+                    .replace(HOST_VAR, &escape_html(host))
+                    .replace(RETRY_VAR, &self.retry_after.to_string()),
+            ),
+            Format::Json => (
+                "application/json",
+                json!({
+                    "error": failure.code(),
+                    "message": failure.message(),
+                    "host": host,
+                    "retry_after": self.retry_after,
+                })
+                .to_string(),
+            ),
+            Format::Text => ("text/plain; charset=utf-8", failure.text().to_string()),
+        };
+        builder
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+/// Browser navigations list `text/html`; `fetch()` and API clients send `*/*` or JSON types.
+fn preferred_format(accept: Option<&HeaderValue>) -> Format {
+    let mut json = false;
+    for media in accept
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+    {
+        let media = media
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if media == "text/html" || media == "application/xhtml+xml" {
+            return Format::Html;
+        }
+        json |= media == "application/json" || media.ends_with("+json");
+    }
+    if json { Format::Json } else { Format::Text }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const ERROR_PAGE_HTML: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>__CMUX_ERROR_TITLE__ · cmux</title>
+<style>
+  :root { color-scheme: light dark; }
+  body { margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center;
+    font: 15px/1.5 -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif;
+    background: #0a0a0a; color: #e5e5e5; }
+  main { max-width: 28rem; padding: 2rem; text-align: center; }
+  h1 { font-size: 1.25rem; margin: 1rem 0 0.5rem; }
+  p { color: #a3a3a3; margin: 0.5rem 0; }
+  code { color: #d4d4d4; }
+  .spinner { width: 28px; height: 28px; margin: 0 auto; border-radius: 50%;
+    border: 3px solid #404040; border-top-color: #e5e5e5; animation: spin 1s linear infinite; }
+  @keyframes spin { to { transform: rotate(360deg); } }
+</style>
+</head>
+<body>
+<main>
+  <div class="spinner"></div>
+  <h1>__CMUX_ERROR_TITLE__</h1>
+  <p>__CMUX_ERROR_MESSAGE__</p>
+  <p><code>__CMUX_ERROR_HOST__</code></p>
+  <p id="cmux-retry">Retrying in __CMUX_RETRY_SECONDS__s…</p>
+</main>
+<script>
+(function () {
+  var delay = __CMUX_RETRY_SECONDS__;
+  var status = document.getElementById('cmux-retry');
+  function check() {
+    status.textContent = 'Checking…';
+    fetch(location.href, { method: 'HEAD', cache: 'no-store', credentials: 'include' })
+      .then(function (res) {
+        if (res.status !== __CMUX_ERROR_STATUS__) {
+          location.reload();
+          return;
+        }
+        schedule();
+      })
+      .catch(schedule);
+  }
+  function schedule() {
+    status.textContent = 'Retrying in ' + delay + 's…';
+    setTimeout(check, delay * 1000);
+    delay = Math.min(delay * 2, 60);
+  }
+  schedule();
+})();
+</script>
+</body>
+</html>
+"#;
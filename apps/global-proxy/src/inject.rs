@@ -0,0 +1,171 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+/// The kind of preview host a request was routed through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteClass {
+    /// `port-<port>-<morph>` hosts.
+    Port,
+    /// `cmux-<morph>-...-<port>` hosts.
+    Cmux,
+    /// `<workspace>-<port>-<vm>` hosts.
+    Workspace,
+}
+
+/// A set of route classes, written as `all`, `none` or a comma-separated list such as
+/// `port,workspace`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouteClasses {
+    pub port: bool,
+    pub cmux: bool,
+    pub workspace: bool,
+}
+
+impl RouteClasses {
+    pub const ALL: Self = Self {
+        port: true,
+        cmux: true,
+        workspace: true,
+    };
+    pub const NONE: Self = Self {
+        port: false,
+        cmux: false,
+        workspace: false,
+    };
+
+    pub fn contains(&self, class: RouteClass) -> bool {
+        match class {
+            RouteClass::Port => self.port,
+            RouteClass::Cmux => self.cmux,
+            RouteClass::Workspace => self.workspace,
+        }
+    }
+}
+
+impl Default for RouteClasses {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl FromStr for RouteClasses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => return Ok(Self::ALL),
+            "none" | "" => return Ok(Self::NONE),
+            _ => {}
+        }
+        let mut classes = Self::NONE;
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.to_ascii_lowercase().as_str() {
+                "port" => classes.port = true,
+                "cmux" => classes.cmux = true,
+                "workspace" => classes.workspace = true,
+                other => {
+                    return Err(format!(
+                        "unknown route class '{}' (expected port, cmux or workspace)",
+                        other
+                    ));
+                }
+            }
+        }
+        Ok(classes)
+    }
+}
+
+impl<'de> Deserialize<'de> for RouteClasses {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for RouteClasses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::ALL {
+            return f.write_str("all");
+        }
+        let names: Vec<&str> = [
+            (self.port, "port"),
+            (self.cmux, "cmux"),
+            (self.workspace, "workspace"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(","))
+        }
+    }
+}
+
+/// Where in the document a snippet is inserted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InjectPosition {
+    /// Start of `<head>`, after the built-in cmux scripts.
+    #[default]
+    HeadStart,
+    /// End of `<head>`.
+    HeadEnd,
+    /// Start of `<body>`.
+    BodyStart,
+    /// End of `<body>`.
+    BodyEnd,
+}
+
+impl FromStr for InjectPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "head" | "head-start" => Ok(Self::HeadStart),
+            "head-end" => Ok(Self::HeadEnd),
+            "body" | "body-start" => Ok(Self::BodyStart),
+            "body-end" => Ok(Self::BodyEnd),
+            other => Err(format!(
+                "unknown injection position '{}' (expected head-start, head-end, body-start or body-end)",
+                other
+            )),
+        }
+    }
+}
+
+/// Extra HTML inserted into proxied pages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HtmlSnippet {
+    pub html: String,
+    pub position: InjectPosition,
+    pub routes: RouteClasses,
+}
+
+/// What gets injected into proxied HTML responses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HtmlInjections {
+    /// Routes that get the `window.__cmuxLocation` script.
+    pub location: RouteClasses,
+    /// Routes that get the service-worker registration. The cmux route and the VS Code port
+    /// never get it.
+    pub service_worker: RouteClasses,
+    /// Additional snippets, inserted in order.
+    pub snippets: Vec<HtmlSnippet>,
+}
+
+impl HtmlInjections {
+    /// Snippets for `class` at `position`, in configuration order.
+    pub(crate) fn snippets_at(
+        &self,
+        class: RouteClass,
+        position: InjectPosition,
+    ) -> impl DoubleEndedIterator<Item = &str> {
+        self.snippets
+            .iter()
+            .filter(move |s| s.position == position && s.routes.contains(class))
+            .map(|s| s.html.as_str())
+    }
+}
@@ -1,29 +1,87 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    cell::RefCell,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
-use futures_util::{SinkExt, StreamExt};
 use http::{
     HeaderMap, Method, Request, Response, StatusCode, Uri, Version,
     header::{self, HeaderValue},
     uri::Scheme,
 };
 use hyper::{
-    Body, Client, body,
-    client::HttpConnector,
-    server::conn::AddrStream,
-    service::{make_service_fn, service_fn},
+    Body, Client, body, body::HttpBody, client::HttpConnector, server::conn::Http,
+    service::service_fn,
 };
 use hyper_rustls::HttpsConnectorBuilder;
-use hyper_tungstenite::{HyperWebsocket, is_upgrade_request};
+use hyper_tungstenite::is_upgrade_request;
 use lol_html::{HtmlRewriter, Settings, element, html_content::ContentType};
 use tokio::{sync::oneshot, task::JoinHandle};
-use tokio_tungstenite::{connect_async, tungstenite::client::IntoClientRequest};
-use tracing::error;
+use tracing::{debug, error, warn};
 
 use chrono::Utc;
 use serde_json::{Value, json};
 
-type HttpClient = Client<hyper_rustls::HttpsConnector<HttpConnector>, Body>;
+mod access_log;
+mod acme;
+mod apex;
+mod auth;
+mod cache;
+mod cors;
+mod drain;
+mod error_page;
+mod inject;
+mod limits;
+mod loop_guard;
+mod metrics;
+mod rate_limit;
+mod resolver;
+mod rules;
+mod service_worker;
+mod timing;
+mod tls;
+mod ws_limit;
+mod ws_relay;
+
+pub use access_log::AccessLogConfig;
+pub use acme::{
+    AcmeChallenge, AcmeConfig, CommandDnsProvider, DnsProvider, LETS_ENCRYPT_DIRECTORY,
+    LETS_ENCRYPT_STAGING_DIRECTORY,
+};
+pub use apex::{ApexSite, StaticSite};
+pub use auth::{AuthConfig, DEFAULT_AUTH_COOKIE};
+pub use cache::CacheConfig;
+pub use cors::DEFAULT_FRAME_ANCESTORS;
+pub use drain::{DEFAULT_DRAIN_TIMEOUT, ShutdownReport};
+pub use error_page::ErrorPageConfig;
+pub use inject::{HtmlInjections, HtmlSnippet, InjectPosition, RouteClass, RouteClasses};
+pub use limits::RequestLimits;
+pub use loop_guard::{DEFAULT_HOPS_HEADER, DEFAULT_LOOP_HEADER, DEFAULT_MAX_HOPS, LoopDetection};
+pub use rate_limit::{RateLimit, RateLimitConfig};
+pub use resolver::{BackendResolver, DnsResolver, StaticResolver};
+pub use rules::{RouteRule, RuleMatch};
+pub use service_worker::{DEFAULT_LOOPBACK_HOSTS, ServiceWorkerConfig};
+
+use access_log::{AccessEntry, AccessLog};
+use acme::AcmeManager;
+use auth::PreviewAuth;
+use cache::ResponseCache;
+use cors::CorsPolicy;
+use drain::Drain;
+use error_page::{ErrorPages, UpstreamFailure};
+use limits::{ConnectionActivity, HeadTimeout};
+use loop_guard::LoopGuard;
+use metrics::{Metrics, RouteLabel};
+use rate_limit::{ClientAddr, LimitScope, RateLimiter};
+use rules::{RouteRules, RuleOutcome};
+use service_worker::ServiceWorker;
+use timing::{ServerTiming, TimedConnector};
+use ws_limit::WsLimiter;
+use ws_relay::FrameLimits;
+
+type HttpClient = Client<hyper_rustls::HttpsConnector<TimedConnector>, Body>;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const GIT_COMMIT: &str = match option_env!("GIT_COMMIT") {
@@ -31,7 +89,19 @@ const GIT_COMMIT: &str = match option_env!("GIT_COMMIT") {
     None => "unknown",
 };
 
-const CSP_FRAME_ANCESTORS_PORT_39378: &str = "frame-ancestors 'self' https://cmux.local http://cmux.local https://www.cmux.sh https://cmux.sh https://www.cmux.dev https://cmux.dev http://localhost:5173;";
+/// Base domains served when none are configured.
+pub const DEFAULT_BASE_DOMAINS: &[&str] = &["cmux.sh", "cmux.localhost", "cmux.app"];
+
+/// Seconds a client refused by the WebSocket cap is asked to wait before retrying.
+const WS_RETRY_AFTER_SECS: u64 = 5;
+/// Handshake headers passed to the backend unchanged so it negotiates directly with the browser.
+const WEBSOCKET_HANDSHAKE_HEADERS: &[&str] = &[
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
 
 #[derive(Clone, Debug)]
 pub struct ProxyConfig {
@@ -40,6 +110,63 @@ pub struct ProxyConfig {
     pub backend_scheme: Scheme,
     pub morph_domain_suffix: Option<String>,
     pub workspace_domain_suffix: Option<String>,
+    /// Resolves each preview host's VM slug and port to a backend address. Takes precedence over
+    /// `backend_host` and the domain suffixes; requests that fail to resolve get `502`.
+    pub backend_resolver: Option<Arc<dyn BackendResolver>>,
+    /// Attach `Server-Timing` metrics (resolve, connect, backend TTFB) to proxied HTTP
+    /// responses.
+    pub server_timing: bool,
+    /// Refuse new WebSocket upgrades to a preview host once it has this many open tunnels.
+    pub max_websockets_per_host: Option<usize>,
+    /// Close WebSocket tunnels that carry a frame with a larger payload, with code `1009`.
+    pub websocket_max_frame_bytes: Option<u64>,
+    /// Like `websocket_max_frame_bytes`, for a whole (possibly fragmented) message.
+    pub websocket_max_message_bytes: Option<u64>,
+    /// Offer HTTP/2 on the TLS listener. WebSocket upgrades keep using HTTP/1.1 connections.
+    pub http2: bool,
+    /// Speak HTTP/2 to backends: prior-knowledge h2c for `http` backends and ALPN for `https`
+    /// ones. Only enable it when every plain-HTTP backend accepts h2c.
+    pub upstream_http2: bool,
+    /// Origins that get CORS headers on cmux routes. Empty allows any origin (`*`); otherwise
+    /// the request's `Origin` is echoed back when listed and CORS headers are left off when not.
+    pub cors_allowed_origins: Vec<String>,
+    /// Origins allowed to frame the VS Code route via CSP `frame-ancestors` (`'self'` is always
+    /// included).
+    pub frame_ancestors: Vec<String>,
+    /// Scripts and snippets injected into proxied HTML pages.
+    pub html_injections: HtmlInjections,
+    /// Domains whose subdomains are routed (`port-…`, `cmux-…` and workspace hosts). A
+    /// request for any other host gets `502 Not a cmux domain`.
+    pub base_domains: Vec<String>,
+    /// Terminate TLS on a second listener with certificates obtained over ACME.
+    pub acme: Option<AcmeConfig>,
+    /// Require a control-plane token on `port-…` and workspace previews.
+    pub auth: Option<AuthConfig>,
+    /// Serve Prometheus `/metrics` and `/healthz` on this internal address.
+    pub metrics_bind_addr: Option<SocketAddr>,
+    /// Cache immutable and long-lived upstream responses.
+    pub cache: Option<CacheConfig>,
+    /// How this proxy marks forwarded requests and recognises ones that loop back.
+    pub loop_detection: LoopDetection,
+    /// The script served at `/proxy-sw.js`.
+    pub service_worker: ServiceWorkerConfig,
+    /// Per-route header transforms, CSP and injection overrides, applied after the built-in
+    /// VS Code rules.
+    pub route_rules: Vec<RouteRule>,
+    /// Per-client-IP and per-VM-slug request limits on preview hosts. Refused requests get
+    /// `429` with `Retry-After`.
+    pub rate_limit: RateLimitConfig,
+    /// The page served when a preview's backend cannot be reached.
+    pub error_page: ErrorPageConfig,
+    /// Log every request (or a sample) with its route, slug, status, duration and size.
+    pub access_log: Option<AccessLogConfig>,
+    /// What the base domains themselves serve.
+    pub apex: ApexSite,
+    /// How long [`ProxyHandle::shutdown`] waits for in-flight requests and WebSocket tunnels
+    /// before closing them.
+    pub drain_timeout: Duration,
+    /// Header size, body size and header read timeout limits on client requests.
+    pub request_limits: RequestLimits,
 }
 
 impl Default for ProxyConfig {
@@ -50,22 +177,55 @@ impl Default for ProxyConfig {
             backend_scheme: Scheme::HTTP,
             morph_domain_suffix: None,
             workspace_domain_suffix: None,
+            backend_resolver: None,
+            server_timing: true,
+            max_websockets_per_host: None,
+            websocket_max_frame_bytes: None,
+            websocket_max_message_bytes: None,
+            http2: true,
+            upstream_http2: false,
+            cors_allowed_origins: Vec::new(),
+            frame_ancestors: DEFAULT_FRAME_ANCESTORS
+                .iter()
+                .map(|origin| origin.to_string())
+                .collect(),
+            html_injections: HtmlInjections::default(),
+            base_domains: DEFAULT_BASE_DOMAINS.iter().map(|d| d.to_string()).collect(),
+            acme: None,
+            auth: None,
+            metrics_bind_addr: None,
+            cache: None,
+            loop_detection: LoopDetection::default(),
+            service_worker: ServiceWorkerConfig::default(),
+            route_rules: Vec::new(),
+            rate_limit: RateLimitConfig::default(),
+            error_page: ErrorPageConfig::default(),
+            access_log: None,
+            apex: ApexSite::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            request_limits: RequestLimits::default(),
         }
     }
 }
 
 pub struct ProxyHandle {
     pub addr: SocketAddr,
+    /// The TLS listener's address when ACME is configured.
+    pub tls_addr: Option<SocketAddr>,
+    /// The metrics listener's address when one is configured.
+    pub metrics_addr: Option<SocketAddr>,
     shutdown: Option<oneshot::Sender<()>>,
-    task: JoinHandle<()>,
+    task: JoinHandle<ShutdownReport>,
 }
 
 impl ProxyHandle {
-    pub async fn shutdown(mut self) {
+    /// Stops accepting connections, waits up to the drain timeout for in-flight requests and
+    /// WebSocket tunnels to finish, then closes whatever is left.
+    pub async fn shutdown(mut self) -> ShutdownReport {
         if let Some(tx) = self.shutdown.take() {
             let _ = tx.send(());
         }
-        let _ = self.task.await;
+        self.task.await.unwrap_or_default()
     }
 }
 
@@ -75,65 +235,361 @@ pub enum ProxyError {
     Io(#[from] std::io::Error),
     #[error("hyper error: {0}")]
     Hyper(#[from] hyper::Error),
+    #[error("invalid configuration: {0}")]
+    Config(String),
 }
 
 struct AppState {
     client: HttpClient,
+    upstream_client: HttpClient,
+    /// Prior-knowledge HTTP/2 client for plain-HTTP backends.
+    h2c_client: Option<HttpClient>,
     backend_host: String,
     backend_scheme: Scheme,
     morph_domain_suffix: Option<String>,
     workspace_domain_suffix: Option<String>,
+    backend_resolver: Option<Arc<dyn BackendResolver>>,
+    server_timing: bool,
+    ws_limiter: WsLimiter,
+    ws_frame_limits: FrameLimits,
+    cors: CorsPolicy,
+    route_rules: RouteRules,
+    html_injections: Arc<HtmlInjections>,
+    service_worker: Arc<ServiceWorker>,
+    base_domains: Vec<String>,
+    acme: Option<Arc<AcmeManager>>,
+    http2: bool,
+    auth: Option<PreviewAuth>,
+    metrics: Arc<Metrics>,
+    cache: Option<ResponseCache>,
+    loop_guard: LoopGuard,
+    rate_limiter: RateLimiter,
+    error_pages: ErrorPages,
+    access_log: Option<Arc<AccessLog>>,
+    apex: ApexSite,
+    drain: Arc<Drain>,
+    limits: RequestLimits,
+}
+
+impl AppState {
+    /// The client for a proxied (non-WebSocket) request to `uri`.
+    fn upstream_client(&self, uri: &Uri) -> &HttpClient {
+        match &self.h2c_client {
+            Some(h2c) if uri.scheme() == Some(&Scheme::HTTP) => h2c,
+            _ => &self.upstream_client,
+        }
+    }
 }
 
 pub async fn spawn_proxy(config: ProxyConfig) -> Result<ProxyHandle, ProxyError> {
+    let loop_guard = LoopGuard::new(config.loop_detection).map_err(ProxyError::Config)?;
+    let apex = config.apex.prepare().map_err(ProxyError::Config)?;
+    let cors = CorsPolicy::new(&config.cors_allowed_origins, &config.frame_ancestors);
+    let route_rules =
+        RouteRules::new(&config.route_rules, cors.frame_ancestors()).map_err(ProxyError::Config)?;
     let listener = std::net::TcpListener::bind(config.bind_addr)?;
     listener.set_nonblocking(true)?;
     let local_addr = listener.local_addr()?;
 
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
     let https = HttpsConnectorBuilder::new()
         .with_webpki_roots()
         .https_or_http()
         .enable_http1()
-        .build();
-    let client: HttpClient = Client::builder().build(https);
+        .wrap_connector(TimedConnector::new(http.clone()));
+    let client: HttpClient = Client::builder().build(https.clone());
+    // WebSocket upgrades and ACME always use `client`, which stays on HTTP/1.1.
+    let (upstream_client, h2c_client) = if config.upstream_http2 {
+        let alpn = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_all_versions()
+            .wrap_connector(TimedConnector::new(http));
+        (
+            Client::builder().build(alpn),
+            Some(Client::builder().http2_only(true).build(https)),
+        )
+    } else {
+        (client.clone(), None)
+    };
 
+    let base_domains = normalize_base_domains(&config.base_domains);
+    let tls = match config.acme {
+        Some(acme_config) => {
+            let tls_listener = std::net::TcpListener::bind(acme_config.tls_bind_addr)?;
+            tls_listener.set_nonblocking(true)?;
+            let tls_listener = tokio::net::TcpListener::from_std(tls_listener)?;
+            let manager = Arc::new(AcmeManager::new(
+                acme_config,
+                client.clone(),
+                base_domains.clone(),
+                config.backend_resolver.clone(),
+            ));
+            Some((tls_listener, manager))
+        }
+        None => None,
+    };
+
+    let (access_log, access_queue) = match &config.access_log {
+        Some(access_config) => {
+            let (log, queue) = AccessLog::new(access_config);
+            (Some(Arc::new(log)), queue)
+        }
+        None => (None, None),
+    };
+
+    let metrics = Arc::new(Metrics::default());
     let state = Arc::new(AppState {
-        client,
+        client: client.clone(),
+        upstream_client,
+        h2c_client,
         backend_host: config.backend_host,
         backend_scheme: config.backend_scheme,
         morph_domain_suffix: config.morph_domain_suffix,
         workspace_domain_suffix: config.workspace_domain_suffix,
+        backend_resolver: config.backend_resolver,
+        server_timing: config.server_timing,
+        ws_limiter: WsLimiter::new(config.max_websockets_per_host),
+        ws_frame_limits: FrameLimits {
+            max_frame: config.websocket_max_frame_bytes,
+            max_message: config.websocket_max_message_bytes,
+        },
+        cors,
+        route_rules,
+        html_injections: Arc::new(config.html_injections),
+        service_worker: Arc::new(ServiceWorker::new(config.service_worker, &base_domains)),
+        base_domains,
+        acme: tls.as_ref().map(|(_, manager)| manager.clone()),
+        http2: config.http2,
+        auth: config.auth.map(PreviewAuth::new),
+        cache: config
+            .cache
+            .map(|cache| ResponseCache::new(cache, metrics.clone())),
+        metrics,
+        loop_guard,
+        rate_limiter: RateLimiter::new(config.rate_limit),
+        error_pages: ErrorPages::new(config.error_page),
+        access_log,
+        apex,
+        drain: Drain::new(),
+        limits: config.request_limits,
     });
 
-    let make_svc = make_service_fn(move |_conn: &AddrStream| {
-        let state = state.clone();
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                let state = state.clone();
-                async move { Ok::<_, hyper::Error>(handle_request(state, req).await) }
-            }))
-        }
-    });
+    let mut tls_addr = None;
+    let mut tls_tasks = None;
+    if let Some((tls_listener, manager)) = tls {
+        tls_addr = Some(tls_listener.local_addr()?);
+        let serve = tokio::spawn(tls::serve(tls_listener, manager.clone(), state.clone()));
+        let maintain = tokio::spawn(manager.maintain());
+        tls_tasks = Some((serve, maintain));
+    }
+
+    let mut metrics_addr = None;
+    let mut metrics_task = None;
+    if let Some(addr) = config.metrics_bind_addr {
+        let metrics_listener = std::net::TcpListener::bind(addr)?;
+        metrics_listener.set_nonblocking(true)?;
+        metrics_addr = Some(metrics_listener.local_addr()?);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let serve = tokio::spawn(metrics::serve(
+            metrics_listener,
+            state.metrics.clone(),
+            stop_rx,
+        ));
+        metrics_task = Some((stop_tx, serve));
+    }
 
-    let server = hyper::Server::from_tcp(listener)?.serve(make_svc);
+    let mut access_log_task = None;
+    if let (Some(access_config), Some(queue)) = (config.access_log, access_queue) {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let export = tokio::spawn(access_log::export(
+            access_config,
+            client.clone(),
+            queue,
+            stop_rx,
+        ));
+        access_log_task = Some((stop_tx, export));
+    }
+
+    let drain_timeout = config.drain_timeout;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let accept = tokio::spawn(serve_http(listener, state.clone()));
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    let graceful = server.with_graceful_shutdown(async move {
-        let _ = shutdown_rx.await;
-    });
     let task = tokio::spawn(async move {
-        if let Err(err) = graceful.await {
-            error!(%err, "proxy server error");
+        let _ = shutdown_rx.await;
+        let report = state.drain.shutdown(drain_timeout).await;
+        let _ = accept.await;
+        if let Some((serve, maintain)) = tls_tasks {
+            let _ = serve.await;
+            maintain.abort();
+        }
+        if let Some((stop_tx, serve)) = metrics_task {
+            let _ = stop_tx.send(());
+            let _ = serve.await;
         }
+        if let Some((stop_tx, export)) = access_log_task {
+            let _ = stop_tx.send(());
+            let _ = export.await;
+        }
+        report
     });
 
     Ok(ProxyHandle {
         addr: local_addr,
+        tls_addr,
+        metrics_addr,
         shutdown: Some(shutdown_tx),
         task,
     })
 }
 
+/// Accepts plain-HTTP connections until shutdown begins.
+async fn serve_http(listener: tokio::net::TcpListener, state: Arc<AppState>) {
+    loop {
+        let (tcp, peer) = tokio::select! {
+            _ = state.drain.draining() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    debug!(%err, "accept failed");
+                    continue;
+                }
+            },
+        };
+        state.drain.spawn(serve_connection(
+            Http::new(),
+            tcp,
+            peer,
+            false,
+            state.clone(),
+        ));
+    }
+}
+
+/// Serves requests on one client connection. When shutdown begins, the connection finishes
+/// its in-flight requests and closes; upgraded WebSocket tunnels carry on separately.
+async fn serve_connection<I>(
+    mut http: Http,
+    io: I,
+    peer: SocketAddr,
+    https: bool,
+    state: Arc<AppState>,
+) where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let drain = state.drain.clone();
+    state.limits.configure(&mut http);
+    let activity = Arc::new(ConnectionActivity::default());
+    let io = HeadTimeout::new(io, state.limits.header_read_timeout, activity.clone());
+    let service = service_fn(move |mut req: Request<Body>| {
+        let in_flight = activity.begin();
+        req.extensions_mut().insert(ClientAddr(peer));
+        if https {
+            req.headers_mut()
+                .insert("x-forwarded-proto", HeaderValue::from_static("https"));
+            // HTTP/2 carries the host in `:authority`; routing reads the Host header.
+            if !req.headers().contains_key(header::HOST)
+                && let Some(authority) = req.uri().authority()
+                && let Ok(host) = HeaderValue::from_str(authority.as_str())
+            {
+                req.headers_mut().insert(header::HOST, host);
+            }
+        }
+        let state = state.clone();
+        async move {
+            let response = handle_request(state, req).await;
+            if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                in_flight.upgraded();
+            }
+            Ok::<_, hyper::Error>(response)
+        }
+    });
+    let connection = http.serve_connection(io, service).with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = drain.draining() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!(%err, "connection error");
+    }
+}
+
 async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Body> {
+    let started = Instant::now();
+    let route = route_label(&state, &req);
+    let access = state
+        .access_log
+        .as_ref()
+        .map(|_| access_entry(&state, &req, route, started));
+    let response = route_request(state.clone(), req, started).await;
+    state
+        .metrics
+        .observe_request(route, response.status(), started.elapsed());
+    match (&state.access_log, access) {
+        (Some(log), Some(entry)) => log.finish(entry, response),
+        _ => response,
+    }
+}
+
+/// Captures what the access log records about `req` before it is routed.
+fn access_entry(
+    state: &AppState,
+    req: &Request<Body>,
+    route: RouteLabel,
+    started: Instant,
+) -> AccessEntry {
+    let host = extract_host(req);
+    let target = host
+        .as_deref()
+        .and_then(|host| parse_cmux_host(host, &state.base_domains))
+        .and_then(|(subdomain, _)| subdomain)
+        .and_then(|subdomain| match parse_route(subdomain) {
+            Route::Port(route) => Some((route.morph_id, route.port)),
+            Route::Cmux(route) => Some((route.morph_id, route.port)),
+            Route::Workspace(route) => Some((route.vm_slug, route.port)),
+            Route::Invalid(_) => None,
+        });
+    AccessEntry::new(req, started, route, host, target)
+}
+
+/// Classifies a request for metrics the same way [`parse_route`] will route it.
+fn route_label(state: &AppState, req: &Request<Body>) -> RouteLabel {
+    let Some(host) = extract_host(req) else {
+        return RouteLabel::Other;
+    };
+    match parse_cmux_host(&host, &state.base_domains) {
+        None => RouteLabel::Other,
+        Some((None, _)) => RouteLabel::Apex,
+        Some((Some(subdomain), _)) if subdomain.starts_with("port-") => RouteLabel::Port,
+        Some((Some(subdomain), _)) if subdomain.starts_with("cmux-") => RouteLabel::Cmux,
+        Some(_) => RouteLabel::Workspace,
+    }
+}
+
+async fn route_request(
+    state: Arc<AppState>,
+    mut req: Request<Body>,
+    started: Instant,
+) -> Response<Body> {
+    if let Some(acme) = &state.acme
+        && let Some(token) = req
+            .uri()
+            .path()
+            .strip_prefix("/.well-known/acme-challenge/")
+        && let Some(key_authorization) = acme.http_challenge(token)
+    {
+        return text_response(StatusCode::OK, &key_authorization);
+    }
+
+    if let Err(status) = state.limits.check(&req) {
+        return text_response(status, status.canonical_reason().unwrap_or_default());
+    }
+
     if req.uri().path() == "/health" {
         return json_response(
             StatusCode::OK,
@@ -154,8 +610,17 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
         }
     };
 
+    if req.uri().path() == "/healthz"
+        && !matches!(
+            parse_cmux_host(&host, &state.base_domains),
+            Some((Some(_), _))
+        )
+    {
+        return metrics::healthz_response();
+    }
+
     if req.uri().path() == "/version" {
-        match parse_cmux_host(&host) {
+        match parse_cmux_host(&host, &state.base_domains) {
             Some((Some(_), _)) => {
                 // Requests to subdomains must be proxied; fall through.
             }
@@ -171,29 +636,52 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
         }
     }
 
-    if let Some((subdomain, _domain)) = parse_cmux_host(&host) {
+    if let Some((subdomain, _domain)) = parse_cmux_host(&host, &state.base_domains) {
         if subdomain.is_none() {
-            return text_response(StatusCode::OK, "cmux!");
+            return state.apex.respond(&req).await;
+        }
+
+        if let Err(retry_after) = state.rate_limiter.check_ip(&req) {
+            return rate_limited_response(&state, LimitScope::Ip, retry_after);
         }
 
         if req.uri().path() == "/proxy-sw.js" {
-            return service_worker_response();
+            return state.service_worker.response(req.headers());
         }
 
+        let html_injections = state.html_injections.clone();
+        let service_worker = state.service_worker.clone();
         match parse_route(subdomain.unwrap()) {
             Route::Port(route) => {
-                if is_loop_header(&req) {
-                    return text_response(StatusCode::LOOP_DETECTED, "Loop detected in proxy");
+                if let Some(response) = state.loop_guard.detect(req.headers()) {
+                    return response;
+                }
+                if let Err(retry_after) = state.rate_limiter.check_slug(&route.morph_id) {
+                    return rate_limited_response(&state, LimitScope::Slug, retry_after);
                 }
 
-                if route.port == 39_378 && *req.method() == Method::OPTIONS {
+                let rules = state
+                    .route_rules
+                    .evaluate(RouteClass::Port, &host, route.port);
+                if rules.strip_cors && *req.method() == Method::OPTIONS {
                     return Response::builder()
                         .status(StatusCode::NO_CONTENT)
                         .body(Body::empty())
                         .unwrap();
                 }
 
-                let target = if let Some(suffix) = state.morph_domain_suffix.clone() {
+                if let Some(auth) = &state.auth
+                    && let Some(response) = auth.check(&mut req, &host, &route.morph_id)
+                {
+                    return response;
+                }
+
+                let target = if state.backend_resolver.is_some() {
+                    Target::Resolve {
+                        slug: route.morph_id,
+                        port: route.port,
+                    }
+                } else if let Some(suffix) = state.morph_domain_suffix.clone() {
                     let host = format!("port-{}-morphvm-{}{}", route.port, route.morph_id, suffix);
                     Target::Absolute {
                         scheme: Scheme::HTTPS,
@@ -204,45 +692,56 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                     Target::BackendPort(route.port)
                 };
 
-                let (strip_cors_headers, frame_ancestors) = if route.skip_service_worker {
-                    (true, Some(CSP_FRAME_ANCESTORS_PORT_39378))
-                } else {
-                    (false, None)
-                };
-
                 return forward_request(
                     state,
                     req,
                     target,
+                    started,
                     ProxyBehavior {
-                        skip_service_worker: route.skip_service_worker,
-                        add_cors: false,
-                        strip_cors_headers,
+                        route_class: RouteClass::Port,
+                        html_injections,
+                        service_worker,
+                        cors_origin: None,
+                        rules,
                         workspace_header: None,
                         port_header: None,
-                        frame_ancestors,
                     },
                 )
                 .await;
             }
             Route::Cmux(route) => {
-                if is_loop_header(&req) {
-                    return text_response(StatusCode::LOOP_DETECTED, "Loop detected in proxy");
+                if let Some(response) = state.loop_guard.detect(req.headers()) {
+                    return response;
+                }
+                if let Err(retry_after) = state.rate_limiter.check_slug(&route.morph_id) {
+                    return rate_limited_response(&state, LimitScope::Slug, retry_after);
                 }
 
-                let is_vscode_route = route.port == 39_378;
+                let rules = state
+                    .route_rules
+                    .evaluate(RouteClass::Cmux, &host, route.port);
+                let cors_origin = if rules.strip_cors {
+                    None
+                } else {
+                    state.cors.allow_origin(req.headers())
+                };
 
                 if *req.method() == Method::OPTIONS {
-                    if is_vscode_route {
+                    if rules.strip_cors {
                         return Response::builder()
                             .status(StatusCode::NO_CONTENT)
                             .body(Body::empty())
                             .unwrap();
                     }
-                    return cors_response(StatusCode::NO_CONTENT);
+                    return cors_response(StatusCode::NO_CONTENT, cors_origin.as_ref());
                 }
 
-                let target = if let Some(suffix) = state.morph_domain_suffix.clone() {
+                let target = if state.backend_resolver.is_some() {
+                    Target::Resolve {
+                        slug: route.morph_id,
+                        port: route.port,
+                    }
+                } else if let Some(suffix) = state.morph_domain_suffix.clone() {
                     let host = format!("port-39379-morphvm-{}{}", route.morph_id, suffix);
                     Target::Absolute {
                         scheme: Scheme::HTTPS,
@@ -257,23 +756,49 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                     state,
                     req,
                     target,
+                    started,
                     ProxyBehavior {
-                        skip_service_worker: true,
-                        add_cors: !is_vscode_route,
-                        strip_cors_headers: is_vscode_route,
+                        route_class: RouteClass::Cmux,
+                        html_injections,
+                        service_worker,
+                        cors_origin,
+                        rules,
                         workspace_header: route.workspace_header,
                         port_header: Some(route.port.to_string()),
-                        frame_ancestors: None,
                     },
                 )
                 .await;
             }
             Route::Workspace(route) => {
-                if is_loop_header(&req) {
-                    return text_response(StatusCode::LOOP_DETECTED, "Loop detected in proxy");
+                if let Some(response) = state.loop_guard.detect(req.headers()) {
+                    return response;
+                }
+                if let Err(retry_after) = state.rate_limiter.check_slug(&route.vm_slug) {
+                    return rate_limited_response(&state, LimitScope::Slug, retry_after);
+                }
+
+                let rules = state
+                    .route_rules
+                    .evaluate(RouteClass::Workspace, &host, route.port);
+                if rules.strip_cors && *req.method() == Method::OPTIONS {
+                    return Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap();
                 }
 
-                let target = if let Some(suffix) = state.workspace_domain_suffix.clone() {
+                if let Some(auth) = &state.auth
+                    && let Some(response) = auth.check(&mut req, &host, &route.vm_slug)
+                {
+                    return response;
+                }
+
+                let target = if state.backend_resolver.is_some() {
+                    Target::Resolve {
+                        slug: route.vm_slug,
+                        port: route.port,
+                    }
+                } else if let Some(suffix) = state.workspace_domain_suffix.clone() {
                     let host = format!("{}{}", route.vm_slug, suffix);
                     Target::Absolute {
                         scheme: Scheme::HTTPS,
@@ -288,13 +813,15 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                     state,
                     req,
                     target,
+                    started,
                     ProxyBehavior {
-                        skip_service_worker: false,
-                        add_cors: false,
-                        strip_cors_headers: false,
+                        route_class: RouteClass::Workspace,
+                        html_injections,
+                        service_worker,
+                        cors_origin: None,
+                        rules,
                         workspace_header: Some(route.workspace),
                         port_header: Some(route.port.to_string()),
-                        frame_ancestors: None,
                     },
                 )
                 .await;
@@ -306,6 +833,30 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
     text_response(StatusCode::BAD_GATEWAY, "Not a cmux domain")
 }
 
+/// `429` for a request over a rate limit, telling the client when to retry.
+fn rate_limited_response(
+    state: &AppState,
+    scope: LimitScope,
+    retry_after: Duration,
+) -> Response<Body> {
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    debug!(scope = scope.as_str(), retry_after, "rate limited request");
+    state.metrics.rate_limited(scope);
+    let mut response = json_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        json!({
+            "error": "rate_limited",
+            "message": "Too many requests; retry shortly.",
+            "scope": scope.as_str(),
+            "retry_after": retry_after,
+        }),
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
 #[derive(Clone)]
 enum Target {
     BackendPort(u16),
@@ -314,41 +865,101 @@ enum Target {
         host: String,
         port: Option<u16>,
     },
+    /// Looked up through the configured [`BackendResolver`].
+    Resolve {
+        slug: String,
+        port: u16,
+    },
+}
+
+/// The scheme and authority to send a request for `target` to.
+async fn resolve_target(
+    state: &AppState,
+    target: Target,
+) -> Result<(Scheme, String), UpstreamFailure> {
+    let (scheme, host, port) = match target {
+        Target::BackendPort(port) => (
+            state.backend_scheme.clone(),
+            state.backend_host.clone(),
+            Some(port),
+        ),
+        Target::Absolute { scheme, host, port } => (scheme, host, port),
+        Target::Resolve { slug, port } => {
+            let Some(resolver) = &state.backend_resolver else {
+                return Err(UpstreamFailure::NotRunning);
+            };
+            return match resolver.resolve(&slug, port).await {
+                Ok(addr) => Ok((state.backend_scheme.clone(), addr.to_string())),
+                Err(err) => {
+                    warn!(%slug, port, %err, "failed to resolve backend");
+                    Err(UpstreamFailure::NotRunning)
+                }
+            };
+        }
+    };
+    Ok(match port {
+        Some(port) => (scheme, format!("{}:{}", host, port)),
+        None => (scheme, host),
+    })
 }
 
 #[derive(Clone)]
 struct ProxyBehavior {
-    skip_service_worker: bool,
-    add_cors: bool,
-    strip_cors_headers: bool,
+    route_class: RouteClass,
+    html_injections: Arc<HtmlInjections>,
+    service_worker: Arc<ServiceWorker>,
+    /// Add CORS headers allowing this origin.
+    cors_origin: Option<HeaderValue>,
+    rules: RuleOutcome,
     workspace_header: Option<String>,
     port_header: Option<String>,
-    frame_ancestors: Option<&'static str>,
 }
 
 async fn forward_request(
     state: Arc<AppState>,
     mut req: Request<Body>,
     target: Target,
+    started: Instant,
     behavior: ProxyBehavior,
 ) -> Response<Body> {
     if is_upgrade_request(&req) {
         return handle_websocket(state, req, target, behavior).await;
     }
 
-    let (scheme, host, port_opt) = match target {
-        Target::BackendPort(port) => (
-            state.backend_scheme.clone(),
-            state.backend_host.clone(),
-            Some(port),
-        ),
-        Target::Absolute { scheme, host, port } => (scheme, host, port),
+    let server_timing = state.server_timing;
+    let mut timing = ServerTiming::new(started);
+    let accept = req.headers().get(header::ACCEPT).cloned();
+    let preview_host = extract_host(&req).unwrap_or_default();
+    let upstream_failed = |failure| {
+        state.metrics.upstream_error(behavior.route_class.into());
+        state
+            .error_pages
+            .response(accept.as_ref(), &preview_host, failure)
+    };
+
+    let cache_key = match &state.cache {
+        Some(_) => extract_host(&req).and_then(|host| ResponseCache::key(&req, &host)),
+        None => None,
     };
+    if let (Some(cache), Some(key)) = (&state.cache, &cache_key)
+        && let Some(cached) = cache.get(key).await
+    {
+        timing.mark_resolved();
+        let mut response = transform_response(cached.to_response(), behavior);
+        response
+            .headers_mut()
+            .insert("x-cmux-cache", HeaderValue::from_static("HIT"));
+        if server_timing {
+            timing.apply(response.headers_mut());
+        }
+        return response;
+    }
 
-    let authority = match port_opt {
-        Some(port) => format!("{}:{}", host, port),
-        None => host,
+    let (scheme, authority) = match resolve_target(&state, target).await {
+        Ok(resolved) => resolved,
+        Err(failure) => return upstream_failed(failure),
     };
+    timing.mark_resolved();
 
     let path_and_query = req
         .uri()
@@ -364,13 +975,17 @@ async fn forward_request(
         };
 
     *req.uri_mut() = target_uri;
+    // Requests from HTTP/2 browsers go upstream as HTTP/1.1 unless the client picks HTTP/2.
+    if req.version() == Version::HTTP_2 {
+        *req.version_mut() = Version::HTTP_11;
+    }
 
     if let Ok(value) = HeaderValue::from_str(&authority) {
         req.headers_mut().insert(header::HOST, value);
     }
 
-    req.headers_mut()
-        .insert("X-Cmux-Proxied", HeaderValue::from_static("true"));
+    state.loop_guard.mark(req.headers_mut());
+    behavior.rules.apply_request(req.headers_mut());
 
     if let Some(port_hdr) = behavior.port_header.as_ref() {
         if let Ok(value) = HeaderValue::from_str(port_hdr) {
@@ -399,9 +1014,44 @@ async fn forward_request(
         None
     };
 
-    let response = match state.client.request(req).await {
+    let body_limit = state.limits.limit_body(&mut req);
+    let response = match timing
+        .time_backend(state.upstream_client(req.uri()).request(req))
+        .await
+    {
         Ok(resp) => resp,
-        Err(_) => return text_response(StatusCode::BAD_GATEWAY, "Upstream fetch failed"),
+        Err(_) if body_limit.exceeded() => {
+            return text_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large");
+        }
+        Err(_) => return upstream_failed(UpstreamFailure::Unreachable),
+    };
+    if is_html(response.headers()) {
+        state.metrics.html_injection(behavior.route_class.into());
+    }
+    let response = match (&state.cache, cache_key) {
+        (Some(cache), Some(key)) => match cache.lifetime(&response) {
+            Some(lifetime) => {
+                let (mut parts, upstream) = response.into_parts();
+                let Ok(bytes) = body::to_bytes(upstream).await else {
+                    return upstream_failed(UpstreamFailure::Unreachable);
+                };
+                cache
+                    .store(
+                        key,
+                        parts.status,
+                        parts.headers.clone(),
+                        bytes.clone(),
+                        lifetime,
+                    )
+                    .await;
+                parts
+                    .headers
+                    .insert("x-cmux-cache", HeaderValue::from_static("MISS"));
+                Response::from_parts(parts, Body::from(bytes))
+            }
+            None => response,
+        },
+        _ => response,
     };
 
     if original_method == Method::HEAD
@@ -409,17 +1059,18 @@ async fn forward_request(
             response.status(),
             StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
         )
+        && let Some(context) = head_fallback_context
+        && let Some(fallback) =
+            handle_head_method_not_allowed(state, context, behavior.clone()).await
     {
-        if let Some(context) = head_fallback_context {
-            if let Some(fallback) =
-                handle_head_method_not_allowed(state, context, behavior.clone()).await
-            {
-                return fallback;
-            }
-        }
+        return fallback;
     }
 
-    transform_response(response, behavior).await
+    let mut response = transform_response(response, behavior);
+    if server_timing {
+        timing.apply(response.headers_mut());
+    }
+    response
 }
 
 /// Captures enough of the original HEAD request to retry with GET when the
@@ -442,14 +1093,18 @@ async fn handle_head_method_not_allowed(
         .body(Body::empty())
         .ok()?;
 
+    let cors_origin = state.cors.allow_origin(&context.headers);
     *get_request.headers_mut() = context.headers;
     get_request.headers_mut().remove(header::CONTENT_LENGTH);
 
-    match state.client.request(get_request).await {
-        Ok(resp) => match transform_head_response_from_get(resp, behavior).await {
-            Ok(head_response) => Some(head_response),
-            Err(_) => None,
-        },
+    match state
+        .upstream_client(get_request.uri())
+        .request(get_request)
+        .await
+    {
+        Ok(resp) => transform_head_response_from_get(resp, behavior, cors_origin)
+            .await
+            .ok(),
         Err(_) => None,
     }
 }
@@ -457,8 +1112,9 @@ async fn handle_head_method_not_allowed(
 async fn transform_head_response_from_get(
     response: Response<Body>,
     behavior: ProxyBehavior,
+    cors_origin: Option<HeaderValue>,
 ) -> Result<Response<Body>, hyper::Error> {
-    let transformed_response = transform_response(response, behavior.clone()).await;
+    let transformed_response = transform_response(response, behavior.clone());
     let status = transformed_response.status();
     let version = transformed_response.version();
     let headers = transformed_response.headers().clone();
@@ -474,7 +1130,7 @@ async fn transform_head_response_from_get(
         &headers,
         &behavior,
         Some(body_len),
-        true,
+        cors_origin.as_ref(),
     ))
 }
 
@@ -484,7 +1140,7 @@ fn build_head_response(
     headers: &HeaderMap,
     behavior: &ProxyBehavior,
     body_len: Option<usize>,
-    force_cors_headers: bool,
+    forced_cors_origin: Option<&HeaderValue>,
 ) -> Response<Body> {
     let mut builder = Response::builder().status(status).version(version);
     // Start from the upstream headers, then strip only the payload metadata we
@@ -493,23 +1149,24 @@ fn build_head_response(
     new_headers.remove(header::CONTENT_LENGTH);
     new_headers.remove(header::TRANSFER_ENCODING);
     strip_csp_headers(&mut new_headers);
-    if behavior.strip_cors_headers {
+    if behavior.rules.strip_cors {
         strip_cors_headers(&mut new_headers);
-    } else if behavior.add_cors {
-        add_cors_headers(&mut new_headers);
+    } else if let Some(origin) = &behavior.cors_origin {
+        add_cors_headers(&mut new_headers, origin);
     }
-    if force_cors_headers && !behavior.strip_cors_headers {
-        add_cors_headers(&mut new_headers);
+    if !behavior.rules.strip_cors
+        && let Some(origin) = forced_cors_origin
+    {
+        add_cors_headers(&mut new_headers, origin);
     }
-    if let Some(frame_ancestors) = behavior.frame_ancestors {
-        if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
-            new_headers.insert("content-security-policy", value);
-        }
+    if let Some(csp) = &behavior.rules.content_security_policy {
+        new_headers.insert("content-security-policy", csp.clone());
     }
-    if let Some(len) = body_len {
-        if let Ok(value) = HeaderValue::from_str(&len.to_string()) {
-            new_headers.insert(header::CONTENT_LENGTH, value);
-        }
+    behavior.rules.apply_response(&mut new_headers);
+    if let Some(len) = body_len
+        && let Ok(value) = HeaderValue::from_str(&len.to_string())
+    {
+        new_headers.insert(header::CONTENT_LENGTH, value);
     }
     let headers_mut = builder.headers_mut().unwrap();
     for (name, value) in new_headers.iter() {
@@ -520,22 +1177,21 @@ fn build_head_response(
 
 async fn handle_websocket(
     state: Arc<AppState>,
-    req: Request<Body>,
+    mut req: Request<Body>,
     target: Target,
     behavior: ProxyBehavior,
 ) -> Response<Body> {
-    let (scheme, host, port_opt) = match target {
-        Target::BackendPort(port) => (
-            state.backend_scheme.clone(),
-            state.backend_host.clone(),
-            Some(port),
-        ),
-        Target::Absolute { scheme, host, port } => (scheme, host, port),
+    let accept = req.headers().get(header::ACCEPT).cloned();
+    let preview_host = extract_host(&req).unwrap_or_default();
+    let upstream_failed = |failure| {
+        state.metrics.upstream_error(behavior.route_class.into());
+        state
+            .error_pages
+            .response(accept.as_ref(), &preview_host, failure)
     };
-
-    let authority = match port_opt {
-        Some(port) => format!("{}:{}", host, port),
-        None => host,
+    let (scheme, authority) = match resolve_target(&state, target).await {
+        Ok(resolved) => resolved,
+        Err(failure) => return upstream_failed(failure),
     };
 
     let path_and_query = req
@@ -543,61 +1199,150 @@ async fn handle_websocket(
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("/");
-    let ws_scheme = match scheme.as_str() {
-        "https" | "wss" => "wss",
-        _ => "ws",
-    };
-    let backend_url = format!("{}://{}{}", ws_scheme, authority, path_and_query);
+    let backend_uri =
+        match format!("{}://{}{}", scheme.as_str(), authority, path_and_query).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => {
+                return text_response(StatusCode::BAD_GATEWAY, "Failed to build upstream URI");
+            }
+        };
 
-    let headers_to_forward = collect_forward_headers(req.headers(), &behavior);
+    let mut headers_to_forward = collect_forward_headers(
+        req.headers(),
+        &behavior,
+        &state.base_domains,
+        &state.loop_guard,
+    );
+    if let Ok(value) = HeaderValue::from_str(&authority) {
+        headers_to_forward.insert(header::HOST, value);
+    }
 
-    match hyper_tungstenite::upgrade(req, None) {
-        Ok((response, websocket)) => {
-            tokio::spawn(async move {
-                if let Err(err) = pump_websocket(websocket, backend_url, headers_to_forward).await {
-                    error!(%err, "websocket proxy error");
-                }
-            });
-            response
-        }
+    let Some(permit) = state.ws_limiter.acquire(&preview_host) else {
+        warn!(host = %preview_host, "refusing websocket upgrade: per-host cap reached");
+        state.metrics.websocket_rejected();
+        let mut response = json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({
+                "error": "too_many_websockets",
+                "message": "Too many open WebSocket connections to this preview; retry shortly.",
+                "limit": state.ws_limiter.cap(),
+                "retry_after": WS_RETRY_AFTER_SECS,
+            }),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(WS_RETRY_AFTER_SECS));
+        return response;
+    };
+
+    // The handshake goes to the backend as-is, so extensions such as permessage-deflate are
+    // negotiated between the browser and the backend and frames are relayed untouched.
+    let mut backend_req = Request::builder()
+        .method(Method::GET)
+        .uri(backend_uri)
+        .body(Body::empty())
+        .unwrap();
+    *backend_req.headers_mut() = headers_to_forward;
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let mut backend_response = match state.client.request(backend_req).await {
+        Ok(response) => response,
         Err(err) => {
-            error!(%err, "failed to upgrade connection");
-            text_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to upgrade WebSocket connection",
-            )
+            error!(%err, "websocket upstream request failed");
+            return upstream_failed(UpstreamFailure::Unreachable);
         }
+    };
+    if backend_response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return backend_response;
     }
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .body(Body::empty())
+        .unwrap();
+    *response.headers_mut() = backend_response.headers().clone();
+    let backend_upgrade = hyper::upgrade::on(&mut backend_response);
+
+    let route = behavior.route_class.into();
+    state.metrics.websocket_upgrade(route);
+    let metrics = state.metrics.clone();
+    let limits = state.ws_frame_limits;
+    state.drain.spawn(async move {
+        // Held for the tunnel's lifetime so the slot frees once the relay ends.
+        let _permit = permit;
+        let (client, backend) = match tokio::try_join!(client_upgrade, backend_upgrade) {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                error!(%err, "failed to upgrade connection");
+                metrics.websocket_closed(false);
+                return;
+            }
+        };
+        let relayed = ws_relay::relay(client, backend, limits, |direction, bytes| {
+            metrics.websocket_bytes(route, direction, bytes)
+        })
+        .await;
+        match relayed {
+            Ok(stats) => {
+                if stats.too_big {
+                    warn!(host = %preview_host, "closed websocket: frame or message too big");
+                }
+                debug!(
+                    host = %preview_host,
+                    to_backend = stats.to_backend,
+                    to_client = stats.to_client,
+                    "websocket closed"
+                );
+                metrics.websocket_closed(stats.too_big);
+            }
+            Err(err) => {
+                error!(%err, "websocket proxy error");
+                metrics.websocket_closed(false);
+            }
+        }
+    });
+    response
 }
 
 fn collect_forward_headers(
     original: &http::HeaderMap,
     behavior: &ProxyBehavior,
+    base_domains: &[String],
+    loop_guard: &LoopGuard,
 ) -> http::HeaderMap {
     let mut headers = http::HeaderMap::new();
-    if let Some(port) = &behavior.port_header {
-        if let Ok(value) = HeaderValue::from_str(port) {
-            headers.insert("X-Cmux-Port-Internal", value);
-        }
+    if let Some(port) = &behavior.port_header
+        && let Ok(value) = HeaderValue::from_str(port)
+    {
+        headers.insert("X-Cmux-Port-Internal", value);
     }
     if let Some(workspace) = behavior.workspace_header.as_ref() {
         if let Ok(value) = HeaderValue::from_str(workspace) {
             headers.insert("X-Cmux-Workspace-Internal", value);
         }
-    } else if let Some(workspace) = derive_workspace_scope_from_headers(original) {
-        if let Ok(value) = HeaderValue::from_str(&workspace) {
-            headers.insert("X-Cmux-Workspace-Internal", value);
-        }
+    } else if let Some(workspace) = derive_workspace_scope_from_headers(original, base_domains)
+        && let Ok(value) = HeaderValue::from_str(&workspace)
+    {
+        headers.insert("X-Cmux-Workspace-Internal", value);
     }
-    headers.insert("X-Cmux-Proxied", HeaderValue::from_static("true"));
+    loop_guard.mark_from(original, &mut headers);
 
     if let Some(value) = original.get(header::USER_AGENT) {
         headers.insert(header::USER_AGENT, value.clone());
     }
+    for name in WEBSOCKET_HANDSHAKE_HEADERS {
+        for value in original.get_all(*name) {
+            headers.append(*name, value.clone());
+        }
+    }
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    behavior.rules.apply_request(&mut headers);
     headers
 }
 
-fn derive_workspace_scope_from_headers(headers: &HeaderMap) -> Option<String> {
+fn derive_workspace_scope_from_headers(
+    headers: &HeaderMap,
+    base_domains: &[String],
+) -> Option<String> {
     let host = headers
         .get("x-forwarded-host")
         .and_then(|value| value.to_str().ok())
@@ -608,7 +1353,7 @@ fn derive_workspace_scope_from_headers(headers: &HeaderMap) -> Option<String> {
         })
         .map(normalize_host)?;
 
-    let (subdomain_opt, _) = parse_cmux_host(&host)?;
+    let (subdomain_opt, _) = parse_cmux_host(&host, base_domains)?;
     let subdomain = subdomain_opt?;
     scope_from_cmux_subdomain(&subdomain)
 }
@@ -621,9 +1366,7 @@ fn scope_from_cmux_subdomain(subdomain: &str) -> Option<String> {
     }
 
     let port_segment = segments.last()?;
-    if port_segment.parse::<u16>().ok().is_none() {
-        return None;
-    }
+    port_segment.parse::<u16>().ok()?;
 
     let scope_segments = &segments[1..segments.len() - 1];
     if scope_segments.is_empty()
@@ -635,130 +1378,46 @@ fn scope_from_cmux_subdomain(subdomain: &str) -> Option<String> {
     }
 }
 
-async fn pump_websocket(
-    websocket: HyperWebsocket,
-    backend_url: String,
-    headers: http::HeaderMap,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_ws = websocket.await?;
-
-    let mut request = backend_url.into_client_request()?;
-    if let Some(host) = request.uri().host() {
-        let host_header = if let Some(port) = request.uri().port_u16() {
-            format!("{}:{}", host, port)
-        } else {
-            host.to_string()
-        };
-        request
-            .headers_mut()
-            .insert(header::HOST, HeaderValue::from_str(&host_header)?);
-    }
-
-    for (name, value) in headers.iter() {
-        request.headers_mut().insert(name.clone(), value.clone());
-    }
-
-    let (backend_ws, _) = connect_async(request).await?;
-
-    let (mut client_sink, mut client_stream) = client_ws.split();
-    let (mut backend_sink, mut backend_stream) = backend_ws.split();
-
-    let to_backend = async {
-        while let Some(msg) = client_stream.next().await {
-            match msg {
-                Ok(message) => {
-                    backend_sink.send(message).await?;
-                }
-                Err(err) => {
-                    backend_sink.close().await?;
-                    return Err(err);
-                }
-            }
-        }
-        backend_sink.close().await?;
-        Ok::<(), hyper_tungstenite::tungstenite::Error>(())
-    };
-
-    let to_client = async {
-        while let Some(msg) = backend_stream.next().await {
-            match msg {
-                Ok(message) => {
-                    client_sink.send(message).await?;
-                }
-                Err(err) => {
-                    client_sink.close().await?;
-                    return Err(err);
-                }
-            }
-        }
-        client_sink.close().await?;
-        Ok::<(), hyper_tungstenite::tungstenite::Error>(())
-    };
-
-    tokio::select! {
-        res = to_backend => { res?; }
-        res = to_client => { res?; }
-    }
-
-    Ok(())
-}
-
-async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -> Response<Body> {
+fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -> Response<Body> {
     let status = response.status();
     let version = response.version();
     let headers = response.headers().clone();
 
-    let content_type = headers
-        .get(header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-
-    if content_type.contains("text/html") {
-        match body::to_bytes(response.into_body()).await {
-            Ok(bytes) => match rewrite_html(bytes, behavior.skip_service_worker) {
-                Ok(body) => {
-                    let mut builder = Response::builder().status(status).version(version);
-                    let mut new_headers =
-                        sanitize_headers(&headers, /* strip_payload_headers */ true);
-                    strip_csp_headers(&mut new_headers);
-                    if behavior.strip_cors_headers {
-                        strip_cors_headers(&mut new_headers);
-                    } else if behavior.add_cors {
-                        add_cors_headers(&mut new_headers);
-                    }
-                    if let Some(frame_ancestors) = behavior.frame_ancestors {
-                        if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
-                            new_headers.insert("content-security-policy", value);
-                        }
-                    }
-                    new_headers.insert(
-                        header::CONTENT_LENGTH,
-                        HeaderValue::from_str(&body.len().to_string()).unwrap(),
-                    );
-                    let headers_mut = builder.headers_mut().unwrap();
-                    for (name, value) in new_headers.iter() {
-                        headers_mut.insert(name, value.clone());
-                    }
-                    builder.body(Body::from(body)).unwrap()
-                }
-                Err(_) => text_response(StatusCode::INTERNAL_SERVER_ERROR, "HTML rewrite failed"),
-            },
-            Err(_) => text_response(StatusCode::BAD_GATEWAY, "Failed to read upstream body"),
+    if is_html(&headers) {
+        let mut builder = Response::builder().status(status).version(version);
+        // The rewritten body has a different length, so drop the upstream payload headers and
+        // let it go out chunked.
+        let mut new_headers = sanitize_headers(&headers, /* strip_payload_headers */ true);
+        strip_csp_headers(&mut new_headers);
+        if behavior.rules.strip_cors {
+            strip_cors_headers(&mut new_headers);
+        } else if let Some(origin) = &behavior.cors_origin {
+            add_cors_headers(&mut new_headers, origin);
+        }
+        if let Some(csp) = &behavior.rules.content_security_policy {
+            new_headers.insert("content-security-policy", csp.clone());
+        }
+        behavior.rules.apply_response(&mut new_headers);
+        let headers_mut = builder.headers_mut().unwrap();
+        for (name, value) in new_headers.iter() {
+            headers_mut.insert(name, value.clone());
         }
+        builder
+            .body(rewrite_html(response.into_body(), behavior))
+            .unwrap()
     } else {
         let mut builder = Response::builder().status(status).version(version);
         let mut new_headers = sanitize_headers(&headers, /* strip_payload_headers */ false);
         strip_csp_headers(&mut new_headers);
-        if behavior.strip_cors_headers {
+        if behavior.rules.strip_cors {
             strip_cors_headers(&mut new_headers);
-        } else if behavior.add_cors {
-            add_cors_headers(&mut new_headers);
+        } else if let Some(origin) = &behavior.cors_origin {
+            add_cors_headers(&mut new_headers, origin);
         }
-        if let Some(frame_ancestors) = behavior.frame_ancestors {
-            if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
-                new_headers.insert("content-security-policy", value);
-            }
+        if let Some(csp) = &behavior.rules.content_security_policy {
+            new_headers.insert("content-security-policy", csp.clone());
         }
+        behavior.rules.apply_response(&mut new_headers);
         let headers_mut = builder.headers_mut().unwrap();
         for (name, value) in new_headers.iter() {
             headers_mut.insert(name, value.clone());
@@ -767,6 +1426,13 @@ async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -
     }
 }
 
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("text/html"))
+}
+
 fn sanitize_headers(headers: &HeaderMap, strip_payload_headers: bool) -> HeaderMap {
     let ignored_payload_headers = [
         "content-length",
@@ -794,8 +1460,11 @@ fn strip_csp_headers(headers: &mut HeaderMap) {
     headers.remove("frame-options");
 }
 
-fn add_cors_headers(headers: &mut HeaderMap) {
-    headers.insert("access-control-allow-origin", HeaderValue::from_static("*"));
+fn add_cors_headers(headers: &mut HeaderMap, origin: &HeaderValue) {
+    headers.insert("access-control-allow-origin", origin.clone());
+    if origin != "*" {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
     headers.insert(
         "access-control-allow-methods",
         HeaderValue::from_static("GET, POST, PUT, DELETE, PATCH, OPTIONS, HEAD"),
@@ -831,39 +1500,106 @@ fn strip_cors_headers(headers: &mut HeaderMap) {
     }
 }
 
-fn rewrite_html(
-    bytes: Bytes,
-    skip_service_worker: bool,
-) -> Result<Vec<u8>, lol_html::errors::RewritingError> {
-    let mut output = Vec::with_capacity(bytes.len());
+/// Injects the configured scripts into an HTML body as it streams through, so large and
+/// streamed (SSR) pages are neither buffered nor delayed. `lol_html`'s rewriter is not `Send`,
+/// so it runs on a blocking thread that pulls upstream chunks and pushes rewritten output.
+fn rewrite_html(upstream: Body, behavior: ProxyBehavior) -> Body {
+    let (mut tx, body) = Body::channel();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = stream_rewrite(&runtime, upstream, &mut tx, &behavior) {
+            warn!(%err, "aborting rewritten HTML response");
+            tx.abort();
+        }
+    });
+    body
+}
+
+fn stream_rewrite(
+    runtime: &tokio::runtime::Handle,
+    mut upstream: Body,
+    tx: &mut body::Sender,
+    behavior: &ProxyBehavior,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let output = RefCell::new(Vec::new());
+    let class = behavior.route_class;
+    let injections = &*behavior.html_injections;
+    let service_worker = behavior.service_worker.registration();
+    let inject_location = behavior
+        .rules
+        .inject_location
+        .unwrap_or_else(|| injections.location.contains(class));
+    let inject_service_worker = behavior
+        .rules
+        .inject_service_worker
+        .unwrap_or_else(|| injections.service_worker.contains(class));
 
     let mut rewriter = HtmlRewriter::new(
         Settings {
             element_content_handlers: vec![
                 element!("head", move |el| {
-                    el.prepend(HEAD_SCRIPT, ContentType::Html);
-                    if !skip_service_worker {
-                        el.prepend(SERVICE_WORKER_SCRIPT, ContentType::Html);
+                    // Prepending puts each insert first, so go in reverse to keep the built-in
+                    // scripts ahead of configured snippets and the snippets in order.
+                    for html in injections
+                        .snippets_at(class, InjectPosition::HeadStart)
+                        .rev()
+                    {
+                        el.prepend(html, ContentType::Html);
+                    }
+                    if inject_location {
+                        el.prepend(HEAD_SCRIPT, ContentType::Html);
+                    }
+                    if inject_service_worker {
+                        el.prepend(service_worker, ContentType::Html);
+                    }
+                    for html in injections.snippets_at(class, InjectPosition::HeadEnd) {
+                        el.append(html, ContentType::Html);
+                    }
+                    Ok(())
+                }),
+                element!("body", move |el| {
+                    for html in injections
+                        .snippets_at(class, InjectPosition::BodyStart)
+                        .rev()
+                    {
+                        el.prepend(html, ContentType::Html);
+                    }
+                    for html in injections.snippets_at(class, InjectPosition::BodyEnd) {
+                        el.append(html, ContentType::Html);
                     }
                     Ok(())
                 }),
                 element!("meta", |el| {
-                    if let Some(value) = el.get_attribute("http-equiv") {
-                        if value.eq_ignore_ascii_case("content-security-policy") {
-                            el.remove();
-                        }
+                    if let Some(value) = el.get_attribute("http-equiv")
+                        && value.eq_ignore_ascii_case("content-security-policy")
+                    {
+                        el.remove();
                     }
                     Ok(())
                 }),
             ],
             ..Settings::default()
         },
-        |c: &[u8]| output.extend_from_slice(c),
+        |c: &[u8]| output.borrow_mut().extend_from_slice(c),
     );
 
-    rewriter.write(&bytes)?;
+    let mut flush = || {
+        let chunk = output.take();
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        runtime
+            .block_on(tx.send_data(Bytes::from(chunk)))
+            .map_err(|_| "client went away")
+    };
+
+    while let Some(chunk) = runtime.block_on(upstream.data()) {
+        rewriter.write(&chunk?)?;
+        flush()?;
+    }
     rewriter.end()?;
-    Ok(output)
+    flush()?;
+    Ok(())
 }
 
 fn parse_route(subdomain: String) -> Route {
@@ -894,11 +1630,7 @@ fn parse_route(subdomain: String) -> Route {
             ));
         }
 
-        return Route::Port(PortRoute {
-            port,
-            morph_id,
-            skip_service_worker: port == 39_378,
-        });
+        return Route::Port(PortRoute { port, morph_id });
     }
 
     if let Some(rest) = subdomain.strip_prefix("cmux-") {
@@ -997,7 +1729,6 @@ fn parse_route(subdomain: String) -> Route {
 struct PortRoute {
     port: u16,
     morph_id: String,
-    skip_service_worker: bool,
 }
 
 struct CmuxRoute {
@@ -1019,17 +1750,11 @@ enum Route {
     Invalid(Response<Body>),
 }
 
-fn is_loop_header(req: &Request<Body>) -> bool {
-    req.headers()
-        .get("X-Cmux-Proxied")
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.eq_ignore_ascii_case("true"))
-        .unwrap_or(false)
-}
-
-fn cors_response(status: StatusCode) -> Response<Body> {
+fn cors_response(status: StatusCode, origin: Option<&HeaderValue>) -> Response<Body> {
     let mut headers = HeaderMap::new();
-    add_cors_headers(&mut headers);
+    if let Some(origin) = origin {
+        add_cors_headers(&mut headers, origin);
+    }
     let mut builder = Response::builder().status(status);
     let headers_mut = builder.headers_mut().unwrap();
     for (name, value) in headers.iter() {
@@ -1071,115 +1796,39 @@ fn extract_host(req: &Request<Body>) -> Option<String> {
 
 fn normalize_host(value: &str) -> String {
     let mut host = value.to_ascii_lowercase();
-    if let Some(idx) = host.rfind(':') {
-        if host[idx + 1..].chars().all(|c| c.is_ascii_digit()) {
-            host.truncate(idx);
-        }
+    if let Some(idx) = host.rfind(':')
+        && host[idx + 1..].chars().all(|c| c.is_ascii_digit())
+    {
+        host.truncate(idx);
     }
     host
 }
 
-fn parse_cmux_host(host: &str) -> Option<(Option<String>, String)> {
-    if host == "cmux.sh" {
-        return Some((None, "cmux.sh".to_string()));
-    }
-    if let Some(prefix) = host.strip_suffix(".cmux.sh") {
-        let subdomain = if prefix.is_empty() {
-            None
-        } else {
-            Some(prefix.to_string())
-        };
-        return Some((subdomain, "cmux.sh".to_string()));
-    }
-
-    if host == "cmux.localhost" {
-        return Some((None, "cmux.localhost".to_string()));
-    }
-    if let Some(prefix) = host.strip_suffix(".cmux.localhost") {
-        let subdomain = if prefix.is_empty() {
-            None
-        } else {
-            Some(prefix.to_string())
-        };
-        return Some((subdomain, "cmux.localhost".to_string()));
-    }
+/// Splits `host` into its subdomain (if any) and the base domain it belongs to. The longest
+/// matching base domain wins, so `preview.example.co.uk` can sit alongside `example.co.uk`.
+fn parse_cmux_host(host: &str, base_domains: &[String]) -> Option<(Option<String>, String)> {
+    base_domains.iter().find_map(|domain| {
+        if host == domain {
+            return Some((None, domain.clone()));
+        }
+        let prefix = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+        let subdomain = (!prefix.is_empty()).then(|| prefix.to_string());
+        Some((subdomain, domain.clone()))
+    })
+}
 
-    if host == "cmux.app" {
-        return Some((None, "cmux.app".to_string()));
-    }
-    if let Some(prefix) = host.strip_suffix(".cmux.app") {
-        let subdomain = if prefix.is_empty() {
-            None
-        } else {
-            Some(prefix.to_string())
-        };
-        return Some((subdomain, "cmux.app".to_string()));
-    }
-    None
+/// Lowercases, trims dots and orders base domains longest first for [`parse_cmux_host`].
+fn normalize_base_domains(domains: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = domains
+        .iter()
+        .map(|d| d.trim().trim_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect();
+    out.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    out.dedup();
+    out
 }
 
 const HEAD_SCRIPT: &str = r#"<script data-cmux-injected="true">
 window.__cmuxLocation = window.location;
 </script>"#;
-
-const SERVICE_WORKER_SCRIPT: &str = r#"<script data-cmux-injected="true">
-// __CMUX_NO_REWRITE__
-if ('serviceWorker' in navigator) {
-  navigator.serviceWorker.register('/proxy-sw.js', { scope: '/' }).catch(console.error);
-}
-</script>"#;
-
-const SERVICE_WORKER_JS: &str = r#"self.addEventListener('install', (event) => {
-  self.skipWaiting();
-});
-
-self.addEventListener('activate', (event) => {
-  event.waitUntil(clients.claim());
-});
-
-function isLoopbackHostname(hostname) {
-  if (!hostname) {
-    return false;
-  }
-  if (hostname === 'localhost' || hostname === '0.0.0.0') {
-    return true;
-  }
-  if (hostname === '::1' || hostname === '[::1]' || hostname === '::') {
-    return true;
-  }
-  return /^127(?:\.\d{1,3}){3}$/.test(hostname);
-}
-
-self.addEventListener('fetch', (event) => {
-  const url = new URL(event.request.url);
-  if (isLoopbackHostname(url.hostname) && url.port) {
-    const currentHost = self.location.hostname;
-    const firstDot = currentHost.indexOf('.');
-    if (firstDot === -1) {
-      return;
-    }
-    const firstLabel = currentHost.slice(0, firstDot);
-    const morphIdMatch = firstLabel.match(/^port-\d+-(.*)$/);
-    if (!morphIdMatch) {
-      return;
-    }
-    const domain = currentHost.slice(firstDot + 1);
-    if (!domain) {
-      return;
-    }
-    const morphId = morphIdMatch[1];
-    const redirectUrl = `https://port-${url.port}-${morphId}.${domain}${url.pathname}${url.search}`;
-    event.respondWith(fetch(redirectUrl, { redirect: 'follow' }));
-    return;
-  }
-});
-"#;
-
-fn service_worker_response() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/javascript")
-        .header(header::CACHE_CONTROL, "no-cache")
-        .body(Body::from(SERVICE_WORKER_JS))
-        .unwrap()
-}
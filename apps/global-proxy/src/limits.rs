@@ -0,0 +1,331 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use http::{Request, StatusCode, header};
+use hyper::{Body, body::HttpBody, server::conn::Http};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+use tracing::debug;
+
+/// hyper refuses read buffers smaller than this.
+const MIN_HTTP1_BUFFER: usize = 8192;
+/// What an HTTP/2 connection starts with; such connections are left to HTTP/2's own limits.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const REQUEST_TIMEOUT: &[u8] = b"HTTP/1.1 408 Request Timeout\r\n\
+connection: close\r\n\
+content-type: text/plain; charset=utf-8\r\n\
+content-length: 15\r\n\
+\r\n\
+Request Timeout";
+
+/// Protects backends from oversized and slow clients. Dev servers behind the proxy have no such
+/// limits of their own.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    /// Request line plus headers, in bytes. Larger requests get `431`.
+    pub max_header_bytes: usize,
+    /// Request bodies, in bytes. Larger bodies get `413`. `None` allows any size.
+    pub max_body_bytes: Option<u64>,
+    /// How long an HTTP/1 client has to finish sending a request head, from connecting or from
+    /// the first byte of a keep-alive request. Slower clients get `408` and are disconnected.
+    pub header_read_timeout: Option<Duration>,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: None,
+            header_read_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Sizes hyper's buffers so oversized heads are refused before they are fully read.
+    pub(crate) fn configure(&self, http: &mut Http) {
+        http.http1_max_buf_size(self.max_header_bytes.max(MIN_HTTP1_BUFFER));
+        // HTTP/2 counts 32 bytes of overhead per field; the exact limit is checked per request.
+        http.http2_max_header_list_size(
+            u32::try_from(self.max_header_bytes.saturating_mul(2)).unwrap_or(u32::MAX),
+        );
+    }
+
+    /// The status to refuse `req` with when its head or declared body is too large.
+    pub(crate) fn check(&self, req: &Request<Body>) -> Result<(), StatusCode> {
+        if head_size(req) > self.max_header_bytes {
+            return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        }
+        if let Some(max) = self.max_body_bytes
+            && req.body().size_hint().lower() > max
+        {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Ok(())
+    }
+
+    /// Cuts off `req`'s body once it passes the limit. Bodies with a `Content-Length` were
+    /// already checked and are framed by it, so only streamed ones are wrapped.
+    pub(crate) fn limit_body(&self, req: &mut Request<Body>) -> BodyLimit {
+        let Some(max) = self.max_body_bytes else {
+            return BodyLimit(None);
+        };
+        if req.body().is_end_stream() || req.headers().contains_key(header::CONTENT_LENGTH) {
+            return BodyLimit(None);
+        }
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let body = std::mem::take(req.body_mut());
+        *req.body_mut() = Body::wrap_stream(LimitedBody {
+            inner: body,
+            remaining: max,
+            exceeded: exceeded.clone(),
+        });
+        BodyLimit(Some(exceeded))
+    }
+}
+
+/// The request line and headers as they would be sent over HTTP/1.
+fn head_size(req: &Request<Body>) -> usize {
+    let line = req.method().as_str().len() + req.uri().to_string().len() + "HTTP/1.1".len() + 4;
+    req.headers().iter().fold(line, |size, (name, value)| {
+        size + name.as_str().len() + value.len() + 4
+    })
+}
+
+/// Whether a body wrapped by [`RequestLimits::limit_body`] went over the limit.
+pub(crate) struct BodyLimit(Option<Arc<AtomicBool>>);
+
+impl BodyLimit {
+    pub(crate) fn exceeded(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|exceeded| exceeded.load(Ordering::Relaxed))
+    }
+}
+
+struct LimitedBody {
+    inner: Body,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Stream for LimitedBody {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(Pin::new(&mut self.inner).poll_data(cx)) {
+            Some(Ok(chunk)) => match self.remaining.checked_sub(chunk.len() as u64) {
+                Some(remaining) => {
+                    self.remaining = remaining;
+                    Poll::Ready(Some(Ok(chunk)))
+                }
+                None => {
+                    self.exceeded.store(true, Ordering::Relaxed);
+                    Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "request body too large",
+                    ))))
+                }
+            },
+            Some(Err(err)) => Poll::Ready(Some(Err(io::Error::other(err)))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Requests being handled on one connection, so [`HeadTimeout`] only times the client while
+/// it is the one expected to send.
+#[derive(Default)]
+pub(crate) struct ConnectionActivity {
+    in_flight: AtomicUsize,
+    upgraded: AtomicBool,
+}
+
+impl ConnectionActivity {
+    /// Marks a request head as received; the request counts as in flight until the guard drops.
+    pub(crate) fn begin(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    fn idle(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+    }
+}
+
+pub(crate) struct InFlight(Arc<ConnectionActivity>);
+
+impl InFlight {
+    /// The connection became a WebSocket tunnel and no longer carries request heads.
+    pub(crate) fn upgraded(&self) {
+        self.0.upgraded.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+enum HeadState {
+    /// Nothing read yet; the protocol is not known.
+    Fresh,
+    Watching,
+    /// HTTP/2, an upgraded tunnel, or no timeout configured.
+    Off,
+    /// Writing the `408`; the count is how much of it has been written.
+    Rejecting(usize),
+    Closed,
+}
+
+/// Answers `408` and closes an HTTP/1 connection whose client is too slow to send a request
+/// head. hyper's own header timeout only drops the connection without a response.
+pub(crate) struct HeadTimeout<I> {
+    io: I,
+    timeout: Duration,
+    deadline: Option<Pin<Box<Sleep>>>,
+    activity: Arc<ConnectionActivity>,
+    state: HeadState,
+}
+
+impl<I> HeadTimeout<I> {
+    pub(crate) fn new(io: I, timeout: Option<Duration>, activity: Arc<ConnectionActivity>) -> Self {
+        match timeout {
+            Some(timeout) => Self {
+                io,
+                timeout,
+                deadline: Some(Box::pin(tokio::time::sleep(timeout))),
+                activity,
+                state: HeadState::Fresh,
+            },
+            None => Self {
+                io,
+                timeout: Duration::ZERO,
+                deadline: None,
+                activity,
+                state: HeadState::Off,
+            },
+        }
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> HeadTimeout<I> {
+    /// Sends the `408`, then reports end of stream so hyper closes the connection.
+    fn poll_reject(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let HeadState::Rejecting(written) = self.state {
+            if written < REQUEST_TIMEOUT.len() {
+                match ready!(Pin::new(&mut self.io).poll_write(cx, &REQUEST_TIMEOUT[written..])) {
+                    Ok(0) | Err(_) => self.state = HeadState::Closed,
+                    Ok(n) => self.state = HeadState::Rejecting(written + n),
+                }
+            } else {
+                let _ = ready!(Pin::new(&mut self.io).poll_shutdown(cx));
+                self.state = HeadState::Closed;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncRead for HeadTimeout<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                HeadState::Off => return Pin::new(&mut this.io).poll_read(cx, buf),
+                HeadState::Rejecting(_) | HeadState::Closed => return this.poll_reject(cx),
+                HeadState::Fresh | HeadState::Watching => {}
+            }
+            if this.activity.upgraded.load(Ordering::SeqCst) {
+                this.state = HeadState::Off;
+                this.deadline = None;
+                continue;
+            }
+
+            let filled = buf.filled().len();
+            match Pin::new(&mut this.io).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) => {
+                    let read = &buf.filled()[filled..];
+                    if read.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                    if let HeadState::Fresh = this.state {
+                        let len = read.len().min(HTTP2_PREFACE.len());
+                        if read[..len] != HTTP2_PREFACE[..len] {
+                            this.state = HeadState::Watching;
+                        } else if len >= 4 {
+                            this.state = HeadState::Off;
+                            this.deadline = None;
+                        }
+                    } else if this.deadline.is_none() && this.activity.idle() {
+                        this.deadline = Some(Box::pin(tokio::time::sleep(this.timeout)));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => {}
+            }
+
+            if !this.activity.idle() {
+                this.deadline = None;
+                return Poll::Pending;
+            }
+            match this.deadline.as_mut() {
+                Some(deadline) if deadline.as_mut().poll(cx).is_ready() => {
+                    debug!("client too slow sending request headers");
+                    this.deadline = None;
+                    this.state = HeadState::Rejecting(0);
+                }
+                _ => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncWrite for HeadTimeout<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
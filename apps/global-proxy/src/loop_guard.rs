@@ -0,0 +1,130 @@
+use http::{
+    HeaderMap, Response, StatusCode,
+    header::{HeaderName, HeaderValue},
+};
+use hyper::Body;
+use tracing::warn;
+
+use crate::text_response;
+
+/// Header added to forwarded requests when none is configured.
+pub const DEFAULT_LOOP_HEADER: &str = "X-Cmux-Proxied";
+/// Header counting the proxies a request has passed through when none is configured.
+pub const DEFAULT_HOPS_HEADER: &str = "X-Cmux-Hops";
+/// Hop limit when none is configured.
+pub const DEFAULT_MAX_HOPS: u32 = 10;
+
+/// How requests that come back around to the proxy are recognised. Chained deployments
+/// (global-proxy in front of cmux-proxy, or several regions) give each layer its own marker
+/// and name, and share the hop counter.
+#[derive(Clone, Debug)]
+pub struct LoopDetection {
+    /// Header and value added to forwarded requests; a request already carrying them is
+    /// refused. `None` turns the check off and leaves only the hop limit.
+    pub marker: Option<(String, String)>,
+    /// Header carrying the hop count, incremented on every forward.
+    pub hops_header: String,
+    /// Requests arriving with this many hops are refused. `0` disables the limit.
+    pub max_hops: u32,
+    /// Name of this layer in `508` responses.
+    pub layer: String,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        Self {
+            marker: Some((DEFAULT_LOOP_HEADER.to_string(), "true".to_string())),
+            hops_header: DEFAULT_HOPS_HEADER.to_string(),
+            max_hops: DEFAULT_MAX_HOPS,
+            layer: "global-proxy".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LoopGuard {
+    marker: Option<(HeaderName, HeaderValue)>,
+    hops_header: HeaderName,
+    max_hops: u32,
+    layer: String,
+}
+
+impl LoopGuard {
+    pub(crate) fn new(config: LoopDetection) -> Result<Self, String> {
+        let marker = match config.marker {
+            Some((name, value)) => Some((
+                HeaderName::from_bytes(name.trim().as_bytes())
+                    .map_err(|_| format!("invalid loop header name '{}'", name))?,
+                HeaderValue::from_str(value.trim())
+                    .map_err(|_| format!("invalid loop header value '{}'", value))?,
+            )),
+            None => None,
+        };
+        let hops_header = HeaderName::from_bytes(config.hops_header.trim().as_bytes())
+            .map_err(|_| format!("invalid hops header name '{}'", config.hops_header))?;
+        Ok(Self {
+            marker,
+            hops_header,
+            max_hops: config.max_hops,
+            layer: config.layer,
+        })
+    }
+
+    /// The `508` to send when `headers` show the request has looped.
+    pub(crate) fn detect(&self, headers: &HeaderMap) -> Option<Response<Body>> {
+        let reason = if let Some((name, value)) = &self.marker
+            && headers.get(name).is_some_and(|seen| {
+                seen.to_str()
+                    .ok()
+                    .zip(value.to_str().ok())
+                    .is_some_and(|(seen, value)| seen.eq_ignore_ascii_case(value))
+            }) {
+            format!("saw {}: {}", name, value.to_str().unwrap_or_default())
+        } else if self.max_hops > 0 && self.hops(headers) >= self.max_hops {
+            format!("saw {} hops (max {})", self.hops(headers), self.max_hops)
+        } else {
+            return None;
+        };
+
+        warn!(layer = %self.layer, %reason, "refusing looped request");
+        let mut response = text_response(
+            StatusCode::LOOP_DETECTED,
+            &format!("Loop detected in proxy: {} {}", self.layer, reason),
+        );
+        if let Ok(layer) = HeaderValue::from_str(&self.layer) {
+            response
+                .headers_mut()
+                .insert("x-cmux-loop-detected-by", layer);
+        }
+        Some(response)
+    }
+
+    /// Adds the marker and increments the hop count on a request about to be forwarded.
+    pub(crate) fn mark(&self, headers: &mut HeaderMap) {
+        let hops = self.hops(headers);
+        self.insert(hops, headers);
+    }
+
+    /// Like [`LoopGuard::mark`], for a forwarded request built from scratch out of `incoming`.
+    pub(crate) fn mark_from(&self, incoming: &HeaderMap, outgoing: &mut HeaderMap) {
+        self.insert(self.hops(incoming), outgoing);
+    }
+
+    fn insert(&self, hops: u32, headers: &mut HeaderMap) {
+        if let Some((name, value)) = &self.marker {
+            headers.insert(name.clone(), value.clone());
+        }
+        headers.insert(
+            self.hops_header.clone(),
+            HeaderValue::from(hops.saturating_add(1)),
+        );
+    }
+
+    fn hops(&self, headers: &HeaderMap) -> u32 {
+        headers
+            .get(&self.hops_header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
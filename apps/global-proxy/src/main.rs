@@ -1,6 +1,12 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
-use global_proxy::{ProxyConfig, spawn_proxy};
+use global_proxy::{
+    AccessLogConfig, AcmeChallenge, AcmeConfig, ApexSite, AuthConfig, BackendResolver, CacheConfig,
+    CommandDnsProvider, DEFAULT_BASE_DOMAINS, DEFAULT_DRAIN_TIMEOUT, DEFAULT_FRAME_ANCESTORS,
+    DnsResolver, ErrorPageConfig, HtmlInjections, HtmlSnippet, InjectPosition, LoopDetection,
+    ProxyConfig, RateLimitConfig, RequestLimits, RouteClasses, RouteRule, ServiceWorkerConfig,
+    StaticResolver, spawn_proxy,
+};
 use http::uri::Scheme;
 use tracing::info;
 
@@ -40,23 +46,243 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .ok()
         .and_then(normalize_suffix);
 
+    let backend_resolver = match std::env::var("GLOBAL_PROXY_BACKEND_RESOLVER") {
+        Ok(value) => Some(
+            backend_resolver(&value)
+                .map_err(|err| format!("GLOBAL_PROXY_BACKEND_RESOLVER: {}", err))?,
+        ),
+        Err(_) => None,
+    };
+
+    let server_timing = match std::env::var("GLOBAL_PROXY_SERVER_TIMING") {
+        Ok(value) => parse_bool(&value)
+            .ok_or_else(|| format!("GLOBAL_PROXY_SERVER_TIMING '{}' is invalid", value))?,
+        Err(_) => true,
+    };
+
+    let max_websockets_per_host = match std::env::var("GLOBAL_PROXY_MAX_WEBSOCKETS_PER_HOST") {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(0) => None,
+            Ok(cap) => Some(cap),
+            Err(_) => {
+                return Err(format!(
+                    "GLOBAL_PROXY_MAX_WEBSOCKETS_PER_HOST '{}' is invalid",
+                    value
+                )
+                .into());
+            }
+        },
+        Err(_) => None,
+    };
+    let websocket_max_frame_bytes = optional_limit("GLOBAL_PROXY_WEBSOCKET_MAX_FRAME_BYTES")?;
+    let websocket_max_message_bytes = optional_limit("GLOBAL_PROXY_WEBSOCKET_MAX_MESSAGE_BYTES")?;
+
+    let http2 = match std::env::var("GLOBAL_PROXY_HTTP2") {
+        Ok(value) => parse_bool(&value)
+            .ok_or_else(|| format!("GLOBAL_PROXY_HTTP2 '{}' is invalid", value))?,
+        Err(_) => true,
+    };
+    let upstream_http2 = match std::env::var("GLOBAL_PROXY_UPSTREAM_HTTP2") {
+        Ok(value) => parse_bool(&value)
+            .ok_or_else(|| format!("GLOBAL_PROXY_UPSTREAM_HTTP2 '{}' is invalid", value))?,
+        Err(_) => false,
+    };
+
+    let cors_allowed_origins = std::env::var("GLOBAL_PROXY_CORS_ALLOWED_ORIGINS")
+        .map(|value| parse_list(&value))
+        .unwrap_or_default();
+    let frame_ancestors = match std::env::var("GLOBAL_PROXY_FRAME_ANCESTORS") {
+        Ok(value) => parse_list(&value),
+        Err(_) => DEFAULT_FRAME_ANCESTORS
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect(),
+    };
+
+    let base_domains = match std::env::var("GLOBAL_PROXY_BASE_DOMAINS") {
+        Ok(value) => parse_list(&value),
+        Err(_) => DEFAULT_BASE_DOMAINS.iter().map(|d| d.to_string()).collect(),
+    };
+    if base_domains.is_empty() {
+        return Err("GLOBAL_PROXY_BASE_DOMAINS must list at least one domain".into());
+    }
+
+    let mut html_injections = HtmlInjections::default();
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_INJECT_LOCATION") {
+        html_injections.location = value
+            .parse()
+            .map_err(|err| format!("GLOBAL_PROXY_INJECT_LOCATION: {}", err))?;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_INJECT_SERVICE_WORKER") {
+        html_injections.service_worker = value
+            .parse()
+            .map_err(|err| format!("GLOBAL_PROXY_INJECT_SERVICE_WORKER: {}", err))?;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_INJECT_SNIPPETS") {
+        for spec in value
+            .split(['\n', ';'])
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+        {
+            html_injections.snippets.push(
+                load_snippet(spec)
+                    .map_err(|err| format!("GLOBAL_PROXY_INJECT_SNIPPETS: {}", err))?,
+            );
+        }
+    }
+
+    let mut service_worker = ServiceWorkerConfig::default();
+    if let Ok(path) = std::env::var("GLOBAL_PROXY_SERVICE_WORKER_FILE") {
+        service_worker.template = Some(
+            std::fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read {}: {}", path, err))?,
+        );
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_SERVICE_WORKER_ALLOWED_HOSTS") {
+        service_worker.allowed_hosts = parse_list(&value);
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_SERVICE_WORKER_LOOPBACK_HOSTS") {
+        service_worker.loopback_hosts = parse_list(&value);
+    }
+    service_worker.version = std::env::var("GLOBAL_PROXY_SERVICE_WORKER_VERSION").ok();
+
+    let mut error_page = ErrorPageConfig::default();
+    if let Ok(path) = std::env::var("GLOBAL_PROXY_ERROR_PAGE_FILE") {
+        error_page.template = Some(
+            std::fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read {}: {}", path, err))?,
+        );
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ERROR_PAGE_RETRY_SECS") {
+        error_page.retry_after =
+            Duration::from_secs(parse_u64("GLOBAL_PROXY_ERROR_PAGE_RETRY_SECS", &value)?);
+    }
+
+    let route_rules: Vec<RouteRule> = match std::env::var("GLOBAL_PROXY_ROUTE_RULES") {
+        Ok(path) => {
+            let rules = std::fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read {}: {}", path, err))?;
+            serde_json::from_str(&rules)
+                .map_err(|err| format!("GLOBAL_PROXY_ROUTE_RULES: {}", err))?
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let acme = match std::env::var("GLOBAL_PROXY_TLS_BIND") {
+        Ok(addr) => Some(acme_config(addr.parse()?, &base_domains)?),
+        Err(_) => None,
+    };
+
+    let metrics_bind_addr = match std::env::var("GLOBAL_PROXY_METRICS_BIND") {
+        Ok(addr) => Some(addr.parse()?),
+        Err(_) => None,
+    };
+
+    let cache = match std::env::var("GLOBAL_PROXY_CACHE_MAX_BYTES") {
+        Ok(value) => match parse_u64("GLOBAL_PROXY_CACHE_MAX_BYTES", &value)? {
+            0 => None,
+            max_bytes => Some(cache_config(max_bytes)?),
+        },
+        Err(_) => None,
+    };
+
+    let auth = match std::env::var("GLOBAL_PROXY_AUTH_SECRET") {
+        Ok(secret) if !secret.is_empty() => {
+            let mut auth = AuthConfig::new(secret);
+            if let Ok(cookie_name) = std::env::var("GLOBAL_PROXY_AUTH_COOKIE") {
+                auth.cookie_name = cookie_name;
+            }
+            auth.issuer = std::env::var("GLOBAL_PROXY_AUTH_ISSUER").ok();
+            auth.login_url = std::env::var("GLOBAL_PROXY_AUTH_LOGIN_URL").ok();
+            auth.public = std::env::var("GLOBAL_PROXY_AUTH_PUBLIC")
+                .map(|value| parse_list(&value))
+                .unwrap_or_default();
+            Some(auth)
+        }
+        _ => None,
+    };
+
+    let loop_detection = loop_detection()?;
+    let rate_limit = rate_limit_config()?;
+    let access_log = access_log_config()?;
+    let apex = apex_site()?;
+    let drain_timeout = match std::env::var("GLOBAL_PROXY_DRAIN_TIMEOUT_SECS") {
+        Ok(value) => Duration::from_secs(parse_u64("GLOBAL_PROXY_DRAIN_TIMEOUT_SECS", &value)?),
+        Err(_) => DEFAULT_DRAIN_TIMEOUT,
+    };
+    let request_limits = request_limits()?;
+
     let handle = spawn_proxy(ProxyConfig {
         bind_addr,
         backend_host,
         backend_scheme,
         morph_domain_suffix,
         workspace_domain_suffix,
+        backend_resolver,
+        server_timing,
+        max_websockets_per_host,
+        websocket_max_frame_bytes,
+        websocket_max_message_bytes,
+        http2,
+        upstream_http2,
+        cors_allowed_origins,
+        frame_ancestors,
+        html_injections,
+        base_domains,
+        acme,
+        auth,
+        metrics_bind_addr,
+        cache,
+        loop_detection,
+        service_worker,
+        route_rules,
+        rate_limit,
+        error_page,
+        access_log,
+        apex,
+        drain_timeout,
+        request_limits,
     })
     .await?;
 
     info!(addr = %handle.addr, "global proxy listening");
+    if let Some(tls_addr) = handle.tls_addr {
+        info!(addr = %tls_addr, "global proxy TLS listening");
+    }
+    if let Some(metrics_addr) = handle.metrics_addr {
+        info!(addr = %metrics_addr, "global proxy metrics listening");
+    }
 
-    tokio::signal::ctrl_c().await?;
+    shutdown_signal().await?;
 
-    handle.shutdown().await;
+    info!(
+        timeout_secs = drain_timeout.as_secs(),
+        "draining connections"
+    );
+    let report = handle.shutdown().await;
+    info!(
+        open = report.open,
+        force_closed = report.force_closed,
+        "global proxy stopped"
+    );
     Ok(())
 }
 
+/// Ctrl-C, or `SIGTERM` from Cloud Run or another supervisor.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
 fn normalize_suffix(value: String) -> Option<String> {
     let trimmed = value.trim();
     if trimmed.is_empty() {
@@ -68,3 +294,286 @@ fn normalize_suffix(value: String) -> Option<String> {
         Some(format!(".{}", trimmed))
     }
 }
+
+/// Builds a resolver from `dns:<template>` (e.g. `dns:{slug}.vms.internal`) or
+/// `static:<file>` (one `<slug> <ip>` pair per line).
+fn backend_resolver(spec: &str) -> Result<Arc<dyn BackendResolver>, String> {
+    match spec.trim().split_once(':') {
+        Some(("dns", template)) if template.contains("{slug}") => {
+            Ok(Arc::new(DnsResolver::new(template)))
+        }
+        Some(("dns", _)) => Err("the dns template must contain {slug}".to_string()),
+        Some(("static", path)) => {
+            let table = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read {}: {}", path, err))?;
+            Ok(Arc::new(StaticResolver::from_table(&table)?))
+        }
+        _ => Err(format!(
+            "'{}' must be dns:<template> or static:<file>",
+            spec
+        )),
+    }
+}
+
+fn cache_config(max_bytes: u64) -> Result<CacheConfig, String> {
+    let mut config = CacheConfig::new(max_bytes);
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_CACHE_MAX_ENTRY_BYTES") {
+        config.max_entry_bytes = parse_u64("GLOBAL_PROXY_CACHE_MAX_ENTRY_BYTES", &value)?;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_CACHE_MIN_MAX_AGE_SECS") {
+        config.min_max_age =
+            Duration::from_secs(parse_u64("GLOBAL_PROXY_CACHE_MIN_MAX_AGE_SECS", &value)?);
+    }
+    config.disk_dir = std::env::var("GLOBAL_PROXY_CACHE_DIR").ok().map(Into::into);
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_CACHE_DISK_MAX_BYTES") {
+        config.disk_max_bytes = parse_u64("GLOBAL_PROXY_CACHE_DISK_MAX_BYTES", &value)?;
+    }
+    Ok(config)
+}
+
+/// An empty `GLOBAL_PROXY_LOOP_HEADER` turns the marker check off, leaving the hop limit.
+fn loop_detection() -> Result<LoopDetection, String> {
+    let mut config = LoopDetection::default();
+    if let Ok(name) = std::env::var("GLOBAL_PROXY_LOOP_HEADER") {
+        config.marker = if name.trim().is_empty() {
+            None
+        } else {
+            let value = std::env::var("GLOBAL_PROXY_LOOP_HEADER_VALUE")
+                .unwrap_or_else(|_| "true".to_string());
+            Some((name, value))
+        };
+    } else if let Ok(value) = std::env::var("GLOBAL_PROXY_LOOP_HEADER_VALUE")
+        && let Some((_, default)) = config.marker.as_mut()
+    {
+        *default = value;
+    }
+    if let Ok(name) = std::env::var("GLOBAL_PROXY_HOPS_HEADER") {
+        config.hops_header = name;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_MAX_HOPS") {
+        config.max_hops = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("GLOBAL_PROXY_MAX_HOPS '{}' is invalid", value))?;
+    }
+    if let Ok(layer) = std::env::var("GLOBAL_PROXY_LAYER_NAME") {
+        config.layer = layer;
+    }
+    Ok(config)
+}
+
+/// Limits are written `<requests>/<window>`; unset or empty leaves that limit off.
+fn rate_limit_config() -> Result<RateLimitConfig, String> {
+    let mut config = RateLimitConfig::default();
+    for (name, limit) in [
+        ("GLOBAL_PROXY_RATE_LIMIT_PER_IP", &mut config.per_ip),
+        ("GLOBAL_PROXY_RATE_LIMIT_PER_SLUG", &mut config.per_slug),
+    ] {
+        if let Ok(value) = std::env::var(name)
+            && !value.trim().is_empty()
+        {
+            *limit = Some(value.parse().map_err(|err| format!("{}: {}", name, err))?);
+        }
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_FORWARDED_HOPS") {
+        config.forwarded_hops = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("GLOBAL_PROXY_FORWARDED_HOPS '{}' is invalid", value))?;
+    }
+    Ok(config)
+}
+
+fn request_limits() -> Result<RequestLimits, String> {
+    let mut limits = RequestLimits::default();
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_MAX_HEADER_BYTES") {
+        limits.max_header_bytes = parse_u64("GLOBAL_PROXY_MAX_HEADER_BYTES", &value)? as usize;
+    }
+    limits.max_body_bytes = optional_limit("GLOBAL_PROXY_MAX_BODY_BYTES")?;
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_HEADER_READ_TIMEOUT_SECS") {
+        let secs = parse_u64("GLOBAL_PROXY_HEADER_READ_TIMEOUT_SECS", &value)?;
+        limits.header_read_timeout = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+    }
+    Ok(limits)
+}
+
+fn apex_site() -> Result<ApexSite, String> {
+    match (
+        std::env::var("GLOBAL_PROXY_APEX_DIR"),
+        std::env::var("GLOBAL_PROXY_APEX_REDIRECT"),
+    ) {
+        (Ok(_), Ok(_)) => {
+            Err("set only one of GLOBAL_PROXY_APEX_DIR and GLOBAL_PROXY_APEX_REDIRECT".to_string())
+        }
+        (Ok(dir), Err(_)) => {
+            let mut site = StaticSite::new(dir);
+            if let Ok(value) = std::env::var("GLOBAL_PROXY_APEX_MAX_AGE_SECS") {
+                site.max_age =
+                    Duration::from_secs(parse_u64("GLOBAL_PROXY_APEX_MAX_AGE_SECS", &value)?);
+            }
+            Ok(ApexSite::Static(site))
+        }
+        (Err(_), Ok(to)) => {
+            let permanent = match std::env::var("GLOBAL_PROXY_APEX_REDIRECT_PERMANENT") {
+                Ok(value) => parse_bool(&value).ok_or_else(|| {
+                    format!(
+                        "GLOBAL_PROXY_APEX_REDIRECT_PERMANENT '{}' is invalid",
+                        value
+                    )
+                })?,
+                Err(_) => false,
+            };
+            Ok(ApexSite::Redirect { to, permanent })
+        }
+        (Err(_), Err(_)) => Ok(ApexSite::Greeting),
+    }
+}
+
+/// On when `GLOBAL_PROXY_ACCESS_LOG` is true or an OTLP endpoint is set.
+fn access_log_config() -> Result<Option<AccessLogConfig>, String> {
+    let mut config = AccessLogConfig {
+        otlp_endpoint: std::env::var("GLOBAL_PROXY_ACCESS_LOG_OTLP_ENDPOINT")
+            .ok()
+            .filter(|endpoint| !endpoint.trim().is_empty()),
+        ..AccessLogConfig::default()
+    };
+    let enabled = match std::env::var("GLOBAL_PROXY_ACCESS_LOG") {
+        Ok(value) => parse_bool(&value)
+            .ok_or_else(|| format!("GLOBAL_PROXY_ACCESS_LOG '{}' is invalid", value))?,
+        Err(_) => config.otlp_endpoint.is_some(),
+    };
+    if !enabled {
+        return Ok(None);
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ACCESS_LOG_SAMPLE_RATE") {
+        config.sample_rate = value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| (0.0..=1.0).contains(rate))
+            .ok_or_else(|| format!("GLOBAL_PROXY_ACCESS_LOG_SAMPLE_RATE '{}' is invalid", value))?;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ACCESS_LOG_OTLP_HEADERS") {
+        for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (name, header_value) = pair.split_once('=').ok_or_else(|| {
+                format!(
+                    "GLOBAL_PROXY_ACCESS_LOG_OTLP_HEADERS entry '{}' must be <name>=<value>",
+                    pair
+                )
+            })?;
+            config
+                .otlp_headers
+                .push((name.trim().to_string(), header_value.trim().to_string()));
+        }
+    }
+    if let Ok(name) = std::env::var("GLOBAL_PROXY_ACCESS_LOG_SERVICE_NAME") {
+        config.service_name = name;
+    }
+    Ok(Some(config))
+}
+
+fn acme_config(
+    tls_bind_addr: SocketAddr,
+    base_domains: &[String],
+) -> Result<AcmeConfig, Box<dyn std::error::Error>> {
+    let storage_dir =
+        std::env::var("GLOBAL_PROXY_ACME_STORAGE").unwrap_or_else(|_| "acme".to_string());
+    let mut config = AcmeConfig::new(tls_bind_addr, storage_dir);
+    if let Ok(url) = std::env::var("GLOBAL_PROXY_ACME_DIRECTORY") {
+        config.directory_url = url;
+    }
+    config.contact_email = std::env::var("GLOBAL_PROXY_ACME_EMAIL").ok();
+
+    let challenge =
+        std::env::var("GLOBAL_PROXY_ACME_CHALLENGE").unwrap_or_else(|_| "http-01".to_string());
+    match challenge.trim().to_ascii_lowercase().as_str() {
+        "http-01" => {}
+        "dns-01" => {
+            let hook = std::env::var("GLOBAL_PROXY_ACME_DNS_HOOK")
+                .map_err(|_| "GLOBAL_PROXY_ACME_DNS_HOOK is required for dns-01")?;
+            let propagation_secs = match std::env::var("GLOBAL_PROXY_ACME_DNS_PROPAGATION_SECS") {
+                Ok(value) => value.trim().parse::<u64>().map_err(|_| {
+                    format!(
+                        "GLOBAL_PROXY_ACME_DNS_PROPAGATION_SECS '{}' is invalid",
+                        value
+                    )
+                })?,
+                Err(_) => 60,
+            };
+            config.challenge = AcmeChallenge::Dns01 {
+                provider: Arc::new(CommandDnsProvider::new(hook)),
+                propagation_delay: Duration::from_secs(propagation_secs),
+            };
+            // Wildcards cover every preview host without per-host issuance.
+            config.domains = base_domains
+                .iter()
+                .flat_map(|domain| [format!("*.{}", domain), domain.clone()])
+                .collect();
+        }
+        other => return Err(format!("GLOBAL_PROXY_ACME_CHALLENGE '{}' is invalid", other).into()),
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ACME_DOMAINS") {
+        config.domains = parse_list(&value);
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ACME_ON_DEMAND") {
+        config.on_demand = parse_bool(&value)
+            .ok_or_else(|| format!("GLOBAL_PROXY_ACME_ON_DEMAND '{}' is invalid", value))?;
+    }
+    if let Ok(value) = std::env::var("GLOBAL_PROXY_ACME_ON_DEMAND_LIMIT") {
+        config.on_demand_limit = value
+            .parse()
+            .map_err(|err| format!("GLOBAL_PROXY_ACME_ON_DEMAND_LIMIT: {}", err))?;
+    }
+    Ok(config)
+}
+
+/// Loads `<position>[:<routes>]=<file>`, e.g. `head-end:port,workspace=/etc/cmux/telemetry.html`.
+fn load_snippet(spec: &str) -> Result<HtmlSnippet, String> {
+    let (target, path) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' must be <position>[:<routes>]=<file>", spec))?;
+    let (position, routes) = match target.split_once(':') {
+        Some((position, routes)) => (position, routes.parse::<RouteClasses>()?),
+        None => (target, RouteClasses::ALL),
+    };
+    let position = position.parse::<InjectPosition>()?;
+    let html = std::fs::read_to_string(path.trim())
+        .map_err(|err| format!("failed to read {}: {}", path.trim(), err))?;
+    Ok(HtmlSnippet {
+        html,
+        position,
+        routes,
+    })
+}
+
+/// Splits a comma- or whitespace-separated list of origins or domains.
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_u64(name: &str, value: &str) -> Result<u64, String> {
+    value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("{} '{}' is invalid", name, value))
+}
+
+/// Reads a byte limit where unset or `0` means no limit.
+fn optional_limit(name: &str) -> Result<Option<u64>, String> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(parse_u64(name, &value)?).filter(|limit| *limit > 0)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
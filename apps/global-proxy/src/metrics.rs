@@ -0,0 +1,374 @@
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use http::{Request, Response, StatusCode, header};
+use hyper::{
+    Body,
+    service::{make_service_fn, service_fn},
+};
+use tokio::sync::oneshot;
+use tracing::error;
+
+use crate::{RouteClass, rate_limit::LimitScope, ws_relay::Direction};
+
+/// Upper bounds (seconds) of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const STATUS_FAMILIES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Metric label for where a request was routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RouteLabel {
+    /// A base domain itself.
+    Apex,
+    Port,
+    Cmux,
+    Workspace,
+    /// Hosts outside the base domains, including health checks addressed by IP.
+    Other,
+}
+
+impl RouteLabel {
+    const ALL: [RouteLabel; 5] = [
+        RouteLabel::Apex,
+        RouteLabel::Port,
+        RouteLabel::Cmux,
+        RouteLabel::Workspace,
+        RouteLabel::Other,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            RouteLabel::Apex => "apex",
+            RouteLabel::Port => "port",
+            RouteLabel::Cmux => "cmux",
+            RouteLabel::Workspace => "workspace",
+            RouteLabel::Other => "other",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl From<RouteClass> for RouteLabel {
+    fn from(class: RouteClass) -> Self {
+        match class {
+            RouteClass::Port => RouteLabel::Port,
+            RouteClass::Cmux => RouteLabel::Cmux,
+            RouteClass::Workspace => RouteLabel::Workspace,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters rendered in the Prometheus text format by the internal listener.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    requests: [[AtomicU64; STATUS_FAMILIES.len()]; RouteLabel::ALL.len()],
+    latency: [Histogram; RouteLabel::ALL.len()],
+    upstream_errors: [AtomicU64; RouteLabel::ALL.len()],
+    websocket_upgrades: [AtomicU64; RouteLabel::ALL.len()],
+    websocket_rejected: AtomicU64,
+    websocket_open: AtomicU64,
+    websocket_too_big: AtomicU64,
+    websocket_bytes: [[AtomicU64; 2]; RouteLabel::ALL.len()],
+    html_injections: [AtomicU64; RouteLabel::ALL.len()],
+    rate_limited: [AtomicU64; 2],
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_stores: AtomicU64,
+    cache_evictions: AtomicU64,
+    cache_memory_bytes: AtomicU64,
+    cache_disk_bytes: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a finished request: its status family and time to response headers.
+    pub(crate) fn observe_request(&self, route: RouteLabel, status: StatusCode, elapsed: Duration) {
+        let family = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+        self.requests[route.index()][family].fetch_add(1, Ordering::Relaxed);
+        self.latency[route.index()].observe(elapsed);
+    }
+
+    pub(crate) fn upstream_error(&self, route: RouteLabel) {
+        self.upstream_errors[route.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn websocket_upgrade(&self, route: RouteLabel) {
+        self.websocket_upgrades[route.index()].fetch_add(1, Ordering::Relaxed);
+        self.websocket_open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a tunnel closing; `too_big` when it was cut for breaking the frame limits.
+    pub(crate) fn websocket_closed(&self, too_big: bool) {
+        self.websocket_open.fetch_sub(1, Ordering::Relaxed);
+        if too_big {
+            self.websocket_too_big.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn websocket_bytes(&self, route: RouteLabel, direction: Direction, bytes: u64) {
+        self.websocket_bytes[route.index()][direction as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn websocket_rejected(&self) {
+        self.websocket_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn html_injection(&self, route: RouteLabel) {
+        self.html_injections[route.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rate_limited(&self, scope: LimitScope) {
+        self.rate_limited[scope as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_store(&self) {
+        self.cache_stores.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_evictions(&self, count: u64) {
+        self.cache_evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_cache_bytes(&self, memory: u64, disk: u64) {
+        self.cache_memory_bytes.store(memory, Ordering::Relaxed);
+        self.cache_disk_bytes.store(disk, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP global_proxy_requests_total Requests handled, by route class and status family.\n\
+             # TYPE global_proxy_requests_total counter\n",
+        );
+        for route in RouteLabel::ALL {
+            for (family, name) in STATUS_FAMILIES.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "global_proxy_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    route.as_str(),
+                    name,
+                    self.requests[route.index()][family].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP global_proxy_request_duration_seconds Time from receiving a request to sending response headers.\n\
+             # TYPE global_proxy_request_duration_seconds histogram\n",
+        );
+        for route in RouteLabel::ALL {
+            let histogram = &self.latency[route.index()];
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "global_proxy_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route.as_str(),
+                    bound,
+                    cumulative
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "global_proxy_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}\n\
+                 global_proxy_request_duration_seconds_sum{{route=\"{}\"}} {}\n\
+                 global_proxy_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route.as_str(),
+                count,
+                route.as_str(),
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+                route.as_str(),
+                count
+            );
+        }
+
+        render_counter(
+            &mut out,
+            "global_proxy_upstream_errors_total",
+            "Requests that failed to reach the backend, by route class.",
+            &self.upstream_errors,
+        );
+        render_counter(
+            &mut out,
+            "global_proxy_websocket_upgrades_total",
+            "WebSocket upgrades accepted, by route class.",
+            &self.websocket_upgrades,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP global_proxy_websocket_rejected_total WebSocket upgrades refused by the per-host cap.\n\
+             # TYPE global_proxy_websocket_rejected_total counter\n\
+             global_proxy_websocket_rejected_total {}",
+            self.websocket_rejected.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP global_proxy_websocket_open WebSocket tunnels currently open.\n\
+             # TYPE global_proxy_websocket_open gauge\n\
+             global_proxy_websocket_open {}\n\
+             # HELP global_proxy_websocket_too_big_total WebSocket tunnels closed for exceeding the frame or message size limit.\n\
+             # TYPE global_proxy_websocket_too_big_total counter\n\
+             global_proxy_websocket_too_big_total {}\n\
+             # HELP global_proxy_websocket_bytes_total Bytes relayed over WebSocket tunnels, by route class and direction.\n\
+             # TYPE global_proxy_websocket_bytes_total counter",
+            self.websocket_open.load(Ordering::Relaxed),
+            self.websocket_too_big.load(Ordering::Relaxed),
+        );
+        for route in RouteLabel::ALL {
+            for (direction, name) in [
+                (Direction::ToBackend, "to_backend"),
+                (Direction::ToClient, "to_client"),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "global_proxy_websocket_bytes_total{{route=\"{}\",direction=\"{}\"}} {}",
+                    route.as_str(),
+                    name,
+                    self.websocket_bytes[route.index()][direction as usize].load(Ordering::Relaxed)
+                );
+            }
+        }
+        render_counter(
+            &mut out,
+            "global_proxy_html_injections_total",
+            "HTML responses rewritten with injected scripts, by route class.",
+            &self.html_injections,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP global_proxy_rate_limited_total Requests refused with 429, by the limit they hit.\n\
+             # TYPE global_proxy_rate_limited_total counter\n\
+             global_proxy_rate_limited_total{{scope=\"ip\"}} {}\n\
+             global_proxy_rate_limited_total{{scope=\"slug\"}} {}",
+            self.rate_limited[LimitScope::Ip as usize].load(Ordering::Relaxed),
+            self.rate_limited[LimitScope::Slug as usize].load(Ordering::Relaxed),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP global_proxy_cache_requests_total Cache lookups, by result.\n\
+             # TYPE global_proxy_cache_requests_total counter\n\
+             global_proxy_cache_requests_total{{result=\"hit\"}} {}\n\
+             global_proxy_cache_requests_total{{result=\"miss\"}} {}\n\
+             # HELP global_proxy_cache_stores_total Responses added to the cache.\n\
+             # TYPE global_proxy_cache_stores_total counter\n\
+             global_proxy_cache_stores_total {}\n\
+             # HELP global_proxy_cache_evictions_total Entries evicted from the memory cache.\n\
+             # TYPE global_proxy_cache_evictions_total counter\n\
+             global_proxy_cache_evictions_total {}\n\
+             # HELP global_proxy_cache_bytes Size of cached entries.\n\
+             # TYPE global_proxy_cache_bytes gauge\n\
+             global_proxy_cache_bytes{{tier=\"memory\"}} {}\n\
+             global_proxy_cache_bytes{{tier=\"disk\"}} {}",
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+            self.cache_stores.load(Ordering::Relaxed),
+            self.cache_evictions.load(Ordering::Relaxed),
+            self.cache_memory_bytes.load(Ordering::Relaxed),
+            self.cache_disk_bytes.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, values: &[AtomicU64]) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for route in RouteLabel::ALL {
+        let _ = writeln!(
+            out,
+            "{}{{route=\"{}\"}} {}",
+            name,
+            route.as_str(),
+            values[route.index()].load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Plain `200 ok` for load balancer health checks.
+pub(crate) fn healthz_response() -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from("ok"))
+        .unwrap()
+}
+
+/// Serves `/metrics` and `/healthz` on the internal listener until `shutdown` fires.
+pub(crate) async fn serve(
+    listener: std::net::TcpListener,
+    metrics: std::sync::Arc<Metrics>,
+    shutdown: oneshot::Receiver<()>,
+) {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    let response = match req.uri().path() {
+                        "/metrics" => Response::builder()
+                            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(metrics.render()))
+                            .unwrap(),
+                        "/healthz" => healthz_response(),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap(),
+                    };
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+    let server = match hyper::Server::from_tcp(listener) {
+        Ok(builder) => builder.http1_only(true).serve(make_svc),
+        Err(err) => {
+            error!(%err, "metrics listener failed");
+            return;
+        }
+    };
+    if let Err(err) = server
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        })
+        .await
+    {
+        error!(%err, "metrics server error");
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::{HeaderMap, Request};
+use hyper::Body;
+
+/// Past this many tracked keys, buckets that have refilled are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Allow `requests` per `window`, refilled continuously, with bursts up to `requests`.
+/// Written as `<requests>/<window>`, e.g. `600/min`, `20/s` or `1000/10m`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit '{}' (expected e.g. 600/min)", s);
+        let (requests, window) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let window = window.trim();
+        let split = window
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(window.len());
+        let (count, unit) = window.split_at(split);
+        let count: u64 = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(|_| invalid())?
+        };
+        let unit_secs = match unit {
+            "s" | "sec" | "second" => 1,
+            "m" | "min" | "minute" => 60,
+            "h" | "hour" => 3600,
+            _ => return Err(invalid()),
+        };
+        if requests == 0 || count == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            requests,
+            window: Duration::from_secs(count * unit_secs),
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}s", self.requests, self.window.as_secs())
+    }
+}
+
+/// Request limits applied before proxying.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    /// Per client IP, across every host.
+    pub per_ip: Option<RateLimit>,
+    /// Per target VM slug, across every client.
+    pub per_slug: Option<RateLimit>,
+    /// How many proxies in front of this one append to `X-Forwarded-For`. The client IP is
+    /// read that many entries from the right; `0` uses the connection's address.
+    pub forwarded_hops: usize,
+}
+
+/// The peer address of the connection a request arrived on.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientAddr(pub(crate) SocketAddr);
+
+/// What a request was refused for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LimitScope {
+    Ip,
+    Slug,
+}
+
+impl LimitScope {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            LimitScope::Ip => "ip",
+            LimitScope::Slug => "slug",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client IP, slug or whatever else is being limited.
+pub(crate) struct Buckets {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.limit.requests) / self.limit.window.as_secs_f64()
+    }
+
+    /// Takes a token for `key`, or returns how long until one is available.
+    pub(crate) fn take(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(self.limit.requests);
+        let rate = self.refill_rate();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+pub(crate) struct RateLimiter {
+    per_ip: Option<Buckets>,
+    per_slug: Option<Buckets>,
+    forwarded_hops: usize,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            per_ip: config.per_ip.map(Buckets::new),
+            per_slug: config.per_slug.map(Buckets::new),
+            forwarded_hops: config.forwarded_hops,
+        }
+    }
+
+    /// Counts a request against its client IP; `Err` carries how long the client should wait.
+    pub(crate) fn check_ip(&self, req: &Request<Body>) -> Result<(), Duration> {
+        let Some(buckets) = &self.per_ip else {
+            return Ok(());
+        };
+        match self.client_ip(req) {
+            Some(ip) => buckets.take(&ip.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a request against the VM slug it targets.
+    pub(crate) fn check_slug(&self, slug: &str) -> Result<(), Duration> {
+        match &self.per_slug {
+            Some(buckets) => buckets.take(&slug.to_ascii_lowercase()),
+            None => Ok(()),
+        }
+    }
+
+    fn client_ip(&self, req: &Request<Body>) -> Option<IpAddr> {
+        if self.forwarded_hops == 0 {
+            return req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip());
+        }
+        forwarded_for(req.headers())
+            .into_iter()
+            .rev()
+            .nth(self.forwarded_hops - 1)
+    }
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.trim().parse().ok())
+        .collect()
+}
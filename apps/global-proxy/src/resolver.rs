@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use futures_util::future::BoxFuture;
+
+/// Maps the VM slug of a preview host (`<slug>` in `port-8080-<slug>`, the morph id in
+/// `cmux-<slug>-…-<port>` and the VM in `<workspace>-<port>-<slug>`) plus the requested port to
+/// the address the proxy should connect to. Implement it to look VMs up in a control-plane API;
+/// [`StaticResolver`] and [`DnsResolver`] cover fixed tables and DNS.
+pub trait BackendResolver: fmt::Debug + Send + Sync {
+    fn resolve<'a>(&'a self, slug: &'a str, port: u16)
+    -> BoxFuture<'a, Result<SocketAddr, String>>;
+}
+
+/// [`BackendResolver`] backed by a fixed slug → IP table.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    table: HashMap<String, IpAddr>,
+}
+
+impl StaticResolver {
+    pub fn new(table: HashMap<String, IpAddr>) -> Self {
+        Self {
+            table: table
+                .into_iter()
+                .map(|(slug, ip)| (slug.to_ascii_lowercase(), ip))
+                .collect(),
+        }
+    }
+
+    /// Parses one `<slug> <ip>` pair per line. Blank lines and `#` comments are skipped.
+    pub fn from_table(table: &str) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        for (index, line) in table.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(slug), Some(ip), None) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(format!("line {}: expected '<slug> <ip>'", index + 1));
+            };
+            let ip = ip
+                .parse::<IpAddr>()
+                .map_err(|_| format!("line {}: '{}' is not an IP address", index + 1, ip))?;
+            entries.insert(slug.to_string(), ip);
+        }
+        Ok(Self::new(entries))
+    }
+}
+
+impl BackendResolver for StaticResolver {
+    fn resolve<'a>(
+        &'a self,
+        slug: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<SocketAddr, String>> {
+        let result = self
+            .table
+            .get(&slug.to_ascii_lowercase())
+            .map(|ip| SocketAddr::new(*ip, port))
+            .ok_or_else(|| format!("no backend for '{}'", slug));
+        Box::pin(async move { result })
+    }
+}
+
+/// [`BackendResolver`] that looks up a hostname built from a template in which `{slug}` is
+/// replaced by the VM slug, e.g. `{slug}.vms.internal`.
+#[derive(Clone, Debug)]
+pub struct DnsResolver {
+    template: String,
+}
+
+impl DnsResolver {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+}
+
+impl BackendResolver for DnsResolver {
+    fn resolve<'a>(
+        &'a self,
+        slug: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<SocketAddr, String>> {
+        Box::pin(async move {
+            let host = self.template.replace("{slug}", slug);
+            tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|err| format!("failed to resolve {}: {}", host, err))?
+                .next()
+                .ok_or_else(|| format!("{} has no addresses", host))
+        })
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+
+use http::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use serde::Deserialize;
+
+use crate::{RouteClass, RouteClasses};
+
+/// Port VS Code is served on; the built-in rules make it embeddable and CORS-free.
+const VSCODE_PORT: u16 = 39_378;
+
+/// Which requests a [`RouteRule`] applies to. Every listed condition has to hold; an empty
+/// list matches anything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleMatch {
+    /// Route classes, written like `GLOBAL_PROXY_INJECT_LOCATION` (`port,workspace`).
+    pub routes: RouteClasses,
+    /// Ports from the preview host.
+    pub ports: Vec<u16>,
+    /// Preview hosts; `*` matches any run of characters, e.g. `port-3000-*.cmux.sh`.
+    pub hosts: Vec<String>,
+}
+
+/// Overrides for requests matching `matches`. Rules are applied in order after the built-in
+/// ones, so a later rule wins for any setting both provide.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteRule {
+    #[serde(rename = "match")]
+    pub matches: RuleMatch,
+    /// Headers set on the request sent upstream.
+    pub request_headers: BTreeMap<String, String>,
+    /// Headers removed from the request sent upstream.
+    pub remove_request_headers: Vec<String>,
+    /// Headers set on the response.
+    pub response_headers: BTreeMap<String, String>,
+    /// Headers removed from the response.
+    pub remove_response_headers: Vec<String>,
+    /// `Content-Security-Policy` sent with the response; empty sends none. Upstream CSP headers
+    /// are always dropped.
+    pub content_security_policy: Option<String>,
+    /// Drop CORS headers from responses and answer preflights with a bare `204` instead of
+    /// forwarding them.
+    pub strip_cors: Option<bool>,
+    /// Inject the `window.__cmuxLocation` script, overriding `HtmlInjections::location`.
+    pub inject_location: Option<bool>,
+    /// Inject the service-worker registration, overriding `HtmlInjections::service_worker`.
+    pub inject_service_worker: Option<bool>,
+}
+
+/// What the rules decided for one request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RuleOutcome {
+    pub(crate) strip_cors: bool,
+    pub(crate) content_security_policy: Option<HeaderValue>,
+    pub(crate) inject_location: Option<bool>,
+    pub(crate) inject_service_worker: Option<bool>,
+    request_headers: Vec<HeaderTransform>,
+    response_headers: Vec<HeaderTransform>,
+}
+
+#[derive(Clone, Debug)]
+enum HeaderTransform {
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+}
+
+impl RuleOutcome {
+    pub(crate) fn apply_request(&self, headers: &mut HeaderMap) {
+        apply(&self.request_headers, headers);
+    }
+
+    pub(crate) fn apply_response(&self, headers: &mut HeaderMap) {
+        apply(&self.response_headers, headers);
+    }
+}
+
+fn apply(transforms: &[HeaderTransform], headers: &mut HeaderMap) {
+    for transform in transforms {
+        match transform {
+            HeaderTransform::Set(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderTransform::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+struct CompiledRule {
+    routes: RouteClasses,
+    ports: Vec<u16>,
+    hosts: Vec<String>,
+    request_headers: Vec<HeaderTransform>,
+    response_headers: Vec<HeaderTransform>,
+    content_security_policy: Option<Option<HeaderValue>>,
+    strip_cors: Option<bool>,
+    inject_location: Option<bool>,
+    inject_service_worker: Option<bool>,
+}
+
+impl CompiledRule {
+    fn matches(&self, class: RouteClass, host: &str, port: u16) -> bool {
+        self.routes.contains(class)
+            && (self.ports.is_empty() || self.ports.contains(&port))
+            && (self.hosts.is_empty() || self.hosts.iter().any(|pattern| glob(pattern, host)))
+    }
+}
+
+/// The built-in rules followed by the configured ones, evaluated per request.
+pub(crate) struct RouteRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl RouteRules {
+    /// `frame_ancestors` is the CSP the built-in VS Code rule sends.
+    pub(crate) fn new(rules: &[RouteRule], frame_ancestors: HeaderValue) -> Result<Self, String> {
+        let mut compiled = vec![
+            // VS Code on a port route is embedded by the cmux app, so it gets the frame-ancestors
+            // CSP and handles CORS itself.
+            CompiledRule {
+                routes: RouteClasses {
+                    port: true,
+                    ..RouteClasses::NONE
+                },
+                ports: vec![VSCODE_PORT],
+                hosts: Vec::new(),
+                request_headers: Vec::new(),
+                response_headers: Vec::new(),
+                content_security_policy: Some(Some(frame_ancestors)),
+                strip_cors: Some(true),
+                inject_location: None,
+                inject_service_worker: Some(false),
+            },
+            CompiledRule {
+                routes: RouteClasses {
+                    cmux: true,
+                    ..RouteClasses::NONE
+                },
+                ports: vec![VSCODE_PORT],
+                hosts: Vec::new(),
+                request_headers: Vec::new(),
+                response_headers: Vec::new(),
+                content_security_policy: None,
+                strip_cors: Some(true),
+                inject_location: None,
+                inject_service_worker: None,
+            },
+            // The cmux route serves the app itself, which registers its own worker.
+            CompiledRule {
+                routes: RouteClasses {
+                    cmux: true,
+                    ..RouteClasses::NONE
+                },
+                ports: Vec::new(),
+                hosts: Vec::new(),
+                request_headers: Vec::new(),
+                response_headers: Vec::new(),
+                content_security_policy: None,
+                strip_cors: None,
+                inject_location: None,
+                inject_service_worker: Some(false),
+            },
+        ];
+        for (index, rule) in rules.iter().enumerate() {
+            compiled.push(compile(rule).map_err(|err| format!("rule {}: {}", index + 1, err))?);
+        }
+        Ok(Self { rules: compiled })
+    }
+
+    pub(crate) fn evaluate(&self, class: RouteClass, host: &str, port: u16) -> RuleOutcome {
+        let mut outcome = RuleOutcome::default();
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(class, host, port))
+        {
+            if let Some(csp) = &rule.content_security_policy {
+                outcome.content_security_policy = csp.clone();
+            }
+            if let Some(strip_cors) = rule.strip_cors {
+                outcome.strip_cors = strip_cors;
+            }
+            if rule.inject_location.is_some() {
+                outcome.inject_location = rule.inject_location;
+            }
+            if rule.inject_service_worker.is_some() {
+                outcome.inject_service_worker = rule.inject_service_worker;
+            }
+            outcome
+                .request_headers
+                .extend(rule.request_headers.iter().cloned());
+            outcome
+                .response_headers
+                .extend(rule.response_headers.iter().cloned());
+        }
+        outcome
+    }
+}
+
+fn compile(rule: &RouteRule) -> Result<CompiledRule, String> {
+    let content_security_policy = match rule.content_security_policy.as_deref().map(str::trim) {
+        None => None,
+        Some("") => Some(None),
+        Some(csp) => Some(Some(
+            HeaderValue::from_str(csp).map_err(|_| format!("invalid CSP '{}'", csp))?,
+        )),
+    };
+    Ok(CompiledRule {
+        routes: rule.matches.routes,
+        ports: rule.matches.ports.clone(),
+        hosts: rule
+            .matches
+            .hosts
+            .iter()
+            .map(|host| host.trim().to_ascii_lowercase())
+            .collect(),
+        request_headers: transforms(&rule.remove_request_headers, &rule.request_headers)?,
+        response_headers: transforms(&rule.remove_response_headers, &rule.response_headers)?,
+        content_security_policy,
+        strip_cors: rule.strip_cors,
+        inject_location: rule.inject_location,
+        inject_service_worker: rule.inject_service_worker,
+    })
+}
+
+/// Removals first, so a rule can replace every value of a header with a single one.
+fn transforms(
+    remove: &[String],
+    set: &BTreeMap<String, String>,
+) -> Result<Vec<HeaderTransform>, String> {
+    let mut out = Vec::new();
+    for name in remove {
+        out.push(HeaderTransform::Remove(header_name(name)?));
+    }
+    for (name, value) in set {
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for header '{}'", name))?;
+        out.push(HeaderTransform::Set(header_name(name)?, value));
+    }
+    Ok(out)
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
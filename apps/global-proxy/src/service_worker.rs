@@ -0,0 +1,194 @@
+use http::{HeaderMap, Response, StatusCode, header};
+use hyper::Body;
+
+/// Hostnames the built-in service worker treats as loopback when none are configured, in
+/// addition to `127.0.0.0/8`.
+pub const DEFAULT_LOOPBACK_HOSTS: &[&str] = &["localhost", "0.0.0.0", "::1", "[::1]", "::"];
+
+/// Placeholders replaced in the service-worker template before it is served.
+const VERSION_VAR: &str = "__CMUX_SW_VERSION__";
+const ALLOWED_HOSTS_VAR: &str = "__CMUX_ALLOWED_HOSTS__";
+const LOOPBACK_HOSTS_VAR: &str = "__CMUX_LOOPBACK_HOSTS__";
+
+/// The script served at `/proxy-sw.js` and how pages register it.
+#[derive(Clone, Debug)]
+pub struct ServiceWorkerConfig {
+    /// Replaces the built-in script. `__CMUX_SW_VERSION__`, `__CMUX_ALLOWED_HOSTS__` and
+    /// `__CMUX_LOOPBACK_HOSTS__` are replaced with the version string and JSON arrays of the
+    /// settings below.
+    pub template: Option<String>,
+    /// Preview domains the worker redirects loopback requests on. Empty means the proxy's base
+    /// domains.
+    pub allowed_hosts: Vec<String>,
+    /// Hostnames treated as loopback; `*.example` matches subdomains.
+    pub loopback_hosts: Vec<String>,
+    /// Appended to the registration URL so browsers install a new worker when it changes.
+    /// Defaults to a hash of the rendered script, so editing the template rolls it out.
+    pub version: Option<String>,
+}
+
+impl Default for ServiceWorkerConfig {
+    fn default() -> Self {
+        Self {
+            template: None,
+            allowed_hosts: Vec::new(),
+            loopback_hosts: DEFAULT_LOOPBACK_HOSTS
+                .iter()
+                .map(|host| host.to_string())
+                .collect(),
+            version: None,
+        }
+    }
+}
+
+pub(crate) struct ServiceWorker {
+    script: String,
+    version: String,
+    registration: String,
+}
+
+impl ServiceWorker {
+    pub(crate) fn new(config: ServiceWorkerConfig, base_domains: &[String]) -> Self {
+        let allowed_hosts: Vec<String> = if config.allowed_hosts.is_empty() {
+            base_domains.to_vec()
+        } else {
+            config
+                .allowed_hosts
+                .iter()
+                .map(|host| host.trim().trim_matches('.').to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect()
+        };
+        let loopback_hosts: Vec<String> = config
+            .loopback_hosts
+            .iter()
+            .map(|host| host.trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+
+        let template = config.template.as_deref().unwrap_or(SERVICE_WORKER_JS);
+        let rendered = template
+            .replace(ALLOWED_HOSTS_VAR, &json_array(&allowed_hosts))
+            .replace(LOOPBACK_HOSTS_VAR, &json_array(&loopback_hosts));
+        let version = config
+            .version
+            .map(|version| version.trim().to_string())
+            .filter(|version| !version.is_empty())
+            .unwrap_or_else(|| content_hash(&rendered));
+        let script = rendered.replace(
+            VERSION_VAR,
+            &serde_json::Value::from(version.as_str()).to_string(),
+        );
+        let registration = SERVICE_WORKER_SCRIPT.replace(
+            VERSION_VAR,
+            &percent_encoding::utf8_percent_encode(&version, percent_encoding::NON_ALPHANUMERIC)
+                .to_string(),
+        );
+
+        Self {
+            script,
+            version,
+            registration,
+        }
+    }
+
+    /// The `<script>` injected into pages to register the worker.
+    pub(crate) fn registration(&self) -> &str {
+        &self.registration
+    }
+
+    pub(crate) fn response(&self, request_headers: &HeaderMap) -> Response<Body> {
+        let etag = format!("\"{}\"", self.version);
+        let not_modified = request_headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+        let builder = Response::builder()
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::ETAG, etag);
+        if not_modified {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+        }
+        builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/javascript")
+            .body(Body::from(self.script.clone()))
+            .unwrap()
+    }
+}
+
+fn json_array(values: &[String]) -> String {
+    serde_json::Value::from(values.to_vec()).to_string()
+}
+
+fn content_hash(script: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, script.as_bytes())
+        .as_ref()
+        .iter()
+        .take(6)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+const SERVICE_WORKER_SCRIPT: &str = r#"<script data-cmux-injected="true">
+// __CMUX_NO_REWRITE__
+if ('serviceWorker' in navigator) {
+  navigator.serviceWorker.register('/proxy-sw.js?v=__CMUX_SW_VERSION__', { scope: '/' }).catch(console.error);
+}
+</script>"#;
+
+const SERVICE_WORKER_JS: &str = r#"const CMUX_SW_VERSION = __CMUX_SW_VERSION__;
+const ALLOWED_HOSTS = __CMUX_ALLOWED_HOSTS__;
+const LOOPBACK_HOSTS = __CMUX_LOOPBACK_HOSTS__;
+
+self.addEventListener('install', (event) => {
+  self.skipWaiting();
+});
+
+self.addEventListener('activate', (event) => {
+  event.waitUntil(clients.claim());
+});
+
+function isLoopbackHostname(hostname) {
+  if (!hostname) {
+    return false;
+  }
+  if (/^127(?:\.\d{1,3}){3}$/.test(hostname)) {
+    return true;
+  }
+  return LOOPBACK_HOSTS.some((pattern) =>
+    pattern.startsWith('*.') ? hostname.endsWith(pattern.slice(1)) : hostname === pattern
+  );
+}
+
+function isAllowedDomain(domain) {
+  return ALLOWED_HOSTS.some((host) => domain === host || domain.endsWith(`.${host}`));
+}
+
+self.addEventListener('fetch', (event) => {
+  const url = new URL(event.request.url);
+  if (isLoopbackHostname(url.hostname) && url.port) {
+    const currentHost = self.location.hostname;
+    const firstDot = currentHost.indexOf('.');
+    if (firstDot === -1) {
+      return;
+    }
+    const firstLabel = currentHost.slice(0, firstDot);
+    const morphIdMatch = firstLabel.match(/^port-\d+-(.*)$/);
+    if (!morphIdMatch) {
+      return;
+    }
+    const domain = currentHost.slice(firstDot + 1);
+    if (!domain || !isAllowedDomain(domain)) {
+      return;
+    }
+    const morphId = morphIdMatch[1];
+    const redirectUrl = `https://port-${url.port}-${morphId}.${domain}${url.pathname}${url.search}`;
+    event.respondWith(fetch(redirectUrl, { redirect: 'follow' }));
+    return;
+  }
+});
+"#;
@@ -0,0 +1,122 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http::{HeaderMap, HeaderValue, Uri};
+use hyper::{client::HttpConnector, service::Service};
+use tokio::net::TcpStream;
+
+tokio::task_local! {
+    /// Slot the connector writes the TCP connect duration into, scoped around a single
+    /// upstream request so concurrent requests never see each other's timings.
+    static CONNECT_TIMING: Arc<Mutex<Option<Duration>>>;
+}
+
+/// Per-request phase durations surfaced to browsers via the `Server-Timing` header.
+#[derive(Debug)]
+pub(crate) struct ServerTiming {
+    started: Instant,
+    resolve: Option<Duration>,
+    connect: Arc<Mutex<Option<Duration>>>,
+    backend: Option<Duration>,
+}
+
+impl ServerTiming {
+    pub(crate) fn new(started: Instant) -> Self {
+        Self {
+            started,
+            resolve: None,
+            connect: Arc::new(Mutex::new(None)),
+            backend: None,
+        }
+    }
+
+    /// Marks the end of host parsing and route resolution.
+    pub(crate) fn mark_resolved(&mut self) {
+        self.resolve = Some(self.started.elapsed());
+    }
+
+    /// Runs the upstream request, recording time to response headers and, when a new
+    /// connection had to be opened, how long the TCP connect took.
+    pub(crate) async fn time_backend<F, T>(&mut self, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let begin = Instant::now();
+        let out = CONNECT_TIMING.scope(self.connect.clone(), fut).await;
+        self.backend = Some(begin.elapsed());
+        out
+    }
+
+    fn header_value(&self) -> Option<HeaderValue> {
+        let mut metrics = Vec::new();
+        if let Some(d) = self.resolve {
+            metrics.push(format!("resolve;dur={:.3}", millis(d)));
+        }
+        if let Some(d) = *self.connect.lock().unwrap() {
+            metrics.push(format!("connect;dur={:.3}", millis(d)));
+        }
+        if let Some(d) = self.backend {
+            metrics.push(format!(
+                "backend;dur={:.3};desc=\"backend TTFB\"",
+                millis(d)
+            ));
+        }
+        if metrics.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&metrics.join(", ")).ok()
+    }
+
+    /// Appends our metrics alongside any `Server-Timing` the backend already sent.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(value) = self.header_value() {
+            headers.append("server-timing", value);
+        }
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// `HttpConnector` wrapper that reports connect durations to the active [`ServerTiming`].
+#[derive(Clone)]
+pub(crate) struct TimedConnector {
+    inner: HttpConnector,
+}
+
+impl TimedConnector {
+    pub(crate) fn new(inner: HttpConnector) -> Self {
+        Self { inner }
+    }
+}
+
+impl Service<Uri> for TimedConnector {
+    type Response = TcpStream;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        // Capture the slot now: the connect future may be moved to a background task if a
+        // pooled connection becomes available first.
+        let slot = CONNECT_TIMING.try_with(|slot| slot.clone()).ok();
+        let connecting = self.inner.call(dst);
+        Box::pin(async move {
+            let begin = Instant::now();
+            let stream = connecting.await?;
+            if let Some(slot) = slot {
+                *slot.lock().unwrap() = Some(begin.elapsed());
+            }
+            Ok(stream)
+        })
+    }
+}
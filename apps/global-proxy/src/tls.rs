@@ -0,0 +1,96 @@
+use std::{sync::Arc, time::Duration};
+
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    LazyConfigAcceptor,
+    rustls::{
+        ServerConfig,
+        server::{Acceptor, ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+};
+use tracing::debug;
+
+use crate::{AppState, acme::AcmeManager, serve_connection};
+
+/// Bound on reading the ClientHello and on finishing the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bound on finding (or issuing on demand) a certificate for a handshake.
+const CERTIFICATE_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct SingleCert(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for SingleCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+/// Accepts TLS connections until shutdown begins, choosing the certificate from the SNI name
+/// before the handshake completes so on-demand issuance can run first.
+pub(crate) async fn serve(listener: TcpListener, acme: Arc<AcmeManager>, state: Arc<AppState>) {
+    loop {
+        let (tcp, peer) = tokio::select! {
+            _ = state.drain.draining() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    debug!(%err, "TLS accept failed");
+                    continue;
+                }
+            },
+        };
+        let acme = acme.clone();
+        let state = state.clone();
+        state.drain.clone().spawn(async move {
+            let Ok(Ok(start)) = tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                LazyConfigAcceptor::new(Acceptor::default(), tcp),
+            )
+            .await
+            else {
+                return;
+            };
+            let host = start
+                .client_hello()
+                .server_name()
+                .map(|name| name.to_ascii_lowercase());
+            let cert = match tokio::time::timeout(
+                CERTIFICATE_TIMEOUT,
+                acme.certificate_for(host.as_deref()),
+            )
+            .await
+            {
+                Ok(Some(cert)) => cert,
+                _ => {
+                    debug!(host = ?host, "no certificate for TLS handshake");
+                    return;
+                }
+            };
+            let mut config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(SingleCert(cert)));
+            config.alpn_protocols = if state.http2 {
+                vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+            } else {
+                vec![b"http/1.1".to_vec()]
+            };
+            let Ok(Ok(stream)) =
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, start.into_stream(Arc::new(config))).await
+            else {
+                return;
+            };
+            let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2".as_slice());
+
+            let mut http = Http::new();
+            if http2 {
+                http.http2_only(true);
+            } else {
+                http.http1_only(true);
+            }
+            serve_connection(http, stream, peer, true, state).await;
+        });
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Counts open WebSocket tunnels per preview host so a widely shared preview link cannot fan
+/// out into more connections than a small dev server can take.
+#[derive(Debug)]
+pub(crate) struct WsLimiter {
+    cap: Option<usize>,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl WsLimiter {
+    pub(crate) fn new(cap: Option<usize>) -> Self {
+        Self {
+            cap,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reserves a tunnel slot for `host`, or returns `None` when the host is at its cap. The
+    /// slot is released when the returned permit is dropped.
+    pub(crate) fn acquire(&self, host: &str) -> Option<WsPermit> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.get(host).copied().unwrap_or(0);
+        if self.cap.is_some_and(|cap| count >= cap) {
+            return None;
+        }
+        open.insert(host.to_string(), count + 1);
+        Some(WsPermit {
+            host: host.to_string(),
+            open: self.open.clone(),
+        })
+    }
+
+    pub(crate) fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct WsPermit {
+    host: String,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for WsPermit {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.host) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                open.remove(&self.host);
+            }
+        }
+    }
+}
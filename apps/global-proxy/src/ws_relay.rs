@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Close code sent when a frame or message exceeds the configured limits.
+const MESSAGE_TOO_BIG: u16 = 1009;
+/// How long the other direction keeps flowing after one side stops sending.
+const HALF_CLOSE_GRACE: Duration = Duration::from_secs(30);
+
+/// Size limits enforced while relaying. Frames are otherwise forwarded byte for byte, so
+/// extensions such as permessage-deflate negotiated between browser and backend keep working.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FrameLimits {
+    pub(crate) max_frame: Option<u64>,
+    pub(crate) max_message: Option<u64>,
+}
+
+/// Which way bytes are flowing through the tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    ToBackend,
+    ToClient,
+}
+
+/// Bytes relayed over one connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RelayStats {
+    pub(crate) to_backend: u64,
+    pub(crate) to_client: u64,
+    /// Set when the connection was closed with `1009` for breaking the size limits.
+    pub(crate) too_big: bool,
+}
+
+enum PumpError {
+    Io(io::Error),
+    TooBig,
+}
+
+impl From<io::Error> for PumpError {
+    fn from(err: io::Error) -> Self {
+        PumpError::Io(err)
+    }
+}
+
+/// Tracks frame boundaries in one direction of the byte stream. Frame headers are held back
+/// until complete so the stream can be cut cleanly between frames.
+struct FrameScanner {
+    limits: FrameLimits,
+    header: Vec<u8>,
+    /// Payload bytes of the current frame still to pass through.
+    remaining: u64,
+    /// Payload bytes of the data message in progress.
+    message: u64,
+}
+
+impl FrameScanner {
+    fn new(limits: FrameLimits) -> Self {
+        Self {
+            limits,
+            header: Vec::with_capacity(14),
+            remaining: 0,
+            message: 0,
+        }
+    }
+
+    /// Whether the forwarded stream ends between frames. Held-back header bytes have not been
+    /// forwarded, so only an unfinished payload counts.
+    fn at_boundary(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Appends the bytes of `data` that may be forwarded to `out`. Stops before the header of
+    /// a frame that breaks the limits.
+    fn feed(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> Result<(), PumpError> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let take = data
+                    .len()
+                    .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+                out.extend_from_slice(&data[..take]);
+                self.remaining -= take as u64;
+                data = &data[take..];
+                continue;
+            }
+
+            let needed = self.header_len().unwrap_or(2);
+            let take = (needed - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            match self.header_len() {
+                Some(len) if self.header.len() == len => self.finish_header(out)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Total header length, once the first two bytes are known.
+    fn header_len(&self) -> Option<usize> {
+        let second = *self.header.get(1)?;
+        let extended = match second & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if second & 0x80 != 0 { 4 } else { 0 };
+        Some(2 + extended + mask)
+    }
+
+    fn finish_header(&mut self, out: &mut Vec<u8>) -> Result<(), PumpError> {
+        let fin = self.header[0] & 0x80 != 0;
+        let opcode = self.header[0] & 0x0f;
+        let payload = match self.header[1] & 0x7f {
+            126 => u64::from(u16::from_be_bytes([self.header[2], self.header[3]])),
+            127 => u64::from_be_bytes(self.header[2..10].try_into().unwrap()),
+            len => u64::from(len),
+        };
+
+        if self.limits.max_frame.is_some_and(|max| payload > max) {
+            return Err(PumpError::TooBig);
+        }
+        // Control frames may arrive between the fragments of a data message.
+        if opcode < 0x8 {
+            if opcode != 0x0 {
+                self.message = 0;
+            }
+            self.message = self.message.saturating_add(payload);
+            if self
+                .limits
+                .max_message
+                .is_some_and(|max| self.message > max)
+            {
+                return Err(PumpError::TooBig);
+            }
+            if fin {
+                self.message = 0;
+            }
+        }
+
+        out.extend_from_slice(&self.header);
+        self.header.clear();
+        self.remaining = payload;
+        Ok(())
+    }
+}
+
+async fn pump<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    scanner: &mut FrameScanner,
+    relayed: &mut u64,
+    on_bytes: &(dyn Fn(u64) + Send + Sync),
+) -> Result<(), PumpError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut out = Vec::with_capacity(buf.len());
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        out.clear();
+        let fed = scanner.feed(&buf[..read], &mut out);
+        if !out.is_empty() {
+            writer.write_all(&out).await?;
+            writer.flush().await?;
+            *relayed += out.len() as u64;
+            on_bytes(out.len() as u64);
+        }
+        fed?;
+    }
+}
+
+/// Lets the direction still open run until its sender hangs up too, or the grace period ends.
+async fn finish_half_closed(
+    rest: impl Future<Output = Result<(), PumpError>>,
+) -> Result<(), PumpError> {
+    tokio::time::timeout(HALF_CLOSE_GRACE, rest)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// A `1009` close frame; frames sent to the backend must be masked.
+fn close_frame(masked: bool) -> Vec<u8> {
+    let mut payload = MESSAGE_TOO_BIG.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"Message too big");
+    let mut frame = vec![0x88];
+    if masked {
+        let mut key = [0u8; 4];
+        let _ = SystemRandom::new().fill(&mut key);
+        frame.push(0x80 | payload.len() as u8);
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        frame.push(payload.len() as u8);
+        frame.extend_from_slice(&payload);
+    }
+    frame
+}
+
+/// Copies frames between the upgraded connections until both sides hang up. Once one side stops
+/// sending, the other is told so and gets `HALF_CLOSE_GRACE` to finish what it is sending back.
+/// `on_bytes` is called as data flows so metrics stay current on long-lived tunnels.
+pub(crate) async fn relay<C, B>(
+    client: C,
+    backend: B,
+    limits: FrameLimits,
+    on_bytes: impl Fn(Direction, u64) + Send + Sync,
+) -> io::Result<RelayStats>
+where
+    C: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut backend_read, mut backend_write) = tokio::io::split(backend);
+    let mut upstream = FrameScanner::new(limits);
+    let mut downstream = FrameScanner::new(limits);
+    let mut stats = RelayStats::default();
+    let to_backend_bytes = |n| on_bytes(Direction::ToBackend, n);
+    let to_client_bytes = |n| on_bytes(Direction::ToClient, n);
+
+    let result = {
+        let to_backend = pump(
+            &mut client_read,
+            &mut backend_write,
+            &mut upstream,
+            &mut stats.to_backend,
+            &to_backend_bytes,
+        );
+        let to_client = pump(
+            &mut backend_read,
+            &mut client_write,
+            &mut downstream,
+            &mut stats.to_client,
+            &to_client_bytes,
+        );
+        tokio::pin!(to_backend, to_client);
+        // A pump that reached EOF has forwarded everything and shut down its write side.
+        let (res, done) = tokio::select! {
+            res = &mut to_backend => (res, Direction::ToBackend),
+            res = &mut to_client => (res, Direction::ToClient),
+        };
+        match (res, done) {
+            (Ok(()), Direction::ToBackend) => finish_half_closed(to_client).await,
+            (Ok(()), Direction::ToClient) => finish_half_closed(to_backend).await,
+            (err, _) => err,
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            let _ = client_write.shutdown().await;
+            let _ = backend_write.shutdown().await;
+            Ok(stats)
+        }
+        Err(PumpError::TooBig) => {
+            stats.too_big = true;
+            if downstream.at_boundary() {
+                let _ = client_write.write_all(&close_frame(false)).await;
+            }
+            if upstream.at_boundary() {
+                let _ = backend_write.write_all(&close_frame(true)).await;
+            }
+            let _ = client_write.shutdown().await;
+            let _ = backend_write.shutdown().await;
+            Ok(stats)
+        }
+        Err(PumpError::Io(err)) => Err(err),
+    }
+}
@@ -1,3 +1,6 @@
+// The tungstenite handshake callback signature returns a full HTTP response as its error type.
+#![allow(clippy::result_large_err)]
+
 use std::{
     net::{Ipv4Addr, SocketAddr},
     sync::{Arc, Mutex},
@@ -5,17 +8,26 @@ use std::{
 };
 
 use futures_util::{SinkExt, StreamExt};
-use global_proxy::{ProxyConfig, spawn_proxy};
+use global_proxy::{
+    AccessLogConfig, ApexSite, DEFAULT_DRAIN_TIMEOUT, ErrorPageConfig, LoopDetection, ProxyConfig,
+    RateLimit, RateLimitConfig, RequestLimits, RouteRule, ServiceWorkerConfig, spawn_proxy,
+};
 use hyper::{
     Body, Method as HyperMethod, Request, Response, Server, StatusCode,
     service::{make_service_fn, service_fn},
 };
 use reqwest::Method;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::{sync::oneshot, task::JoinHandle};
 use tokio_tungstenite::tungstenite::{
-    Message, client::IntoClientRequest, handshake::server::Request as WsHandshakeRequest,
+    Message,
+    client::IntoClientRequest,
+    handshake::{derive_accept_key, server::Request as WsHandshakeRequest},
 };
 
+/// Serializes tests whose backend must bind the fixed VS Code port.
+static VSCODE_PORT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
 struct TestProxy {
     addr: SocketAddr,
     handle: Option<global_proxy::ProxyHandle>,
@@ -24,9 +36,21 @@ struct TestProxy {
 
 impl TestProxy {
     async fn spawn() -> Self {
-        let mut config = ProxyConfig::default();
-        config.bind_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
-        config.backend_host = "127.0.0.1".to_string();
+        Self::spawn_with_config(ProxyConfig::default()).await
+    }
+
+    async fn spawn_with_config(config: ProxyConfig) -> Self {
+        let config = ProxyConfig {
+            bind_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            backend_host: "127.0.0.1".to_string(),
+            // Tests that leave a WebSocket open would otherwise wait out the full drain.
+            drain_timeout: if config.drain_timeout == DEFAULT_DRAIN_TIMEOUT {
+                Duration::from_millis(200)
+            } else {
+                config.drain_timeout
+            },
+            ..config
+        };
 
         let handle = spawn_proxy(config).await.expect("failed to start proxy");
 
@@ -43,6 +67,13 @@ impl TestProxy {
         }
     }
 
+    fn tls_addr(&self) -> SocketAddr {
+        self.handle
+            .as_ref()
+            .and_then(|handle| handle.tls_addr)
+            .expect("TLS listener")
+    }
+
     fn url(&self, path: &str) -> String {
         format!("http://{}{}", self.addr, path)
     }
@@ -398,6 +429,107 @@ async fn apex_returns_greeting() {
     proxy.shutdown().await;
 }
 
+#[tokio::test]
+async fn apex_serves_static_directory() {
+    let root = test_storage_dir("apex-static");
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+    std::fs::write(root.join("pricing.html"), "<h1>pricing</h1>").unwrap();
+    std::fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+    std::fs::write(root.join("app.css"), "body{}").unwrap();
+    std::fs::write(root.join("404.html"), "<h1>missing</h1>").unwrap();
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        apex: ApexSite::Static(global_proxy::StaticSite::new(&root)),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    for (path, body) in [
+        ("/", "<h1>home</h1>"),
+        ("/pricing", "<h1>pricing</h1>"),
+        ("/docs/", "<h1>docs</h1>"),
+        ("/docs", "<h1>docs</h1>"),
+    ] {
+        let response = proxy.request(Method::GET, "cmux.sh", path, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+        assert_eq!(response.text().await.unwrap(), body, "{path}");
+    }
+
+    let response = proxy.request(Method::GET, "cmux.sh", "/app.css", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/css; charset=utf-8"
+    );
+    assert_eq!(response.headers()["cache-control"], "public, max-age=3600");
+    assert!(response.headers().contains_key("last-modified"));
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let response = proxy
+        .request(
+            Method::GET,
+            "cmux.sh",
+            "/app.css",
+            &[("if-none-match", &etag)],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    for path in ["/nope", "/../etc/passwd", "/%2e%2e/etc/passwd"] {
+        let response = proxy.request(Method::GET, "cmux.sh", path, &[]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        assert_eq!(response.text().await.unwrap(), "<h1>missing</h1>");
+    }
+    let response = proxy.request(Method::POST, "cmux.sh", "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    // Proxy endpoints keep working on the apex.
+    let response = proxy.request(Method::GET, "cmux.sh", "/health", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("healthy"));
+
+    proxy.shutdown().await;
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn apex_redirects_keep_path_and_query() {
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        apex: ApexSite::Redirect {
+            to: "https://cmux.dev/".to_string(),
+            permanent: true,
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let response = proxy
+        .request(Method::GET, "cmux.sh", "/blog?ref=x", &[])
+        .await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        response.headers()["location"],
+        "https://cmux.dev/blog?ref=x"
+    );
+
+    proxy.shutdown().await;
+
+    let invalid = global_proxy::spawn_proxy(ProxyConfig {
+        bind_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        apex: ApexSite::Redirect {
+            to: "cmux.dev".to_string(),
+            permanent: false,
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    assert!(invalid.is_err());
+}
+
 #[tokio::test]
 async fn service_worker_route() {
     let proxy = TestProxy::spawn().await;
@@ -420,6 +552,88 @@ async fn service_worker_route() {
     proxy.shutdown().await;
 }
 
+#[tokio::test]
+async fn service_worker_template_and_version() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .header("content-type", "text/html")
+            .body(Body::from("<html><head></head><body></body></html>"))
+            .unwrap()
+    }))
+    .await;
+
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        service_worker: ServiceWorkerConfig {
+            template: Some(
+                "const v = __CMUX_SW_VERSION__;\nconst a = __CMUX_ALLOWED_HOSTS__;\nconst l = __CMUX_LOOPBACK_HOSTS__;"
+                    .to_string(),
+            ),
+            allowed_hosts: vec!["preview.example.com".to_string()],
+            loopback_hosts: vec!["localhost".to_string(), "*.localhost".to_string()],
+            version: Some("2024 06".to_string()),
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+
+    let response = proxy.request(Method::GET, &host, "/proxy-sw.js", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["etag"], "\"2024 06\"");
+    assert_eq!(
+        response.text().await.expect("body"),
+        "const v = \"2024 06\";\nconst a = [\"preview.example.com\"];\nconst l = [\"localhost\",\"*.localhost\"];"
+    );
+
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/proxy-sw.js",
+            &[("If-None-Match", "\"2024 06\"")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let body = proxy
+        .request(Method::GET, &host, "/", &[])
+        .await
+        .text()
+        .await
+        .expect("body");
+    assert!(
+        body.contains("register('/proxy-sw.js?v=2024%2006'"),
+        "{}",
+        body
+    );
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn service_worker_version_follows_script() {
+    let mut versions = Vec::new();
+    for template in ["one", "two", "one"] {
+        let proxy = TestProxy::spawn_with_config(ProxyConfig {
+            service_worker: ServiceWorkerConfig {
+                template: Some(template.to_string()),
+                ..ServiceWorkerConfig::default()
+            },
+            ..ProxyConfig::default()
+        })
+        .await;
+        let response = proxy
+            .request(Method::GET, "port-8080-test.cmux.sh", "/proxy-sw.js", &[])
+            .await;
+        versions.push(response.headers()["etag"].to_str().unwrap().to_string());
+        assert_eq!(response.text().await.expect("body"), template);
+        proxy.shutdown().await;
+    }
+    assert_ne!(versions[0], versions[1]);
+    assert_eq!(versions[0], versions[2]);
+}
+
 #[tokio::test]
 async fn port_options_preflight() {
     let proxy = TestProxy::spawn().await;
@@ -464,7 +678,7 @@ async fn port_loop_detection() {
     assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
     assert_eq!(
         response.text().await.expect("text"),
-        "Loop detected in proxy"
+        "Loop detected in proxy: global-proxy saw x-cmux-proxied: true"
     );
 
     proxy.shutdown().await;
@@ -566,7 +780,7 @@ async fn cmux_loop_detection() {
     assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
     assert_eq!(
         response.text().await.expect("text"),
-        "Loop detected in proxy"
+        "Loop detected in proxy: global-proxy saw x-cmux-proxied: true"
     );
 
     proxy.shutdown().await;
@@ -630,10 +844,92 @@ async fn workspace_loop_detection() {
     assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
     assert_eq!(
         response.text().await.expect("text"),
-        "Loop detected in proxy"
+        "Loop detected in proxy: global-proxy saw x-cmux-proxied: true"
+    );
+
+    proxy.shutdown().await;
+}
+
+#[tokio::test]
+async fn configured_loop_marker_and_hop_limit() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let backend = TestHttpBackend::serve(Arc::new({
+        let seen = seen.clone();
+        move |req: Request<Body>| {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .map(|value| value.to_str().unwrap().to_string())
+            };
+            seen.lock().unwrap().push((
+                header("x-edge-proxied"),
+                header("x-cmux-proxied"),
+                header("x-cmux-hops"),
+            ));
+            Response::new(Body::from("ok"))
+        }
+    }))
+    .await;
+
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        loop_detection: LoopDetection {
+            marker: Some(("X-Edge-Proxied".to_string(), "edge-1".to_string())),
+            max_hops: 3,
+            layer: "edge".to_string(),
+            ..LoopDetection::default()
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+
+    // Another layer's marker passes through; the hop count keeps climbing.
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/",
+            &[("X-Cmux-Proxied", "true"), ("X-Cmux-Hops", "1")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.expect("text"), "ok");
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.text().await.expect("text"), "ok");
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            (
+                Some("edge-1".to_string()),
+                Some("true".to_string()),
+                Some("2".to_string())
+            ),
+            (Some("edge-1".to_string()), None, Some("1".to_string())),
+        ]
+    );
+
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("X-Edge-Proxied", "EDGE-1")])
+        .await;
+    assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
+    assert_eq!(response.headers()["x-cmux-loop-detected-by"], "edge");
+    assert_eq!(
+        response.text().await.expect("text"),
+        "Loop detected in proxy: edge saw x-edge-proxied: edge-1"
+    );
+
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("X-Cmux-Hops", "3")])
+        .await;
+    assert_eq!(response.status(), StatusCode::LOOP_DETECTED);
+    assert_eq!(
+        response.text().await.expect("text"),
+        "Loop detected in proxy: edge saw 3 hops (max 3)"
     );
+    assert_eq!(seen.lock().unwrap().len(), 2);
 
     proxy.shutdown().await;
+    backend.shutdown().await;
 }
 
 #[tokio::test]
@@ -668,6 +964,90 @@ async fn html_responses_inject_scripts() {
     backend.shutdown().await;
 }
 
+#[tokio::test]
+async fn route_rules_transform_matching_routes() {
+    let backend = TestHttpBackend::serve(Arc::new(|req: Request<Body>| {
+        let tenant = req
+            .headers()
+            .get("x-tenant")
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let cookie = req.headers().contains_key("cookie");
+        Response::builder()
+            .header("content-type", "text/html")
+            .header("content-security-policy", "default-src 'none'")
+            .header("x-powered-by", "demo")
+            .body(Body::from(format!(
+                "<html><head></head><body>{} {}</body></html>",
+                tenant, cookie
+            )))
+            .unwrap()
+    }))
+    .await;
+
+    let rules: Vec<RouteRule> = serde_json::from_value(serde_json::json!([
+        {
+            "match": { "routes": "port", "hosts": ["port-*-docs.cmux.sh"] },
+            "request_headers": { "x-tenant": "docs" },
+            "remove_request_headers": ["cookie"],
+            "response_headers": { "x-frame-options": "DENY" },
+            "remove_response_headers": ["x-powered-by"],
+            "content_security_policy": "frame-ancestors 'none'",
+            "inject_location": false,
+            "inject_service_worker": false,
+        },
+        {
+            "match": { "routes": "workspace", "ports": [backend.port()] },
+            "strip_cors": true,
+        },
+    ]))
+    .expect("rules");
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        route_rules: rules,
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-docs.cmux.sh", backend.port());
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("Cookie", "a=b")])
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-security-policy"],
+        "frame-ancestors 'none'"
+    );
+    assert_eq!(response.headers()["x-frame-options"], "DENY");
+    assert!(!response.headers().contains_key("x-powered-by"));
+    let body = response.text().await.expect("body");
+    assert!(body.contains("docs false"), "{}", body);
+    assert!(!body.contains("__cmuxLocation"), "{}", body);
+    assert!(!body.contains("serviceWorker"), "{}", body);
+
+    // Other hosts keep the defaults.
+    let host = format!("port-{}-blog.cmux.sh", backend.port());
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("Cookie", "a=b")])
+        .await;
+    assert!(!response.headers().contains_key("content-security-policy"));
+    assert_eq!(response.headers()["x-powered-by"], "demo");
+    let body = response.text().await.expect("body");
+    assert!(body.contains(" true"), "{}", body);
+    assert!(body.contains("__cmuxLocation"), "{}", body);
+
+    let host = format!("ws-{}-vm.cmux.sh", backend.port());
+    let response = proxy.request(Method::OPTIONS, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(
+        !response
+            .headers()
+            .contains_key("access-control-allow-origin")
+    );
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
 #[tokio::test]
 async fn html_responses_skip_service_worker_for_cmux_route() {
     let backend = TestHttpBackend::serve(Arc::new(|_req| {
@@ -719,6 +1099,7 @@ async fn port_39378_strips_cors_and_applies_csp() {
         }
     });
 
+    let _port = VSCODE_PORT.lock().await;
     let backend = TestHttpBackend::serve_on_port(39_378, handler).await;
     let proxy = TestProxy::spawn().await;
 
@@ -1017,3 +1398,2245 @@ async fn websocket_proxy_for_cmux_route() {
     proxy.shutdown().await;
     backend.shutdown().await;
 }
+
+#[tokio::test]
+async fn proxied_responses_include_server_timing() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html")
+            .header("server-timing", "app;dur=1")
+            .body(Body::from(
+                "<html><head><title>Demo</title></head><body>Hello</body></html>",
+            ))
+            .unwrap()
+    }))
+    .await;
+
+    let proxy = TestProxy::spawn().await;
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let timings: Vec<String> = response
+        .headers()
+        .get_all("server-timing")
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .collect();
+    assert!(timings.iter().any(|v| v == "app;dur=1"), "{:?}", timings);
+    let ours = timings.iter().find(|v| v.contains("backend;dur=")).unwrap();
+    assert!(ours.contains("resolve;dur="), "{}", ours);
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+/// Reads an HTTP head off a raw socket, up to and including the blank line.
+async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.expect("read head");
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).expect("utf8 head")
+}
+
+#[tokio::test]
+async fn websocket_extensions_and_compressed_frames_pass_through() {
+    // A compressed text frame: FIN and RSV1 set. The proxy must not inspect the payload.
+    const FROM_BACKEND: &[u8] = &[0xc1, 0x05, 0xf2, 0x48, 0xcd, 0xc9, 0x07];
+    const FROM_CLIENT: &[u8] = &[0xc1, 0x82, 1, 2, 3, 4, 0xf3, 0x4a];
+
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .expect("bind backend");
+    let backend_port = listener.local_addr().unwrap().port();
+    let backend = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("accept");
+        let head = read_head(&mut stream).await;
+        assert!(
+            head.to_ascii_lowercase()
+                .contains("sec-websocket-extensions: permessage-deflate; client_max_window_bits"),
+            "{}",
+            head
+        );
+        let key = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("sec-websocket-key")
+                    .then_some(value)
+            })
+            .expect("key");
+        let accept = derive_accept_key(key.trim().as_bytes());
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+                    accept
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        stream.write_all(FROM_BACKEND).await.unwrap();
+        let mut frame = vec![0u8; FROM_CLIENT.len()];
+        stream.read_exact(&mut frame).await.expect("client frame");
+        frame
+    });
+
+    let proxy = TestProxy::spawn().await;
+    let mut client = tokio::net::TcpStream::connect(proxy.addr)
+        .await
+        .expect("connect");
+    client
+        .write_all(
+            format!(
+                "GET /ws HTTP/1.1\r\nHost: port-{}-test.cmux.sh\r\nConnection: Upgrade\r\n\
+                 Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+                backend_port
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await.to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(
+        head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="),
+        "{}",
+        head
+    );
+    assert!(
+        head.contains("sec-websocket-extensions: permessage-deflate"),
+        "{}",
+        head
+    );
+
+    let mut frame = vec![0u8; FROM_BACKEND.len()];
+    client.read_exact(&mut frame).await.expect("backend frame");
+    assert_eq!(frame, FROM_BACKEND);
+    client.write_all(FROM_CLIENT).await.unwrap();
+    assert_eq!(backend.await.unwrap(), FROM_CLIENT);
+
+    drop(client);
+    proxy.shutdown().await;
+}
+
+#[tokio::test]
+async fn websocket_backend_can_reply_after_client_stops_sending() {
+    const FROM_BACKEND: &[u8] = &[0x81, 0x03, b'b', b'y', b'e'];
+
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .expect("bind backend");
+    let backend_port = listener.local_addr().unwrap().port();
+    let backend = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("accept");
+        let head = read_head(&mut stream).await;
+        let key = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("sec-websocket-key")
+                    .then_some(value)
+            })
+            .expect("key");
+        let accept = derive_accept_key(key.trim().as_bytes());
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        // Only answers once the client has hung up its sending side.
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.expect("client eof");
+        stream.write_all(FROM_BACKEND).await.unwrap();
+    });
+
+    let proxy = TestProxy::spawn().await;
+    let mut client = tokio::net::TcpStream::connect(proxy.addr)
+        .await
+        .expect("connect");
+    client
+        .write_all(
+            format!(
+                "GET /ws HTTP/1.1\r\nHost: port-{}-test.cmux.sh\r\nConnection: Upgrade\r\n\
+                 Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                backend_port
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await.to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+
+    client.shutdown().await.expect("half-close");
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+        .await
+        .expect("relay ended")
+        .expect("read");
+    assert_eq!(received, FROM_BACKEND);
+    backend.await.unwrap();
+
+    proxy.shutdown().await;
+}
+
+#[tokio::test]
+async fn websocket_frames_over_limit_close_with_1009() {
+    let backend = TestWsBackend::spawn_echo().await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        websocket_max_frame_bytes: Some(1024),
+        metrics_bind_addr: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        ..ProxyConfig::default()
+    })
+    .await;
+    let metrics_addr = proxy
+        .handle
+        .as_ref()
+        .and_then(|handle| handle.metrics_addr)
+        .expect("metrics listener");
+
+    let mut request = format!("ws://{}/ws", proxy.addr)
+        .into_client_request()
+        .expect("request");
+    request.headers_mut().insert(
+        "Host",
+        format!("port-{}-test.cmux.sh", backend.port())
+            .parse()
+            .expect("host header"),
+    );
+    let (mut ws, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("connect through proxy");
+
+    ws.send(Message::Binary(vec![7; 1000])).await.expect("send");
+    let reply = ws.next().await.expect("reply").expect("message");
+    assert_eq!(reply.into_data().len(), 1000);
+
+    ws.send(Message::Binary(vec![7; 2000])).await.expect("send");
+    match ws.next().await.expect("close").expect("message") {
+        Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 1009),
+        other => panic!("expected close, got {:?}", other),
+    }
+    while ws.next().await.is_some() {}
+
+    let mut metrics = String::new();
+    for _ in 0..50 {
+        metrics = reqwest::get(format!("http://{}/metrics", metrics_addr))
+            .await
+            .expect("metrics")
+            .text()
+            .await
+            .unwrap();
+        if metrics.contains("global_proxy_websocket_open 0") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(
+        metrics.contains("global_proxy_websocket_too_big_total 1"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("global_proxy_websocket_open 0"),
+        "{}",
+        metrics
+    );
+    let bytes = |direction: &str| -> u64 {
+        let prefix = format!(
+            "global_proxy_websocket_bytes_total{{route=\"port\",direction=\"{}\"}} ",
+            direction
+        );
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .expect("bytes counter")
+            .parse()
+            .unwrap()
+    };
+    // One 1000-byte frame each way, plus headers; the oversized frame never went through.
+    assert!((1000..2000).contains(&bytes("to_backend")), "{}", metrics);
+    assert!((1000..2000).contains(&bytes("to_client")), "{}", metrics);
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn websocket_upgrades_over_per_host_cap_are_refused() {
+    let backend = TestWsBackend::spawn_echo().await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        max_websockets_per_host: Some(1),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+    let connect = || {
+        let mut request = format!("ws://{}/ws", proxy.addr)
+            .into_client_request()
+            .expect("request");
+        request
+            .headers_mut()
+            .insert("Host", host.parse().expect("host header"));
+        tokio_tungstenite::connect_async(request)
+    };
+
+    let (mut first, _) = connect().await.expect("first connection");
+    first.send(Message::Text("one".into())).await.expect("send");
+    let reply = first.next().await.expect("reply").expect("message");
+    assert_eq!(reply.into_text().unwrap(), "one");
+
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/ws",
+            &[
+                ("Connection", "Upgrade"),
+                ("Upgrade", "websocket"),
+                ("Sec-WebSocket-Version", "13"),
+                ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        response
+            .headers()
+            .get("retry-after")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        "5"
+    );
+    let body: serde_json::Value = response.json().await.expect("json body");
+    assert_eq!(body["error"], "too_many_websockets");
+    assert_eq!(body["limit"], 1);
+
+    // Other preview hosts are counted separately.
+    let other = format!("port-{}-other.cmux.sh", backend.port());
+    let mut request = format!("ws://{}/ws", proxy.addr)
+        .into_client_request()
+        .expect("request");
+    request
+        .headers_mut()
+        .insert("Host", other.parse().expect("host header"));
+    let (mut second, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("other host connection");
+    second.close(None).await.unwrap();
+
+    // Closing the first tunnel frees its slot.
+    first.close(None).await.unwrap();
+    while first.next().await.is_some() {}
+    let mut reconnected = None;
+    for _ in 0..50 {
+        if let Ok((ws, _)) = connect().await {
+            reconnected = Some(ws);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut ws = reconnected.expect("slot released after close");
+    ws.close(None).await.unwrap();
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn configured_cors_origins_and_frame_ancestors() {
+    let handler = Arc::new(|_req: Request<Body>| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("ok"))
+            .unwrap()
+    });
+    let _port = VSCODE_PORT.lock().await;
+    let backend = TestHttpBackend::serve(handler.clone()).await;
+    let vscode = TestHttpBackend::serve_on_port(39_378, handler).await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        cors_allowed_origins: vec!["https://app.example.com".to_string()],
+        frame_ancestors: vec!["https://app.example.com".to_string()],
+        ..ProxyConfig::default()
+    })
+    .await;
+    let host = format!("cmux-test-base-{}.cmux.sh", backend.port());
+
+    let allowed = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/",
+            &[("Origin", "https://app.example.com")],
+        )
+        .await;
+    assert_eq!(allowed.status(), StatusCode::OK);
+    assert_eq!(
+        allowed
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(allowed.headers().get("vary").unwrap(), "Origin");
+
+    let preflight = proxy
+        .request(
+            Method::OPTIONS,
+            &host,
+            "/",
+            &[("Origin", "https://app.example.com")],
+        )
+        .await;
+    assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        preflight
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://app.example.com"
+    );
+
+    for origin in ["https://cmux.dev", "https://evil.example"] {
+        let refused = proxy
+            .request(Method::GET, &host, "/", &[("Origin", origin)])
+            .await;
+        assert_eq!(refused.status(), StatusCode::OK);
+        assert!(
+            refused
+                .headers()
+                .get("access-control-allow-origin")
+                .is_none()
+        );
+        let preflight = proxy
+            .request(Method::OPTIONS, &host, "/", &[("Origin", origin)])
+            .await;
+        assert!(
+            preflight
+                .headers()
+                .get("access-control-allow-origin")
+                .is_none()
+        );
+    }
+
+    let framed = proxy
+        .request(Method::GET, "port-39378-test.cmux.sh", "/", &[])
+        .await;
+    assert_eq!(framed.status(), StatusCode::OK);
+    assert_eq!(
+        framed.headers().get("content-security-policy").unwrap(),
+        "frame-ancestors 'self' https://app.example.com;"
+    );
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+    vscode.shutdown().await;
+}
+
+#[tokio::test]
+async fn configured_html_injections() {
+    use global_proxy::{HtmlInjections, HtmlSnippet, InjectPosition, RouteClasses};
+
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html")
+            .body(Body::from(
+                "<html><head><title>Demo</title></head><body>Hello</body></html>",
+            ))
+            .unwrap()
+    }))
+    .await;
+
+    let snippet = |html: &str, position, routes: &str| HtmlSnippet {
+        html: html.to_string(),
+        position,
+        routes: routes.parse().unwrap(),
+    };
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        html_injections: HtmlInjections {
+            location: "cmux,workspace".parse().unwrap(),
+            service_worker: RouteClasses::ALL,
+            snippets: vec![
+                snippet("<i>first</i>", InjectPosition::HeadStart, "all"),
+                snippet("<i>second</i>", InjectPosition::HeadStart, "port"),
+                snippet("<i>head-end</i>", InjectPosition::HeadEnd, "port"),
+                snippet("<i>body-start</i>", InjectPosition::BodyStart, "port"),
+                snippet("<i>body-end</i>", InjectPosition::BodyEnd, "port"),
+                snippet("<i>cmux-only</i>", InjectPosition::HeadEnd, "cmux"),
+            ],
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.text().await.expect("body");
+    assert!(!body.contains("window.__cmuxLocation"));
+    assert!(!body.contains("cmux-only"));
+    let position = |needle: &str| {
+        body.find(needle)
+            .unwrap_or_else(|| panic!("missing {needle} in {body}"))
+    };
+    assert!(position("navigator.serviceWorker.register") < position("<i>first</i>"));
+    assert!(position("<i>first</i>") < position("<i>second</i>"));
+    assert!(position("<title>") < position("<i>head-end</i>"));
+    assert!(position("<i>head-end</i>") < position("</head>"));
+    assert!(position("<body>") < position("<i>body-start</i>"));
+    assert!(position("<i>body-start</i>") < position("Hello"));
+    assert!(position("Hello") < position("<i>body-end</i>"));
+
+    let host = format!("cmux-demo-{}.cmux.sh", backend.port());
+    let body = proxy
+        .request(Method::GET, &host, "/", &[])
+        .await
+        .text()
+        .await
+        .expect("body");
+    assert!(body.contains("window.__cmuxLocation"));
+    assert!(body.contains("<i>first</i>"));
+    assert!(body.contains("<i>cmux-only</i>"));
+    assert!(!body.contains("<i>second</i>"));
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn html_injection_streams_without_buffering() {
+    // The backend holds back the end of the page until the test has seen the injected head.
+    let (rest_tx, rest_rx) = oneshot::channel::<()>();
+    let rest_rx = Arc::new(Mutex::new(Some(rest_rx)));
+    let backend = TestHttpBackend::serve(Arc::new(move |_req| {
+        let (mut tx, body) = Body::channel();
+        let rest_rx = rest_rx.lock().unwrap().take();
+        tokio::spawn(async move {
+            let _ = tx
+                .send_data("<html><head><title>Demo</title></head><body>".into())
+                .await;
+            if let Some(rest_rx) = rest_rx {
+                let _ = rest_rx.await;
+            }
+            let _ = tx.send_data("Hello</body></html>".into()).await;
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html")
+            .body(body)
+            .unwrap()
+    }))
+    .await;
+
+    let proxy = TestProxy::spawn().await;
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+    let mut response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("content-length").is_none());
+
+    let mut received = String::new();
+    while !received.contains("</head>") {
+        let chunk = tokio::time::timeout(Duration::from_secs(2), response.chunk())
+            .await
+            .expect("head was buffered")
+            .expect("chunk")
+            .expect("body ended early");
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    assert!(received.contains("window.__cmuxLocation"));
+    assert!(!received.contains("Hello"));
+
+    rest_tx.send(()).unwrap();
+    received.push_str(&response.text().await.expect("rest of body"));
+    assert!(received.ends_with("Hello</body></html>"), "{}", received);
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn custom_base_domains_route_all_host_patterns() {
+    let backend = TestHttpBackend::serve(Arc::new(|req| {
+        let workspace = req
+            .headers()
+            .get("x-cmux-workspace-internal")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(workspace))
+            .unwrap()
+    }))
+    .await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        base_domains: vec![
+            "Example.co.uk".to_string(),
+            ".preview.example.co.uk.".to_string(),
+        ],
+        ..ProxyConfig::default()
+    })
+    .await;
+    let port = backend.port();
+
+    for apex in ["example.co.uk", "preview.example.co.uk"] {
+        let response = proxy.request(Method::GET, apex, "/", &[]).await;
+        assert_eq!(response.text().await.expect("text"), "cmux!");
+    }
+
+    // The longest base domain wins, so these hosts route as plain `port-…` subdomains.
+    for host in [
+        format!("port-{port}-abc.example.co.uk"),
+        format!("port-{port}-abc.preview.example.co.uk"),
+    ] {
+        let response = proxy.request(Method::GET, &host, "/", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", host);
+        assert_eq!(response.text().await.expect("text"), "-");
+    }
+
+    let response = proxy
+        .request(
+            Method::GET,
+            &format!("cmux-abc-demo-{port}.preview.example.co.uk"),
+            "/",
+            &[],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.expect("text"), "demo");
+
+    let response = proxy
+        .request(
+            Method::GET,
+            &format!("my-ws-{port}-vm.example.co.uk"),
+            "/",
+            &[],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.expect("text"), "my-ws");
+
+    for host in [
+        format!("port-{port}-abc.cmux.sh"),
+        format!("port-{port}-abc.notexample.co.uk"),
+    ] {
+        let response = proxy.request(Method::GET, &host, "/", &[]).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{}", host);
+        assert_eq!(response.text().await.expect("text"), "Not a cmux domain");
+    }
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+/// Sends each slug to its own backend regardless of the requested port.
+#[derive(Debug, Default)]
+struct TableResolver {
+    backends: std::collections::HashMap<String, SocketAddr>,
+    calls: Mutex<Vec<(String, u16)>>,
+}
+
+impl global_proxy::BackendResolver for TableResolver {
+    fn resolve<'a>(
+        &'a self,
+        slug: &'a str,
+        port: u16,
+    ) -> futures_util::future::BoxFuture<'a, Result<SocketAddr, String>> {
+        self.calls.lock().unwrap().push((slug.to_string(), port));
+        let result = self
+            .backends
+            .get(slug)
+            .copied()
+            .ok_or_else(|| format!("unknown vm {}", slug));
+        Box::pin(async move { result })
+    }
+}
+
+#[tokio::test]
+async fn backend_resolver_routes_each_vm_slug() {
+    let backend_a = TestHttpBackend::serve(Arc::new(|req| {
+        let workspace = req
+            .headers()
+            .get("x-cmux-workspace-internal")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(format!("a {}", workspace)))
+            .unwrap()
+    }))
+    .await;
+    let backend_b = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("b"))
+            .unwrap()
+    }))
+    .await;
+    let resolver = Arc::new(TableResolver {
+        backends: [
+            (
+                "vma".to_string(),
+                SocketAddr::from((Ipv4Addr::LOCALHOST, backend_a.port())),
+            ),
+            (
+                "vmb".to_string(),
+                SocketAddr::from((Ipv4Addr::LOCALHOST, backend_b.port())),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        calls: Mutex::new(Vec::new()),
+    });
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        backend_resolver: Some(resolver.clone()),
+        // Ignored in favour of the resolver.
+        morph_domain_suffix: Some(".http.cloud.morph.so".to_string()),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    for (host, expected) in [
+        ("port-8080-vma.cmux.sh", "a -"),
+        ("port-8080-vmb.cmux.sh", "b"),
+        ("cmux-vma-demo-3000.cmux.sh", "a demo"),
+        ("my-ws-5173-vmb.cmux.sh", "b"),
+    ] {
+        let response = proxy.request(Method::GET, host, "/", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", host);
+        assert_eq!(response.text().await.expect("text"), expected, "{}", host);
+    }
+    assert_eq!(
+        *resolver.calls.lock().unwrap(),
+        vec![
+            ("vma".to_string(), 8080),
+            ("vmb".to_string(), 8080),
+            ("vma".to_string(), 3000),
+            ("vmb".to_string(), 5173),
+        ]
+    );
+
+    let response = proxy
+        .request(Method::GET, "port-8080-unknown.cmux.sh", "/", &[])
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        response.text().await.expect("text"),
+        "Failed to resolve backend"
+    );
+
+    proxy.shutdown().await;
+    backend_a.shutdown().await;
+    backend_b.shutdown().await;
+}
+
+#[tokio::test]
+async fn static_resolver_table_uses_requested_port() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("static"))
+            .unwrap()
+    }))
+    .await;
+    let resolver = global_proxy::StaticResolver::from_table(
+        "# slug  address\n\nVM-A 127.0.0.1  # local vm\nvm-b ::1\n",
+    )
+    .expect("table");
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        backend_resolver: Some(Arc::new(resolver)),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-vm-a.cmux.sh", backend.port());
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.expect("text"), "static");
+
+    let host = format!("port-{}-vm-c.cmux.sh", backend.port());
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    assert!(global_proxy::StaticResolver::from_table("vm-a").is_err());
+    assert!(global_proxy::StaticResolver::from_table("vm-a not-an-ip").is_err());
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn metrics_listener_reports_route_counters() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html")
+            .body(Body::from("<html><head></head><body>hi</body></html>"))
+            .unwrap()
+    }))
+    .await;
+    let ws_backend = TestWsBackend::spawn_echo().await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        metrics_bind_addr: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        ..ProxyConfig::default()
+    })
+    .await;
+    let metrics_addr = proxy
+        .handle
+        .as_ref()
+        .and_then(|handle| handle.metrics_addr)
+        .expect("metrics listener");
+
+    // `/healthz` answers on the main listener except on preview hosts, which own their paths.
+    let response = proxy.request(Method::GET, "cmux.sh", "/healthz", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "ok");
+    let host = format!("port-{}-abc.cmux.sh", backend.port());
+    let response = proxy.request(Method::GET, &host, "/healthz", &[]).await;
+    assert!(response.text().await.unwrap().contains("hi"));
+
+    let response = proxy
+        .request(Method::GET, "port-1-abc.cmux.sh", "/", &[])
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let response = proxy.request(Method::GET, "example.com", "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    let mut request = format!("ws://{}/ws", proxy.addr)
+        .into_client_request()
+        .expect("request");
+    request.headers_mut().insert(
+        "Host",
+        format!("cmux-abc-base-{}.cmux.sh", ws_backend.port())
+            .parse()
+            .unwrap(),
+    );
+    let (mut ws, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("connect through proxy");
+    ws.close(None).await.unwrap();
+
+    let client = reqwest::Client::new();
+    let healthz = client
+        .get(format!("http://{}/healthz", metrics_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(healthz.text().await.unwrap(), "ok");
+    let response = client
+        .get(format!("http://{}/metrics", metrics_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/plain; version=0.0.4"
+    );
+    let body = response.text().await.unwrap();
+    for line in [
+        "global_proxy_requests_total{route=\"apex\",status=\"2xx\"} 1",
+        "global_proxy_requests_total{route=\"port\",status=\"2xx\"} 1",
+        "global_proxy_requests_total{route=\"port\",status=\"5xx\"} 1",
+        "global_proxy_requests_total{route=\"other\",status=\"5xx\"} 1",
+        "global_proxy_requests_total{route=\"cmux\",status=\"1xx\"} 1",
+        "global_proxy_request_duration_seconds_count{route=\"port\"} 2",
+        "global_proxy_request_duration_seconds_bucket{route=\"port\",le=\"+Inf\"} 2",
+        "global_proxy_upstream_errors_total{route=\"port\"} 1",
+        "global_proxy_websocket_upgrades_total{route=\"cmux\"} 1",
+        "global_proxy_websocket_rejected_total 0",
+        "global_proxy_html_injections_total{route=\"port\"} 1",
+    ] {
+        assert!(body.lines().any(|l| l == line), "missing {line}\n{body}");
+    }
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+    ws_backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn immutable_assets_are_cached_in_memory_and_on_disk() {
+    let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let backend_hits = hits.clone();
+    let backend = TestHttpBackend::serve(Arc::new(move |req| {
+        backend_hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let (content_type, cache_control) = match req.uri().path() {
+            "/assets/app-1a2b.js" => ("text/javascript", "public, max-age=31536000, immutable"),
+            "/short.js" => ("text/javascript", "max-age=60"),
+            "/index.html" => ("text/html", "max-age=31536000, immutable"),
+            _ => ("text/plain", "no-store"),
+        };
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", content_type)
+            .header("cache-control", cache_control)
+            .header("content-length", "12")
+            .body(Body::from("console.log;"))
+            .unwrap()
+    }))
+    .await;
+    let storage = test_storage_dir("cache");
+    let mut cache = global_proxy::CacheConfig::new(1024 * 1024);
+    cache.disk_dir = Some(storage.clone());
+    let config = ProxyConfig {
+        cache: Some(cache),
+        metrics_bind_addr: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        ..ProxyConfig::default()
+    };
+    let proxy = TestProxy::spawn_with_config(config.clone()).await;
+    let host = format!("port-{}-abc.cmux.sh", backend.port());
+    let backend_count = || hits.load(std::sync::atomic::Ordering::SeqCst);
+
+    let response = proxy
+        .request(Method::GET, &host, "/assets/app-1a2b.js", &[])
+        .await;
+    assert_eq!(response.headers()["x-cmux-cache"], "MISS");
+    assert_eq!(response.text().await.unwrap(), "console.log;");
+    let response = proxy
+        .request(Method::GET, &host, "/assets/app-1a2b.js", &[])
+        .await;
+    assert_eq!(response.headers()["x-cmux-cache"], "HIT");
+    assert!(response.headers().contains_key("age"));
+    assert_eq!(response.text().await.unwrap(), "console.log;");
+    assert_eq!(backend_count(), 1);
+
+    // Short-lived, uncacheable, HTML and ranged responses always go upstream.
+    for (path, headers) in [
+        ("/short.js", vec![]),
+        ("/api", vec![]),
+        ("/index.html", vec![]),
+        ("/assets/app-1a2b.js", vec![("range", "bytes=0-1")]),
+    ] {
+        let before = backend_count();
+        for _ in 0..2 {
+            let response = proxy.request(Method::GET, &host, path, &headers).await;
+            assert!(!response.headers().contains_key("x-cmux-cache"), "{path}");
+            response.text().await.unwrap();
+        }
+        assert_eq!(backend_count() - before, 2, "{path}");
+    }
+
+    // Entries are per preview host.
+    let other = format!("port-{}-other.cmux.sh", backend.port());
+    let response = proxy
+        .request(Method::GET, &other, "/assets/app-1a2b.js", &[])
+        .await;
+    assert_eq!(response.headers()["x-cmux-cache"], "MISS");
+    response.text().await.unwrap();
+
+    let metrics_addr = proxy.handle.as_ref().unwrap().metrics_addr.unwrap();
+    let body = reqwest::get(format!("http://{}/metrics", metrics_addr))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("global_proxy_cache_requests_total{result=\"hit\"} 1\n"));
+    assert!(body.contains("global_proxy_cache_stores_total 2\n"));
+    proxy.shutdown().await;
+
+    // A new instance serves the asset from disk without the backend.
+    let port = backend.port();
+    backend.shutdown().await;
+    let proxy = TestProxy::spawn_with_config(config).await;
+    let response = proxy
+        .request(
+            Method::GET,
+            &format!("port-{}-abc.cmux.sh", port),
+            "/assets/app-1a2b.js",
+            &[],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-cmux-cache"], "HIT");
+    assert_eq!(response.text().await.unwrap(), "console.log;");
+
+    proxy.shutdown().await;
+    let _ = std::fs::remove_dir_all(&storage);
+}
+
+#[tokio::test]
+async fn rate_limits_refuse_with_429_per_ip_and_per_slug() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("ok"))
+            .unwrap()
+    }))
+    .await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        rate_limit: RateLimitConfig {
+            per_ip: Some("2/min".parse().expect("limit")),
+            per_slug: Some("3/min".parse().expect("limit")),
+            forwarded_hops: 1,
+        },
+        metrics_bind_addr: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        ..ProxyConfig::default()
+    })
+    .await;
+    let host_a = format!("port-{}-vma.cmux.sh", backend.port());
+    let host_b = format!("port-{}-vmb.cmux.sh", backend.port());
+
+    for _ in 0..2 {
+        let response = proxy
+            .request(
+                Method::GET,
+                &host_a,
+                "/",
+                &[("x-forwarded-for", "10.0.0.1")],
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = proxy
+        .request(
+            Method::GET,
+            &host_b,
+            "/",
+            &[("x-forwarded-for", "10.0.0.1")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=30).contains(&retry_after), "{retry_after}");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "rate_limited");
+    assert_eq!(body["scope"], "ip");
+
+    // Only the entry appended by the trusted hop counts; a spoofed prefix is ignored.
+    let response = proxy
+        .request(
+            Method::GET,
+            &host_a,
+            "/",
+            &[("x-forwarded-for", "10.0.0.1, 10.0.0.2")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = proxy
+        .request(
+            Method::GET,
+            &host_a,
+            "/",
+            &[("x-forwarded-for", "10.0.0.3")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["scope"], "slug");
+
+    // Apex requests are not limited.
+    for _ in 0..3 {
+        let response = proxy
+            .request(
+                Method::GET,
+                "cmux.sh",
+                "/",
+                &[("x-forwarded-for", "10.0.0.1")],
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let metrics_addr = proxy
+        .handle
+        .as_ref()
+        .and_then(|handle| handle.metrics_addr)
+        .expect("metrics listener");
+    let body = reqwest::get(format!("http://{}/metrics", metrics_addr))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    for line in [
+        "global_proxy_rate_limited_total{scope=\"ip\"} 1",
+        "global_proxy_rate_limited_total{scope=\"slug\"} 1",
+    ] {
+        assert!(body.lines().any(|l| l == line), "missing {line}\n{body}");
+    }
+
+    assert!("0/min".parse::<RateLimit>().is_err());
+    assert!("10/fortnight".parse::<RateLimit>().is_err());
+    assert_eq!(
+        "1000/10m".parse::<RateLimit>().unwrap(),
+        RateLimit {
+            requests: 1000,
+            window: Duration::from_secs(600),
+        }
+    );
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn unreachable_backends_get_error_page_by_accept() {
+    let resolver = global_proxy::StaticResolver::from_table("vm-a 127.0.0.1\n").expect("table");
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        backend_resolver: Some(Arc::new(resolver)),
+        error_page: ErrorPageConfig {
+            retry_after: Duration::from_secs(7),
+            ..ErrorPageConfig::default()
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    let html = &[("accept", "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8")];
+
+    // Nothing listens on port 1.
+    let response = proxy
+        .request(Method::GET, "port-1-vm-a.cmux.sh", "/", html)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(response.headers()["retry-after"], "7");
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    let body = response.text().await.unwrap();
+    assert!(body.contains("<h1>Workspace is waking up</h1>"), "{body}");
+    assert!(body.contains("port-1-vm-a.cmux.sh"), "{body}");
+    assert!(body.contains("var delay = 7;"), "{body}");
+
+    let response = proxy
+        .request(Method::GET, "port-1-vm-b.cmux.sh", "/", html)
+        .await;
+    assert!(
+        response
+            .text()
+            .await
+            .unwrap()
+            .contains("<h1>Workspace is not running</h1>")
+    );
+
+    let response = proxy
+        .request(
+            Method::GET,
+            "port-1-vm-b.cmux.sh",
+            "/api",
+            &[("accept", "application/json")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "workspace_not_running");
+    assert_eq!(body["host"], "port-1-vm-b.cmux.sh");
+    assert_eq!(body["retry_after"], 7);
+
+    let response = proxy
+        .request(
+            Method::GET,
+            "port-1-vm-a.cmux.sh",
+            "/",
+            &[("accept", "*/*")],
+        )
+        .await;
+    assert_eq!(response.text().await.unwrap(), "Upstream fetch failed");
+    proxy.shutdown().await;
+
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        error_page: ErrorPageConfig {
+            template: Some(
+                "<p>__CMUX_ERROR_STATUS__ __CMUX_ERROR_TITLE__ on __CMUX_ERROR_HOST__</p>"
+                    .to_string(),
+            ),
+            ..ErrorPageConfig::default()
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    let response = proxy
+        .request(Method::GET, "my-ws-1-<b>.cmux.sh", "/", html)
+        .await;
+    assert_eq!(response.headers()["retry-after"], "5");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "<p>502 Workspace is waking up on my-ws-1-&lt;b&gt;.cmux.sh</p>"
+    );
+    proxy.shutdown().await;
+}
+
+#[tokio::test]
+async fn access_log_samples_requests_and_exports_otlp() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("hello"))
+            .unwrap()
+    }))
+    .await;
+    let exports: Arc<Mutex<Vec<(Option<String>, serde_json::Value)>>> = Arc::default();
+    let collector = TestHttpBackend::serve(Arc::new({
+        let exports = exports.clone();
+        move |req: Request<Body>| {
+            let exports = exports.clone();
+            let api_key = req
+                .headers()
+                .get("x-api-key")
+                .map(|value| value.to_str().unwrap().to_string());
+            tokio::spawn(async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let payload = serde_json::from_slice(&body).unwrap();
+                exports.lock().unwrap().push((api_key, payload));
+            });
+            Response::new(Body::empty())
+        }
+    }))
+    .await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        access_log: Some(AccessLogConfig {
+            sample_rate: 0.5,
+            otlp_endpoint: Some(format!("http://{}/v1/logs", collector.addr)),
+            otlp_headers: vec![("x-api-key".to_string(), "secret".to_string())],
+            ..AccessLogConfig::default()
+        }),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-vma.cmux.sh", backend.port());
+    for _ in 0..4 {
+        let response = proxy
+            .request(
+                Method::GET,
+                &host,
+                "/app.js",
+                &[("user-agent", "test-agent")],
+            )
+            .await;
+        assert_eq!(response.text().await.unwrap(), "hello");
+    }
+    // Server errors are logged regardless of sampling.
+    let response = proxy
+        .request(Method::GET, "port-1-vmb.cmux.sh", "/", &[])
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    response.bytes().await.unwrap();
+    proxy.shutdown().await;
+
+    let mut records = Vec::new();
+    for _ in 0..50 {
+        records = exports
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(api_key, payload)| {
+                assert_eq!(api_key.as_deref(), Some("secret"));
+                assert_eq!(
+                    payload["resourceLogs"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+                    "global-proxy"
+                );
+                payload["resourceLogs"][0]["scopeLogs"][0]["logRecords"]
+                    .as_array()
+                    .unwrap()
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        if records.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(records.len(), 3, "{records:?}");
+
+    let attribute = |record: &serde_json::Value, key: &str| {
+        record["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attr| attr["key"] == key)
+            .map(|attr| attr["value"].clone())
+            .unwrap_or_default()
+    };
+    let (errors, successes): (Vec<_>, Vec<_>) = records
+        .iter()
+        .partition(|record| record["severityText"] == "ERROR");
+    assert_eq!(successes.len(), 2);
+    for record in successes {
+        assert_eq!(attribute(record, "server.address")["stringValue"], host);
+        assert_eq!(attribute(record, "cmux.route")["stringValue"], "port");
+        assert_eq!(attribute(record, "cmux.slug")["stringValue"], "vma");
+        assert_eq!(
+            attribute(record, "cmux.port")["intValue"],
+            backend.port().to_string()
+        );
+        assert_eq!(attribute(record, "url.path")["stringValue"], "/app.js");
+        assert_eq!(
+            attribute(record, "http.response.status_code")["intValue"],
+            "200"
+        );
+        assert_eq!(
+            attribute(record, "http.response.body.size")["intValue"],
+            "5"
+        );
+        assert_eq!(
+            attribute(record, "user_agent.original")["stringValue"],
+            "test-agent"
+        );
+        assert!(attribute(record, "http.server.request.duration")["doubleValue"].is_f64());
+    }
+    assert_eq!(errors.len(), 1);
+    assert_eq!(attribute(errors[0], "cmux.slug")["stringValue"], "vmb");
+
+    backend.shutdown().await;
+    collector.shutdown().await;
+}
+
+fn preview_token(secret: &[u8], claims: serde_json::Value) -> String {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let signed = format!(
+        "{}.{}",
+        b64.encode(br#"{"alg":"HS256","typ":"JWT"}"#),
+        b64.encode(claims.to_string())
+    );
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret);
+    let signature = ring::hmac::sign(&key, signed.as_bytes());
+    format!("{}.{}", signed, b64.encode(signature.as_ref()))
+}
+
+#[tokio::test]
+async fn preview_auth_requires_control_plane_token() {
+    let backend = TestHttpBackend::serve(Arc::new(|req| {
+        let cookie = req
+            .headers()
+            .get("cookie")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(cookie))
+            .unwrap()
+    }))
+    .await;
+    let secret = b"preview-secret";
+    let mut auth = global_proxy::AuthConfig::new(secret.to_vec());
+    auth.issuer = Some("cmux".to_string());
+    auth.login_url = Some("https://cmux.sh/preview-login".to_string());
+    auth.public = vec!["demo".to_string()];
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        auth: Some(auth),
+        ..ProxyConfig::default()
+    })
+    .await;
+    let port = backend.port();
+    let host = format!("port-{port}-abc.cmux.sh");
+    let exp = chrono::Utc::now().timestamp() + 3600;
+    let token = preview_token(
+        secret,
+        serde_json::json!({ "iss": "cmux", "exp": exp, "vms": ["abc"] }),
+    );
+
+    // API clients get a 401; browsers are sent to the login page.
+    let response = proxy.request(Method::GET, &host, "/api?x=1", &[]).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/app?x=1",
+            &[("accept", "text/html"), ("x-forwarded-proto", "https")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers()["location"],
+        format!(
+            "https://cmux.sh/preview-login?redirect=https%3A%2F%2Fport%2D{port}%2Dabc%2Ecmux%2Esh%2Fapp%3Fx%3D1"
+        )
+        .as_str()
+    );
+
+    // Coming back from the login page moves the token into a cookie.
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            &format!("/app?x=1&cmux_preview_token={token}"),
+            &[("x-forwarded-proto", "https")],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers()["location"],
+        format!("https://{host}/app?x=1").as_str()
+    );
+    let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(set_cookie.starts_with(&format!("cmux_preview_token={token}; Path=/;")));
+    assert!(set_cookie.contains("HttpOnly") && set_cookie.contains("Secure"));
+
+    // The token is accepted from the cookie or header and never forwarded.
+    let cookie = format!("theme=dark; cmux_preview_token={token}; lang=en");
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("cookie", &cookie)])
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "theme=dark; lang=en");
+    let response = proxy
+        .request(
+            Method::GET,
+            &host,
+            "/",
+            &[("x-cmux-preview-token", token.as_str())],
+        )
+        .await;
+    assert_eq!(response.text().await.unwrap(), "-");
+
+    // Tokens for other VMs, from other issuers, expired or signed with another key fail.
+    for bad in [
+        token.clone(),
+        preview_token(secret, serde_json::json!({ "iss": "other", "exp": exp })),
+        preview_token(
+            secret,
+            serde_json::json!({ "iss": "cmux", "exp": exp - 7200 }),
+        ),
+        preview_token(b"wrong", serde_json::json!({ "iss": "cmux", "exp": exp })),
+    ] {
+        let response = proxy
+            .request(
+                Method::GET,
+                &format!("port-{port}-xyz.cmux.sh"),
+                "/",
+                &[("x-cmux-preview-token", bad.as_str())],
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Workspace previews are protected too; public slugs and cmux routes are not.
+    let response = proxy
+        .request(Method::GET, &format!("ws-{port}-abc.cmux.sh"), "/", &[])
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    for host in [
+        format!("port-{port}-demo.cmux.sh"),
+        format!("cmux-abc-base-{port}.cmux.sh"),
+    ] {
+        let response = proxy.request(Method::GET, &host, "/", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", host);
+    }
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
+
+fn test_storage_dir(name: &str) -> std::path::PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("global-proxy-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn fixture(name: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+#[derive(Default)]
+struct MockAcmeState {
+    base: String,
+    proxy_addr: Option<SocketAddr>,
+    nonce: u64,
+    thumbprint: Option<String>,
+    domains: Vec<String>,
+    validated: bool,
+    finalized: bool,
+    errors: Vec<String>,
+}
+
+/// A minimal ACME CA: one order at a time, no signature checks, and the fixture certificate
+/// handed out regardless of the CSR. It does validate HTTP-01 against the proxy and DNS-01
+/// against [`RecordingDns`].
+struct MockAcme {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockAcmeState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl MockAcme {
+    async fn spawn(dns: RecordingDns) -> Self {
+        let listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .expect("bind mock acme");
+        listener.set_nonblocking(true).expect("set nonblocking");
+        let addr = listener.local_addr().expect("local addr");
+        let state = Arc::new(Mutex::new(MockAcmeState {
+            base: format!("http://{}", addr),
+            ..MockAcmeState::default()
+        }));
+
+        let svc_state = state.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let state = svc_state.clone();
+            let dns = dns.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let state = state.clone();
+                    let dns = dns.clone();
+                    async move { Ok::<_, hyper::Error>(mock_acme_handle(state, dns, req).await) }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener)
+            .expect("server from tcp")
+            .serve(make_svc);
+        let (tx, rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let _ = server
+                .with_graceful_shutdown(async {
+                    let _ = rx.await;
+                })
+                .await;
+        });
+        Self {
+            addr,
+            state,
+            shutdown: Some(tx),
+            task,
+        }
+    }
+
+    fn directory_url(&self) -> String {
+        format!("http://{}/dir", self.addr)
+    }
+
+    async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+fn acme_reply(
+    state: &mut MockAcmeState,
+    status: StatusCode,
+    body: serde_json::Value,
+) -> Response<Body> {
+    state.nonce += 1;
+    let mut builder = Response::builder()
+        .status(status)
+        .header("replay-nonce", format!("nonce-{}", state.nonce))
+        .header("content-type", "application/json");
+    if status == StatusCode::CREATED {
+        let location = if body.get("identifiers").is_some() {
+            format!("{}/order/1", state.base)
+        } else {
+            format!("{}/account/1", state.base)
+        };
+        builder = builder.header("location", location);
+    }
+    builder.body(Body::from(body.to_string())).unwrap()
+}
+
+fn jws_part(jws: &serde_json::Value, part: &str) -> serde_json::Value {
+    use base64::Engine;
+    let encoded = jws[part].as_str().unwrap_or_default();
+    if encoded.is_empty() {
+        return serde_json::Value::Null;
+    }
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded)
+        .expect("base64url");
+    serde_json::from_slice(&decoded).expect("jws json")
+}
+
+fn sha256_b64url(input: &str) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        input.as_bytes(),
+    ))
+}
+
+async fn mock_acme_handle(
+    state: Arc<Mutex<MockAcmeState>>,
+    dns: RecordingDns,
+    req: Request<Body>,
+) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    if req.method() == HyperMethod::HEAD {
+        let mut state = state.lock().unwrap();
+        return acme_reply(&mut state, StatusCode::OK, serde_json::Value::Null);
+    }
+    if path == "/dir" {
+        let base = state.lock().unwrap().base.clone();
+        return Response::new(Body::from(
+            serde_json::json!({
+                "newNonce": format!("{base}/nonce"),
+                "newAccount": format!("{base}/account"),
+                "newOrder": format!("{base}/order"),
+            })
+            .to_string(),
+        ));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+    let jws: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    let protected = jws_part(&jws, "protected");
+    let payload = jws_part(&jws, "payload");
+    {
+        let mut state = state.lock().unwrap();
+        if protected["alg"] != "ES256" || protected["nonce"].as_str().is_none() {
+            state
+                .errors
+                .push(format!("bad protected header on {}", path));
+        }
+        if path != "/account" && protected["kid"].as_str().is_none() {
+            state.errors.push(format!("missing kid on {}", path));
+        }
+    }
+
+    // HTTP-01 is checked against the proxy outside the state lock.
+    if path == "/chal/http" {
+        let (domain, thumbprint, proxy_addr) = {
+            let state = state.lock().unwrap();
+            (
+                state.domains[0].clone(),
+                state.thumbprint.clone().unwrap(),
+                state.proxy_addr.unwrap(),
+            )
+        };
+        let served = reqwest::Client::new()
+            .get(format!(
+                "http://{}/.well-known/acme-challenge/http-token",
+                proxy_addr
+            ))
+            .header("Host", domain)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let mut state = state.lock().unwrap();
+        if served == format!("http-token.{}", thumbprint) {
+            state.validated = true;
+        } else {
+            state
+                .errors
+                .push(format!("bad http-01 response: {}", served));
+        }
+        return acme_reply(
+            &mut state,
+            StatusCode::OK,
+            serde_json::json!({ "status": "processing" }),
+        );
+    }
+
+    let mut state = state.lock().unwrap();
+    let base = state.base.clone();
+    let order = |state: &MockAcmeState| {
+        let status = if state.finalized {
+            "valid"
+        } else if state.validated {
+            "ready"
+        } else {
+            "pending"
+        };
+        serde_json::json!({
+            "status": status,
+            "identifiers": state.domains.iter().map(|d| serde_json::json!({ "type": "dns", "value": d })).collect::<Vec<_>>(),
+            "authorizations": [format!("{base}/authz/1")],
+            "finalize": format!("{base}/finalize/1"),
+            "certificate": format!("{base}/cert/1"),
+        })
+    };
+    match path.as_str() {
+        "/account" => {
+            let jwk = &protected["jwk"];
+            let canonical = format!(
+                r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+                jwk["crv"].as_str().unwrap_or_default(),
+                jwk["kty"].as_str().unwrap_or_default(),
+                jwk["x"].as_str().unwrap_or_default(),
+                jwk["y"].as_str().unwrap_or_default(),
+            );
+            state.thumbprint = Some(sha256_b64url(&canonical));
+            if payload["termsOfServiceAgreed"] != true {
+                state.errors.push("terms not agreed".to_string());
+            }
+            acme_reply(
+                &mut state,
+                StatusCode::CREATED,
+                serde_json::json!({ "status": "valid" }),
+            )
+        }
+        "/order" => {
+            state.domains = payload["identifiers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| id["value"].as_str().unwrap().to_string())
+                .collect();
+            state.validated = false;
+            state.finalized = false;
+            let body = order(&state);
+            acme_reply(&mut state, StatusCode::CREATED, body)
+        }
+        "/order/1" => {
+            let body = order(&state);
+            acme_reply(&mut state, StatusCode::OK, body)
+        }
+        "/authz/1" => {
+            let domain = state.domains[0].clone();
+            let body = serde_json::json!({
+                "status": if state.validated { "valid" } else { "pending" },
+                "identifier": { "type": "dns", "value": domain.trim_start_matches("*.") },
+                "wildcard": domain.starts_with("*."),
+                "challenges": [
+                    { "type": "http-01", "url": format!("{base}/chal/http"), "token": "http-token" },
+                    { "type": "dns-01", "url": format!("{base}/chal/dns"), "token": "dns-token" },
+                ],
+            });
+            acme_reply(&mut state, StatusCode::OK, body)
+        }
+        "/chal/dns" => {
+            let domain = state.domains[0].trim_start_matches("*.").to_string();
+            let expected =
+                sha256_b64url(&format!("dns-token.{}", state.thumbprint.clone().unwrap()));
+            let record = dns
+                .records
+                .lock()
+                .unwrap()
+                .get(&format!("_acme-challenge.{}", domain))
+                .cloned();
+            if record.as_deref() == Some(expected.as_str()) {
+                state.validated = true;
+            } else {
+                state
+                    .errors
+                    .push(format!("bad dns-01 record: {:?}", record));
+            }
+            acme_reply(
+                &mut state,
+                StatusCode::OK,
+                serde_json::json!({ "status": "processing" }),
+            )
+        }
+        "/finalize/1" => {
+            if payload["csr"].as_str().is_none_or(str::is_empty) || !state.validated {
+                state
+                    .errors
+                    .push("finalize without csr or validation".to_string());
+            }
+            state.finalized = true;
+            let body = order(&state);
+            acme_reply(&mut state, StatusCode::OK, body)
+        }
+        "/cert/1" => {
+            state.nonce += 1;
+            Response::builder()
+                .header("replay-nonce", format!("nonce-{}", state.nonce))
+                .header("content-type", "application/pem-certificate-chain")
+                .body(Body::from(std::fs::read(fixture("wildcard.crt")).unwrap()))
+                .unwrap()
+        }
+        other => {
+            state
+                .errors
+                .push(format!("unexpected request to {}", other));
+            acme_reply(&mut state, StatusCode::NOT_FOUND, serde_json::Value::Null)
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct RecordingDns {
+    records: Arc<Mutex<std::collections::HashMap<String, String>>>,
+    cleared: Arc<Mutex<Vec<String>>>,
+}
+
+impl global_proxy::DnsProvider for RecordingDns {
+    fn set_txt<'a>(
+        &'a self,
+        name: &'a str,
+        value: &'a str,
+    ) -> futures_util::future::BoxFuture<'a, Result<(), String>> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value.to_string());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn clear_txt<'a>(
+        &'a self,
+        name: &'a str,
+        _value: &'a str,
+    ) -> futures_util::future::BoxFuture<'a, Result<(), String>> {
+        self.records.lock().unwrap().remove(name);
+        self.cleared.lock().unwrap().push(name.to_string());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+async fn wait_for_file(path: &std::path::Path) {
+    for _ in 0..100 {
+        if path.exists() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} was never written", path.display());
+}
+
+#[tokio::test]
+async fn acme_dns01_issues_configured_wildcard() {
+    let dns = RecordingDns::default();
+    let ca = MockAcme::spawn(dns.clone()).await;
+    let storage = test_storage_dir("dns01");
+    let mut acme =
+        global_proxy::AcmeConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &storage);
+    acme.directory_url = ca.directory_url();
+    acme.contact_email = Some("ops@example.com".to_string());
+    acme.challenge = global_proxy::AcmeChallenge::Dns01 {
+        provider: Arc::new(dns.clone()),
+        propagation_delay: Duration::ZERO,
+    };
+    acme.domains = vec!["*.cmux.sh".to_string()];
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        acme: Some(acme),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    wait_for_file(&storage.join("certs/_.cmux.sh.pem")).await;
+    assert!(storage.join("certs/_.cmux.sh.key").exists());
+    assert!(storage.join("account.key").exists());
+    assert_eq!(
+        std::fs::read(storage.join("certs/_.cmux.sh.pem")).unwrap(),
+        std::fs::read(fixture("wildcard.crt")).unwrap()
+    );
+    {
+        let state = ca.state.lock().unwrap();
+        assert!(state.errors.is_empty(), "{:?}", state.errors);
+        assert_eq!(state.domains, vec!["*.cmux.sh".to_string()]);
+    }
+    assert_eq!(
+        *dns.cleared.lock().unwrap(),
+        vec!["_acme-challenge.cmux.sh".to_string()]
+    );
+    assert!(dns.records.lock().unwrap().is_empty());
+
+    proxy.shutdown().await;
+    ca.shutdown().await;
+    let _ = std::fs::remove_dir_all(&storage);
+}
+
+#[tokio::test]
+async fn acme_http01_issues_on_first_handshake() {
+    let ca = MockAcme::spawn(RecordingDns::default()).await;
+    let storage = test_storage_dir("http01");
+    let mut acme =
+        global_proxy::AcmeConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &storage);
+    acme.directory_url = ca.directory_url();
+    acme.on_demand = true;
+    acme.on_demand_limit = "1/h".parse().unwrap();
+    let resolver = global_proxy::StaticResolver::from_table("abc 127.0.0.1").unwrap();
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        acme: Some(acme),
+        backend_resolver: Some(Arc::new(resolver)),
+        ..ProxyConfig::default()
+    })
+    .await;
+    ca.state.lock().unwrap().proxy_addr = Some(proxy.addr);
+
+    // Nothing is issued until a handshake asks for a host.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(ca.state.lock().unwrap().domains.is_empty());
+
+    // Hosts outside the base domains, and preview hosts of VMs that do not resolve, never
+    // trigger issuance.
+    for host in [
+        "preview.example.com",
+        "port-3000-nope.cmux.sh",
+        "junk.cmux.sh",
+    ] {
+        let client = reqwest::Client::builder()
+            .resolve(host, proxy.tls_addr())
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let url = format!("https://{}:{}/", host, proxy.tls_addr().port());
+        assert!(client.get(url).send().await.is_err());
+    }
+    assert!(ca.state.lock().unwrap().domains.is_empty());
+
+    // The mock CA hands back the fixture certificate, which does not match the issued key,
+    // so the handshake itself fails; issuance is what this checks.
+    let host = "port-3000-abc.cmux.sh";
+    let client = reqwest::Client::builder()
+        .resolve(host, proxy.tls_addr())
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap();
+    let _ = client
+        .get(format!("https://{}:{}/", host, proxy.tls_addr().port()))
+        .send()
+        .await;
+    wait_for_file(&storage.join(format!("certs/{}.pem", host))).await;
+    {
+        let state = ca.state.lock().unwrap();
+        assert!(state.errors.is_empty(), "{:?}", state.errors);
+        assert_eq!(state.domains, vec![host.to_string()]);
+        assert!(state.validated);
+    }
+
+    // The order limit is shared by every host.
+    let other = "port-4000-abc.cmux.sh";
+    let client = reqwest::Client::builder()
+        .resolve(other, proxy.tls_addr())
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    let url = format!("https://{}:{}/", other, proxy.tls_addr().port());
+    assert!(client.get(url).send().await.is_err());
+    assert_eq!(ca.state.lock().unwrap().domains, vec![host.to_string()]);
+
+    // The challenge token is only served while its authorization is pending.
+    let response = proxy
+        .request(
+            Method::GET,
+            host,
+            "/.well-known/acme-challenge/http-token",
+            &[],
+        )
+        .await;
+    assert_ne!(response.status(), StatusCode::OK);
+
+    proxy.shutdown().await;
+    ca.shutdown().await;
+    let _ = std::fs::remove_dir_all(&storage);
+}
+
+#[tokio::test]
+async fn acme_tls_listener_serves_stored_certificates() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("over tls"))
+            .unwrap()
+    }))
+    .await;
+    let storage = test_storage_dir("stored");
+    std::fs::create_dir_all(storage.join("certs")).unwrap();
+    std::fs::copy(fixture("wildcard.crt"), storage.join("certs/_.cmux.sh.pem")).unwrap();
+    std::fs::copy(fixture("wildcard.key"), storage.join("certs/_.cmux.sh.key")).unwrap();
+
+    let mut acme =
+        global_proxy::AcmeConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &storage);
+    // Fails if contacted: the stored certificate is good for decades.
+    acme.directory_url = "http://127.0.0.1:9/dir".to_string();
+    acme.domains = vec!["*.cmux.sh".to_string()];
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        acme: Some(acme),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-abc.cmux.sh", backend.port());
+    let ca =
+        reqwest::Certificate::from_pem(&std::fs::read(fixture("wildcard.crt")).unwrap()).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .resolve(&host, proxy.tls_addr())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://{}:{}/", host, proxy.tls_addr().port()))
+        .send()
+        .await
+        .expect("TLS request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "over tls");
+
+    // No certificate covers other names, so the handshake is refused.
+    let client = reqwest::Client::builder()
+        .resolve("other.example.com", proxy.tls_addr())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    assert!(
+        client
+            .get(format!(
+                "https://other.example.com:{}/",
+                proxy.tls_addr().port()
+            ))
+            .send()
+            .await
+            .is_err()
+    );
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+    let _ = std::fs::remove_dir_all(&storage);
+}
+
+#[tokio::test]
+async fn tls_listener_speaks_http2_and_h2c_upstream() {
+    let backend = TestHttpBackend::serve(Arc::new(|req: Request<Body>| {
+        let host = req
+            .headers()
+            .get("host")
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        Response::new(Body::from(format!("{:?} {}", req.version(), host)))
+    }))
+    .await;
+    let storage = test_storage_dir("http2");
+    std::fs::create_dir_all(storage.join("certs")).unwrap();
+    std::fs::copy(fixture("wildcard.crt"), storage.join("certs/_.cmux.sh.pem")).unwrap();
+    std::fs::copy(fixture("wildcard.key"), storage.join("certs/_.cmux.sh.key")).unwrap();
+
+    let mut acme =
+        global_proxy::AcmeConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &storage);
+    acme.directory_url = "http://127.0.0.1:9/dir".to_string();
+    acme.domains = vec!["*.cmux.sh".to_string()];
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        acme: Some(acme),
+        upstream_http2: true,
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let host = format!("port-{}-abc.cmux.sh", backend.port());
+    let url = format!("https://{}:{}/", host, proxy.tls_addr().port());
+    let ca =
+        reqwest::Certificate::from_pem(&std::fs::read(fixture("wildcard.crt")).unwrap()).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca.clone())
+        .resolve(&host, proxy.tls_addr())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let response = client.get(&url).send().await.expect("h2 request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!("HTTP/2.0 127.0.0.1:{}", backend.port())
+    );
+
+    // HTTP/1.1 clients (and so WebSocket upgrades) are still served.
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .resolve(&host, proxy.tls_addr())
+        .http1_only()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let response = client.get(&url).send().await.expect("h1 request");
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+    assert!(response.text().await.unwrap().starts_with("HTTP/2.0"));
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+    let _ = std::fs::remove_dir_all(&storage);
+}
+
+#[tokio::test]
+async fn shutdown_drains_requests_and_closes_lingering_tunnels() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let _ = sender.send_data("slow".into()).await;
+        });
+        Response::new(body)
+    }))
+    .await;
+    let mut proxy = TestProxy::spawn_with_config(ProxyConfig {
+        drain_timeout: Duration::from_secs(5),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    // A response still streaming when shutdown begins is allowed to finish.
+    let response = proxy
+        .request(
+            Method::GET,
+            &format!("port-{}-test.cmux.sh", backend.port()),
+            "/",
+            &[],
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = proxy.handle.take().unwrap().shutdown();
+    let (report, body) = tokio::join!(report, response.text());
+    assert_eq!(body.expect("body"), "slow");
+    assert_eq!(report.open, 1);
+    assert_eq!(report.force_closed, 0);
+    backend.shutdown().await;
+
+    // A tunnel still open at the drain timeout is closed.
+    let backend = TestWsBackend::spawn_echo().await;
+    let mut proxy = TestProxy::spawn_with_config(ProxyConfig {
+        drain_timeout: Duration::from_millis(100),
+        ..ProxyConfig::default()
+    })
+    .await;
+    let mut request = format!("ws://{}/ws", proxy.addr)
+        .into_client_request()
+        .expect("request");
+    request.headers_mut().insert(
+        "Host",
+        format!("port-{}-test.cmux.sh", backend.port())
+            .parse()
+            .expect("host header"),
+    );
+    let (mut ws, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("connect through proxy");
+    ws.send(Message::Text("hi".into())).await.expect("send");
+    let reply = ws.next().await.expect("reply").expect("message");
+    assert_eq!(reply.into_text().unwrap(), "hi");
+
+    let report = proxy.handle.take().unwrap().shutdown().await;
+    assert_eq!(report.force_closed, 1);
+    assert!(
+        !matches!(ws.next().await, Some(Ok(Message::Text(_)))),
+        "tunnel should be closed"
+    );
+
+    backend.shutdown().await;
+}
+
+#[tokio::test]
+async fn request_limits_refuse_large_and_slow_requests() {
+    let backend = TestHttpBackend::serve(Arc::new(|_req| Response::new(Body::from("ok")))).await;
+    let proxy = TestProxy::spawn_with_config(ProxyConfig {
+        request_limits: RequestLimits {
+            max_header_bytes: 1024,
+            max_body_bytes: Some(16),
+            header_read_timeout: Some(Duration::from_millis(200)),
+        },
+        ..ProxyConfig::default()
+    })
+    .await;
+    let host = format!("port-{}-test.cmux.sh", backend.port());
+
+    let response = proxy.request(Method::GET, &host, "/", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let large = "a".repeat(2048);
+    let response = proxy
+        .request(Method::GET, &host, "/", &[("x-large", large.as_str())])
+        .await;
+    assert_eq!(
+        response.status(),
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+    );
+
+    let response = proxy
+        .client
+        .post(proxy.url("/"))
+        .header("Host", &host)
+        .body(vec![0u8; 64])
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Streamed bodies are cut off once they pass the limit. The backend never answers, so the
+    // 413 can only come from the proxy.
+    let silent = tokio::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .expect("bind backend");
+    let silent_port = silent.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = silent.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {}
+            });
+        }
+    });
+    let mut stream = tokio::net::TcpStream::connect(proxy.addr)
+        .await
+        .expect("connect");
+    stream
+        .write_all(
+            format!(
+                "POST / HTTP/1.1\r\nHost: port-{}-test.cmux.sh\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 20\r\n{}\r\n20\r\n{}\r\n0\r\n\r\n",
+                silent_port,
+                "b".repeat(32),
+                "c".repeat(32)
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut reply = vec![0u8; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut reply))
+        .await
+        .expect("reply")
+        .unwrap();
+    let reply = String::from_utf8_lossy(&reply[..n]);
+    assert!(reply.starts_with("HTTP/1.1 413"), "{}", reply);
+
+    // A client that never finishes its headers gets 408 and is disconnected.
+    let mut stream = tokio::net::TcpStream::connect(proxy.addr)
+        .await
+        .expect("connect");
+    stream
+        .write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\n", host).as_bytes())
+        .await
+        .unwrap();
+    let mut reply = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut reply))
+        .await
+        .expect("closed")
+        .unwrap();
+    assert!(reply.starts_with("HTTP/1.1 408"), "{}", reply);
+
+    // Idle keep-alive connections are not timed, only requests in progress.
+    let mut stream = tokio::net::TcpStream::connect(proxy.addr)
+        .await
+        .expect("connect");
+    let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+    for _ in 0..2 {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(b"\r\n\r\nok") {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&reply));
+            reply.extend_from_slice(&buf[..n]);
+        }
+        assert!(reply.starts_with(b"HTTP/1.1 200"));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+    }
+
+    proxy.shutdown().await;
+    backend.shutdown().await;
+}
@@ -0,0 +1,191 @@
+use anyhow::Result;
+use gix::{hash::ObjectId, Repository};
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::diff::refs::oid_from_rev_parse;
+use crate::merge_base::merge_base_many;
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::types::{AheadBehind, GitAheadBehindOptions};
+
+// Which tips of a walk a commit is reachable from: bit 0 for the base, bit `i + 1` for head `i`.
+#[derive(Clone, PartialEq, Eq)]
+struct Tips(Vec<u64>);
+
+impl Tips {
+  fn new(count: usize) -> Self {
+    Tips(vec![0; count.div_ceil(64)])
+  }
+
+  fn set(&mut self, i: usize) {
+    self.0[i / 64] |= 1u64 << (i % 64);
+  }
+
+  fn has(&self, i: usize) -> bool {
+    self.0[i / 64] & (1u64 << (i % 64)) != 0
+  }
+
+  // Adds the tips of `other`; false when none were new.
+  fn absorb(&mut self, other: &Tips) -> bool {
+    let mut changed = false;
+    for (mine, theirs) in self.0.iter_mut().zip(&other.0) {
+      changed |= *theirs & !*mine != 0;
+      *mine |= theirs;
+    }
+    changed
+  }
+}
+
+fn commit_time(repo: &Repository, id: ObjectId) -> Result<i64> {
+  Ok(repo.find_object(id)?.try_into_commit()?.committer()?.time.seconds)
+}
+
+fn resolve_commit(repo: &Repository, rev: &str) -> Result<ObjectId> {
+  let id = oid_from_rev_parse(repo, rev)?;
+  Ok(repo.find_object(id)?.peel_to_commit()?.id)
+}
+
+/// For each of `heads`, the commits reachable from it but not `base` and the other way around,
+/// like `git rev-list --left-right --count base...head`, in a single walk for all of them.
+///
+/// The tips are walked together newest first, marking each commit with the tips it is reachable
+/// from. The walk ends once every queued commit is reachable from all of them, since so are
+/// their ancestors. A commit that gains a tip after it was walked is queued again, so clock skew
+/// only costs time.
+pub(crate) fn count_ahead_behind(repo: &Repository, base: ObjectId, heads: &[ObjectId]) -> Result<Vec<(i32, i32)>> {
+  let count = heads.len() + 1;
+  let mut all = Tips::new(count);
+  for i in 0..count {
+    all.set(i);
+  }
+  let mut marks: HashMap<ObjectId, Tips> = HashMap::new();
+  for (i, id) in std::iter::once(&base).chain(heads).enumerate() {
+    marks.entry(*id).or_insert_with(|| Tips::new(count)).set(i);
+  }
+  // Entries remember whether they were reachable from every tip when queued; `pending` counts
+  // those that were not.
+  let mut queue: BinaryHeap<(i64, ObjectId, bool)> = BinaryHeap::new();
+  for (id, tips) in &marks {
+    queue.push((commit_time(repo, *id)?, *id, *tips == all));
+  }
+  let mut pending = queue.iter().filter(|(_, _, done)| !done).count();
+  while pending > 0 {
+    crate::cancel::check()?;
+    let Some((_, id, done)) = queue.pop() else { break };
+    if !done {
+      pending -= 1;
+    }
+    let tips = marks[&id].clone();
+    let commit = repo.find_object(id)?.try_into_commit()?;
+    for parent in commit.parent_ids() {
+      let parent = parent.detach();
+      let parent_tips = marks.entry(parent).or_insert_with(|| Tips::new(count));
+      if !parent_tips.absorb(&tips) {
+        continue;
+      }
+      let done = *parent_tips == all;
+      queue.push((commit_time(repo, parent)?, parent, done));
+      if !done {
+        pending += 1;
+      }
+    }
+  }
+  let mut counts = vec![(0, 0); heads.len()];
+  for tips in marks.values() {
+    let in_base = tips.has(0);
+    for (i, (ahead, behind)) in counts.iter_mut().enumerate() {
+      match (in_base, tips.has(i + 1)) {
+        (false, true) => *ahead += 1,
+        (true, false) => *behind += 1,
+        _ => {}
+      }
+    }
+  }
+  Ok(counts)
+}
+
+/// How many commits `head` has that `base` lacks and the reverse, with their merge base, so a
+/// branch can be shown as "3 ahead, 2 behind" without diffing it.
+pub fn ahead_behind(opts: GitAheadBehindOptions) -> Result<AheadBehind> {
+  let offline = opts.offline.unwrap_or(false);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let base = resolve_commit(&repo, opts.base.trim())?;
+  let head = resolve_commit(&repo, opts.head.trim())?;
+  let (ahead, behind) = count_ahead_behind(&repo, base, &[head])?[0];
+  // Criss-cross merges leave more than one; like `git merge-base`, report the first.
+  let merge_base_sha = merge_base_many(&repo, vec![base, head])?.into_iter().next();
+  Ok(AheadBehind {
+    ahead,
+    behind,
+    mergeBaseSha: merge_base_sha.map(|id| id.to_hex().to_string()),
+    stale: stale.then_some(true),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{commit_file, git, rev};
+  use crate::util::run_git;
+  use tempfile::tempdir;
+
+  fn counts(repo: &str, base: &str, head: &str) -> AheadBehind {
+    ahead_behind(GitAheadBehindOptions {
+      originPathOverride: Some(repo.to_string()),
+      base: base.into(),
+      head: head.into(),
+      ..Default::default()
+    })
+    .expect("ahead/behind")
+  }
+
+  #[test]
+  fn counts_commits_on_each_side_of_the_merge_base() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    commit_file(repo, "root");
+    let fork = commit_file(repo, "fork point");
+    run_git(repo, &["checkout", "-b", "feature"]).unwrap();
+    for i in 0..3 {
+      commit_file(repo, &format!("feature {i}"));
+    }
+    run_git(repo, &["checkout", "main"]).unwrap();
+    commit_file(repo, "main 1");
+    commit_file(repo, "main 2");
+
+    let r = counts(repo, "main", "feature");
+    assert_eq!((r.ahead, r.behind), (3, 2));
+    assert_eq!(r.mergeBaseSha.as_deref(), Some(fork.as_str()));
+    assert_eq!(r.stale, None);
+    let r = counts(repo, "feature", "main");
+    assert_eq!((r.ahead, r.behind), (2, 3));
+
+    // Merging main in leaves feature only ahead, by its own commits and the merge.
+    run_git(repo, &["checkout", "feature"]).unwrap();
+    git(repo, &["merge", "--no-edit", "main"]);
+    let r = counts(repo, "main", "feature");
+    assert_eq!((r.ahead, r.behind), (4, 0));
+    let main = rev(repo, "main");
+    assert_eq!(r.mergeBaseSha.as_deref(), Some(main.as_str()));
+
+    let r = counts(repo, "feature", "feature");
+    assert_eq!((r.ahead, r.behind), (0, 0));
+    assert!(ahead_behind(GitAheadBehindOptions {
+      originPathOverride: Some(repo.to_string()),
+      base: "main".into(),
+      head: "missing".into(),
+      ..Default::default()
+    })
+    .is_err());
+  }
+}
@@ -0,0 +1,727 @@
+use anyhow::{anyhow, bail, Result};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Component, Path, PathBuf},
+};
+
+use crate::types::{AppliedFile, ApplyPatchResult, GitApplyPatchOptions};
+
+// One hunk; lines keep their `\n` unless the patch marks them as missing it.
+#[derive(Debug, Default)]
+struct Hunk {
+  old_start: usize,
+  old_count: usize,
+  new_count: usize,
+  lines: Vec<(char, String)>,
+}
+
+impl Hunk {
+  fn side(&self, keep: char) -> Vec<&str> {
+    self.lines.iter().filter(|(tag, _)| *tag == ' ' || *tag == keep).map(|(_, l)| l.as_str()).collect()
+  }
+}
+
+#[derive(Debug, Default)]
+struct FilePatch {
+  // None for `/dev/null`.
+  old_path: Option<String>,
+  new_path: Option<String>,
+  new_mode: Option<String>,
+  rename: bool,
+  copy: bool,
+  // The preimage blob from the `index` line, as abbreviated there.
+  old_blob: Option<String>,
+  binary: bool,
+  hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+  fn status(&self) -> &'static str {
+    match (&self.old_path, &self.new_path) {
+      (None, _) => "added",
+      (_, None) => "deleted",
+      _ if self.rename => "renamed",
+      _ if self.copy => "copied",
+      _ => "modified",
+    }
+  }
+
+  fn path(&self) -> &str {
+    self.new_path.as_deref().or(self.old_path.as_deref()).unwrap_or_default()
+  }
+}
+
+// Undoes git's C-style quoting of paths with unusual characters.
+fn unquote(path: &str) -> String {
+  let Some(inner) = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) else { return path.to_string() };
+  let mut bytes = Vec::with_capacity(inner.len());
+  let mut it = inner.bytes().peekable();
+  while let Some(b) = it.next() {
+    if b != b'\\' {
+      bytes.push(b);
+      continue;
+    }
+    match it.next() {
+      Some(b'n') => bytes.push(b'\n'),
+      Some(b't') => bytes.push(b'\t'),
+      Some(d @ b'0'..=b'7') => {
+        let mut v = (d - b'0') as u32;
+        for _ in 0..2 {
+          match it.peek() {
+            Some(n @ b'0'..=b'7') => {
+              v = v * 8 + (n - b'0') as u32;
+              it.next();
+            }
+            _ => break,
+          }
+        }
+        bytes.push(v as u8);
+      }
+      Some(other) => bytes.push(other),
+      None => {}
+    }
+  }
+  String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// A `---`/`+++` path: None for `/dev/null`, else without its `a/`/`b/` prefix or trailing
+// timestamp.
+fn header_path(raw: &str) -> Option<String> {
+  let raw = raw.trim_end_matches(['\r', '\n']);
+  let raw = if raw.starts_with('"') { raw } else { raw.split('\t').next().unwrap_or(raw) };
+  let path = unquote(raw.trim_end());
+  if path == "/dev/null" {
+    return None;
+  }
+  Some(path.strip_prefix("a/").or_else(|| path.strip_prefix("b/")).unwrap_or(&path).to_string())
+}
+
+// `@@ -a,b +c,d @@`; counts default to 1.
+fn parse_hunk_header(line: &str) -> Option<Hunk> {
+  let rest = line.strip_prefix("@@ -")?;
+  let (ranges, _) = rest.split_once(" @@")?;
+  let (old, new) = ranges.split_once(" +")?;
+  let range = |r: &str| -> Option<(usize, usize)> {
+    match r.split_once(',') {
+      Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+      None => Some((r.parse().ok()?, 1)),
+    }
+  };
+  let (old_start, old_count) = range(old)?;
+  let (_, new_count) = range(new)?;
+  Some(Hunk { old_start, old_count, new_count, ..Default::default() })
+}
+
+// Splits a patch into per-file patches. Accepts `git diff` output and plain unified diffs.
+fn parse_patch(patch: &str) -> Result<Vec<FilePatch>> {
+  let lines: Vec<&str> = patch.split_inclusive('\n').collect();
+  let mut files: Vec<FilePatch> = Vec::new();
+  // Whether the current file's extended headers are still being read.
+  let mut in_header = false;
+  let mut i = 0;
+  while i < lines.len() {
+    let line = lines[i];
+    let trimmed = line.trim_end_matches(['\r', '\n']);
+    i += 1;
+    if let Some(rest) = trimmed.strip_prefix("diff --git ") {
+      let mut file = FilePatch::default();
+      // Only a fallback: `---`/`+++` or rename lines name the paths unambiguously.
+      if let Some((a, b)) = rest.split_once(" b/") {
+        file.old_path = header_path(a);
+        file.new_path = Some(unquote(b));
+      }
+      files.push(file);
+      in_header = true;
+      continue;
+    }
+    if trimmed.starts_with("--- ") && lines.get(i).is_some_and(|next| next.starts_with("+++ ")) {
+      if !in_header {
+        files.push(FilePatch::default());
+      }
+      let file = files.last_mut().expect("file");
+      file.old_path = header_path(&trimmed[4..]);
+      file.new_path = header_path(&lines[i][4..]);
+      i += 1;
+      in_header = false;
+      continue;
+    }
+    if trimmed.starts_with("@@ ") {
+      let Some(file) = files.last_mut() else { bail!("hunk before any file header at line {i}") };
+      let mut hunk = parse_hunk_header(trimmed).ok_or_else(|| anyhow!("malformed hunk header at line {i}: {trimmed}"))?;
+      let (mut old_left, mut new_left) = (hunk.old_count, hunk.new_count);
+      while (old_left > 0 || new_left > 0) && i < lines.len() {
+        let body = lines[i];
+        // Some tools strip the space of empty context lines.
+        let (tag, text) = match body.chars().next() {
+          Some(tag @ (' ' | '-' | '+')) => (tag, &body[1..]),
+          Some('\n') | Some('\r') => (' ', body),
+          _ => break,
+        };
+        i += 1;
+        let mut text = text.to_string();
+        if lines.get(i).is_some_and(|next| next.starts_with('\\')) {
+          if text.ends_with('\n') {
+            text.pop();
+          }
+          i += 1;
+        }
+        match tag {
+          ' ' => {
+            old_left = old_left.saturating_sub(1);
+            new_left = new_left.saturating_sub(1);
+          }
+          '-' => old_left = old_left.saturating_sub(1),
+          _ => new_left = new_left.saturating_sub(1),
+        }
+        hunk.lines.push((tag, text));
+      }
+      if old_left > 0 || new_left > 0 {
+        bail!("truncated hunk in patch for {}", file.path());
+      }
+      file.hunks.push(hunk);
+      in_header = false;
+      continue;
+    }
+    let Some(file) = files.last_mut().filter(|_| in_header) else { continue };
+    if let Some(mode) = trimmed.strip_prefix("new file mode ") {
+      file.old_path = None;
+      file.new_mode = Some(mode.to_string());
+    } else if trimmed.starts_with("deleted file mode ") {
+      file.new_path = None;
+    } else if let Some(mode) = trimmed.strip_prefix("new mode ") {
+      file.new_mode = Some(mode.to_string());
+    } else if let Some(p) = trimmed.strip_prefix("rename from ") {
+      file.old_path = Some(unquote(p));
+      file.rename = true;
+    } else if let Some(p) = trimmed.strip_prefix("rename to ") {
+      file.new_path = Some(unquote(p));
+      file.rename = true;
+    } else if let Some(p) = trimmed.strip_prefix("copy from ") {
+      file.old_path = Some(unquote(p));
+      file.copy = true;
+    } else if let Some(p) = trimmed.strip_prefix("copy to ") {
+      file.new_path = Some(unquote(p));
+      file.copy = true;
+    } else if let Some(index) = trimmed.strip_prefix("index ") {
+      let ids = index.split(' ').next().unwrap_or_default();
+      file.old_blob = ids.split_once("..").map(|(old, _)| old.to_string()).filter(|old| old.bytes().any(|b| b != b'0'));
+      // The mode of a file whose mode did not change.
+      if let (Some(mode), None) = (index.split(' ').nth(1), &file.new_mode) {
+        file.new_mode = Some(mode.to_string());
+      }
+    } else if trimmed.starts_with("Binary files ") || trimmed == "GIT binary patch" {
+      file.binary = true;
+    }
+  }
+  if files.is_empty() {
+    bail!("no file patches found");
+  }
+  Ok(files)
+}
+
+// Applies `hunks` to `lines` in order, letting each move from where the header puts it to the
+// nearest exact match, like `git apply` without fuzz.
+fn apply_hunks(lines: &[&str], hunks: &[Hunk]) -> std::result::Result<Vec<String>, String> {
+  let mut out: Vec<String> = Vec::with_capacity(lines.len());
+  // Next line of `lines` not yet copied.
+  let mut pos = 0usize;
+  // How far the previous hunks were found from where their headers said.
+  let mut offset: isize = 0;
+  for (n, hunk) in hunks.iter().enumerate() {
+    let pre = hunk.side('-');
+    let post = hunk.side('+');
+    // An empty preimage inserts after line `old_start`.
+    let start = if hunk.old_count == 0 { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+    let expected = (start as isize + offset).clamp(pos as isize, lines.len() as isize) as usize;
+    let fits = |at: usize| at + pre.len() <= lines.len() && lines[at..at + pre.len()] == pre[..];
+    let found = (0..=lines.len()).find_map(|d| {
+      let after = expected + d;
+      if after <= lines.len() && fits(after) {
+        return Some(after);
+      }
+      let before = expected.checked_sub(d)?;
+      (before >= pos && fits(before)).then_some(before)
+    });
+    let Some(at) = found else {
+      return Err(format!("hunk #{} at line {} does not apply", n + 1, hunk.old_start));
+    };
+    out.extend(lines[pos..at].iter().map(|l| l.to_string()));
+    out.extend(post.iter().map(|l| l.to_string()));
+    pos = at + pre.len();
+    offset = at as isize - start as isize;
+  }
+  out.extend(lines[pos..].iter().map(|l| l.to_string()));
+  Ok(out)
+}
+
+// A change of one side against the base: base lines `start..end` become `lines`.
+struct Region<'a> {
+  start: usize,
+  end: usize,
+  lines: &'a [&'a str],
+}
+
+fn regions<'a>(base: &[&str], side: &'a [&'a str]) -> Vec<Region<'a>> {
+  capture_diff_slices(Algorithm::Myers, base, side)
+    .into_iter()
+    .filter_map(|op| match op {
+      DiffOp::Equal { .. } => None,
+      DiffOp::Delete { old_index, old_len, new_index } => Some(Region { start: old_index, end: old_index + old_len, lines: &side[new_index..new_index] }),
+      DiffOp::Insert { old_index, new_index, new_len } => Some(Region { start: old_index, end: old_index, lines: &side[new_index..new_index + new_len] }),
+      DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+        Some(Region { start: old_index, end: old_index + old_len, lines: &side[new_index..new_index + new_len] })
+      }
+    })
+    .collect()
+}
+
+// One side's text for base lines `start..end`, given its changes within them.
+fn side_text<'a>(base: &[&'a str], changes: &[&Region<'a>], start: usize, end: usize) -> Vec<&'a str> {
+  let mut out = Vec::new();
+  let mut at = start;
+  for r in changes {
+    out.extend_from_slice(&base[at..r.start]);
+    out.extend_from_slice(r.lines);
+    at = r.end;
+  }
+  out.extend_from_slice(&base[at..end]);
+  out
+}
+
+fn push_block(out: &mut String, lines: &[&str]) {
+  for l in lines {
+    out.push_str(l);
+  }
+  if !out.is_empty() && !out.ends_with('\n') {
+    out.push('\n');
+  }
+}
+
+// Line-based three-way merge. Changes of both sides that overlap or touch, and differ, become
+// a conflict marked like `git apply --3way` does; returns whether there was one.
+fn merge3(base: &[&str], ours: &[&str], theirs: &[&str]) -> (String, bool) {
+  let (a, b) = (regions(base, ours), regions(base, theirs));
+  let (mut i, mut j) = (0, 0);
+  let mut out = String::new();
+  let mut at = 0;
+  let mut conflicted = false;
+  while i < a.len() || j < b.len() {
+    let start = match (a.get(i), b.get(j)) {
+      (Some(x), Some(y)) => x.start.min(y.start),
+      (Some(x), None) => x.start,
+      (None, Some(y)) => y.start,
+      (None, None) => unreachable!(),
+    };
+    for l in &base[at..start] {
+      out.push_str(l);
+    }
+    let (mut ours_changes, mut theirs_changes) = (Vec::new(), Vec::new());
+    let mut end = start;
+    loop {
+      let mut grew = false;
+      while let Some(r) = a.get(i).filter(|r| r.start <= end) {
+        end = end.max(r.end);
+        ours_changes.push(r);
+        i += 1;
+        grew = true;
+      }
+      while let Some(r) = b.get(j).filter(|r| r.start <= end) {
+        end = end.max(r.end);
+        theirs_changes.push(r);
+        j += 1;
+        grew = true;
+      }
+      if !grew {
+        break;
+      }
+    }
+    let ours_text = side_text(base, &ours_changes, start, end);
+    let theirs_text = side_text(base, &theirs_changes, start, end);
+    if theirs_changes.is_empty() || ours_text == theirs_text {
+      out.extend(ours_text);
+    } else if ours_changes.is_empty() {
+      out.extend(theirs_text);
+    } else {
+      conflicted = true;
+      push_block(&mut out, &[]);
+      out.push_str("<<<<<<< ours\n");
+      push_block(&mut out, &ours_text);
+      out.push_str("=======\n");
+      push_block(&mut out, &theirs_text);
+      out.push_str(">>>>>>> theirs\n");
+    }
+    at = end;
+  }
+  for l in &base[at..] {
+    out.push_str(l);
+  }
+  (out, conflicted)
+}
+
+// `path` under `root`, refusing anything that would leave it.
+fn worktree_file(root: &Path, path: &str) -> Result<PathBuf> {
+  let rel = Path::new(path);
+  if path.is_empty() || rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+    bail!("refusing to touch path outside the worktree: {path}");
+  }
+  if rel.components().next().is_some_and(|c| c.as_os_str() == ".git") {
+    bail!("refusing to touch path inside .git: {path}");
+  }
+  // Like `git apply`, never follow a symlink already in the worktree: a linked directory (or a
+  // link in place of the file) would let the patch write outside it.
+  let mut at = root.to_path_buf();
+  for c in rel.components() {
+    at.push(c);
+    match fs::symlink_metadata(&at) {
+      Ok(meta) if meta.file_type().is_symlink() => bail!("refusing to touch path beyond a symlink: {path}"),
+      Ok(_) => {}
+      Err(_) => break,
+    }
+  }
+  Ok(root.join(rel))
+}
+
+fn read_text(path: &Path, name: &str) -> std::result::Result<String, String> {
+  let bytes = fs::read(path).map_err(|e| format!("cannot read {name}: {e}"))?;
+  String::from_utf8(bytes).map_err(|_| format!("{name} is not a text file"))
+}
+
+// The preimage blob the patch was made against, for a three-way merge.
+fn preimage(root: &Path, blob: &str) -> Option<String> {
+  let repo = gix::open(root).ok()?;
+  let data = repo.rev_parse_single(blob).ok()?.object().ok()?.detach().data;
+  String::from_utf8(data).ok()
+}
+
+// What one file becomes: its new contents, or None when it is deleted.
+struct Outcome {
+  contents: Option<String>,
+  outcome: &'static str,
+}
+
+fn apply_file(root: &Path, file: &FilePatch, three_way: bool, pending: &HashMap<String, Option<String>>) -> std::result::Result<Outcome, String> {
+  if file.binary {
+    return Err("binary patches are not supported".into());
+  }
+  let current = match &file.old_path {
+    None => {
+      let path = file.path();
+      let target = worktree_file(root, path).map_err(|e| e.to_string())?;
+      if pending.get(path).is_some_and(Option::is_some) || (!pending.contains_key(path) && target.exists()) {
+        return Err(format!("{path} already exists in the worktree"));
+      }
+      String::new()
+    }
+    Some(old) => match pending.get(old) {
+      Some(Some(text)) => text.clone(),
+      Some(None) => return Err(format!("{old} was deleted earlier in the patch")),
+      None => read_text(&worktree_file(root, old).map_err(|e| e.to_string())?, old)?,
+    },
+  };
+  let lines: Vec<&str> = current.split_inclusive('\n').collect();
+  let merged = match apply_hunks(&lines, &file.hunks) {
+    Ok(out) => Outcome { contents: Some(out.concat()), outcome: "applied" },
+    Err(reason) => {
+      let base = file.old_blob.as_deref().filter(|_| three_way).and_then(|blob| preimage(root, blob));
+      let Some(base) = base else { return Err(reason) };
+      let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+      let theirs = apply_hunks(&base_lines, &file.hunks).map_err(|e| format!("{e} to its preimage either"))?;
+      let theirs: Vec<&str> = theirs.iter().map(String::as_str).collect();
+      let (text, conflicted) = merge3(&base_lines, &lines, &theirs);
+      Outcome { contents: Some(text), outcome: if conflicted { "conflicted" } else { "merged" } }
+    }
+  };
+  if file.new_path.is_none() {
+    if merged.contents.as_deref().is_some_and(|c| !c.is_empty()) {
+      return Err("deleted file still has contents after the patch".into());
+    }
+    return Ok(Outcome { contents: None, outcome: merged.outcome });
+  }
+  Ok(merged)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<&str>) -> Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  let Some(mode) = mode else { return Ok(()) };
+  let executable = mode == "100755";
+  let mut perms = fs::metadata(path)?.permissions();
+  let bits = if executable { perms.mode() | 0o111 } else { perms.mode() & !0o111 };
+  perms.set_mode(bits);
+  fs::set_permissions(path, perms)?;
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<&str>) -> Result<()> {
+  Ok(())
+}
+
+/// Applies a unified diff, as `git diff` prints it, to the files of a worktree.
+///
+/// Like `git apply`, either every file applies or nothing is written; `files` says which did
+/// not and why. New contents are staged beside their targets before any is renamed into place,
+/// so a failed write leaves no file half-written. With `threeWay`, a file whose hunks no longer
+/// match is merged from the preimage blob named by the patch's `index` line, leaving conflict
+/// markers where both sides changed the same lines; conflicted files are still written and
+/// listed in `conflicts`. The index is never touched. Paths that leave the worktree, enter
+/// `.git`, or pass through a symlink are rejected.
+pub fn apply_patch(opts: GitApplyPatchOptions) -> Result<ApplyPatchResult> {
+  let root = PathBuf::from(&opts.worktreePath);
+  if !root.is_dir() {
+    bail!("worktree {} does not exist", root.display());
+  }
+  let three_way = opts.threeWay.unwrap_or(false);
+  let files = parse_patch(&opts.patch)?;
+
+  // Later file patches see the results of earlier ones.
+  let mut pending: HashMap<String, Option<String>> = HashMap::new();
+  let mut writes: Vec<(String, Option<String>, Option<String>)> = Vec::new();
+  let mut result = ApplyPatchResult { applied: true, ..Default::default() };
+  for file in &files {
+    let mut entry = AppliedFile {
+      path: file.path().to_string(),
+      oldPath: file.old_path.clone().filter(|old| file.new_path.as_ref().is_some_and(|new| new != old)),
+      status: file.status().to_string(),
+      ..Default::default()
+    };
+    let checked = file.new_path.as_deref().map_or(Ok(()), |p| worktree_file(&root, p).map(|_| ()));
+    match checked.map_err(|e| e.to_string()).and_then(|_| apply_file(&root, file, three_way, &pending)) {
+      Ok(outcome) => {
+        entry.outcome = outcome.outcome.to_string();
+        if outcome.outcome == "conflicted" {
+          result.conflicts.push(entry.path.clone());
+        }
+        if file.rename {
+          if let Some(old) = &file.old_path {
+            pending.insert(old.clone(), None);
+            writes.push((old.clone(), None, None));
+          }
+        }
+        let path = entry.path.clone();
+        pending.insert(path.clone(), outcome.contents.clone());
+        writes.push((path, outcome.contents, file.new_mode.clone()));
+      }
+      Err(reason) => {
+        entry.outcome = "rejected".into();
+        entry.error = Some(reason);
+        result.applied = false;
+      }
+    }
+    result.files.push(entry);
+  }
+
+  if result.applied && !opts.checkOnly.unwrap_or(false) {
+    let mut staged: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+    if let Err(e) = stage_writes(&root, writes, &mut staged) {
+      for (_, tmp) in &staged {
+        if let Some(tmp) = tmp {
+          let _ = fs::remove_file(tmp);
+        }
+      }
+      return Err(e);
+    }
+    for (target, tmp) in staged {
+      match tmp {
+        Some(tmp) => fs::rename(&tmp, &target)?,
+        None if target.symlink_metadata().is_ok() => fs::remove_file(&target)?,
+        None => {}
+      }
+    }
+  }
+  Ok(result)
+}
+
+// Writes each new file's contents to a temp file next to it, in patch order; deletions stage
+// nothing. `staged` holds what was done so far, so a failure can be cleaned up.
+fn stage_writes(root: &Path, writes: Vec<(String, Option<String>, Option<String>)>, staged: &mut Vec<(PathBuf, Option<PathBuf>)>) -> Result<()> {
+  for (i, (path, contents, mode)) in writes.into_iter().enumerate() {
+    let target = worktree_file(root, &path)?;
+    let Some(text) = contents else {
+      staged.push((target, None));
+      continue;
+    };
+    if let Some(dir) = target.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    // Directories created above may not be links either.
+    let target = worktree_file(root, &path)?;
+    let mut tmp = target.clone().into_os_string();
+    tmp.push(format!(".{}.{i}.tmp", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    staged.push((target.clone(), Some(tmp.clone())));
+    fs::write(&tmp, text)?;
+    // The rename replaces the file, so carry its mode over unless the patch sets one.
+    if let Ok(meta) = fs::metadata(&target) {
+      fs::set_permissions(&tmp, meta.permissions())?;
+    }
+    set_mode(&tmp, mode.as_deref())?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{commit, git};
+  use tempfile::tempdir;
+
+  fn apply(root: &Path, patch: &str, three_way: bool, check_only: bool) -> ApplyPatchResult {
+    apply_patch(GitApplyPatchOptions {
+      worktreePath: root.to_string_lossy().into_owned(),
+      patch: patch.to_string(),
+      threeWay: Some(three_way),
+      checkOnly: Some(check_only),
+    })
+    .expect("apply")
+  }
+
+  fn outcomes(r: &ApplyPatchResult) -> Vec<(&str, &str, &str)> {
+    r.files.iter().map(|f| (f.path.as_str(), f.status.as_str(), f.outcome.as_str())).collect()
+  }
+
+  #[test]
+  fn applies_git_diff_output_and_rejects_atomically() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path();
+    let repo = root.to_str().unwrap();
+    git(repo, &["init", "-b", "main"]);
+    let body: String = (1..=20).map(|n| format!("line {n}\n")).collect();
+    fs::write(root.join("a.txt"), &body).unwrap();
+    fs::write(root.join("old.txt"), "moved\n").unwrap();
+    fs::write(root.join("gone.txt"), "bye\n").unwrap();
+    commit(repo, "init");
+
+    // Produce the patch with git itself, then reset the worktree.
+    fs::write(root.join("a.txt"), body.replace("line 2\n", "line two\n").replace("line 19\n", "line 19\nline 19.5\n")).unwrap();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/new.txt"), "fresh\nno newline").unwrap();
+    fs::remove_file(root.join("gone.txt")).unwrap();
+    git(repo, &["mv", "old.txt", "moved.txt"]);
+    git(repo, &["add", "-A"]);
+    // The helper trims the patch's final newline.
+    let patch = git(repo, &["diff", "--cached", "-M"]) + "\n";
+    let expected_a = fs::read_to_string(root.join("a.txt")).unwrap();
+    git(repo, &["reset", "-q", "--hard"]);
+    git(repo, &["clean", "-fdq"]);
+
+    // Shift the first hunk's target down; it still applies at an offset.
+    fs::write(root.join("a.txt"), format!("header\n{body}")).unwrap();
+    let r = apply(root, &patch, false, true);
+    assert!(r.applied);
+    let mut got = outcomes(&r);
+    got.sort();
+    assert_eq!(
+      got,
+      vec![
+        ("a.txt", "modified", "applied"),
+        ("gone.txt", "deleted", "applied"),
+        ("moved.txt", "renamed", "applied"),
+        ("src/new.txt", "added", "applied"),
+      ]
+    );
+    assert_eq!(r.files.iter().find(|f| f.path == "moved.txt").and_then(|f| f.oldPath.as_deref()), Some("old.txt"));
+    // Checking writes nothing.
+    assert!(!root.join("src/new.txt").exists());
+
+    let r = apply(root, &patch, false, false);
+    assert!(r.applied && r.conflicts.is_empty());
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), format!("header\n{expected_a}"));
+    assert_eq!(fs::read_to_string(root.join("src/new.txt")).unwrap(), "fresh\nno newline");
+    assert_eq!(fs::read_to_string(root.join("moved.txt")).unwrap(), "moved\n");
+    assert!(!root.join("old.txt").exists() && !root.join("gone.txt").exists());
+
+    // Applying again fails on every file and leaves the worktree alone.
+    let r = apply(root, &patch, false, false);
+    assert!(!r.applied);
+    assert!(r.files.iter().all(|f| f.outcome == "rejected" && f.error.is_some()));
+    assert_eq!(fs::read_to_string(root.join("src/new.txt")).unwrap(), "fresh\nno newline");
+
+    // Paths may not leave the worktree.
+    let escape = "--- a/../x.txt\n+++ b/../x.txt\n@@ -0,0 +1 @@\n+x\n";
+    let r = apply(root, escape, false, false);
+    assert!(!r.applied);
+    assert!(r.files[0].error.as_deref().unwrap().contains("outside the worktree"));
+    assert!(apply_patch(GitApplyPatchOptions { worktreePath: repo.into(), patch: "not a patch\n".into(), ..Default::default() }).is_err());
+  }
+
+  #[test]
+  fn three_way_merges_from_the_preimage_and_reports_conflicts() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path();
+    let repo = root.to_str().unwrap();
+    git(repo, &["init", "-b", "main"]);
+    let body: String = (1..=12).map(|n| format!("line {n}\n")).collect();
+    fs::write(root.join("a.txt"), &body).unwrap();
+    commit(repo, "init");
+    fs::write(root.join("a.txt"), body.replace("line 2\n", "line TWO\n")).unwrap();
+    let patch = git(repo, &["diff"]) + "\n";
+
+    // The worktree changed the hunk's context but not its lines: merged cleanly.
+    fs::write(root.join("a.txt"), body.replace("line 5\n", "line five\n")).unwrap();
+    assert!(!apply(root, &patch, false, false).applied);
+    let r = apply(root, &patch, true, false);
+    assert_eq!(outcomes(&r), vec![("a.txt", "modified", "merged")]);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), body.replace("line 2\n", "line TWO\n").replace("line 5\n", "line five\n"));
+
+    // Both changed line 2: written with markers and listed as a conflict.
+    fs::write(root.join("a.txt"), body.replace("line 2\n", "line deux\n")).unwrap();
+    let r = apply(root, &patch, true, false);
+    assert!(r.applied);
+    assert_eq!(r.conflicts, vec!["a.txt".to_string()]);
+    let text = fs::read_to_string(root.join("a.txt")).unwrap();
+    assert!(text.starts_with("line 1\n<<<<<<< ours\nline deux\n=======\nline TWO\n>>>>>>> theirs\nline 3\n"), "{text}");
+  }
+  #[cfg(unix)]
+  #[test]
+  fn refuses_to_write_through_symlinks() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path().join("repo");
+    let outside = tmp.path().join("outside");
+    fs::create_dir_all(&root).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("x.txt"), "x\n").unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+    std::os::unix::fs::symlink(outside.join("x.txt"), root.join("x.txt")).unwrap();
+
+    for patch in [
+      "--- /dev/null\n+++ b/linked/new.txt\n@@ -0,0 +1 @@\n+new\n",
+      "--- a/linked/x.txt\n+++ b/linked/x.txt\n@@ -1 +1 @@\n-x\n+y\n",
+      "--- a/linked/x.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n",
+      "--- a/x.txt\n+++ b/x.txt\n@@ -1 +1 @@\n-x\n+y\n",
+    ] {
+      let r = apply(&root, patch, false, false);
+      assert!(!r.applied, "{patch}");
+      assert!(r.files[0].error.as_deref().unwrap().contains("symlink"), "{:?}", r.files[0].error);
+    }
+    assert!(!outside.join("new.txt").exists());
+    assert_eq!(fs::read_to_string(outside.join("x.txt")).unwrap(), "x\n");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn a_failed_write_leaves_the_worktree_alone() {
+    use std::os::unix::fs::PermissionsExt;
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path();
+    fs::write(root.join("a.txt"), "a\n").unwrap();
+    fs::write(root.join("b.txt"), "b\n").unwrap();
+    fs::set_permissions(root.join("a.txt"), fs::Permissions::from_mode(0o755)).unwrap();
+    let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+A\n--- a/b.txt\n+++ b/b.txt\n@@ -1 +1 @@\n-b\n+B\n";
+
+    // `b.txt/c.txt` applies but cannot be written: nothing else may be either.
+    let blocked = format!("{patch}--- /dev/null\n+++ b/b.txt/c.txt\n@@ -0,0 +1 @@\n+c\n");
+    let opts = GitApplyPatchOptions { worktreePath: root.to_string_lossy().into_owned(), patch: blocked, ..Default::default() };
+    assert!(apply_patch(opts).is_err());
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "a\n");
+    assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "b\n");
+    assert_eq!(fs::read_dir(root).unwrap().count(), 2, "temp files left behind");
+
+    // A replaced file keeps its mode.
+    assert!(apply(root, patch, false, false).applied);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "A\n");
+    assert_eq!(fs::metadata(root.join("a.txt")).unwrap().permissions().mode() & 0o777, 0o755);
+  }
+}
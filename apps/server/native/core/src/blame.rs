@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Result};
+use gix::bstr::ByteSlice;
+use gix::{hash::ObjectId, Repository};
+use similar::{DiffOp, TextDiff};
+use std::collections::HashMap;
+
+use crate::diff::refs::oid_from_rev_parse;
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::repo::partial::{fetch_missing, no_fetch};
+use crate::types::{BlameLine, GitBlameOptions};
+
+pub(crate) struct CommitInfo {
+  pub(crate) author_name: String,
+  pub(crate) author_email: String,
+  pub(crate) author_time: i64,
+  pub(crate) summary: String,
+}
+
+// Entry at `path` (slash-separated) in the tree `tree_id`, with whether it is a tree itself.
+pub(crate) fn entry_at_path(repo: &Repository, tree_id: ObjectId, path: &str) -> Result<Option<(ObjectId, bool)>> {
+  let mut tree_id = tree_id;
+  let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
+  while let Some(part) = parts.next() {
+    let tree = repo.find_object(tree_id)?.try_into_tree()?;
+    let mut found = None;
+    for entry in tree.iter() {
+      let entry = entry?;
+      if entry.filename().as_bytes() == part.as_bytes() {
+        found = Some((entry.oid().to_owned(), entry.mode().is_tree()));
+        break;
+      }
+    }
+    let Some((id, is_tree)) = found else { return Ok(None) };
+    match (parts.peek().is_none(), is_tree) {
+      (true, _) => return Ok(Some((id, is_tree))),
+      (false, true) => tree_id = id,
+      (false, false) => return Ok(None),
+    }
+  }
+  Ok(None)
+}
+
+// Blob at `path`; None when the path is missing or names a directory.
+pub(crate) fn blob_at_path(repo: &Repository, tree_id: ObjectId, path: &str) -> Result<Option<ObjectId>> {
+  Ok(entry_at_path(repo, tree_id, path)?.and_then(|(id, is_tree)| (!is_tree).then_some(id)))
+}
+
+// Contents of a text blob; None for binary data.
+pub(crate) fn blob_text(repo: &Repository, id: ObjectId) -> Result<Option<String>> {
+  fetch_missing(repo, [id])?;
+  let blob = repo.find_object(id)?.try_into_blob()?;
+  if blob.data.contains(&0) {
+    return Ok(None);
+  }
+  Ok(std::str::from_utf8(&blob.data).ok().map(|s| s.to_string()))
+}
+
+pub(crate) fn commit_tree(repo: &Repository, id: ObjectId) -> Result<ObjectId> {
+  Ok(repo.find_object(id)?.try_into_commit()?.tree_id()?.detach())
+}
+
+pub(crate) fn commit_info(repo: &Repository, id: ObjectId) -> Result<CommitInfo> {
+  let commit = repo.find_object(id)?.try_into_commit()?;
+  let author = commit.author()?;
+  let info = CommitInfo {
+    author_name: author.name.to_str_lossy().into_owned(),
+    author_email: author.email.to_str_lossy().into_owned(),
+    author_time: author.time.seconds * 1000,
+    summary: commit.message()?.summary().to_str_lossy().into_owned(),
+  };
+  Ok(info)
+}
+
+/// Blame `filePath` at `ref`: for each line, the commit that last changed it.
+///
+/// History is walked from `ref` the way `git blame` does, except through merges: blame passes
+/// to a parent that has the file unchanged, and otherwise only to the first parent, so lines
+/// brought in by a merge are attributed to the merge commit. Renames are not followed.
+pub fn blame_file(opts: GitBlameOptions) -> Result<Vec<BlameLine>> {
+  let offline = opts.offline.unwrap_or(false);
+  let _no_fetch = no_fetch(offline);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let path = opts.filePath.trim_start_matches('/');
+  let rev = opts.refName.trim();
+  let head = oid_from_rev_parse(&repo, rev)?;
+  let head_blob = blob_at_path(&repo, commit_tree(&repo, head)?, path)?
+    .ok_or_else(|| anyhow!("{} does not exist at {}", path, rev))?;
+  let head_text = blob_text(&repo, head_blob)?
+    .ok_or_else(|| anyhow!("{} is a binary file", path))?;
+
+  let total = head_text.lines().count();
+  let (first, last) = match &opts.lineRange {
+    Some(range) => {
+      if range.start < 1 || range.end < range.start || range.start as usize > total {
+        return Err(anyhow!("line range {}-{} is outside {} ({} lines)", range.start, range.end, path, total));
+      }
+      (range.start as usize - 1, (range.end as usize).min(total))
+    }
+    None => (0, total),
+  };
+
+  // (line at `ref`, the same line in the version being looked at), both 0-based.
+  let mut pending: Vec<(usize, usize)> = (first..last).map(|i| (i, i)).collect();
+  let mut found: Vec<Option<(ObjectId, usize)>> = vec![None; last - first];
+  let mut current = head;
+  let mut current_blob = head_blob;
+  let mut current_text = head_text;
+  while !pending.is_empty() {
+    crate::cancel::check()?;
+    let commit = repo.find_object(current)?.try_into_commit()?;
+    let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+    let mut parent_blobs = Vec::with_capacity(parents.len());
+    for parent in &parents {
+      parent_blobs.push(blob_at_path(&repo, commit_tree(&repo, *parent)?, path)?);
+    }
+    // A parent with the file unchanged takes all of the blame.
+    if let Some(i) = parent_blobs.iter().position(|b| *b == Some(current_blob)) {
+      current = parents[i];
+      continue;
+    }
+    let parent = match parent_blobs.first().copied().flatten() {
+      Some(blob) => blob_text(&repo, blob)?.map(|text| (blob, text)),
+      None => None,
+    };
+    // The file starts here (root commit, added, or previously binary): so does every line
+    // still pending.
+    let Some((parent_blob, parent_text)) = parent else {
+      for (line, at) in pending.drain(..) {
+        found[line - first] = Some((current, at));
+      }
+      break;
+    };
+    let mut to_parent: HashMap<usize, usize> = HashMap::new();
+    for op in TextDiff::from_lines(&parent_text, &current_text).ops() {
+      if let DiffOp::Equal { old_index, new_index, len } = *op {
+        for k in 0..len {
+          to_parent.insert(new_index + k, old_index + k);
+        }
+      }
+    }
+    pending.retain_mut(|(line, at)| match to_parent.get(at) {
+      Some(&old) => {
+        *at = old;
+        true
+      }
+      None => {
+        found[*line - first] = Some((current, *at));
+        false
+      }
+    });
+    current = parents[0];
+    current_blob = parent_blob;
+    current_text = parent_text;
+  }
+
+  let mut infos: HashMap<ObjectId, CommitInfo> = HashMap::new();
+  let mut out = Vec::with_capacity(found.len());
+  for (i, entry) in found.into_iter().enumerate() {
+    let (id, at) = entry.ok_or_else(|| anyhow!("line {} was not blamed", first + i + 1))?;
+    if !infos.contains_key(&id) {
+      infos.insert(id, commit_info(&repo, id)?);
+    }
+    let info = &infos[&id];
+    out.push(BlameLine {
+      lineNumber: (first + i + 1) as i32,
+      originalLineNumber: (at + 1) as i32,
+      commitSha: id.to_hex().to_string(),
+      authorName: info.author_name.clone(),
+      authorEmail: info.author_email.clone(),
+      authorTime: info.author_time,
+      summary: info.summary.clone(),
+      stale: stale.then_some(true),
+    });
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::BlameLineRange;
+  use crate::test_util::commit_as;
+  use crate::util::run_git;
+  use std::fs;
+  use tempfile::tempdir;
+
+  fn opts(repo: &str, file: &str, range: Option<(i32, i32)>) -> GitBlameOptions {
+    GitBlameOptions {
+      originPathOverride: Some(repo.to_string()),
+      refName: "HEAD".into(),
+      filePath: file.into(),
+      lineRange: range.map(|(start, end)| BlameLineRange { start, end }),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn blames_each_line_to_the_commit_that_last_changed_it() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    fs::create_dir_all(tmp.path().join("src")).unwrap();
+    fs::write(tmp.path().join("src/a.txt"), "one\ntwo\nthree\n").unwrap();
+    let c1 = commit_as(repo, "Ada", None, "add a.txt");
+    fs::write(tmp.path().join("src/a.txt"), "one\nTWO\nthree\n").unwrap();
+    let c2 = commit_as(repo, "Bob", None, "shout two\n\nlonger body");
+    fs::write(tmp.path().join("other.txt"), "unrelated\n").unwrap();
+    commit_as(repo, "Cy", None, "touch another file");
+    fs::write(tmp.path().join("src/a.txt"), "zero\none\nTWO\nthree\n").unwrap();
+    let c4 = commit_as(repo, "Dee", None, "prepend zero");
+
+    let lines = blame_file(opts(repo, "src/a.txt", None)).expect("blame");
+    let shas: Vec<&str> = lines.iter().map(|l| l.commitSha.as_str()).collect();
+    assert_eq!(shas, vec![c4.as_str(), c1.as_str(), c2.as_str(), c1.as_str()]);
+    assert_eq!(lines[2].lineNumber, 3);
+    assert_eq!(lines[2].originalLineNumber, 2);
+    assert_eq!(lines[2].authorName, "Bob");
+    assert_eq!(lines[2].authorEmail, "bob@example.com");
+    assert_eq!(lines[2].summary, "shout two");
+    assert!(lines[2].authorTime > 0);
+    assert!(lines.iter().all(|l| l.stale.is_none()));
+
+    let ranged = blame_file(opts(repo, "src/a.txt", Some((2, 99)))).expect("blame range");
+    let numbers: Vec<i32> = ranged.iter().map(|l| l.lineNumber).collect();
+    assert_eq!(numbers, vec![2, 3, 4]);
+    assert_eq!(ranged[1].commitSha, c2);
+
+    let err = blame_file(opts(repo, "src/missing.txt", None)).expect_err("missing file");
+    assert!(err.to_string().contains("does not exist"), "{err}");
+    let err = blame_file(opts(repo, "src/a.txt", Some((9, 10)))).expect_err("bad range");
+    assert!(err.to_string().contains("outside"), "{err}");
+  }
+}
@@ -2,17 +2,24 @@ use anyhow::Result;
 use gix::bstr::ByteSlice;
 use gix::{hash::ObjectId};
 
-use crate::repo::cache::{ensure_repo, resolve_repo_url, swr_fetch_origin_all_path};
+use crate::ahead_behind::count_ahead_behind;
+use crate::repo::cache::{cached_repo, ensure_repo, is_mirror, is_stale, resolve_repo_url, swr_fetch_origin_all_path, RepoOptions};
 use crate::types::{BranchInfo, GitListRemoteBranchesOptions};
 
-fn refname_to_branch(name: &str) -> Option<(String /*remote*/, String /*branch*/)> {
-  // Expect refs/remotes/<remote>/<branch>
-  let p = name.strip_prefix("refs/remotes/")?;
-  let mut it = p.splitn(2, '/');
-  let remote = it.next().unwrap_or("");
-  let branch = it.next().unwrap_or("");
+// Where a repo keeps origin's branches and which ref names its default branch: a cached mirror
+// has them as its own branches and HEAD, a regular clone as `refs/remotes/origin/*`.
+fn origin_layout(repo: &gix::Repository) -> (&'static str, &'static str) {
+  if is_mirror(repo) {
+    ("refs/heads/", "HEAD")
+  } else {
+    ("refs/remotes/origin/", "refs/remotes/origin/HEAD")
+  }
+}
+
+fn refname_to_branch(name: &str, prefix: &str) -> Option<String> {
+  let branch = name.strip_prefix(prefix)?;
   if branch.is_empty() || branch == "HEAD" { return None; }
-  Some((remote.to_string(), branch.to_string()))
+  Some(branch.to_string())
 }
 
 fn oid_to_hex(oid: ObjectId) -> String {
@@ -20,46 +27,54 @@ fn oid_to_hex(oid: ObjectId) -> String {
 }
 
 pub fn list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<BranchInfo>> {
+  let offline = opts.offline.unwrap_or(false);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
   // Resolve local repo path
   let repo_path = if let Some(p) = &opts.originPathOverride {
     std::path::PathBuf::from(p)
   } else {
-    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref())?;
-    ensure_repo(&url)?
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
   };
 
-  // Make sure remotes are fresh (this is cheap if within SWR window)
-  let _ = swr_fetch_origin_all_path(&repo_path, crate::repo::cache::fetch_window_ms());
+  // `ensure_repo` already fetched a cached clone; a caller's own repo is fetched here (this is
+  // cheap if within the SWR window). Offline callers get whatever the cache has, flagged when
+  // it is older than the window.
+  let stale = if offline {
+    is_stale(&repo_path, repo_opts.window_ms())
+  } else {
+    if opts.originPathOverride.is_some() {
+      let _ = swr_fetch_origin_all_path(&repo_path, repo_opts.window_ms());
+    }
+    false
+  };
 
   let repo = gix::open(&repo_path)?;
 
   // Iterate remote refs and assemble info
   let refs = repo.references()?;
   let mut out: Vec<BranchInfo> = Vec::new();
-  let mut iter = refs.all()?;
+  // Tip commit of each entry of `out`; None when the tip is not a commit.
+  let mut tips: Vec<Option<ObjectId>> = Vec::new();
+  let iter = refs.all()?;
 
   // Determine origin/HEAD target branch (short name)
+  let (prefix, head_name) = origin_layout(&repo);
   let mut origin_head_short: Option<String> = None;
-  if let Ok(head_ref) = repo.find_reference("refs/remotes/origin/HEAD") {
+  if let Ok(head_ref) = repo.find_reference(head_name) {
     if let Some(name) = head_ref.target().try_name() {
       let s = name.as_bstr().to_str_lossy().into_owned();
-      if let Some((remote, short)) = refname_to_branch(&s) {
-        if remote == "origin" {
-          origin_head_short = Some(short);
-        }
-      }
+      origin_head_short = refname_to_branch(&s, prefix);
     }
   }
 
-  while let Some(r) = iter.next() {
+  for r in iter {
     let r = match r {
       Ok(v) => v,
       Err(_) => continue,
     };
     let name = r.name().as_bstr().to_str_lossy().into_owned();
-    if !name.starts_with("refs/remotes/") { continue; }
-    let Some((remote, short)) = refname_to_branch(&name) else { continue };
-    if remote != "origin" { continue; }
+    let Some(short) = refname_to_branch(&name, prefix) else { continue };
 
     // Resolve target OID (skip symbolic remote/HEAD)
     let tgt = r.target();
@@ -67,8 +82,15 @@ pub fn list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<Br
     let id: ObjectId = id_ref.to_owned();
     // Read commit to get committer time; if it's not a commit, skip time
     let mut last_ts: Option<i64> = None;
+    let mut author: Option<(String, String)> = None;
+    let mut tip: Option<ObjectId> = None;
     if let Ok(obj) = repo.find_object(id) {
       if let Ok(commit) = obj.try_into_commit() {
+        tip = Some(id);
+        author = commit
+          .author()
+          .ok()
+          .map(|sig| (sig.name.to_str_lossy().into_owned(), sig.email.to_str_lossy().into_owned()));
         // Prefer committer time, then author time
         let t = commit
           .committer()
@@ -76,7 +98,7 @@ pub fn list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<Br
           .map(|sig| sig.time)
           .or_else(|| commit.author().ok().map(|sig| sig.time));
         if let Some(t) = t {
-          last_ts = Some((t.seconds as i64) * 1000);
+          last_ts = Some(t.seconds * 1000);
         }
       }
     }
@@ -85,6 +107,7 @@ pub fn list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<Br
       .as_ref()
       .map(|h| h == &short)
       .unwrap_or(false);
+    let (author_name, author_email) = author.unzip();
     out.push(BranchInfo {
       name: short,
       lastCommitSha: Some(oid_to_hex(id)),
@@ -92,7 +115,31 @@ pub fn list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<Br
       isDefault: Some(is_default),
       lastKnownBaseSha: None,
       lastKnownMergeCommitSha: None,
+      authorName: author_name,
+      authorEmail: author_email,
+      stale: stale.then_some(true),
+      ..Default::default()
     });
+    tips.push(tip);
+  }
+
+  // Ahead/behind the default branch, for every branch in a single walk.
+  let default_tip = origin_head_short
+    .as_ref()
+    .and_then(|h| out.iter().zip(&tips).find(|(b, _)| &b.name == h))
+    .and_then(|(_, tip)| *tip);
+  if let Some(base) = default_tip {
+    let heads: Vec<ObjectId> = tips.iter().flatten().copied().collect();
+    if let Ok(counts) = count_ahead_behind(&repo, base, &heads) {
+      let mut counts = counts.into_iter();
+      for (b, tip) in out.iter_mut().zip(&tips) {
+        if tip.is_none() { continue; }
+        let Some((ahead, behind)) = counts.next() else { break };
+        b.ahead = Some(ahead);
+        b.behind = Some(behind);
+        b.isMerged = Some(ahead == 0);
+      }
+    }
   }
 
   // Sort: pin main/dev/master/develop first; then by activity desc; then name asc
@@ -196,6 +243,12 @@ mod tests {
       repoFullName: None,
       repoUrl: None,
       originPathOverride: Some(clone.to_string_lossy().to_string()),
+      offline: None,
+      authToken: None,
+      useSsh: None,
+      providerBaseUrl: None,
+      partialClone: None,
+      fetchWindowMs: None,
     }).expect("list branches");
     let names: Vec<String> = res.iter().map(|b| b.name.clone()).collect();
 
@@ -210,5 +263,59 @@ mod tests {
     // Verify isDefault marker for main
     let main_row = res.iter().find(|b| b.name == "main").unwrap();
     assert_eq!(main_row.isDefault, Some(true));
+    assert_eq!(main_row.authorName.as_deref(), Some("Test"));
+    assert_eq!(main_row.authorEmail.as_deref(), Some("test@example.com"));
+
+    // Counted against main, whose second commit neither branch has.
+    let counts = |name: &str| {
+      let b = res.iter().find(|b| b.name == name).unwrap();
+      (b.ahead, b.behind, b.isMerged)
+    };
+    assert_eq!(counts("main"), (Some(0), Some(0), Some(true)));
+    assert_eq!(counts("dev"), (Some(1), Some(1), Some(false)));
+    assert_eq!(counts("feature"), (Some(2), Some(1), Some(false)));
+
+    // A mirror, as the repo cache keeps, lists the same branches from its own heads.
+    let mirror = root.join("mirror.git");
+    run_git(root.to_str().unwrap(), &["clone", "--mirror", &origin_url, mirror.file_name().unwrap().to_str().unwrap()]).unwrap();
+    let mirrored = list_remote_branches(GitListRemoteBranchesOptions {
+      originPathOverride: Some(mirror.to_string_lossy().to_string()),
+      ..Default::default()
+    }).expect("list mirror branches");
+    let mirrored_names: Vec<String> = mirrored.iter().map(|b| b.name.clone()).collect();
+    assert_eq!(mirrored_names, names);
+    assert_eq!(mirrored.iter().find(|b| b.name == "main").unwrap().isDefault, Some(true));
+
+    // Offline never fetches: a branch pushed after the last fetch stays invisible, and the
+    // answer is not stale because the clone was fetched just now.
+    run_git(seed.to_str().unwrap(), &["checkout", "-b", "late"]).unwrap();
+    run_git(seed.to_str().unwrap(), &["push", "-u", "origin", "late"]).unwrap();
+    let offline = list_remote_branches(GitListRemoteBranchesOptions {
+      repoFullName: None,
+      repoUrl: None,
+      originPathOverride: Some(clone.to_string_lossy().to_string()),
+      offline: Some(true),
+      authToken: None,
+      useSsh: None,
+      providerBaseUrl: None,
+      partialClone: None,
+      fetchWindowMs: None,
+    }).expect("list branches offline");
+    assert!(!offline.iter().any(|b| b.name == "late"));
+    assert!(offline.iter().all(|b| b.stale.is_none()));
+
+    // Offline without a cached clone refuses to clone.
+    let err = list_remote_branches(GitListRemoteBranchesOptions {
+      repoFullName: None,
+      repoUrl: Some("https://example.invalid/nobody/never-cached.git".into()),
+      originPathOverride: None,
+      offline: Some(true),
+      authToken: None,
+      useSsh: None,
+      providerBaseUrl: None,
+      partialClone: None,
+      fetchWindowMs: None,
+    }).expect_err("no cache");
+    assert!(err.to_string().contains("offline"), "{err}");
   }
 }
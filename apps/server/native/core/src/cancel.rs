@@ -0,0 +1,147 @@
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// How long a cancellation that arrived before its call started is kept for that call.
+const PENDING_TTL: Duration = Duration::from_secs(60);
+
+/// The error a cancelled call fails with; see `is_cancelled_error`.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("cancelled")
+  }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[derive(Default)]
+struct Registry {
+  running: HashMap<String, Vec<Arc<AtomicBool>>>,
+  // Ids cancelled before any call with them started, e.g. when the caller gave up while the
+  // call was still queued for a blocking thread.
+  pending: HashMap<String, Instant>,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+thread_local! {
+  static CURRENT: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+fn registry() -> &'static Mutex<Registry> {
+  REGISTRY.get_or_init(|| Mutex::new(Registry::default()))
+}
+
+fn current() -> Option<Arc<AtomicBool>> {
+  CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Makes the work on this thread cancellable under `id` until dropped. Without an id the
+/// scope does nothing.
+pub struct Scope {
+  id: Option<String>,
+  flag: Arc<AtomicBool>,
+  previous: Option<Arc<AtomicBool>>,
+}
+
+pub fn scope(id: Option<String>) -> Scope {
+  let flag = Arc::new(AtomicBool::new(false));
+  let previous = current();
+  if let Some(id) = &id {
+    if let Ok(mut r) = registry().lock() {
+      if r.pending.remove(id).is_some() {
+        flag.store(true, Ordering::Relaxed);
+      }
+      r.running.entry(id.clone()).or_default().push(flag.clone());
+    }
+    CURRENT.with(|c| *c.borrow_mut() = Some(flag.clone()));
+  }
+  Scope { id, flag, previous }
+}
+
+impl Drop for Scope {
+  fn drop(&mut self) {
+    let Some(id) = &self.id else { return };
+    if let Ok(mut r) = registry().lock() {
+      if let Some(flags) = r.running.get_mut(id) {
+        flags.retain(|f| !Arc::ptr_eq(f, &self.flag));
+        if flags.is_empty() {
+          r.running.remove(id);
+        }
+      }
+    }
+    CURRENT.with(|c| *c.borrow_mut() = self.previous.take());
+  }
+}
+
+/// Cancels every running call tagged `id`; false when there was none, in which case a call
+/// with that id starting within the next minute starts out cancelled.
+pub fn cancel(id: &str) -> bool {
+  let Ok(mut r) = registry().lock() else { return false };
+  if let Some(flags) = r.running.get(id) {
+    for flag in flags {
+      flag.store(true, Ordering::Relaxed);
+    }
+    return true;
+  }
+  r.pending.retain(|_, at| at.elapsed() < PENDING_TTL);
+  r.pending.insert(id.to_string(), Instant::now());
+  false
+}
+
+/// Fails with `Cancelled` once this thread's call has been cancelled. Long loops call it.
+pub fn check() -> Result<()> {
+  match current() {
+    Some(flag) if flag.load(Ordering::Relaxed) => Err(Cancelled.into()),
+    _ => Ok(()),
+  }
+}
+
+/// Whether this thread is running a call that can be cancelled.
+pub fn in_scope() -> bool {
+  current().is_some()
+}
+
+pub fn is_cancelled_error(e: &anyhow::Error) -> bool {
+  e.downcast_ref::<Cancelled>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::run_git;
+
+  #[test]
+  fn cancels_running_and_not_yet_started_calls() {
+    {
+      let _scope = scope(Some("job-1".into()));
+      assert!(check().is_ok());
+      assert!(run_git(".", &["--version"]).is_ok());
+      assert!(!cancel("job-2"));
+      assert!(cancel("job-1"));
+      let err = check().expect_err("cancelled");
+      assert!(is_cancelled_error(&err));
+      let err = run_git(".", &["--version"]).expect_err("git is not run once cancelled");
+      assert!(is_cancelled_error(&err), "{err}");
+    }
+    assert!(check().is_ok());
+
+    // Cancelling before the call starts still cancels it.
+    assert!(!cancel("job-3"));
+    {
+      let _scope = scope(Some("job-3".into()));
+      assert!(check().is_err());
+    }
+    let _scope = scope(Some("job-3".into()));
+    assert!(check().is_ok());
+
+    let _untagged = scope(None);
+    assert!(check().is_ok());
+  }
+}
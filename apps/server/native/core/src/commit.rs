@@ -0,0 +1,115 @@
+use anyhow::Result;
+use gix::bstr::ByteSlice;
+
+use crate::blame::commit_tree;
+use crate::diff::refs::oid_from_rev_parse;
+use crate::log::{commit_stats, file_stats};
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::repo::partial::no_fetch;
+use crate::types::{CommitDetails, GitCommitInfoOptions};
+
+/// Everything about one commit a commit card shows: who wrote and committed it, the full
+/// message, its parents, and the files it changed against its first parent.
+pub fn commit_details(opts: GitCommitInfoOptions) -> Result<CommitDetails> {
+  let offline = opts.offline.unwrap_or(false);
+  let _no_fetch = no_fetch(offline);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let id = oid_from_rev_parse(&repo, opts.sha.trim())?;
+  let commit = repo.find_object(id)?.peel_to_commit()?;
+  let id = commit.id;
+  let parents: Vec<gix::hash::ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+  let parent_tree = match parents.first() {
+    Some(parent) => Some(commit_tree(&repo, *parent)?),
+    None => None,
+  };
+  let files = file_stats(&repo, commit.tree_id()?.detach(), parent_tree)?;
+  let decoded = commit.decode()?;
+  let (author, committer) = (decoded.author, decoded.committer);
+  Ok(CommitDetails {
+    sha: id.to_hex().to_string(),
+    parents: parents.iter().map(|p| p.to_hex().to_string()).collect(),
+    authorName: author.name.to_str_lossy().into_owned(),
+    authorEmail: author.email.to_str_lossy().into_owned(),
+    authorTime: author.time.seconds * 1000,
+    committerName: committer.name.to_str_lossy().into_owned(),
+    committerEmail: committer.email.to_str_lossy().into_owned(),
+    committerTime: committer.time.seconds * 1000,
+    summary: commit.message()?.summary().to_str_lossy().into_owned(),
+    message: decoded.message.to_str_lossy().trim_end().to_string(),
+    stats: commit_stats(&files),
+    files,
+    stale: stale.then_some(true),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::commit_as;
+  use crate::util::run_git;
+  use std::fs;
+  use tempfile::tempdir;
+
+  #[test]
+  fn describes_a_commit_and_its_files() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    let commit = |message: &str| commit_as(repo, "Ada", None, message);
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    fs::write(tmp.path().join("a.txt"), "one\ntwo\n").unwrap();
+    fs::write(tmp.path().join("gone.txt"), "bye\n").unwrap();
+    let c1 = commit("first");
+    fs::write(tmp.path().join("a.txt"), "one\n2\nthree\n").unwrap();
+    fs::remove_file(tmp.path().join("gone.txt")).unwrap();
+    fs::write(tmp.path().join("logo.bin"), [0u8, 1, 2, 0]).unwrap();
+    let c2 = commit("second\n\nWith a body.");
+
+    let details = commit_details(GitCommitInfoOptions {
+      originPathOverride: Some(repo.to_string()),
+      sha: c2.clone(),
+      ..Default::default()
+    })
+    .expect("details");
+    assert_eq!(details.sha, c2);
+    assert_eq!(details.parents, vec![c1.clone()]);
+    assert_eq!((details.authorName.as_str(), details.authorEmail.as_str()), ("Ada", "ada@example.com"));
+    assert_eq!(details.committerEmail, "ada@example.com");
+    assert_eq!(details.summary, "second");
+    assert_eq!(details.message, "second\n\nWith a body.");
+    let files: Vec<(&str, &str, i32, i32, bool)> = details
+      .files
+      .iter()
+      .map(|f| (f.path.as_str(), f.status.as_str(), f.additions, f.deletions, f.binary))
+      .collect();
+    assert_eq!(
+      files,
+      vec![
+        ("a.txt", "modified", 2, 1, false),
+        ("gone.txt", "deleted", 0, 1, false),
+        ("logo.bin", "added", 0, 0, true),
+      ]
+    );
+    assert_eq!((details.stats.filesChanged, details.stats.additions, details.stats.deletions), (3, 2, 2));
+
+    // A root commit is compared against nothing; refs resolve like elsewhere.
+    let root = commit_details(GitCommitInfoOptions {
+      originPathOverride: Some(repo.to_string()),
+      sha: "main~1".into(),
+      ..Default::default()
+    })
+    .expect("root");
+    assert_eq!(root.sha, c1);
+    assert!(root.parents.is_empty());
+    assert_eq!(root.stats.additions, 3);
+  }
+}
@@ -0,0 +1,89 @@
+// Paths that are rarely worth reviewing line-by-line (generated, vendored or minified). Used
+// when `textOnly` is set and the caller does not pass its own `collapsePatterns`.
+pub const DEFAULT_COLLAPSE_PATTERNS: &[&str] = &[
+  "package-lock.json",
+  "pnpm-lock.yaml",
+  "yarn.lock",
+  "bun.lock",
+  "bun.lockb",
+  "Cargo.lock",
+  "Gemfile.lock",
+  "poetry.lock",
+  "composer.lock",
+  "go.sum",
+  "*.snap",
+  "__snapshots__/",
+  "*.map",
+  "*.min.js",
+  "*.min.css",
+];
+
+/// Decides which files are returned as collapsed entries (sizes only, no content or counts).
+///
+/// Pattern forms:
+/// - `*.ext` matches by suffix (`*.min.js`, `*.snap`)
+/// - `dir/` matches any path with a directory component named `dir`
+/// - anything else matches the file name exactly (`yarn.lock`)
+#[derive(Debug, Clone)]
+pub struct CollapseFilter {
+  patterns: Vec<String>,
+}
+
+impl CollapseFilter {
+  pub fn new(patterns: Vec<String>) -> Self {
+    Self { patterns }
+  }
+
+  /// Returns None unless `textOnly` is set; an explicit pattern list replaces the defaults.
+  pub fn from_options(text_only: Option<bool>, patterns: Option<&[String]>) -> Option<Self> {
+    if !text_only.unwrap_or(false) { return None; }
+    let patterns = match patterns {
+      Some(p) => p.to_vec(),
+      None => DEFAULT_COLLAPSE_PATTERNS.iter().map(|s| s.to_string()).collect(),
+    };
+    Some(Self::new(patterns))
+  }
+
+  pub fn matches(&self, path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    self.patterns.iter().any(|pat| {
+      let pat = pat.trim();
+      if pat.is_empty() { return false; }
+      if let Some(suffix) = pat.strip_prefix('*') {
+        return !suffix.is_empty() && name.ends_with(suffix);
+      }
+      if let Some(dir) = pat.strip_suffix('/') {
+        return path.split('/').rev().skip(1).any(|c| c == dir);
+      }
+      name == pat
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn default_patterns_cover_lockfiles_snapshots_and_maps() {
+    let f = CollapseFilter::from_options(Some(true), None).unwrap();
+    assert!(f.matches("pnpm-lock.yaml"));
+    assert!(f.matches("apps/www/package-lock.json"));
+    assert!(f.matches("src/__snapshots__/view.test.ts.snap"));
+    assert!(f.matches("src/__snapshots__/data.json"));
+    assert!(f.matches("dist/app.js.map"));
+    assert!(f.matches("vendor/jquery.min.js"));
+    assert!(!f.matches("src/lib.rs"));
+    assert!(!f.matches("docs/yarn.lock.md"));
+    assert!(!f.matches("__snapshots__"));
+  }
+
+  #[test]
+  fn explicit_patterns_replace_defaults_and_text_only_gates() {
+    assert!(CollapseFilter::from_options(None, None).is_none());
+    assert!(CollapseFilter::from_options(Some(false), Some(&["*.lock".to_string()])).is_none());
+    let f = CollapseFilter::from_options(Some(true), Some(&["*.generated.ts".to_string()])).unwrap();
+    assert!(f.matches("src/api.generated.ts"));
+    assert!(!f.matches("pnpm-lock.yaml"));
+  }
+}
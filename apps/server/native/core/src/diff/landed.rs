@@ -1,170 +1,9 @@
 use anyhow::Result;
-use gix::{bstr::ByteSlice, hash::ObjectId, Repository};
 use std::time::{Instant};
 
+use crate::landed::{detect_landing, resolve_ref_with_origin};
 use crate::types::{DiffEntry, GitDiffLandedOptions, GitDiffRefsOptions};
 
-fn oid_from_rev_parse(repo: &Repository, rev: &str) -> anyhow::Result<ObjectId> {
-  if let Ok(oid) = ObjectId::from_hex(rev.as_bytes()) { return Ok(oid); }
-  let candidates = [
-    rev.to_string(),
-    format!("refs/remotes/origin/{}", rev),
-    format!("refs/heads/{}", rev),
-    format!("refs/tags/{}", rev),
-  ];
-  for cand in candidates {
-    if let Ok(r) = repo.find_reference(&cand) {
-      if let Some(id) = r.target().try_id() { return Ok(id.to_owned()); }
-    }
-  }
-  if let Ok(spec) = repo.rev_parse_single(rev) {
-    if let Ok(obj) = spec.object() { return Ok(obj.id); }
-  }
-  Err(anyhow::anyhow!("could not resolve rev '{}'", rev))
-}
-
-fn fallback_origin_ref(name: &str) -> Option<String> {
-  let trimmed = name.trim();
-  if trimmed.is_empty() || trimmed == "origin" || trimmed.starts_with("refs/") {
-    return None;
-  }
-  let mut without = trimmed;
-  while let Some(stripped) = without.strip_prefix("origin/") {
-    without = stripped;
-  }
-  let candidate = format!("origin/{}", without);
-  if candidate == trimmed {
-    None
-  } else {
-    Some(candidate)
-  }
-}
-
-fn resolve_ref_with_origin(repo: &Repository, name: &str) -> anyhow::Result<ObjectId> {
-  match oid_from_rev_parse(repo, name) {
-    Ok(oid) => Ok(oid),
-    Err(orig_err) => {
-      if let Some(fallback) = fallback_origin_ref(name) {
-        match oid_from_rev_parse(repo, &fallback) {
-          Ok(oid) => Ok(oid),
-          Err(_) => Err(orig_err),
-        }
-      } else {
-        Err(orig_err)
-      }
-    }
-  }
-}
-
-fn is_ancestor(repo: &Repository, anc: ObjectId, desc: ObjectId) -> bool {
-  // ancestor if merge-base(desc, anc) == anc
-  match crate::merge_base::merge_base("", repo, desc, anc, crate::merge_base::MergeBaseStrategy::Bfs) {
-    Some(x) if x == anc => true,
-    _ => false,
-  }
-}
-
-fn first_commit_after_b0_on_first_parent(repo: &Repository, b_tip: ObjectId, b0: ObjectId) -> Option<ObjectId> {
-  let mut cur = b_tip;
-  let mut guard = 0usize;
-  while guard < 200_000 {
-    guard += 1;
-    if cur == b0 { return None; }
-    let obj = repo.find_object(cur).ok()?;
-    let commit = obj.try_into_commit().ok()?;
-    let mut parents = commit.parent_ids();
-    let p1 = parents.next()?.detach();
-    if p1 == b0 { return Some(cur); }
-    cur = p1;
-  }
-  None
-}
-
-fn find_merge_integrating_head(repo: &Repository, base_tip: ObjectId, head_tip: ObjectId, limit: usize) -> Option<(ObjectId, ObjectId)> {
-  let mut cur = base_tip;
-  let mut seen = 0usize;
-  while seen < limit {
-    seen += 1;
-    let obj = repo.find_object(cur).ok()?;
-    let commit = obj.try_into_commit().ok()?;
-    let (p1, p2) = {
-      let mut it = commit.parent_ids();
-      (it.next().map(|x| x.detach()), it.next().map(|x| x.detach()))
-    };
-    if let (Some(p1), Some(p2)) = (p1, p2) {
-      if is_ancestor(repo, p2, head_tip) {
-        return Some((p1, cur));
-      }
-    }
-    let pnext = {
-      let mut it = commit.parent_ids();
-      it.next().map(|x| x.detach())
-    };
-    if let Some(p1) = pnext { cur = p1; } else { break; }
-  }
-  None
-}
-
-fn find_merge_by_message(
-  repo: &Repository,
-  base_tip: ObjectId,
-  head_ref: &str,
-  limit: usize,
-) -> Option<(ObjectId, ObjectId)> {
-  let mut cur = base_tip;
-  let mut seen = 0usize;
-  let needle = head_ref.trim_start_matches("origin/");
-  while seen < limit {
-    seen += 1;
-    let obj = repo.find_object(cur).ok()?;
-    let commit = obj.try_into_commit().ok()?;
-    // Only consider merge commits (>=2 parents)
-    let (p1, p2) = {
-      let mut it = commit.parent_ids();
-      (it.next().map(|x| x.detach()), it.next().map(|x| x.detach()))
-    };
-    if let (Some(p1), Some(_p2)) = (p1, p2) {
-      // Match commit message against head branch name
-      let msg = commit.message_raw().ok()?;
-      let text = msg.to_str_lossy();
-      if text.contains(needle) {
-        #[cfg(debug_assertions)]
-        println!(
-          "[native.landed] merge-by-message matched branch '{}' at {}",
-          needle, cur
-        );
-        return Some((p1, cur));
-      }
-    }
-    // Walk first-parent chain
-    let next = {
-      let mut it = commit.parent_ids();
-      it.next().map(|x| x.detach())
-    };
-    if let Some(n) = next { cur = n; } else { break; }
-  }
-  None
-}
-
-fn last_fp_block_ancestor_of_head(repo: &Repository, b_tip: ObjectId, b0: ObjectId, head_tip: ObjectId) -> Option<ObjectId> {
-  let mut cur = b_tip;
-  let mut last = None;
-  let mut guard = 0usize;
-  while guard < 200_000 {
-    guard += 1;
-    if is_ancestor(repo, cur, head_tip) { last = Some(cur); }
-    if cur == b0 { break; }
-    let obj = repo.find_object(cur).ok()?;
-    let commit = obj.try_into_commit().ok()?;
-    let pnext = {
-      let mut it = commit.parent_ids();
-      it.next().map(|x| x.detach())
-    };
-    if let Some(p1) = pnext { cur = p1; } else { break; }
-  }
-  last
-}
-
 pub fn landed_diff(opts: GitDiffLandedOptions) -> Result<Vec<DiffEntry>> {
   let t_total = Instant::now();
   #[cfg(debug_assertions)]
@@ -178,8 +17,8 @@ pub fn landed_diff(opts: GitDiffLandedOptions) -> Result<Vec<DiffEntry>> {
   // Reuse ensure_repo & path resolution
   let t_repo_path = Instant::now();
   let repo_path = if let Some(p) = &opts.originPathOverride { std::path::PathBuf::from(p) } else {
-    let url = crate::repo::cache::resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref())?;
-    crate::repo::cache::ensure_repo(&url)?
+    let url = crate::repo::cache::resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    crate::repo::cache::ensure_repo(&url, crate::repo::cache::RepoOptions::default())?
   };
   let _d_repo_path = t_repo_path.elapsed();
   let cwd = repo_path.to_string_lossy().to_string();
@@ -215,56 +54,18 @@ pub fn landed_diff(opts: GitDiffLandedOptions) -> Result<Vec<DiffEntry>> {
 
   // Determine ref pair to diff via refs-diff
   let t_detect = Instant::now();
-  // Precompute if head is already ancestor of base (i.e., HEAD tip is contained in base).
-  // This is true for: (a) merged via merge-commit; (b) merged via fast-forward; (c) no commits on head yet.
-  // We'll use this only as a guard to avoid expensive and error-prone heuristics when there's no merge-by-message.
-  let head_is_ancestor_of_base = is_ancestor(&repo, h_tip, b_tip);
-
-  let pair: Option<(String, String)> = if let Some(b0s) = &opts.b0Ref {
-    let b0 = resolve_ref_with_origin(&repo, b0s)?;
-    if let Some(c1) = first_commit_after_b0_on_first_parent(&repo, b_tip, b0) {
-      let c1_commit = repo.find_object(c1)?.try_into_commit()?;
-      let mut parents = c1_commit.parent_ids();
-      let p1_opt = parents.next().map(|x| x.detach());
-      let p2_opt = parents.next().map(|x| x.detach());
-      if let (Some(p1), Some(_p2)) = (p1_opt, p2_opt) {
-        // Merge-commit: landed is P1 -> C1
-        Some((p1.to_string(), c1.to_string()))
-      } else if is_ancestor(&repo, c1, h_tip) {
-        // Fast-forward: extend block to last ancestor of head
-        let h0 = last_fp_block_ancestor_of_head(&repo, b_tip, b0, h_tip).unwrap_or(c1);
-        Some((b0.to_string(), h0.to_string()))
-      } else {
-        // Squash or rebase-merge: minimal landed slice B0 -> C1
-        Some((b0.to_string(), c1.to_string()))
-      }
-    } else {
-      None
-    }
-  } else {
-    // No B0: prefer message-based detection (GitHub-style merge commits)
-    #[cfg(debug_assertions)]
-    println!("[native.landed] scanning merges on base first-parent (by message, then heuristic)");
-    if let Some((p1, m)) = find_merge_by_message(&repo, b_tip, &opts.headRef, 10_000) {
-      #[cfg(debug_assertions)]
-      println!("[native.landed] strategy=merge-by-message P1={} MERGE={}", p1, m);
-      Some((p1.to_string(), m.to_string()))
-    } else if head_is_ancestor_of_base {
-      // Head tip is already contained in base, but no merge-by-message matched -> likely unmerged branch with no commits.
-      // Avoid heuristic false-positives; return empty.
-      #[cfg(debug_assertions)]
-      println!("[native.landed] head is ancestor of base and no message match; returning empty");
-      None
-    } else if let Some((p1, m)) = find_merge_integrating_head(&repo, b_tip, h_tip, 10_000) {
-      #[cfg(debug_assertions)]
-      println!("[native.landed] strategy=heuristic-merge P1={} MERGE={}", p1, m);
-      Some((p1.to_string(), m.to_string()))
-    } else {
-      #[cfg(debug_assertions)]
-      println!("[native.landed] no merging commit found on base first-parent");
-      None
-    }
+  let b0 = match &opts.b0Ref {
+    Some(b0s) => Some(resolve_ref_with_origin(&repo, b0s)?),
+    None => None,
   };
+  let landing = detect_landing(&repo, b_tip, h_tip, b0, &opts.headRef)?;
+  #[cfg(debug_assertions)]
+  if let Some(l) = &landing {
+    println!("[native.landed] strategy={} commit={}", l.strategy, l.commit);
+  } else {
+    println!("[native.landed] no landing commit found on base first-parent");
+  }
+  let pair: Option<(String, String)> = landing.map(|l| (l.from.to_string(), l.to.to_string()));
 
   let _d_detect = t_detect.elapsed();
   if let Some((r1, r2)) = pair {
@@ -0,0 +1,192 @@
+use anyhow::Result;
+use gix::hash::ObjectId;
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  sync::{Mutex, OnceLock},
+};
+
+// Bump whenever the way additions/deletions are counted changes; memo files written with a
+// different version are discarded wholesale on load.
+const MEMO_VERSION: u32 = 1;
+
+pub const DEFAULT_MEMO_MAX_ENTRIES: usize = 50_000;
+
+pub fn memo_max_entries() -> usize {
+  if let Ok(v) = std::env::var("CMUX_TEXTDIFF_MEMO_MAX_ENTRIES") {
+    if let Ok(parsed) = v.parse::<usize>() { return parsed; }
+  }
+  DEFAULT_MEMO_MAX_ENTRIES
+}
+
+fn memo_enabled() -> bool {
+  !matches!(std::env::var("CMUX_TEXTDIFF_MEMO").as_deref(), Ok("0") | Ok("false"))
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct MemoEntry {
+  additions: i32,
+  deletions: i32,
+  last_used_ms: u128,
+}
+
+#[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
+struct MemoFile {
+  version: u32,
+  entries: HashMap<String, MemoEntry>,
+}
+
+/// Add/delete line counts per (old blob, new blob) pair. Blob OIDs are content hashes, so an
+/// entry never goes stale; the only invalidation needed is on algorithm change or size pressure.
+#[derive(Debug)]
+pub struct TextDiffMemo {
+  path: PathBuf,
+  max_entries: usize,
+  entries: HashMap<String, MemoEntry>,
+  dirty: bool,
+}
+
+fn key(old: ObjectId, new: ObjectId) -> String {
+  format!("{}:{}", old, new)
+}
+
+fn now_ms() -> u128 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_millis()
+}
+
+impl TextDiffMemo {
+  pub fn load(path: &Path, max_entries: usize) -> Self {
+    let entries = fs::read(path)
+      .ok()
+      .and_then(|data| serde_json::from_slice::<MemoFile>(&data).ok())
+      .filter(|f| f.version == MEMO_VERSION)
+      .map(|f| f.entries)
+      .unwrap_or_default();
+    Self { path: path.to_path_buf(), max_entries, entries, dirty: false }
+  }
+
+  pub fn get(&mut self, old: ObjectId, new: ObjectId) -> Option<(i32, i32)> {
+    let e = self.entries.get_mut(&key(old, new))?;
+    e.last_used_ms = now_ms();
+    Some((e.additions, e.deletions))
+  }
+
+  pub fn insert(&mut self, old: ObjectId, new: ObjectId, additions: i32, deletions: i32) {
+    self.entries.insert(key(old, new), MemoEntry { additions, deletions, last_used_ms: now_ms() });
+    self.dirty = true;
+  }
+
+  #[cfg(test)]
+  pub fn len(&self) -> usize { self.entries.len() }
+
+  fn evict(&mut self) {
+    if self.entries.len() <= self.max_entries { return; }
+    let mut by_age: Vec<(String, u128)> = self.entries.iter().map(|(k, e)| (k.clone(), e.last_used_ms)).collect();
+    by_age.sort_by_key(|(_, t)| *t);
+    let excess = self.entries.len() - self.max_entries;
+    for (k, _) in by_age.into_iter().take(excess) { self.entries.remove(&k); }
+  }
+
+  /// Write the memo back to disk if anything was added since the last save.
+  pub fn save(&mut self) -> Result<()> {
+    if !self.dirty { return Ok(()); }
+    self.evict();
+    if let Some(parent) = self.path.parent() { fs::create_dir_all(parent)?; }
+    let file = MemoFile { version: MEMO_VERSION, entries: std::mem::take(&mut self.entries) };
+    let data = serde_json::to_vec(&file);
+    self.entries = file.entries;
+    // Write then rename so concurrent readers never observe a partial file.
+    let tmp = self.path.with_extension(format!("tmp-{}", std::process::id()));
+    fs::write(&tmp, data?)?;
+    fs::rename(&tmp, &self.path)?;
+    self.dirty = false;
+    Ok(())
+  }
+
+  pub fn clear(&mut self) -> Result<()> {
+    self.entries.clear();
+    self.dirty = false;
+    match fs::remove_file(&self.path) {
+      Ok(()) => Ok(()),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+      Err(e) => Err(e.into()),
+    }
+  }
+}
+
+static MEMO: OnceLock<Mutex<TextDiffMemo>> = OnceLock::new();
+
+fn memo_path() -> PathBuf {
+  crate::repo::cache::default_cache_root().join("textdiff-memo.json")
+}
+
+/// Run `f` against the process-wide memo. Returns None when the memo is disabled via
+/// `CMUX_TEXTDIFF_MEMO=0`.
+pub fn with_memo<T>(f: impl FnOnce(&mut TextDiffMemo) -> T) -> Option<T> {
+  if !memo_enabled() { return None; }
+  let memo = MEMO.get_or_init(|| Mutex::new(TextDiffMemo::load(&memo_path(), memo_max_entries())));
+  let mut guard = memo.lock().ok()?;
+  Some(f(&mut guard))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::tempdir;
+
+  fn oid(n: u8) -> ObjectId {
+    ObjectId::from_hex(format!("{:040x}", n).as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn memo_roundtrips_through_disk() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("memo.json");
+    let mut memo = TextDiffMemo::load(&path, 10);
+    assert_eq!(memo.get(oid(1), oid(2)), None);
+    memo.insert(oid(1), oid(2), 7, 3);
+    memo.save().unwrap();
+
+    let mut reloaded = TextDiffMemo::load(&path, 10);
+    assert_eq!(reloaded.get(oid(1), oid(2)), Some((7, 3)));
+    // Order matters: (new, old) is a different diff.
+    assert_eq!(reloaded.get(oid(2), oid(1)), None);
+  }
+
+  #[test]
+  fn memo_evicts_least_recently_used_beyond_limit() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("memo.json");
+    let mut memo = TextDiffMemo::load(&path, 2);
+    memo.insert(oid(1), oid(2), 1, 1);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    memo.insert(oid(3), oid(4), 2, 2);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    memo.insert(oid(5), oid(6), 3, 3);
+    memo.save().unwrap();
+
+    let mut reloaded = TextDiffMemo::load(&path, 2);
+    assert_eq!(reloaded.len(), 2);
+    assert_eq!(reloaded.get(oid(1), oid(2)), None);
+    assert_eq!(reloaded.get(oid(5), oid(6)), Some((3, 3)));
+  }
+
+  #[test]
+  fn memo_discards_other_versions_and_corrupt_files() {
+    let tmp = tempdir().unwrap();
+    let path = tmp.path().join("memo.json");
+    fs::write(&path, format!(r#"{{"version":{},"entries":{{}}}}"#, MEMO_VERSION + 1)).unwrap();
+    assert_eq!(TextDiffMemo::load(&path, 10).len(), 0);
+    fs::write(&path, "not json").unwrap();
+    let mut memo = TextDiffMemo::load(&path, 10);
+    assert_eq!(memo.len(), 0);
+    memo.insert(oid(1), oid(2), 1, 0);
+    memo.save().unwrap();
+    memo.clear().unwrap();
+    assert!(!path.exists());
+  }
+}
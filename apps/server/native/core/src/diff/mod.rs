@@ -1,3 +1,8 @@
 #[cfg(test)]
 pub mod workspace;
 pub mod refs;
+pub mod filter;
+pub mod memo;
+pub mod patch;
+pub mod pathspec;
+pub mod renames;
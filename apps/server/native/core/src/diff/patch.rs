@@ -0,0 +1,333 @@
+use similar::{ChangeTag, TextDiff};
+
+use crate::types::{DiffEntry, DiffHunk, DiffLine, DiffSpan};
+
+pub const DEFAULT_CONTEXT: usize = 3;
+
+// A git-style patch for one entry, built from its contents; None when the contents were
+// omitted or collapsed, or when neither the text nor the mode changed.
+fn entry_patch(e: &DiffEntry, context: usize) -> Option<String> {
+  let new_path = e.filePath.as_str();
+  let old_path = e.oldPath.as_deref().unwrap_or(new_path);
+  let mut out = format!("diff --git a/{old_path} b/{new_path}\n");
+  if e.status == "submodule" {
+    submodule_patch(e, &mut out);
+    return Some(out);
+  }
+  // `git apply` needs the mode line to create or delete a file; regular files are assumed
+  // for entries without modes.
+  let old_mode = e.oldMode.as_deref().unwrap_or("100644");
+  let new_mode = e.newMode.as_deref().unwrap_or("100644");
+  let mode_changed = e.status != "added" && e.status != "deleted" && old_mode != new_mode;
+  if mode_changed {
+    out.push_str(&format!("old mode {old_mode}\nnew mode {new_mode}\n"));
+  }
+  if let Some(similarity) = e.similarity {
+    out.push_str(&format!("similarity index {similarity}%\n"));
+  }
+  match e.status.as_str() {
+    "renamed" => out.push_str(&format!("rename from {old_path}\nrename to {new_path}\n")),
+    "copied" => out.push_str(&format!("copy from {old_path}\ncopy to {new_path}\n")),
+    "added" => out.push_str(&format!("new file mode {new_mode}\n")),
+    "deleted" => out.push_str(&format!("deleted file mode {old_mode}\n")),
+    _ => {}
+  }
+  // Headers alone describe a pure rename or mode change.
+  let header_only = e.status == "renamed" || mode_changed;
+  if e.isBinary {
+    if e.status != "renamed" {
+      out.push_str(&format!("Binary files a/{old_path} and b/{new_path} differ\n"));
+    }
+    return Some(out);
+  }
+  let (Some(old), Some(new)) = (e.oldContent.as_deref(), e.newContent.as_deref()) else {
+    return header_only.then_some(out);
+  };
+  let old_name = if e.status == "added" { "/dev/null".to_string() } else { format!("a/{old_path}") };
+  let new_name = if e.status == "deleted" { "/dev/null".to_string() } else { format!("b/{new_path}") };
+  let diff = TextDiff::from_lines(old, new);
+  let hunks = diff
+    .unified_diff()
+    .context_radius(context)
+    .header(&old_name, &new_name)
+    .to_string();
+  if hunks.is_empty() && e.status == "modified" && !mode_changed {
+    return None;
+  }
+  out.push_str(&hunks);
+  Some(out)
+}
+
+// The rest of a submodule's patch: git diffs the pinned commit as a one-line file.
+fn submodule_patch(e: &DiffEntry, out: &mut String) {
+  let path = e.filePath.as_str();
+  let (old, new) = (e.oldSubmoduleSha.as_deref(), e.newSubmoduleSha.as_deref());
+  match (old, new) {
+    (None, Some(_)) => out.push_str("new file mode 160000\n"),
+    (Some(_), None) => out.push_str("deleted file mode 160000\n"),
+    _ => {}
+  }
+  let old_name = if old.is_some() { format!("a/{path}") } else { "/dev/null".to_string() };
+  let new_name = if new.is_some() { format!("b/{path}") } else { "/dev/null".to_string() };
+  let range = |sha: Option<&str>| if sha.is_some() { "1" } else { "0,0" };
+  out.push_str(&format!("--- {old_name}\n+++ {new_name}\n@@ -{} +{} @@\n", range(old), range(new)));
+  if let Some(sha) = old {
+    out.push_str(&format!("-Subproject commit {sha}\n"));
+  }
+  if let Some(sha) = new {
+    out.push_str(&format!("+Subproject commit {sha}\n"));
+  }
+}
+
+fn diff_line(tag: ChangeTag, value: &str, old_index: Option<usize>, new_index: Option<usize>, missing_newline: bool) -> DiffLine {
+  DiffLine {
+    tag: match tag {
+      ChangeTag::Equal => "context",
+      ChangeTag::Insert => "add",
+      ChangeTag::Delete => "delete",
+    }
+    .into(),
+    content: value.strip_suffix('\n').unwrap_or(value).to_string(),
+    oldLineNumber: old_index.map(|i| i as i32 + 1),
+    newLineNumber: new_index.map(|i| i as i32 + 1),
+    noNewlineAtEnd: missing_newline.then_some(true),
+    spans: None,
+  }
+}
+
+// Changed stretches of `segments` (emphasized or not, in order) as UTF-16 offsets into the
+// line without its newline, merging neighbours.
+fn intraline_spans<'a>(segments: impl Iterator<Item = (bool, std::borrow::Cow<'a, str>)>) -> Vec<DiffSpan> {
+  let mut spans: Vec<DiffSpan> = Vec::new();
+  let mut offset = 0i32;
+  for (emphasized, value) in segments {
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    let len = value.encode_utf16().count() as i32;
+    if emphasized && len > 0 {
+      match spans.last_mut() {
+        Some(last) if last.end == offset => last.end += len,
+        _ => spans.push(DiffSpan { start: offset, end: offset + len }),
+      }
+    }
+    offset += len;
+  }
+  spans
+}
+
+// Hunks for one entry's contents, with the same grouping as its patch; None when the contents
+// were omitted.
+fn entry_hunks(e: &DiffEntry, context: usize, intraline: bool) -> Option<Vec<DiffHunk>> {
+  if e.isBinary {
+    return None;
+  }
+  let (old, new) = (e.oldContent.as_deref()?, e.newContent.as_deref()?);
+  let diff = TextDiff::from_lines(old, new);
+  let mut hunks = Vec::new();
+  for group in diff.grouped_ops(context) {
+    let (Some(first), Some(last)) = (group.first(), group.last()) else { continue };
+    let (old_start, old_end) = (first.old_range().start, last.old_range().end);
+    let (new_start, new_end) = (first.new_range().start, last.new_range().end);
+    let mut lines = Vec::new();
+    for op in &group {
+      if !intraline {
+        for change in diff.iter_changes(op) {
+          lines.push(diff_line(change.tag(), change.value(), change.old_index(), change.new_index(), change.missing_newline()));
+        }
+        continue;
+      }
+      for change in diff.iter_inline_changes(op) {
+        let value: String = change.iter_strings_lossy().map(|(_, s)| s).collect();
+        let mut line = diff_line(change.tag(), &value, change.old_index(), change.new_index(), change.missing_newline());
+        let spans = intraline_spans(change.iter_strings_lossy());
+        // A line changed as a whole has nothing to single out.
+        let whole = spans.len() == 1 && spans[0].start == 0 && spans[0].end as usize == line.content.encode_utf16().count();
+        if !spans.is_empty() && !whole {
+          line.spans = Some(spans);
+        }
+        lines.push(line);
+      }
+    }
+    // Unified diff convention: an empty range starts at the line before it.
+    let start = |start: usize, len: usize| if len == 0 { start } else { start + 1 };
+    let range = |start: usize, len: usize| if len == 1 { start.to_string() } else { format!("{start},{len}") };
+    let (old_len, new_len) = (old_end - old_start, new_end - new_start);
+    let (old_first, new_first) = (start(old_start, old_len), start(new_start, new_len));
+    hunks.push(DiffHunk {
+      header: format!("@@ -{} +{} @@", range(old_first, old_len), range(new_first, new_len)),
+      oldStart: old_first as i32,
+      oldLines: old_len as i32,
+      newStart: new_first as i32,
+      newLines: new_len as i32,
+      lines,
+    });
+  }
+  Some(hunks)
+}
+
+/// Which diff renderings to add to entries, built from their contents after the fact.
+#[derive(Debug, Clone)]
+pub struct PatchOutput {
+  /// Lines of context around each change.
+  pub context: usize,
+  /// Fill `patch` and `patchSize` with a git-style patch that `git apply` accepts.
+  pub patch: bool,
+  /// Fill `hunks`.
+  pub hunks: bool,
+  /// Add word-level `spans` to changed lines in `hunks`.
+  pub intraline: bool,
+  /// Contents are read to build patches and hunks even when the caller did not ask for them;
+  /// without this they are dropped again.
+  pub keep_contents: bool,
+}
+
+impl PatchOutput {
+  /// Returns None unless `includePatch`, `includeHunks` or `includeIntraline` is set; spans
+  /// live on hunk lines, so `includeIntraline` implies `includeHunks`.
+  pub fn from_options(
+    include_contents: Option<bool>,
+    include_patch: Option<bool>,
+    include_hunks: Option<bool>,
+    include_intraline: Option<bool>,
+    context: Option<i32>,
+  ) -> Option<Self> {
+    let intraline = include_intraline.unwrap_or(false);
+    let (patch, hunks) = (include_patch.unwrap_or(false), include_hunks.unwrap_or(false) || intraline);
+    if !patch && !hunks { return None; }
+    Some(Self {
+      context: context.map(|n| n.max(0) as usize).unwrap_or(DEFAULT_CONTEXT),
+      patch,
+      hunks,
+      intraline,
+      keep_contents: include_contents.unwrap_or(true),
+    })
+  }
+
+  /// Adds the requested renderings to every entry that has contents (a pure rename or a
+  /// submodule bump gets a patch too); collapsed entries are left alone.
+  pub fn apply(&self, entries: &mut [DiffEntry]) {
+    for e in entries.iter_mut() {
+      if e.collapsed != Some(true) {
+        if self.patch {
+          if let Some(patch) = entry_patch(e, self.context) {
+            e.patchSize = Some(patch.len() as i32);
+            e.patch = Some(patch);
+          }
+        }
+        if self.hunks {
+          e.hunks = entry_hunks(e, self.context, self.intraline);
+        }
+      }
+      if !self.keep_contents {
+        e.oldContent = None;
+        e.newContent = None;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(status: &str, path: &str, old: &str, new: &str) -> DiffEntry {
+    DiffEntry {
+      filePath: path.into(),
+      status: status.into(),
+      oldContent: Some(old.into()),
+      newContent: Some(new.into()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn patches_have_git_headers_and_requested_context() {
+    let old: String = (1..=10).map(|i| format!("{i}\n")).collect();
+    let new = old.replace("5\n", "five\n");
+    let mut entries = vec![
+      entry("modified", "m.txt", &old, &new),
+      entry("added", "a.txt", "", "hello\n"),
+      entry("deleted", "d.txt", "bye", ""),
+      DiffEntry { filePath: "new.txt".into(), oldPath: Some("old.txt".into()), status: "renamed".into(), ..Default::default() },
+      DiffEntry { filePath: "big.txt".into(), status: "modified".into(), contentOmitted: Some(true), ..Default::default() },
+    ];
+    PatchOutput::from_options(None, Some(true), None, None, Some(1)).unwrap().apply(&mut entries);
+
+    assert_eq!(
+      entries[0].patch.as_deref(),
+      Some("diff --git a/m.txt b/m.txt\n--- a/m.txt\n+++ b/m.txt\n@@ -4,3 +4,3 @@\n 4\n-5\n+five\n 6\n")
+    );
+    assert_eq!(entries[0].patchSize, Some(entries[0].patch.as_ref().unwrap().len() as i32));
+    assert_eq!(
+      entries[1].patch.as_deref(),
+      Some("diff --git a/a.txt b/a.txt\nnew file mode 100644\n--- /dev/null\n+++ b/a.txt\n@@ -0,0 +1 @@\n+hello\n")
+    );
+    assert!(entries[2].patch.as_deref().unwrap().starts_with("diff --git a/d.txt b/d.txt\ndeleted file mode 100644\n"));
+    assert!(entries[2].patch.as_deref().unwrap().contains("+++ /dev/null\n@@ -1 +0,0 @@\n-bye\n\\ No newline at end of file\n"));
+    assert_eq!(
+      entries[3].patch.as_deref(),
+      Some("diff --git a/old.txt b/new.txt\nrename from old.txt\nrename to new.txt\n")
+    );
+    assert_eq!(entries[4].patch, None);
+
+    let mut entries = vec![entry("modified", "m.txt", "a\n", "b\n")];
+    PatchOutput::from_options(Some(false), Some(true), None, None, None).unwrap().apply(&mut entries);
+    assert!(entries[0].patch.is_some() && entries[0].hunks.is_none());
+    assert!(entries[0].oldContent.is_none() && entries[0].newContent.is_none());
+  }
+
+  #[test]
+  fn hunks_carry_ranges_tags_and_line_numbers() {
+    let old: String = (1..=10).map(|i| format!("{i}\n")).collect();
+    let new = old.replace("2\n", "two\n").replace("9\n", "") + "11";
+    let mut entries = vec![
+      entry("modified", "m.txt", &old, &new),
+      DiffEntry { filePath: "bin".into(), status: "modified".into(), isBinary: true, ..Default::default() },
+    ];
+    assert!(PatchOutput::from_options(None, None, None, None, None).is_none());
+    PatchOutput::from_options(None, None, Some(true), None, Some(1)).unwrap().apply(&mut entries);
+    assert!(entries[0].patch.is_none() && entries[0].oldContent.is_some());
+    assert!(entries[1].hunks.is_none());
+
+    let hunks = entries[0].hunks.as_ref().unwrap();
+    assert_eq!(hunks.len(), 2);
+    assert_eq!(hunks[0].header, "@@ -1,3 +1,3 @@");
+    assert_eq!((hunks[0].oldStart, hunks[0].oldLines, hunks[0].newStart, hunks[0].newLines), (1, 3, 1, 3));
+    let tags: Vec<&str> = hunks[0].lines.iter().map(|l| l.tag.as_str()).collect();
+    assert_eq!(tags, vec!["context", "delete", "add", "context"]);
+    assert_eq!(hunks[0].lines[2].content, "two");
+    assert_eq!((hunks[0].lines[2].oldLineNumber, hunks[0].lines[2].newLineNumber), (None, Some(2)));
+    assert_eq!((hunks[0].lines[3].oldLineNumber, hunks[0].lines[3].newLineNumber), (Some(3), Some(3)));
+
+    // The same hunks a patch would have.
+    let patch = {
+      let mut with_patch = vec![entry("modified", "m.txt", &old, &new)];
+      PatchOutput::from_options(None, Some(true), None, None, Some(1)).unwrap().apply(&mut with_patch);
+      with_patch[0].patch.clone().unwrap()
+    };
+    assert!(patch.contains(&format!("{}\n", hunks[1].header)), "{patch}");
+    let last = hunks[1].lines.last().unwrap();
+    assert_eq!((last.tag.as_str(), last.content.as_str(), last.noNewlineAtEnd), ("add", "11", Some(true)));
+  }
+
+  #[test]
+  fn intraline_spans_mark_changed_words_in_utf16() {
+    let mut entries = vec![entry("modified", "m.txt", "café is old here\nkeep\n", "café is new here\nkeep\nadded line\n")];
+    PatchOutput::from_options(Some(false), None, None, Some(true), Some(0)).unwrap().apply(&mut entries);
+    let hunks = entries[0].hunks.as_ref().expect("intraline implies hunks");
+    let lines: Vec<&DiffLine> = hunks.iter().flat_map(|h| h.lines.iter()).collect();
+    let deleted = lines.iter().find(|l| l.tag == "delete").unwrap();
+    let added = lines.iter().find(|l| l.tag == "add" && l.content.starts_with("café")).unwrap();
+    let span = |l: &DiffLine| {
+      let s = l.spans.as_ref().expect("spans")[0].clone();
+      String::from_utf16(&l.content.encode_utf16().collect::<Vec<_>>()[s.start as usize..s.end as usize]).unwrap()
+    };
+    assert_eq!(deleted.spans.as_ref().unwrap().len(), 1);
+    assert_eq!(deleted.spans.as_ref().unwrap()[0].start, 8);
+    assert_eq!(span(deleted), "old");
+    assert_eq!(span(added), "new");
+    // A wholly new line has no spans.
+    let new_line = lines.iter().find(|l| l.content == "added line").unwrap();
+    assert!(new_line.spans.is_none());
+    assert!(entries[0].oldContent.is_none());
+  }
+}
@@ -0,0 +1,143 @@
+/// Limits a diff to matching paths, so trees outside them are never collected or diffed.
+///
+/// Pattern forms:
+/// - `*` matches within one path segment, `?` one character, `**` any number of segments
+/// - a pattern also selects everything under a directory it matches (`src` is `src/**`)
+/// - `:!pattern` or `:^pattern` excludes matches; with only exclusions, everything else is kept
+#[derive(Debug, Clone)]
+pub struct Pathspec {
+  include: Vec<Vec<String>>,
+  exclude: Vec<Vec<String>>,
+}
+
+fn segments(pattern: &str) -> Vec<String> {
+  pattern
+    .split('/')
+    .filter(|s| !s.is_empty() && *s != ".")
+    .map(|s| s.to_string())
+    .collect()
+}
+
+// `*` and `?` within a single segment.
+fn wildcard(pat: &str, s: &str) -> bool {
+  let mut pc = pat.chars();
+  match pc.next() {
+    None => s.is_empty(),
+    Some('*') => {
+      let rest = pc.as_str();
+      s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len())).any(|i| wildcard(rest, &s[i..]))
+    }
+    Some(c) => {
+      let mut sc = s.chars();
+      match sc.next() {
+        Some(first) if c == '?' || c == first => wildcard(pc.as_str(), sc.as_str()),
+        _ => false,
+      }
+    }
+  }
+}
+
+// Whether the pattern matches `path` or one of its leading directories.
+fn matches_path(pat: &[String], path: &[&str]) -> bool {
+  match pat.split_first() {
+    None => true,
+    Some((seg, rest)) if seg == "**" => (0..=path.len()).any(|i| matches_path(rest, &path[i..])),
+    Some((seg, rest)) => match path.split_first() {
+      Some((first, tail)) => wildcard(seg, first) && matches_path(rest, tail),
+      None => false,
+    },
+  }
+}
+
+// Whether the pattern could match some path under the directory `dir`.
+fn could_match_under(pat: &[String], dir: &[&str]) -> bool {
+  match (pat.split_first(), dir.split_first()) {
+    (None, _) | (_, None) => true,
+    (Some((seg, _)), _) if seg == "**" => true,
+    (Some((seg, rest)), Some((first, tail))) => wildcard(seg, first) && could_match_under(rest, tail),
+  }
+}
+
+impl Pathspec {
+  pub fn new(patterns: &[String]) -> Self {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    for pattern in patterns {
+      let pattern = pattern.trim();
+      if pattern.is_empty() { continue; }
+      let excluded = [":!", ":^", ":(exclude)"].iter().find_map(|magic| pattern.strip_prefix(magic));
+      match excluded {
+        Some(rest) => exclude.push(segments(rest)),
+        None => include.push(segments(pattern)),
+      }
+    }
+    Self { include, exclude }
+  }
+
+  /// Returns None when no pathspecs were given, so callers can skip matching entirely.
+  pub fn from_options(patterns: Option<&[String]>) -> Option<Self> {
+    let spec = Self::new(patterns?);
+    (!spec.include.is_empty() || !spec.exclude.is_empty()).then_some(spec)
+  }
+
+  pub fn matches(&self, path: &str) -> bool {
+    let path: Vec<&str> = path.split('/').collect();
+    (self.include.is_empty() || self.include.iter().any(|p| matches_path(p, &path)))
+      && !self.exclude.iter().any(|p| matches_path(p, &path))
+  }
+
+  /// False when nothing under the directory `dir` can match, so it need not be walked.
+  pub fn may_contain(&self, dir: &str) -> bool {
+    let dir: Vec<&str> = dir.split('/').collect();
+    (self.include.is_empty() || self.include.iter().any(|p| could_match_under(p, &dir)))
+      && !self.exclude.iter().any(|p| matches_path(p, &dir))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn spec(patterns: &[&str]) -> Pathspec {
+    Pathspec::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+  }
+
+  #[test]
+  fn globs_select_files_and_directories() {
+    let s = spec(&["src/**/*.rs", "docs"]);
+    assert!(s.matches("src/lib.rs"));
+    assert!(s.matches("src/diff/refs.rs"));
+    assert!(!s.matches("src/lib.ts"));
+    assert!(s.matches("docs/guide/intro.md"));
+    assert!(!s.matches("docsite/index.md"));
+    assert!(s.may_contain("src/diff"));
+    assert!(!s.may_contain("apps"));
+
+    let s = spec(&["apps/*/package.json", "?.txt"]);
+    assert!(s.matches("apps/www/package.json"));
+    assert!(!s.matches("apps/www/nested/package.json"));
+    assert!(s.matches("a.txt"));
+    assert!(!s.matches("ab.txt"));
+    assert!(s.may_contain("apps/www"));
+    assert!(!s.may_contain("packages"));
+  }
+
+  #[test]
+  fn exclusions_apply_with_or_without_includes() {
+    let s = spec(&[":!**/package-lock.json", ":^vendor"]);
+    assert!(s.matches("src/lib.rs"));
+    assert!(!s.matches("package-lock.json"));
+    assert!(!s.matches("apps/www/package-lock.json"));
+    assert!(!s.matches("vendor/lib/a.js"));
+    assert!(!s.may_contain("vendor"));
+    assert!(s.may_contain("apps"));
+
+    let s = spec(&["src", ":(exclude)src/generated"]);
+    assert!(s.matches("src/a.rs"));
+    assert!(!s.matches("src/generated/api.rs"));
+    assert!(!s.matches("README.md"));
+
+    assert!(Pathspec::from_options(None).is_none());
+    assert!(Pathspec::from_options(Some(&["  ".to_string()])).is_none());
+  }
+}
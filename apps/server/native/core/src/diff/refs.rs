@@ -1,19 +1,32 @@
 use anyhow::Result;
 use gix::bstr::ByteSlice;
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 #[cfg(test)]
 use std::cell::RefCell;
 
+use super::{filter::CollapseFilter, memo, patch::PatchOutput, pathspec::Pathspec, renames};
 use crate::{
-  repo::cache::{ensure_repo, resolve_repo_url},
+  repo::cache::{cached_repo, ensure_repo, is_mirror, is_stale, resolve_repo_url, RepoOptions},
+  repo::partial::{fetch_missing, no_fetch},
   types::{DiffEntry, GitDiffOptions},
 };
 use gix::{Repository, hash::ObjectId};
 use similar::TextDiff;
 
-fn oid_from_rev_parse(repo: &Repository, rev: &str) -> anyhow::Result<ObjectId> {
-  if let Ok(oid) = ObjectId::from_hex(rev.as_bytes()) { return Ok(oid); }
+pub(crate) fn oid_from_rev_parse(repo: &Repository, rev: &str) -> anyhow::Result<ObjectId> {
+  if let Some(id) = resolve_rev(repo, rev) { return Ok(id); }
+  // A cached mirror keeps origin's branches as `refs/heads/*` and its default branch as HEAD,
+  // while callers still name them `origin/<branch>` and `origin/HEAD`.
+  let branch = rev.strip_prefix("refs/remotes/origin/").or_else(|| rev.strip_prefix("origin/"));
+  if let Some(branch) = branch.filter(|_| is_mirror(repo)) {
+    if let Some(id) = resolve_rev(repo, branch) { return Ok(id); }
+  }
+  Err(anyhow::anyhow!("could not resolve rev '{}'", rev))
+}
+
+fn resolve_rev(repo: &Repository, rev: &str) -> Option<ObjectId> {
+  if let Ok(oid) = ObjectId::from_hex(rev.as_bytes()) { return Some(oid); }
   let candidates = [
     rev.to_string(),
     format!("refs/remotes/origin/{}", rev),
@@ -22,20 +35,83 @@ fn oid_from_rev_parse(repo: &Repository, rev: &str) -> anyhow::Result<ObjectId>
   ];
   for cand in candidates {
     if let Ok(r) = repo.find_reference(&cand) {
-      if let Some(id) = r.target().try_id() { return Ok(id.to_owned()); }
+      if let Some(id) = r.target().try_id() { return Some(id.to_owned()); }
     }
   }
-  if let Ok(spec) = repo.rev_parse_single(rev) {
-    if let Ok(obj) = spec.object() { return Ok(obj.id); }
+  let spec = repo.rev_parse_single(rev).ok()?;
+  spec.object().ok().map(|obj| obj.id)
+}
+
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+  data.contains(&0) || std::str::from_utf8(data).is_err()
+}
+
+fn blob_size(repo: &Repository, id: ObjectId) -> Option<i32> {
+  repo.find_header(id).ok().map(|h| h.size() as i32)
+}
+
+// Entry for a file hidden by `textOnly`: sizes only, read from object headers so the blob
+// itself is never inflated.
+fn collapsed_entry(repo: &Repository, path: &str, old_path: Option<&str>, status: &str, old_id: Option<ObjectId>, new_id: Option<ObjectId>) -> DiffEntry {
+  DiffEntry{
+    filePath: path.to_string(),
+    oldPath: old_path.map(|p| p.to_string()),
+    status: status.into(),
+    oldSize: old_id.and_then(|id| blob_size(repo, id)).or(Some(0)),
+    newSize: new_id.and_then(|id| blob_size(repo, id)).or(Some(0)),
+    contentOmitted: Some(true),
+    collapsed: Some(true),
+    ..Default::default()
   }
-  Err(anyhow::anyhow!("could not resolve rev '{}'", rev))
 }
 
-fn is_binary(data: &[u8]) -> bool {
-  data.iter().any(|&b| b == 0) || std::str::from_utf8(data).is_err()
+// A rename or copy paired by similar contents rather than by an identical blob.
+struct SimilarPair {
+  old_path: String,
+  new_path: String,
+  status: &'static str,
+  similarity: i32,
+  old_text: String,
+  new_text: String,
+}
+
+fn collapse_in_place(e: &mut DiffEntry) {
+  e.additions = 0;
+  e.deletions = 0;
+  e.oldContent = None;
+  e.newContent = None;
+  e.contentOmitted = Some(true);
+  e.collapsed = Some(true);
+}
+
+pub(crate) fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, out: &mut HashMap<String, ObjectId>) -> anyhow::Result<()> {
+  let mut files = TreeFiles::default();
+  collect_matching_blobs(repo, tree_id, prefix, None, &mut files)?;
+  out.extend(files.blobs);
+  out.extend(files.gitlinks);
+  Ok(())
+}
+
+pub(crate) const REGULAR_MODE: &str = "100644";
+
+// Files under a tree: blobs, submodule commits kept apart from them, and the mode of every
+// blob that is not a regular file (executables and symlinks).
+#[derive(Default)]
+pub(crate) struct TreeFiles {
+  pub(crate) blobs: HashMap<String, ObjectId>,
+  pub(crate) gitlinks: HashMap<String, ObjectId>,
+  pub(crate) modes: HashMap<String, &'static str>,
+}
+
+impl TreeFiles {
+  pub(crate) fn mode(&self, path: &str) -> &'static str {
+    self.modes.get(path).copied().unwrap_or(REGULAR_MODE)
+  }
 }
 
-fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, out: &mut HashMap<String, ObjectId>) -> anyhow::Result<()> {
+// Like `collect_tree_blobs`, but skips subtrees and files outside `spec`.
+pub(crate) fn collect_matching_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, spec: Option<&Pathspec>, out: &mut TreeFiles) -> anyhow::Result<()> {
+  crate::cancel::check()?;
   let obj = repo.find_object(tree_id)?;
   let tree = obj.try_into_tree()?;
   for entry_res in tree.iter() {
@@ -44,16 +120,58 @@ fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, out: &
     let full = if prefix.is_empty() { name.clone() } else { format!("{}/{}", prefix, name) };
     let mode = entry.mode();
     if mode.is_tree() {
+      if spec.is_some_and(|s| !s.may_contain(&full)) { continue; }
       let id = entry.oid().to_owned();
-      collect_tree_blobs(repo, id, &full, out)?;
+      collect_matching_blobs(repo, id, &full, spec, out)?;
     } else {
+      if spec.is_some_and(|s| !s.matches(&full)) { continue; }
       let id = entry.oid().to_owned();
-      out.insert(full, id);
+      if mode.is_commit() {
+        out.gitlinks.insert(full, id);
+        continue;
+      }
+      if mode.is_link() {
+        out.modes.insert(full.clone(), "120000");
+      } else if mode.is_executable() {
+        out.modes.insert(full.clone(), "100755");
+      }
+      out.blobs.insert(full, id);
     }
   }
   Ok(())
 }
 
+// Sets `oldMode`/`newMode` for the sides an entry has, and `typeChanged` when a file became a
+// symlink or the other way around.
+fn fill_modes(e: &mut DiffEntry, base: &TreeFiles, head: &TreeFiles) {
+  let old_path = e.oldPath.clone().unwrap_or_else(|| e.filePath.clone());
+  if e.status != "added" {
+    e.oldMode = Some(base.mode(&old_path).to_string());
+  }
+  if e.status != "deleted" {
+    e.newMode = Some(head.mode(&e.filePath).to_string());
+  }
+  if let (Some(old), Some(new)) = (&e.oldMode, &e.newMode) {
+    if (old == "120000") != (new == "120000") {
+      e.typeChanged = Some(true);
+    }
+  }
+}
+
+// Entry for a submodule whose pinned commit changed, or that was added or removed.
+fn submodule_entry(path: &str, old: Option<ObjectId>, new: Option<ObjectId>) -> DiffEntry {
+  DiffEntry{
+    filePath: path.to_string(),
+    status: "submodule".into(),
+    contentOmitted: Some(false),
+    oldMode: old.map(|_| "160000".to_string()),
+    newMode: new.map(|_| "160000".to_string()),
+    oldSubmoduleSha: old.map(|id| id.to_hex().to_string()),
+    newSubmoduleSha: new.map(|id| id.to_hex().to_string()),
+    ..Default::default()
+  }
+}
+
 fn resolve_default_base(repo: &Repository, head_oid: ObjectId) -> ObjectId {
   if let Ok(r) = repo.find_reference("refs/remotes/origin/HEAD") {
     if let Some(name) = r.target().try_name() {
@@ -90,7 +208,7 @@ pub struct DiffComputationDebug {
 
 #[cfg(test)]
 thread_local! {
-  static LAST_DIFF_DEBUG: RefCell<Option<DiffComputationDebug>> = RefCell::new(None);
+  static LAST_DIFF_DEBUG: RefCell<Option<DiffComputationDebug>> = const { RefCell::new(None) };
 }
 
 #[cfg(test)]
@@ -99,16 +217,16 @@ pub fn last_diff_debug() -> Option<DiffComputationDebug> {
 }
 
 fn is_ancestor(repo: &Repository, anc: ObjectId, desc: ObjectId) -> bool {
-  match crate::merge_base::merge_base(
-    "",
-    repo,
-    desc,
-    anc,
-    crate::merge_base::MergeBaseStrategy::Bfs,
-  ) {
-    Some(x) if x == anc => true,
-    _ => false,
-  }
+  matches!(
+    crate::merge_base::merge_base(
+      "",
+      repo,
+      desc,
+      anc,
+      crate::merge_base::MergeBaseStrategy::Bfs,
+    ),
+    Some(x) if x == anc
+  )
 }
 
 fn find_merge_parent_on_base(
@@ -127,7 +245,7 @@ fn find_merge_parent_on_base(
     let first_parent = parents_iter.next().map(|p| p.detach());
     let rest: Vec<ObjectId> = parents_iter.map(|p| p.detach()).collect();
     if let Some(p1) = first_parent {
-      if rest.iter().any(|p| *p == head_tip) {
+      if rest.contains(&head_tip) {
         return Some((base_tip, p1));
       }
       if ancestor_candidate.is_none()
@@ -152,8 +270,19 @@ fn parse_oid(hex: &str) -> Option<ObjectId> {
 }
 
 pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
-  let include = opts.includeContents.unwrap_or(true);
+  let patch_output = PatchOutput::from_options(
+    opts.includeContents,
+    opts.includePatch,
+    opts.includeHunks,
+    opts.includeIntraline,
+    opts.patchContext,
+  );
+  // Patches and hunks are built from contents, so those are read whenever either is asked for.
+  let include = opts.includeContents.unwrap_or(true) || patch_output.is_some();
   let max_bytes = opts.maxBytes.unwrap_or(950*1024) as usize;
+  let collapse = CollapseFilter::from_options(opts.textOnly, opts.collapsePatterns.as_deref());
+  let is_collapsed = |path: &str| collapse.as_ref().map(|f| f.matches(path)).unwrap_or(false);
+  let pathspec = Pathspec::from_options(opts.pathspecs.as_deref());
   let t_total = Instant::now();
   #[cfg(test)]
   LAST_DIFF_DEBUG.with(|cell| {
@@ -182,26 +311,18 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     opts.repoFullName
   );
 
+  let offline = opts.offline.unwrap_or(false);
+  let _no_fetch = no_fetch(offline);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
   let t_repo_path = Instant::now();
   let repo_path = if let Some(p) = &opts.originPathOverride { std::path::PathBuf::from(p) } else {
-    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref())?;
-    ensure_repo(&url)?
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
   };
   let _d_repo_path = t_repo_path.elapsed();
   let cwd = repo_path.to_string_lossy().to_string();
-
-  // If a specific repo path is provided, assume the caller ensures freshness.
-  // Avoid synchronous fetch here to reduce latency.
-  let _d_fetch = if opts.originPathOverride.is_some() {
-    Duration::from_millis(0)
-  } else {
-    let t_fetch = Instant::now();
-    let _ = crate::repo::cache::swr_fetch_origin_all_path(
-      std::path::Path::new(&cwd),
-      crate::repo::cache::fetch_window_ms(),
-    );
-    t_fetch.elapsed()
-  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
 
   let t_open = Instant::now();
   let repo = gix::open(&cwd)?;
@@ -317,14 +438,15 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
   let head_tree_id = head_commit.tree_id()?.detach();
   let _d_tree_ids = t_tree_ids.elapsed();
 
-  let mut base_map: HashMap<String, ObjectId> = HashMap::new();
-  let mut head_map: HashMap<String, ObjectId> = HashMap::new();
+  let mut base_files = TreeFiles::default();
+  let mut head_files = TreeFiles::default();
   let t_collect_base = Instant::now();
-  collect_tree_blobs(&repo, base_tree_id, "", &mut base_map)?;
+  collect_matching_blobs(&repo, base_tree_id, "", pathspec.as_ref(), &mut base_files)?;
   let _d_collect_base = t_collect_base.elapsed();
   let t_collect_head = Instant::now();
-  collect_tree_blobs(&repo, head_tree_id, "", &mut head_map)?;
+  collect_matching_blobs(&repo, head_tree_id, "", pathspec.as_ref(), &mut head_files)?;
   let _d_collect_head = t_collect_head.elapsed();
+  let (base_map, head_map) = (&base_files.blobs, &head_files.blobs);
 
   // Utility closures to obtain blob data safely; handle submodules and non-blobs gracefully
   let mut out: Vec<DiffEntry> = Vec::new();
@@ -336,6 +458,7 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
   let mut _blob_read_ns: u128 = 0;
   let mut _textdiff_ns: u128 = 0;
   let mut _textdiff_count: usize = 0;
+  let mut _memo_hits: usize = 0;
   let mut _max_diff_ns: u128 = 0;
   let mut _max_diff_path: Option<String> = None;
 
@@ -348,11 +471,27 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     None
   };
 
+  // Submodules report their pinned commit moving instead of being diffed as files
+  let mut link_paths: Vec<&String> = base_files.gitlinks.keys().chain(head_files.gitlinks.keys()).collect();
+  link_paths.sort();
+  link_paths.dedup();
+  for path in link_paths {
+    let (old_id, new_id) = (base_files.gitlinks.get(path).copied(), head_files.gitlinks.get(path).copied());
+    if old_id != new_id {
+      out.push(submodule_entry(path, old_id, new_id));
+    }
+  }
+
+  // A blobless clone only has the blobs it was asked for; fetch this diff's in one go.
+  let changed = base_map.iter().filter(|(p, id)| head_map.get(*p) != Some(*id))
+    .chain(head_map.iter().filter(|(p, id)| base_map.get(*p) != Some(*id)));
+  fetch_missing(&repo, changed.map(|(_, id)| *id))?;
+
   // Precompute path partitions
   let mut base_only: HashMap<String, ObjectId> = HashMap::new();
   let mut head_only: HashMap<String, ObjectId> = HashMap::new();
-  for (p, oid) in &base_map { if !head_map.contains_key(p) { base_only.insert(p.clone(), *oid); } }
-  for (p, oid) in &head_map { if !base_map.contains_key(p) { head_only.insert(p.clone(), *oid); } }
+  for (p, oid) in base_map { if !head_map.contains_key(p) { base_only.insert(p.clone(), *oid); } }
+  for (p, oid) in head_map { if !base_map.contains_key(p) { head_only.insert(p.clone(), *oid); } }
 
   // Identity-based rename detection: pair deletions and additions with the same blob OID
   let mut id_to_old: HashMap<ObjectId, Vec<String>> = HashMap::new();
@@ -375,8 +514,64 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     }
   }
 
+  // Similarity-based renames, then copies, among files identity pairing left unmatched.
+  // Collapsed, binary and oversized files only ever pair by identity.
+  let rename_threshold = opts.renameThreshold.unwrap_or(renames::DEFAULT_THRESHOLD).clamp(1, 100);
+  let detect_copies = opts.detectCopies.unwrap_or(false);
+  let pairing_text = |path: &str, id: ObjectId| -> Option<String> {
+    if is_collapsed(path) { return None; }
+    let buf = get_blob_bytes(id)?;
+    if is_binary(&buf) || buf.len() > max_bytes { return None; }
+    Some(String::from_utf8_lossy(&buf).into_owned())
+  };
+  let mut similar_pairs: Vec<SimilarPair> = Vec::new();
+  if rename_threshold < 100 || detect_copies {
+    let t_pair = Instant::now();
+    let mut deleted: Vec<(String, String)> = base_only.iter().filter_map(|(p, id)| pairing_text(p, *id).map(|t| (p.clone(), t))).collect();
+    let mut added: Vec<(String, String)> = head_only.iter().filter_map(|(p, id)| pairing_text(p, *id).map(|t| (p.clone(), t))).collect();
+    deleted.sort();
+    added.sort();
+    let mut paired: HashSet<String> = HashSet::new();
+    if rename_threshold < 100 {
+      let sources: Vec<&str> = deleted.iter().map(|(_, t)| t.as_str()).collect();
+      let targets: Vec<&str> = added.iter().map(|(_, t)| t.as_str()).collect();
+      for (si, ti, score) in renames::pair_by_similarity(&sources, &targets, rename_threshold, false) {
+        paired.insert(added[ti].0.clone());
+        similar_pairs.push(SimilarPair{ old_path: deleted[si].0.clone(), new_path: added[ti].0.clone(), status: "renamed", similarity: score, old_text: deleted[si].1.clone(), new_text: added[ti].1.clone() });
+      }
+    }
+    if detect_copies {
+      // Like `git diff -C`, copies come from files this diff deleted or modified, and a
+      // source may be copied more than once.
+      let mut modified: Vec<(String, String)> = head_map.iter()
+        .filter_map(|(p, new_id)| {
+          let old_id = base_map.get(p).filter(|old_id| *old_id != new_id)?;
+          pairing_text(p, *old_id).map(|t| (p.clone(), t))
+        })
+        .collect();
+      modified.sort();
+      let copy_sources: Vec<&(String, String)> = deleted.iter().chain(modified.iter()).collect();
+      let copy_targets: Vec<&(String, String)> = added.iter().filter(|(p, _)| !paired.contains(p)).collect();
+      let sources: Vec<&str> = copy_sources.iter().map(|(_, t)| t.as_str()).collect();
+      let targets: Vec<&str> = copy_targets.iter().map(|(_, t)| t.as_str()).collect();
+      for (si, ti, score) in renames::pair_by_similarity(&sources, &targets, rename_threshold, true) {
+        paired.insert(copy_targets[ti].0.clone());
+        similar_pairs.push(SimilarPair{ old_path: copy_sources[si].0.clone(), new_path: copy_targets[ti].0.clone(), status: "copied", similarity: score, old_text: copy_sources[si].1.clone(), new_text: copy_targets[ti].1.clone() });
+      }
+    }
+    for pair in &similar_pairs {
+      if pair.status == "renamed" { base_only.remove(&pair.old_path); }
+      head_only.remove(&pair.new_path);
+    }
+    _textdiff_ns += t_pair.elapsed().as_nanos();
+  }
+
   // Emit renames (content identical by OID)
   for (old_path, new_path, oid) in renamed_pairs {
+    if is_collapsed(&new_path) {
+      out.push(collapsed_entry(&repo, &new_path, Some(&old_path), "renamed", Some(oid), Some(oid)));
+      continue;
+    }
     let t_bl = Instant::now();
     let new_data = get_blob_bytes(oid);
     _blob_read_ns += t_bl.elapsed().as_nanos();
@@ -396,11 +591,34 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     out.push(e);
   }
 
+  // Emit renames and copies paired by similarity, with their line changes
+  for pair in similar_pairs {
+    let (adds, dels) = renames::line_counts(&pair.old_text, &pair.new_text);
+    let old_sz = pair.old_text.len();
+    let new_sz = pair.new_text.len();
+    let mut e = DiffEntry{ filePath: pair.new_path, oldPath: Some(pair.old_path), status: pair.status.into(), additions: adds, deletions: dels, isBinary: false, similarity: Some(pair.similarity), ..Default::default() };
+    e.oldSize = Some(old_sz as i32);
+    e.newSize = Some(new_sz as i32);
+    if include && old_sz + new_sz <= max_bytes {
+      e.oldContent = Some(pair.old_text);
+      e.newContent = Some(pair.new_text);
+      e.contentOmitted = Some(false);
+      _total_scanned_bytes += old_sz + new_sz;
+    } else { e.contentOmitted = Some(include); }
+    out.push(e);
+  }
+
   // Handle modifications where the path exists in both
   let t_loop_add_mod = Instant::now();
-  for (path, new_id) in &head_map {
+  for (path, new_id) in head_map {
+    crate::cancel::check()?;
     if let Some(old_id) = base_map.get(path) {
-      if old_id == new_id { continue; }
+      if old_id == new_id && base_files.mode(path) == head_files.mode(path) { continue; }
+      if is_collapsed(path) {
+        out.push(collapsed_entry(&repo, path, None, "modified", Some(*old_id), Some(*new_id)));
+        _num_modified += 1;
+        continue;
+      }
       let t_bl1 = Instant::now();
       let old_data = get_blob_bytes(*old_id);
       let new_data = get_blob_bytes(*new_id);
@@ -413,27 +631,35 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
       if include && !bin {
         let old_str = String::from_utf8_lossy(old_data.as_ref().unwrap()).into_owned();
         let new_str = String::from_utf8_lossy(new_data.as_ref().unwrap()).into_owned();
-        let old_sz = old_str.as_bytes().len();
-        let new_sz = new_str.as_bytes().len();
+        let old_sz = old_str.len();
+        let new_sz = new_str.len();
         e.oldSize = Some(old_sz as i32);
         e.newSize = Some(new_sz as i32);
         if old_sz + new_sz <= max_bytes {
-          let t_diff = Instant::now();
-          // Use changes grouped by operations; count per-line inserts/deletes only.
-          let diff = TextDiff::from_lines(&old_str, &new_str);
-          let mut adds = 0i32; let mut dels = 0i32;
-          for op in diff.ops() {
-            for change in diff.iter_changes(op) {
-              match change.tag() {
-                similar::ChangeTag::Insert => adds += 1,
-                similar::ChangeTag::Delete => dels += 1,
-                _ => {}
+          // Blob pairs that were already counted on a previous call skip TextDiff entirely.
+          let (adds, dels) = match memo::with_memo(|m| m.get(*old_id, *new_id)).flatten() {
+            Some(counts) => { _memo_hits += 1; counts }
+            None => {
+              let t_diff = Instant::now();
+              // Use changes grouped by operations; count per-line inserts/deletes only.
+              let diff = TextDiff::from_lines(&old_str, &new_str);
+              let mut adds = 0i32; let mut dels = 0i32;
+              for op in diff.ops() {
+                for change in diff.iter_changes(op) {
+                  match change.tag() {
+                    similar::ChangeTag::Insert => adds += 1,
+                    similar::ChangeTag::Delete => dels += 1,
+                    _ => {}
+                  }
+                }
               }
+              let d_diff = t_diff.elapsed().as_nanos();
+              _textdiff_ns += d_diff; _textdiff_count += 1; _total_scanned_bytes += old_sz + new_sz;
+              if d_diff > _max_diff_ns { _max_diff_ns = d_diff; _max_diff_path = Some(path.clone()); }
+              memo::with_memo(|m| m.insert(*old_id, *new_id, adds, dels));
+              (adds, dels)
             }
-          }
-          let d_diff = t_diff.elapsed().as_nanos();
-          _textdiff_ns += d_diff; _textdiff_count += 1; _total_scanned_bytes += old_sz + new_sz;
-          if d_diff > _max_diff_ns { _max_diff_ns = d_diff; _max_diff_path = Some(path.clone()); }
+          };
           e.additions = adds; e.deletions = dels;
           e.oldContent = Some(old_str);
           e.newContent = Some(new_str);
@@ -447,9 +673,19 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     }
   }
   let _d_loop_add_mod = t_loop_add_mod.elapsed();
+  if let Some(Err(_err)) = memo::with_memo(|m| m.save()) {
+    #[cfg(debug_assertions)]
+    println!("[cmux_native_git] failed to persist textdiff memo: {:#}", _err);
+  }
 
   // Additions not matched as renames
   for (path, new_id) in &head_only {
+    crate::cancel::check()?;
+    if is_collapsed(path) {
+      out.push(collapsed_entry(&repo, path, None, "added", None, Some(*new_id)));
+      _num_added += 1;
+      continue;
+    }
     let t_bl = Instant::now();
     let new_data = get_blob_bytes(*new_id);
     _blob_read_ns += t_bl.elapsed().as_nanos();
@@ -478,6 +714,12 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
   // Deletions not matched as renames
   let t_loop_del = Instant::now();
   for (path, old_id) in &base_only {
+    crate::cancel::check()?;
+    if is_collapsed(path) {
+      out.push(collapsed_entry(&repo, path, None, "deleted", Some(*old_id), None));
+      _num_deleted += 1;
+      continue;
+    }
     let t_bl = Instant::now();
     let old_data = get_blob_bytes(*old_id);
     _blob_read_ns += t_bl.elapsed().as_nanos();
@@ -506,10 +748,9 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
   let _d_total = t_total.elapsed();
   #[cfg(debug_assertions)]
   println!(
-    "[cmux_native_git] git_diff timings: total={}ms repo_path={}ms fetch={}ms open_repo={}ms resolve_head={}ms resolve_base={}ms merge_base={}ms tree_ids={}ms collect_base={}ms collect_head={}ms add_mod_loop={}ms del_loop={}ms blob_read={}ms textdiff={}ms textdiff_count={} scanned_bytes={} files: +{} ~{} -{} (binary={}) max_textdiff={{path: {:?}, ms: {}}} cwd={} out_len={}",
+    "[cmux_native_git] git_diff timings: total={}ms repo_path={}ms open_repo={}ms resolve_head={}ms resolve_base={}ms merge_base={}ms tree_ids={}ms collect_base={}ms collect_head={}ms add_mod_loop={}ms del_loop={}ms blob_read={}ms textdiff={}ms textdiff_count={} memo_hits={} scanned_bytes={} files: +{} ~{} -{} (binary={}) max_textdiff={{path: {:?}, ms: {}}} cwd={} out_len={}",
     _d_total.as_millis(),
     _d_repo_path.as_millis(),
-    _d_fetch.as_millis(),
     _d_open.as_millis(),
     _d_head.as_millis(),
     _d_base.as_millis(),
@@ -522,6 +763,7 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     (_blob_read_ns as f64 / 1_000_000.0) as i64,
     (_textdiff_ns as f64 / 1_000_000.0) as i64,
     _textdiff_count,
+    _memo_hits,
     _total_scanned_bytes,
     _num_added,
     _num_modified,
@@ -551,69 +793,67 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
         if parts.is_empty() { continue; }
         let status = parts[0].trim();
         match status {
-          "A" => {
-            if parts.len() >= 2 {
-              let path = parts[1].to_string();
-              let mut e = DiffEntry{ filePath: path.clone(), status: "added".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
-              if include {
-                // new content from head
-                if let Ok(buf) = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, path)]) {
-                  let new_sz = buf.as_bytes().len();
-                  e.newSize = Some(new_sz as i32);
-                  e.oldSize = Some(0);
-                  if new_sz <= max_bytes { e.newContent = Some(buf.clone()); e.oldContent = Some(String::new()); e.additions = buf.lines().count() as i32; e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
-                }
+          "A" if parts.len() >= 2 => {
+            let path = parts[1].to_string();
+            let mut e = DiffEntry{ filePath: path.clone(), status: "added".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
+            if include {
+              // new content from head
+              if let Ok(buf) = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, path)]) {
+                let new_sz = buf.len();
+                e.newSize = Some(new_sz as i32);
+                e.oldSize = Some(0);
+                if new_sz <= max_bytes { e.newContent = Some(buf.clone()); e.oldContent = Some(String::new()); e.additions = buf.lines().count() as i32; e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
               }
-              fallback.push(e);
             }
+            fallback.push(e);
           }
-          "M" => {
-            if parts.len() >= 2 {
-              let path = parts[1].to_string();
-              let mut e = DiffEntry{ filePath: path.clone(), status: "modified".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
-              if include {
-                let old_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", compare_base_oid, path)]).unwrap_or_default();
-                let new_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, path)]).unwrap_or_default();
-                let old_sz = old_s.as_bytes().len(); let new_sz = new_s.as_bytes().len();
-                e.oldSize = Some(old_sz as i32); e.newSize = Some(new_sz as i32);
-                if old_sz + new_sz <= max_bytes {
-                  let diff = TextDiff::from_lines(&old_s, &new_s);
-                  let mut adds=0i32; let mut dels=0i32; for op in diff.ops(){ let tag=op.tag(); for ch in diff.iter_changes(op){ match (tag, ch.tag()) { (similar::DiffTag::Insert, _) => adds+=1, (similar::DiffTag::Delete, _) => dels+=1, _=>{} } } }
-                  e.additions = adds; e.deletions = dels; e.oldContent = Some(old_s); e.newContent = Some(new_s); e.contentOmitted = Some(false);
-                } else { e.contentOmitted = Some(true); }
-              }
-              fallback.push(e);
+          "M" if parts.len() >= 2 => {
+            let path = parts[1].to_string();
+            let mut e = DiffEntry{ filePath: path.clone(), status: "modified".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
+            if include {
+              let old_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", compare_base_oid, path)]).unwrap_or_default();
+              let new_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, path)]).unwrap_or_default();
+              let old_sz = old_s.len(); let new_sz = new_s.len();
+              e.oldSize = Some(old_sz as i32); e.newSize = Some(new_sz as i32);
+              if old_sz + new_sz <= max_bytes {
+                let diff = TextDiff::from_lines(&old_s, &new_s);
+                let mut adds=0i32; let mut dels=0i32; for op in diff.ops(){ let tag=op.tag(); for ch in diff.iter_changes(op){ match (tag, ch.tag()) { (similar::DiffTag::Insert, _) => adds+=1, (similar::DiffTag::Delete, _) => dels+=1, _=>{} } } }
+                e.additions = adds; e.deletions = dels; e.oldContent = Some(old_s); e.newContent = Some(new_s); e.contentOmitted = Some(false);
+              } else { e.contentOmitted = Some(true); }
             }
+            fallback.push(e);
           }
-          "D" => {
-            if parts.len() >= 2 {
-              let path = parts[1].to_string();
-              let mut e = DiffEntry{ filePath: path.clone(), status: "deleted".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
-              if include {
-                if let Ok(buf) = crate::util::run_git(&cwd, &["show", &format!("{}:{}", compare_base_oid, path)]) {
-                  let old_sz = buf.as_bytes().len(); e.oldSize = Some(old_sz as i32);
-                  if old_sz <= max_bytes { e.oldContent = Some(buf.clone()); e.newContent = Some(String::new()); e.deletions = buf.lines().count() as i32; e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
-                }
+          "D" if parts.len() >= 2 => {
+            let path = parts[1].to_string();
+            let mut e = DiffEntry{ filePath: path.clone(), status: "deleted".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
+            if include {
+              if let Ok(buf) = crate::util::run_git(&cwd, &["show", &format!("{}:{}", compare_base_oid, path)]) {
+                let old_sz = buf.len(); e.oldSize = Some(old_sz as i32);
+                if old_sz <= max_bytes { e.oldContent = Some(buf.clone()); e.newContent = Some(String::new()); e.deletions = buf.lines().count() as i32; e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
               }
-              fallback.push(e);
             }
+            fallback.push(e);
           }
-          "R" | "R100" | "R099" | "R098" | "R097" | "R096" | "R095" | "R094" | "R093" | "R092" | "R091" | "R090" => {
-            if parts.len() >= 3 {
-              let oldp = parts[1].to_string();
-              let newp = parts[2].to_string();
-              let mut e = DiffEntry{ filePath: newp.clone(), oldPath: Some(oldp.clone()), status: "renamed".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
-              if include {
-                let new_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, newp)]).unwrap_or_default();
-                let new_sz = new_s.as_bytes().len(); e.newSize = Some(new_sz as i32); e.oldSize = Some(new_sz as i32);
-                if new_sz <= max_bytes { e.oldContent = Some(new_s.clone()); e.newContent = Some(new_s); e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
-              }
-              fallback.push(e);
+          "R" | "R100" | "R099" | "R098" | "R097" | "R096" | "R095" | "R094" | "R093" | "R092" | "R091" | "R090" if parts.len() >= 3 => {
+            let oldp = parts[1].to_string();
+            let newp = parts[2].to_string();
+            let mut e = DiffEntry{ filePath: newp.clone(), oldPath: Some(oldp.clone()), status: "renamed".into(), additions: 0, deletions: 0, isBinary: false, ..Default::default() };
+            if include {
+              let new_s = crate::util::run_git(&cwd, &["show", &format!("{}:{}", head_oid, newp)]).unwrap_or_default();
+              let new_sz = new_s.len(); e.newSize = Some(new_sz as i32); e.oldSize = Some(new_sz as i32);
+              if new_sz <= max_bytes { e.oldContent = Some(new_s.clone()); e.newContent = Some(new_s); e.contentOmitted = Some(false);} else { e.contentOmitted = Some(true); }
             }
+            fallback.push(e);
           }
           _ => {}
         }
       }
+      if let Some(spec) = &pathspec {
+        fallback.retain(|e| spec.matches(&e.filePath) || e.oldPath.as_deref().is_some_and(|p| spec.matches(p)));
+      }
+      if collapse.is_some() {
+        for e in fallback.iter_mut().filter(|e| is_collapsed(&e.filePath)) { collapse_in_place(e); }
+      }
       if !fallback.is_empty() {
         #[cfg(debug_assertions)] println!("[native.refs] CLI fallback returning {} entries", fallback.len());
         // Stable sort by filePath (case-insensitive)
@@ -621,16 +861,35 @@ pub fn diff_refs(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
           a.filePath.to_lowercase().cmp(&b.filePath.to_lowercase())
             .then_with(|| a.filePath.cmp(&b.filePath))
         });
+        if let Some(p) = &patch_output { p.apply(&mut fallback); }
+        mark_stale(&mut fallback, stale);
         return Ok(fallback);
       }
     }
   }
 
+  // Under textOnly, binaries carry no reviewable content either.
+  if collapse.is_some() {
+    for e in out.iter_mut().filter(|e| e.isBinary) { collapse_in_place(e); }
+  }
+
+  for e in out.iter_mut().filter(|e| e.status != "submodule") {
+    fill_modes(e, &base_files, &head_files);
+  }
+
   // Stable sort by filePath (case-insensitive)
   out.sort_by(|a, b| {
     a.filePath.to_lowercase().cmp(&b.filePath.to_lowercase())
       .then_with(|| a.filePath.cmp(&b.filePath))
   });
+  if let Some(p) = &patch_output { p.apply(&mut out); }
+  mark_stale(&mut out, stale);
 
   Ok(out)
 }
+
+fn mark_stale(entries: &mut [DiffEntry], stale: bool) {
+  if stale {
+    for e in entries.iter_mut() { e.stale = Some(true); }
+  }
+}
@@ -0,0 +1,93 @@
+use similar::{ChangeTag, TextDiff};
+
+/// Same default as `git diff -M`: at least half of the lines must survive a rename.
+pub const DEFAULT_THRESHOLD: i32 = 50;
+// Like git's `diff.renameLimit`: past this many candidate pairs similarity detection is skipped
+// and unmatched files stay additions and deletions.
+const MAX_PAIRS: usize = 10_000;
+
+/// Share of lines two texts have in common, in whole percent.
+pub fn similarity(old: &str, new: &str) -> i32 {
+  (TextDiff::from_lines(old, new).ratio() * 100.0).round() as i32
+}
+
+/// Lines added and removed going from `old` to `new`.
+pub fn line_counts(old: &str, new: &str) -> (i32, i32) {
+  let diff = TextDiff::from_lines(old, new);
+  let (mut adds, mut dels) = (0i32, 0i32);
+  for change in diff.iter_all_changes() {
+    match change.tag() {
+      ChangeTag::Insert => adds += 1,
+      ChangeTag::Delete => dels += 1,
+      ChangeTag::Equal => {}
+    }
+  }
+  (adds, dels)
+}
+
+/// Pairs each target with its most similar source, scoring at least `threshold` percent, as
+/// `(source index, target index, similarity)`. Best scores are claimed first; a source pairs
+/// at most once unless `reuse_sources` (copies may share a source, renames may not). Empty
+/// files never pair, and nothing pairs when there are too many candidates to compare.
+pub fn pair_by_similarity(sources: &[&str], targets: &[&str], threshold: i32, reuse_sources: bool) -> Vec<(usize, usize, i32)> {
+  if sources.len().saturating_mul(targets.len()) > MAX_PAIRS {
+    return Vec::new();
+  }
+  let source_lines: Vec<usize> = sources.iter().map(|s| s.lines().count()).collect();
+  let target_lines: Vec<usize> = targets.iter().map(|t| t.lines().count()).collect();
+  let mut scored: Vec<(i32, usize, usize)> = Vec::new();
+  for (ti, target) in targets.iter().enumerate() {
+    for (si, source) in sources.iter().enumerate() {
+      let (a, b) = (source_lines[si], target_lines[ti]);
+      if source.is_empty() || target.is_empty() {
+        continue;
+      }
+      // Every line of the shorter file in common is the best two files can do.
+      if (200 * a.min(b)).div_ceil((a + b).max(1)) < threshold.max(0) as usize {
+        continue;
+      }
+      let score = similarity(source, target);
+      if score >= threshold {
+        scored.push((score, si, ti));
+      }
+    }
+  }
+  scored.sort_by(|x, y| y.0.cmp(&x.0).then(x.1.cmp(&y.1)).then(x.2.cmp(&y.2)));
+  let mut source_taken = vec![false; sources.len()];
+  let mut target_taken = vec![false; targets.len()];
+  let mut out = Vec::new();
+  for (score, si, ti) in scored {
+    if target_taken[ti] || (!reuse_sources && source_taken[si]) {
+      continue;
+    }
+    target_taken[ti] = true;
+    source_taken[si] = true;
+    out.push((si, ti, score));
+  }
+  out.sort_by_key(|&(_, ti, _)| ti);
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pairs_most_similar_files_first() {
+    let body: String = (1..=10).map(|i| format!("line {i}\n")).collect();
+    let edited = body.replace("line 3\n", "line three\n");
+    let other: String = (1..=10).map(|i| format!("other {i}\n")).collect();
+    let sources = [other.as_str(), body.as_str()];
+    let targets = [edited.as_str(), body.as_str()];
+
+    // The identical copy wins `body`; the edited one has no source left.
+    assert_eq!(pair_by_similarity(&sources, &targets, DEFAULT_THRESHOLD, false), vec![(1, 1, 100)]);
+    assert_eq!(
+      pair_by_similarity(&sources, &targets, DEFAULT_THRESHOLD, true),
+      vec![(1, 0, 90), (1, 1, 100)]
+    );
+    assert!(pair_by_similarity(&sources, &targets[..1], 95, false).is_empty());
+    assert!(pair_by_similarity(&[""], &[""], DEFAULT_THRESHOLD, false).is_empty());
+    assert_eq!(line_counts(&body, &edited), (1, 1));
+  }
+}
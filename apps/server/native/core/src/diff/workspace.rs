@@ -3,9 +3,10 @@ use gix::bstr::ByteSlice;
 use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
 use gix::{hash::ObjectId, Repository};
 use similar::TextDiff;
+use super::{patch::PatchOutput, pathspec::Pathspec};
 use crate::types::{DiffEntry, GitDiffWorkspaceOptions};
 
-fn is_binary(data: &[u8]) -> bool { data.iter().any(|&b| b == 0) || std::str::from_utf8(data).is_err() }
+fn is_binary(data: &[u8]) -> bool { data.contains(&0) || std::str::from_utf8(data).is_err() }
 
 fn default_remote_head(repo: &Repository) -> Option<ObjectId> {
   if let Ok(r) = repo.find_reference("refs/remotes/origin/HEAD") {
@@ -56,7 +57,7 @@ fn merge_base_oid(repo: &Repository, a: ObjectId, b: ObjectId) -> ObjectId {
   best.map(|(id, _)| id).unwrap_or(a)
 }
 
-fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, out: &mut HashMap<String, ObjectId>) -> anyhow::Result<()> {
+fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, spec: Option<&Pathspec>, out: &mut HashMap<String, ObjectId>) -> anyhow::Result<()> {
   let obj = repo.find_object(tree_id)?;
   let tree = obj.try_into_tree()?;
   for entry_res in tree.iter() {
@@ -65,9 +66,11 @@ fn collect_tree_blobs(repo: &Repository, tree_id: ObjectId, prefix: &str, out: &
     let full = if prefix.is_empty() { name.clone() } else { format!("{}/{}", prefix, name) };
     let mode = entry.mode();
     if mode.is_tree() {
+      if spec.is_some_and(|s| !s.may_contain(&full)) { continue; }
       let id = entry.oid().to_owned();
-      collect_tree_blobs(repo, id, &full, out)?;
+      collect_tree_blobs(repo, id, &full, spec, out)?;
     } else {
+      if spec.is_some_and(|s| !s.matches(&full)) { continue; }
       let id = entry.oid().to_owned();
       out.insert(full, id);
     }
@@ -81,8 +84,7 @@ fn should_ignore(root: &Path, rel: &str) -> bool {
     for line in s.lines() {
       let rule = line.trim();
       if rule.is_empty() || rule.starts_with('#') { continue; }
-      if rule.ends_with('/') {
-        let d = &rule[..rule.len()-1];
+      if let Some(d) = rule.strip_suffix('/') {
         if rel == d || rel.starts_with(&format!("{}/", d)) { return true; }
       } else {
         if rel == rule || rel.starts_with(&format!("{}/", rule)) { return true; }
@@ -92,27 +94,37 @@ fn should_ignore(root: &Path, rel: &str) -> bool {
   false
 }
 
-fn scan_workdir(root: &Path) -> Vec<String> {
+fn scan_workdir(root: &Path, spec: Option<&Pathspec>) -> Vec<String> {
   let mut out = Vec::new();
-  fn rec(cur: &Path, base: &Path, out: &mut Vec<String>) {
+  fn rec(cur: &Path, base: &Path, spec: Option<&Pathspec>, out: &mut Vec<String>) {
     if let Ok(entries) = fs::read_dir(cur) {
       for ent in entries.flatten() {
         let p = ent.path();
         if p.file_name().map(|s| s == ".git").unwrap_or(false) { continue; }
         let rel = p.strip_prefix(base).unwrap().to_string_lossy().replace('\\', "/");
         if should_ignore(base, &rel) { continue; }
-        if p.is_dir() { rec(&p, base, out); } else if p.is_file() { out.push(rel); }
+        if p.is_dir() {
+          if spec.is_none_or(|s| s.may_contain(&rel)) { rec(&p, base, spec, out); }
+        } else if p.is_file() && spec.is_none_or(|s| s.matches(&rel)) { out.push(rel); }
       }
     }
   }
-  rec(root, root, &mut out);
+  rec(root, root, spec, &mut out);
   out
 }
 
 pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
   let cwd = PathBuf::from(&opts.worktreePath);
-  let include = opts.includeContents.unwrap_or(true);
+  let patch_output = PatchOutput::from_options(
+    opts.includeContents,
+    opts.includePatch,
+    opts.includeHunks,
+    opts.includeIntraline,
+    opts.patchContext,
+  );
+  let include = opts.includeContents.unwrap_or(true) || patch_output.is_some();
   let max_bytes = opts.maxBytes.unwrap_or(950*1024) as usize;
+  let pathspec = Pathspec::from_options(opts.pathspecs.as_deref());
   let _ = crate::repo::cache::swr_fetch_origin_all_path(&cwd, crate::repo::cache::fetch_window_ms());
   let repo = gix::open(&cwd)?;
 
@@ -125,7 +137,7 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
       let merge_base = merge_base_oid(&repo, base_candidate, head_oid);
       let base_commit = repo.find_object(merge_base)?.try_into_commit()?;
       let base_tree_id = base_commit.tree_id()?.detach();
-      collect_tree_blobs(&repo, base_tree_id, "", &mut base_map)?;
+      collect_tree_blobs(&repo, base_tree_id, "", pathspec.as_ref(), &mut base_map)?;
     }
     Err(_) => {
       // Unborn HEAD: try remote default HEAD tree; otherwise empty base
@@ -133,7 +145,7 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
         if let Ok(obj) = repo.find_object(remote_head) {
           if let Ok(base_commit) = obj.try_into_commit() {
             if let Ok(tree_id) = base_commit.tree_id() {
-              collect_tree_blobs(&repo, tree_id.detach(), "", &mut base_map)?;
+              collect_tree_blobs(&repo, tree_id.detach(), "", pathspec.as_ref(), &mut base_map)?;
             }
           }
         }
@@ -141,8 +153,8 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
     }
   }
 
-  let workdir = repo.work_dir().unwrap_or_else(|| cwd.as_path());
-  let files = scan_workdir(workdir);
+  let workdir = repo.work_dir().unwrap_or(cwd.as_path());
+  let files = scan_workdir(workdir, pathspec.as_ref());
 
   let mut out: Vec<DiffEntry> = Vec::new();
 
@@ -155,7 +167,7 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
         let mut e = DiffEntry{ filePath: rel.clone(), status: "added".into(), additions: 0, deletions: 0, isBinary: bin, ..Default::default() };
         if include && !bin {
           let new_str = String::from_utf8_lossy(&new_data).into_owned();
-          let new_sz = new_str.as_bytes().len();
+          let new_sz = new_str.len();
           e.newSize = Some(new_sz as i32);
           e.oldSize = Some(0);
           if new_sz <= max_bytes { e.newContent = Some(new_str.clone()); e.oldContent = Some(String::new()); e.contentOmitted = Some(false); e.additions = new_str.lines().count() as i32; } else { e.contentOmitted = Some(true) }
@@ -166,12 +178,12 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
         let old_blob = repo.find_object(*old_id)?.try_into_blob()?;
         let old_data = &old_blob.data;
         if new_data == *old_data { continue; }
-        let bin = is_binary(&old_data) || is_binary(&new_data);
+        let bin = is_binary(old_data) || is_binary(&new_data);
         let mut e = DiffEntry{ filePath: rel.clone(), status: "modified".into(), additions: 0, deletions: 0, isBinary: bin, ..Default::default() };
         if include && !bin {
-          let old_str = String::from_utf8_lossy(&old_data).into_owned();
+          let old_str = String::from_utf8_lossy(old_data).into_owned();
           let new_str = String::from_utf8_lossy(&new_data).into_owned();
-          let old_sz = old_str.as_bytes().len(); let new_sz = new_str.as_bytes().len();
+          let old_sz = old_str.len(); let new_sz = new_str.len();
           if old_sz + new_sz <= max_bytes { let diff = TextDiff::from_lines(&old_str, &new_str); let mut adds=0i32; let mut dels=0i32; for op in diff.ops(){ let tag=op.tag(); for ch in diff.iter_changes(op){ match (tag, ch.tag()) { (similar::DiffTag::Insert, _) => adds+=1, (similar::DiffTag::Delete, _) => dels+=1, _=>{} } } } e.additions=adds; e.deletions=dels; e.oldContent=Some(old_str); e.newContent=Some(new_str); e.contentOmitted=Some(false);} else { e.contentOmitted=Some(true) }
           e.oldSize = Some(old_sz as i32); e.newSize = Some(new_sz as i32);
         } else { e.contentOmitted = Some(false) }
@@ -186,11 +198,11 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
     if file_set.contains(rel.as_str()) { continue; }
     let old_blob = repo.find_object(*old_id)?.try_into_blob()?;
     let old_data = &old_blob.data;
-    let bin = is_binary(&old_data);
+    let bin = is_binary(old_data);
     let mut e = DiffEntry{ filePath: rel.clone(), status: "deleted".into(), additions: 0, deletions: 0, isBinary: bin, ..Default::default() };
     if include && !bin {
-      let old_str = String::from_utf8_lossy(&old_data).into_owned();
-      let old_sz = old_str.as_bytes().len();
+      let old_str = String::from_utf8_lossy(old_data).into_owned();
+      let old_sz = old_str.len();
       e.oldSize = Some(old_sz as i32);
       if old_sz <= max_bytes { e.oldContent = Some(old_str); e.newContent = Some(String::new()); e.contentOmitted = Some(false); e.deletions = e.oldContent.as_ref().unwrap().lines().count() as i32; } else { e.contentOmitted = Some(true) }
     } else { e.contentOmitted = Some(false) }
@@ -202,6 +214,7 @@ pub fn diff_workspace(opts: GitDiffWorkspaceOptions) -> Result<Vec<DiffEntry>> {
     a.filePath.to_lowercase().cmp(&b.filePath.to_lowercase())
       .then_with(|| a.filePath.cmp(&b.filePath))
   });
+  if let Some(p) = &patch_output { p.apply(&mut out); }
 
   Ok(out)
 }
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use gix::{hash::ObjectId, Repository};
+use similar::TextDiff;
+use std::collections::HashMap;
+
+use crate::blame::{blob_at_path, blob_text, commit_info, commit_tree};
+use crate::diff::refs::{collect_tree_blobs, oid_from_rev_parse};
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::repo::partial::{fetch_missing, no_fetch};
+use crate::types::{FileHistoryEntry, GitFileHistoryOptions};
+
+const DEFAULT_MAX_COUNT: usize = 50;
+// Same default as `git log --follow`: at least half of the lines must survive a rename.
+const RENAME_SIMILARITY: f32 = 0.5;
+
+// Where the blob `blob` at a path that `parent_tree` lacks came from: a file deleted between
+// `parent_tree` and `tree` with the same contents, or else the most similar one.
+fn find_rename(repo: &Repository, tree: ObjectId, parent_tree: ObjectId, blob: ObjectId) -> Result<Option<(String, ObjectId)>> {
+  let mut now: HashMap<String, ObjectId> = HashMap::new();
+  let mut before: HashMap<String, ObjectId> = HashMap::new();
+  collect_tree_blobs(repo, tree, "", &mut now)?;
+  collect_tree_blobs(repo, parent_tree, "", &mut before)?;
+  let mut deleted: Vec<(String, ObjectId)> = before.into_iter().filter(|(p, _)| !now.contains_key(p)).collect();
+  deleted.sort();
+  if let Some((path, id)) = deleted.iter().find(|(_, id)| *id == blob) {
+    return Ok(Some((path.clone(), *id)));
+  }
+  let Some(text) = blob_text(repo, blob)? else { return Ok(None) };
+  fetch_missing(repo, deleted.iter().map(|(_, id)| *id))?;
+  let mut best: Option<(f32, &String, ObjectId)> = None;
+  for (path, id) in &deleted {
+    let Some(old) = blob_text(repo, *id)? else { continue };
+    // Files more than twice the size of each other cannot be half the same.
+    if old.len() > text.len() * 2 || text.len() > old.len() * 2 {
+      continue;
+    }
+    let ratio = TextDiff::from_lines(&old, &text).ratio();
+    if ratio < RENAME_SIMILARITY || best.is_some_and(|(r, _, _)| r >= ratio) {
+      continue;
+    }
+    best = Some((ratio, path, *id));
+  }
+  Ok(best.map(|(_, path, id)| (path.clone(), id)))
+}
+
+/// Commits that changed `path` as of `ref`, newest first, following it back through renames.
+///
+/// A rename is a file the commit deleted with the same contents, or failing that with at least
+/// half of its lines in common. Merges are followed the way `git_blame` follows them: into a
+/// parent with the file unchanged, otherwise into the first parent.
+pub fn file_history(opts: GitFileHistoryOptions) -> Result<Vec<FileHistoryEntry>> {
+  let offline = opts.offline.unwrap_or(false);
+  let _no_fetch = no_fetch(offline);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let rev = opts.refName.trim();
+  let max_count = opts.maxCount.map(|n| n.max(0) as usize).unwrap_or(DEFAULT_MAX_COUNT);
+  let mut current = oid_from_rev_parse(&repo, rev)?;
+  let mut path = opts.path.trim_start_matches('/').to_string();
+  let mut blob = blob_at_path(&repo, commit_tree(&repo, current)?, &path)?
+    .ok_or_else(|| anyhow!("{} does not exist at {}", path, rev))?;
+
+  let mut out: Vec<FileHistoryEntry> = Vec::new();
+  while out.len() < max_count {
+    crate::cancel::check()?;
+    let commit = repo.find_object(current)?.try_into_commit()?;
+    let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+    let mut parent_blobs = Vec::with_capacity(parents.len());
+    for parent in &parents {
+      parent_blobs.push(blob_at_path(&repo, commit_tree(&repo, *parent)?, &path)?);
+    }
+    if let Some(i) = parent_blobs.iter().position(|b| *b == Some(blob)) {
+      current = parents[i];
+      continue;
+    }
+
+    // This commit changed the file: find what it was before.
+    let previous = match (parents.first(), parent_blobs.first().copied().flatten()) {
+      (None, _) => None,
+      (Some(parent), Some(parent_blob)) => Some((*parent, path.clone(), parent_blob)),
+      (Some(parent), None) => {
+        let tree = commit.tree_id()?.detach();
+        find_rename(&repo, tree, commit_tree(&repo, *parent)?, blob)?
+          .map(|(old_path, old_blob)| (*parent, old_path, old_blob))
+      }
+    };
+    let info = commit_info(&repo, current)?;
+    let (status, previous_path) = match &previous {
+      None => ("added", None),
+      Some((_, old_path, _)) if *old_path != path => ("renamed", Some(old_path.clone())),
+      Some(_) => ("modified", None),
+    };
+    out.push(FileHistoryEntry {
+      sha: current.to_hex().to_string(),
+      path: path.clone(),
+      previousPath: previous_path,
+      status: status.into(),
+      authorName: info.author_name,
+      authorEmail: info.author_email,
+      authorTime: info.author_time,
+      summary: info.summary,
+      stale: stale.then_some(true),
+    });
+    let Some((parent, old_path, old_blob)) = previous else { break };
+    current = parent;
+    path = old_path;
+    blob = old_blob;
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::commit;
+  use crate::util::run_git;
+  use std::fs;
+  use tempfile::tempdir;
+
+  fn opts(repo: &str, path: &str) -> GitFileHistoryOptions {
+    GitFileHistoryOptions {
+      originPathOverride: Some(repo.to_string()),
+      refName: "HEAD".into(),
+      path: path.into(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn follows_exact_and_similar_renames() {
+    let tmp = tempdir().expect("tempdir");
+    let root = tmp.path();
+    let repo = root.to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    let body: String = (1..=20).map(|i| format!("line {i}\n")).collect();
+    fs::write(root.join("old.txt"), &body).unwrap();
+    fs::write(root.join("other.txt"), "unrelated\n").unwrap();
+    let c1 = commit(repo, "add old.txt");
+    fs::write(root.join("old.txt"), format!("{body}line 21\n")).unwrap();
+    let c2 = commit(repo, "extend old.txt");
+    fs::rename(root.join("old.txt"), root.join("mid.txt")).unwrap();
+    let c3 = commit(repo, "rename to mid.txt");
+    fs::write(root.join("other.txt"), "still unrelated\n").unwrap();
+    commit(repo, "touch other.txt");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::remove_file(root.join("mid.txt")).unwrap();
+    fs::write(root.join("src/new.txt"), format!("header\n{body}line 21\n")).unwrap();
+    let c5 = commit(repo, "move and edit");
+
+    let history = file_history(opts(repo, "src/new.txt")).expect("history");
+    let chain: Vec<(&str, &str, &str)> =
+      history.iter().map(|e| (e.sha.as_str(), e.path.as_str(), e.status.as_str())).collect();
+    assert_eq!(
+      chain,
+      vec![
+        (c5.as_str(), "src/new.txt", "renamed"),
+        (c3.as_str(), "mid.txt", "renamed"),
+        (c2.as_str(), "old.txt", "modified"),
+        (c1.as_str(), "old.txt", "added"),
+      ]
+    );
+    assert_eq!(history[0].previousPath.as_deref(), Some("mid.txt"));
+    assert_eq!(history[1].previousPath.as_deref(), Some("old.txt"));
+    assert_eq!(history[2].previousPath, None);
+    assert_eq!(history[0].summary, "move and edit");
+
+    let limited = file_history(GitFileHistoryOptions { maxCount: Some(2), ..opts(repo, "src/new.txt") }).expect("limited");
+    assert_eq!(limited.len(), 2);
+
+    let err = file_history(opts(repo, "old.txt")).expect_err("gone at HEAD");
+    assert!(err.to_string().contains("does not exist"), "{err}");
+  }
+}
@@ -0,0 +1,279 @@
+use anyhow::Result;
+use gix::{bstr::ByteSlice, hash::ObjectId, Repository};
+
+use crate::diff::refs::oid_from_rev_parse;
+use crate::merge_base::merge_base_many;
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::types::{GitIsLandedOptions, LandedInfo};
+
+// Bounds the first-parent walks down base's history.
+const FIRST_PARENT_LIMIT: usize = 10_000;
+
+fn fallback_origin_ref(name: &str) -> Option<String> {
+  let trimmed = name.trim();
+  if trimmed.is_empty() || trimmed == "origin" || trimmed.starts_with("refs/") {
+    return None;
+  }
+  let mut without = trimmed;
+  while let Some(stripped) = without.strip_prefix("origin/") {
+    without = stripped;
+  }
+  let candidate = format!("origin/{}", without);
+  if candidate == trimmed {
+    None
+  } else {
+    Some(candidate)
+  }
+}
+
+/// Resolves `name`, falling back to `origin/<name>` when it does not resolve as given.
+pub(crate) fn resolve_ref_with_origin(repo: &Repository, name: &str) -> Result<ObjectId> {
+  match oid_from_rev_parse(repo, name) {
+    Ok(oid) => Ok(oid),
+    Err(orig_err) => match fallback_origin_ref(name) {
+      Some(fallback) => oid_from_rev_parse(repo, &fallback).map_err(|_| orig_err),
+      None => Err(orig_err),
+    },
+  }
+}
+
+fn is_ancestor(repo: &Repository, anc: ObjectId, desc: ObjectId) -> Result<bool> {
+  Ok(merge_base_many(repo, vec![anc, desc])? == [anc])
+}
+
+fn parent_ids(repo: &Repository, id: ObjectId) -> Result<Vec<ObjectId>> {
+  Ok(repo.find_object(id)?.try_into_commit()?.parent_ids().map(|p| p.detach()).collect())
+}
+
+// The commit right after `b0` on the first-parent chain from `b_tip`.
+fn first_commit_after_b0_on_first_parent(repo: &Repository, b_tip: ObjectId, b0: ObjectId) -> Result<Option<ObjectId>> {
+  let mut cur = b_tip;
+  for _ in 0..FIRST_PARENT_LIMIT {
+    crate::cancel::check()?;
+    if cur == b0 {
+      return Ok(None);
+    }
+    let Some(p1) = parent_ids(repo, cur)?.first().copied() else { return Ok(None) };
+    if p1 == b0 {
+      return Ok(Some(cur));
+    }
+    cur = p1;
+  }
+  Ok(None)
+}
+
+// The newest commit between `b0` and `b_tip` on base's first-parent chain that head contains.
+fn last_fp_block_ancestor_of_head(repo: &Repository, b_tip: ObjectId, b0: ObjectId, head_tip: ObjectId) -> Result<Option<ObjectId>> {
+  let mut cur = b_tip;
+  for _ in 0..FIRST_PARENT_LIMIT {
+    crate::cancel::check()?;
+    if cur == b0 {
+      break;
+    }
+    if is_ancestor(repo, cur, head_tip)? {
+      return Ok(Some(cur));
+    }
+    let Some(p1) = parent_ids(repo, cur)?.first().copied() else { break };
+    cur = p1;
+  }
+  Ok(None)
+}
+
+// The newest merge on base's first-parent chain that satisfies `matches`, with its first parent.
+fn find_merge(
+  repo: &Repository,
+  base_tip: ObjectId,
+  mut matches: impl FnMut(&gix::Commit<'_>, ObjectId) -> Result<bool>,
+) -> Result<Option<(ObjectId, ObjectId)>> {
+  let mut cur = base_tip;
+  for _ in 0..FIRST_PARENT_LIMIT {
+    crate::cancel::check()?;
+    let commit = repo.find_object(cur)?.try_into_commit()?;
+    let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+    if let [p1, p2, ..] = parents[..] {
+      if matches(&commit, p2)? {
+        return Ok(Some((p1, cur)));
+      }
+    }
+    let Some(p1) = parents.first().copied() else { break };
+    cur = p1;
+  }
+  Ok(None)
+}
+
+// A merge whose message names the head branch, as GitHub's merge commits do.
+fn find_merge_by_message(repo: &Repository, base_tip: ObjectId, head_ref: &str) -> Result<Option<(ObjectId, ObjectId)>> {
+  let needle = head_ref.trim().trim_start_matches("origin/");
+  find_merge(repo, base_tip, |commit, _| Ok(commit.message_raw()?.to_str_lossy().contains(needle)))
+}
+
+// A merge whose second parent head contains.
+fn find_merge_integrating_head(repo: &Repository, base_tip: ObjectId, head_tip: ObjectId) -> Result<Option<(ObjectId, ObjectId)>> {
+  find_merge(repo, base_tip, |_, p2| is_ancestor(repo, p2, head_tip))
+}
+
+/// How head landed on base.
+pub(crate) struct Landing {
+  /// `merge`, `squash` or `fast-forward`.
+  pub strategy: &'static str,
+  /// The commit on base's first-parent chain that brought head in.
+  pub commit: ObjectId,
+  /// Diffing `from` to `to` shows what landed.
+  pub from: ObjectId,
+  pub to: ObjectId,
+}
+
+/// Finds the commit that landed `h_tip` on `b_tip`'s first-parent history.
+///
+/// With `b0`, base's tip when head was opened, the first commit after it is the landing: a
+/// merge, the start of a fast-forwarded block head contains, or otherwise a squash or rebase.
+/// Without it, a merge whose message names `head_ref` is preferred over one whose second parent
+/// head contains; a head its base already contains is only reported landed by message, since a
+/// branch without commits of its own would match any merge.
+pub(crate) fn detect_landing(
+  repo: &Repository,
+  b_tip: ObjectId,
+  h_tip: ObjectId,
+  b0: Option<ObjectId>,
+  head_ref: &str,
+) -> Result<Option<Landing>> {
+  if b_tip == h_tip {
+    return Ok(None);
+  }
+  if let Some(b0) = b0 {
+    let Some(c1) = first_commit_after_b0_on_first_parent(repo, b_tip, b0)? else { return Ok(None) };
+    let parents = parent_ids(repo, c1)?;
+    return Ok(Some(if parents.len() > 1 {
+      Landing { strategy: "merge", commit: c1, from: parents[0], to: c1 }
+    } else if is_ancestor(repo, c1, h_tip)? {
+      let h0 = last_fp_block_ancestor_of_head(repo, b_tip, b0, h_tip)?.unwrap_or(c1);
+      Landing { strategy: "fast-forward", commit: h0, from: b0, to: h0 }
+    } else {
+      Landing { strategy: "squash", commit: c1, from: b0, to: c1 }
+    }));
+  }
+  let merge = match find_merge_by_message(repo, b_tip, head_ref)? {
+    Some(found) => Some(found),
+    None if is_ancestor(repo, h_tip, b_tip)? => None,
+    None => find_merge_integrating_head(repo, b_tip, h_tip)?,
+  };
+  Ok(merge.map(|(p1, m)| Landing { strategy: "merge", commit: m, from: p1, to: m }))
+}
+
+/// Whether `headRef` has landed on `baseRef`, and through which commit, without diffing
+/// anything. `headContained` answers whether anything on head is still left to land.
+pub fn is_landed(opts: GitIsLandedOptions) -> Result<LandedInfo> {
+  let offline = opts.offline.unwrap_or(false);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let b_tip = resolve_ref_with_origin(&repo, &opts.baseRef)?;
+  let h_tip = resolve_ref_with_origin(&repo, &opts.headRef)?;
+  let b0 = match opts.b0Ref.as_deref() {
+    Some(b0) => Some(resolve_ref_with_origin(&repo, b0)?),
+    None => None,
+  };
+  let landing = detect_landing(&repo, b_tip, h_tip, b0, &opts.headRef)?;
+  let mut info = LandedInfo {
+    headContained: is_ancestor(&repo, h_tip, b_tip)?,
+    stale: stale.then_some(true),
+    ..Default::default()
+  };
+  if let Some(landing) = landing {
+    info.landed = true;
+    info.strategy = Some(landing.strategy.to_string());
+    info.mergeCommitSha = Some(landing.commit.to_hex().to_string());
+    info.mergeParents = parent_ids(&repo, landing.commit)?.iter().map(|p| p.to_hex().to_string()).collect();
+    info.diffBaseSha = Some(landing.from.to_hex().to_string());
+    info.diffHeadSha = Some(landing.to.to_hex().to_string());
+  }
+  Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{commit_file, git, rev};
+  use crate::util::run_git;
+  use tempfile::tempdir;
+
+  fn landed(repo: &str, base: &str, head: &str, b0: Option<&str>) -> LandedInfo {
+    is_landed(GitIsLandedOptions {
+      originPathOverride: Some(repo.to_string()),
+      baseRef: base.into(),
+      headRef: head.into(),
+      b0Ref: b0.map(str::to_string),
+      ..Default::default()
+    })
+    .expect("is landed")
+  }
+
+  #[test]
+  fn detects_merge_squash_and_fast_forward_landings() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    commit_file(repo, "root");
+    run_git(repo, &["checkout", "-b", "feature"]).unwrap();
+    commit_file(repo, "f1");
+    let f2 = commit_file(repo, "f2");
+    run_git(repo, &["checkout", "main"]).unwrap();
+    let m1 = commit_file(repo, "m1");
+    git(repo, &["merge", "--no-ff", "--no-edit", "feature"]);
+    let merge = rev(repo, "HEAD");
+
+    // Found by its message, and again as the first commit after the fork point.
+    for b0 in [None, Some(m1.as_str())] {
+      let r = landed(repo, "main", "feature", b0);
+      assert!(r.landed);
+      assert_eq!(r.strategy.as_deref(), Some("merge"));
+      assert_eq!(r.mergeCommitSha.as_deref(), Some(merge.as_str()));
+      assert_eq!(r.mergeParents, vec![m1.clone(), f2.clone()]);
+      assert_eq!((r.diffBaseSha.as_deref(), r.diffHeadSha.as_deref()), (Some(m1.as_str()), Some(merge.as_str())));
+      assert!(r.headContained);
+    }
+
+    // A squash leaves head's own commits out of base.
+    run_git(repo, &["checkout", "-b", "topic"]).unwrap();
+    commit_file(repo, "t1");
+    run_git(repo, &["checkout", "main"]).unwrap();
+    run_git(repo, &["merge", "--squash", "topic"]).unwrap();
+    git(repo, &["commit", "-m", "Squashed"]);
+    let squash = rev(repo, "HEAD");
+    let r = landed(repo, "main", "topic", Some(&merge));
+    assert_eq!(r.strategy.as_deref(), Some("squash"));
+    assert_eq!(r.mergeCommitSha.as_deref(), Some(squash.as_str()));
+    assert_eq!(r.mergeParents, vec![merge.clone()]);
+    assert_eq!(r.diffBaseSha.as_deref(), Some(merge.as_str()));
+    assert!(!r.headContained);
+
+    // A fast-forward lands through the newest commit of the block.
+    run_git(repo, &["checkout", "-b", "quick"]).unwrap();
+    let x1 = commit_file(repo, "x1");
+    let x2 = commit_file(repo, "x2");
+    run_git(repo, &["checkout", "main"]).unwrap();
+    run_git(repo, &["merge", "--ff-only", "quick"]).unwrap();
+    let r = landed(repo, "main", "quick", Some(&squash));
+    assert_eq!(r.strategy.as_deref(), Some("fast-forward"));
+    assert_eq!(r.mergeCommitSha.as_deref(), Some(x2.as_str()));
+    assert_eq!(r.mergeParents, vec![x1]);
+    assert_eq!((r.diffBaseSha.as_deref(), r.diffHeadSha.as_deref()), (Some(squash.as_str()), Some(x2.as_str())));
+    assert!(r.headContained);
+
+    // A branch off the root that never merged has not landed.
+    run_git(repo, &["checkout", "-b", "other", "main~5"]).unwrap();
+    commit_file(repo, "o1");
+    let r = landed(repo, "main", "other", None);
+    assert!(!r.landed);
+    assert_eq!((r.strategy, r.mergeCommitSha, r.mergeParents.len()), (None, None, 0));
+    assert!(!r.headContained);
+  }
+}
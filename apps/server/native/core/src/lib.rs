@@ -6,10 +6,45 @@ mod repo;
 mod diff;
 mod merge_base;
 mod branches;
+mod blame;
+mod log;
+mod file_history;
+mod ahead_behind;
+mod tags;
+mod commit;
+mod landed;
+mod apply;
+mod cancel;
+mod progress;
 
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
 use napi_derive::napi;
-use types::{BranchInfo, DiffEntry, GitDiffOptions, GitListRemoteBranchesOptions};
+use types::{
+  AheadBehind, ApplyPatchResult, BlameLine, BranchInfo, CachedRepo, CommitDetails, DiffEntry, FileHistoryEntry, GitAheadBehindOptions,
+  GitApplyPatchOptions, GitBlameOptions, GitCacheConfig, GitCommitInfoOptions, GitDiffOptions, GitFileHistoryOptions, GitIsLandedOptions,
+  GitListRemoteBranchesOptions, GitListTagsOptions, GitLogOptions, GitProgress, LandedInfo, LogCommit, TagInfo,
+};
+
+type ProgressCallback = ThreadsafeFunction<GitProgress, ErrorStrategy::Fatal>;
+
+// Clones and fetches made while serving a call report to its `onProgress` callback, if any.
+fn progress_sink(on_progress: Option<ProgressCallback>) -> Option<progress::Sink> {
+  on_progress.map(|f| -> progress::Sink {
+    std::sync::Arc::new(move |p: GitProgress| {
+      f.call(p, ThreadsafeFunctionCallMode::NonBlocking);
+    })
+  })
+}
+
+// Cancellation gets its own status so callers can tell it apart from a failure.
+fn git_error(e: anyhow::Error) -> Error {
+  if cancel::is_cancelled_error(&e) {
+    Error::new(Status::Cancelled, "cancelled".to_string())
+  } else {
+    Error::from_reason(format!("{e:#}"))
+  }
+}
 
 #[napi]
 pub async fn get_time() -> String {
@@ -21,7 +56,7 @@ pub async fn get_time() -> String {
 }
 
 #[napi]
-pub async fn git_diff(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
+pub async fn git_diff(opts: GitDiffOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<DiffEntry>> {
   #[cfg(debug_assertions)]
   println!(
     "[cmux_native_git] git_diff headRef={} baseRef={:?} originPathOverride={:?} repoUrl={:?} repoFullName={:?} includeContents={:?} maxBytes={:?}",
@@ -33,14 +68,19 @@ pub async fn git_diff(opts: GitDiffOptions) -> Result<Vec<DiffEntry>> {
     opts.includeContents,
     opts.maxBytes
   );
-  tokio::task::spawn_blocking(move || diff::refs::diff_refs(opts))
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    diff::refs::diff_refs(opts)
+  })
     .await
     .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
-    .map_err(|e| Error::from_reason(format!("{e:#}")))
+    .map_err(git_error)
 }
 
 #[napi]
-pub async fn git_list_remote_branches(opts: GitListRemoteBranchesOptions) -> Result<Vec<BranchInfo>> {
+pub async fn git_list_remote_branches(opts: GitListRemoteBranchesOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<BranchInfo>> {
   #[cfg(debug_assertions)]
   println!(
     "[cmux_native_git] git_list_remote_branches repoFullName={:?} repoUrl={:?} originPathOverride={:?}",
@@ -48,7 +88,259 @@ pub async fn git_list_remote_branches(opts: GitListRemoteBranchesOptions) -> Res
     opts.repoUrl,
     opts.originPathOverride
   );
-  tokio::task::spawn_blocking(move || branches::list_remote_branches(opts))
+  tokio::task::spawn_blocking(move || {
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    branches::list_remote_branches(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// The repo's tags, newest first, with annotated tags peeled to their commits.
+#[napi]
+pub async fn git_list_tags(opts: GitListTagsOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<TagInfo>> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_list_tags repoFullName={:?} repoUrl={:?} originPathOverride={:?}",
+    opts.repoFullName,
+    opts.repoUrl,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    tags::list_tags(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Per-line blame for `filePath` at `ref`, without shelling out to git.
+#[napi]
+pub async fn git_blame(opts: GitBlameOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<BlameLine>> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_blame ref={} filePath={} lineRange={:?} repoFullName={:?} originPathOverride={:?}",
+    opts.refName,
+    opts.filePath,
+    opts.lineRange,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    blame::blame_file(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// One page of the commits reachable from `ref`, newest first.
+#[napi]
+pub async fn git_log(opts: GitLogOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<LogCommit>> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_log ref={} maxCount={:?} skip={:?} pathFilter={:?} author={:?} repoFullName={:?} originPathOverride={:?}",
+    opts.refName,
+    opts.maxCount,
+    opts.skip,
+    opts.pathFilter,
+    opts.author,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    log::log_commits(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// One commit's authorship, message, parents and per-file line counts.
+#[napi]
+pub async fn git_commit_info(opts: GitCommitInfoOptions, on_progress: Option<ProgressCallback>) -> Result<CommitDetails> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_commit_info sha={} repoFullName={:?} originPathOverride={:?}",
+    opts.sha,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    commit::commit_details(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// Whether `headRef` landed on `baseRef`, through which commit, and whether base contains it.
+#[napi]
+pub async fn git_is_landed(opts: GitIsLandedOptions, on_progress: Option<ProgressCallback>) -> Result<LandedInfo> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_is_landed baseRef={} headRef={} b0Ref={:?} repoFullName={:?} originPathOverride={:?}",
+    opts.baseRef,
+    opts.headRef,
+    opts.b0Ref,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    landed::is_landed(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// Apply a unified diff to a worktree, reporting per file whether it applied, merged or
+/// conflicted. Nothing is written unless every file applies.
+#[napi]
+pub async fn git_apply_patch(opts: GitApplyPatchOptions) -> Result<ApplyPatchResult> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_apply_patch worktreePath={} patchBytes={} threeWay={:?} checkOnly={:?}",
+    opts.worktreePath,
+    opts.patch.len(),
+    opts.threeWay,
+    opts.checkOnly
+  );
+  tokio::task::spawn_blocking(move || apply::apply_patch(opts))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Commits that changed `path`, newest first, following renames.
+#[napi]
+pub async fn git_file_history(opts: GitFileHistoryOptions, on_progress: Option<ProgressCallback>) -> Result<Vec<FileHistoryEntry>> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_file_history ref={} path={} maxCount={:?} repoFullName={:?} originPathOverride={:?}",
+    opts.refName,
+    opts.path,
+    opts.maxCount,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    file_history::file_history(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// Commits `head` has that `base` lacks and the reverse, and their merge base.
+#[napi]
+pub async fn git_ahead_behind(opts: GitAheadBehindOptions, on_progress: Option<ProgressCallback>) -> Result<AheadBehind> {
+  #[cfg(debug_assertions)]
+  println!(
+    "[cmux_native_git] git_ahead_behind base={} head={} repoFullName={:?} originPathOverride={:?}",
+    opts.base,
+    opts.head,
+    opts.repoFullName,
+    opts.originPathOverride
+  );
+  tokio::task::spawn_blocking(move || {
+    let _cancel = cancel::scope(opts.cancelId.clone());
+    let _progress = progress::scope(progress_sink(on_progress));
+    let _auth = repo::auth::scope(opts.authToken.as_deref());
+    ahead_behind::ahead_behind(opts)
+  })
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(git_error)
+}
+
+/// Cancel the calls started with `cancelId: id`; they fail with status `Cancelled`. Returns
+/// false when none of them is still running.
+#[napi]
+pub fn git_cancel(id: String) -> bool {
+  cancel::cancel(&id)
+}
+
+/// Drop all memoized textdiff line counts, in memory and on disk.
+#[napi]
+pub async fn git_clear_diff_memo() -> Result<()> {
+  tokio::task::spawn_blocking(|| diff::memo::with_memo(|m| m.clear()).unwrap_or(Ok(())))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Archive the cached clone for `slug` (`owner/repo`, a clone URL, or a cache slug) to
+/// `tarPath`. Returns the cache slug.
+#[napi]
+pub async fn git_cache_export(slug: String, tar_path: String) -> Result<String> {
+  tokio::task::spawn_blocking(move || repo::cache::export_repo(&slug, std::path::Path::new(&tar_path)))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Seed the git cache from an archive written by `git_cache_export`. Returns the cache slug.
+#[napi]
+pub async fn git_cache_import(tar_path: String) -> Result<String> {
+  tokio::task::spawn_blocking(move || repo::cache::import_repo(std::path::Path::new(&tar_path)))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Set where the git cache lives and how large it may grow, evicting anything the new limits
+/// exclude. Omitted fields keep their current value.
+#[napi]
+pub async fn git_cache_configure(opts: GitCacheConfig) -> Result<()> {
+  tokio::task::spawn_blocking(move || repo::cache::configure(&opts))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// The cached clones with their size on disk, most recently used first.
+#[napi]
+pub async fn git_cache_list() -> Result<Vec<CachedRepo>> {
+  tokio::task::spawn_blocking(repo::cache::list_repos)
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Delete the cached clone for `slug` (`owner/repo`, a clone URL, or a cache slug). Returns
+/// false when it was not cached.
+#[napi]
+pub async fn git_cache_evict(slug: String) -> Result<bool> {
+  tokio::task::spawn_blocking(move || repo::cache::evict_repo(&slug))
+    .await
+    .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
+    .map_err(|e| Error::from_reason(format!("{e:#}")))
+}
+
+/// Delete every cached clone. Returns how many were removed.
+#[napi]
+pub async fn git_cache_purge() -> Result<u32> {
+  tokio::task::spawn_blocking(repo::cache::purge_repos)
     .await
     .map_err(|e| Error::from_reason(format!("Join error: {e}")))?
     .map_err(|e| Error::from_reason(format!("{e:#}")))
@@ -56,3 +348,5 @@ pub async fn git_list_remote_branches(opts: GitListRemoteBranchesOptions) -> Res
 
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use gix::bstr::ByteSlice;
+use gix::{hash::ObjectId, Repository};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::blame::{commit_tree, entry_at_path};
+use crate::diff::refs::{collect_tree_blobs, is_binary, oid_from_rev_parse};
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, RepoOptions};
+use crate::repo::partial::{fetch_missing, no_fetch};
+use crate::types::{CommitFileStat, CommitStats, GitLogOptions, LogCommit};
+
+const DEFAULT_MAX_COUNT: usize = 50;
+
+fn blob_bytes(repo: &Repository, id: ObjectId) -> Option<Vec<u8>> {
+  let blob = repo.find_object(id).ok()?.try_into_blob().ok()?;
+  Some(blob.data.to_vec())
+}
+
+/// Each file that differs from the first parent (or from nothing, for a root commit), by path,
+/// with the lines added and removed. Binary files have no line counts.
+pub(crate) fn file_stats(repo: &Repository, tree: ObjectId, parent_tree: Option<ObjectId>) -> Result<Vec<CommitFileStat>> {
+  let mut new_map: HashMap<String, ObjectId> = HashMap::new();
+  let mut old_map: HashMap<String, ObjectId> = HashMap::new();
+  collect_tree_blobs(repo, tree, "", &mut new_map)?;
+  if let Some(parent_tree) = parent_tree {
+    collect_tree_blobs(repo, parent_tree, "", &mut old_map)?;
+  }
+  let mut paths: Vec<&String> = new_map.keys().chain(old_map.keys()).filter(|p| old_map.get(*p) != new_map.get(*p)).collect();
+  paths.sort();
+  paths.dedup();
+  fetch_missing(repo, paths.iter().flat_map(|p| old_map.get(*p).into_iter().chain(new_map.get(*p)).copied()))?;
+  let mut out = Vec::with_capacity(paths.len());
+  for path in paths {
+    let old_id = old_map.get(path).copied();
+    let new_id = new_map.get(path).copied();
+    let status = match (old_id, new_id) {
+      (None, _) => "added",
+      (_, None) => "deleted",
+      _ => "modified",
+    };
+    let mut stat = CommitFileStat { path: path.clone(), status: status.into(), ..Default::default() };
+    let old = old_id.and_then(|id| blob_bytes(repo, id)).unwrap_or_default();
+    let new = new_id.and_then(|id| blob_bytes(repo, id)).unwrap_or_default();
+    if is_binary(&old) || is_binary(&new) {
+      stat.binary = true;
+      out.push(stat);
+      continue;
+    }
+    let old = String::from_utf8_lossy(&old);
+    let new = String::from_utf8_lossy(&new);
+    let diff = TextDiff::from_lines(old.as_ref(), new.as_ref());
+    for change in diff.iter_all_changes() {
+      match change.tag() {
+        ChangeTag::Insert => stat.additions += 1,
+        ChangeTag::Delete => stat.deletions += 1,
+        ChangeTag::Equal => {}
+      }
+    }
+    out.push(stat);
+  }
+  Ok(out)
+}
+
+// Totals of `file_stats`.
+pub(crate) fn commit_stats(files: &[CommitFileStat]) -> CommitStats {
+  CommitStats {
+    filesChanged: files.len() as i32,
+    additions: files.iter().map(|f| f.additions).sum(),
+    deletions: files.iter().map(|f| f.deletions).sum(),
+  }
+}
+
+/// Commits reachable from `ref`, newest first by committer time, like `git log`.
+///
+/// `pathFilter` keeps commits whose entry at that path (file or directory) differs from every
+/// parent's; unlike `git log -- path`, side branches are still walked when a merge left the path
+/// unchanged. `author` matches a case-insensitive substring of `Name <email>`. `skip` and
+/// `maxCount` (default 50) apply after filtering.
+pub fn log_commits(opts: GitLogOptions) -> Result<Vec<LogCommit>> {
+  let offline = opts.offline.unwrap_or(false);
+  let _no_fetch = no_fetch(offline);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // A caller-provided path is the caller's to keep fresh, so it is never reported stale.
+  let stale = offline && opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms());
+
+  let repo = gix::open(&repo_path)?;
+  let head = oid_from_rev_parse(&repo, opts.refName.trim())?;
+  let max_count = opts.maxCount.map(|n| n.max(0) as usize).unwrap_or(DEFAULT_MAX_COUNT);
+  let mut to_skip = opts.skip.unwrap_or(0).max(0) as usize;
+  let path_filter = opts
+    .pathFilter
+    .as_deref()
+    .map(|p| p.trim_matches('/'))
+    .filter(|p| !p.is_empty());
+  let author_filter = opts
+    .author
+    .as_deref()
+    .map(|a| a.to_lowercase())
+    .filter(|a| !a.is_empty());
+  let include_stats = opts.includeStats.unwrap_or(false);
+
+  let mut out: Vec<LogCommit> = Vec::new();
+  let mut queue: BinaryHeap<(i64, ObjectId)> = BinaryHeap::new();
+  let mut seen: HashSet<ObjectId> = HashSet::new();
+  let head_time = repo.find_object(head)?.try_into_commit()?.committer()?.time.seconds;
+  queue.push((head_time, head));
+  seen.insert(head);
+  while out.len() < max_count {
+    crate::cancel::check()?;
+    let Some((_, id)) = queue.pop() else { break };
+    let commit = repo.find_object(id)?.try_into_commit()?;
+    let parents: Vec<ObjectId> = commit.parent_ids().map(|p| p.detach()).collect();
+    for parent in &parents {
+      if seen.insert(*parent) {
+        let time = repo.find_object(*parent)?.try_into_commit()?.committer()?.time.seconds;
+        queue.push((time, *parent));
+      }
+    }
+
+    let decoded = commit.decode()?;
+    let author = decoded.author;
+    if let Some(needle) = &author_filter {
+      let who = format!("{} <{}>", author.name.to_str_lossy(), author.email.to_str_lossy());
+      if !who.to_lowercase().contains(needle.as_str()) {
+        continue;
+      }
+    }
+    let tree = commit.tree_id()?.detach();
+    if let Some(path) = path_filter {
+      let entry = entry_at_path(&repo, tree, path)?;
+      let mut touched = true;
+      if parents.is_empty() {
+        touched = entry.is_some();
+      }
+      for parent in &parents {
+        if entry_at_path(&repo, commit_tree(&repo, *parent)?, path)? == entry {
+          touched = false;
+          break;
+        }
+      }
+      if !touched {
+        continue;
+      }
+    }
+    if to_skip > 0 {
+      to_skip -= 1;
+      continue;
+    }
+
+    let stats = if include_stats {
+      let parent_tree = match parents.first() {
+        Some(parent) => Some(commit_tree(&repo, *parent)?),
+        None => None,
+      };
+      Some(commit_stats(&file_stats(&repo, tree, parent_tree)?))
+    } else {
+      None
+    };
+    let committer = decoded.committer;
+    out.push(LogCommit {
+      sha: id.to_hex().to_string(),
+      parents: parents.iter().map(|p| p.to_hex().to_string()).collect(),
+      authorName: author.name.to_str_lossy().into_owned(),
+      authorEmail: author.email.to_str_lossy().into_owned(),
+      authorTime: author.time.seconds * 1000,
+      committerName: committer.name.to_str_lossy().into_owned(),
+      committerTime: committer.time.seconds * 1000,
+      summary: commit.message()?.summary().to_str_lossy().into_owned(),
+      message: decoded.message.to_str_lossy().trim_end().to_string(),
+      stats,
+      stale: stale.then_some(true),
+    });
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::commit_as;
+  use crate::util::run_git;
+  use std::fs;
+  use tempfile::tempdir;
+
+  fn opts(repo: &str) -> GitLogOptions {
+    GitLogOptions { originPathOverride: Some(repo.to_string()), refName: "HEAD".into(), ..Default::default() }
+  }
+
+  #[test]
+  fn pages_and_filters_history() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    fs::create_dir_all(tmp.path().join("docs")).unwrap();
+    fs::write(tmp.path().join("a.txt"), "one\n").unwrap();
+    let c1 = commit_as(repo, "Ada", Some("2024-01-01T00:00:00Z"), "add a");
+    fs::write(tmp.path().join("docs/readme.md"), "# hi\n").unwrap();
+    let c2 = commit_as(repo, "Bob", Some("2024-01-02T00:00:00Z"), "add docs\n\nwith a body");
+    fs::write(tmp.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+    let c3 = commit_as(repo, "Ada", Some("2024-01-03T00:00:00Z"), "grow a");
+    fs::write(tmp.path().join("docs/readme.md"), "# hello\n").unwrap();
+    let c4 = commit_as(repo, "Bob", Some("2024-01-04T00:00:00Z"), "reword docs");
+
+    let all = log_commits(opts(repo)).expect("log");
+    let shas: Vec<&str> = all.iter().map(|c| c.sha.as_str()).collect();
+    assert_eq!(shas, vec![c4.as_str(), c3.as_str(), c2.as_str(), c1.as_str()]);
+    assert_eq!(all[0].parents, vec![c3.clone()]);
+    assert!(all[3].parents.is_empty());
+    assert_eq!(all[2].summary, "add docs");
+    assert_eq!(all[2].message, "add docs\n\nwith a body");
+    assert_eq!(all[2].authorEmail, "bob@example.com");
+    assert_eq!(all[3].authorTime, 1_704_067_200_000);
+    assert!(all.iter().all(|c| c.stats.is_none() && c.stale.is_none()));
+
+    let page = log_commits(GitLogOptions { skip: Some(1), maxCount: Some(2), ..opts(repo) }).expect("page");
+    let shas: Vec<&str> = page.iter().map(|c| c.sha.as_str()).collect();
+    assert_eq!(shas, vec![c3.as_str(), c2.as_str()]);
+
+    let docs = log_commits(GitLogOptions { pathFilter: Some("docs".into()), ..opts(repo) }).expect("path");
+    let shas: Vec<&str> = docs.iter().map(|c| c.sha.as_str()).collect();
+    assert_eq!(shas, vec![c4.as_str(), c2.as_str()]);
+
+    let ada = log_commits(GitLogOptions { author: Some("ADA".into()), skip: Some(1), ..opts(repo) }).expect("author");
+    let shas: Vec<&str> = ada.iter().map(|c| c.sha.as_str()).collect();
+    assert_eq!(shas, vec![c1.as_str()]);
+
+    let stats = log_commits(GitLogOptions { includeStats: Some(true), maxCount: Some(2), ..opts(repo) }).expect("stats");
+    let grow = stats[1].stats.as_ref().expect("stats for c3");
+    assert_eq!((grow.filesChanged, grow.additions, grow.deletions), (1, 2, 0));
+    let reword = stats[0].stats.as_ref().expect("stats for c4");
+    assert_eq!((reword.filesChanged, reword.additions, reword.deletions), (1, 1, 1));
+  }
+}
@@ -0,0 +1,198 @@
+use anyhow::Result;
+use gix::{hash::ObjectId, Repository};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+const FROM_ONE: u8 = 1;
+const FROM_TWOS: u8 = 2;
+// Below a common ancestor already found, so not a best one.
+const STALE: u8 = 4;
+
+fn commit_time(repo: &Repository, id: ObjectId) -> Result<i64> {
+  Ok(repo.find_object(id)?.try_into_commit()?.committer()?.time.seconds)
+}
+
+fn parents(repo: &Repository, id: ObjectId) -> Result<Vec<ObjectId>> {
+  Ok(repo.find_object(id)?.try_into_commit()?.parent_ids().map(|p| p.detach()).collect())
+}
+
+// Common ancestors of `one` and any of `twos` that no other common ancestor found descends
+// from, walking newest first like git's `paint_down_to_common`. Clock skew can leave some that
+// are ancestors of others; see `remove_redundant`.
+fn paint_down_to_common(repo: &Repository, one: ObjectId, twos: &[ObjectId]) -> Result<Vec<ObjectId>> {
+  let mut marks: HashMap<ObjectId, u8> = HashMap::new();
+  *marks.entry(one).or_default() |= FROM_ONE;
+  for two in twos {
+    *marks.entry(*two).or_default() |= FROM_TWOS;
+  }
+  // Entries carry the marks they were queued with; `live` counts those not stale.
+  let mut queue: BinaryHeap<(i64, ObjectId, u8)> = BinaryHeap::new();
+  for (id, m) in &marks {
+    queue.push((commit_time(repo, *id)?, *id, *m));
+  }
+  let mut live = queue.len();
+  let mut found: Vec<ObjectId> = Vec::new();
+  while live > 0 {
+    crate::cancel::check()?;
+    let Some((_, id, queued)) = queue.pop() else { break };
+    if queued & STALE == 0 {
+      live -= 1;
+    }
+    let mut flags = marks[&id];
+    if flags == FROM_ONE | FROM_TWOS {
+      if !found.contains(&id) {
+        found.push(id);
+      }
+      // Its ancestors are common too, but not best.
+      flags |= STALE;
+    }
+    for parent in parents(repo, id)? {
+      let before = marks.get(&parent).copied().unwrap_or(0);
+      let after = before | flags;
+      if after == before {
+        continue;
+      }
+      marks.insert(parent, after);
+      queue.push((commit_time(repo, parent)?, parent, after));
+      if after & STALE == 0 {
+        live += 1;
+      }
+    }
+  }
+  // One found before a common descendant of it was walked was marked stale afterwards.
+  Ok(found.into_iter().filter(|id| marks[id] & STALE == 0).collect())
+}
+
+// Whether `ancestor` is reachable from `from`. Commits older than `ancestor` are not walked.
+fn reaches(repo: &Repository, from: ObjectId, ancestor: ObjectId, cutoff: i64) -> Result<bool> {
+  let mut seen: HashSet<ObjectId> = HashSet::from([from]);
+  let mut queue: BinaryHeap<(i64, ObjectId)> = BinaryHeap::from([(commit_time(repo, from)?, from)]);
+  while let Some((time, id)) = queue.pop() {
+    if id == ancestor {
+      return Ok(true);
+    }
+    if time < cutoff {
+      break;
+    }
+    for parent in parents(repo, id)? {
+      if seen.insert(parent) {
+        queue.push((commit_time(repo, parent)?, parent));
+      }
+    }
+  }
+  Ok(false)
+}
+
+// Drops every candidate that is an ancestor of another, like git's `reduce_heads`.
+fn remove_redundant(repo: &Repository, candidates: Vec<ObjectId>) -> Result<Vec<ObjectId>> {
+  if candidates.len() < 2 {
+    return Ok(candidates);
+  }
+  let mut keep = Vec::with_capacity(candidates.len());
+  for (i, c) in candidates.iter().enumerate() {
+    let cutoff = commit_time(repo, *c)?;
+    let mut redundant = false;
+    for (j, other) in candidates.iter().enumerate() {
+      if i != j && reaches(repo, *other, *c, cutoff)? {
+        redundant = true;
+        break;
+      }
+    }
+    if !redundant {
+      keep.push(*c);
+    }
+  }
+  Ok(keep)
+}
+
+/// Every best common ancestor of `refs`: common to all of them, and none an ancestor of
+/// another. With two refs this is `git merge-base --all`; with more it is
+/// `git merge-base --octopus --all` less the results that are ancestors of other results.
+/// Empty when they share no history.
+pub fn merge_base_many(repo: &Repository, refs: Vec<ObjectId>) -> Result<Vec<ObjectId>> {
+  let mut refs = refs.into_iter();
+  let Some(first) = refs.next() else { return Ok(Vec::new()) };
+  let mut bases = vec![first];
+  for next in refs {
+    let mut merged: Vec<ObjectId> = Vec::new();
+    for base in &bases {
+      for id in paint_down_to_common(repo, next, std::slice::from_ref(base))? {
+        if !merged.contains(&id) {
+          merged.push(id);
+        }
+      }
+    }
+    bases = remove_redundant(repo, merged)?;
+    if bases.is_empty() {
+      break;
+    }
+  }
+  Ok(bases)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{commit_file, git, rev};
+  use crate::util::run_git;
+  use tempfile::tempdir;
+
+  fn commit(repo: &str, message: &str) -> ObjectId {
+    oid(&commit_file(repo, message))
+  }
+
+  fn oid(hex: &str) -> ObjectId {
+    ObjectId::from_hex(hex.as_bytes()).unwrap()
+  }
+
+  fn via_git(repo: &str, args: &[&str]) -> Vec<ObjectId> {
+    let out = run_git(repo, &[&["merge-base", "--all"][..], args].concat()).unwrap_or_default();
+    let mut ids: Vec<ObjectId> = out.lines().map(|l| oid(l.trim())).collect();
+    ids.sort();
+    ids
+  }
+
+  fn sorted(mut ids: Vec<ObjectId>) -> Vec<ObjectId> {
+    ids.sort();
+    ids
+  }
+
+  #[test]
+  fn finds_every_best_common_ancestor() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    let root = commit(repo, "root");
+    run_git(repo, &["checkout", "-b", "x"]).unwrap();
+    let x = commit(repo, "x");
+    run_git(repo, &["checkout", "-b", "y", "main"]).unwrap();
+    let y = commit(repo, "y");
+    // A criss-cross: `a` and `b` each merge both `x` and `y`, so both are best.
+    run_git(repo, &["checkout", "-b", "a", "x"]).unwrap();
+    git(repo, &["merge", "--no-edit", "y"]);
+    commit(repo, "a");
+    run_git(repo, &["checkout", "-b", "b", "y"]).unwrap();
+    git(repo, &["merge", "--no-edit", "x"]);
+    commit(repo, "b");
+    let (a, b) = (oid(&rev(repo, "a")), oid(&rev(repo, "b")));
+    let repo_handle = gix::open(repo).unwrap();
+
+    let all = sorted(merge_base_many(&repo_handle, vec![a, b]).unwrap());
+    assert_eq!(all, sorted(vec![x, y]));
+    assert_eq!(all, via_git(repo, &["a", "b"]));
+
+    // Across three refs only what all of them share counts.
+    run_git(repo, &["checkout", "-b", "c", "x"]).unwrap();
+    let c = commit(repo, "c");
+    assert_eq!(merge_base_many(&repo_handle, vec![a, b, c]).unwrap(), vec![x]);
+    // git also lists `root`, the merge base of `c` and `y`, though it is an ancestor of `x`.
+    assert_eq!(via_git(repo, &["--octopus", "a", "b", "c"]), sorted(vec![x, root]));
+    assert_eq!(merge_base_many(&repo_handle, vec![a, y, root]).unwrap(), vec![root]);
+    assert_eq!(merge_base_many(&repo_handle, vec![a]).unwrap(), vec![a]);
+    assert!(merge_base_many(&repo_handle, Vec::new()).unwrap().is_empty());
+
+    // Unrelated histories share nothing.
+    run_git(repo, &["checkout", "--orphan", "lonely"]).unwrap();
+    let lonely = commit(repo, "lonely");
+    assert!(merge_base_many(&repo_handle, vec![a, lonely]).unwrap().is_empty());
+  }
+}
@@ -16,6 +16,9 @@ pub fn merge_base(cwd: &str, repo: &gix::Repository, a: ObjectId, b: ObjectId, s
 
 pub mod git;
 pub mod bfs;
+pub mod many;
+
+pub use many::merge_base_many;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use crate::types::GitProgress;
+
+pub type Sink = Arc<dyn Fn(GitProgress) + Send + Sync>;
+
+// Phases end in one of these words; anything else on stderr is not progress.
+const PHASE_NOUNS: &[&str] = &["objects", "deltas", "files", "connectivity", "content"];
+
+thread_local! {
+  static CURRENT: RefCell<Option<Sink>> = const { RefCell::new(None) };
+}
+
+pub fn current() -> Option<Sink> {
+  CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Sends progress from the clones and fetches on this thread to `sink` until dropped. Without a
+/// sink the scope does nothing.
+pub struct Scope {
+  active: bool,
+  previous: Option<Sink>,
+}
+
+pub fn scope(sink: Option<Sink>) -> Scope {
+  let previous = current();
+  let active = sink.is_some();
+  if active {
+    CURRENT.with(|c| *c.borrow_mut() = sink);
+  }
+  Scope { active, previous }
+}
+
+impl Drop for Scope {
+  fn drop(&mut self) {
+    if self.active {
+      CURRENT.with(|c| *c.borrow_mut() = self.previous.take());
+    }
+  }
+}
+
+fn parse_size(s: &str) -> Option<i64> {
+  let (n, unit) = s.trim().split_once(' ')?;
+  let scale = match unit.trim() {
+    "bytes" | "byte" => 1.0,
+    "KiB" => 1024.0,
+    "MiB" => 1024.0 * 1024.0,
+    "GiB" => 1024.0 * 1024.0 * 1024.0,
+    _ => return None,
+  };
+  Some((n.parse::<f64>().ok()? * scale).round() as i64)
+}
+
+/// Parses one line of `git clone --progress` / `git fetch --progress` stderr, e.g.
+/// `Receiving objects:  45% (450/1000), 1.20 MiB | 2.00 MiB/s` or
+/// `remote: Enumerating objects: 1234, done.`
+pub fn parse_line(line: &str) -> Option<GitProgress> {
+  let line = line.trim();
+  let line = line.strip_prefix("remote:").map(str::trim_start).unwrap_or(line);
+  let (phase, rest) = line.split_once(':')?;
+  if !phase.rsplit(' ').next().is_some_and(|noun| PHASE_NOUNS.contains(&noun)) {
+    return None;
+  }
+  let rest = rest.trim();
+  if !rest.starts_with(|c: char| c.is_ascii_digit()) {
+    return None;
+  }
+  let mut progress = GitProgress { phase: phase.to_string(), ..Default::default() };
+  match rest.split_once('%') {
+    Some((percent, after)) => {
+      progress.percent = percent.trim().parse().ok();
+      if let Some((counts, after)) = after.trim_start().strip_prefix('(').and_then(|s| s.split_once(')')) {
+        if let Some((done, total)) = counts.split_once('/') {
+          progress.objects = done.trim().parse().ok();
+          progress.totalObjects = total.trim().parse().ok();
+        }
+        if let Some(size) = after.strip_prefix(',') {
+          progress.receivedBytes = parse_size(size.split('|').next().unwrap_or(""));
+        }
+      }
+    }
+    None => {
+      progress.objects = rest.split(|c: char| !c.is_ascii_digit()).next().and_then(|n| n.parse().ok());
+    }
+  }
+  Some(progress)
+}
+
+/// Turns stderr lines into progress reports for `sink`. git redraws a meter much more often
+/// than its numbers change, so repeats are dropped.
+pub fn stderr_reporter(sink: Sink) -> Box<dyn FnMut(&str) + Send> {
+  let mut last: Option<(String, Option<i32>, Option<i64>)> = None;
+  Box::new(move |line| {
+    let Some(progress) = parse_line(line) else { return };
+    let key = (progress.phase.clone(), progress.percent, progress.objects);
+    if last.as_ref() != Some(&key) {
+      last = Some(key);
+      sink(progress);
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::run_git;
+  use std::sync::Mutex;
+  use tempfile::tempdir;
+
+  #[test]
+  fn parses_git_progress_lines() {
+    let p = parse_line("Receiving objects:  45% (450/1000), 1.20 MiB | 2.00 MiB/s").expect("receiving");
+    assert_eq!(p.phase, "Receiving objects");
+    assert_eq!((p.percent, p.objects, p.totalObjects), (Some(45), Some(450), Some(1000)));
+    assert_eq!(p.receivedBytes, Some(1_258_291));
+
+    let p = parse_line("remote: Compressing objects: 100% (5/5), done.").expect("compressing");
+    assert_eq!((p.phase.as_str(), p.percent, p.totalObjects, p.receivedBytes), ("Compressing objects", Some(100), Some(5), None));
+
+    let p = parse_line("remote: Enumerating objects: 1234, done.").expect("enumerating");
+    assert_eq!((p.percent, p.objects), (None, Some(1234)));
+
+    assert!(parse_line("Cloning into 'repo'...").is_none());
+    assert!(parse_line("fatal: repository 'x' not found").is_none());
+    assert!(parse_line("warning: 3 objects remain").is_none());
+  }
+
+  #[test]
+  fn reports_clone_progress_to_the_scope_sink() {
+    let tmp = tempdir().expect("tempdir");
+    let src = tmp.path().join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    let src = src.to_str().unwrap();
+    run_git(src, &["init", "-b", "main"]).unwrap();
+    std::fs::write(tmp.path().join("src/a.txt"), "a\n").unwrap();
+    run_git(src, &["add", "-A"]).unwrap();
+    run_git(src, &["-c", "user.name=Test", "-c", "user.email=test@example.com", "commit", "-m", "a"]).unwrap();
+
+    let seen: Arc<Mutex<Vec<GitProgress>>> = Arc::default();
+    let sink = seen.clone();
+    {
+      let _progress = scope(Some(Arc::new(move |p: GitProgress| sink.lock().unwrap().push(p))));
+      // `file://` forces the pack transport, which reports progress; a plain path would not.
+      let url = format!("file://{src}");
+      run_git(tmp.path().to_str().unwrap(), &["clone", &url, "dst"]).expect("clone");
+    }
+    let seen = seen.lock().unwrap();
+    assert!(seen.iter().any(|p| p.phase == "Receiving objects" && p.percent == Some(100)), "{seen:?}");
+    assert!(current().is_none());
+  }
+}
@@ -0,0 +1,77 @@
+use std::cell::RefCell;
+use std::process::Command;
+use std::sync::Arc;
+
+// The child reads the token from its environment, so it never shows up in argv or git config.
+const TOKEN_ENV: &str = "CMUX_GIT_AUTH_TOKEN";
+// Answers HTTPS credential requests with the token, the way GitHub expects an app or personal
+// access token. The empty helper before it drops any helpers configured by the user.
+const TOKEN_HELPER: &str = "!f() { test \"$1\" = get && echo username=x-access-token && echo \"password=$CMUX_GIT_AUTH_TOKEN\"; }; f";
+
+thread_local! {
+  static CURRENT: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+pub fn current() -> Option<Arc<str>> {
+  CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Authenticates the HTTPS clones and fetches on this thread with `token` until dropped.
+/// Without a token the scope does nothing.
+pub struct Scope {
+  active: bool,
+  previous: Option<Arc<str>>,
+}
+
+pub fn scope(token: Option<&str>) -> Scope {
+  let token = token.map(str::trim).filter(|t| !t.is_empty());
+  let previous = current();
+  let active = token.is_some();
+  if let Some(token) = token {
+    CURRENT.with(|c| *c.borrow_mut() = Some(token.into()));
+  }
+  Scope { active, previous }
+}
+
+impl Drop for Scope {
+  fn drop(&mut self) {
+    if self.active {
+      CURRENT.with(|c| *c.borrow_mut() = self.previous.take());
+    }
+  }
+}
+
+/// Sets up `cmd` to run git non-interactively with the credentials in scope: a missing
+/// credential fails the command instead of waiting on a prompt nobody will answer, and SSH
+/// URLs authenticate through the user's ssh-agent as usual.
+pub fn apply(cmd: &mut Command) {
+  cmd.env("GIT_TERMINAL_PROMPT", "0");
+  let Some(token) = current() else { return };
+  cmd
+    .env(TOKEN_ENV, &*token)
+    .env("GIT_CONFIG_COUNT", "2")
+    .env("GIT_CONFIG_KEY_0", "credential.helper")
+    .env("GIT_CONFIG_VALUE_0", "")
+    .env("GIT_CONFIG_KEY_1", "credential.helper")
+    .env("GIT_CONFIG_VALUE_1", TOKEN_HELPER);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::run_git;
+
+  #[test]
+  fn token_scope_installs_credential_helper() {
+    let helpers = |cwd: &str| run_git(cwd, &["config", "--get-all", "credential.helper"]).unwrap_or_default();
+    assert!(!helpers(".").contains(TOKEN_ENV));
+    {
+      let _auth = scope(Some(" ghs_example "));
+      assert_eq!(current().as_deref(), Some("ghs_example"));
+      assert!(helpers(".").contains(TOKEN_ENV));
+    }
+    assert!(current().is_none());
+    let _blank = scope(Some(""));
+    assert!(current().is_none());
+  }
+}
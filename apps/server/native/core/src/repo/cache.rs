@@ -1,22 +1,62 @@
 use anyhow::{anyhow, Result};
 use dirs_next::cache_dir;
-use std::{collections::HashMap, fs, path::PathBuf};
-use std::sync::{Mutex, OnceLock};
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::Duration;
 
+use super::partial::partial_clone_default;
+use crate::types::{CachedRepo, GitCacheConfig};
 use crate::util::run_git;
 
-const MAX_CACHE_REPOS: usize = 20;
+const DEFAULT_MAX_CACHE_REPOS: usize = 20;
+const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
 
 // Default SWR window for git fetches. Lower means fetch more often.
 pub const DEFAULT_FETCH_WINDOW_MS: u128 = 5_000; // 5s
+// How often the background refresher fetches hot repos, i.e. those a call used lately.
+pub const DEFAULT_REFRESH_INTERVAL_MS: u128 = 60_000;
+const HOT_FOR_MS: u128 = 15 * 60 * 1000;
 
 pub fn fetch_window_ms() -> u128 {
+  if let Some(ms) = cache_settings().fetch_window_ms { return ms; }
   if let Ok(v) = std::env::var("CMUX_GIT_FETCH_WINDOW_MS") {
     if let Ok(parsed) = v.parse::<u128>() { return parsed; }
   }
   DEFAULT_FETCH_WINDOW_MS
 }
 
+// 0 turns the refresher off.
+fn refresh_interval_ms() -> u128 {
+  if let Some(ms) = cache_settings().refresh_interval_ms { return ms; }
+  if let Ok(v) = std::env::var("CMUX_GIT_REFRESH_INTERVAL_MS") {
+    if let Ok(parsed) = v.parse::<u128>() { return parsed; }
+  }
+  DEFAULT_REFRESH_INTERVAL_MS
+}
+
+/// How a call wants its cached clone made and kept fresh.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepoOptions {
+  /// Clone blobless; see `ensure_repo`.
+  pub partial: bool,
+  /// SWR window for this call instead of the configured one.
+  pub fetch_window_ms: Option<u128>,
+}
+
+impl RepoOptions {
+  /// From a call's `partialClone` and `fetchWindowMs` options.
+  pub fn from_call(partial_clone: Option<bool>, fetch_window_ms: Option<i64>) -> Self {
+    Self {
+      partial: partial_clone.unwrap_or_else(partial_clone_default),
+      fetch_window_ms: fetch_window_ms.map(|ms| ms.max(0) as u128),
+    }
+  }
+
+  pub fn window_ms(&self) -> u128 {
+    self.fetch_window_ms.unwrap_or_else(fetch_window_ms)
+  }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct CacheIndexEntry {
   slug: String,
@@ -24,6 +64,14 @@ struct CacheIndexEntry {
   last_access_ms: u128,
   #[serde(default)]
   last_fetch_ms: Option<u128>,
+  #[serde(default)]
+  last_fetch_duration_ms: Option<u128>,
+  /// Why the last fetch failed; cleared by the next one that succeeds.
+  #[serde(default)]
+  last_fetch_error: Option<String>,
+  /// Bytes on disk as of the last clone or fetch, so eviction need not walk every clone.
+  #[serde(default)]
+  size_bytes: Option<u64>,
 }
 
 #[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -31,54 +79,320 @@ struct CacheIndex {
   entries: Vec<CacheIndexEntry>,
 }
 
-fn default_cache_root() -> PathBuf {
+/// Where the cache lives, the limits it is held to after each clone, and how often it fetches;
+/// set through `configure`.
+#[derive(Debug, Clone)]
+struct CacheSettings {
+  root: Option<PathBuf>,
+  max_repos: usize,
+  max_bytes: Option<u64>,
+  ttl_ms: Option<u128>,
+  fetch_window_ms: Option<u128>,
+  refresh_interval_ms: Option<u128>,
+}
+
+impl Default for CacheSettings {
+  fn default() -> Self {
+    Self {
+      root: None,
+      max_repos: DEFAULT_MAX_CACHE_REPOS,
+      max_bytes: None,
+      ttl_ms: None,
+      fetch_window_ms: None,
+      refresh_interval_ms: None,
+    }
+  }
+}
+
+static CACHE_SETTINGS: OnceLock<Mutex<CacheSettings>> = OnceLock::new();
+
+fn cache_settings() -> CacheSettings {
+  CACHE_SETTINGS.get_or_init(|| Mutex::new(CacheSettings::default())).lock().map(|l| l.clone()).unwrap_or_default()
+}
+
+/// Updates the cache settings given in `opts` and evicts whatever the new limits exclude.
+/// Omitted fields keep their value; an empty `root` restores the default location, and a zero
+/// `maxBytes` or `ttlDays` lifts that limit.
+pub fn configure(opts: &GitCacheConfig) -> Result<()> {
+  {
+    let mut limits = CACHE_SETTINGS
+      .get_or_init(|| Mutex::new(CacheSettings::default()))
+      .lock()
+      .map_err(|_| anyhow!("cache settings lock poisoned"))?;
+    if let Some(root) = &opts.root {
+      let root = root.trim();
+      limits.root = (!root.is_empty()).then(|| PathBuf::from(root));
+    }
+    if let Some(n) = opts.maxRepos {
+      limits.max_repos = n.max(1) as usize;
+    }
+    if let Some(bytes) = opts.maxBytes {
+      limits.max_bytes = (bytes > 0).then_some(bytes as u64);
+    }
+    if let Some(days) = opts.ttlDays {
+      limits.ttl_ms = (days > 0.0).then(|| (days * DAY_MS) as u128);
+    }
+    if let Some(ms) = opts.fetchWindowMs {
+      limits.fetch_window_ms = Some(ms.max(0) as u128);
+    }
+    if let Some(ms) = opts.refreshIntervalMs {
+      limits.refresh_interval_ms = Some(ms.max(0) as u128);
+    }
+  }
+  let root = default_cache_root();
+  if root.exists() {
+    enforce_cache_limit(&root)?;
+  }
+  Ok(())
+}
+
+pub(crate) fn default_cache_root() -> PathBuf {
+  if let Some(root) = cache_settings().root { return root; }
   if let Ok(dir) = std::env::var("CMUX_RUST_GIT_CACHE") { return PathBuf::from(dir); }
   if let Some(mut d) = cache_dir() { d.push("cmux-git-cache"); return d; }
   std::env::temp_dir().join("cmux-git-cache")
 }
 
+// Host (lowercased, without user or port) and path segments of a clone URL. Local paths and
+// bare `owner/repo` names have no host.
+fn split_url(url: &str) -> (Option<String>, Vec<&str>) {
+  let url = url.trim().trim_end_matches('/');
+  let url = url.strip_suffix(".git").unwrap_or(url);
+  let (authority, path) = match url.split_once("://") {
+    Some(("file", rest)) => (None, rest),
+    Some((_, rest)) => match rest.split_once('/') {
+      Some((authority, path)) => (Some(authority), path),
+      None => (Some(rest), ""),
+    },
+    // scp-like `[user@]host:path`; a single letter before the colon is a Windows drive.
+    None => match url.split_once(':') {
+      Some((authority, path)) if authority.len() > 1 && !authority.contains(['/', '\\']) => (Some(authority), path),
+      _ => (None, url),
+    },
+  };
+  let host = authority.map(|a| {
+    let host = a.rsplit_once('@').map_or(a, |(_, h)| h);
+    let host = host.split_once(':').map_or(host, |(h, _)| h).to_ascii_lowercase();
+    if host == "ssh.dev.azure.com" { "dev.azure.com".to_string() } else { host }
+  });
+  let mut segments: Vec<&str> = path.split('/').filter(|s| !matches!(*s, "" | "." | ".." | "_git")).collect();
+  // Azure DevOps SSH paths start with a protocol version.
+  if host.as_deref() == Some("dev.azure.com") && segments.first() == Some(&"v3") {
+    segments.remove(0);
+  }
+  (host, segments)
+}
+
+// GitHub clones keep their historical `owner__repo` slugs; other hosts lead with the host so
+// `acme/widgets` on GitHub and on GitLab get separate cache entries. SSH and HTTPS URLs of the
+// same repo share one.
 fn slug_from_url(url: &str) -> String {
-  let clean = url.trim_end_matches(".git");
-  let name = clean.split('/').rev().take(2).collect::<Vec<_>>();
-  if name.len() == 2 { format!("{}__{}", name[1], name[0]) } else { clean.replace(['/', ':', '@', '\\'], "_") }
+  let (host, segments) = split_url(url);
+  let slug = match host.as_deref() {
+    None | Some("github.com") => segments[segments.len().saturating_sub(2)..].join("__"),
+    Some(host) => std::iter::once(host).chain(segments).collect::<Vec<_>>().join("__"),
+  };
+  let slug = if slug.is_empty() { url.trim().trim_matches(['/', '.']) } else { &slug };
+  slug.replace(['/', ':', '@', '\\'], "_")
+}
+
+// Cached clones are bare mirrors: diffs read trees and blobs from the object store and never
+// need a checkout, and every ref on the remote (`refs/heads/*`, `refs/tags/*`, `refs/pull/*`, ...)
+// is kept under its own name. Origin's branches are therefore `refs/heads/*` and its default
+// branch is HEAD; ref lookups map callers' `origin/<branch>` onto them (see `is_mirror`).
+fn is_cached_clone(path: &Path) -> bool {
+  path.join("HEAD").is_file() && path.join("objects").is_dir()
+}
+
+/// Whether `repo` is a mirror, i.e. keeps origin's branches as `refs/heads/*`.
+pub fn is_mirror(repo: &gix::Repository) -> bool {
+  repo.config_snapshot().boolean("remote.origin.mirror").unwrap_or(false)
 }
 
-pub fn ensure_repo(url: &str) -> Result<PathBuf> {
+/// Turns a cached clone made before clones were bare mirrors into one in place: a worktree
+/// clone drops its worktree and index, and `refs/remotes/origin/*` become `refs/heads/*` with
+/// HEAD at origin's default branch. Does nothing for a mirror.
+fn migrate_cached_clone(root: &Path, path: &Path) -> Result<()> {
+  let git_dir = path.join(".git");
+  if git_dir.join("HEAD").is_file() {
+    let staging = root.join(format!(".migrate-{}-{}", std::process::id(), now_ms()));
+    fs::rename(&git_dir, &staging)?;
+    let moved = fs::remove_dir_all(path).and_then(|_| fs::rename(&staging, path));
+    if let Err(e) = moved {
+      // The caller drops `path` and clones again; don't leave the refs behind as well.
+      let _ = fs::remove_dir_all(&staging);
+      return Err(e.into());
+    }
+    let _ = fs::remove_file(path.join("index"));
+    run_git(path.to_string_lossy().as_ref(), &["config", "core.bare", "true"])?;
+  }
+  if !is_cached_clone(path) {
+    return Ok(());
+  }
+  let cwd = path.to_string_lossy();
+  let mirrored = run_git(&cwd, &["config", "--bool", "remote.origin.mirror"]).is_ok_and(|v| v.trim() == "true");
+  if mirrored || run_git(&cwd, &["config", "remote.origin.url"]).is_err() {
+    return Ok(());
+  }
+  if let Ok(target) = run_git(&cwd, &["symbolic-ref", "--quiet", "refs/remotes/origin/HEAD"]) {
+    if let Some(branch) = target.trim().strip_prefix("refs/remotes/origin/") {
+      run_git(&cwd, &["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])?;
+    }
+    run_git(&cwd, &["symbolic-ref", "--delete", "refs/remotes/origin/HEAD"])?;
+  }
+  let tracking = run_git(&cwd, &["for-each-ref", "--format=%(refname:lstrip=2)", "refs/remotes/origin/"])?;
+  if !tracking.trim().is_empty() {
+    // Origin's branches replace the local ones in one local fetch, then the tracking refs go.
+    run_git(&cwd, &["fetch", "--quiet", "--prune", "--no-tags", ".", "+refs/remotes/origin/*:refs/heads/*"])?;
+    let mut args = vec!["branch", "--quiet", "-r", "-D"];
+    args.extend(tracking.lines());
+    run_git(&cwd, &args)?;
+  }
+  run_git(&cwd, &["config", "--replace-all", "remote.origin.fetch", "+refs/*:refs/*"])?;
+  run_git(&cwd, &["config", "remote.origin.mirror", "true"])?;
+  Ok(())
+}
+
+// Clones `url` as a bare mirror into the empty directory `path`.
+fn clone_mirror(path: &Path, url: &str, partial: bool) -> Result<()> {
+  let mut args = vec!["clone", "--mirror"];
+  if partial {
+    // Recorded as the promisor filter, so every later fetch leaves blobs out too.
+    args.push("--filter=blob:none");
+  }
+  args.extend([url, "."]);
+  run_git(path.to_string_lossy().as_ref(), &args)?;
+  Ok(())
+}
+
+/// Path of the cached clone for `url`, cloning it first if needed and otherwise fetching
+/// within the SWR window. A new clone is blobless when `opts.partial` is set; an existing clone
+/// keeps the kind it was made as.
+pub fn ensure_repo(url: &str, opts: RepoOptions) -> Result<PathBuf> {
   let root = default_cache_root();
   fs::create_dir_all(&root)?;
   let path = root.join(slug_from_url(url));
-  let git_dir = path.join(".git");
-  let head = git_dir.join("HEAD");
-  if path.exists() && (!git_dir.exists() || !head.exists()) {
+  if migrate_cached_clone(&root, &path).is_err() || (path.exists() && !is_cached_clone(&path)) {
     let _ = fs::remove_dir_all(&path);
   }
   if !path.exists() {
     fs::create_dir_all(&path)?;
-    run_git(
-      root.to_string_lossy().as_ref(),
-      &["clone", "--no-single-branch", url, path.file_name().unwrap().to_str().unwrap()]
-    )?;
+    // A failed or cancelled clone must not be mistaken for a cached one next time.
+    if let Err(e) = clone_mirror(&path, url, opts.partial) {
+      let _ = fs::remove_dir_all(&path);
+      return Err(e);
+    }
     let _ = update_cache_index_with(&root, &path, Some(now_ms()));
+    let _ = record_size(&root, &path);
   } else {
-    let _ = swr_fetch_origin_all_path_bool(&path, fetch_window_ms());
+    let _ = swr_fetch_origin_all_path_bool(&path, opts.window_ms());
   }
-  let shallow = path.join(".git").join("shallow");
-  if shallow.exists() {
+  if path.join("shallow").exists() {
     let _ = run_git(path.to_string_lossy().as_ref(), &["fetch", "--unshallow", "--tags"]);
   }
 
   update_cache_index(&root, &path)?;
   enforce_cache_limit(&root)?;
+  mark_hot(&root, &path);
+  Ok(path)
+}
+
+/// Path of the cached clone for `url`, without cloning or fetching. Used by `offline` callers.
+pub fn cached_repo(url: &str) -> Result<PathBuf> {
+  let root = default_cache_root();
+  let path = root.join(slug_from_url(url));
+  let _ = migrate_cached_clone(&root, &path);
+  if !is_cached_clone(&path) {
+    return Err(anyhow!("offline: no cached clone for {} (clone/fetch disabled)", url));
+  }
+  let _ = update_cache_index(&root, &path);
   Ok(path)
 }
 
-pub fn resolve_repo_url(repo_full_name: Option<&str>, repo_url: Option<&str>) -> Result<String> {
+/// True when `path` has not been fetched within `window_ms`, i.e. a non-offline call would
+/// have fetched synchronously before answering.
+pub fn is_stale(path: &Path, window_ms: u128) -> bool {
+  let last_fetch = get_cache_last_fetch(&default_cache_root(), path).or_else(|| get_map_last_fetch(path));
+  match last_fetch {
+    Some(t) => now_ms().saturating_sub(t) > window_ms,
+    None => true,
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Provider {
+  GitHub,
+  GitLab,
+  Bitbucket,
+  AzureDevOps,
+}
+
+impl Provider {
+  fn default_base_url(self) -> &'static str {
+    match self {
+      Provider::GitHub => "https://github.com",
+      Provider::GitLab => "https://gitlab.com",
+      Provider::Bitbucket => "https://bitbucket.org",
+      Provider::AzureDevOps => "https://dev.azure.com",
+    }
+  }
+}
+
+// Splits a `gitlab:group/project` style prefix off a full name; None without a known prefix.
+fn split_provider(full_name: &str) -> Option<(Provider, &str)> {
+  let (prefix, rest) = full_name.split_once(':')?;
+  let provider = match prefix.to_ascii_lowercase().as_str() {
+    "github" => Provider::GitHub,
+    "gitlab" => Provider::GitLab,
+    "bitbucket" => Provider::Bitbucket,
+    "azure" | "azure-devops" => Provider::AzureDevOps,
+    _ => return None,
+  };
+  Some((provider, rest))
+}
+
+/// `repoUrl` as given, or else the clone URL for `repoFullName`.
+///
+/// A full name is `owner/repo` on GitHub unless prefixed with its provider: `gitlab:group/project`
+/// (subgroups allowed), `bitbucket:workspace/repo` or `azure:organization/project/repo`.
+/// `base_url` points the provider at a self-hosted instance, e.g. `https://gitlab.example.com`.
+/// URLs are HTTPS, or SSH (authenticated through the user's ssh-agent) when `ssh` is set.
+pub fn resolve_repo_url(repo_full_name: Option<&str>, repo_url: Option<&str>, ssh: bool, base_url: Option<&str>) -> Result<String> {
   if let Some(u) = repo_url { return Ok(u.to_string()); }
-  if let Some(full) = repo_full_name { return Ok(format!("https://github.com/{}.git", full)); }
-  Err(anyhow!("repoUrl or repoFullName required"))
+  let Some(full) = repo_full_name else { return Err(anyhow!("repoUrl or repoFullName required")) };
+  let (provider, name) = split_provider(full).unwrap_or((Provider::GitHub, full));
+  let name = name.trim_matches('/');
+  let segments = name.split('/').filter(|s| !s.is_empty()).count();
+  let valid = match provider {
+    Provider::GitLab => segments >= 2,
+    Provider::AzureDevOps => segments == 3,
+    Provider::GitHub | Provider::Bitbucket => segments == 2,
+  };
+  if !valid {
+    return Err(anyhow!("invalid repoFullName {:?}", full));
+  }
+  let base = base_url.map(|b| b.trim().trim_end_matches('/')).filter(|b| !b.is_empty()).unwrap_or(provider.default_base_url());
+  if ssh {
+    if provider == Provider::AzureDevOps {
+      if base_url.is_some() {
+        return Err(anyhow!("SSH to a self-hosted Azure DevOps server needs repoUrl"));
+      }
+      return Ok(format!("git@ssh.dev.azure.com:v3/{}", name));
+    }
+    let (host, _) = split_url(base);
+    let host = host.ok_or_else(|| anyhow!("invalid providerBaseUrl {:?}", base))?;
+    return Ok(format!("git@{}:{}.git", host, name));
+  }
+  Ok(match provider {
+    Provider::AzureDevOps => {
+      let (org_project, repo) = name.rsplit_once('/').unwrap_or(("", name));
+      format!("{}/{}/_git/{}", base, org_project, repo)
+    }
+    _ => format!("{}/{}.git", base, name),
+  })
 }
 
-fn load_index(root: &PathBuf) -> CacheIndex {
+fn load_index(root: &Path) -> CacheIndex {
   let idx_path = root.join("cache-index.json");
   if let Ok(data) = fs::read(&idx_path) {
     if let Ok(idx) = serde_json::from_slice::<CacheIndex>(&data) {
@@ -88,15 +402,37 @@ fn load_index(root: &PathBuf) -> CacheIndex {
   CacheIndex::default()
 }
 
-fn save_index(root: &PathBuf, idx: &CacheIndex) -> Result<()> {
+// Written to a temp file and renamed over the index, so readers never see a partial one.
+fn save_index(root: &Path, idx: &CacheIndex) -> Result<()> {
   let idx_path = root.join("cache-index.json");
+  let tmp_path = root.join(format!("cache-index.json.{}.tmp", std::process::id()));
   let data = serde_json::to_vec_pretty(idx)?;
-  fs::write(idx_path, data)?;
+  fs::write(&tmp_path, data)?;
+  if let Err(e) = fs::rename(&tmp_path, &idx_path) {
+    let _ = fs::remove_file(&tmp_path);
+    return Err(e.into());
+  }
   Ok(())
 }
 
-fn update_cache_index(root: &PathBuf, repo_path: &PathBuf) -> Result<()> {
+// Held across every load-modify-save of the index: calls and the background fetches they
+// start update it concurrently.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+// Loads the index, lets `f` change it, and saves it, with no other update in between.
+fn update_index<T>(root: &Path, f: impl FnOnce(&mut CacheIndex) -> T) -> Result<T> {
+  let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
   let mut idx = load_index(root);
+  let out = f(&mut idx);
+  save_index(root, &idx)?;
+  Ok(out)
+}
+
+fn update_cache_index(root: &Path, repo_path: &Path) -> Result<()> {
+  update_index(root, |idx| touch_index_entry(idx, repo_path))
+}
+
+fn touch_index_entry(idx: &mut CacheIndex, repo_path: &Path) {
   let slug = repo_path
     .file_name()
     .and_then(|s| s.to_str())
@@ -116,12 +452,13 @@ fn update_cache_index(root: &PathBuf, repo_path: &PathBuf) -> Result<()> {
       path: repo_path.to_string_lossy().to_string(),
       last_access_ms: now,
       last_fetch_ms: None,
+      last_fetch_duration_ms: None,
+      last_fetch_error: None,
+      size_bytes: None,
     });
   }
-  idx.entries.sort_by(|a, b| b.last_access_ms.cmp(&a.last_access_ms));
+  idx.entries.sort_by_key(|e| std::cmp::Reverse(e.last_access_ms));
   idx.entries.dedup_by(|a, b| a.slug == b.slug);
-  save_index(root, &idx)?;
-  Ok(())
 }
 
 fn now_ms() -> u128 {
@@ -131,8 +468,11 @@ fn now_ms() -> u128 {
     .as_millis()
 }
 
-fn update_cache_index_with(root: &PathBuf, repo_path: &PathBuf, last_fetch_ms: Option<u128>) -> Result<()> {
-  let mut idx = load_index(root);
+fn update_cache_index_with(root: &Path, repo_path: &Path, last_fetch_ms: Option<u128>) -> Result<()> {
+  update_index(root, |idx| touch_index_entry_with(idx, repo_path, last_fetch_ms))
+}
+
+fn touch_index_entry_with(idx: &mut CacheIndex, repo_path: &Path, last_fetch_ms: Option<u128>) {
   let pstr = repo_path.to_string_lossy().to_string();
   let now = now_ms();
   if let Some(e) = idx.entries.iter_mut().find(|e| e.path == pstr) {
@@ -149,15 +489,16 @@ fn update_cache_index_with(root: &PathBuf, repo_path: &PathBuf, last_fetch_ms: O
       path: pstr,
       last_access_ms: now,
       last_fetch_ms,
+      last_fetch_duration_ms: None,
+      last_fetch_error: None,
+      size_bytes: None,
     });
   }
-  idx.entries.sort_by(|a, b| b.last_access_ms.cmp(&a.last_access_ms));
+  idx.entries.sort_by_key(|e| std::cmp::Reverse(e.last_access_ms));
   idx.entries.dedup_by(|a, b| a.slug == b.slug);
-  save_index(root, &idx)?;
-  Ok(())
 }
 
-fn get_cache_last_fetch(root: &PathBuf, repo_path: &PathBuf) -> Option<u128> {
+fn get_cache_last_fetch(root: &Path, repo_path: &Path) -> Option<u128> {
   let idx = load_index(root);
   let pstr = repo_path.to_string_lossy().to_string();
   idx.entries.into_iter().find(|e| e.path == pstr).and_then(|e| e.last_fetch_ms)
@@ -169,45 +510,142 @@ fn swr_map() -> &'static Mutex<HashMap<String, u128>> {
   SWR_FETCH_MAP.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
-fn get_map_last_fetch(repo_path: &PathBuf) -> Option<u128> {
+fn get_map_last_fetch(repo_path: &Path) -> Option<u128> {
   let pstr = repo_path.to_string_lossy().to_string();
   swr_map().lock().ok().and_then(|m| m.get(&pstr).copied())
 }
 
-fn set_map_last_fetch(repo_path: &PathBuf, t: u128) {
+fn set_map_last_fetch(repo_path: &Path, t: u128) {
   let pstr = repo_path.to_string_lossy().to_string();
   if let Ok(mut m) = swr_map().lock() { m.insert(pstr, t); }
 }
 
-pub fn swr_fetch_origin_all_path_bool(path: &std::path::Path, window_ms: u128) -> Result<bool> {
-  let cwd = path.to_string_lossy().to_string();
-  let root = default_cache_root();
-  let now = now_ms();
+// Records a fetch of `repo_path` that finished at `at`, leaving the clone `size_bytes` big when
+// measured. Only clones already in the index get an entry: other paths a call fetched (its own
+// checkout) are not the cache's to evict.
+fn record_fetch(
+  root: &Path,
+  repo_path: &Path,
+  at: u128,
+  duration_ms: u128,
+  error: Option<String>,
+  size_bytes: Option<u64>,
+) -> Result<()> {
+  set_map_last_fetch(repo_path, at);
+  let pstr = repo_path.to_string_lossy().to_string();
+  update_index(root, |idx| {
+    if let Some(e) = idx.entries.iter_mut().find(|e| e.path == pstr) {
+      e.last_fetch_ms = Some(at);
+      e.last_fetch_duration_ms = Some(duration_ms);
+      e.last_fetch_error = error;
+      e.size_bytes = size_bytes.or(e.size_bytes);
+    }
+  })
+}
 
-  let last_fetch_idx = get_cache_last_fetch(&root, &PathBuf::from(&cwd));
-  let last_fetch_map = get_map_last_fetch(&PathBuf::from(&cwd));
-  let last_fetch = last_fetch_idx.or(last_fetch_map);
-
-  if let Some(t) = last_fetch {
-    if now.saturating_sub(t) <= window_ms {
-      let cwd_bg = cwd.clone();
-      let root_bg = root.clone();
-      std::thread::spawn(move || {
-        let _ = run_git(&cwd_bg, &["fetch", "--all", "--tags", "--prune"]);
-        let _ = update_cache_index_with(&root_bg, &PathBuf::from(&cwd_bg), Some(now_ms()));
-        set_map_last_fetch(&PathBuf::from(&cwd_bg), now_ms());
-      });
-      return Ok(false);
+fn fetch_and_record(root: &Path, repo_path: &Path) {
+  let started = now_ms();
+  let result = run_git(repo_path.to_string_lossy().as_ref(), &["fetch", "--all", "--tags", "--prune"]);
+  let at = now_ms();
+  // Only cached clones are measured; a caller's own checkout can be arbitrarily large.
+  let size = repo_path.starts_with(root).then(|| dir_size(repo_path));
+  let _ = record_fetch(root, repo_path, at, at.saturating_sub(started), result.err().map(|e| format!("{e:#}")), size);
+}
+
+// Measures the clone at `repo_path` and stores its size in the index.
+fn record_size(root: &Path, repo_path: &Path) -> Result<()> {
+  let size = dir_size(repo_path);
+  let pstr = repo_path.to_string_lossy().to_string();
+  update_index(root, |idx| {
+    if let Some(e) = idx.entries.iter_mut().find(|e| e.path == pstr) {
+      e.size_bytes = Some(size);
     }
+  })
+}
+
+static IN_FLIGHT: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+
+fn in_flight() -> &'static Mutex<HashSet<PathBuf>> {
+  IN_FLIGHT.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+// Fetches `path` on another thread unless a fetch of it is already running. The auth token of
+// the calling thread goes with it.
+fn background_fetch(root: PathBuf, path: PathBuf, token: Option<Arc<str>>) {
+  match in_flight().lock() {
+    Ok(mut running) if running.insert(path.clone()) => {}
+    _ => return,
   }
+  std::thread::spawn(move || {
+    let _auth = super::auth::scope(token.as_deref());
+    fetch_and_record(&root, &path);
+    if let Ok(mut running) = in_flight().lock() { running.remove(&path); }
+  });
+}
 
-  let _ = run_git(&cwd, &["fetch", "--all", "--tags", "--prune"]);
-  let now2 = now_ms();
-  let _ = update_cache_index_with(&root, &PathBuf::from(&cwd), Some(now2));
-  set_map_last_fetch(&PathBuf::from(&cwd), now2);
+pub fn swr_fetch_origin_all_path_bool(path: &std::path::Path, window_ms: u128) -> Result<bool> {
+  let root = default_cache_root();
+  let last_fetch = get_cache_last_fetch(&root, path).or_else(|| get_map_last_fetch(path));
+  if last_fetch.is_some_and(|t| now_ms().saturating_sub(t) <= window_ms) {
+    background_fetch(root, path.to_path_buf(), super::auth::current());
+    return Ok(false);
+  }
+  fetch_and_record(&root, path);
   Ok(true)
 }
 
+#[derive(Clone)]
+struct HotRepo {
+  root: PathBuf,
+  used_ms: u128,
+  token: Option<Arc<str>>,
+}
+
+static HOT_REPOS: OnceLock<Mutex<HashMap<PathBuf, HotRepo>>> = OnceLock::new();
+static REFRESHER: Once = Once::new();
+
+fn hot_repos() -> &'static Mutex<HashMap<PathBuf, HotRepo>> {
+  HOT_REPOS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Keeps `path` fetched in the background for a while after a call used it, starting the
+// refresher the first time.
+fn mark_hot(root: &Path, path: &Path) {
+  if let Ok(mut hot) = hot_repos().lock() {
+    hot.insert(
+      path.to_path_buf(),
+      HotRepo { root: root.to_path_buf(), used_ms: now_ms(), token: super::auth::current() },
+    );
+  }
+  REFRESHER.call_once(|| {
+    std::thread::spawn(|| loop {
+      let interval = refresh_interval_ms();
+      if interval > 0 {
+        refresh_hot_repos(now_ms(), interval);
+      }
+      // Re-read every few seconds so a changed interval takes effect without a restart.
+      std::thread::sleep(Duration::from_millis(interval.clamp(1_000, 5_000) as u64));
+    });
+  });
+}
+
+// Fetches every hot repo not fetched within `interval_ms`, in the background, and forgets the
+// ones unused for `HOT_FOR_MS` or no longer cached.
+fn refresh_hot_repos(now: u128, interval_ms: u128) {
+  let due: Vec<(PathBuf, HotRepo)> = {
+    let Ok(mut hot) = hot_repos().lock() else { return };
+    hot.retain(|path, r| now.saturating_sub(r.used_ms) <= HOT_FOR_MS && is_cached_clone(path));
+    hot.iter().map(|(p, r)| (p.clone(), r.clone())).collect()
+  };
+  for (path, r) in due {
+    let last_fetch = get_cache_last_fetch(&r.root, &path).or_else(|| get_map_last_fetch(&path));
+    if last_fetch.is_some_and(|t| now.saturating_sub(t) < interval_ms) {
+      continue;
+    }
+    background_fetch(r.root, path, r.token);
+  }
+}
+
 pub fn swr_fetch_origin_all_path(path: &std::path::Path, window_ms: u128) -> Result<()> {
   let _ = swr_fetch_origin_all_path_bool(path, window_ms)?;
   Ok(())
@@ -219,21 +657,217 @@ pub fn fetch_origin_all_path(path: &std::path::Path) -> Result<()> {
   Ok(())
 }
 
-fn enforce_cache_limit(root: &PathBuf) -> Result<()> {
-  let mut idx = load_index(root);
-  if idx.entries.len() <= MAX_CACHE_REPOS { return Ok(()); }
-  idx.entries.sort_by(|a, b| b.last_access_ms.cmp(&a.last_access_ms));
-  let survivors = idx.entries[..MAX_CACHE_REPOS].to_vec();
-  let victims = idx.entries[MAX_CACHE_REPOS..].to_vec();
+fn dir_size(path: &Path) -> u64 {
+  let Ok(entries) = fs::read_dir(path) else { return 0 };
+  entries
+    .filter_map(|e| e.ok())
+    .map(|e| match e.file_type() {
+      Ok(t) if t.is_dir() => dir_size(&e.path()),
+      Ok(t) if t.is_file() => e.metadata().map(|m| m.len()).unwrap_or(0),
+      _ => 0,
+    })
+    .sum()
+}
+
+fn enforce_cache_limit(root: &Path) -> Result<()> {
+  evict(root, &cache_settings(), now_ms())
+}
+
+// Least recently used clones go first: those unused for longer than the TTL, then any past
+// `max_repos`, then as many as it takes to fit `max_bytes`. The most recently used clone, the
+// one a call is about to read, is never evicted for size. Sizes come from the index, as
+// recorded at clone and fetch time.
+fn evict(root: &Path, limits: &CacheSettings, now: u128) -> Result<()> {
+  let victims = update_index(root, |idx| {
+    idx.entries.sort_by_key(|e| std::cmp::Reverse(e.last_access_ms));
+    let mut survivors: Vec<CacheIndexEntry> = Vec::new();
+    let mut victims: Vec<CacheIndexEntry> = Vec::new();
+    let mut total_bytes: u64 = 0;
+    for mut e in std::mem::take(&mut idx.entries) {
+      let expired = limits.ttl_ms.is_some_and(|ttl| now.saturating_sub(e.last_access_ms) > ttl);
+      if expired || survivors.len() >= limits.max_repos {
+        victims.push(e);
+        continue;
+      }
+      if let Some(max_bytes) = limits.max_bytes {
+        // Clones indexed before sizes were recorded are measured once, here.
+        let size = *e.size_bytes.get_or_insert_with(|| dir_size(Path::new(&e.path)));
+        if !survivors.is_empty() && total_bytes + size > max_bytes {
+          victims.push(e);
+          continue;
+        }
+        total_bytes += size;
+      }
+      survivors.push(e);
+    }
+    idx.entries = survivors;
+    victims
+  })?;
+  // Deleting can take a while; the index already no longer lists them.
   for v in &victims {
     let p = PathBuf::from(&v.path);
     let _ = fs::remove_dir_all(&p);
   }
-  idx.entries = survivors;
-  save_index(root, &idx)?;
   Ok(())
 }
 
+fn run_tar(args: &[&str]) -> Result<()> {
+  let output = std::process::Command::new("tar")
+    .args(args)
+    .stdin(std::process::Stdio::null())
+    .output()?;
+  if output.status.success() {
+    Ok(())
+  } else {
+    Err(anyhow!("tar {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr)))
+  }
+}
+
+/// Cache slug for `owner/repo`, a provider-prefixed full name (`gitlab:group/project`), a clone
+/// URL, or an existing slug (`owner__repo`).
+pub fn cache_slug(repo: &str) -> String {
+  if split_provider(repo).is_some() {
+    if let Ok(url) = resolve_repo_url(Some(repo), None, false, None) {
+      return slug_from_url(&url);
+    }
+  }
+  slug_from_url(repo)
+}
+
+/// Write the cached clone for `repo` to `tar_path` so another machine can seed its cache
+/// with `import_repo` instead of cold-cloning.
+pub fn export_repo(repo: &str, tar_path: &Path) -> Result<String> {
+  export_repo_at(&default_cache_root(), repo, tar_path)
+}
+
+fn export_repo_at(root: &Path, repo: &str, tar_path: &Path) -> Result<String> {
+  let slug = cache_slug(repo);
+  let path = root.join(&slug);
+  let _ = migrate_cached_clone(root, &path);
+  if !is_cached_clone(&path) {
+    return Err(anyhow!("no cached repo for {} at {}", repo, path.display()));
+  }
+  if let Some(parent) = tar_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    fs::create_dir_all(parent)?;
+  }
+  let tar = tar_path.to_string_lossy();
+  let root_str = root.to_string_lossy();
+  run_tar(&["-cf", &tar, "-C", &root_str, &slug])?;
+  Ok(slug)
+}
+
+/// Unpack an archive produced by `export_repo` into the cache, replacing any existing clone of
+/// the same repo. Returns the imported slug.
+pub fn import_repo(tar_path: &Path) -> Result<String> {
+  import_repo_at(&default_cache_root(), tar_path)
+}
+
+fn import_repo_at(root: &Path, tar_path: &Path) -> Result<String> {
+  fs::create_dir_all(root)?;
+  // Extract next to the cache so the final move is a rename on the same filesystem.
+  let staging = root.join(format!(".import-{}-{}", std::process::id(), now_ms()));
+  fs::create_dir_all(&staging)?;
+  let result = (|| {
+    let tar = tar_path.to_string_lossy();
+    let staging_str = staging.to_string_lossy();
+    run_tar(&["-xf", &tar, "-C", &staging_str])?;
+
+    let mut dirs = fs::read_dir(&staging)?
+      .filter_map(|e| e.ok())
+      .filter(|e| e.path().is_dir())
+      .collect::<Vec<_>>();
+    if dirs.len() != 1 {
+      return Err(anyhow!("expected one repo directory in {}, found {}", tar_path.display(), dirs.len()));
+    }
+    let entry = dirs.pop().unwrap();
+    let slug = entry.file_name().to_string_lossy().to_string();
+    if !is_cached_clone(&entry.path()) && !entry.path().join(".git").join("HEAD").is_file() {
+      return Err(anyhow!("{} does not contain a git clone", tar_path.display()));
+    }
+
+    let dest = root.join(&slug);
+    if dest.exists() {
+      fs::remove_dir_all(&dest)?;
+    }
+    fs::rename(entry.path(), &dest)?;
+    // Archives exported before cached clones were mirrors carry a worktree or remote-tracking refs.
+    migrate_cached_clone(root, &dest)?;
+    // The archive may be stale; leave the fetch time unset so the next diff fetches.
+    update_cache_index(root, &dest)?;
+    record_size(root, &dest)?;
+    enforce_cache_limit(root)?;
+    Ok(slug)
+  })();
+  let _ = fs::remove_dir_all(&staging);
+  result
+}
+
+/// The cached clones, most recently used first.
+pub fn list_repos() -> Result<Vec<CachedRepo>> {
+  Ok(list_repos_at(&default_cache_root()))
+}
+
+fn list_repos_at(root: &Path) -> Vec<CachedRepo> {
+  let mut idx = load_index(root);
+  idx.entries.sort_by_key(|e| std::cmp::Reverse(e.last_access_ms));
+  idx.entries
+    .into_iter()
+    .filter(|e| is_cached_clone(Path::new(&e.path)))
+    .map(|e| CachedRepo {
+      sizeBytes: e.size_bytes.unwrap_or_else(|| dir_size(Path::new(&e.path))) as i64,
+      slug: e.slug,
+      path: e.path,
+      lastAccessTime: e.last_access_ms as i64,
+      lastFetchTime: e.last_fetch_ms.map(|t| t as i64),
+      lastFetchDurationMs: e.last_fetch_duration_ms.map(|t| t as i64),
+      lastFetchError: e.last_fetch_error,
+    })
+    .collect()
+}
+
+fn remove_clone(path: &Path) {
+  let _ = fs::remove_dir_all(path);
+  if let Ok(mut m) = swr_map().lock() { m.remove(path.to_string_lossy().as_ref()); }
+}
+
+/// Delete the cached clone for `repo` (`owner/repo`, a clone URL, or a cache slug). False
+/// when there was none.
+pub fn evict_repo(repo: &str) -> Result<bool> {
+  evict_repo_at(&default_cache_root(), repo)
+}
+
+fn evict_repo_at(root: &Path, repo: &str) -> Result<bool> {
+  let slug = cache_slug(repo);
+  let path = root.join(&slug);
+  let indexed = update_index(root, |idx| {
+    let before = idx.entries.len();
+    idx.entries.retain(|e| e.slug != slug);
+    idx.entries.len() != before
+  })?;
+  let existed = path.exists() || indexed;
+  remove_clone(&path);
+  Ok(existed)
+}
+
+/// Delete every cached clone, including ones missing from the index. Returns how many.
+pub fn purge_repos() -> Result<u32> {
+  purge_repos_at(&default_cache_root())
+}
+
+fn purge_repos_at(root: &Path) -> Result<u32> {
+  let Ok(dirs) = fs::read_dir(root) else { return Ok(0) };
+  let mut removed = 0;
+  for entry in dirs.filter_map(|e| e.ok()) {
+    let path = entry.path();
+    if is_cached_clone(&path) || path.join(".git").join("HEAD").is_file() {
+      remove_clone(&path);
+      removed += 1;
+    }
+  }
+  update_index(root, |idx| *idx = CacheIndex::default())?;
+  Ok(removed)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -256,4 +890,308 @@ mod tests {
     assert!(first, "first call should be synchronous fetch");
     assert!(!second, "second call within window should skip and background");
   }
+
+  #[test]
+  fn ssh_and_https_urls_share_a_cache_entry() {
+    let https = resolve_repo_url(Some("acme/widgets"), None, false, None).unwrap();
+    let ssh = resolve_repo_url(Some("acme/widgets"), None, true, None).unwrap();
+    assert_eq!(https, "https://github.com/acme/widgets.git");
+    assert_eq!(ssh, "git@github.com:acme/widgets.git");
+    assert_eq!(slug_from_url(&https), "acme__widgets");
+    assert_eq!(slug_from_url(&ssh), "acme__widgets");
+    assert_eq!(resolve_repo_url(Some("acme/widgets"), Some("ssh://git@host/x/y.git"), false, None).unwrap(), "ssh://git@host/x/y.git");
+  }
+
+  #[test]
+  fn resolves_provider_full_names_to_distinct_cache_entries() {
+    let url = |full: &str, ssh: bool, base: Option<&str>| resolve_repo_url(Some(full), None, ssh, base).unwrap();
+    assert_eq!(url("gitlab:acme/tools/widgets", false, None), "https://gitlab.com/acme/tools/widgets.git");
+    assert_eq!(url("gitlab:acme/widgets", true, Some("https://git.example.com:8443/")), "git@git.example.com:acme/widgets.git");
+    assert_eq!(url("gitlab:acme/widgets", false, Some("https://git.example.com/gitlab")), "https://git.example.com/gitlab/acme/widgets.git");
+    assert_eq!(url("bitbucket:acme/widgets", false, None), "https://bitbucket.org/acme/widgets.git");
+    assert_eq!(url("azure:acme/platform/widgets", false, None), "https://dev.azure.com/acme/platform/_git/widgets");
+    assert_eq!(url("azure:acme/platform/widgets", true, None), "git@ssh.dev.azure.com:v3/acme/platform/widgets");
+    assert!(resolve_repo_url(Some("azure:acme/widgets"), None, false, None).is_err());
+    assert!(resolve_repo_url(Some("acme"), None, false, None).is_err());
+
+    let slugs: Vec<String> = ["acme/widgets", "gitlab:acme/widgets", "bitbucket:acme/widgets"].iter().map(|r| cache_slug(r)).collect();
+    assert_eq!(slugs, vec!["acme__widgets", "gitlab.com__acme__widgets", "bitbucket.org__acme__widgets"]);
+    assert_ne!(cache_slug("gitlab:a/b/widgets"), cache_slug("gitlab:c/b/widgets"));
+    assert_eq!(slug_from_url("https://acme@dev.azure.com/acme/platform/_git/widgets"), slug_from_url("git@ssh.dev.azure.com:v3/acme/platform/widgets"));
+    assert_eq!(cache_slug("gitlab.com__acme__widgets"), "gitlab.com__acme__widgets");
+  }
+
+  #[test]
+  fn clones_mirrors_and_migrates_older_clones() {
+    let tmp = tempdir().unwrap();
+    let origin = tmp.path().join("origin");
+    std::fs::create_dir_all(&origin).unwrap();
+    let origin_str = origin.to_string_lossy().to_string();
+    run_git(&origin_str, &["init", "-b", "main"]).unwrap();
+    std::fs::write(origin.join("a.txt"), "hello\n").unwrap();
+    run_git(&origin_str, &["add", "."]).unwrap();
+    run_git(&origin_str, &["-c", "user.email=t@example.com", "-c", "user.name=t", "commit", "-m", "init"]).unwrap();
+    run_git(&origin_str, &["branch", "feature"]).unwrap();
+    run_git(&origin_str, &["update-ref", "refs/pull/1/head", "HEAD"]).unwrap();
+    let head = run_git(&origin_str, &["rev-parse", "HEAD"]).unwrap();
+    let url = format!("file://{}", origin_str);
+
+    let mirror = tmp.path().join("mirror");
+    std::fs::create_dir_all(&mirror).unwrap();
+    clone_mirror(&mirror, &url, false).expect("clone");
+    assert!(is_cached_clone(&mirror));
+    assert!(!mirror.join("a.txt").exists());
+    let mirror_str = mirror.to_string_lossy();
+    for name in ["refs/heads/main", "refs/heads/feature", "refs/pull/1/head"] {
+      assert_eq!(run_git(&mirror_str, &["rev-parse", name]).unwrap(), head);
+    }
+    assert_eq!(run_git(&mirror_str, &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/main");
+    // Callers keep naming origin's branches as in a regular clone.
+    let repo = gix::open(&mirror).unwrap();
+    for rev in ["origin/main", "origin/feature", "refs/remotes/origin/main", "origin/HEAD"] {
+      assert_eq!(crate::diff::refs::oid_from_rev_parse(&repo, rev).unwrap().to_string(), head.trim());
+    }
+    assert!(is_mirror(&repo));
+
+    // Clones from before cached clones were mirrors: a regular one with a worktree, and a bare
+    // one with remote-tracking branches. Both end up laid out like a fresh mirror.
+    run_git(tmp.path().to_str().unwrap(), &["clone", &url, "legacy"]).unwrap();
+    run_git(tmp.path().to_str().unwrap(), &["clone", "--bare", &url, "bare"]).unwrap();
+    let bare_str = tmp.path().join("bare").to_string_lossy().to_string();
+    run_git(&bare_str, &["config", "remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*"]).unwrap();
+    run_git(&bare_str, &["fetch", "origin"]).unwrap();
+    run_git(&bare_str, &["remote", "set-head", "origin", "main"]).unwrap();
+    run_git(&bare_str, &["branch", "-D", "feature"]).unwrap();
+    for name in ["legacy", "bare"] {
+      let path = tmp.path().join(name);
+      migrate_cached_clone(tmp.path(), &path).expect("migrate");
+      assert!(is_cached_clone(&path));
+      assert!(is_mirror(&gix::open(&path).unwrap()));
+      assert!(!path.join("a.txt").exists() && !path.join(".git").exists());
+      let path_str = path.to_string_lossy();
+      assert_eq!(run_git(&path_str, &["config", "core.bare"]).unwrap().trim(), "true");
+      assert_eq!(run_git(&path_str, &["rev-parse", "refs/heads/feature"]).unwrap(), head);
+      assert_eq!(run_git(&path_str, &["symbolic-ref", "HEAD"]).unwrap().trim(), "refs/heads/main");
+      assert_eq!(run_git(&path_str, &["for-each-ref", "refs/remotes/"]).unwrap(), "");
+      // Later fetches mirror every ref, including ones a regular clone leaves out.
+      run_git(&path_str, &["fetch", "--all", "--tags", "--prune"]).unwrap();
+      assert_eq!(run_git(&path_str, &["rev-parse", "refs/pull/1/head"]).unwrap(), head);
+    }
+    assert!(std::fs::read_dir(tmp.path()).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().starts_with(".migrate-")));
+  }
+
+  #[test]
+  fn evicts_by_age_count_and_size() {
+    let root = tempdir().unwrap();
+    let day = DAY_MS as u128;
+    let now = 100 * day;
+    // (slug, bytes, days since last access), most recent first.
+    let repos = [("a", 400, 0), ("b", 400, 1), ("c", 400, 2), ("d", 10, 40)];
+    let seed = || {
+      let mut idx = CacheIndex::default();
+      for (slug, bytes, age) in repos {
+        let path = root.path().join(slug);
+        std::fs::create_dir_all(path.join("objects")).unwrap();
+        std::fs::write(path.join("objects/pack"), vec![0u8; bytes]).unwrap();
+        idx.entries.push(CacheIndexEntry {
+          slug: slug.into(),
+          path: path.to_string_lossy().into_owned(),
+          last_access_ms: now - age * day,
+          last_fetch_ms: None,
+          last_fetch_duration_ms: None,
+          last_fetch_error: None,
+          // `d` predates recorded sizes and is measured on disk.
+          size_bytes: (slug != "d").then_some(bytes as u64),
+        });
+      }
+      save_index(root.path(), &idx).unwrap();
+    };
+    let kept = || -> Vec<String> {
+      let on_disk: Vec<String> = repos.iter().map(|r| r.0.to_string()).filter(|s| root.path().join(s).exists()).collect();
+      let indexed: Vec<String> = load_index(root.path()).entries.into_iter().map(|e| e.slug).collect();
+      assert_eq!(on_disk, indexed);
+      indexed
+    };
+
+    seed();
+    evict(root.path(), &CacheSettings::default(), now).unwrap();
+    assert_eq!(kept(), vec!["a", "b", "c", "d"]);
+
+    evict(root.path(), &CacheSettings { ttl_ms: Some(30 * day), ..Default::default() }, now).unwrap();
+    assert_eq!(kept(), vec!["a", "b", "c"]);
+
+    seed();
+    evict(root.path(), &CacheSettings { max_repos: 2, ..Default::default() }, now).unwrap();
+    assert_eq!(kept(), vec!["a", "b"]);
+
+    // Size eviction skips a clone too big to fit but keeps smaller, older ones.
+    seed();
+    evict(root.path(), &CacheSettings { max_bytes: Some(500), ..Default::default() }, now).unwrap();
+    assert_eq!(kept(), vec!["a", "d"]);
+    let sizes: Vec<Option<u64>> = load_index(root.path()).entries.into_iter().map(|e| e.size_bytes).collect();
+    assert_eq!(sizes, vec![Some(400), Some(10)]);
+
+    // Eviction trusts the recorded size rather than walking the clone.
+    seed();
+    let mut idx = load_index(root.path());
+    idx.entries[0].size_bytes = Some(10);
+    save_index(root.path(), &idx).unwrap();
+    evict(root.path(), &CacheSettings { max_bytes: Some(500), ..Default::default() }, now).unwrap();
+    assert_eq!(kept(), vec!["a", "b", "d"]);
+
+    // The most recent clone stays even when it alone is over the quota.
+    seed();
+    evict(root.path(), &CacheSettings { max_bytes: Some(100), ..Default::default() }, now).unwrap();
+    assert_eq!(kept(), vec!["a"]);
+  }
+
+  #[test]
+  fn concurrent_index_updates_are_not_lost() {
+    let root = tempdir().unwrap();
+    let workers: Vec<_> = (0..8)
+      .map(|t| {
+        let root = root.path().to_path_buf();
+        std::thread::spawn(move || {
+          for i in 0..20 {
+            let path = root.join(format!("r{t}-{i}"));
+            update_cache_index(&root, &path).unwrap();
+            record_fetch(&root, &path, now_ms(), 1, None, None).unwrap();
+          }
+        })
+      })
+      .collect();
+    for w in workers {
+      w.join().unwrap();
+    }
+    let idx = load_index(root.path());
+    assert_eq!(idx.entries.len(), 160);
+    assert!(idx.entries.iter().all(|e| e.last_fetch_ms.is_some()));
+    let leftovers: Vec<_> = std::fs::read_dir(root.path()).unwrap().filter_map(|e| e.ok()).map(|e| e.file_name()).collect();
+    assert_eq!(leftovers, vec![std::ffi::OsString::from("cache-index.json")]);
+  }
+
+  #[test]
+  fn refreshes_hot_repos_and_records_fetches() {
+    let tmp = tempdir().unwrap();
+    let origin = tmp.path().join("origin");
+    std::fs::create_dir_all(&origin).unwrap();
+    let origin_str = origin.to_string_lossy().to_string();
+    let commit = |message: &str| {
+      std::fs::write(origin.join("a.txt"), format!("{message}\n")).unwrap();
+      run_git(&origin_str, &["add", "."]).unwrap();
+      run_git(&origin_str, &["-c", "user.email=t@example.com", "-c", "user.name=t", "commit", "-m", message]).unwrap();
+      run_git(&origin_str, &["rev-parse", "HEAD"]).unwrap()
+    };
+    run_git(&origin_str, &["init", "-b", "main"]).unwrap();
+    commit("init");
+    let root = tmp.path().join("cache");
+    let clone = root.join("acme__widgets");
+    let broken = root.join("acme__broken");
+    for path in [&clone, &broken] {
+      std::fs::create_dir_all(path).unwrap();
+      clone_mirror(path, &format!("file://{}", origin_str), false).expect("clone");
+      update_cache_index_with(&root, path, Some(1)).unwrap();
+      if let Ok(mut hot) = hot_repos().lock() {
+        hot.insert(path.clone(), HotRepo { root: root.clone(), used_ms: now_ms(), token: None });
+      }
+    }
+    run_git(broken.to_string_lossy().as_ref(), &["remote", "set-url", "origin", "file:///nonexistent/repo"]).unwrap();
+    let head = commit("second");
+
+    let wait_for_fetches = || {
+      for _ in 0..200 {
+        let running = in_flight().lock().unwrap().iter().any(|p| p.starts_with(&root));
+        if !running { return; }
+        std::thread::sleep(Duration::from_millis(50));
+      }
+      panic!("background fetches did not finish");
+    };
+    refresh_hot_repos(now_ms(), 60_000);
+    wait_for_fetches();
+    assert_eq!(run_git(clone.to_string_lossy().as_ref(), &["rev-parse", "refs/heads/main"]).unwrap(), head);
+    let listed = list_repos_at(&root);
+    let widgets = listed.iter().find(|r| r.slug == "acme__widgets").unwrap();
+    assert!(widgets.lastFetchTime.is_some_and(|t| t > 1));
+    assert!(widgets.lastFetchDurationMs.is_some() && widgets.lastFetchError.is_none());
+    let broken_entry = listed.iter().find(|r| r.slug == "acme__broken").unwrap();
+    assert!(broken_entry.lastFetchError.is_some());
+    let indexed = load_index(&root);
+    assert!(indexed.entries.iter().all(|e| e.size_bytes.is_some_and(|size| size > 0)));
+
+    // Fetched just now, so not due again; cold repos are dropped.
+    let fetched_at = widgets.lastFetchTime;
+    refresh_hot_repos(now_ms(), 60_000);
+    wait_for_fetches();
+    let again = list_repos_at(&root);
+    assert_eq!(again.iter().find(|r| r.slug == "acme__widgets").unwrap().lastFetchTime, fetched_at);
+    refresh_hot_repos(now_ms() + HOT_FOR_MS + 1, 60_000);
+    assert!(!hot_repos().lock().unwrap().contains_key(&clone));
+  }
+
+  #[test]
+  fn lists_evicts_and_purges_cached_clones() {
+    let root = tempdir().unwrap();
+    for slug in ["acme__widgets", "acme__gadgets"] {
+      let path = root.path().join(slug);
+      std::fs::create_dir_all(&path).unwrap();
+      run_git(path.to_string_lossy().as_ref(), &["init", "--bare", "--quiet"]).unwrap();
+      update_cache_index_with(root.path(), &path, (slug == "acme__widgets").then_some(7)).unwrap();
+    }
+    // Not a clone, so neither listed nor purged.
+    std::fs::create_dir_all(root.path().join("scratch")).unwrap();
+
+    let listed = list_repos_at(root.path());
+    let slugs: Vec<&str> = listed.iter().map(|r| r.slug.as_str()).collect();
+    assert_eq!(slugs.len(), 2);
+    assert!(slugs.contains(&"acme__widgets") && slugs.contains(&"acme__gadgets"));
+    assert!(listed.iter().all(|r| r.sizeBytes > 0 && r.lastAccessTime > 0));
+    let widgets = listed.iter().find(|r| r.slug == "acme__widgets").unwrap();
+    assert_eq!(widgets.lastFetchTime, Some(7));
+
+    assert!(evict_repo_at(root.path(), "acme/widgets").unwrap());
+    assert!(!evict_repo_at(root.path(), "acme/widgets").unwrap());
+    assert!(!root.path().join("acme__widgets").exists());
+    assert_eq!(list_repos_at(root.path()).len(), 1);
+
+    assert_eq!(purge_repos_at(root.path()).unwrap(), 1);
+    assert!(list_repos_at(root.path()).is_empty());
+    assert!(root.path().join("scratch").exists());
+  }
+
+  #[test]
+  fn export_then_import_round_trips_cached_repo() {
+    let src_root = tempdir().unwrap();
+    let repo_dir = src_root.path().join("acme__widgets");
+    std::fs::create_dir_all(&repo_dir).unwrap();
+    let cwd = repo_dir.to_string_lossy().to_string();
+    run_git(&cwd, &["init"]).unwrap();
+    std::fs::write(repo_dir.join("a.txt"), "hello\n").unwrap();
+    run_git(&cwd, &["add", "."]).unwrap();
+    run_git(&cwd, &["-c", "user.email=t@example.com", "-c", "user.name=t", "commit", "-m", "init"]).unwrap();
+    let head = run_git(&cwd, &["rev-parse", "HEAD"]).unwrap();
+
+    let out = tempdir().unwrap();
+    let tar_path = out.path().join("artifacts").join("cache.tar");
+    let slug = export_repo_at(src_root.path(), "acme/widgets", &tar_path).expect("export");
+    assert_eq!(slug, "acme__widgets");
+    assert!(export_repo_at(src_root.path(), "acme/missing", &tar_path).is_err());
+
+    let dst_root = tempdir().unwrap();
+    let imported = import_repo_at(dst_root.path(), &tar_path).expect("import");
+    assert_eq!(imported, "acme__widgets");
+    let dst_repo = dst_root.path().join("acme__widgets");
+    let imported_head = run_git(dst_repo.to_string_lossy().as_ref(), &["rev-parse", "HEAD"]).unwrap();
+    assert_eq!(imported_head, head);
+    let idx = load_index(dst_root.path());
+    assert!(idx.entries.iter().any(|e| e.slug == "acme__widgets" && e.last_fetch_ms.is_none()));
+    let leftovers = std::fs::read_dir(dst_root.path())
+      .unwrap()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_name().to_string_lossy().starts_with(".import-"))
+      .count();
+    assert_eq!(leftovers, 0);
+
+    // Importing again replaces the existing clone.
+    import_repo_at(dst_root.path(), &tar_path).expect("re-import");
+  }
 }
@@ -1,2 +1,4 @@
+pub mod auth;
 pub mod cache;
+pub mod partial;
 
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use gix::{hash::ObjectId, Repository};
+use std::cell::Cell;
+
+use crate::util::run_git;
+
+// Object ids per fetch, to stay well inside command line limits.
+const FETCH_CHUNK: usize = 500;
+
+thread_local! {
+  static NO_FETCH: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether new cache clones are blobless unless a call says otherwise, from
+/// `CMUX_GIT_PARTIAL_CLONE`.
+pub fn partial_clone_default() -> bool {
+  std::env::var("CMUX_GIT_PARTIAL_CLONE").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Keeps `fetch_missing` from fetching on this thread until dropped, for `offline` calls.
+pub struct NoFetch {
+  previous: bool,
+}
+
+pub fn no_fetch(on: bool) -> NoFetch {
+  NoFetch { previous: NO_FETCH.with(|c| c.replace(on || c.get())) }
+}
+
+impl Drop for NoFetch {
+  fn drop(&mut self) {
+    NO_FETCH.with(|c| c.set(self.previous));
+  }
+}
+
+/// Whether `repo` is a partial clone, which may lack blobs until they are asked for.
+pub fn is_partial(repo: &Repository) -> bool {
+  repo.config_snapshot().boolean("remote.origin.promisor").unwrap_or(false)
+}
+
+/// Fetches whichever of `ids` a partial clone lacks, in as few round trips as possible, the
+/// way git fetches a promisor's objects on demand. Does nothing for full clones, and under
+/// `no_fetch`, where missing blobs stay missing.
+pub fn fetch_missing(repo: &Repository, ids: impl IntoIterator<Item = ObjectId>) -> Result<()> {
+  if NO_FETCH.with(|c| c.get()) || !is_partial(repo) {
+    return Ok(());
+  }
+  let mut missing: Vec<String> = ids.into_iter().filter(|id| !repo.has_object(id)).map(|id| id.to_hex().to_string()).collect();
+  if missing.is_empty() {
+    return Ok(());
+  }
+  missing.sort();
+  missing.dedup();
+  let cwd = repo.git_dir().to_string_lossy().into_owned();
+  for chunk in missing.chunks(FETCH_CHUNK) {
+    let mut args = vec![
+      "-c", "fetch.negotiationAlgorithm=noop",
+      "fetch", "origin", "--no-tags", "--no-write-fetch-head", "--recurse-submodules=no", "--filter=blob:none",
+    ];
+    args.extend(chunk.iter().map(String::as_str));
+    run_git(&cwd, &args)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use gix::bstr::ByteSlice;
+use gix::Repository;
+
+use crate::repo::cache::{cached_repo, ensure_repo, is_stale, resolve_repo_url, swr_fetch_origin_all_path, RepoOptions};
+use crate::types::{GitListTagsOptions, TagInfo};
+
+// One tag ref, peeled to what it finally points at. Refs whose objects are missing are skipped.
+fn tag_info(repo: &Repository, name: &str, id: gix::hash::ObjectId) -> Option<TagInfo> {
+  let obj = repo.find_object(id).ok()?;
+  let mut info = TagInfo { name: name.to_string(), ..Default::default() };
+  if let Ok(tag) = obj.try_to_tag_ref() {
+    info.tagSha = Some(id.to_hex().to_string());
+    if let Some(tagger) = tag.tagger {
+      info.taggerName = Some(tagger.name.to_str_lossy().into_owned());
+      info.taggerEmail = Some(tagger.email.to_str_lossy().into_owned());
+      info.date = Some(tagger.time.seconds * 1000);
+    }
+    let message = tag.message.to_str_lossy().trim_end().to_string();
+    info.message = (!message.is_empty()).then_some(message);
+  }
+  let target = obj.peel_tags_to_end().ok()?;
+  info.sha = target.id.to_hex().to_string();
+  if info.date.is_none() {
+    if let Ok(commit) = target.try_into_commit() {
+      info.date = commit.committer().ok().map(|sig| sig.time.seconds * 1000);
+    }
+  }
+  Some(info)
+}
+
+/// Every tag of the repo, newest first: by tagger time for annotated tags and otherwise by the
+/// tagged commit's committer time, then by name.
+pub fn list_tags(opts: GitListTagsOptions) -> Result<Vec<TagInfo>> {
+  let offline = opts.offline.unwrap_or(false);
+  let repo_opts = RepoOptions::from_call(opts.partialClone, opts.fetchWindowMs);
+  let repo_path = if let Some(p) = &opts.originPathOverride {
+    std::path::PathBuf::from(p)
+  } else {
+    let url = resolve_repo_url(opts.repoFullName.as_deref(), opts.repoUrl.as_deref(), opts.useSsh.unwrap_or(false), opts.providerBaseUrl.as_deref())?;
+    if offline { cached_repo(&url)? } else { ensure_repo(&url, repo_opts)? }
+  };
+  // Same freshness rules as `list_remote_branches`.
+  let stale = if offline {
+    opts.originPathOverride.is_none() && is_stale(&repo_path, repo_opts.window_ms())
+  } else {
+    if opts.originPathOverride.is_some() {
+      let _ = swr_fetch_origin_all_path(&repo_path, repo_opts.window_ms());
+    }
+    false
+  };
+
+  let repo = gix::open(&repo_path)?;
+  let mut out: Vec<TagInfo> = Vec::new();
+  for r in repo.references()?.tags()? {
+    let Ok(r) = r else { continue };
+    let full = r.name().as_bstr().to_str_lossy().into_owned();
+    let Some(name) = full.strip_prefix("refs/tags/") else { continue };
+    let Some(id) = r.target().try_id().map(|id| id.to_owned()) else { continue };
+    if let Some(mut info) = tag_info(&repo, name, id) {
+      info.stale = stale.then_some(true);
+      out.push(info);
+    }
+  }
+  out.sort_by(|a, b| b.date.unwrap_or(i64::MIN).cmp(&a.date.unwrap_or(i64::MIN)).then_with(|| a.name.cmp(&b.name)));
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::git_at;
+  use crate::util::run_git;
+  use std::fs;
+  use tempfile::tempdir;
+
+  #[test]
+  fn lists_lightweight_and_annotated_tags() {
+    let tmp = tempdir().expect("tempdir");
+    let repo = tmp.path().to_str().unwrap();
+    run_git(repo, &["init", "-b", "main"]).unwrap();
+    fs::write(tmp.path().join("a.txt"), "one\n").unwrap();
+    run_git(repo, &["add", "-A"]).unwrap();
+    git_at(repo, "2024-01-01T00:00:00Z", &["commit", "-m", "one"]);
+    let first = git_at(repo, "2024-01-01T00:00:00Z", &["rev-parse", "HEAD"]);
+    run_git(repo, &["tag", "v0.1"]).unwrap();
+    // An annotated tag is dated by its tagger, not by its commit.
+    git_at(repo, "2024-06-01T00:00:00Z", &["tag", "-a", "v1.0", "-m", "First release\n\nNotes."]);
+    fs::write(tmp.path().join("a.txt"), "two\n").unwrap();
+    git_at(repo, "2024-03-01T00:00:00Z", &["commit", "-am", "two"]);
+    let second = git_at(repo, "2024-03-01T00:00:00Z", &["rev-parse", "HEAD"]);
+    run_git(repo, &["tag", "v1.1"]).unwrap();
+    // A tag of a tag still peels to the commit.
+    git_at(repo, "2024-07-01T00:00:00Z", &["tag", "-a", "v1.0-again", "v1.0", "-m", "Retag"]);
+
+    let tags = list_tags(GitListTagsOptions { originPathOverride: Some(repo.to_string()), ..Default::default() }).expect("tags");
+    let names: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, vec!["v1.0-again", "v1.0", "v1.1", "v0.1"]);
+
+    let light = &tags[3];
+    assert_eq!(light.sha, first);
+    assert_eq!((light.tagSha.as_deref(), light.message.as_deref(), light.taggerName.as_deref()), (None, None, None));
+    assert_eq!(light.date, Some(1_704_067_200_000));
+
+    let annotated = &tags[1];
+    assert_eq!(annotated.sha, first);
+    let tag_object = run_git(repo, &["rev-parse", "v1.0"]).unwrap().trim().to_string();
+    assert_eq!(annotated.tagSha.as_deref(), Some(tag_object.as_str()));
+    assert_eq!(annotated.message.as_deref(), Some("First release\n\nNotes."));
+    assert_eq!(annotated.taggerName.as_deref(), Some("Test"));
+    assert_eq!(annotated.taggerEmail.as_deref(), Some("test@example.com"));
+    assert_eq!(annotated.date, Some(1_717_200_000_000));
+
+    assert_eq!(tags[0].sha, first);
+    assert_eq!(tags[2].sha, second);
+    assert!(tags.iter().all(|t| t.stale.is_none()));
+  }
+}
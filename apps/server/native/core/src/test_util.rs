@@ -0,0 +1,55 @@
+//! Builders for the small repositories the unit tests run against.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs git in `repo` as `author` (mailed at `<author>@example.com`), pinning the author and
+/// committer dates to `date` when given, and returns its trimmed stdout.
+pub(crate) fn git_as(repo: &str, author: &str, date: Option<&str>, args: &[&str]) -> String {
+  let mut cmd = Command::new("git");
+  cmd
+    .arg("-c")
+    .arg(format!("user.name={author}"))
+    .arg("-c")
+    .arg(format!("user.email={}@example.com", author.to_lowercase()))
+    .args(args)
+    .current_dir(repo);
+  if let Some(date) = date {
+    cmd.env("GIT_AUTHOR_DATE", date).env("GIT_COMMITTER_DATE", date);
+  }
+  let out = cmd.output().expect("spawn git");
+  assert!(out.status.success(), "git {args:?}: {}", String::from_utf8_lossy(&out.stderr));
+  String::from_utf8_lossy(&out.stdout).trim().to_string()
+}
+
+/// Runs git as the default test user.
+pub(crate) fn git(repo: &str, args: &[&str]) -> String {
+  git_as(repo, "Test", None, args)
+}
+
+/// Runs git as the default test user with its dates pinned to `date`.
+pub(crate) fn git_at(repo: &str, date: &str, args: &[&str]) -> String {
+  git_as(repo, "Test", Some(date), args)
+}
+
+pub(crate) fn rev(repo: &str, name: &str) -> String {
+  git(repo, &["rev-parse", name])
+}
+
+/// Stages everything and commits it as `author`, returning the new HEAD.
+pub(crate) fn commit_as(repo: &str, author: &str, date: Option<&str>, message: &str) -> String {
+  git(repo, &["add", "-A"]);
+  git_as(repo, author, date, &["commit", "-m", message]);
+  rev(repo, "HEAD")
+}
+
+pub(crate) fn commit(repo: &str, message: &str) -> String {
+  commit_as(repo, "Test", None, message)
+}
+
+/// Commits a new `<message>.txt`, so each call touches a file of its own.
+pub(crate) fn commit_file(repo: &str, message: &str) -> String {
+  fs::write(Path::new(repo).join(format!("{message}.txt")), message).unwrap();
+  commit(repo, message)
+}
@@ -12,7 +12,7 @@ use std::path::{Path, PathBuf};
 use serde::Deserialize;
 use crate::{
   diff::refs,
-  repo::cache::{ensure_repo, resolve_repo_url},
+  repo::cache::{ensure_repo, resolve_repo_url, RepoOptions},
   types::{GitDiffOptions, GitDiffWorkspaceOptions},
   util::run_git,
 };
@@ -111,8 +111,8 @@ fn ground_truth() -> &'static GroundTruthFile {
 }
 
 fn ensure_repo_with_pull_refs(repo_slug: &str) -> PathBuf {
-  let url = resolve_repo_url(Some(repo_slug), None).expect("resolve repo url");
-  let repo_path = ensure_repo(&url).expect("ensure repo path");
+  let url = resolve_repo_url(Some(repo_slug), None, false, None).expect("resolve repo url");
+  let repo_path = ensure_repo(&url, RepoOptions::default()).expect("ensure repo path");
   let repo_path_str = repo_path.to_string_lossy().to_string();
 
   let cache = PULL_FETCH_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
@@ -189,6 +189,22 @@ fn compute_diff_for_pr(pr: &PullRequestRecord) -> CachedDiff {
     maxBytes: Some(LARGE_MAX_BYTES),
     lastKnownBaseSha: None,
     lastKnownMergeCommitSha: None,
+    textOnly: None,
+    collapsePatterns: None,
+    offline: None,
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    renameThreshold: None,
+    detectCopies: None,
+    pathspecs: None,
+    cancelId: None,
+    authToken: None,
+    useSsh: None,
+    providerBaseUrl: None,
+    partialClone: None,
+    fetchWindowMs: None,
   })
   .unwrap_or_else(|err| panic!("diff_refs failed for {}#{}: {err}", pr.repo, pr.number));
 
@@ -229,11 +245,10 @@ fn ensure_merge_commit(base_repo: &Path, pr_number: u64, merge_sha: &str) -> boo
   if run_git(&repo_str, &["cat-file", "-e", &format!("{merge_sha}^{{commit}}")]).is_ok() {
     return true;
   }
-  if run_git(&repo_str, &["fetch", "origin", merge_sha]).is_ok() {
-    if run_git(&repo_str, &["cat-file", "-e", &format!("{merge_sha}^{{commit}}")]).is_ok() {
+  if run_git(&repo_str, &["fetch", "origin", merge_sha]).is_ok()
+    && run_git(&repo_str, &["cat-file", "-e", &format!("{merge_sha}^{{commit}}")]).is_ok() {
       return true;
     }
-  }
   let merge_spec = format!("refs/pull/{}/merge:refs/cmux-tests/merge/{}", pr_number, pr_number);
   run_git(&repo_str, &["fetch", "origin", &merge_spec]).is_ok()
 }
@@ -422,6 +437,11 @@ fn workspace_diff_basic() {
     worktreePath: work.to_string_lossy().to_string(),
     includeContents: Some(true),
     maxBytes: Some(1024*1024),
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    pathspecs: None,
   }).unwrap();
 
   let mut has_a = false;
@@ -441,7 +461,7 @@ fn workspace_diff_unborn_head_uses_remote_default() {
   // Create bare origin with a main branch and one file
   let origin_path = root.join("origin.git");
   fs::create_dir_all(&origin_path).unwrap();
-  run(&root, &format!("git init --bare {}", origin_path.file_name().unwrap().to_str().unwrap()));
+  run(root, &format!("git init --bare {}", origin_path.file_name().unwrap().to_str().unwrap()));
 
   // Seed repo to populate origin/main
   let seed = root.join("seed");
@@ -473,6 +493,11 @@ fn workspace_diff_unborn_head_uses_remote_default() {
     worktreePath: work.to_string_lossy().to_string(),
     includeContents: Some(true),
     maxBytes: Some(1024*1024),
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    pathspecs: None,
   }).expect("diff workspace unborn");
 
   // Expect a diff against remote default: a.txt should be modified
@@ -513,11 +538,357 @@ fn refs_diff_basic_on_local_repo() {
     maxBytes: Some(1024*1024),
     lastKnownBaseSha: None,
     lastKnownMergeCommitSha: None,
+    textOnly: None,
+    collapsePatterns: None,
+    offline: None,
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    renameThreshold: None,
+    detectCopies: None,
+    pathspecs: None,
+    cancelId: None,
+    authToken: None,
+    useSsh: None,
+    providerBaseUrl: None,
+    partialClone: None,
+    fetchWindowMs: None,
   }).unwrap();
 
   assert!(out.iter().any(|e| e.filePath == "b.txt"));
 }
 
+#[test]
+fn refs_diff_patches_apply_with_git() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(&work).unwrap();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  let body: String = (1..=12).map(|i| format!("line {i}\n")).collect();
+  std::fs::write(work.join("a.txt"), &body).unwrap();
+  std::fs::write(work.join("gone.txt"), b"bye\n").unwrap();
+  std::fs::write(work.join("old.txt"), b"moved\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&work, "git checkout -b feature");
+  std::fs::write(work.join("a.txt"), body.replace("line 6\n", "line six\n")).unwrap();
+  std::fs::write(work.join("new.txt"), b"hello").unwrap();
+  run(&work, "git rm -q gone.txt");
+  run(&work, "git mv old.txt moved.txt");
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m change");
+
+  let out = crate::diff::refs::diff_refs(GitDiffOptions{
+    baseRef: Some("main".into()),
+    headRef: "feature".into(),
+    originPathOverride: Some(work.to_string_lossy().to_string()),
+    includeContents: Some(false),
+    includePatch: Some(true),
+    patchContext: Some(1),
+    ..Default::default()
+  }).unwrap();
+  assert_eq!(out.len(), 4);
+  assert!(out.iter().all(|e| e.patch.is_some() && e.oldContent.is_none() && e.newContent.is_none()));
+  let a = out.iter().find(|e| e.filePath == "a.txt").unwrap();
+  assert!(a.patch.as_deref().unwrap().contains("@@ -5,3 +5,3 @@\n line 5\n-line 6\n+line six\n line 7\n"));
+
+  // The concatenated patches turn main into feature.
+  let patch: String = out.iter().filter_map(|e| e.patch.clone()).collect();
+  std::fs::write(tmp.path().join("all.patch"), patch).unwrap();
+  run(&work, "git checkout -q main");
+  run(&work, "git apply ../all.patch");
+  run(&work, "git add -A");
+  run(&work, "git diff --cached --quiet feature");
+}
+
+#[test]
+fn refs_diff_pairs_edited_renames_and_copies_by_similarity() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(&work).unwrap();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  let body: String = (1..=12).map(|i| format!("line {i}\n")).collect();
+  let util: String = (1..=10).map(|i| format!("u{i}\n")).collect();
+  std::fs::write(work.join("a.txt"), &body).unwrap();
+  std::fs::write(work.join("util.txt"), &util).unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&work, "git checkout -b feature");
+  run(&work, "git mv a.txt b.txt");
+  std::fs::write(work.join("b.txt"), body.replace("line 6\n", "line six\n")).unwrap();
+  std::fs::write(work.join("copy.txt"), format!("{util}u11\n")).unwrap();
+  std::fs::write(work.join("util.txt"), util.replace("u10\n", "u ten\n")).unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m change");
+
+  let diff = |threshold: Option<i32>, copies: Option<bool>| crate::diff::refs::diff_refs(GitDiffOptions{
+    baseRef: Some("main".into()),
+    headRef: "feature".into(),
+    originPathOverride: Some(work.to_string_lossy().to_string()),
+    includePatch: Some(true),
+    renameThreshold: threshold,
+    detectCopies: copies,
+    ..Default::default()
+  }).unwrap();
+  let statuses = |out: &[crate::types::DiffEntry]| -> Vec<(String, String)> {
+    out.iter().map(|e| (e.filePath.clone(), e.status.clone())).collect()
+  };
+
+  let pairs = |v: &[(&str, &str)]| -> Vec<(String, String)> { v.iter().map(|(p, s)| (p.to_string(), s.to_string())).collect() };
+
+  let out = diff(None, None);
+  assert_eq!(statuses(&out), pairs(&[("b.txt", "renamed"), ("copy.txt", "added"), ("util.txt", "modified")]));
+  let b = &out[0];
+  assert_eq!(b.oldPath.as_deref(), Some("a.txt"));
+  assert_eq!((b.additions, b.deletions, b.similarity), (1, 1, Some(92)));
+  assert!(b.patch.as_deref().unwrap().contains("similarity index 92%\nrename from a.txt\nrename to b.txt\n"));
+
+  let out = diff(None, Some(true));
+  assert_eq!(statuses(&out), pairs(&[("b.txt", "renamed"), ("copy.txt", "copied"), ("util.txt", "modified")]));
+  let copy = &out[1];
+  assert_eq!(copy.oldPath.as_deref(), Some("util.txt"));
+  assert_eq!((copy.additions, copy.deletions), (1, 0));
+
+  // The rename and copy patches still turn main into feature.
+  let patch: String = out.iter().filter_map(|e| e.patch.clone()).collect();
+  std::fs::write(tmp.path().join("similar.patch"), patch).unwrap();
+  run(&work, "git checkout -q main");
+  run(&work, "git apply ../similar.patch");
+  run(&work, "git add -A");
+  run(&work, "git diff --cached --quiet feature");
+  run(&work, "git reset -q --hard");
+
+  let out = diff(Some(100), None);
+  assert_eq!(
+    statuses(&out),
+    pairs(&[("a.txt", "deleted"), ("b.txt", "added"), ("copy.txt", "added"), ("util.txt", "modified")])
+  );
+}
+
+#[test]
+fn refs_diff_limits_to_pathspecs() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(work.join("src/nested")).unwrap();
+  std::fs::create_dir_all(work.join("docs")).unwrap();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  std::fs::write(work.join("src/lib.rs"), b"fn a() {}\n").unwrap();
+  std::fs::write(work.join("docs/guide.md"), b"# guide\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&work, "git checkout -b feature");
+  std::fs::write(work.join("src/lib.rs"), b"fn a() {}\nfn b() {}\n").unwrap();
+  std::fs::write(work.join("src/nested/package-lock.json"), b"{}\n").unwrap();
+  std::fs::write(work.join("docs/guide.md"), b"# Guide\n").unwrap();
+  std::fs::write(work.join("package-lock.json"), b"{}\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m change");
+
+  let paths = |specs: &[&str]| -> Vec<String> {
+    let out = crate::diff::refs::diff_refs(GitDiffOptions{
+      baseRef: Some("main".into()),
+      headRef: "feature".into(),
+      originPathOverride: Some(work.to_string_lossy().to_string()),
+      pathspecs: Some(specs.iter().map(|s| s.to_string()).collect()),
+      ..Default::default()
+    }).unwrap();
+    out.into_iter().map(|e| e.filePath).collect()
+  };
+  assert_eq!(paths(&["src/**"]), vec!["src/lib.rs", "src/nested/package-lock.json"]);
+  assert_eq!(paths(&[":!**/package-lock.json"]), vec!["docs/guide.md", "src/lib.rs"]);
+  assert_eq!(paths(&["src", ":!**/package-lock.json"]), vec!["src/lib.rs"]);
+  assert!(paths(&["missing/**"]).is_empty());
+}
+
+#[test]
+fn refs_diff_reports_submodule_commits() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(&work).unwrap();
+  let repo = work.to_string_lossy().to_string();
+  let head = || crate::util::run_git(&repo, &["rev-parse", "HEAD"]).unwrap().trim().to_string();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  std::fs::write(work.join("a.txt"), b"a\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  let c1 = head();
+  // Gitlinks only need an index entry; the submodule's own repository is never read.
+  run(&work, &format!("git update-index --add --cacheinfo 160000,{c1},sub"));
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m add-sub");
+  let c2 = head();
+  run(&work, "git checkout -b feature");
+  run(&work, &format!("git update-index --cacheinfo 160000,{c2},sub"));
+  run(&work, &format!("git update-index --add --cacheinfo 160000,{c1},libs/other"));
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m bump");
+
+  let out = crate::diff::refs::diff_refs(GitDiffOptions{
+    baseRef: Some("main".into()),
+    headRef: "feature".into(),
+    originPathOverride: Some(repo.clone()),
+    includePatch: Some(true),
+    ..Default::default()
+  }).unwrap();
+  assert_eq!(out.len(), 2);
+  let other = &out[0];
+  assert_eq!((other.filePath.as_str(), other.status.as_str()), ("libs/other", "submodule"));
+  assert_eq!((other.oldSubmoduleSha.as_deref(), other.newSubmoduleSha.as_deref()), (None, Some(c1.as_str())));
+  let sub = &out[1];
+  assert_eq!((sub.filePath.as_str(), sub.status.as_str(), sub.isBinary), ("sub", "submodule", false));
+  assert_eq!((sub.oldSubmoduleSha.as_deref(), sub.newSubmoduleSha.as_deref()), (Some(c1.as_str()), Some(c2.as_str())));
+  assert_eq!(
+    sub.patch.as_deref(),
+    Some(format!("diff --git a/sub b/sub\n--- a/sub\n+++ b/sub\n@@ -1 +1 @@\n-Subproject commit {c1}\n+Subproject commit {c2}\n").as_str())
+  );
+}
+
+#[cfg(unix)]
+#[test]
+fn refs_diff_reports_mode_and_type_changes() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(&work).unwrap();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  std::fs::write(work.join("run.sh"), b"echo hi\n").unwrap();
+  std::fs::write(work.join("target.txt"), b"target\n").unwrap();
+  std::fs::write(work.join("link.txt"), b"plain\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&work, "git checkout -b feature");
+  run(&work, "chmod +x run.sh && rm link.txt && ln -s target.txt link.txt");
+  run(&work, "git add -A");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m modes");
+
+  let out = crate::diff::refs::diff_refs(GitDiffOptions{
+    baseRef: Some("main".into()),
+    headRef: "feature".into(),
+    originPathOverride: Some(work.to_string_lossy().to_string()),
+    includePatch: Some(true),
+    ..Default::default()
+  }).unwrap();
+  assert_eq!(out.len(), 2);
+  let link = &out[0];
+  assert_eq!((link.filePath.as_str(), link.status.as_str()), ("link.txt", "modified"));
+  assert_eq!((link.oldMode.as_deref(), link.newMode.as_deref()), (Some("100644"), Some("120000")));
+  assert_eq!(link.typeChanged, Some(true));
+  let script = &out[1];
+  assert_eq!((script.filePath.as_str(), script.additions, script.deletions), ("run.sh", 0, 0));
+  assert_eq!((script.oldMode.as_deref(), script.newMode.as_deref()), (Some("100644"), Some("100755")));
+  assert_eq!(script.typeChanged, None);
+  assert_eq!(script.patch.as_deref(), Some("diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n"));
+}
+
+#[test]
+fn refs_diff_fetches_blobs_missing_from_a_partial_clone() {
+  let tmp = tempdir().unwrap();
+  let origin = tmp.path().join("origin");
+  std::fs::create_dir_all(&origin).unwrap();
+  run(&origin, "git init");
+  run(&origin, "git -c user.email=a@b -c user.name=test checkout -b main");
+  run(&origin, "git config uploadpack.allowFilter true");
+  run(&origin, "git config uploadpack.allowAnySHA1InWant true");
+  std::fs::write(origin.join("a.txt"), b"one\n").unwrap();
+  run(&origin, "git add .");
+  run(&origin, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&origin, "git checkout -b feature");
+  std::fs::write(origin.join("a.txt"), b"one\ntwo\n").unwrap();
+  run(&origin, "git -c user.email=a@b -c user.name=test commit -am change");
+  run(tmp.path(), &format!("git clone --filter=blob:none --no-checkout file://{} clone", origin.display()));
+
+  let clone = tmp.path().join("clone");
+  let repo = gix::open(&clone).unwrap();
+  let blob = repo.rev_parse_single("origin/feature:a.txt").unwrap().detach();
+  assert!(crate::repo::partial::is_partial(&repo));
+  assert!(!repo.has_object(blob));
+
+  let out = crate::diff::refs::diff_refs(GitDiffOptions{
+    baseRef: Some("origin/main".into()),
+    headRef: "origin/feature".into(),
+    originPathOverride: Some(clone.to_string_lossy().to_string()),
+    ..Default::default()
+  }).unwrap();
+  assert_eq!(out.len(), 1);
+  assert_eq!((out[0].additions, out[0].deletions), (1, 0));
+  assert_eq!(out[0].newContent.as_deref(), Some("one\ntwo\n"));
+}
+
+#[test]
+fn refs_diff_text_only_collapses_denylisted_files() {
+  let tmp = tempdir().unwrap();
+  let work = tmp.path().join("repo");
+  std::fs::create_dir_all(&work).unwrap();
+  run(&work, "git init");
+  run(&work, "git -c user.email=a@b -c user.name=test checkout -b main");
+  std::fs::write(work.join("a.txt"), b"a1\n").unwrap();
+  std::fs::write(work.join("pnpm-lock.yaml"), b"lock: 1\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m init");
+  run(&work, "git checkout -b feature");
+  std::fs::write(work.join("a.txt"), b"a1\na2\n").unwrap();
+  std::fs::write(work.join("pnpm-lock.yaml"), b"lock: 2\nmore: yes\n").unwrap();
+  std::fs::write(work.join("app.js.map"), b"{}\n").unwrap();
+  run(&work, "git add .");
+  run(&work, "git -c user.email=a@b -c user.name=test commit -m change");
+
+  let opts = |text_only: Option<bool>, patterns: Option<Vec<String>>| GitDiffOptions{
+    baseRef: Some("main".into()),
+    headRef: "feature".into(),
+    repoFullName: None,
+    repoUrl: None,
+    teamSlugOrId: None,
+    originPathOverride: Some(work.to_string_lossy().to_string()),
+    includeContents: Some(true),
+    maxBytes: Some(1024*1024),
+    lastKnownBaseSha: None,
+    lastKnownMergeCommitSha: None,
+    textOnly: text_only,
+    collapsePatterns: patterns,
+    offline: None,
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    renameThreshold: None,
+    detectCopies: None,
+    pathspecs: None,
+    cancelId: None,
+    authToken: None,
+    useSsh: None,
+    providerBaseUrl: None,
+    partialClone: None,
+    fetchWindowMs: None,
+  };
+
+  let out = crate::diff::refs::diff_refs(opts(Some(true), None)).unwrap();
+  let lock = out.iter().find(|e| e.filePath == "pnpm-lock.yaml").expect("has lockfile");
+  assert_eq!(lock.collapsed, Some(true));
+  assert_eq!(lock.status, "modified");
+  assert_eq!((lock.additions, lock.deletions), (0, 0));
+  assert!(lock.newContent.is_none());
+  assert_eq!(lock.oldSize, Some(8));
+  assert_eq!(lock.newSize, Some(18));
+  let map = out.iter().find(|e| e.filePath == "app.js.map").expect("has map");
+  assert_eq!(map.collapsed, Some(true));
+  assert_eq!(map.status, "added");
+  let text = out.iter().find(|e| e.filePath == "a.txt").expect("has a.txt");
+  assert_eq!(text.collapsed, None);
+  assert_eq!(text.additions, 1);
+
+  // Custom patterns replace the defaults.
+  let out = crate::diff::refs::diff_refs(opts(Some(true), Some(vec!["a.txt".into()]))).unwrap();
+  assert_eq!(out.iter().find(|e| e.filePath == "a.txt").unwrap().collapsed, Some(true));
+  assert_eq!(out.iter().find(|e| e.filePath == "pnpm-lock.yaml").unwrap().collapsed, None);
+
+  // Without textOnly nothing is collapsed.
+  let out = crate::diff::refs::diff_refs(opts(None, None)).unwrap();
+  assert!(out.iter().all(|e| e.collapsed.is_none()));
+}
+
 #[test]
 fn refs_merge_base_after_merge_is_branch_tip() {
   let tmp = tempdir().unwrap();
@@ -553,6 +924,22 @@ fn refs_merge_base_after_merge_is_branch_tip() {
     maxBytes: Some(1024*1024),
     lastKnownBaseSha: None,
     lastKnownMergeCommitSha: None,
+    textOnly: None,
+    collapsePatterns: None,
+    offline: None,
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    renameThreshold: None,
+    detectCopies: None,
+    pathspecs: None,
+    cancelId: None,
+    authToken: None,
+    useSsh: None,
+    providerBaseUrl: None,
+    partialClone: None,
+    fetchWindowMs: None,
   }).unwrap();
   assert_eq!(out.len(), 0, "Expected no differences after merge, got: {:?}", out);
 }
@@ -584,6 +971,22 @@ fn refs_diff_numstat_matches_known_pairs() {
       maxBytes: Some(10*1024*1024),
       lastKnownBaseSha: None,
       lastKnownMergeCommitSha: None,
+      textOnly: None,
+      collapsePatterns: None,
+      offline: None,
+      includePatch: None,
+      includeHunks: None,
+      includeIntraline: None,
+      patchContext: None,
+      renameThreshold: None,
+      detectCopies: None,
+      pathspecs: None,
+      cancelId: None,
+      authToken: None,
+      useSsh: None,
+      providerBaseUrl: None,
+      partialClone: None,
+      fetchWindowMs: None,
     }).expect("diff refs");
     let adds: i32 = out.iter().map(|e| e.additions).sum();
     let dels: i32 = out.iter().map(|e| e.deletions).sum();
@@ -635,6 +1038,22 @@ fn refs_diff_handles_binary_files() {
     maxBytes: Some(1024*1024),
     lastKnownBaseSha: None,
     lastKnownMergeCommitSha: None,
+    textOnly: None,
+    collapsePatterns: None,
+    offline: None,
+    includePatch: None,
+    includeHunks: None,
+    includeIntraline: None,
+    patchContext: None,
+    renameThreshold: None,
+    detectCopies: None,
+    pathspecs: None,
+    cancelId: None,
+    authToken: None,
+    useSsh: None,
+    providerBaseUrl: None,
+    partialClone: None,
+    fetchWindowMs: None,
   }).expect("diff refs binary");
 
   let bin_entry = out.iter().find(|e| e.filePath == "bin.dat").expect("binary entry");
@@ -17,17 +17,89 @@ pub struct DiffEntry {
   pub newSize: Option<i32>,
   pub patchSize: Option<i32>,
   pub patch: Option<String>,
+  /// Set when the file matched the `textOnly` denylist (or is binary under `textOnly`); only
+  /// sizes are reported.
+  pub collapsed: Option<bool>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+  /// Set under `includeHunks`: the changes, grouped with `patchContext` lines of context.
+  pub hunks: Option<Vec<DiffHunk>>,
+  /// Percent of lines shared with `oldPath`, for renames and copies paired by content.
+  pub similarity: Option<i32>,
+  /// Git mode before the change (`100644`, `100755`, `120000` for a symlink, `160000` for a
+  /// submodule); unset for added files.
+  pub oldMode: Option<String>,
+  /// Git mode after the change; unset for deleted files.
+  pub newMode: Option<String>,
+  /// Set when a regular file became a symlink or the other way around.
+  pub typeChanged: Option<bool>,
+  /// For `submodule` entries: the commit pinned before, unset when the submodule was added.
+  pub oldSubmoduleSha: Option<String>,
+  /// For `submodule` entries: the commit pinned after, unset when the submodule was removed.
+  pub newSubmoduleSha: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct DiffHunk {
+  /// `@@ -oldStart,oldLines +newStart,newLines @@`, as in a unified diff.
+  pub header: String,
+  pub oldStart: i32,
+  pub oldLines: i32,
+  pub newStart: i32,
+  pub newLines: i32,
+  pub lines: Vec<DiffLine>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct DiffLine {
+  /// `context`, `add` or `delete`.
+  pub tag: String,
+  /// The line without its newline.
+  pub content: String,
+  /// 1-based; unset for added lines.
+  pub oldLineNumber: Option<i32>,
+  /// 1-based; unset for deleted lines.
+  pub newLineNumber: Option<i32>,
+  /// Set on the last line of a file that does not end with a newline.
+  pub noNewlineAtEnd: Option<bool>,
+  /// Set under `includeIntraline` on changed lines paired with a similar line: the stretches
+  /// of `content` that differ from it.
+  pub spans: Option<Vec<DiffSpan>>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct DiffSpan {
+  /// UTF-16 offset into `content`, so it indexes JS strings directly.
+  pub start: i32,
+  /// Exclusive UTF-16 offset.
+  pub end: i32,
 }
 
 #[napi(object)]
 #[derive(Default, Debug, Clone)]
 pub struct BranchInfo {
   pub name: String,
+  /// Tip commit.
   pub lastCommitSha: Option<String>,
+  /// Committer time of the tip, in milliseconds since the epoch.
   pub lastActivityAt: Option<i64>,
   pub isDefault: Option<bool>,
   pub lastKnownBaseSha: Option<String>,
   pub lastKnownMergeCommitSha: Option<String>,
+  /// Author of the tip commit.
+  pub authorName: Option<String>,
+  pub authorEmail: Option<String>,
+  /// Commits on this branch that the default branch lacks; unset without an `origin/HEAD`.
+  pub ahead: Option<i32>,
+  /// Commits on the default branch that this branch lacks.
+  pub behind: Option<i32>,
+  /// Every commit of the branch is on the default branch, like `git branch --merged`.
+  pub isMerged: Option<bool>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
 }
 
 #[napi(object)]
@@ -36,6 +108,22 @@ pub struct GitListRemoteBranchesOptions {
   pub repoFullName: Option<String>,
   pub repoUrl: Option<String>,
   pub originPathOverride: Option<String>,
+  /// Never clone or fetch; answer from the cache and mark results `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
 }
 
 #[cfg(test)]
@@ -44,6 +132,11 @@ pub struct GitDiffWorkspaceOptions {
   pub worktreePath: String,
   pub includeContents: Option<bool>,
   pub maxBytes: Option<i32>,
+  pub includePatch: Option<bool>,
+  pub includeHunks: Option<bool>,
+  pub includeIntraline: Option<bool>,
+  pub patchContext: Option<i32>,
+  pub pathspecs: Option<Vec<String>>,
 }
 
 #[napi(object)]
@@ -51,6 +144,8 @@ pub struct GitDiffWorkspaceOptions {
 pub struct GitDiffOptions {
   pub headRef: String,
   pub baseRef: Option<String>,
+  /// `owner/repo` on GitHub, or provider-prefixed: `gitlab:group/project`,
+  /// `bitbucket:workspace/repo`, `azure:organization/project/repo`.
   pub repoFullName: Option<String>,
   pub repoUrl: Option<String>,
   pub teamSlugOrId: Option<String>,
@@ -59,4 +154,507 @@ pub struct GitDiffOptions {
   pub maxBytes: Option<i32>,
   pub lastKnownBaseSha: Option<String>,
   pub lastKnownMergeCommitSha: Option<String>,
+  /// Collapse generated/noisy files (lockfiles, snapshots, source maps) and binaries instead
+  /// of returning their contents and line counts.
+  pub textOnly: Option<bool>,
+  /// Replaces the default `textOnly` denylist. Supports `*.ext`, `dir/` and exact file names.
+  pub collapsePatterns: Option<Vec<String>>,
+  /// Never clone or fetch; answer from the cache and mark entries `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Fill `patch` with a unified diff `git apply` accepts, for entries within `maxBytes`.
+  pub includePatch: Option<bool>,
+  /// Fill `hunks` so callers can render changes without diffing contents themselves. Pair with
+  /// `includeContents: false` to keep payloads small.
+  pub includeHunks: Option<bool>,
+  /// Add word-level `spans` to changed lines in `hunks`; implies `includeHunks`.
+  pub includeIntraline: Option<bool>,
+  /// Context lines around each hunk in `patch` and `hunks`; defaults to 3.
+  pub patchContext: Option<i32>,
+  /// Pair a deleted and an added file as a rename when at least this percent of their lines
+  /// match, like `git diff -M50%`; defaults to 50. 100 pairs only identical files.
+  pub renameThreshold: Option<i32>,
+  /// Also report added files similar to a deleted or modified one as `copied` from it.
+  pub detectCopies: Option<bool>,
+  /// Only diff matching paths: globs like `src/**` or `*.md`, and `:!pattern` to exclude.
+  pub pathspecs: Option<Vec<String>>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct BlameLineRange {
+  /// First line, 1-based.
+  pub start: i32,
+  /// Last line, inclusive; clamped to the end of the file.
+  pub end: i32,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitBlameOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  #[napi(js_name = "ref")]
+  pub refName: String,
+  pub filePath: String,
+  /// Only blame these lines of the file at `ref`.
+  pub lineRange: Option<BlameLineRange>,
+  /// Never clone or fetch; answer from the cache and mark lines `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct BlameLine {
+  /// Line in the file at `ref`, 1-based.
+  pub lineNumber: i32,
+  /// Line in the file as of `commitSha`, 1-based.
+  pub originalLineNumber: i32,
+  pub commitSha: String,
+  pub authorName: String,
+  pub authorEmail: String,
+  /// Author time in milliseconds since the epoch.
+  pub authorTime: i64,
+  pub summary: String,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitLogOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  #[napi(js_name = "ref")]
+  pub refName: String,
+  /// Commits returned per page; defaults to 50.
+  pub maxCount: Option<i32>,
+  /// Matching commits to pass over before the page starts.
+  pub skip: Option<i32>,
+  /// Only commits that change this file or directory.
+  pub pathFilter: Option<String>,
+  /// Only commits whose `Name <email>` contains this, ignoring case.
+  pub author: Option<String>,
+  /// Compute `stats` against the first parent; costs a tree diff per commit.
+  pub includeStats: Option<bool>,
+  /// Never clone or fetch; answer from the cache and mark commits `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct CommitStats {
+  pub filesChanged: i32,
+  pub additions: i32,
+  pub deletions: i32,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct LogCommit {
+  pub sha: String,
+  pub parents: Vec<String>,
+  pub authorName: String,
+  pub authorEmail: String,
+  /// Milliseconds since the epoch.
+  pub authorTime: i64,
+  pub committerName: String,
+  /// Milliseconds since the epoch.
+  pub committerTime: i64,
+  pub summary: String,
+  pub message: String,
+  pub stats: Option<CommitStats>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitFileHistoryOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  #[napi(js_name = "ref")]
+  pub refName: String,
+  /// The file as it is named at `ref`.
+  pub path: String,
+  /// Defaults to 50.
+  pub maxCount: Option<i32>,
+  /// Never clone or fetch; answer from the cache and mark entries `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct FileHistoryEntry {
+  pub sha: String,
+  /// The file's path in this commit.
+  pub path: String,
+  /// Set when this commit renamed the file from here.
+  pub previousPath: Option<String>,
+  /// `added`, `modified` or `renamed`.
+  pub status: String,
+  pub authorName: String,
+  pub authorEmail: String,
+  /// Milliseconds since the epoch.
+  pub authorTime: i64,
+  pub summary: String,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitAheadBehindOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  /// Branch, ref or sha to compare against, e.g. `main` (resolved as `origin/main` first).
+  pub base: String,
+  pub head: String,
+  /// Never clone or fetch; answer from the cache and mark the result `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct AheadBehind {
+  /// Commits on `head` that `base` lacks.
+  pub ahead: i32,
+  /// Commits on `base` that `head` lacks.
+  pub behind: i32,
+  /// Unset when the two share no history.
+  pub mergeBaseSha: Option<String>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitListTagsOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  /// Never clone or fetch; answer from the cache and mark tags `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct TagInfo {
+  /// Without `refs/tags/`.
+  pub name: String,
+  /// What the tag finally points at, usually a commit; annotated tags are peeled.
+  pub sha: String,
+  /// The tag object; unset for lightweight tags.
+  pub tagSha: Option<String>,
+  pub taggerName: Option<String>,
+  pub taggerEmail: Option<String>,
+  /// Tagger time for annotated tags, else the commit's committer time; milliseconds since the
+  /// epoch.
+  pub date: Option<i64>,
+  /// Annotation message; unset for lightweight tags.
+  pub message: Option<String>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitCommitInfoOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  /// Commit sha, or any ref resolving to a commit.
+  pub sha: String,
+  /// Never clone or fetch; answer from the cache and mark the result `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct CommitFileStat {
+  pub path: String,
+  /// `added`, `modified` or `deleted`; renames show as a deletion and an addition.
+  pub status: String,
+  pub additions: i32,
+  pub deletions: i32,
+  /// Binary files have no line counts.
+  pub binary: bool,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct CommitDetails {
+  pub sha: String,
+  pub parents: Vec<String>,
+  pub authorName: String,
+  pub authorEmail: String,
+  /// Milliseconds since the epoch.
+  pub authorTime: i64,
+  pub committerName: String,
+  pub committerEmail: String,
+  /// Milliseconds since the epoch.
+  pub committerTime: i64,
+  pub summary: String,
+  pub message: String,
+  /// Totals of `files`.
+  pub stats: CommitStats,
+  /// Files changed against the first parent, by path.
+  pub files: Vec<CommitFileStat>,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitIsLandedOptions {
+  pub repoFullName: Option<String>,
+  pub repoUrl: Option<String>,
+  pub originPathOverride: Option<String>,
+  /// Branch head landed on, e.g. `main`; a ref that does not resolve is retried as `origin/<ref>`.
+  pub baseRef: String,
+  pub headRef: String,
+  /// Base's tip when head was opened. Without it only merge commits are detected.
+  pub b0Ref: Option<String>,
+  /// Never clone or fetch; answer from the cache and mark the result `stale` instead.
+  pub offline: Option<bool>,
+  /// Token for private HTTPS repos, e.g. a GitHub App installation token. Only handed to git
+  /// for the call; never written to the cached clone.
+  pub authToken: Option<String>,
+  /// Clone `repoFullName` over SSH, authenticating through the user's ssh-agent.
+  pub useSsh: Option<bool>,
+  /// Base URL of a self-hosted provider, e.g. `https://gitlab.example.com`; `repoFullName` is
+  /// resolved against it.
+  pub providerBaseUrl: Option<String>,
+  /// Clone blobless (`--filter=blob:none`) when the repo is not cached yet, fetching blobs as
+  /// they are read. Defaults to `CMUX_GIT_PARTIAL_CLONE`.
+  pub partialClone: Option<bool>,
+  /// Fetch synchronously when the cached clone was last fetched longer ago than this, and in the
+  /// background otherwise. Defaults to the configured window.
+  pub fetchWindowMs: Option<i64>,
+  /// Tag for `git_cancel`; a cancelled call fails with status `Cancelled`.
+  pub cancelId: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct LandedInfo {
+  /// Whether a commit on base's first-parent history brought head in.
+  pub landed: bool,
+  /// `merge`, `squash` or `fast-forward`; squashes and fast-forwards need `b0Ref`.
+  pub strategy: Option<String>,
+  /// The commit that landed head: the merge, the squashed commit, or the newest commit of a
+  /// fast-forward.
+  pub mergeCommitSha: Option<String>,
+  /// Its parents, first parent first.
+  pub mergeParents: Vec<String>,
+  /// Diffing `diffBaseSha` to `diffHeadSha` shows what landed.
+  pub diffBaseSha: Option<String>,
+  pub diffHeadSha: Option<String>,
+  /// Whether base contains head's tip, so nothing on head is left to land. False after a squash.
+  pub headContained: bool,
+  /// Set under `offline` when the cached clone could not be refreshed within the fetch window.
+  pub stale: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitApplyPatchOptions {
+  pub worktreePath: String,
+  /// Unified diff, as `git diff` prints it; plain `diff -u` output works too.
+  pub patch: String,
+  /// Merge files whose hunks no longer match from the preimage blob named by the patch's
+  /// `index` lines, like `git apply --3way`.
+  pub threeWay: Option<bool>,
+  /// Only check that the patch applies; write nothing.
+  pub checkOnly: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct AppliedFile {
+  pub path: String,
+  /// Set for renames and copies.
+  pub oldPath: Option<String>,
+  /// `added`, `modified`, `deleted`, `renamed` or `copied`.
+  pub status: String,
+  /// `applied`, `merged` (three-way, cleanly), `conflicted` (written with conflict markers) or
+  /// `rejected`.
+  pub outcome: String,
+  /// Why the file was rejected, e.g. which hunk did not apply.
+  pub error: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct ApplyPatchResult {
+  /// False when any file was rejected, in which case nothing was written.
+  pub applied: bool,
+  /// Every file of the patch, in patch order.
+  pub files: Vec<AppliedFile>,
+  /// Files written with conflict markers.
+  pub conflicts: Vec<String>,
+}
+
+/// One progress report from a clone or fetch of the repo cache, as git prints it.
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitProgress {
+  /// git's name for the step, e.g. `Receiving objects` or `Resolving deltas`.
+  pub phase: String,
+  pub percent: Option<i32>,
+  /// Objects handled so far in this phase.
+  pub objects: Option<i64>,
+  pub totalObjects: Option<i64>,
+  /// Bytes received so far; only reported while receiving objects.
+  pub receivedBytes: Option<i64>,
+}
+
+/// Settings for `git_cache_configure`. Omitted fields keep their current value.
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct GitCacheConfig {
+  /// Directory holding the cached clones; empty restores the default.
+  pub root: Option<String>,
+  /// Defaults to 20.
+  pub maxRepos: Option<i32>,
+  /// Total size of the cached clones; 0 for no limit, the default.
+  pub maxBytes: Option<i64>,
+  /// Clones unused for this many days are evicted; 0 to keep them, the default.
+  pub ttlDays: Option<f64>,
+  /// How long after a fetch calls answer from the clone as is, fetching in the background.
+  /// Defaults to `CMUX_GIT_FETCH_WINDOW_MS`, else 5 seconds.
+  pub fetchWindowMs: Option<i64>,
+  /// How often recently used clones are fetched in the background; 0 turns that off. Defaults
+  /// to `CMUX_GIT_REFRESH_INTERVAL_MS`, else a minute.
+  pub refreshIntervalMs: Option<i64>,
+}
+
+#[napi(object)]
+#[derive(Default, Debug, Clone)]
+pub struct CachedRepo {
+  /// Directory name under the cache root; accepted by `git_cache_evict` and `git_cache_export`.
+  pub slug: String,
+  pub path: String,
+  pub sizeBytes: i64,
+  /// Milliseconds since the epoch.
+  pub lastAccessTime: i64,
+  /// Milliseconds since the epoch; unset until the clone is first fetched.
+  pub lastFetchTime: Option<i64>,
+  /// How long the last fetch took.
+  pub lastFetchDurationMs: Option<i64>,
+  /// Why the last fetch failed; unset when it succeeded.
+  pub lastFetchError: Option<String>,
 }
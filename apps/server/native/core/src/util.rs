@@ -1,10 +1,28 @@
 use anyhow::{anyhow, Result};
-use std::process::{Command, Stdio};
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+// How often a running git child process is checked for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type LineHandler = Box<dyn FnMut(&str) + Send>;
 
 pub fn run_git(cwd: &str, args: &[&str]) -> Result<String> {
   let mut cmd = Command::new("git");
-  cmd.current_dir(cwd).args(args).stdin(Stdio::null());
-  let output = cmd.output()?;
+  cmd.current_dir(cwd).stdin(Stdio::null());
+  crate::repo::auth::apply(&mut cmd);
+  let progress = crate::progress::current();
+  // git only reports progress to a terminal unless asked to.
+  match args.split_first() {
+    Some((sub, rest)) if progress.is_some() && (*sub == "clone" || *sub == "fetch") => {
+      cmd.arg(sub).arg("--progress").args(rest);
+    }
+    _ => {
+      cmd.args(args);
+    }
+  }
+  let output = output(&mut cmd, progress.map(crate::progress::stderr_reporter))?;
   if output.status.success() {
     Ok(String::from_utf8_lossy(&output.stdout).into_owned())
   } else {
@@ -12,3 +30,56 @@ pub fn run_git(cwd: &str, args: &[&str]) -> Result<String> {
     Err(anyhow!("git {:?} failed: {}", args, err))
   }
 }
+
+// `cmd.output()`, except that inside a cancellable call the child is killed once the call is
+// cancelled, and `on_stderr` sees stderr line by line as it arrives.
+fn output(cmd: &mut Command, on_stderr: Option<LineHandler>) -> Result<Output> {
+  if !crate::cancel::in_scope() && on_stderr.is_none() {
+    return Ok(cmd.output()?);
+  }
+  crate::cancel::check()?;
+  let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+  let stdout = drain(child.stdout.take(), None);
+  let stderr = drain(child.stderr.take(), on_stderr);
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if let Err(e) = crate::cancel::check() {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(e);
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  };
+  Ok(Output {
+    status,
+    stdout: stdout.join().unwrap_or_default(),
+    stderr: stderr.join().unwrap_or_default(),
+  })
+}
+
+// Pipes are drained on their own threads so a chatty child never blocks on a full pipe. Lines
+// end at `\r` as well as `\n`, since progress meters redraw with `\r`.
+fn drain<R: Read + Send + 'static>(pipe: Option<R>, mut on_line: Option<LineHandler>) -> std::thread::JoinHandle<Vec<u8>> {
+  std::thread::spawn(move || {
+    let mut buf = Vec::new();
+    let Some(mut pipe) = pipe else { return buf };
+    let mut chunk = [0u8; 8192];
+    let mut line_start = 0;
+    loop {
+      let n = match pipe.read(&mut chunk) {
+        Ok(0) | Err(_) => break,
+        Ok(n) => n,
+      };
+      buf.extend_from_slice(&chunk[..n]);
+      if let Some(handler) = on_line.as_mut() {
+        while let Some(len) = buf[line_start..].iter().position(|b| *b == b'\r' || *b == b'\n') {
+          handler(&String::from_utf8_lossy(&buf[line_start..line_start + len]));
+          line_start += len + 1;
+        }
+      }
+    }
+    buf
+  })
+}
@@ -1,3 +1,4 @@
+import { randomUUID } from "node:crypto";
 import * as fs from "node:fs";
 import { createRequire } from "node:module";
 import * as path from "node:path";
@@ -8,6 +9,7 @@ import type { ReplaceDiffEntry } from "@cmux/shared/diff-types";
 export interface GitDiffOptions {
   headRef: string;
   baseRef?: string;
+  /** "owner/repo" on GitHub, or "gitlab:group/project", "bitbucket:workspace/repo", "azure:org/project/repo". */
   repoFullName?: string;
   repoUrl?: string;
   teamSlugOrId?: string;
@@ -16,16 +18,436 @@ export interface GitDiffOptions {
   maxBytes?: number;
   lastKnownBaseSha?: string;
   lastKnownMergeCommitSha?: string;
+  textOnly?: boolean;
+  collapsePatterns?: string[];
+  /** Never clone or fetch; entries are marked `stale` when the cache is past the fetch window. */
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Fill `patch` with a unified diff that `git apply` accepts. */
+  includePatch?: boolean;
+  /** Fill `hunks` for rendering; pair with `includeContents: false` to keep payloads small. */
+  includeHunks?: boolean;
+  /** Add word-level `spans` to changed hunk lines; implies `includeHunks`. */
+  includeIntraline?: boolean;
+  /** Context lines around each hunk in `patch` and `hunks`; defaults to 3. */
+  patchContext?: number;
+  /** Minimum percent of matching lines to pair a deleted and an added file as a rename; defaults to 50. */
+  renameThreshold?: number;
+  /** Also report added files similar to a deleted or modified one as `copied`. */
+  detectCopies?: boolean;
+  /** Only diff matching paths: globs like `src/**`, and `:!pattern` to exclude. */
+  pathspecs?: string[];
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
 }
 
+export interface GitBlameOptions {
+  ref: string;
+  filePath: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  /** 1-based, inclusive; `end` is clamped to the file's length. */
+  lineRange?: { start: number; end: number };
+  /** Never clone or fetch; lines are marked `stale` when the cache is past the fetch window. */
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface BlameLine {
+  lineNumber: number;
+  /** The line's number as of `commitSha`. */
+  originalLineNumber: number;
+  commitSha: string;
+  authorName: string;
+  authorEmail: string;
+  /** Milliseconds since the epoch. */
+  authorTime: number;
+  summary: string;
+  stale?: boolean;
+}
+
+export interface GitLogOptions {
+  ref: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  /** Commits per page; defaults to 50. */
+  maxCount?: number;
+  skip?: number;
+  /** Only commits that change this file or directory. */
+  pathFilter?: string;
+  /** Case-insensitive substring of `Name <email>`. */
+  author?: string;
+  includeStats?: boolean;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface LogCommit {
+  sha: string;
+  parents: string[];
+  authorName: string;
+  authorEmail: string;
+  /** Milliseconds since the epoch. */
+  authorTime: number;
+  committerName: string;
+  committerTime: number;
+  summary: string;
+  message: string;
+  stats?: { filesChanged: number; additions: number; deletions: number };
+  stale?: boolean;
+}
+
+export interface GitFileHistoryOptions {
+  ref: string;
+  /** The file as it is named at `ref`. */
+  path: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  /** Defaults to 50. */
+  maxCount?: number;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface FileHistoryEntry {
+  sha: string;
+  /** The file's path in this commit. */
+  path: string;
+  /** Set when this commit renamed the file from `previousPath`. */
+  previousPath?: string;
+  status: "added" | "modified" | "renamed";
+  authorName: string;
+  authorEmail: string;
+  /** Milliseconds since the epoch. */
+  authorTime: number;
+  summary: string;
+  stale?: boolean;
+}
+
+export interface GitCommitInfoOptions {
+  /** Commit sha, or any ref resolving to a commit. */
+  sha: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface CommitDetails {
+  sha: string;
+  parents: string[];
+  authorName: string;
+  authorEmail: string;
+  /** Milliseconds since the epoch. */
+  authorTime: number;
+  committerName: string;
+  committerEmail: string;
+  /** Milliseconds since the epoch. */
+  committerTime: number;
+  summary: string;
+  message: string;
+  stats: { filesChanged: number; additions: number; deletions: number };
+  /** Files changed against the first parent, by path. */
+  files: Array<{
+    path: string;
+    status: "added" | "modified" | "deleted";
+    additions: number;
+    deletions: number;
+    binary: boolean;
+  }>;
+  stale?: boolean;
+}
+
+export interface GitApplyPatchOptions {
+  worktreePath: string;
+  /** Unified diff, as `git diff` prints it. */
+  patch: string;
+  /** Merge files whose hunks no longer match from the patch's preimage blobs, like `git apply --3way`. */
+  threeWay?: boolean;
+  /** Only check that the patch applies; write nothing. */
+  checkOnly?: boolean;
+}
+
+export interface ApplyPatchResult {
+  /** False when any file was rejected, in which case nothing was written. */
+  applied: boolean;
+  files: Array<{
+    path: string;
+    oldPath?: string;
+    status: "added" | "modified" | "deleted" | "renamed" | "copied";
+    outcome: "applied" | "merged" | "conflicted" | "rejected";
+    error?: string;
+  }>;
+  /** Files written with conflict markers. */
+  conflicts: string[];
+}
+
+export interface GitIsLandedOptions {
+  /** Branch head landed on; a ref that does not resolve is retried as `origin/<ref>`. */
+  baseRef: string;
+  headRef: string;
+  /** Base's tip when head was opened; needed to detect squashes and fast-forwards. */
+  b0Ref?: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface LandedInfo {
+  landed: boolean;
+  strategy?: "merge" | "squash" | "fast-forward";
+  /** The merge, the squashed commit, or the newest commit of a fast-forward. */
+  mergeCommitSha?: string;
+  /** Its parents, first parent first. */
+  mergeParents: string[];
+  /** Diffing `diffBaseSha` to `diffHeadSha` shows what landed. */
+  diffBaseSha?: string;
+  diffHeadSha?: string;
+  /** Base contains head's tip, so nothing is left to land; false after a squash. */
+  headContained: boolean;
+  stale?: boolean;
+}
+
+export interface GitAheadBehindOptions {
+  /** e.g. "main"; branch names resolve to `origin/<name>` first. */
+  base: string;
+  head: string;
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+  /** Tag for `gitCancel`; a cancelled call rejects with `code: "Cancelled"`. */
+  cancelId?: string;
+}
+
+export interface AheadBehind {
+  /** Commits on `head` that `base` lacks. */
+  ahead: number;
+  /** Commits on `base` that `head` lacks. */
+  behind: number;
+  /** Unset when the two share no history. */
+  mergeBaseSha?: string;
+  stale?: boolean;
+}
+
+export interface GitListTagsOptions {
+  repoFullName?: string;
+  repoUrl?: string;
+  originPathOverride?: string;
+  offline?: boolean;
+  /** Token for private HTTPS repos; only handed to git for this call. */
+  authToken?: string;
+  /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+  useSsh?: boolean;
+  /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+  providerBaseUrl?: string;
+  /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+  partialClone?: boolean;
+  /** Fetch inline when the clone is older than this, in the background otherwise. */
+  fetchWindowMs?: number;
+}
+
+export interface TagInfo {
+  name: string;
+  /** The tagged commit; annotated tags are peeled. */
+  sha: string;
+  /** The tag object; unset for lightweight tags. */
+  tagSha?: string;
+  taggerName?: string;
+  taggerEmail?: string;
+  /** Tagger time, else the commit's committer time; milliseconds since the epoch. */
+  date?: number;
+  /** Annotation message; unset for lightweight tags. */
+  message?: string;
+  stale?: boolean;
+}
+
+/** A clone or fetch progress report, as git prints it. */
+export interface GitProgress {
+  /** e.g. "Receiving objects" or "Resolving deltas". */
+  phase: string;
+  percent?: number;
+  objects?: number;
+  totalObjects?: number;
+  /** Only reported while receiving objects. */
+  receivedBytes?: number;
+}
+
+/** Repo cache settings; omitted fields keep their current value. */
+export interface GitCacheConfig {
+  /** Directory holding the cached clones; "" restores the default. */
+  root?: string;
+  /** Defaults to 20. */
+  maxRepos?: number;
+  /** Total size of the cached clones; 0 (the default) for no limit. */
+  maxBytes?: number;
+  /** Evict clones unused for this many days; 0 (the default) keeps them. */
+  ttlDays?: number;
+  /** Answer from the clone as is for this long after a fetch; defaults to 5 seconds. */
+  fetchWindowMs?: number;
+  /** How often recently used clones are fetched in the background; 0 turns it off. */
+  refreshIntervalMs?: number;
+}
+
+export interface CachedRepo {
+  /** Accepted by `gitCacheEvict` and `gitCacheExport`. */
+  slug: string;
+  path: string;
+  sizeBytes: number;
+  /** Milliseconds since the epoch. */
+  lastAccessTime: number;
+  /** Milliseconds since the epoch; unset until the clone is first fetched. */
+  lastFetchTime?: number;
+  lastFetchDurationMs?: number;
+  /** Why the last fetch failed; unset when it succeeded. */
+  lastFetchError?: string;
+}
+
+/** Called as the repo cache clones or fetches on behalf of a call. */
+export type GitProgressCallback = (progress: GitProgress) => void;
+
 type NativeGitModule = {
   // napi-rs exports as camelCase
-  gitDiff?: (opts: GitDiffOptions) => Promise<ReplaceDiffEntry[]>;
-  gitListRemoteBranches?: (opts: {
-    repoFullName?: string;
-    repoUrl?: string;
-    originPathOverride?: string;
-  }) => Promise<
+  gitDiff?: (
+    opts: GitDiffOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<ReplaceDiffEntry[]>;
+  gitBlame?: (
+    opts: GitBlameOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<BlameLine[]>;
+  gitLog?: (
+    opts: GitLogOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<LogCommit[]>;
+  gitFileHistory?: (
+    opts: GitFileHistoryOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<FileHistoryEntry[]>;
+  gitCommitInfo?: (
+    opts: GitCommitInfoOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<CommitDetails>;
+  gitIsLanded?: (
+    opts: GitIsLandedOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<LandedInfo>;
+  gitApplyPatch?: (opts: GitApplyPatchOptions) => Promise<ApplyPatchResult>;
+  gitAheadBehind?: (
+    opts: GitAheadBehindOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<AheadBehind>;
+  gitListTags?: (
+    opts: GitListTagsOptions,
+    onProgress?: GitProgressCallback
+  ) => Promise<TagInfo[]>;
+  gitCancel?: (id: string) => boolean;
+  gitClearDiffMemo?: () => Promise<void>;
+  gitCacheExport?: (slug: string, tarPath: string) => Promise<string>;
+  gitCacheImport?: (tarPath: string) => Promise<string>;
+  gitCacheConfigure?: (opts: GitCacheConfig) => Promise<void>;
+  gitCacheList?: () => Promise<CachedRepo[]>;
+  gitCacheEvict?: (slug: string) => Promise<boolean>;
+  gitCachePurge?: () => Promise<number>;
+  gitListRemoteBranches?: (
+    opts: {
+      repoFullName?: string;
+      repoUrl?: string;
+      originPathOverride?: string;
+      offline?: boolean;
+      /** Token for private HTTPS repos; only handed to git for this call. */
+      authToken?: string;
+      /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+      useSsh?: boolean;
+      /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+      providerBaseUrl?: string;
+      /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+      partialClone?: boolean;
+      /** Fetch inline when the clone is older than this, in the background otherwise. */
+      fetchWindowMs?: number;
+    },
+    onProgress?: GitProgressCallback
+  ) => Promise<
     Array<{
       name: string;
       lastCommitSha?: string;
@@ -33,6 +455,14 @@ type NativeGitModule = {
       isDefault?: boolean;
       lastKnownBaseSha?: string;
       lastKnownMergeCommitSha?: string;
+      authorName?: string;
+      authorEmail?: string;
+      /** Relative to the default branch; unset without an `origin/HEAD`. */
+      ahead?: number;
+      behind?: number;
+      /** Every commit is on the default branch. */
+      isMerged?: boolean;
+      stale?: boolean;
     }>
   >;
 };
@@ -94,19 +524,161 @@ export function loadNativeGit(): NativeGitModule | null {
   return cachedNative ?? null;
 }
 
-export async function gitDiff(opts: GitDiffOptions): Promise<ReplaceDiffEntry[]> {
+export async function gitDiff(
+  opts: GitDiffOptions,
+  onProgress?: GitProgressCallback
+): Promise<ReplaceDiffEntry[]> {
   const mod = loadNativeGit();
   if (!mod?.gitDiff) {
     throw new Error("Native gitDiff not available; rebuild @cmux/native-core");
   }
-  return mod.gitDiff(opts);
+  return mod.gitDiff(opts, onProgress);
 }
 
-export async function listRemoteBranches(opts: {
-  repoFullName?: string;
-  repoUrl?: string;
-  originPathOverride?: string;
-}): Promise<
+/** Per-line blame for `filePath` at `ref`, for blame gutters. */
+export async function gitBlame(
+  opts: GitBlameOptions,
+  onProgress?: GitProgressCallback
+): Promise<BlameLine[]> {
+  const mod = loadNativeGit();
+  if (!mod?.gitBlame) {
+    throw new Error("Native gitBlame not available; rebuild @cmux/native-core");
+  }
+  return mod.gitBlame(opts, onProgress);
+}
+
+/** One page of branch history, newest first; page with `skip` and `maxCount`. */
+export async function gitLog(
+  opts: GitLogOptions,
+  onProgress?: GitProgressCallback
+): Promise<LogCommit[]> {
+  const mod = loadNativeGit();
+  if (!mod?.gitLog) {
+    throw new Error("Native gitLog not available; rebuild @cmux/native-core");
+  }
+  return mod.gitLog(opts, onProgress);
+}
+
+/** Commits that changed a file, newest first, following it back through renames. */
+export async function gitFileHistory(
+  opts: GitFileHistoryOptions,
+  onProgress?: GitProgressCallback
+): Promise<FileHistoryEntry[]> {
+  const mod = loadNativeGit();
+  if (!mod?.gitFileHistory) {
+    throw new Error(
+      "Native gitFileHistory not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitFileHistory(opts, onProgress);
+}
+
+/** One commit's details and per-file stats, e.g. for a commit card. */
+export async function gitCommitInfo(
+  opts: GitCommitInfoOptions,
+  onProgress?: GitProgressCallback
+): Promise<CommitDetails> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCommitInfo) {
+    throw new Error(
+      "Native gitCommitInfo not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitCommitInfo(opts, onProgress);
+}
+
+/** Whether a branch landed on its base, and through which commit. */
+export async function gitIsLanded(
+  opts: GitIsLandedOptions,
+  onProgress?: GitProgressCallback
+): Promise<LandedInfo> {
+  const mod = loadNativeGit();
+  if (!mod?.gitIsLanded) {
+    throw new Error(
+      "Native gitIsLanded not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitIsLanded(opts, onProgress);
+}
+
+/** Apply a unified diff to a worktree, e.g. an agent-generated patch; all or nothing. */
+export async function gitApplyPatch(
+  opts: GitApplyPatchOptions
+): Promise<ApplyPatchResult> {
+  const mod = loadNativeGit();
+  if (!mod?.gitApplyPatch) {
+    throw new Error(
+      "Native gitApplyPatch not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitApplyPatch(opts);
+}
+
+/** How far `head` is ahead of and behind `base`, e.g. for "3 ahead / 2 behind origin/main". */
+export async function gitAheadBehind(
+  opts: GitAheadBehindOptions,
+  onProgress?: GitProgressCallback
+): Promise<AheadBehind> {
+  const mod = loadNativeGit();
+  if (!mod?.gitAheadBehind) {
+    throw new Error(
+      "Native gitAheadBehind not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitAheadBehind(opts, onProgress);
+}
+
+/** Cancel the native calls started with `cancelId: id`. */
+export function gitCancel(id: string): boolean {
+  const mod = loadNativeGit();
+  if (!mod?.gitCancel) {
+    throw new Error("Native gitCancel not available; rebuild @cmux/native-core");
+  }
+  return mod.gitCancel(id);
+}
+
+/** A fresh `cancelId` that aborting `signal` cancels. */
+export function cancelIdForSignal(signal: AbortSignal): string {
+  const id = randomUUID();
+  const cancel = () => {
+    loadNativeGit()?.gitCancel?.(id);
+  };
+  if (signal.aborted) {
+    cancel();
+  } else {
+    signal.addEventListener("abort", cancel, { once: true });
+  }
+  return id;
+}
+
+/** True for the rejection of a native call cancelled through its `cancelId`. */
+export function isNativeGitCancelled(error: unknown): boolean {
+  return (
+    typeof error === "object" &&
+    error !== null &&
+    (error as { code?: unknown }).code === "Cancelled"
+  );
+}
+
+export async function listRemoteBranches(
+  opts: {
+    repoFullName?: string;
+    repoUrl?: string;
+    originPathOverride?: string;
+    offline?: boolean;
+    /** Token for private HTTPS repos; only handed to git for this call. */
+    authToken?: string;
+    /** Clone `repoFullName` over SSH through the user's ssh-agent. */
+    useSsh?: boolean;
+    /** Self-hosted provider, e.g. "https://gitlab.example.com"; `repoFullName` resolves against it. */
+    providerBaseUrl?: string;
+    /** Clone blobless when not cached yet; blobs are fetched as they are read. */
+    partialClone?: boolean;
+    /** Fetch inline when the clone is older than this, in the background otherwise. */
+    fetchWindowMs?: number;
+  },
+  onProgress?: GitProgressCallback
+): Promise<
   Array<{
     name: string;
     lastCommitSha?: string;
@@ -114,6 +686,14 @@ export async function listRemoteBranches(opts: {
     isDefault?: boolean;
     lastKnownBaseSha?: string;
     lastKnownMergeCommitSha?: string;
+    authorName?: string;
+    authorEmail?: string;
+    /** Relative to the default branch; unset without an `origin/HEAD`. */
+    ahead?: number;
+    behind?: number;
+    /** Every commit is on the default branch. */
+    isMerged?: boolean;
+    stale?: boolean;
   }>
 > {
   const mod = loadNativeGit();
@@ -122,5 +702,82 @@ export async function listRemoteBranches(opts: {
       "Native gitListRemoteBranches not available; rebuild @cmux/native-core"
     );
   }
-  return mod.gitListRemoteBranches(opts);
+  return mod.gitListRemoteBranches(opts, onProgress);
+}
+
+/** The repo's tags, newest first, for picking them as refs next to branches. */
+export async function gitListTags(
+  opts: GitListTagsOptions,
+  onProgress?: GitProgressCallback
+): Promise<TagInfo[]> {
+  const mod = loadNativeGit();
+  if (!mod?.gitListTags) {
+    throw new Error(
+      "Native gitListTags not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitListTags(opts, onProgress);
+}
+
+/** Archive the cached clone for `slug` (owner/repo or URL) so CI can restore it later. */
+export async function gitCacheExport(
+  slug: string,
+  tarPath: string
+): Promise<string> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCacheExport) {
+    throw new Error(
+      "Native gitCacheExport not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitCacheExport(slug, tarPath);
+}
+
+/** Seed the git cache from an archive produced by `gitCacheExport`. */
+export async function gitCacheImport(tarPath: string): Promise<string> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCacheImport) {
+    throw new Error(
+      "Native gitCacheImport not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitCacheImport(tarPath);
+}
+
+/** Move or limit the git cache, e.g. for desktop users with small disks. */
+export async function gitCacheConfigure(opts: GitCacheConfig): Promise<void> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCacheConfigure) {
+    throw new Error(
+      "Native gitCacheConfigure not available; rebuild @cmux/native-core"
+    );
+  }
+  return mod.gitCacheConfigure(opts);
+}
+
+/** Cached clones, most recently used first, for the settings screen. */
+export async function gitCacheList(): Promise<CachedRepo[]> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCacheList) {
+    throw new Error("Native gitCacheList not available; rebuild @cmux/native-core");
+  }
+  return mod.gitCacheList();
+}
+
+/** Delete one cached clone; false when it was not cached. */
+export async function gitCacheEvict(slug: string): Promise<boolean> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCacheEvict) {
+    throw new Error("Native gitCacheEvict not available; rebuild @cmux/native-core");
+  }
+  return mod.gitCacheEvict(slug);
+}
+
+/** Delete every cached clone; resolves to how many were removed. */
+export async function gitCachePurge(): Promise<number> {
+  const mod = loadNativeGit();
+  if (!mod?.gitCachePurge) {
+    throw new Error("Native gitCachePurge not available; rebuild @cmux/native-core");
+  }
+  return mod.gitCachePurge();
 }
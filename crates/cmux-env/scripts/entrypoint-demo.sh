@@ -44,7 +44,7 @@ Try these:
   envctl ping
   envctl status
   envctl set FOO=bar
-  envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD"
+  envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD" --session "$$"
   envctl unset FOO
 
 Directory-scoped overlay:
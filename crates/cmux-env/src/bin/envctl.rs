@@ -1,17 +1,26 @@
 use std::fs::{self, File};
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use cmux_env::{
-    client_send, client_send_autostart, parse_dotenv, parse_dotenv_base64, Request, Response,
-    Scope, ShellKind,
+    client_send, client_send_autostart, client_watch, instance_socket_path, parse_dotenv,
+    parse_dotenv_base64, Definition, DumpFormat, HistoryEntry, Op, Origin, Request, Response,
+    Scope, ShellKind, Source,
 };
 
 #[derive(Parser, Debug)]
 #[command(name = "envctl", version, about = "Client for cmux-envd")]
 struct Cli {
+    /// Talk to the envd on this socket; also read from $CMUX_ENVD_SOCKET.
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "instance")]
+    socket: Option<PathBuf>,
+    /// Talk to the envd instance NAME; also read from $CMUX_ENVD_INSTANCE.
+    #[arg(long, global = true, value_name = "NAME")]
+    instance: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -23,18 +32,43 @@ enum Commands {
         kv: String,
         #[arg(long)]
         dir: Option<PathBuf>,
+        /// Store the value encrypted; `get` and `list` mask it and only `export` emits it.
+        #[arg(long)]
+        secret: bool,
+        /// Unset the key after this long: seconds, or a number with an s, m, h or d suffix.
+        #[arg(long, value_name = "DURATION", value_parser = parse_ttl)]
+        ttl: Option<u64>,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
     },
     /// Unset KEY. Optional --dir to scope to directory.
     Unset {
+        /// A key, or a pattern where `*` matches any run of characters, e.g. 'AWS_*'.
         key: String,
         #[arg(long)]
         dir: Option<PathBuf>,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
     },
     /// Reset environment variables, optionally scoped to a directory.
     Reset {
         #[arg(long)]
         dir: Option<PathBuf>,
     },
+    /// List directories with their own values, with how many and when they last changed
+    Scopes,
+    /// Unset everything in one directory scope (or the globals) as a single change
+    Clear {
+        #[arg(long, required_unless_present = "global", conflicts_with = "global")]
+        dir: Option<PathBuf>,
+        #[arg(long)]
+        global: bool,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
+    },
     /// Get effective value for KEY at PWD
     Get {
         key: String,
@@ -45,6 +79,18 @@ enum Commands {
     List {
         #[arg(long)]
         pwd: Option<PathBuf>,
+        /// Show which scope and write each value comes from.
+        #[arg(long)]
+        annotate: bool,
+        /// Only keys starting with PREFIX.
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Show every definition of KEY at PWD and where each came from
+    Describe {
+        key: String,
+        #[arg(long)]
+        pwd: Option<PathBuf>,
     },
     /// Load .env from file or stdin (-). Optional --dir to scope to directory.
     Load {
@@ -54,6 +100,38 @@ enum Commands {
         dir: Option<PathBuf>,
         #[arg(long, help = "Treat INPUT (or stdin) as base64-encoded content")]
         base64: bool,
+        /// Store every loaded value as a secret.
+        #[arg(long)]
+        secret: bool,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
+    },
+    /// Load a .env file and reload it whenever it changes. Optional --dir to scope to directory.
+    Track {
+        file: PathBuf,
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Stop reloading a tracked .env file; its values stay set
+    Untrack { file: PathBuf },
+    /// Run PATH/.envrc (or an .envrc file) and load what it exports into its directory's scope
+    Allow {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Unload an allowed .envrc and stop loading it
+    Deny {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Apply a JSON array of set, unset and load ops from file or stdin (-) as one change
+    Batch {
+        #[arg(value_name = "INPUT")]
+        input: String,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
     },
     /// Print export/unset script diff since GEN and bump gen
     Export {
@@ -62,8 +140,95 @@ enum Commands {
         since: u64,
         #[arg(long)]
         pwd: Option<PathBuf>,
+        /// Identifies the shell (e.g. its pid), so after a `cd` every value that differs in
+        /// the new directory is exported.
+        #[arg(long, value_name = "ID")]
+        session: Option<String>,
+    },
+    /// Run COMMAND with the effective values at PWD set, including secrets and keys the export
+    /// policy keeps out of shells
+    Exec {
+        #[arg(long)]
+        pwd: Option<PathBuf>,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Print the effective values at PWD with the scope each comes from, for tools
+    Effective {
+        #[arg(long)]
+        pwd: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = FormatType::Json)]
+        format: FormatType,
+        /// Include secret values; otherwise they are masked.
+        #[arg(long)]
+        reveal_secrets: bool,
+    },
+    /// Print stored values, unexpanded, for backups or checking into a repo
+    Dump {
+        #[arg(long, value_enum, default_value_t = FormatType::Json)]
+        format: FormatType,
+        /// Only this directory scope.
+        #[arg(long, conflicts_with = "global")]
+        dir: Option<PathBuf>,
+        /// Only global values.
+        #[arg(long)]
+        global: bool,
+        /// Include secret values; otherwise they are listed without a value.
+        #[arg(long)]
+        reveal_secrets: bool,
+    },
+    /// Apply a dump from file or stdin (-) over the current state
+    Import {
+        #[arg(value_name = "INPUT")]
+        input: String,
+        #[arg(long, value_enum, default_value_t = FormatType::Json)]
+        format: FormatType,
+        /// Only apply this directory scope's entries; dotenv input is stored there.
+        #[arg(long, conflicts_with = "global")]
+        dir: Option<PathBuf>,
+        /// Only apply global entries.
+        #[arg(long)]
+        global: bool,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
+    },
+    /// Show recorded changes, oldest first
+    History {
+        /// Only changes after generation N.
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        since: u64,
+        /// Only keys matching PATTERN, where `*` matches any run of characters.
+        #[arg(long, value_name = "PATTERN")]
+        key: Option<String>,
+        /// Only changes to this directory scope.
+        #[arg(long, conflicts_with = "global")]
+        dir: Option<PathBuf>,
+        /// Only changes to global values.
+        #[arg(long)]
+        global: bool,
+    },
+    /// Revert the latest change, or the change at --generation, to the value it replaced
+    Undo {
+        /// The generation of the change to revert.
+        #[arg(long, value_name = "N")]
+        generation: Option<u64>,
+        /// Fail instead of writing if the daemon generation is no longer N.
+        #[arg(long, value_name = "N")]
+        if_generation: Option<u64>,
+    },
+    /// Stream changes visible at PWD as JSON lines until interrupted
+    Watch {
+        #[arg(long, conflicts_with = "all")]
+        pwd: Option<PathBuf>,
+        /// Stream changes to every scope, not just those visible at PWD.
+        #[arg(long)]
+        all: bool,
+        /// Replay changes after generation N before streaming new ones.
+        #[arg(long, value_name = "N")]
+        since: Option<u64>,
     },
-    /// Print hook for bash/zsh/fish
+    /// Print the prompt hook for a shell, ready to eval: eval "$(envctl hook bash)"
     Hook { shell: ShellType },
     /// Install hook into the user's shell rc file
     InstallHook {
@@ -82,6 +247,10 @@ enum ShellType {
     Bash,
     Zsh,
     Fish,
+    #[value(name = "powershell", alias = "pwsh")]
+    PowerShell,
+    #[value(alias = "nu")]
+    Nushell,
 }
 
 impl From<ShellType> for ShellKind {
@@ -90,6 +259,26 @@ impl From<ShellType> for ShellKind {
             ShellType::Bash => ShellKind::Bash,
             ShellType::Zsh => ShellKind::Zsh,
             ShellType::Fish => ShellKind::Fish,
+            ShellType::PowerShell => ShellKind::PowerShell,
+            ShellType::Nushell => ShellKind::Nushell,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum FormatType {
+    Dotenv,
+    Json,
+    #[value(alias = "yml")]
+    Yaml,
+}
+
+impl From<FormatType> for DumpFormat {
+    fn from(f: FormatType) -> Self {
+        match f {
+            FormatType::Dotenv => DumpFormat::Dotenv,
+            FormatType::Json => DumpFormat::Json,
+            FormatType::Yaml => DumpFormat::Yaml,
         }
     }
 }
@@ -100,6 +289,8 @@ impl ShellType {
             ShellType::Bash => "bash",
             ShellType::Zsh => "zsh",
             ShellType::Fish => "fish",
+            ShellType::PowerShell => "powershell",
+            ShellType::Nushell => "nushell",
         }
     }
 }
@@ -116,6 +307,16 @@ fn obfuscate_value(value: &str) -> String {
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    // Through the environment, so an autostarted envd and commands run by `exec` use the
+    // same daemon.
+    let socket = match (cli.socket, &cli.instance) {
+        (Some(path), _) => Some(path),
+        (None, Some(name)) => Some(instance_socket_path(name)?),
+        (None, None) => None,
+    };
+    if let Some(socket) = socket {
+        std::env::set_var("CMUX_ENVD_SOCKET", socket);
+    }
     match cli.command {
         Commands::Ping => {
             let resp = client_send(&Request::Ping)?;
@@ -134,29 +335,60 @@ fn main() -> Result<()> {
                     generation,
                     globals,
                     scopes,
+                    secrets,
+                    checkpoint,
                 } => {
                     println!("generation: {}", generation);
                     println!("globals: {}", globals);
                     println!("scopes: {}", scopes);
+                    println!("secrets: {}", secrets);
+                    if checkpoint > 0 {
+                        println!("history compacted through: {}", checkpoint);
+                    }
                     Ok(())
                 }
                 _ => Err(anyhow!("unexpected response")),
             }
         }
-        Commands::Set { kv, dir } => {
+        Commands::Set {
+            kv,
+            dir,
+            secret,
+            ttl,
+            if_generation,
+        } => {
             let (key, val) = parse_kv(&kv)?;
             let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
-            let _ = client_send_autostart(&Request::Set {
+            let resp = client_send_autostart(&Request::Set {
                 key,
                 value: val,
                 scope,
+                secret,
+                ttl_secs: ttl,
+                origin: Some(origin(None)),
+                if_generation,
             })?;
-            Ok(())
+            check_write(resp)
         }
-        Commands::Unset { key, dir } => {
+        Commands::Unset {
+            key,
+            dir,
+            if_generation,
+        } => {
             let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
-            let _ = client_send_autostart(&Request::Unset { key, scope })?;
-            Ok(())
+            // Keys never contain `*`, so one that does is a pattern.
+            let (key, pattern) = if key.contains('*') {
+                (String::new(), Some(key))
+            } else {
+                (key, None)
+            };
+            let resp = client_send_autostart(&Request::Unset {
+                key,
+                scope,
+                pattern,
+                if_generation,
+            })?;
+            check_write(resp)
         }
         Commands::Reset { dir } => {
             let scope = dir.map(Scope::Dir);
@@ -166,6 +398,34 @@ fn main() -> Result<()> {
                 _ => Err(anyhow!("unexpected response")),
             }
         }
+        Commands::Scopes => {
+            let resp = client_send_autostart(&Request::Scopes)?;
+            match resp {
+                Response::Scopes { scopes } => {
+                    for scope in &scopes {
+                        println!(
+                            "{}  {} vars  generation {}",
+                            scope.path.display(),
+                            scope.vars,
+                            scope.generation
+                        );
+                    }
+                    Ok(())
+                }
+                _ => Err(anyhow!("unexpected response")),
+            }
+        }
+        Commands::Clear {
+            dir,
+            global,
+            if_generation,
+        } => {
+            let scope = scope_filter(dir, global).unwrap_or(Scope::Global);
+            check_write(client_send_autostart(&Request::ClearScope {
+                scope,
+                if_generation,
+            })?)
+        }
         Commands::Get { key, pwd } => {
             let pwd = match pwd {
                 Some(pwd) => pwd,
@@ -185,14 +445,26 @@ fn main() -> Result<()> {
                 _ => Err(anyhow!("unexpected response")),
             }
         }
-        Commands::List { pwd } => {
+        Commands::List {
+            pwd,
+            annotate,
+            prefix,
+        } => {
             let pwd = match pwd {
                 Some(pwd) => pwd,
                 None => std::env::current_dir()?,
             };
-            let resp = client_send_autostart(&Request::List { pwd: Some(pwd) })?;
+            let resp = client_send_autostart(&Request::List {
+                pwd: Some(pwd),
+                annotate,
+                prefix,
+            })?;
             match resp {
-                Response::Map { entries } => {
+                Response::Map {
+                    entries,
+                    ttls,
+                    definitions,
+                } => {
                     let mut pairs: Vec<_> = entries.into_iter().collect();
                     pairs.sort_by(|a, b| a.0.cmp(&b.0));
 
@@ -201,7 +473,14 @@ fn main() -> Result<()> {
                     } else {
                         println!("Active environment variables ({}):", pairs.len());
                         for (key, value) in pairs {
-                            println!("  - {}={}", key, obfuscate_value(&value));
+                            let mut line = format!("  - {}={}", key, obfuscate_value(&value));
+                            if let Some(secs) = ttls.get(&key) {
+                                line.push_str(&format!(" (expires in {}s)", secs));
+                            }
+                            if let Some(definition) = definitions.get(&key) {
+                                line.push_str(&format!(" [{}]", describe_definition(definition)));
+                            }
+                            println!("{}", line);
                         }
                     }
                     Ok(())
@@ -209,7 +488,42 @@ fn main() -> Result<()> {
                 _ => Err(anyhow!("unexpected response")),
             }
         }
-        Commands::Load { input, dir, base64 } => {
+        Commands::Describe { key, pwd } => {
+            let pwd = match pwd {
+                Some(pwd) => pwd,
+                None => std::env::current_dir()?,
+            };
+            let resp = client_send_autostart(&Request::Describe {
+                key: key.clone(),
+                pwd: Some(pwd.clone()),
+            })?;
+            match resp {
+                Response::Description { value, definitions } => {
+                    match value {
+                        Some(value) => println!("{}={}", key, value),
+                        None => println!("{} is not set at {}", key, pwd.display()),
+                    }
+                    let effective = definitions.len().saturating_sub(1);
+                    for (idx, definition) in definitions.iter().enumerate() {
+                        println!(
+                            "{} {} = {}",
+                            if idx == effective { "*" } else { " " },
+                            describe_definition(definition),
+                            definition.raw
+                        );
+                    }
+                    Ok(())
+                }
+                _ => Err(anyhow!("unexpected response")),
+            }
+        }
+        Commands::Load {
+            input,
+            dir,
+            base64,
+            secret,
+            if_generation,
+        } => {
             let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
             let entries = if base64 {
                 let payload = if input == "-" {
@@ -228,10 +542,72 @@ fn main() -> Result<()> {
                 let f = File::open(&input).with_context(|| format!("open {}", input))?;
                 parse_dotenv(f)?
             };
-            let _ = client_send_autostart(&Request::Load { entries, scope })?;
-            Ok(())
+            let file = (!base64 && input != "-").then(|| Path::new(&input));
+            let resp = client_send_autostart(&Request::Load {
+                entries,
+                scope,
+                secret,
+                origin: Some(origin(file)),
+                if_generation,
+            })?;
+            check_write(resp)
         }
-        Commands::Export { shell, since, pwd } => {
+        Commands::Track { file, dir } => {
+            let scope = dir.map(Scope::Dir).unwrap_or(Scope::Global);
+            let origin = origin(Some(&file));
+            let path = origin.file.clone().unwrap_or(file);
+            let resp = client_send_autostart(&Request::Track {
+                path,
+                scope,
+                origin: Some(origin),
+            })?;
+            check_write(resp)
+        }
+        Commands::Untrack { file } => {
+            let path = fs::canonicalize(&file).unwrap_or(file);
+            let resp = client_send_autostart(&Request::Untrack { path })?;
+            check_write(resp)
+        }
+        Commands::Allow { path } => {
+            let path = envrc_path(path)?;
+            let resp = client_send_autostart(&Request::Allow {
+                origin: Some(origin(Some(&path))),
+                path,
+            })?;
+            check_write(resp)
+        }
+        Commands::Deny { path } => {
+            let resp = client_send_autostart(&Request::Deny {
+                path: envrc_path(path)?,
+            })?;
+            check_write(resp)
+        }
+        Commands::Batch {
+            input,
+            if_generation,
+        } => {
+            let data = if input == "-" {
+                let mut buf = String::new();
+                io::stdin().read_to_string(&mut buf)?;
+                buf
+            } else {
+                fs::read_to_string(&input).with_context(|| format!("read {}", input))?
+            };
+            let ops: Vec<Op> = serde_json::from_str(&data).context("parse batch")?;
+            let file = (input != "-").then(|| Path::new(&input));
+            let resp = client_send_autostart(&Request::Batch {
+                ops,
+                origin: Some(origin(file)),
+                if_generation,
+            })?;
+            check_write(resp)
+        }
+        Commands::Export {
+            shell,
+            since,
+            pwd,
+            session,
+        } => {
             let shell: ShellKind = shell.into();
             let pwd = pwd.unwrap_or(std::env::current_dir()?);
             // If --since not specified (0), try ENVCTL_GEN to provide a smoother UX
@@ -243,7 +619,12 @@ fn main() -> Result<()> {
             } else {
                 since
             };
-            let resp = client_send_autostart(&Request::Export { shell, since, pwd })?;
+            let resp = client_send_autostart(&Request::Export {
+                shell,
+                since,
+                pwd,
+                session,
+            })?;
             match resp {
                 Response::Export {
                     script,
@@ -255,14 +636,149 @@ fn main() -> Result<()> {
                 _ => Err(anyhow!("unexpected response")),
             }
         }
-        Commands::Hook { shell } => {
-            match shell {
-                ShellType::Bash => print!("{}", hook_bash()),
-                ShellType::Zsh => print!("{}", hook_zsh()),
-                ShellType::Fish => print!("{}", hook_fish()),
+        Commands::Exec { pwd, command } => {
+            let pwd = match pwd {
+                Some(pwd) => pwd,
+                None => std::env::current_dir()?,
+            };
+            let resp = client_send_autostart(&Request::Resolve { pwd: Some(pwd) })?;
+            let entries = match resp {
+                Response::Map { entries, .. } => entries,
+                Response::Error { message } => return Err(anyhow!(message)),
+                _ => return Err(anyhow!("unexpected response")),
+            };
+            // Only returns if the command could not be started.
+            let err = std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .envs(entries)
+                .exec();
+            Err(err).with_context(|| format!("run {}", command[0]))
+        }
+        Commands::Effective {
+            pwd,
+            format,
+            reveal_secrets,
+        } => {
+            let pwd = match pwd {
+                Some(pwd) => pwd,
+                None => std::env::current_dir()?,
+            };
+            let resp = client_send_autostart(&Request::Effective {
+                pwd: Some(pwd),
+                format: format.into(),
+                reveal_secrets,
+            })?;
+            match resp {
+                Response::Dump { data } => {
+                    print!("{}", data);
+                    Ok(())
+                }
+                Response::Error { message } => Err(anyhow!(message)),
+                _ => Err(anyhow!("unexpected response")),
+            }
+        }
+        Commands::Dump {
+            format,
+            dir,
+            global,
+            reveal_secrets,
+        } => {
+            let resp = client_send_autostart(&Request::Dump {
+                format: format.into(),
+                scope: scope_filter(dir, global),
+                reveal_secrets,
+            })?;
+            match resp {
+                Response::Dump { data } => {
+                    print!("{}", data);
+                    Ok(())
+                }
+                Response::Error { message } => Err(anyhow!(message)),
+                _ => Err(anyhow!("unexpected response")),
+            }
+        }
+        Commands::Import {
+            input,
+            format,
+            dir,
+            global,
+            if_generation,
+        } => {
+            let data = if input == "-" {
+                let mut buf = String::new();
+                io::stdin().read_to_string(&mut buf)?;
+                buf
+            } else {
+                fs::read_to_string(&input).with_context(|| format!("read {}", input))?
+            };
+            let file = (input != "-").then(|| Path::new(&input));
+            let resp = client_send_autostart(&Request::Import {
+                format: format.into(),
+                data,
+                scope: scope_filter(dir, global),
+                origin: Some(origin(file)),
+                if_generation,
+            })?;
+            check_write(resp)
+        }
+        Commands::History {
+            since,
+            key,
+            dir,
+            global,
+        } => {
+            let resp = client_send_autostart(&Request::History {
+                since,
+                key_filter: key,
+                scope_filter: scope_filter(dir, global),
+            })?;
+            match resp {
+                Response::History { entries } => {
+                    for entry in &entries {
+                        println!("{}", describe_change(entry));
+                    }
+                    Ok(())
+                }
+                _ => Err(anyhow!("unexpected response")),
+            }
+        }
+        Commands::Undo {
+            generation,
+            if_generation,
+        } => {
+            let resp = client_send_autostart(&Request::Revert {
+                generation,
+                origin: Some(origin(None)),
+                if_generation,
+            })?;
+            match resp {
+                Response::History { entries } => {
+                    for entry in &entries {
+                        println!("{}", describe_change(entry));
+                    }
+                    Ok(())
+                }
+                other => check_write(other),
+            }
+        }
+        Commands::Watch { pwd, all, since } => {
+            let pwd = match (all, pwd) {
+                (true, _) => None,
+                (false, Some(pwd)) => Some(pwd),
+                (false, None) => Some(std::env::current_dir()?),
+            };
+            let stdout = io::stdout();
+            for event in client_watch(pwd, since)? {
+                let mut out = stdout.lock();
+                writeln!(out, "{}", serde_json::to_string(&event?)?)?;
+                out.flush()?;
             }
             Ok(())
         }
+        Commands::Hook { shell } => {
+            print!("{}", hook_text(shell)?);
+            Ok(())
+        }
         Commands::InstallHook { shell, rcfile } => {
             install_hook(shell, rcfile)?;
             Ok(())
@@ -270,10 +786,120 @@ fn main() -> Result<()> {
     }
 }
 
+// Writes are attributed to the process that ran envctl, which outlives it.
+fn origin(file: Option<&Path>) -> Origin {
+    Origin {
+        pid: Some(std::os::unix::process::parent_id()),
+        file: file.map(|f| fs::canonicalize(f).unwrap_or_else(|_| f.to_path_buf())),
+    }
+}
+
+// e.g. `/work/app: loaded from /work/app/.env by pid 4242 5m ago (generation 7)`.
+fn describe_definition(definition: &Definition) -> String {
+    let mut out = match &definition.scope {
+        Scope::Global => "global".to_string(),
+        Scope::Dir(dir) => dir.display().to_string(),
+    };
+    let Some(provenance) = &definition.provenance else {
+        return out;
+    };
+    out.push_str(match provenance.source {
+        Source::Set => ": set",
+        Source::Load => ": loaded",
+        Source::Import => ": imported",
+        Source::Revert => ": reverted",
+    });
+    if let Some(file) = &provenance.file {
+        out.push_str(&format!(" from {}", file.display()));
+    }
+    if let Some(pid) = provenance.pid {
+        out.push_str(&format!(" by pid {}", pid));
+    }
+    out.push_str(&format!(
+        " {} ago (generation {})",
+        age(provenance.at),
+        provenance.generation
+    ));
+    out
+}
+
+// e.g. `7  5m ago  /work/app  PORT: 3000 -> 4000`.
+fn describe_change(entry: &HistoryEntry) -> String {
+    let scope = match &entry.scope {
+        Scope::Global => "global".to_string(),
+        Scope::Dir(dir) => dir.display().to_string(),
+    };
+    let show = |value: &Option<String>| value.as_deref().unwrap_or("(unset)").to_string();
+    format!(
+        "{}  {} ago  {}  {}: {} -> {}",
+        entry.generation,
+        age(entry.at),
+        scope,
+        entry.key,
+        show(&entry.old),
+        show(&entry.new)
+    )
+}
+
+// How long ago the Unix time `at` was, in its largest whole unit.
+fn age(at: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let age = now.saturating_sub(at);
+    match age {
+        0..=59 => format!("{}s", age),
+        60..=3599 => format!("{}m", age / 60),
+        3600..=86399 => format!("{}h", age / 3600),
+        _ => format!("{}d", age / 86400),
+    }
+}
+
+// The .envrc a path names: the file itself, or the one in a directory. It may be gone when
+// denying it.
+fn envrc_path(path: PathBuf) -> Result<PathBuf> {
+    let path = if path.is_dir() {
+        path.join(".envrc")
+    } else {
+        path
+    };
+    fs::canonicalize(&path)
+        .or_else(|_| std::path::absolute(&path))
+        .with_context(|| format!("{}", path.display()))
+}
+
+fn scope_filter(dir: Option<PathBuf>, global: bool) -> Option<Scope> {
+    match (dir, global) {
+        (Some(dir), _) => Some(Scope::Dir(dir)),
+        (None, true) => Some(Scope::Global),
+        (None, false) => None,
+    }
+}
+
+// Surfaces generation conflicts and daemon errors from writes; other responses keep the old
+// lenient behavior.
+fn check_write(resp: Response) -> Result<()> {
+    match resp {
+        Response::Conflict {
+            expected_generation,
+            current_generation,
+        } => Err(anyhow!(
+            "generation conflict: expected {}, daemon is at {}",
+            expected_generation,
+            current_generation
+        )),
+        Response::Error { message } => Err(anyhow!(message)),
+        _ => Ok(()),
+    }
+}
+
 fn install_hook(shell: ShellType, rcfile: Option<PathBuf>) -> Result<()> {
     const START_MARKER: &str = "# >>> envctl hook >>>";
     const END_MARKER: &str = "# <<< envctl hook <<<";
 
+    // Checked before touching the rc file.
+    hook_text(shell)?;
     let rc_path = rcfile.unwrap_or(default_rc_path(shell)?);
     if let Some(parent) = rc_path.parent() {
         fs::create_dir_all(parent)
@@ -309,11 +935,7 @@ fn install_hook(shell: ShellType, rcfile: Option<PathBuf>) -> Result<()> {
         contents.push('\n');
     }
 
-    let hook_body = match shell {
-        ShellType::Bash => hook_bash(),
-        ShellType::Zsh => hook_zsh(),
-        ShellType::Fish => hook_fish(),
-    };
+    let hook_body = hook_text(shell)?;
 
     let mut block = String::new();
     block.push_str(START_MARKER);
@@ -346,6 +968,11 @@ fn default_rc_path(shell: ShellType) -> Result<PathBuf> {
         ShellType::Bash => base.join(".bashrc"),
         ShellType::Zsh => base.join(".zshrc"),
         ShellType::Fish => base.join(".config").join("fish").join("config.fish"),
+        ShellType::PowerShell => base
+            .join(".config")
+            .join("powershell")
+            .join("Microsoft.PowerShell_profile.ps1"),
+        ShellType::Nushell => base.join(".config").join("nushell").join("config.nu"),
     };
     Ok(path)
 }
@@ -362,12 +989,51 @@ fn parse_kv(s: &str) -> Result<(String, String)> {
     }
 }
 
+// Seconds from `90`, `90s`, `15m`, `2h` or `1d`.
+fn parse_ttl(s: &str) -> Result<u64> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| anyhow!("invalid duration: {}", s))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(anyhow!("invalid duration unit: {}", unit)),
+    };
+    let secs = n
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow!("duration too long: {}", s))?;
+    if secs == 0 {
+        return Err(anyhow!("ttl must be positive"));
+    }
+    Ok(secs)
+}
+
+fn hook_text(shell: ShellType) -> Result<String> {
+    match shell {
+        ShellType::Bash => Ok(hook_bash()),
+        ShellType::Zsh => Ok(hook_zsh()),
+        ShellType::Fish => Ok(hook_fish()),
+        ShellType::PowerShell => Ok(hook_powershell()),
+        // Nushell cannot evaluate generated code at runtime, so there is no prompt hook;
+        // `envctl export nushell` output has to be saved and `source`d instead.
+        ShellType::Nushell => Err(anyhow!(
+            "nushell has no prompt hook; save `envctl export nushell` to a file and `source` it"
+        )),
+    }
+}
+
 fn hook_bash() -> String {
     r#"# envctl bash hook
 # Apply env diffs safely (idempotent, uses ENVCTL_GEN)
 __envctl_apply() {
   local out
-  out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD")" || return
+  out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD" --session "$$")" || return
   eval "$out"
 }
 
@@ -425,10 +1091,12 @@ fn hook_zsh() -> String {
 autoload -U add-zsh-hook
 envctl_preexec() {
   local out
-  out="$(envctl export zsh --since "${ENVCTL_GEN:-0}" --pwd "$PWD")" || return
+  out="$(envctl export zsh --since "${ENVCTL_GEN:-0}" --pwd "$PWD" --session "$$")" || return
   eval "$out"
 }
+# Before each command, and before each prompt so the prompt sees the current values
 add-zsh-hook preexec envctl_preexec
+add-zsh-hook precmd envctl_preexec
 # Apply once at shell start
 envctl_preexec
 "#
@@ -438,13 +1106,33 @@ envctl_preexec
 fn hook_fish() -> String {
     r#"# envctl fish hook
 function __envctl_preexec --on-event fish_preexec
-  envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+  envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" --session "$fish_pid" | source
 end
 function __envctl_prompt --on-event fish_prompt
-  envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+  envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" --session "$fish_pid" | source
 end
 # Apply once at shell start
-envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" | source
+envctl export fish --since "$ENVCTL_GEN" --pwd "$PWD" --session "$fish_pid" | source
+"#
+    .to_string()
+}
+
+fn hook_powershell() -> String {
+    r#"# envctl powershell hook
+function global:__envctl_apply {
+  $pwdArg = (Get-Location).ProviderPath
+  $out = envctl export powershell --since "$(if ($env:ENVCTL_GEN) { $env:ENVCTL_GEN } else { 0 })" --pwd "$pwdArg" --session "$PID" | Out-String
+  if ($LASTEXITCODE -eq 0 -and $out) { Invoke-Expression $out }
+}
+if (-not (Test-Path variable:global:__envctl_prev_prompt)) {
+  $global:__envctl_prev_prompt = $function:prompt
+}
+function global:prompt {
+  __envctl_apply
+  & $global:__envctl_prev_prompt
+}
+# Apply once at shell start
+__envctl_apply
 "#
     .to_string()
 }
@@ -1,7 +1,119 @@
-use anyhow::Result;
-use cmux_env::run_server;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use cmux_env::{instance_socket_path, run_server_with, Replication, ServerOptions, SyncScopes};
+
+#[derive(Parser, Debug)]
+#[command(name = "envd", version, about = "Environment daemon for cmux")]
+struct Cli {
+    /// Listen on this socket instead of the default; also read from $CMUX_ENVD_SOCKET.
+    #[arg(long, value_name = "PATH", conflicts_with = "instance")]
+    socket: Option<PathBuf>,
+    /// Run as the instance NAME, with its own socket next to the default one; also read from
+    /// $CMUX_ENVD_INSTANCE.
+    #[arg(long, value_name = "NAME")]
+    instance: Option<String>,
+    /// Export policy file. Defaults to $XDG_CONFIG_HOME/cmux-env/policy.yaml, if it exists.
+    #[arg(long, value_name = "FILE")]
+    policy: Option<PathBuf>,
+    /// Changes kept in the history before the oldest are compacted. Defaults to 10000.
+    #[arg(long, value_name = "N")]
+    history_limit: Option<usize>,
+    /// Accept replication peers on ADDR (host:port); requires --tls-cert, --tls-key and
+    /// --sync-token-file.
+    #[arg(long, value_name = "ADDR", requires_all = ["tls_cert", "tls_key", "sync_token_file"])]
+    sync_listen: Option<String>,
+    /// PEM certificate chain presented to replication peers.
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert.
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// Replicate with the envd listening on ADDR (host:port); requires --tls-ca and
+    /// --sync-token-file.
+    #[arg(long, value_name = "ADDR", requires_all = ["tls_ca", "sync_token_file"])]
+    sync_to: Option<String>,
+    /// PEM certificates trusted for the --sync-to peer.
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// Name expected in the --sync-to peer's certificate. Defaults to the host in ADDR.
+    #[arg(long)]
+    tls_server_name: Option<String>,
+    /// File holding the token replication peers authenticate with.
+    #[arg(long)]
+    sync_token_file: Option<PathBuf>,
+    /// With --sync-to, replicate global variables.
+    #[arg(long)]
+    sync_global: bool,
+    /// With --sync-to, replicate LOCAL and its subdirectories as REMOTE on the peer.
+    #[arg(long, value_name = "LOCAL=REMOTE", value_parser = parse_sync_dir)]
+    sync_dir: Vec<(PathBuf, PathBuf)>,
+}
 
 fn main() -> Result<()> {
-    // Simple foreground server
-    run_server()
+    let cli = Cli::parse();
+    let socket = match (cli.socket, &cli.instance) {
+        (Some(path), _) => Some(path),
+        (None, Some(name)) => Some(instance_socket_path(name)?),
+        (None, None) => None,
+    };
+    let token = match &cli.sync_token_file {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("read {}", path.display()))?
+            .trim()
+            .to_string(),
+        None => String::new(),
+    };
+    let mut replications = Vec::new();
+    if let Some(addr) = cli.sync_listen {
+        replications.push(Replication::Listen {
+            addr,
+            cert: cli.tls_cert.unwrap(),
+            key: cli.tls_key.unwrap(),
+            token: token.clone(),
+        });
+    }
+    if let Some(addr) = cli.sync_to {
+        if !cli.sync_global && cli.sync_dir.is_empty() {
+            return Err(anyhow!("--sync-to needs --sync-global or --sync-dir"));
+        }
+        let server_name = match cli.tls_server_name {
+            Some(name) => name,
+            None => host(&addr).to_string(),
+        };
+        replications.push(Replication::Connect {
+            addr,
+            ca: cli.tls_ca.unwrap(),
+            server_name,
+            token,
+            scopes: SyncScopes {
+                global: cli.sync_global,
+                dirs: cli.sync_dir,
+            },
+        });
+    }
+    run_server_with(ServerOptions {
+        replications,
+        policy_file: cli.policy,
+        history_limit: cli.history_limit,
+        socket,
+    })
+}
+
+fn parse_sync_dir(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (local, remote) = s
+        .split_once('=')
+        .ok_or_else(|| "expected LOCAL=REMOTE".to_string())?;
+    if local.is_empty() || remote.is_empty() {
+        return Err("expected LOCAL=REMOTE".to_string());
+    }
+    Ok((PathBuf::from(local), PathBuf::from(remote)))
+}
+
+// The host part of `host:port`, without the brackets around an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
 }
@@ -1,17 +1,30 @@
 use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
-use parking_lot::Mutex;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+use parking_lot::{Condvar, Mutex, RwLock, RwLockWriteGuard};
+use sd_notify::NotifyState;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::FromRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // ---------------- Path helpers ----------------
 
@@ -24,20 +37,61 @@ pub fn runtime_dir() -> PathBuf {
     PathBuf::from("/tmp")
 }
 
-pub fn socket_path() -> PathBuf {
-    let base = runtime_dir().join("cmux-envd");
-    base.join("envd.sock")
+/// The socket clients and envd use: `$CMUX_ENVD_SOCKET` when set, otherwise the socket of
+/// the `$CMUX_ENVD_INSTANCE` instance, otherwise `$XDG_RUNTIME_DIR/cmux-envd/envd.sock`.
+pub fn socket_path() -> Result<PathBuf> {
+    if let Some(p) = std::env::var_os("CMUX_ENVD_SOCKET") {
+        if !p.is_empty() {
+            return Ok(PathBuf::from(p));
+        }
+    }
+    match std::env::var("CMUX_ENVD_INSTANCE") {
+        Ok(name) if !name.is_empty() => instance_socket_path(&name),
+        _ => Ok(runtime_dir().join("cmux-envd").join("envd.sock")),
+    }
+}
+
+/// The socket of the envd instance called `name`, next to the default one, so daemons for
+/// different projects or tests can run side by side.
+pub fn instance_socket_path(name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(anyhow!(
+            "invalid instance name {:?}: use letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+    Ok(runtime_dir()
+        .join("cmux-envd")
+        .join(format!("envd-{}.sock", name)))
+}
+
+// envd's pid file sits next to its socket: `envd.sock` goes with `envd.pid`.
+fn pid_path(sock: &Path) -> PathBuf {
+    sock.with_extension("pid")
+}
+
+/// `$XDG_CONFIG_HOME/cmux-env/policy.yaml`, or under `~/.config`; see [`ExportPolicy`].
+pub fn default_policy_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(p) if !p.is_empty() => PathBuf::from(p),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("cmux-env").join("policy.yaml"))
 }
 
-fn ensure_socket_dir() -> Result<PathBuf> {
-    let dir = runtime_dir().join("cmux-envd");
-    fs::create_dir_all(&dir).with_context(|| format!("creating dir {}", dir.display()))?;
-    Ok(dir)
+fn ensure_socket_dir(sock: &Path) -> Result<()> {
+    let dir = sock.parent().unwrap_or(Path::new("/"));
+    fs::create_dir_all(dir).with_context(|| format!("creating dir {}", dir.display()))?;
+    Ok(())
 }
 
-fn write_pid_file(dir: &Path) -> Result<()> {
-    let pid_path = dir.join("envd.pid");
-    fs::write(&pid_path, format!("{}\n", std::process::id()))
+fn write_pid_file(pid_path: &Path) -> Result<()> {
+    fs::write(pid_path, format!("{}\n", std::process::id()))
         .with_context(|| format!("writing pid file {}", pid_path.display()))?;
     Ok(())
 }
@@ -50,10 +104,24 @@ pub enum ShellKind {
     Bash,
     Zsh,
     Fish,
+    PowerShell,
+    Nushell,
 }
 
 impl ShellKind {}
 
+/// Formats for [`Request::Dump`] and [`Request::Import`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpFormat {
+    /// One scope's values; secrets are only included when revealed, and not marked as such.
+    Dotenv,
+    /// Every scope, with the secret flag.
+    Json,
+    /// Same document as `Json`.
+    Yaml,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type", content = "path")]
 pub enum Scope {
@@ -61,6 +129,125 @@ pub enum Scope {
     Dir(PathBuf),
 }
 
+/// What a client can say about where a write comes from; kept as the values' provenance.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Origin {
+    /// The process that asked for the write, e.g. the shell or agent that ran `envctl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// The dotenv or dump file the values were read from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Set,
+    Load,
+    Import,
+    Revert,
+}
+
+/// Where a stored value came from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Provenance {
+    pub source: Source,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Unix time of the write, in seconds.
+    pub at: u64,
+    /// The generation the write produced.
+    pub generation: u64,
+}
+
+/// One scope's definition of a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Definition {
+    pub scope: Scope,
+    /// As written, before expansion; [`SECRET_MASK`] for secrets.
+    pub raw: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// Missing for values stored without going through a request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Seconds left when the value was set with a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+/// The effective environment at a directory, as `Effective` reports it in JSON or YAML.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EffectiveEnv {
+    pub pwd: PathBuf,
+    pub generation: u64,
+    pub vars: BTreeMap<String, EffectiveVar>,
+}
+
+/// One effective value and where it comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EffectiveVar {
+    /// Expanded; [`SECRET_MASK`] when it is or references a secret, unless revealed.
+    pub value: String,
+    /// Whether a secret went into the value.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// The scope's definition in effect.
+    pub definition: Definition,
+}
+
+/// A recorded change, as `History` reports it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub generation: u64,
+    pub key: String,
+    pub scope: Scope,
+    /// Unix time of the change, in seconds.
+    pub at: u64,
+    /// The value before and after the change, as written; `None` when unset, and
+    /// [`SECRET_MASK`] for secrets.
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A directory scope, as `Scopes` reports it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScopeInfo {
+    pub path: PathBuf,
+    /// How many values it holds.
+    pub vars: usize,
+    /// The last generation that changed it.
+    pub generation: u64,
+}
+
+/// One write in a [`Request::Batch`], with the same meaning as the request of the same name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Op {
+    Set {
+        key: String,
+        value: String,
+        scope: Scope,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        secret: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+    },
+    Unset {
+        key: String,
+        scope: Scope,
+    },
+    Load {
+        entries: Vec<(String, String)>,
+        scope: Scope,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        secret: bool,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Request {
@@ -70,10 +257,30 @@ pub enum Request {
         key: String,
         value: String,
         scope: Scope,
+        /// Keep the value encrypted; `Get` and `List` mask it and only `Export` emits it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        secret: bool,
+        /// Unset the key after this many seconds. Setting it again without one clears the TTL.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+        /// Only apply if the daemon is still at this generation; otherwise the daemon answers
+        /// with `Conflict` and nothing is written.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
     },
     Unset {
+        /// Empty when `pattern` is given.
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         key: String,
         scope: Scope,
+        /// Unset every key in `scope` matching this pattern, where `*` matches any run of
+        /// characters, as one change.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
     },
     Get {
         key: String,
@@ -81,18 +288,152 @@ pub enum Request {
     },
     List {
         pwd: Option<PathBuf>,
+        /// Also answer with the definition each effective value comes from.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        annotate: bool,
+        /// Only keys starting with this.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+    /// Every definition of `key` that applies at `pwd`, with where each came from.
+    Describe {
+        key: String,
+        pwd: Option<PathBuf>,
     },
     Load {
         entries: Vec<(String, String)>,
         scope: Scope,
+        /// Store every entry as a secret, as with `Set`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        secret: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+        /// Checked once for the whole batch, so either every entry is applied or none is.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
+    },
+    /// Applies `ops` in order as one change: every write is recorded under a single
+    /// generation, so exports and watchers never see part of the batch.
+    Batch {
+        ops: Vec<Op>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
+    },
+    /// Loads the dotenv file at `path` into `scope` and reloads it whenever it changes on
+    /// disk. Keys removed from the file are unset; tracking it again moves it to `scope`.
+    Track {
+        path: PathBuf,
+        scope: Scope,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+    },
+    /// Stops reloading a tracked file; the values it loaded stay.
+    Untrack {
+        path: PathBuf,
+    },
+    /// Runs the `.envrc` at `path` with bash and sets what it exports in its directory's scope.
+    /// Only this content is allowed: once the file changes, what it loaded is unset until it
+    /// is allowed again.
+    Allow {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+    },
+    /// Forgets an allowed `.envrc` and unsets what it loaded.
+    Deny {
+        path: PathBuf,
     },
     Reset {
         scope: Option<Scope>,
     },
+    /// Every directory scope holding values, answered with `Scopes`.
+    Scopes,
+    /// Unsets everything in `scope` as one change.
+    ClearScope {
+        scope: Scope,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
+    },
     Export {
         shell: ShellKind,
         since: u64,
         pwd: PathBuf,
+        /// Identifies the shell, e.g. by its pid. The daemon remembers the `pwd` each session
+        /// last exported at, so after a `cd` the script also fixes up every value that differs
+        /// between the two directories.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    /// Every effective value at `pwd` in the clear, including secrets and keys the export
+    /// policy withholds, for running a command with them. Answered with `Map`.
+    Resolve {
+        pwd: Option<PathBuf>,
+    },
+    /// Stored values as written, before expansion, for backups and for checking into repos.
+    Dump {
+        format: DumpFormat,
+        /// Only this scope; every scope when `None`, or globals for `Dotenv`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scope: Option<Scope>,
+        /// Include secret values in the clear. Otherwise secrets are listed without a value.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        reveal_secrets: bool,
+    },
+    /// The effective environment at `pwd` with the definition each value comes from, for
+    /// tools such as editors rather than shells. Answered with `Dump`; `Dotenv` gives just the
+    /// merged values.
+    Effective {
+        pwd: Option<PathBuf>,
+        format: DumpFormat,
+        /// Include values that are or reference secrets in the clear. Otherwise they read as
+        /// [`SECRET_MASK`], or are left out of `Dotenv`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        reveal_secrets: bool,
+    },
+    /// Applies a dump on top of the current state. Either every entry is applied or none is.
+    Import {
+        format: DumpFormat,
+        data: String,
+        /// Only apply this scope's entries; `Dotenv` data goes into it (globals when `None`).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scope: Option<Scope>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
+    },
+    /// Recorded changes after `since`, oldest first.
+    History {
+        #[serde(default)]
+        since: u64,
+        /// Only keys matching this pattern, where `*` matches any run of characters.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_filter: Option<String>,
+        /// Only changes to this scope.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scope_filter: Option<Scope>,
+    },
+    /// Undoes the change at `generation` (the latest change when `None`) by writing back the
+    /// values it replaced; a batch is undone as a whole. Answered with `History` holding the
+    /// revert's own entries.
+    Revert {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        generation: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        if_generation: Option<u64>,
+    },
+    /// Keeps the connection open and streams a `Change` for every change visible from `pwd`
+    /// (every change when `None`), after a `Watching` acknowledgement.
+    Watch {
+        pwd: Option<PathBuf>,
+        /// Replay changes after this generation first, so nothing is missed between reading
+        /// state and subscribing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<u64>,
     },
 }
 
@@ -104,38 +445,139 @@ pub enum Response {
         generation: u64,
         globals: usize,
         scopes: usize,
+        /// Stored secret values, across all scopes.
+        #[serde(default)]
+        secrets: usize,
+        /// Changes up to this generation were compacted out of `History`.
+        #[serde(default)]
+        checkpoint: u64,
     },
     Ok,
+    /// Secrets, and values referencing them, read as [`SECRET_MASK`].
     Value {
         value: Option<String>,
     },
     Map {
         entries: HashMap<String, String>,
+        /// Seconds left for entries that were set with a TTL.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        ttls: HashMap<String, u64>,
+        /// The definition each entry comes from, for annotated lists.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        definitions: HashMap<String, Definition>,
+    },
+    History {
+        entries: Vec<HistoryEntry>,
+    },
+    /// Sorted by path.
+    Scopes {
+        scopes: Vec<ScopeInfo>,
+    },
+    Description {
+        /// The effective value, masked like `Get`.
+        value: Option<String>,
+        /// Least specific first; the last one is in effect.
+        definitions: Vec<Definition>,
     },
     Export {
         script: String,
         new_generation: u64,
     },
+    Dump {
+        data: String,
+    },
+    /// A write's `if_generation` precondition failed because the state advanced.
+    Conflict {
+        expected_generation: u64,
+        current_generation: u64,
+    },
     Error {
         message: String,
     },
+    /// A watch is registered; changes after `generation` follow.
+    Watching {
+        generation: u64,
+    },
+    Change(ChangeEvent),
 }
 
-fn read_json(stream: &mut UnixStream) -> Result<Request> {
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-    reader.read_line(&mut line)?;
-    if line.is_empty() {
-        return Err(anyhow!("empty request"));
+/// Sent first by clients using length-prefixed framing. Connections that start any other way
+/// use the original protocol of one JSON document per line.
+pub const FRAMED_PREAMBLE: &[u8] = b"\0envd-framed/1\n";
+/// The largest frame either side accepts.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// How requests and responses are delimited on a connection. With `Length`, each frame is a
+/// big-endian `u32` byte count followed by that many bytes of JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Lines,
+    Length,
+}
+
+// Reads the preamble if the client sent one. A connection closed before sending anything is
+// treated as line-framed; the first read then sees the end of the stream.
+fn detect_framing(reader: &mut impl BufRead) -> Result<Framing> {
+    let buf = reader.fill_buf()?;
+    if buf.first() != Some(&FRAMED_PREAMBLE[0]) {
+        return Ok(Framing::Lines);
+    }
+    let mut preamble = [0u8; FRAMED_PREAMBLE.len()];
+    reader.read_exact(&mut preamble).context("read preamble")?;
+    if preamble != FRAMED_PREAMBLE {
+        return Err(anyhow!("unknown protocol preamble"));
     }
-    let req: Request = serde_json::from_str(&line).context("parse request")?;
-    Ok(req)
+    Ok(Framing::Length)
+}
+
+// Reads one frame; `None` once the peer has closed the connection.
+fn read_json<T: serde::de::DeserializeOwned>(
+    reader: &mut impl BufRead,
+    framing: Framing,
+) -> Result<Option<T>> {
+    let data = match framing {
+        Framing::Lines => {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            if line.is_empty() {
+                return Ok(None);
+            }
+            line.into_bytes()
+        }
+        Framing::Length => {
+            let mut len = [0u8; 4];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_FRAME_LEN {
+                return Err(anyhow!("frame of {} bytes is too large", len));
+            }
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data).context("read frame")?;
+            data
+        }
+    };
+    Ok(Some(serde_json::from_slice(&data).context("parse frame")?))
 }
 
-fn write_json(stream: &mut UnixStream, resp: &Response) -> Result<()> {
-    let s = serde_json::to_string(resp)?;
-    stream.write_all(s.as_bytes())?;
-    stream.write_all(b"\n")?;
+fn write_json<T: Serialize>(writer: &mut impl Write, framing: Framing, value: &T) -> Result<()> {
+    let data = serde_json::to_vec(value)?;
+    match framing {
+        Framing::Lines => {
+            writer.write_all(&data)?;
+            writer.write_all(b"\n")?;
+        }
+        Framing::Length => {
+            if data.len() > MAX_FRAME_LEN {
+                return Err(anyhow!("frame of {} bytes is too large", data.len()));
+            }
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(&data)?;
+        }
+    }
     Ok(())
 }
 
@@ -146,76 +588,358 @@ pub struct ChangeEvent {
     pub generation: u64,
     pub key: String,
     pub scope: Scope,
+    // Kept for `History` and `Revert`; watchers only get the fields above.
+    #[serde(skip)]
+    at: u64,
+    #[serde(skip)]
+    old: Option<Stored>,
+    #[serde(skip)]
+    new: Option<Stored>,
+    // Applied from a replication peer rather than written locally.
+    #[serde(skip)]
+    replicated: bool,
+}
+
+impl From<&ChangeEvent> for HistoryEntry {
+    fn from(ev: &ChangeEvent) -> Self {
+        let shown = |stored: &Option<Stored>| {
+            stored.as_ref().map(|stored| match stored {
+                Stored::Plain(v) => v.clone(),
+                Stored::Secret(_) => SECRET_MASK.to_string(),
+            })
+        };
+        HistoryEntry {
+            generation: ev.generation,
+            key: ev.key.clone(),
+            scope: ev.scope.clone(),
+            at: ev.at,
+            old: shown(&ev.old),
+            new: shown(&ev.new),
+        }
+    }
+}
+
+impl ChangeEvent {
+    // Whether the change can affect the effective environment at `pwd`.
+    fn visible_from(&self, pwd: &Path) -> bool {
+        match &self.scope {
+            Scope::Global => true,
+            Scope::Dir(dir) => is_ancestor(dir, pwd),
+        }
+    }
 }
 
+#[derive(Debug)]
+struct Watcher {
+    id: u64,
+    pwd: Option<PathBuf>,
+    tx: Sender<ChangeEvent>,
+}
+
+// How many export sessions are remembered at once.
+const MAX_EXPORT_SESSIONS: usize = 1024;
+
 #[derive(Debug, Default)]
 pub struct State {
     pub generation: u64,
-    pub globals: HashMap<String, String>,
-    pub scoped: HashMap<PathBuf, HashMap<String, String>>, // Dir -> (key -> value)
+    pub globals: HashMap<String, Stored>,
+    pub scoped: HashMap<PathBuf, HashMap<String, Stored>>, // Dir -> (key -> value)
     pub history: Vec<ChangeEvent>,
+    watchers: Vec<Watcher>,
+    next_watcher: u64,
+    // Opened on the first secret, so daemons without secrets never touch the keyring.
+    vault: Option<Vault>,
+    // When keys set with a TTL are unset; cleared by any other change to the key.
+    expiries: HashMap<(Scope, String), Instant>,
+    // Where each stored value came from; cleared by any change to the key.
+    provenance: HashMap<(Scope, String), Provenance>,
+    // The generation a batch started at while one is being applied; its changes share the
+    // next generation.
+    batch_start: Option<u64>,
+    // Dotenv files reloaded when they change, by canonical path.
+    tracked: HashMap<PathBuf, Tracked>,
+    // Set by the server; without it tracked files are only loaded when tracked.
+    file_watcher: Option<RecommendedWatcher>,
+    // Allowed `.envrc` files, by canonical path.
+    envrcs: HashMap<PathBuf, Envrc>,
+    // Set while applying a replication peer's writes, so they are not sent back to it.
+    replicating: bool,
+    // Where each export session last exported, and when. Locked on its own, since exports
+    // only read the rest of the state.
+    export_sessions: Mutex<HashMap<String, (PathBuf, Instant)>>,
+    // Which keys exports may carry.
+    policy: ExportPolicy,
+    // Changes up to this generation were compacted out of `history` into `folded`.
+    checkpoint: u64,
+    // The last compacted change to each key, so exports and replicas that are further behind
+    // than `history` goes still learn what changed.
+    folded: HashMap<(Scope, String), Folded>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Folded {
+    generation: u64,
+    replicated: bool,
+}
+
+#[derive(Debug)]
+struct Envrc {
+    // The content that was allowed; any other content is not run.
+    hash: [u8; 32],
+    // Size and mtime when the file was last found to still hash to `hash`. While they match,
+    // reads don't open the file again.
+    stamp: Option<(u64, SystemTime)>,
+    // What it exported when it last ran, all set in its directory's scope.
+    keys: HashSet<String>,
+    // Changed since it was allowed, so what it loaded has been unset.
+    blocked: bool,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    scope: Scope,
+    pid: Option<u32>,
+    // What the file defined at its last load, so keys removed from it can be unset.
+    keys: HashSet<String>,
 }
 
 impl State {
     pub fn set(&mut self, scope: Scope, key: String, value: String) -> bool {
-        match scope {
-            Scope::Global => {
-                let changed = self.globals.get(&key) != Some(&value);
-                if changed {
-                    self.globals.insert(key.clone(), value);
-                    self.bump(key, Scope::Global);
-                }
-                changed
-            }
-            Scope::Dir(path) => {
-                let path_c = canon(path);
-                let entry = self.scoped.entry(path_c.clone()).or_default();
-                let changed = entry.get(&key) != Some(&value);
-                if changed {
-                    entry.insert(key.clone(), value);
-                    self.bump(key, Scope::Dir(path_c));
-                }
-                changed
+        self.store(scope, key, value, None)
+    }
+
+    /// Like [`State::set`], but the value is kept encrypted.
+    pub fn set_secret(&mut self, scope: Scope, key: String, value: String) -> Result<bool> {
+        let vault = self.vault()?.clone();
+        Ok(self.store(scope, key, value, Some(&vault)))
+    }
+
+    // Stores `value`, sealed when a vault is given, and records a change unless the key
+    // already holds the same value with the same secrecy.
+    fn store(&mut self, scope: Scope, key: String, value: String, vault: Option<&Vault>) -> bool {
+        let scope = match scope {
+            Scope::Dir(p) => Scope::Dir(canon(p)),
+            x => x,
+        };
+        let map = match &scope {
+            Scope::Global => &mut self.globals,
+            Scope::Dir(path) => self.scoped.entry(path.clone()).or_default(),
+        };
+        let unchanged = match (map.get(&key), vault) {
+            (Some(Stored::Plain(current)), None) => *current == value,
+            (Some(Stored::Secret(current)), Some(vault)) => {
+                vault.open(current).as_deref() == Some(value.as_str())
             }
+            _ => false,
+        };
+        if unchanged {
+            return false;
         }
+        let stored = match vault {
+            Some(vault) => Stored::Secret(vault.seal(&value)),
+            None => Stored::Plain(value),
+        };
+        let old = map.insert(key.clone(), stored);
+        self.bump(key, scope, old);
+        true
     }
 
     pub fn unset(&mut self, scope: Scope, key: String) -> bool {
         match scope {
-            Scope::Global => {
-                let existed = self.globals.remove(&key).is_some();
-                if existed {
-                    self.bump(key, Scope::Global);
+            Scope::Global => match self.globals.remove(&key) {
+                Some(old) => {
+                    self.bump(key, Scope::Global, Some(old));
+                    true
                 }
-                existed
-            }
+                None => false,
+            },
             Scope::Dir(path) => {
                 let path = canon(path);
-                if let Some(map) = self.scoped.get_mut(&path) {
-                    let existed = map.remove(&key).is_some();
-                    if existed {
-                        self.bump(key, Scope::Dir(path));
+                match self.scoped.get_mut(&path).and_then(|map| map.remove(&key)) {
+                    Some(old) => {
+                        self.bump(key, Scope::Dir(path), Some(old));
+                        true
                     }
-                    existed
-                } else {
-                    false
+                    None => false,
+                }
+            }
+        }
+    }
+
+    /// Unsets every key in `scope` matching `pattern`, where `*` matches any run of
+    /// characters, under one generation. Returns how many were set.
+    pub fn unset_matching(&mut self, scope: Scope, pattern: &str) -> usize {
+        let scope = canon_scope(scope);
+        let keys: Vec<String> = self
+            .vars_in(&scope)
+            .map(|vars| {
+                vars.keys()
+                    .filter(|key| glob_match(pattern, key))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.batched(|st| {
+            let mut unset = 0;
+            for key in keys {
+                if st.unset(scope.clone(), key) {
+                    unset += 1;
                 }
             }
+            unset
+        })
+    }
+
+    /// Unsets `key` once `ttl` has passed, or keeps it indefinitely when `None`.
+    pub fn set_expiry(&mut self, scope: Scope, key: String, ttl: Option<Duration>) {
+        let scope = match scope {
+            Scope::Dir(p) => Scope::Dir(canon(p)),
+            x => x,
+        };
+        // A TTL too long to represent never runs out.
+        match ttl.and_then(|ttl| Instant::now().checked_add(ttl)) {
+            Some(at) => {
+                self.expiries.insert((scope, key), at);
+            }
+            None => {
+                self.expiries.remove(&(scope, key));
+            }
+        }
+    }
+
+    /// Unsets every key whose TTL ran out by `now`. Returns whether any did.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let mut due: Vec<(Instant, Scope, String)> = self
+            .expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|((scope, key), at)| (*at, scope.clone(), key.clone()))
+            .collect();
+        due.sort_by_key(|(at, _, _)| *at);
+        let mut changed = false;
+        for (_, scope, key) in due {
+            self.expiries.remove(&(scope.clone(), key.clone()));
+            changed |= self.unset(scope, key);
         }
+        changed
     }
 
-    fn bump(&mut self, key: String, scope: Scope) {
-        self.generation += 1;
+    /// The earliest pending expiry.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.values().min().copied()
+    }
+
+    // Records a change to `key`, which held `old` before and holds whatever is stored now.
+    fn bump(&mut self, key: String, scope: Scope, old: Option<Stored>) {
+        if self
+            .batch_start
+            .is_none_or(|start| start == self.generation)
+        {
+            self.generation += 1;
+        }
         // normalize dir scope to canonical form
         let scope = match scope {
             Scope::Dir(p) => Scope::Dir(canon(p)),
             x => x,
         };
-        self.history.push(ChangeEvent {
+        let id = (scope.clone(), key.clone());
+        self.expiries.remove(&id);
+        self.provenance.remove(&id);
+        let new = self
+            .vars_in(&scope)
+            .and_then(|vars| vars.get(&key))
+            .cloned();
+        let event = ChangeEvent {
             generation: self.generation,
             key,
             scope,
+            at: unix_now(),
+            old,
+            new,
+            replicated: self.replicating,
+        };
+        // Watchers whose receiving end is gone are dropped here.
+        self.watchers.retain(|w| match &w.pwd {
+            Some(pwd) if !event.visible_from(pwd) => true,
+            _ => w.tx.send(event.clone()).is_ok(),
         });
+        self.history.push(event);
+    }
+
+    /// Sends every future change visible from `pwd` (all changes when `None`) to `tx`.
+    /// Returns an id for [`State::unwatch`].
+    pub fn watch(&mut self, pwd: Option<&Path>, tx: Sender<ChangeEvent>) -> u64 {
+        self.next_watcher += 1;
+        self.watchers.push(Watcher {
+            id: self.next_watcher,
+            pwd: pwd.map(canon),
+            tx,
+        });
+        self.next_watcher
+    }
+
+    pub fn unwatch(&mut self, id: u64) {
+        self.watchers.retain(|w| w.id != id);
+    }
+
+    /// Recorded changes after generation `since` that are visible from `pwd`, oldest first.
+    /// Compacted changes are reported once per key, at the last generation that changed it.
+    pub fn changes_since(&self, since: u64, pwd: Option<&Path>) -> Vec<ChangeEvent> {
+        let pwd = pwd.map(canon);
+        let mut folded: Vec<ChangeEvent> = self
+            .folded_since(since)
+            .map(|((scope, key), folded)| ChangeEvent {
+                generation: folded.generation,
+                key: key.clone(),
+                scope: scope.clone(),
+                at: 0,
+                old: None,
+                new: self.vars_in(scope).and_then(|vars| vars.get(key)).cloned(),
+                replicated: folded.replicated,
+            })
+            .collect();
+        folded.sort_by_key(|ev| ev.generation);
+        folded
+            .into_iter()
+            .chain(
+                self.history
+                    .iter()
+                    .filter(|ev| ev.generation > since)
+                    .cloned(),
+            )
+            .filter(|ev| match &pwd {
+                Some(pwd) => ev.visible_from(pwd),
+                None => true,
+            })
+            .collect()
+    }
+
+    // Compacted changes after `since`, if it is before the checkpoint.
+    fn folded_since(&self, since: u64) -> impl Iterator<Item = (&(Scope, String), &Folded)> {
+        self.folded
+            .iter()
+            .filter(move |(_, folded)| folded.generation > since)
+    }
+
+    /// Folds all but about the newest `keep` changes out of the history, keeping only the
+    /// last generation that changed each key. Generations are never split, so batches stay
+    /// whole for `Revert`.
+    pub fn compact(&mut self, keep: usize) {
+        if self.history.len() <= keep {
+            return;
+        }
+        let cutoff = self.history[self.history.len() - keep].generation;
+        let folded = self.history.partition_point(|ev| ev.generation < cutoff);
+        for ev in self.history.drain(..folded) {
+            self.checkpoint = ev.generation;
+            self.folded.insert(
+                (ev.scope, ev.key),
+                Folded {
+                    generation: ev.generation,
+                    replicated: ev.replicated,
+                },
+            );
+        }
     }
 
     pub fn load(&mut self, scope: Scope, entries: Vec<(String, String)>) {
@@ -224,254 +948,2553 @@ impl State {
         }
     }
 
-    pub fn reset_globals(&mut self) -> bool {
-        if self.globals.is_empty() {
-            return false;
+    /// Like [`State::load`], storing every entry as a secret. Nothing is stored when the
+    /// secret key cannot be loaded.
+    pub fn load_secret(&mut self, scope: Scope, entries: Vec<(String, String)>) -> Result<()> {
+        let vault = self.vault()?.clone();
+        for (k, v) in entries {
+            self.store(scope.clone(), k, v, Some(&vault));
         }
-        let keys: Vec<String> = self.globals.keys().cloned().collect();
-        let mut changed = false;
-        for key in keys {
-            if self.globals.remove(&key).is_some() {
-                self.bump(key, Scope::Global);
-                changed = true;
+        Ok(())
+    }
+
+    /// See [`Request::Track`].
+    pub fn track(&mut self, path: &Path, scope: Scope, origin: Origin) -> Result<()> {
+        let path = fs::canonicalize(path).with_context(|| format!("track {}", path.display()))?;
+        let entries = read_tracked(&path)?;
+        if let Some(watcher) = &mut self.file_watcher {
+            // Editors often save by renaming over the file, which a watch on the file itself
+            // would not follow.
+            let dir = path.parent().unwrap_or(Path::new("/"));
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("watch {}", dir.display()))?;
+        }
+        let scope = canon_scope(scope);
+        let keys = match self.tracked.remove(&path) {
+            Some(previous) if previous.scope == scope => previous.keys,
+            Some(previous) => {
+                self.batched(|st| {
+                    for key in previous.keys {
+                        st.unset(previous.scope.clone(), key);
+                    }
+                });
+                HashSet::new()
             }
+            None => HashSet::new(),
+        };
+        self.tracked.insert(
+            path.clone(),
+            Tracked {
+                scope,
+                pid: origin.pid,
+                keys,
+            },
+        );
+        self.apply_tracked(&path, entries);
+        Ok(())
+    }
+
+    /// See [`Request::Untrack`].
+    pub fn untrack(&mut self, path: &Path) -> Result<()> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.tracked.remove(&path).is_none() {
+            return Err(anyhow!("{} is not tracked", path.display()));
         }
-        changed
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        let dir_in_use = self.tracked.keys().any(|p| p.parent() == Some(dir));
+        if let (Some(watcher), false) = (&mut self.file_watcher, dir_in_use) {
+            let _ = watcher.unwatch(dir);
+        }
+        Ok(())
     }
 
-    pub fn reset_dir<P: AsRef<Path>>(&mut self, dir: P) -> bool {
-        let dir_c = canon(dir);
-        match self.scoped.remove(&dir_c) {
-            Some(map) => {
-                let scope = Scope::Dir(dir_c);
-                let mut changed = false;
-                for key in map.into_keys() {
-                    self.bump(key, scope.clone());
-                    changed = true;
-                }
-                changed
-            }
-            None => false,
+    /// Reloads `path` if it is tracked. A file that no longer exists unsets everything it
+    /// defined; one that no longer parses keeps its last loaded values.
+    pub fn reload(&mut self, path: &Path) -> Result<()> {
+        if !self.tracked.contains_key(path) {
+            return Ok(());
         }
+        let entries = read_tracked(path)?;
+        self.apply_tracked(path, entries);
+        Ok(())
     }
 
-    pub fn reset_all(&mut self) -> bool {
-        let mut changed = self.reset_globals();
-        let scoped_dirs: Vec<PathBuf> = self.scoped.keys().cloned().collect();
-        for dir in scoped_dirs {
-            if self.reset_dir(dir) {
-                changed = true;
+    // Brings a tracked file's scope in line with `entries` as one change.
+    fn apply_tracked(&mut self, path: &Path, entries: Vec<(String, String)>) {
+        let Some(tracked) = self.tracked.get(path) else {
+            return;
+        };
+        let scope = tracked.scope.clone();
+        let origin = Origin {
+            pid: tracked.pid,
+            file: Some(path.to_path_buf()),
+        };
+        let keys: HashSet<String> = entries.iter().map(|(k, _)| k.clone()).collect();
+        let removed: Vec<String> = tracked.keys.difference(&keys).cloned().collect();
+        let before = self.generation;
+        self.batched(|st| {
+            for key in removed {
+                st.unset(scope.clone(), key);
             }
+            st.load(scope.clone(), entries);
+        });
+        self.record_provenance(before, Source::Load, origin);
+        if let Some(tracked) = self.tracked.get_mut(path) {
+            tracked.keys = keys;
         }
-        changed
     }
 
-    pub fn effective_for_pwd(&self, pwd: &Path) -> HashMap<String, String> {
-        let mut map = self.globals.clone();
-        if let Some((_, overlay)) = self.best_scope_for_pwd(pwd) {
-            for (k, v) in overlay.iter() {
-                map.insert(k.clone(), v.clone());
+    /// Loads what an allowed `.envrc` at `path` exported into its directory's scope, as one
+    /// change. Keys it no longer exports are unset. `hash` is the content that was allowed; see
+    /// [`State::check_envrcs`].
+    pub fn allow_envrc(
+        &mut self,
+        path: &Path,
+        hash: [u8; 32],
+        vars: Vec<(String, String)>,
+        origin: Origin,
+    ) {
+        let path = canon(path);
+        let scope = Scope::Dir(path.parent().unwrap_or(Path::new("/")).to_path_buf());
+        let keys: HashSet<String> = vars.iter().map(|(k, _)| k.clone()).collect();
+        let removed: Vec<String> = self
+            .envrcs
+            .get(&path)
+            .map(|envrc| envrc.keys.difference(&keys).cloned().collect())
+            .unwrap_or_default();
+        let before = self.generation;
+        self.batched(|st| {
+            for key in removed {
+                st.unset(scope.clone(), key);
             }
-        }
-        map
+            st.load(scope.clone(), vars);
+        });
+        self.record_provenance(
+            before,
+            Source::Load,
+            Origin {
+                pid: origin.pid,
+                file: Some(path.clone()),
+            },
+        );
+        self.envrcs.insert(
+            path,
+            Envrc {
+                hash,
+                stamp: None,
+                keys,
+                blocked: false,
+            },
+        );
     }
 
-    pub fn get_effective(&self, key: &str, pwd: &Path) -> Option<String> {
-        if let Some((_, overlay)) = self.best_scope_for_pwd(pwd) {
-            if let Some(v) = overlay.get(key) {
-                return Some(v.clone());
+    /// Forgets an allowed `.envrc` and unsets what it loaded.
+    pub fn deny_envrc(&mut self, path: &Path) -> Result<()> {
+        let path = canon(path);
+        let envrc = self
+            .envrcs
+            .remove(&path)
+            .ok_or_else(|| anyhow!("{} is not allowed", path.display()))?;
+        self.unset_envrc(&path, envrc.keys);
+        Ok(())
+    }
+
+    /// Rechecks allowed `.envrc` files found by [`State::touched_envrcs`]. Those whose content
+    /// is still what was allowed are stamped again; the others are unloaded and stay blocked
+    /// until allowed again.
+    pub fn check_envrcs(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            // Stat before reading, so an edit racing with the read leaves a stale stamp behind.
+            let stamp = envrc_stamp(&path);
+            let hash = fs::read(&path).map(|content| sha256(&content)).ok();
+            let Some(envrc) = self.envrcs.get_mut(&path) else {
+                continue;
+            };
+            if envrc.blocked {
+                continue;
+            }
+            if hash == Some(envrc.hash) {
+                envrc.stamp = stamp;
+                continue;
             }
+            envrc.blocked = true;
+            let keys = std::mem::take(&mut envrc.keys);
+            self.unset_envrc(&path, keys);
         }
-        self.globals.get(key).cloned()
     }
 
-    // Returns best matching directory scope (deepest ancestor) and its map
-    fn best_scope_for_pwd(&self, pwd: &Path) -> Option<(PathBuf, &HashMap<String, String>)> {
-        let pwd = canon(pwd);
-        let mut best: Option<(PathBuf, &HashMap<String, String>)> = None;
-        for (dir, vars) in &self.scoped {
-            if is_ancestor(dir, &pwd) {
-                match &best {
-                    None => best = Some((dir.clone(), vars)),
-                    Some((bdir, _)) => {
-                        if dir.components().count() > bdir.components().count() {
-                            best = Some((dir.clone(), vars));
-                        }
-                    }
-                }
+    // Allowed `.envrc` files applying at `pwd` whose size or mtime no longer match their stamp,
+    // so their content may have changed. Only these are read and hashed again.
+    fn touched_envrcs(&self, pwd: &Path) -> Vec<PathBuf> {
+        self.envrcs
+            .iter()
+            .filter(|(path, envrc)| {
+                !envrc.blocked
+                    && path.parent().is_some_and(|dir| is_ancestor(dir, pwd))
+                    && (envrc.stamp.is_none() || envrc_stamp(path) != envrc.stamp)
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    // `Some` when a read at `pwd` has to unset expired keys or recheck `.envrc` files first,
+    // with the files to recheck.
+    fn needs_upkeep(&self, now: Instant, pwd: Option<&Path>) -> Option<Vec<PathBuf>> {
+        let touched = pwd.map(|pwd| self.touched_envrcs(pwd)).unwrap_or_default();
+        (self.next_expiry().is_some_and(|at| at <= now) || !touched.is_empty()).then_some(touched)
+    }
+
+    fn unset_envrc(&mut self, path: &Path, keys: HashSet<String>) {
+        let scope = Scope::Dir(path.parent().unwrap_or(Path::new("/")).to_path_buf());
+        self.batched(|st| {
+            for key in keys {
+                st.unset(scope.clone(), key);
             }
-        }
-        best
+        });
     }
 
-    pub fn export_since(&self, shell: ShellKind, since: u64, pwd: &Path) -> (String, u64) {
-        let new_gen = self.generation;
-        let mut changed_keys: HashSet<String> = HashSet::new();
-        let pwd_c = canon(pwd);
-        for ev in self.history.iter().filter(|e| e.generation > since) {
-            match &ev.scope {
-                Scope::Global => {
-                    changed_keys.insert(ev.key.clone());
-                }
-                Scope::Dir(dir) => {
-                    if is_ancestor(dir, &pwd_c) {
-                        changed_keys.insert(ev.key.clone());
+    // Applies writes received from a replication peer. They are marked as replicated so they
+    // are not sent back.
+    fn apply_replicated(&mut self, puts: Vec<Put>) -> Result<()> {
+        if puts.iter().any(|put| put.secret) {
+            self.vault()?;
+        }
+        self.replicating = true;
+        let applied = self.batched(|st| {
+            for put in puts {
+                match put.value {
+                    Some(value) if put.secret => {
+                        st.set_secret(put.scope, put.key, value)?;
+                    }
+                    Some(value) => {
+                        st.set(put.scope, put.key, value);
+                    }
+                    None => {
+                        st.unset(put.scope, put.key);
                     }
                 }
             }
-        }
+            Ok(())
+        });
+        self.replicating = false;
+        applied
+    }
+
+    // Local changes after `since` to scopes `map` replicates, as the peer should apply them.
+    fn replication_changes(&self, since: u64, map: impl Fn(&Scope) -> Option<Scope>) -> Vec<Put> {
+        self.changes_since(since, None)
+            .into_iter()
+            .filter(|ev| !ev.replicated)
+            .filter_map(|ev| {
+                let scope = map(&ev.scope)?;
+                Some(self.put(ev.key, scope, ev.new.as_ref()))
+            })
+            .collect()
+    }
+
+    // Every value in scopes `map` replicates.
+    fn replication_snapshot(&self, map: impl Fn(&Scope) -> Option<Scope>) -> Vec<Put> {
+        let scopes = std::iter::once((Scope::Global, &self.globals)).chain(
+            self.scoped
+                .iter()
+                .map(|(dir, vars)| (Scope::Dir(dir.clone()), vars)),
+        );
+        let mut puts = Vec::new();
+        for (scope, vars) in scopes {
+            let Some(scope) = map(&scope) else {
+                continue;
+            };
+            for (key, stored) in vars {
+                puts.push(self.put(key.clone(), scope.clone(), Some(stored)));
+            }
+        }
+        puts
+    }
+
+    fn put(&self, key: String, scope: Scope, stored: Option<&Stored>) -> Put {
+        let (value, secret) = match stored {
+            Some(Stored::Plain(value)) => (Some(value.clone()), false),
+            Some(Stored::Secret(sealed)) => (
+                self.vault.as_ref().and_then(|vault| vault.open(sealed)),
+                true,
+            ),
+            None => (None, false),
+        };
+        Put {
+            key,
+            scope,
+            value,
+            secret,
+        }
+    }
+
+    /// Applies `ops` in order, recording every change under one generation. Nothing is written
+    /// when a secret is needed and the secret key cannot be loaded.
+    pub fn batch(&mut self, ops: Vec<Op>, origin: Origin) -> Result<()> {
+        let needs_vault = ops.iter().any(|op| match op {
+            Op::Set { secret, .. } | Op::Load { secret, .. } => *secret,
+            Op::Unset { .. } => false,
+        });
+        if needs_vault {
+            self.vault()?;
+        }
+        self.batched(|st| {
+            let before = st.generation;
+            for op in ops {
+                let source = match op {
+                    Op::Set {
+                        key,
+                        value,
+                        scope,
+                        secret,
+                        ttl_secs,
+                    } => {
+                        if secret {
+                            st.set_secret(scope.clone(), key.clone(), value)?;
+                        } else {
+                            st.set(scope.clone(), key.clone(), value);
+                        }
+                        st.set_expiry(scope, key, ttl_secs.map(Duration::from_secs));
+                        Source::Set
+                    }
+                    Op::Unset { key, scope } => {
+                        st.unset(scope, key);
+                        continue;
+                    }
+                    Op::Load {
+                        entries,
+                        scope,
+                        secret,
+                    } => {
+                        if secret {
+                            st.load_secret(scope, entries)?;
+                        } else {
+                            st.load(scope, entries);
+                        }
+                        Source::Load
+                    }
+                };
+                // Keys written again later in the batch take the later op's provenance.
+                st.record_provenance(before, source, origin.clone());
+            }
+            Ok(())
+        })
+    }
+
+    // Runs `f` with every change it makes recorded under one generation.
+    fn batched<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        if self.batch_start.is_some() {
+            return f(self);
+        }
+        self.batch_start = Some(self.generation);
+        let out = f(self);
+        self.batch_start = None;
+        out
+    }
+
+    /// Stored values as written, before expansion. See [`Request::Dump`].
+    pub fn dump(
+        &self,
+        format: DumpFormat,
+        scope: Option<Scope>,
+        reveal_secrets: bool,
+    ) -> Result<String> {
+        let scope = scope.map(canon_scope);
+        if format == DumpFormat::Dotenv {
+            let scope = scope.unwrap_or(Scope::Global);
+            let vars: BTreeMap<&String, DocValue> = self
+                .vars_in(&scope)
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (k, self.doc_value(v, reveal_secrets)))
+                .collect();
+            let mut out = String::new();
+            for (key, value) in vars {
+                match value {
+                    DocValue::Plain(v) | DocValue::Detailed { value: Some(v), .. } => {
+                        out.push_str(&format!("{}={}\n", key, dotenv_double_quote(&v)));
+                    }
+                    DocValue::Detailed { value: None, .. } => {
+                        out.push_str(&format!("# {}: secret value omitted\n", key));
+                    }
+                }
+            }
+            return Ok(out);
+        }
+
+        let mut doc = EnvDocument::default();
+        for (dir_scope, vars) in std::iter::once((Scope::Global, &self.globals)).chain(
+            self.scoped
+                .iter()
+                .map(|(dir, vars)| (Scope::Dir(dir.clone()), vars)),
+        ) {
+            if vars.is_empty() || scope.as_ref().is_some_and(|s| *s != dir_scope) {
+                continue;
+            }
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.clone(), self.doc_value(v, reveal_secrets)))
+                .collect();
+            match dir_scope {
+                Scope::Global => doc.globals = vars,
+                Scope::Dir(dir) => {
+                    doc.dirs.insert(dir, vars);
+                }
+            }
+        }
+        Ok(match format {
+            DumpFormat::Yaml => serde_yaml::to_string(&doc)?,
+            _ => serde_json::to_string_pretty(&doc)? + "\n",
+        })
+    }
+
+    /// The effective environment at `pwd` in `format`. See [`Request::Effective`].
+    pub fn effective(
+        &self,
+        pwd: &Path,
+        format: DumpFormat,
+        reveal_secrets: bool,
+    ) -> Result<String> {
+        let pwd = canon(pwd);
+        let resolver = self.resolver(&pwd);
+        let mut definitions = self.definitions_for_pwd(&pwd);
+        let mut vars = BTreeMap::new();
+        for key in resolver.keys() {
+            let (Some((value, secret)), Some(definition)) =
+                (resolver.resolve(&key), definitions.remove(&key))
+            else {
+                continue;
+            };
+            let value = if secret && !reveal_secrets {
+                SECRET_MASK.to_string()
+            } else {
+                value
+            };
+            vars.insert(
+                key,
+                EffectiveVar {
+                    value,
+                    secret,
+                    definition,
+                },
+            );
+        }
+        if format == DumpFormat::Dotenv {
+            let mut out = String::new();
+            for (key, var) in vars {
+                if var.secret && !reveal_secrets {
+                    out.push_str(&format!("# {}: secret value omitted\n", key));
+                } else {
+                    out.push_str(&format!("{}={}\n", key, dotenv_double_quote(&var.value)));
+                }
+            }
+            return Ok(out);
+        }
+        let env = EffectiveEnv {
+            pwd,
+            generation: self.generation,
+            vars,
+        };
+        Ok(match format {
+            DumpFormat::Yaml => serde_yaml::to_string(&env)?,
+            _ => serde_json::to_string_pretty(&env)? + "\n",
+        })
+    }
+
+    /// Applies a dump over the current state. See [`Request::Import`].
+    pub fn import(&mut self, format: DumpFormat, data: &str, scope: Option<Scope>) -> Result<()> {
+        let scope = scope.map(canon_scope);
+        let mut entries: Vec<(Scope, String, DocValue)> = Vec::new();
+        match format {
+            DumpFormat::Dotenv => {
+                let target = scope.unwrap_or(Scope::Global);
+                for (k, v) in parse_dotenv(data.as_bytes())? {
+                    entries.push((target.clone(), k, DocValue::Plain(v)));
+                }
+            }
+            DumpFormat::Json | DumpFormat::Yaml => {
+                let doc: EnvDocument = if format == DumpFormat::Json {
+                    serde_json::from_str(data).context("parse JSON dump")?
+                } else {
+                    serde_yaml::from_str(data).context("parse YAML dump")?
+                };
+                for (k, v) in doc.globals {
+                    entries.push((Scope::Global, k, v));
+                }
+                for (dir, vars) in doc.dirs {
+                    let dir_scope = canon_scope(Scope::Dir(dir));
+                    for (k, v) in vars {
+                        entries.push((dir_scope.clone(), k, v));
+                    }
+                }
+                if let Some(scope) = &scope {
+                    entries.retain(|(s, _, _)| s == scope);
+                }
+            }
+        }
+
+        // Everything is checked before anything is written.
+        let mut writes = Vec::new();
+        for (scope, key, value) in entries {
+            if !is_valid_key(&key) {
+                return Err(anyhow!("invalid key: {}", key));
+            }
+            match value {
+                DocValue::Plain(v) => writes.push((scope, key, v, false)),
+                DocValue::Detailed {
+                    value: Some(v),
+                    secret,
+                } => writes.push((scope, key, v, secret)),
+                // A scrubbed secret; whatever is stored stays.
+                DocValue::Detailed {
+                    value: None,
+                    secret: true,
+                } => {}
+                DocValue::Detailed {
+                    value: None,
+                    secret: false,
+                } => return Err(anyhow!("missing value for {}", key)),
+            }
+        }
+        let vault = if writes.iter().any(|(_, _, _, secret)| *secret) {
+            Some(self.vault()?.clone())
+        } else {
+            None
+        };
+        for (scope, key, value, secret) in writes {
+            self.store(scope, key, value, vault.as_ref().filter(|_| secret));
+        }
+        Ok(())
+    }
+
+    fn vars_in(&self, scope: &Scope) -> Option<&HashMap<String, Stored>> {
+        match scope {
+            Scope::Global => Some(&self.globals),
+            Scope::Dir(dir) => self.scoped.get(dir),
+        }
+    }
+
+    fn doc_value(&self, stored: &Stored, reveal_secrets: bool) -> DocValue {
+        match stored {
+            Stored::Plain(v) => DocValue::Plain(v.clone()),
+            Stored::Secret(sealed) => DocValue::Detailed {
+                value: self
+                    .vault
+                    .as_ref()
+                    .filter(|_| reveal_secrets)
+                    .and_then(|vault| vault.open(sealed)),
+                secret: true,
+            },
+        }
+    }
+
+    /// Number of stored secret values, across all scopes.
+    pub fn secret_count(&self) -> usize {
+        std::iter::once(&self.globals)
+            .chain(self.scoped.values())
+            .flat_map(|vars| vars.values())
+            .filter(|v| matches!(v, Stored::Secret(_)))
+            .count()
+    }
+
+    fn vault(&mut self) -> Result<&Vault> {
+        let vault = match self.vault.take() {
+            Some(vault) => vault,
+            None => Vault::load()?,
+        };
+        Ok(self.vault.insert(vault))
+    }
+
+    fn resolver(&self, pwd: &Path) -> Resolver<'_> {
+        Resolver::new(self.layers_for_pwd(pwd), self.vault.as_ref())
+    }
+
+    pub fn reset_globals(&mut self) -> bool {
+        if self.globals.is_empty() {
+            return false;
+        }
+        let keys: Vec<String> = self.globals.keys().cloned().collect();
+        let mut changed = false;
+        for key in keys {
+            if let Some(old) = self.globals.remove(&key) {
+                self.bump(key, Scope::Global, Some(old));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn reset_dir<P: AsRef<Path>>(&mut self, dir: P) -> bool {
+        let dir_c = canon(dir);
+        match self.scoped.remove(&dir_c) {
+            Some(map) => {
+                let scope = Scope::Dir(dir_c);
+                let mut changed = false;
+                for (key, old) in map {
+                    self.bump(key, scope.clone(), Some(old));
+                    changed = true;
+                }
+                changed
+            }
+            None => false,
+        }
+    }
+
+    /// Unsets everything in `scope` under one generation. Returns whether anything was set.
+    pub fn clear_scope(&mut self, scope: Scope) -> bool {
+        self.batched(|st| match scope {
+            Scope::Global => st.reset_globals(),
+            Scope::Dir(dir) => st.reset_dir(dir),
+        })
+    }
+
+    /// Directory scopes holding values, sorted by path.
+    pub fn scopes(&self) -> Vec<ScopeInfo> {
+        let mut changed: HashMap<&Path, u64> = HashMap::new();
+        let folded = self
+            .folded
+            .iter()
+            .map(|((scope, _), folded)| (scope, folded.generation));
+        for (scope, generation) in
+            folded.chain(self.history.iter().map(|ev| (&ev.scope, ev.generation)))
+        {
+            if let Scope::Dir(dir) = scope {
+                let last = changed.entry(dir).or_default();
+                *last = (*last).max(generation);
+            }
+        }
+        let mut scopes: Vec<ScopeInfo> = self
+            .scoped
+            .iter()
+            .filter(|(_, vars)| !vars.is_empty())
+            .map(|(path, vars)| ScopeInfo {
+                path: path.clone(),
+                vars: vars.len(),
+                generation: changed.get(path.as_path()).copied().unwrap_or_default(),
+            })
+            .collect();
+        scopes.sort_by(|a, b| a.path.cmp(&b.path));
+        scopes
+    }
+
+    pub fn reset_all(&mut self) -> bool {
+        let mut changed = self.reset_globals();
+        let scoped_dirs: Vec<PathBuf> = self.scoped.keys().cloned().collect();
+        for dir in scoped_dirs {
+            if self.reset_dir(dir) {
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Effective values at `pwd`, with secrets masked.
+    pub fn effective_for_pwd(&self, pwd: &Path) -> HashMap<String, String> {
+        let resolver = self.resolver(pwd);
+        resolver
+            .keys()
+            .into_iter()
+            .filter_map(|k| resolver.masked(&k).map(|v| (k, v)))
+            .collect()
+    }
+
+    /// Effective values at `pwd`, secrets included.
+    pub fn resolve_for_pwd(&self, pwd: &Path) -> HashMap<String, String> {
+        let resolver = self.resolver(pwd);
+        resolver
+            .keys()
+            .into_iter()
+            .filter_map(|k| resolver.value(&k).map(|v| (k, v)))
+            .collect()
+    }
+
+    /// The effective value of `key` at `pwd`, masked if it is or references a secret.
+    pub fn get_effective(&self, key: &str, pwd: &Path) -> Option<String> {
+        self.resolver(pwd).masked(key)
+    }
+
+    /// Whole seconds left, rounded up, for effective keys at `pwd` that were set with a TTL.
+    pub fn ttls_for_pwd(&self, pwd: &Path) -> HashMap<String, u64> {
+        let now = Instant::now();
+        let mut defined_in: HashMap<&String, Scope> = HashMap::new();
+        for (scope, vars) in self.scopes_for_pwd(pwd) {
+            for key in vars.keys() {
+                defined_in.insert(key, scope.clone());
+            }
+        }
+        defined_in
+            .into_iter()
+            .filter_map(|(key, scope)| {
+                let at = self.expiries.get(&(scope, key.clone()))?;
+                Some((key.clone(), secs_left(*at, now)))
+            })
+            .collect()
+    }
+
+    /// Recorded changes after generation `since`, oldest first, optionally only for keys
+    /// matching `key_filter` (`*` matches any run of characters) or changes to one scope.
+    pub fn history(
+        &self,
+        since: u64,
+        key_filter: Option<&str>,
+        scope_filter: Option<Scope>,
+    ) -> Vec<HistoryEntry> {
+        let scope_filter = scope_filter.map(canon_scope);
+        self.history
+            .iter()
+            .filter(|ev| ev.generation > since)
+            .filter(|ev| key_filter.is_none_or(|pattern| glob_match(pattern, &ev.key)))
+            .filter(|ev| scope_filter.as_ref().is_none_or(|scope| *scope == ev.scope))
+            .map(HistoryEntry::from)
+            .collect()
+    }
+
+    /// Undoes the change at `generation`, or the latest change when `None`, by writing back
+    /// the values it replaced; a batch is undone as a whole. The revert is itself a change.
+    pub fn revert(&mut self, generation: Option<u64>) -> Result<Vec<HistoryEntry>> {
+        let generation = match generation {
+            Some(generation) => generation,
+            None => self
+                .history
+                .last()
+                .map(|ev| ev.generation)
+                .ok_or_else(|| anyhow!("no changes to revert"))?,
+        };
+        let events: Vec<ChangeEvent> = self
+            .history
+            .iter()
+            .filter(|ev| ev.generation == generation)
+            .cloned()
+            .collect();
+        if events.is_empty() && generation <= self.checkpoint {
+            return Err(anyhow!(
+                "generation {} was compacted; history starts after generation {}",
+                generation,
+                self.checkpoint
+            ));
+        }
+        if events.is_empty() {
+            return Err(anyhow!("no change at generation {}", generation));
+        }
+        // A key changed more than once goes back to what it held before the first change.
+        let mut seen = HashSet::new();
+        let pending: Vec<ChangeEvent> = events
+            .into_iter()
+            .filter(|ev| seen.insert((ev.scope.clone(), ev.key.clone())))
+            .filter(|ev| {
+                let current = self.vars_in(&ev.scope).and_then(|vars| vars.get(&ev.key));
+                !self.same_stored(current, ev.old.as_ref())
+            })
+            .collect();
+        if pending.is_empty() {
+            return Err(anyhow!(
+                "everything changed at generation {} already has its old value",
+                generation
+            ));
+        }
+        let start = self.history.len();
+        self.batched(|st| {
+            for event in pending {
+                let map = match &event.scope {
+                    Scope::Global => &mut st.globals,
+                    Scope::Dir(path) => st.scoped.entry(path.clone()).or_default(),
+                };
+                let old = match event.old {
+                    Some(value) => map.insert(event.key.clone(), value),
+                    None => map.remove(&event.key),
+                };
+                st.bump(event.key, event.scope, old);
+            }
+        });
+        Ok(self.history[start..]
+            .iter()
+            .map(HistoryEntry::from)
+            .collect())
+    }
+
+    fn same_stored(&self, a: Option<&Stored>, b: Option<&Stored>) -> bool {
+        match (a, b) {
+            (None, None) => true,
+            (Some(Stored::Plain(a)), Some(Stored::Plain(b))) => a == b,
+            (Some(Stored::Secret(a)), Some(Stored::Secret(b))) => self
+                .vault
+                .as_ref()
+                .is_some_and(|vault| vault.open(a) == vault.open(b)),
+            _ => false,
+        }
+    }
+
+    /// Records `origin` as where every value written after generation `since` came from.
+    pub fn record_provenance(&mut self, since: u64, source: Source, origin: Origin) {
+        let at = unix_now();
+        // Newest first, so a key written twice keeps its last write.
+        let written: Vec<(Scope, String, u64)> = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|ev| ev.generation > since)
+            .map(|ev| (ev.scope.clone(), ev.key.clone(), ev.generation))
+            .collect();
+        for (scope, key, generation) in written {
+            if !self
+                .vars_in(&scope)
+                .is_some_and(|vars| vars.contains_key(&key))
+            {
+                continue;
+            }
+            self.provenance
+                .entry((scope, key))
+                .or_insert_with(|| Provenance {
+                    source,
+                    file: origin.file.clone(),
+                    pid: origin.pid,
+                    at,
+                    generation,
+                });
+        }
+    }
+
+    /// Every definition of `key` that applies at `pwd`, least specific first.
+    pub fn describe(&self, key: &str, pwd: &Path) -> Vec<Definition> {
+        let now = Instant::now();
+        self.scopes_for_pwd(pwd)
+            .into_iter()
+            .filter_map(|(scope, vars)| {
+                let stored = vars.get(key)?;
+                Some(self.definition(scope, key, stored, now))
+            })
+            .collect()
+    }
+
+    /// The definition each effective key at `pwd` comes from.
+    pub fn definitions_for_pwd(&self, pwd: &Path) -> HashMap<String, Definition> {
+        let now = Instant::now();
+        let mut definitions = HashMap::new();
+        for (scope, vars) in self.scopes_for_pwd(pwd) {
+            for (key, stored) in vars {
+                let definition = self.definition(scope.clone(), key, stored, now);
+                definitions.insert(key.clone(), definition);
+            }
+        }
+        definitions
+    }
+
+    fn definition(&self, scope: Scope, key: &str, stored: &Stored, now: Instant) -> Definition {
+        let id = (scope, key.to_string());
+        let (raw, secret) = match stored {
+            Stored::Plain(v) => (v.clone(), false),
+            Stored::Secret(_) => (SECRET_MASK.to_string(), true),
+        };
+        Definition {
+            raw,
+            secret,
+            provenance: self.provenance.get(&id).cloned(),
+            ttl_secs: self.expiries.get(&id).map(|at| secs_left(*at, now)),
+            scope: id.0,
+        }
+    }
+
+    fn layers_for_pwd(&self, pwd: &Path) -> Vec<&HashMap<String, Stored>> {
+        self.scopes_for_pwd(pwd)
+            .into_iter()
+            .map(|(_, vars)| vars)
+            .collect()
+    }
+
+    // The scopes that apply at pwd, least specific first: globals, then every directory scope
+    // from the root down to pwd, so deeper scopes win per key.
+    fn scopes_for_pwd(&self, pwd: &Path) -> Vec<(Scope, &HashMap<String, Stored>)> {
+        let pwd = canon(pwd);
+        let mut ancestors: Vec<(&PathBuf, &HashMap<String, Stored>)> = self
+            .scoped
+            .iter()
+            .filter(|(dir, _)| is_ancestor(dir, &pwd))
+            .collect();
+        ancestors.sort_by_key(|(dir, _)| dir.components().count());
+        let mut scopes = vec![(Scope::Global, &self.globals)];
+        scopes.extend(
+            ancestors
+                .into_iter()
+                .map(|(dir, vars)| (Scope::Dir(dir.clone()), vars)),
+        );
+        scopes
+    }
+
+    // The directory session `id` last exported at.
+    fn export_pwd(&self, id: &str) -> Option<PathBuf> {
+        self.export_sessions
+            .lock()
+            .get(id)
+            .map(|(pwd, _)| pwd.clone())
+    }
+
+    fn record_export(&self, id: String, pwd: PathBuf) {
+        let mut sessions = self.export_sessions.lock();
+        sessions.insert(id, (pwd, Instant::now()));
+        // Shells that exited never say so; forget the longest idle ones.
+        if sessions.len() > MAX_EXPORT_SESSIONS {
+            let idle = sessions
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(id, _)| id.clone());
+            if let Some(idle) = idle {
+                sessions.remove(&idle);
+            }
+        }
+    }
+
+    /// A script bringing a shell that exported at `since` in `previous` (or in `pwd`, when
+    /// `None`) up to date in `pwd`, and the generation it brings it to.
+    pub fn export_since(
+        &self,
+        shell: ShellKind,
+        since: u64,
+        pwd: &Path,
+        previous: Option<&Path>,
+    ) -> (String, u64) {
+        let new_gen = self.generation;
+        let mut changed_keys: HashSet<String> = HashSet::new();
+        let pwd_c = canon(pwd);
+        let previous = previous.map(canon).filter(|prev| *prev != pwd_c);
+        // A generation this daemon never reached came from an earlier daemon; start over.
+        let since = if since > new_gen { 0 } else { since };
+        for ev in self.changes_since(since, None) {
+            let seen_before = previous
+                .as_deref()
+                .is_some_and(|prev| ev.visible_from(prev));
+            if ev.visible_from(&pwd_c) || seen_before {
+                changed_keys.insert(ev.key);
+            }
+        }
+        let resolver = self.resolver(&pwd_c);
+        // After a `cd`, everything that resolves differently in the new directory.
+        if let Some(prev) = &previous {
+            let before = self.resolver(prev);
+            for key in before.keys().into_iter().chain(resolver.keys()) {
+                if !changed_keys.contains(&key) && before.value(&key) != resolver.value(&key) {
+                    changed_keys.insert(key);
+                }
+            }
+        }
+        // Values that reference a changed key expand differently now too.
+        let keys = resolver.keys();
+        loop {
+            let before = changed_keys.len();
+            for key in &keys {
+                if !changed_keys.contains(key)
+                    && resolver
+                        .references(key)
+                        .iter()
+                        .any(|r| changed_keys.contains(r))
+                {
+                    changed_keys.insert(key.clone());
+                }
+            }
+            if changed_keys.len() == before {
+                break;
+            }
+        }
+
+        // For each changed key, compute current effective value for pwd. Exports are the one
+        // place secrets are emitted in the clear.
+        let withheld = withheld_keys(&self.policy, &resolver);
+        let mut actions: Vec<(String, Option<String>)> = Vec::new();
+        for key in changed_keys.into_iter() {
+            if withheld.contains(&key) {
+                continue;
+            }
+            let val = resolver.value(&key);
+            actions.push((key, val));
+        }
+        actions.sort_by(|a, b| a.0.cmp(&b.0));
+        let script = render_script(shell, &actions, new_gen);
+        (script, new_gen)
+    }
+}
+
+// --------------- Dumps ---------------
+
+// What `Dump` writes as JSON or YAML, keys sorted:
+//
+//     globals:
+//       FOO: bar
+//       TOKEN: { secret: true }
+//     dirs:
+//       /work/app:
+//         FOO: baz
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvDocument {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    globals: BTreeMap<String, DocValue>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dirs: BTreeMap<PathBuf, BTreeMap<String, DocValue>>,
+}
+
+// Plain values are bare strings; secrets carry the flag, and a value only when revealed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum DocValue {
+    Plain(String),
+    Detailed {
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        secret: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+}
+
+// --------------- Secrets ---------------
+
+/// What `Get` and `List` answer in place of a secret, or of a value that references one.
+pub const SECRET_MASK: &str = "********";
+
+// Derive the secret key from this file's contents instead of keeping one in the OS keyring.
+const PASSPHRASE_FILE_ENV: &str = "ENVD_PASSPHRASE_FILE";
+const KEYRING_SERVICE: &str = "cmux-envd";
+const KEYRING_USER: &str = "secret-key";
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone)]
+pub enum Stored {
+    Plain(String),
+    /// Encrypted in memory; only decrypted while resolving values.
+    Secret(Sealed),
+}
+
+#[derive(Clone)]
+pub struct Sealed {
+    // The salt of the vault that sealed it.
+    salt: [u8; SALT_LEN],
+    nonce: Nonce,
+    ciphertext: Vec<u8>,
+}
+
+impl fmt::Debug for Sealed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sealed(..)")
+    }
+}
+
+// Encrypts secrets with a key derived from `ENVD_PASSPHRASE_FILE` by argon2id, or else kept in
+// the OS keyring. Without a usable keyring the key is random and lives only as long as the
+// daemon, which loses nothing since state is not persisted either. Each vault draws a random
+// salt, so the same passphrase never yields the same key twice; sealed values carry it.
+#[derive(Clone)]
+struct Vault {
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_LEN],
+}
+
+impl fmt::Debug for Vault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Vault(..)")
+    }
+}
+
+impl Vault {
+    fn load() -> Result<Self> {
+        match std::env::var_os(PASSPHRASE_FILE_ENV) {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let passphrase = fs::read(&path)
+                    .with_context(|| format!("reading passphrase file {}", path.display()))?;
+                let passphrase = passphrase.trim_ascii();
+                if passphrase.is_empty() {
+                    return Err(anyhow!("passphrase file {} is empty", path.display()));
+                }
+                Self::from_passphrase(passphrase)
+            }
+            None => {
+                let key =
+                    keyring_key().unwrap_or_else(|_| ChaCha20Poly1305::generate_key(&mut OsRng));
+                Ok(Self::new(&key, new_salt()))
+            }
+        }
+    }
+
+    fn from_passphrase(passphrase: &[u8]) -> Result<Self> {
+        let salt = new_salt();
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase, &salt, &mut key)
+            .map_err(|e| anyhow!("deriving secret key: {e}"))?;
+        Ok(Self::new(&key, salt))
+    }
+
+    fn new(key: &Key, salt: [u8; SALT_LEN]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            salt,
+        }
+    }
+
+    fn seal(&self, value: &str) -> Sealed {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, value.as_bytes())
+            .expect("encrypt secret");
+        Sealed {
+            salt: self.salt,
+            nonce,
+            ciphertext,
+        }
+    }
+
+    // None for values another vault sealed.
+    fn open(&self, sealed: &Sealed) -> Option<String> {
+        if sealed.salt != self.salt {
+            return None;
+        }
+        let plain = self
+            .cipher
+            .decrypt(&sealed.nonce, sealed.ciphertext.as_slice())
+            .ok()?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+fn new_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+// The key stored in the OS keyring, created on first use.
+fn keyring_key() -> keyring::Result<Key> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)?;
+    match entry.get_secret() {
+        Ok(secret) if secret.len() == 32 => Ok(Key::clone_from_slice(&secret)),
+        Ok(_) | Err(keyring::Error::NoEntry) => {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+            entry.set_secret(&key)?;
+            Ok(key)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// --------------- Interpolation ---------------
+
+// Expands `$NAME` and `${NAME}` references when values are read. A reference resolves to the
+// effective value at pwd, except a key referring to itself (`PATH=$PATH:/opt/bin`), which
+// resolves to its value in the less specific scopes. Names no scope defines fall back to the
+// daemon's own environment. References caught in a cycle expand to nothing. A value that is
+// or references a secret is tainted, so it can be masked as a whole.
+struct Resolver<'a> {
+    layers: Vec<&'a HashMap<String, Stored>>,
+    vault: Option<&'a Vault>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(layers: Vec<&'a HashMap<String, Stored>>, vault: Option<&'a Vault>) -> Self {
+        Self { layers, vault }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let keys: HashSet<&String> = self.layers.iter().flat_map(|l| l.keys()).collect();
+        keys.into_iter().cloned().collect()
+    }
+
+    fn value(&self, key: &str) -> Option<String> {
+        self.lookup(key, self.layers.len(), &mut Vec::new(), &mut false)
+    }
+
+    fn masked(&self, key: &str) -> Option<String> {
+        let (value, secret) = self.resolve(key)?;
+        Some(if secret {
+            SECRET_MASK.to_string()
+        } else {
+            value
+        })
+    }
+
+    // The expanded value of `key`, and whether a secret went into it.
+    fn resolve(&self, key: &str) -> Option<(String, bool)> {
+        let mut secret = false;
+        let value = self.lookup(key, self.layers.len(), &mut Vec::new(), &mut secret)?;
+        Some((value, secret))
+    }
+
+    // The value as written, before expansion.
+    fn raw(&self, stored: &Stored) -> String {
+        match stored {
+            Stored::Plain(value) => value.clone(),
+            Stored::Secret(sealed) => self
+                .vault
+                .and_then(|vault| vault.open(sealed))
+                .unwrap_or_default(),
+        }
+    }
+
+    // Names referenced by any definition of `key`.
+    fn references(&self, key: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        for stored in self.layers.iter().filter_map(|l| l.get(key)) {
+            interpolate(&self.raw(stored), |name| {
+                names.push(name.to_string());
+                String::new()
+            });
+        }
+        names
+    }
+
+    // The expanded value of `key` from the layers below `depth`. Sets `secret` when a secret
+    // went into it.
+    fn lookup(
+        &self,
+        key: &str,
+        depth: usize,
+        visiting: &mut Vec<(String, usize)>,
+        secret: &mut bool,
+    ) -> Option<String> {
+        let (layer, stored) = self.layers[..depth]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(idx, l)| l.get(key).map(|stored| (idx, stored)))?;
+        if visiting.iter().any(|(k, l)| k == key && *l == layer) {
+            return Some(String::new());
+        }
+        *secret |= matches!(stored, Stored::Secret(_));
+        visiting.push((key.to_string(), layer));
+        let value = interpolate(&self.raw(stored), |name| {
+            let depth = if name == key {
+                layer
+            } else {
+                self.layers.len()
+            };
+            self.lookup(name, depth, visiting, secret)
+                .or_else(|| std::env::var(name).ok())
+                .unwrap_or_default()
+        });
+        visiting.pop();
+        Some(value)
+    }
+}
+
+// Replaces each `$NAME` / `${NAME}` in `raw` with `lookup(NAME)`. `\$` is a literal `$`, and a
+// `$` not followed by a name is kept as is.
+fn interpolate(raw: &str, mut lookup: impl FnMut(&str) -> String) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(pos) = rest.find(['$', '\\']) {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(after) = tail.strip_prefix("\\$") {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        if let Some(after) = tail.strip_prefix('\\') {
+            out.push('\\');
+            rest = after;
+            continue;
+        }
+        let after = &tail[1..];
+        if let Some(braced) = after.strip_prefix('{') {
+            if let Some(end) = braced.find('}') {
+                if is_valid_key(&braced[..end]) {
+                    out.push_str(&lookup(&braced[..end]));
+                    rest = &braced[end + 1..];
+                    continue;
+                }
+            }
+        } else {
+            let len = after
+                .char_indices()
+                .find(|(idx, c)| {
+                    !(*c == '_' || c.is_ascii_alphanumeric()) || (*idx == 0 && c.is_ascii_digit())
+                })
+                .map_or(after.len(), |(idx, _)| idx);
+            if len > 0 {
+                out.push_str(&lookup(&after[..len]));
+                rest = &after[len..];
+                continue;
+            }
+        }
+        out.push('$');
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_ancestor(a: &Path, b: &Path) -> bool {
+    let a = canon(a);
+    let b = canon(b);
+    b.starts_with(a)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Whether `text` matches `pattern`, where `*` matches any run of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+// A tracked dotenv file's entries; none once it has been deleted.
+fn read_tracked(path: &Path) -> Result<Vec<(String, String)>> {
+    match fs::File::open(path) {
+        Ok(file) => parse_dotenv(file).with_context(|| format!("parse {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+// Whole seconds until `at`, rounded up.
+fn secs_left(at: Instant, now: Instant) -> u64 {
+    let left = at.saturating_duration_since(now);
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+fn canon_scope(scope: Scope) -> Scope {
+    match scope {
+        Scope::Dir(p) => Scope::Dir(canon(p)),
+        x => x,
+    }
+}
+
+fn canon<P: AsRef<Path>>(p: P) -> PathBuf {
+    let p = p.as_ref();
+    match p.canonicalize() {
+        Ok(c) => c,
+        Err(_) => p.to_path_buf(),
+    }
+}
+
+// --------------- Scripting ---------------
+
+fn sh_single_quote(val: &str) -> String {
+    // Replace ' with '\'' pattern
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('\'');
+    for ch in val.chars() {
+        if ch == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(ch);
+        }
+    }
+    out.push('\'');
+    out
+}
+
+// Double-quoted with exactly the escapes `parse_dotenv` understands, so values round-trip.
+fn dotenv_double_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for ch in val.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// PowerShell single-quoted strings have no escapes; a quote is doubled. PowerShell also treats
+// the typographic single quotes as quote characters, so those are doubled too.
+fn ps_single_quote(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('\'');
+    for ch in val.chars() {
+        if matches!(ch, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            out.push(ch);
+        }
+        out.push(ch);
+    }
+    out.push('\'');
+    out
+}
+
+// Nushell raw strings (`r#'...'#`) take any text verbatim; only `'` followed by as many `#`
+// as the delimiter would end one early, so the delimiter gets one more `#` than any such run.
+fn nu_raw_string(val: &str) -> String {
+    let mut longest = 0;
+    for (idx, _) in val.match_indices('\'') {
+        let run = val[idx + 1..].chars().take_while(|c| *c == '#').count();
+        longest = longest.max(run);
+    }
+    let hashes = "#".repeat(longest + 1);
+    format!("r{hashes}'{val}'{hashes}")
+}
+
+fn render_script(shell: ShellKind, actions: &[(String, Option<String>)], new_gen: u64) -> String {
+    let mut out = String::new();
+    match shell {
+        ShellKind::Bash | ShellKind::Zsh => {
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("export {}={}\n", k, sh_single_quote(val)));
+                        }
+                        None => {
+                            out.push_str(&format!("unset -v {}\n", k));
+                        }
+                    }
+                }
+            }
+            out.push_str(&format!("export ENVCTL_GEN={}\n", new_gen));
+        }
+        ShellKind::Fish => {
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("set -x {} {}\n", k, sh_single_quote(val)))
+                        }
+                        None => out.push_str(&format!("set -e {}\n", k)),
+                    }
+                }
+            }
+            out.push_str(&format!("set -x ENVCTL_GEN {}\n", new_gen));
+        }
+        ShellKind::PowerShell => {
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("$env:{} = {}\n", k, ps_single_quote(val)))
+                        }
+                        None => out.push_str(&format!(
+                            "Remove-Item -Path Env:{} -ErrorAction SilentlyContinue\n",
+                            k
+                        )),
+                    }
+                }
+            }
+            out.push_str(&format!("$env:ENVCTL_GEN = '{}'\n", new_gen));
+        }
+        ShellKind::Nushell => {
+            for (k, v) in actions {
+                if is_valid_key(k) {
+                    match v {
+                        Some(val) => {
+                            out.push_str(&format!("$env.{} = {}\n", k, nu_raw_string(val)))
+                        }
+                        None => out.push_str(&format!("hide-env --ignore-errors {}\n", k)),
+                    }
+                }
+            }
+            out.push_str(&format!("$env.ENVCTL_GEN = '{}'\n", new_gen));
+        }
+    }
+    out
+}
+
+fn is_valid_key(k: &str) -> bool {
+    let first = k.chars().next();
+    if !first
+        .map(|c| c == '_' || c.is_ascii_alphabetic())
+        .unwrap_or(false)
+    {
+        return false;
+    }
+    k.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// --------------- Export policy ---------------
+
+/// Which keys exports to shells may carry, so deployment credentials stay out of interactive
+/// shells and their history expansions. A key matching a `deny` pattern is withheld unless it
+/// also matches an `allow` pattern, as is any value referencing a withheld key. Withheld keys
+/// are still answered by `Get` and `Resolve`, and passed to commands run with `envctl exec`.
+/// Patterns use `*` for any run of characters.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportPolicy {
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+// A policy file; YAML, so JSON works too.
+//
+//     export:
+//       deny: ["AWS_*", "*_DEPLOY_KEY"]
+//       allow: ["AWS_REGION"]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    export: ExportPolicy,
+}
+
+impl ExportPolicy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let file: PolicyFile =
+            serde_yaml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+        Ok(file.export)
+    }
+
+    /// Whether `key` itself may be exported.
+    pub fn exports(&self, key: &str) -> bool {
+        !self.deny.iter().any(|p| glob_match(p, key))
+            || self.allow.iter().any(|p| glob_match(p, key))
+    }
+}
+
+// Keys withheld from exports at a resolver's pwd: those the policy denies, and those whose
+// value references one, even through other keys.
+fn withheld_keys(policy: &ExportPolicy, resolver: &Resolver<'_>) -> HashSet<String> {
+    let mut withheld = HashSet::new();
+    if policy.deny.is_empty() {
+        return withheld;
+    }
+    let keys = resolver.keys();
+    loop {
+        let before = withheld.len();
+        for key in &keys {
+            if !withheld.contains(key)
+                && (!policy.exports(key)
+                    || resolver
+                        .references(key)
+                        .iter()
+                        .any(|r| withheld.contains(r) || !policy.exports(r)))
+            {
+                withheld.insert(key.clone());
+            }
+        }
+        if withheld.len() == before {
+            return withheld;
+        }
+    }
+}
+
+// --------------- .envrc ---------------
+
+// Variables bash itself sets, which an `.envrc` never means to export.
+const SHELL_VARS: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+
+// Prints every exported variable NUL-terminated, then an empty entry as a separator, sources
+// the file given as `$1`, and prints them again.
+const ENVRC_SCRIPT: &str = r#"dump() { local k; for k in $(compgen -e); do printf '%s=%s\0' "$k" "${!k}"; done; }
+dump
+printf '\0'
+source "$1" >/dev/null || exit
+dump
+"#;
+
+// What running an `.envrc` produced.
+struct EnvrcRun {
+    // Hash of the content that ran.
+    hash: [u8; 32],
+    // Variables it exported or changed.
+    vars: Vec<(String, String)>,
+}
+
+// Runs the `.envrc` at `path` with bash in its directory.
+fn eval_envrc(path: &Path) -> Result<EnvrcRun> {
+    let content = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let out = Command::new("bash")
+        .args(["--noprofile", "--norc", "-c", ENVRC_SCRIPT, "bash"])
+        .arg(path)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("run bash")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8(out.stdout).context("envrc output is not UTF-8")?;
+    let mut entries = stdout.split('\0');
+    let before: HashMap<&str, &str> = entries
+        .by_ref()
+        .take_while(|entry| !entry.is_empty())
+        .filter_map(|entry| entry.split_once('='))
+        .collect();
+    let vars = entries
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(k, v)| !SHELL_VARS.contains(k) && before.get(k) != Some(v))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    Ok(EnvrcRun {
+        hash: sha256(&content),
+        vars,
+    })
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+// Size and mtime of an allowed `.envrc`, used to skip rehashing it while they are unchanged.
+fn envrc_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+// --------------- Replication ---------------
+
+// How often a connected peer exchanges changes when nothing changed locally, which bounds how
+// long changes made on the listening side take to arrive.
+const SYNC_POLL: Duration = Duration::from_secs(1);
+// A listening peer drops connections that go this long without an exchange.
+const SYNC_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const SYNC_RETRY: Duration = Duration::from_secs(5);
+
+/// Replication of variables with another envd over TLS, e.g. from a laptop into a
+/// devcontainer. The connecting side chooses what is synced; after it connects, its values
+/// are pushed, and from then on changes on either side reach the other.
+#[derive(Debug, Clone)]
+pub enum Replication {
+    /// Accepts peers on `addr` that present `token`.
+    Listen {
+        addr: String,
+        cert: PathBuf,
+        key: PathBuf,
+        token: String,
+    },
+    /// Connects to the envd listening on `addr`, reconnecting whenever the connection drops.
+    Connect {
+        addr: String,
+        /// PEM certificates trusted for the peer.
+        ca: PathBuf,
+        server_name: String,
+        token: String,
+        scopes: SyncScopes,
+    },
+}
+
+/// What a connecting peer syncs.
+#[derive(Debug, Clone, Default)]
+pub struct SyncScopes {
+    pub global: bool,
+    /// Local directories and where they are on the peer; subdirectories follow.
+    pub dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SyncScopes {
+    fn to_remote(&self, scope: &Scope) -> Option<Scope> {
+        match scope {
+            Scope::Global => self.global.then_some(Scope::Global),
+            Scope::Dir(dir) => self.dirs.iter().find_map(|(local, remote)| {
+                let rest = dir.strip_prefix(canon(local)).ok()?;
+                Some(Scope::Dir(remote.join(rest)))
+            }),
+        }
+    }
+
+    fn to_local(&self, scope: &Scope) -> Option<Scope> {
+        match scope {
+            Scope::Global => self.global.then_some(Scope::Global),
+            Scope::Dir(dir) => self.dirs.iter().find_map(|(local, remote)| {
+                let rest = dir.strip_prefix(remote).ok()?;
+                Some(Scope::Dir(canon(local).join(rest)))
+            }),
+        }
+    }
+}
+
+// One write sent to a peer; `value` is `None` for an unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Put {
+    key: String,
+    scope: Scope,
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    secret: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum SyncRequest {
+    /// `dirs` are the listening side's paths.
+    Hello {
+        token: String,
+        global: bool,
+        dirs: Vec<PathBuf>,
+    },
+    /// The connecting side's changes, answered with the listening side's.
+    Exchange { puts: Vec<Put> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum SyncResponse {
+    Welcome,
+    Changes { puts: Vec<Put> },
+    Error { message: String },
+}
+
+fn start_replication(replication: Replication, state: &Arc<RwLock<State>>) -> Result<()> {
+    match replication {
+        Replication::Listen {
+            addr,
+            cert,
+            key,
+            token,
+        } => {
+            let config = tls_server_config(&cert, &key)?;
+            let listener = TcpListener::bind(&addr).with_context(|| format!("bind {}", addr))?;
+            let state = state.clone();
+            thread::spawn(move || {
+                for tcp in listener.incoming().flatten() {
+                    let (config, token, state) = (config.clone(), token.clone(), state.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = serve_peer(tcp, config, &token, &state) {
+                            eprintln!("envd: sync peer: {:#}", e);
+                        }
+                    });
+                }
+            });
+        }
+        Replication::Connect {
+            addr,
+            ca,
+            server_name,
+            token,
+            scopes,
+        } => {
+            let config = tls_client_config(&ca)?;
+            let server_name = rustls::ServerName::try_from(server_name.as_str())
+                .map_err(|_| anyhow!("invalid server name {}", server_name))?;
+            let state = state.clone();
+            thread::spawn(move || loop {
+                if let Err(e) = sync_to_peer(&addr, &config, &server_name, &token, &scopes, &state)
+                {
+                    eprintln!("envd: sync with {}: {:#}", addr, e);
+                }
+                thread::sleep(SYNC_RETRY);
+            });
+        }
+    }
+    Ok(())
+}
+
+fn serve_peer(
+    tcp: TcpStream,
+    config: Arc<rustls::ServerConfig>,
+    token: &str,
+    state: &RwLock<State>,
+) -> Result<()> {
+    tcp.set_read_timeout(Some(SYNC_IDLE_TIMEOUT))?;
+    let tls = rustls::StreamOwned::new(rustls::ServerConnection::new(config)?, tcp);
+    let mut stream = BufReader::new(tls);
+    let (global, dirs) = match read_json(&mut stream, Framing::Length)? {
+        Some(SyncRequest::Hello {
+            token: offered,
+            global,
+            dirs,
+        }) if Sha256::digest(&offered) == Sha256::digest(token) => {
+            (global, dirs.into_iter().map(canon).collect::<Vec<_>>())
+        }
+        _ => {
+            let refused = SyncResponse::Error {
+                message: "authentication failed".to_string(),
+            };
+            write_json(stream.get_mut(), Framing::Length, &refused)?;
+            return Err(anyhow!("peer failed to authenticate"));
+        }
+    };
+    let synced = |scope: &Scope| match scope {
+        Scope::Global => global.then_some(Scope::Global),
+        Scope::Dir(dir) => dirs
+            .iter()
+            .any(|root| dir.starts_with(root))
+            .then(|| scope.clone()),
+    };
+    let mut seen = state.read().generation;
+    write_json(stream.get_mut(), Framing::Length, &SyncResponse::Welcome)?;
+    stream.get_mut().flush()?;
+
+    while let Some(req) = read_json(&mut stream, Framing::Length)? {
+        let SyncRequest::Exchange { puts } = req else {
+            return Err(anyhow!("unexpected sync request"));
+        };
+        let resp = {
+            let mut st = state.write();
+            let puts = puts.into_iter().filter(|put| synced(&put.scope).is_some());
+            match st.apply_replicated(puts.collect()) {
+                Ok(()) => {
+                    let changes = st.replication_changes(seen, synced);
+                    seen = st.generation;
+                    SyncResponse::Changes { puts: changes }
+                }
+                Err(e) => SyncResponse::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        };
+        write_json(stream.get_mut(), Framing::Length, &resp)?;
+        stream.get_mut().flush()?;
+    }
+    Ok(())
+}
+
+fn sync_to_peer(
+    addr: &str,
+    config: &Arc<rustls::ClientConfig>,
+    server_name: &rustls::ServerName,
+    token: &str,
+    scopes: &SyncScopes,
+    state: &RwLock<State>,
+) -> Result<()> {
+    let tcp = TcpStream::connect(addr)?;
+    let conn = rustls::ClientConnection::new(config.clone(), server_name.clone())?;
+    let mut stream = BufReader::new(rustls::StreamOwned::new(conn, tcp));
+    let hello = SyncRequest::Hello {
+        token: token.to_string(),
+        global: scopes.global,
+        dirs: scopes
+            .dirs
+            .iter()
+            .map(|(_, remote)| remote.clone())
+            .collect(),
+    };
+    write_json(stream.get_mut(), Framing::Length, &hello)?;
+    stream.get_mut().flush()?;
+    match read_json(&mut stream, Framing::Length)? {
+        Some(SyncResponse::Welcome) => {}
+        Some(SyncResponse::Error { message }) => return Err(anyhow!(message)),
+        _ => return Err(anyhow!("unexpected sync response")),
+    }
+
+    // Local changes wake the exchange loop so they go out without waiting for the next poll.
+    let (tx, rx) = mpsc::channel();
+    let id = state.write().watch(None, tx);
+    let result = exchange_with_peer(&mut stream, scopes, state, &rx);
+    state.write().unwatch(id);
+    result
+}
+
+fn exchange_with_peer(
+    stream: &mut BufReader<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>,
+    scopes: &SyncScopes,
+    state: &RwLock<State>,
+    wake: &mpsc::Receiver<ChangeEvent>,
+) -> Result<()> {
+    let (mut puts, mut seen) = {
+        let st = state.read();
+        let snapshot = st.replication_snapshot(|scope| scopes.to_remote(scope));
+        (snapshot, st.generation)
+    };
+    loop {
+        write_json(
+            stream.get_mut(),
+            Framing::Length,
+            &SyncRequest::Exchange { puts },
+        )?;
+        stream.get_mut().flush()?;
+        let remote = match read_json(stream, Framing::Length)? {
+            Some(SyncResponse::Changes { puts }) => puts,
+            Some(SyncResponse::Error { message }) => return Err(anyhow!(message)),
+            _ => return Err(anyhow!("unexpected sync response")),
+        };
+        let local: Vec<Put> = remote
+            .into_iter()
+            .filter_map(|put| {
+                let scope = scopes.to_local(&put.scope)?;
+                Some(Put { scope, ..put })
+            })
+            .collect();
+        state.write().apply_replicated(local)?;
+
+        if wake.recv_timeout(SYNC_POLL).is_ok() {
+            while wake.try_recv().is_ok() {}
+        }
+        let st = state.read();
+        puts = st.replication_changes(seen, |scope| scopes.to_remote(scope));
+        seen = st.generation;
+    }
+}
+
+fn tls_server_config(cert: &Path, key: &Path) -> Result<Arc<rustls::ServerConfig>> {
+    let certs = read_certs(cert)?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(
+        fs::File::open(key).with_context(|| format!("open {}", key.display()))?,
+    ))
+    .with_context(|| format!("read private key from {}", key.display()))?;
+    let key = items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key found in {}", key.display()))?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate/key")?;
+    Ok(Arc::new(config))
+}
+
+fn tls_client_config(ca: &Path) -> Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in read_certs(ca)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("invalid certificate in {}", ca.display()))?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+// --------------- Server plumbing ---------------
+
+// Longest the sweeper sleeps, bounding how late a TTL set in the meantime is noticed.
+const EXPIRY_POLL: Duration = Duration::from_millis(250);
+// How long a tracked file must go without events before it is reloaded.
+const RELOAD_SETTLE: Duration = Duration::from_millis(50);
+// Changes kept in the history by default before the oldest are compacted.
+const HISTORY_LIMIT: usize = 10_000;
+// Connections served at once; more clients wait until one hangs up. A connection that turns
+// into a watch gives its slot back and takes one of `MAX_WATCHES` instead.
+const MAX_CONNECTIONS: usize = 256;
+// Watches streamed at once; past this, a new watch is refused rather than queued.
+const MAX_WATCHES: usize = 1024;
+
+/// Serves requests until SIGTERM or SIGINT. Under systemd, a socket passed in with
+/// `LISTEN_FDS` is used instead of binding one, readiness is reported with `sd_notify`, and the
+/// watchdog is pinged when `WatchdogSec` is set.
+pub fn run_server() -> Result<()> {
+    run_server_with(ServerOptions::default())
+}
+
+/// How [`run_server_with`] runs envd beyond the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Other envd instances to replicate variables with.
+    pub replications: Vec<Replication>,
+    /// Read instead of [`default_policy_path`], and required to exist.
+    pub policy_file: Option<PathBuf>,
+    /// Changes kept in the history before older ones are compacted; 10,000 when `None`.
+    pub history_limit: Option<usize>,
+    /// Listen here instead of at [`socket_path`].
+    pub socket: Option<PathBuf>,
+}
+
+/// Like [`run_server`], with `options`.
+pub fn run_server_with(options: ServerOptions) -> Result<()> {
+    let policy = match &options.policy_file {
+        Some(path) => ExportPolicy::load(path)?,
+        None => match default_policy_path() {
+            Some(path) if path.exists() => ExportPolicy::load(&path)?,
+            _ => ExportPolicy::default(),
+        },
+    };
+    let sock = match options.socket {
+        Some(sock) => sock,
+        None => socket_path()?,
+    };
+    ensure_socket_dir(&sock)?;
+    let pid_file = pid_path(&sock);
+    let (listener, owned_socket) = match activated_listener()? {
+        Some(listener) => (listener, None),
+        None => {
+            if sock.exists() {
+                let _ = fs::remove_file(&sock);
+            }
+            let listener =
+                UnixListener::bind(&sock).with_context(|| format!("bind {}", sock.display()))?;
+            (listener, Some(sock))
+        }
+    };
+    write_pid_file(&pid_file)?;
+    let state = Arc::new(RwLock::new(State {
+        policy,
+        ..State::default()
+    }));
+    shutdown_on_signal(&state, owned_socket, pid_file)?;
+    for replication in options.replications {
+        start_replication(replication, &state)?;
+    }
+    let sweeper = state.clone();
+    let history_limit = options.history_limit.unwrap_or(HISTORY_LIMIT).max(2);
+    thread::spawn(move || expire_loop(&sweeper, history_limit));
+    let (file_events, file_rx) = mpsc::channel();
+    match notify::recommended_watcher(file_events) {
+        Ok(watcher) => state.write().file_watcher = Some(watcher),
+        Err(e) => eprintln!("envd: tracked files will not reload: {:#}", e),
+    }
+    let reloader = state.clone();
+    thread::spawn(move || reload_loop(&reloader, file_rx));
+    let mut watchdog_usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+        let pinger = state.clone();
+        let interval = Duration::from_micros(watchdog_usec) / 2;
+        thread::spawn(move || watchdog_loop(&pinger, interval));
+    }
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+
+    let slots = Arc::new(ConnectionSlots::new(MAX_CONNECTIONS));
+    let watch_slots = Arc::new(ConnectionSlots::new(MAX_WATCHES));
+    loop {
+        // Taken before accepting, so clients past the limit wait in the listen backlog.
+        let slot = slots.acquire();
+        let (stream, _addr) = listener.accept()?;
+        let state = state.clone();
+        let watch_slots = watch_slots.clone();
+        std::thread::spawn(move || {
+            let _ = handle_connection(stream, &state, slot, &watch_slots);
+        });
+    }
+}
+
+// Counts connections being served, each on its own thread.
+struct ConnectionSlots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+// Held for the life of a connection.
+struct ConnectionSlot(Arc<ConnectionSlots>);
+
+impl ConnectionSlots {
+    fn new(limit: usize) -> Self {
+        Self {
+            free: Mutex::new(limit),
+            freed: Condvar::new(),
+        }
+    }
 
-        // For each changed key, compute current effective value for pwd
-        let mut actions: Vec<(String, Option<String>)> = Vec::new();
-        for key in changed_keys.into_iter() {
-            let val = self.get_effective(&key, &pwd_c);
-            actions.push((key, val));
+    // Waits until fewer than the limit are being served.
+    fn acquire(self: &Arc<Self>) -> ConnectionSlot {
+        let mut free = self.free.lock();
+        while *free == 0 {
+            self.freed.wait(&mut free);
         }
-        actions.sort_by(|a, b| a.0.cmp(&b.0));
-        let script = render_script(shell, &actions, new_gen);
-        (script, new_gen)
+        *free -= 1;
+        ConnectionSlot(self.clone())
     }
-}
 
-fn is_ancestor(a: &Path, b: &Path) -> bool {
-    let a = canon(a);
-    let b = canon(b);
-    b.starts_with(a)
+    // Like `acquire`, but gives up instead of waiting when none are free.
+    fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let mut free = self.free.lock();
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(ConnectionSlot(self.clone()))
+    }
 }
 
-fn canon<P: AsRef<Path>>(p: P) -> PathBuf {
-    let p = p.as_ref();
-    match p.canonicalize() {
-        Ok(c) => c,
-        Err(_) => p.to_path_buf(),
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.0.free.lock() += 1;
+        self.0.freed.notify_one();
     }
 }
 
-// --------------- Scripting ---------------
+// The socket systemd passed in, when socket-activated.
+fn activated_listener() -> Result<Option<UnixListener>> {
+    let mut fds = sd_notify::listen_fds().context("read LISTEN_FDS")?;
+    // SAFETY: LISTEN_FDS descriptors are handed to this process to own, and only the first
+    // is used.
+    Ok(fds
+        .next()
+        .map(|fd| unsafe { UnixListener::from_raw_fd(fd) }))
+}
 
-fn sh_single_quote(val: &str) -> String {
-    // Replace ' with '\'' pattern
-    let mut out = String::with_capacity(val.len() + 2);
-    out.push('\'');
-    for ch in val.chars() {
-        if ch == '\'' {
-            out.push_str("'\\''");
-        } else {
-            out.push(ch);
+// On SIGTERM or SIGINT, waits for the request being handled, removes the socket (unless
+// systemd owns it) and the pid file, and exits. State lives only in memory.
+fn shutdown_on_signal(
+    state: &Arc<RwLock<State>>,
+    socket: Option<PathBuf>,
+    pid_file: PathBuf,
+) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("install signal handlers")?;
+    let state = state.clone();
+    thread::spawn(move || {
+        if signals.forever().next().is_none() {
+            return;
+        }
+        let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+        let _held = state.write();
+        if let Some(socket) = &socket {
+            let _ = fs::remove_file(socket);
         }
+        let _ = fs::remove_file(&pid_file);
+        std::process::exit(0);
+    });
+    Ok(())
+}
+
+// Pings the systemd watchdog for as long as writes can still take the state lock.
+fn watchdog_loop(state: &RwLock<State>, interval: Duration) {
+    loop {
+        drop(state.write());
+        let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+        thread::sleep(interval);
     }
-    out.push('\'');
-    out
 }
 
-fn render_script(shell: ShellKind, actions: &[(String, Option<String>)], new_gen: u64) -> String {
-    let mut out = String::new();
-    match shell {
-        ShellKind::Bash | ShellKind::Zsh => {
-            for (k, v) in actions {
-                if is_valid_key(k) {
-                    match v {
-                        Some(val) => {
-                            out.push_str(&format!("export {}={}\n", k, sh_single_quote(val)));
-                        }
-                        None => {
-                            out.push_str(&format!("unset -v {}\n", k));
-                        }
-                    }
+fn reload_loop(state: &RwLock<State>, events: mpsc::Receiver<notify::Result<notify::Event>>) {
+    let mut changed = HashSet::new();
+    // A save is often several events (truncate, write, rename); reload once they settle.
+    while let Ok(mut next) = events.recv() {
+        loop {
+            if let Ok(event) = next {
+                if !event.kind.is_access() {
+                    changed.extend(event.paths);
                 }
             }
-            out.push_str(&format!("export ENVCTL_GEN={}\n", new_gen));
+            match events.recv_timeout(RELOAD_SETTLE) {
+                Ok(event) => next = event,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
         }
-        ShellKind::Fish => {
-            for (k, v) in actions {
-                if is_valid_key(k) {
-                    match v {
-                        Some(val) => {
-                            out.push_str(&format!("set -x {} {}\n", k, sh_single_quote(val)))
-                        }
-                        None => out.push_str(&format!("set -e {}\n", k)),
-                    }
-                }
+        let mut st = state.write();
+        for path in changed.drain() {
+            if let Err(e) = st.reload(&path) {
+                eprintln!("envd: {:#}", e);
             }
-            out.push_str(&format!("set -x ENVCTL_GEN {}\n", new_gen));
         }
     }
-    out
 }
 
-fn is_valid_key(k: &str) -> bool {
-    let first = k.chars().next();
-    if !first
-        .map(|c| c == '_' || c.is_ascii_alphabetic())
-        .unwrap_or(false)
-    {
-        return false;
+// Unsets keys as their TTLs run out, so watchers and shells see the change without anyone
+// having to ask first. Also compacts the history once it grows past `history_limit` changes,
+// down to half of that.
+fn expire_loop(state: &RwLock<State>, history_limit: usize) {
+    loop {
+        let next = {
+            let mut st = state.write();
+            st.expire(Instant::now());
+            if st.history.len() > history_limit {
+                st.compact(history_limit / 2);
+            }
+            st.next_expiry()
+        };
+        let wait = next.map_or(EXPIRY_POLL, |at| {
+            at.saturating_duration_since(Instant::now())
+                .min(EXPIRY_POLL)
+        });
+        thread::sleep(wait);
     }
-    k.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
 }
 
-// --------------- Server plumbing ---------------
-
-pub fn run_server() -> Result<()> {
-    let dir = ensure_socket_dir()?;
-    let sock = socket_path();
-    if sock.exists() {
-        let _ = fs::remove_file(&sock);
-    }
-    let listener = UnixListener::bind(&sock).with_context(|| format!("bind {}", sock.display()))?;
-    write_pid_file(&dir)?;
-    let state = Arc::new(Mutex::new(State::default()));
-
+// Answers requests until the client hangs up; a `Watch` turns the connection into a stream of
+// changes for the rest of its life, trading `slot` for one of `watch_slots` so long-lived
+// watchers never crowd out short requests.
+fn handle_connection(
+    stream: UnixStream,
+    state: &Arc<RwLock<State>>,
+    slot: ConnectionSlot,
+    watch_slots: &Arc<ConnectionSlots>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let framing = detect_framing(&mut reader)?;
+    // Requests are answered in order, so clients may send several before reading.
     loop {
-        let (mut stream, _addr) = listener.accept()?;
-        let state = state.clone();
-        std::thread::spawn(move || {
-            let resp = match read_json(&mut stream) {
-                Ok(req) => handle_request(req, &state),
-                Err(e) => Response::Error {
+        let req = match read_json(&mut reader, framing) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let resp = Response::Error {
                     message: format!("read error: {}", e),
-                },
-            };
-            let _ = write_json(&mut stream, &resp);
-        });
+                };
+                return write_json(&mut writer, framing, &resp);
+            }
+        };
+        match req {
+            Request::Watch { pwd, since } => {
+                let Some(_watch_slot) = watch_slots.try_acquire() else {
+                    let resp = Response::Error {
+                        message: format!("too many watches (limit {})", MAX_WATCHES),
+                    };
+                    return write_json(&mut writer, framing, &resp);
+                };
+                drop(slot);
+                return watch(reader, writer, framing, pwd, since, state);
+            }
+            req => write_json(&mut writer, framing, &handle_request(req, state))?,
+        }
+    }
+}
+
+fn watch(
+    mut reader: BufReader<UnixStream>,
+    mut writer: UnixStream,
+    framing: Framing,
+    pwd: Option<PathBuf>,
+    since: Option<u64>,
+    state: &Arc<RwLock<State>>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let (id, generation, backlog) = {
+        let mut st = state.write();
+        let backlog = match since {
+            Some(since) => st.changes_since(since, pwd.as_deref()),
+            None => Vec::new(),
+        };
+        (st.watch(pwd.as_deref(), tx), st.generation, backlog)
+    };
+
+    // Nothing more is expected from the client; when it hangs up, dropping its sender ends
+    // the loop below even if no change ever arrives.
+    let hangup_state = state.clone();
+    thread::spawn(move || {
+        let _ = io::copy(&mut reader, &mut io::sink());
+        hangup_state.write().unwatch(id);
+    });
+
+    let result = stream_changes(&mut writer, framing, generation, backlog, rx);
+    state.write().unwatch(id);
+    let _ = writer.shutdown(Shutdown::Both);
+    result
+}
+
+fn stream_changes(
+    writer: &mut UnixStream,
+    framing: Framing,
+    generation: u64,
+    backlog: Vec<ChangeEvent>,
+    rx: mpsc::Receiver<ChangeEvent>,
+) -> Result<()> {
+    write_json(writer, framing, &Response::Watching { generation })?;
+    for event in backlog.into_iter().chain(rx) {
+        write_json(writer, framing, &Response::Change(event))?;
     }
+    Ok(())
 }
 
 fn resolve_pwd(pwd: Option<PathBuf>) -> PathBuf {
     pwd.unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
 }
 
-fn handle_request(req: Request, state: &Arc<Mutex<State>>) -> Response {
-    let mut st = state.lock();
+// Returns the conflict response when a writer's expected generation is stale.
+fn check_generation(st: &State, if_generation: Option<u64>) -> Option<Response> {
+    match if_generation {
+        Some(expected) if expected != st.generation => Some(Response::Conflict {
+            expected_generation: expected,
+            current_generation: st.generation,
+        }),
+        _ => None,
+    }
+}
+
+fn handle_request(req: Request, state: &Arc<RwLock<State>>) -> Response {
+    let now = Instant::now();
+    let Some(pwd) = reads_at(&req) else {
+        let mut st = state.write();
+        // The sweeper may not have run yet; never answer with a value past its TTL.
+        st.expire(now);
+        return handle_write(req, &mut st);
+    };
+    // Reads share the lock. It is only taken exclusively first when there are expired keys or
+    // changed `.envrc` exports to unset.
+    let mut st = state.read();
+    if let Some(touched) = st.needs_upkeep(now, pwd.as_deref()) {
+        drop(st);
+        let mut upkeep = state.write();
+        upkeep.expire(now);
+        upkeep.check_envrcs(touched);
+        st = RwLockWriteGuard::downgrade(upkeep);
+    }
+    handle_read(req, &st)
+}
+
+// `Some` for requests that only read state, with the directory whose `.envrc` files apply to
+// them, if any.
+fn reads_at(req: &Request) -> Option<Option<PathBuf>> {
+    match req {
+        Request::Get { pwd, .. }
+        | Request::List { pwd, .. }
+        | Request::Describe { pwd, .. }
+        | Request::Effective { pwd, .. }
+        | Request::Resolve { pwd } => Some(Some(resolve_pwd(pwd.clone()))),
+        Request::Export { pwd, .. } => Some(Some(pwd.clone())),
+        Request::Ping
+        | Request::Status
+        | Request::Scopes
+        | Request::Dump { .. }
+        | Request::History { .. } => Some(None),
+        _ => None,
+    }
+}
+
+fn handle_read(req: Request, st: &State) -> Response {
     match req {
         Request::Ping => Response::Pong,
         Request::Status => Response::Status {
             generation: st.generation,
             globals: st.globals.len(),
             scopes: st.scoped.len(),
+            secrets: st.secret_count(),
+            checkpoint: st.checkpoint,
         },
-        Request::Set { key, value, scope } => {
-            st.set(scope, key, value);
-            Response::Ok
-        }
-        Request::Unset { key, scope } => {
-            st.unset(scope, key);
-            Response::Ok
-        }
         Request::Get { key, pwd } => {
             let pwd = resolve_pwd(pwd);
             let v = st.get_effective(&key, &pwd);
             Response::Value { value: v }
         }
-        Request::List { pwd } => {
+        Request::List {
+            pwd,
+            annotate,
+            prefix,
+        } => {
+            let pwd = resolve_pwd(pwd);
+            let mut entries = st.effective_for_pwd(&pwd);
+            let mut ttls = st.ttls_for_pwd(&pwd);
+            let mut definitions = if annotate {
+                st.definitions_for_pwd(&pwd)
+            } else {
+                HashMap::new()
+            };
+            if let Some(prefix) = prefix {
+                entries.retain(|key, _| key.starts_with(&prefix));
+                ttls.retain(|key, _| key.starts_with(&prefix));
+                definitions.retain(|key, _| key.starts_with(&prefix));
+            }
+            Response::Map {
+                entries,
+                ttls,
+                definitions,
+            }
+        }
+        Request::Describe { key, pwd } => {
+            let pwd = resolve_pwd(pwd);
+            Response::Description {
+                value: st.get_effective(&key, &pwd),
+                definitions: st.describe(&key, &pwd),
+            }
+        }
+        Request::Scopes => Response::Scopes {
+            scopes: st.scopes(),
+        },
+        Request::Export {
+            shell,
+            since,
+            pwd,
+            session,
+        } => {
+            let previous = session.as_ref().and_then(|id| st.export_pwd(id));
+            let (script, new_generation) = st.export_since(shell, since, &pwd, previous.as_deref());
+            if let Some(id) = session {
+                st.record_export(id, canon(&pwd));
+            }
+            Response::Export {
+                script,
+                new_generation,
+            }
+        }
+        Request::Dump {
+            format,
+            scope,
+            reveal_secrets,
+        } => match st.dump(format, scope, reveal_secrets) {
+            Ok(data) => Response::Dump { data },
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        },
+        Request::Effective {
+            pwd,
+            format,
+            reveal_secrets,
+        } => {
             let pwd = resolve_pwd(pwd);
-            let entries = st.effective_for_pwd(&pwd);
-            Response::Map { entries }
+            match st.effective(&pwd, format, reveal_secrets) {
+                Ok(data) => Response::Dump { data },
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+        Request::History {
+            since,
+            key_filter,
+            scope_filter,
+        } => Response::History {
+            entries: st.history(since, key_filter.as_deref(), scope_filter),
+        },
+        Request::Resolve { pwd } => Response::Map {
+            entries: st.resolve_for_pwd(&resolve_pwd(pwd)),
+            ttls: HashMap::new(),
+            definitions: HashMap::new(),
+        },
+        req => unreachable!("{:?} is not a read", req),
+    }
+}
+
+fn handle_write(req: Request, st: &mut RwLockWriteGuard<'_, State>) -> Response {
+    match req {
+        Request::Set {
+            key,
+            value,
+            scope,
+            secret,
+            ttl_secs,
+            origin,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            let before = st.generation;
+            let stored = if secret {
+                st.set_secret(scope.clone(), key.clone(), value)
+            } else {
+                Ok(st.set(scope.clone(), key.clone(), value))
+            };
+            match stored {
+                Ok(_) => {
+                    st.record_provenance(before, Source::Set, origin.unwrap_or_default());
+                    st.set_expiry(scope, key, ttl_secs.map(Duration::from_secs));
+                    Response::Ok
+                }
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
         }
-        Request::Load { entries, scope } => {
-            st.load(scope, entries);
+        Request::Unset {
+            key,
+            scope,
+            pattern,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            match pattern {
+                Some(_) if !key.is_empty() => {
+                    return Response::Error {
+                        message: "unset takes a key or a pattern, not both".to_string(),
+                    }
+                }
+                Some(pattern) => {
+                    st.unset_matching(scope, &pattern);
+                }
+                None => {
+                    st.unset(scope, key);
+                }
+            }
             Response::Ok
         }
+        Request::Load {
+            entries,
+            scope,
+            secret,
+            origin,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            let before = st.generation;
+            let loaded = if secret {
+                st.load_secret(scope, entries)
+            } else {
+                st.load(scope, entries);
+                Ok(())
+            };
+            match loaded {
+                Ok(()) => {
+                    st.record_provenance(before, Source::Load, origin.unwrap_or_default());
+                    Response::Ok
+                }
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+        Request::Batch {
+            ops,
+            origin,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            match st.batch(ops, origin.unwrap_or_default()) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+        Request::Track {
+            path,
+            scope,
+            origin,
+        } => match st.track(&path, scope, origin.unwrap_or_default()) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        },
+        Request::Untrack { path } => match st.untrack(&path) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        },
+        Request::Allow { path, origin } => {
+            let path = canon(&path);
+            // Other clients are served while it runs.
+            match RwLockWriteGuard::unlocked(st, || eval_envrc(&path)) {
+                Ok(run) => {
+                    st.allow_envrc(&path, run.hash, run.vars, origin.unwrap_or_default());
+                    Response::Ok
+                }
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+        Request::Deny { path } => match st.deny_envrc(&path) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+            },
+        },
         Request::Reset { scope } => {
             match scope {
                 Some(Scope::Global) => {
@@ -486,13 +3509,61 @@ fn handle_request(req: Request, state: &Arc<Mutex<State>>) -> Response {
             }
             Response::Ok
         }
-        Request::Export { shell, since, pwd } => {
-            let (script, new_generation) = st.export_since(shell, since, &pwd);
-            Response::Export {
-                script,
-                new_generation,
+        Request::ClearScope {
+            scope,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            st.clear_scope(scope);
+            Response::Ok
+        }
+        Request::Import {
+            format,
+            data,
+            scope,
+            origin,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            let before = st.generation;
+            match st.import(format, &data, scope) {
+                Ok(()) => {
+                    st.record_provenance(before, Source::Import, origin.unwrap_or_default());
+                    Response::Ok
+                }
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+        Request::Revert {
+            generation,
+            origin,
+            if_generation,
+        } => {
+            if let Some(conflict) = check_generation(st, if_generation) {
+                return conflict;
+            }
+            let before = st.generation;
+            match st.revert(generation) {
+                Ok(entries) => {
+                    st.record_provenance(before, Source::Revert, origin.unwrap_or_default());
+                    Response::History { entries }
+                }
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
             }
         }
+        // Handled by the connection loop, which owns the stream.
+        Request::Watch { .. } => Response::Error {
+            message: "watch must be the last request on a connection".to_string(),
+        },
+        req => unreachable!("{:?} is a read", req),
     }
 }
 
@@ -507,22 +3578,94 @@ pub fn client_send_autostart(req: &Request) -> Result<Response> {
 }
 
 fn client_send_inner(req: &Request, autostart: bool) -> Result<Response> {
-    let mut stream = connect_daemon(autostart)?;
-    let s = serde_json::to_string(req)?;
-    stream.write_all(s.as_bytes())?;
-    stream.write_all(b"\n")?;
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
-    reader.read_line(&mut line)?;
-    if line.is_empty() {
-        return Err(anyhow!("empty response"));
+    Client::connect(autostart)?.send(req)
+}
+
+/// A connection to the daemon that carries any number of requests, using length-prefixed
+/// frames.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    /// Connects to the daemon, starting it first when `autostart` is set and it is not running.
+    pub fn connect(autostart: bool) -> Result<Self> {
+        let mut writer = connect_daemon(autostart)?;
+        writer.write_all(FRAMED_PREAMBLE)?;
+        Ok(Client {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    pub fn send(&mut self, req: &Request) -> Result<Response> {
+        write_json(&mut self.writer, Framing::Length, req)?;
+        self.recv()
+    }
+
+    /// Sends every request before reading any response; responses come back in the same
+    /// order.
+    pub fn pipeline(&mut self, reqs: &[Request]) -> Result<Vec<Response>> {
+        let mut out = Vec::new();
+        for req in reqs {
+            write_json(&mut out, Framing::Length, req)?;
+        }
+        self.writer.write_all(&out)?;
+        reqs.iter().map(|_| self.recv()).collect()
+    }
+
+    /// Turns the connection into a watch; see [`client_watch`].
+    pub fn watch(mut self, pwd: Option<PathBuf>, since: Option<u64>) -> Result<Watch> {
+        match self.send(&Request::Watch { pwd, since })? {
+            Response::Watching { generation } => Ok(Watch {
+                reader: self.reader,
+                generation,
+            }),
+            Response::Error { message } => Err(anyhow!(message)),
+            _ => Err(anyhow!("unexpected response")),
+        }
+    }
+
+    fn recv(&mut self) -> Result<Response> {
+        read_json(&mut self.reader, Framing::Length)?.ok_or_else(|| anyhow!("empty response"))
+    }
+}
+
+/// Subscribes to changes visible from `pwd`, or to every change when `None`. With `since`,
+/// changes after that generation are replayed before live ones.
+pub fn client_watch(pwd: Option<PathBuf>, since: Option<u64>) -> Result<Watch> {
+    Client::connect(true)?.watch(pwd, since)
+}
+
+/// Changes streamed by the daemon; iteration ends when the daemon closes the connection.
+pub struct Watch {
+    reader: BufReader<UnixStream>,
+    generation: u64,
+}
+
+impl Watch {
+    /// The daemon's generation when the watch was registered.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_json(&mut self.reader, Framing::Length) {
+            Ok(Some(Response::Change(event))) => Some(Ok(event)),
+            Ok(Some(_)) => Some(Err(anyhow!("unexpected response"))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
     }
-    let resp: Response = serde_json::from_str(&line).context("parse response")?;
-    Ok(resp)
 }
 
 fn connect_daemon(autostart: bool) -> Result<UnixStream> {
-    let sock = socket_path();
+    let sock = socket_path()?;
     match UnixStream::connect(&sock) {
         Ok(stream) => Ok(stream),
         Err(err) => {
@@ -543,9 +3686,10 @@ fn should_autostart(kind: std::io::ErrorKind) -> bool {
 }
 
 fn start_daemon_and_connect(sock: &Path) -> Result<UnixStream> {
-    ensure_socket_dir()?;
+    ensure_socket_dir(sock)?;
     let envd_path = envd_executable_path()?;
     let mut cmd = Command::new(&envd_path);
+    cmd.arg("--socket").arg(sock);
     cmd.stdin(Stdio::null());
     cmd.stdout(Stdio::null());
     cmd.stderr(Stdio::null());
@@ -610,30 +3754,117 @@ fn envd_binary_name() -> &'static str {
     "envd"
 }
 
+/// Parses dotenv content as JS tooling writes it: optional `export ` prefixes, unquoted values
+/// with ` #` inline comments, single-quoted and backtick-quoted literals, double-quoted values
+/// with `\n`, `\r`, `\t`, `\"` and `\\` escapes, quoted values spanning lines, and CRLF.
 pub fn parse_dotenv<R: Read>(mut r: R) -> Result<Vec<(String, String)>> {
     let mut s = String::new();
     r.read_to_string(&mut s)?;
+    let s = s.replace("\r\n", "\n");
+    let s = s.strip_prefix('\u{feff}').unwrap_or(&s);
     let mut out = Vec::new();
-    for (idx, line) in s.lines().enumerate() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
+    let mut lines = s.split('\n').enumerate();
+    while let Some((idx, line)) = lines.next() {
+        // Trailing whitespace may belong to a quoted value that continues on the next line.
+        let line = line.trim_start();
+        if line.trim_end().is_empty() || line.starts_with('#') {
             continue;
         }
-        let line = line.strip_prefix("export ").unwrap_or(line);
-        if let Some(eq) = line.find('=') {
-            let (k, v) = line.split_at(eq);
-            let k = k.trim().to_string();
-            let v = v[1..].trim().to_string();
-            let v = strip_quotes(&v);
-            if !is_valid_key(&k) {
-                return Err(anyhow!("invalid key at line {}: {}", idx + 1, k));
+        let line = strip_export(line);
+        let Some(eq) = line.find('=') else {
+            return Err(anyhow!("invalid line {}: {}", idx + 1, line.trim_end()));
+        };
+        let k = line[..eq].trim().to_string();
+        if !is_valid_key(&k) {
+            return Err(anyhow!("invalid key at line {}: {}", idx + 1, k));
+        }
+        let raw = &line[eq + 1..];
+        let rest = raw.trim_start();
+        let v = match rest.chars().next() {
+            Some(quote @ ('"' | '\'' | '`')) => {
+                // Quoted values may continue over the following lines.
+                let mut body = rest[1..].to_string();
+                let (v, after) = loop {
+                    if let Some(parsed) = split_quoted(&body, quote) {
+                        break parsed;
+                    }
+                    match lines.next() {
+                        Some((_, next)) => {
+                            body.push('\n');
+                            body.push_str(next);
+                        }
+                        None => {
+                            return Err(anyhow!(
+                                "unterminated quoted value for {} at line {}",
+                                k,
+                                idx + 1
+                            ))
+                        }
+                    }
+                };
+                let after = after.trim_start();
+                if !after.is_empty() && !after.starts_with('#') {
+                    return Err(anyhow!(
+                        "unexpected characters after quoted value for {}: {}",
+                        k,
+                        after
+                    ));
+                }
+                v
+            }
+            _ => strip_inline_comment(raw).trim().to_string(),
+        };
+        out.push((k, v));
+    }
+    Ok(out)
+}
+
+fn strip_export(line: &str) -> &str {
+    match line.strip_prefix("export") {
+        Some(rest) if rest.starts_with([' ', '\t']) => rest.trim_start(),
+        _ => line,
+    }
+}
+
+// A `#` starts a comment only after whitespace, so `URL=http://host/#frag` keeps its fragment.
+fn strip_inline_comment(value: &str) -> &str {
+    let mut prev_space = false;
+    for (idx, ch) in value.char_indices() {
+        if ch == '#' && prev_space {
+            return &value[..idx];
+        }
+        prev_space = ch.is_whitespace();
+    }
+    value
+}
+
+// Splits `body` (the text after an opening quote) at its closing quote, returning the decoded
+// value and what follows the quote, or `None` when the quote is not closed yet. Only double
+// quotes process escapes; unknown escapes such as `\$` are kept as written.
+fn split_quoted(body: &str, quote: char) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = body.char_indices();
+    while let Some((idx, ch)) = chars.next() {
+        if ch == quote {
+            return Some((value, &body[idx + ch.len_utf8()..]));
+        }
+        if ch == '\\' && quote == '"' {
+            match chars.next()?.1 {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                '"' => value.push('"'),
+                '\\' => value.push('\\'),
+                other => {
+                    value.push('\\');
+                    value.push(other);
+                }
             }
-            out.push((k, v));
         } else {
-            return Err(anyhow!("invalid line {}: {}", idx + 1, line));
+            value.push(ch);
         }
     }
-    Ok(out)
+    None
 }
 
 pub fn parse_dotenv_base64<S: AsRef<str>>(data: S) -> Result<Vec<(String, String)>> {
@@ -648,10 +3879,26 @@ pub fn parse_dotenv_base64<S: AsRef<str>>(data: S) -> Result<Vec<(String, String
     parse_dotenv(Cursor::new(decoded))
 }
 
-fn strip_quotes(s: &str) -> String {
-    if (s.starts_with('\"') && s.ends_with('\"')) || (s.starts_with('\'') && s.ends_with('\'')) {
-        s[1..s.len() - 1].to_string()
-    } else {
-        s.to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vaults_from_one_passphrase_use_their_own_salt() {
+        let a = Vault::from_passphrase(b"correct horse").expect("vault a");
+        let b = Vault::from_passphrase(b"correct horse").expect("vault b");
+        assert_ne!(a.salt, b.salt);
+
+        let (sealed_a, sealed_b) = (a.seal("one"), b.seal("two"));
+        assert_eq!(a.open(&sealed_a).as_deref(), Some("one"));
+        assert_eq!(b.open(&sealed_b).as_deref(), Some("two"));
+        // Different salts give different keys; neither opens the other's values.
+        assert_eq!(a.open(&sealed_b), None);
+        assert_eq!(b.open(&sealed_a), None);
+        let forged = Sealed {
+            salt: a.salt,
+            ..sealed_b
+        };
+        assert_eq!(a.open(&forged), None);
     }
 }
@@ -4,14 +4,18 @@ use expectrl::{spawn, ControlCode};
 use predicates::prelude::*;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process::{Command, Stdio};
 use std::thread;
 use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 fn kill_envd_by_pid(tmp: &TempDir) {
-    let pid_path = tmp.path().join("cmux-envd/envd.pid");
-    let contents = match std::fs::read_to_string(&pid_path) {
+    kill_envd_by_pid_file(&tmp.path().join("cmux-envd/envd.pid"));
+}
+
+fn kill_envd_by_pid_file(pid_path: &Path) {
+    let contents = match std::fs::read_to_string(pid_path) {
         Ok(s) => s,
         Err(_) => return,
     };
@@ -29,13 +33,28 @@ fn kill_envd_by_pid(tmp: &TempDir) {
 }
 
 fn start_envd_with_runtime(tmp: &TempDir) -> std::process::Child {
+    start_envd_with_args(tmp, &[])
+}
+
+fn start_envd_with_args(tmp: &TempDir, args: &[&str]) -> std::process::Child {
+    start_envd_at(tmp, args, &tmp.path().join("cmux-envd/envd.sock"))
+}
+
+// Starts envd with `args` and waits for it to listen on `sock`.
+fn start_envd_at(tmp: &TempDir, args: &[&str], sock: &Path) -> std::process::Child {
+    // Keeps tests away from the OS keyring.
+    let passphrase = tmp.path().join("passphrase");
+    fs::write(&passphrase, "correct horse battery staple\n").unwrap();
     let mut cmd = Command::cargo_bin("envd").expect("binary envd");
+    cmd.args(args);
     cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("ENVD_PASSPHRASE_FILE", &passphrase);
+    // Keeps tests away from the user's export policy.
+    cmd.env("XDG_CONFIG_HOME", tmp.path().join("config"));
     cmd.stdout(Stdio::null());
     cmd.stderr(Stdio::null());
     let mut child = cmd.spawn().expect("start envd");
     // Wait for socket to show up
-    let sock = tmp.path().join("cmux-envd/envd.sock");
     let start = Instant::now();
     while !sock.exists() {
         if start.elapsed() > Duration::from_secs(3) {
@@ -119,6 +138,363 @@ fn set_and_export_bash() {
     let _ = child.wait();
 }
 
+#[test]
+fn export_powershell_and_nushell_escape_values() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "QUOTE=it's ‘here’"]).success();
+    run_envctl(&tmp, &["set", "RAW=a'#b"]).success();
+    run_envctl(&tmp, &["set", "GONE=1"]).success();
+    run_envctl(&tmp, &["unset", "GONE"]).success();
+
+    run_envctl(&tmp, &["export", "powershell", "--since", "0"])
+        .success()
+        .stdout(predicate::str::diff(
+            "Remove-Item -Path Env:GONE -ErrorAction SilentlyContinue\n\
+             $env:QUOTE = 'it''s ‘‘here’’'\n\
+             $env:RAW = 'a''#b'\n\
+             $env:ENVCTL_GEN = '4'\n",
+        ));
+    run_envctl(&tmp, &["export", "pwsh", "--since", "3"])
+        .success()
+        .stdout(predicate::str::diff(
+            "Remove-Item -Path Env:GONE -ErrorAction SilentlyContinue\n$env:ENVCTL_GEN = '4'\n",
+        ));
+
+    run_envctl(&tmp, &["export", "nushell", "--since", "0"])
+        .success()
+        .stdout(predicate::str::diff(
+            "hide-env --ignore-errors GONE\n\
+             $env.QUOTE = r#'it's ‘here’'#\n\
+             $env.RAW = r##'a'#b'##\n\
+             $env.ENVCTL_GEN = '4'\n",
+        ));
+
+    run_envctl(&tmp, &["hook", "nu"])
+        .failure()
+        .stderr(predicate::str::contains("nushell has no prompt hook"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn values_expand_references_at_resolution_time() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let project = tmp.path().join("project");
+    std::fs::create_dir_all(&project).unwrap();
+    let project_dir = project.to_str().unwrap();
+
+    run_envctl(&tmp, &["set", "DB_HOST=localhost"]).success();
+    run_envctl(&tmp, &["set", "DB_NAME=app"]).success();
+    run_envctl(
+        &tmp,
+        &["set", "DATABASE_URL=postgres://$DB_HOST/${DB_NAME}_dev"],
+    )
+    .success();
+    run_envctl(&tmp, &["get", "DATABASE_URL"])
+        .success()
+        .stdout(predicate::str::diff("postgres://localhost/app_dev\n"));
+
+    // References follow the effective scope chain, and a key referring to itself extends the
+    // value from the less specific scope.
+    run_envctl(&tmp, &["set", "DB_HOST=db.internal", "--dir", project_dir]).success();
+    run_envctl(&tmp, &["set", "TOOLPATH=/usr/bin"]).success();
+    run_envctl(
+        &tmp,
+        &[
+            "set",
+            "TOOLPATH=$TOOLPATH:/opt/tool/bin",
+            "--dir",
+            project_dir,
+        ],
+    )
+    .success();
+    run_envctl(&tmp, &["get", "DATABASE_URL", "--pwd", project_dir])
+        .success()
+        .stdout(predicate::str::diff("postgres://db.internal/app_dev\n"));
+    run_envctl(&tmp, &["get", "TOOLPATH", "--pwd", project_dir])
+        .success()
+        .stdout(predicate::str::diff("/usr/bin:/opt/tool/bin\n"));
+
+    // Escaped dollars stay literal; cycles expand to nothing instead of hanging.
+    run_envctl(&tmp, &["set", "PRICE=\\$5 $ 1"]).success();
+    run_envctl(&tmp, &["get", "PRICE"])
+        .success()
+        .stdout(predicate::str::diff("$5 $ 1\n"));
+    run_envctl(&tmp, &["set", "LOOP_A=a:$LOOP_B"]).success();
+    run_envctl(&tmp, &["set", "LOOP_B=b:$LOOP_A"]).success();
+    run_envctl(&tmp, &["get", "LOOP_A"])
+        .success()
+        .stdout(predicate::str::diff("a:b:\n"));
+
+    // Exports pick up values whose references changed.
+    run_envctl(&tmp, &["set", "DB_NAME=shop"]).success();
+    run_envctl(&tmp, &["export", "bash", "--since", "9"])
+        .success()
+        .stdout(predicate::str::diff(
+            "export DATABASE_URL='postgres://localhost/shop_dev'\n\
+             export DB_NAME='shop'\n\
+             export ENVCTL_GEN=10\n",
+        ));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn secrets_are_masked_except_in_exports() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "API_TOKEN=tok-123", "--secret"]).success();
+    run_envctl(
+        &tmp,
+        &["set", "HOOK_URL=https://hooks.local/?token=$API_TOKEN"],
+    )
+    .success();
+    run_envctl(&tmp, &["set", "PLAIN=visible"]).success();
+    let env_file = tmp.path().join("secrets.env");
+    fs::write(&env_file, "DB_PASSWORD='hunter2'\n").unwrap();
+    run_envctl(&tmp, &["load", env_file.to_str().unwrap(), "--secret"]).success();
+
+    // Secrets, and values built from them, never leave the daemon through get or list.
+    for key in ["API_TOKEN", "HOOK_URL", "DB_PASSWORD"] {
+        run_envctl(&tmp, &["get", key])
+            .success()
+            .stdout(predicate::str::diff("********\n"));
+    }
+    run_envctl(&tmp, &["get", "PLAIN"])
+        .success()
+        .stdout(predicate::str::diff("visible\n"));
+    run_envctl(&tmp, &["list"])
+        .success()
+        .stdout(predicate::str::contains("API_TOKEN=********\n"))
+        .stdout(predicate::str::contains("tok-123").not());
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("secrets: 2"));
+
+    run_envctl(&tmp, &["export", "bash", "--since", "0"])
+        .success()
+        .stdout(predicate::str::contains("export API_TOKEN='tok-123'\n"))
+        .stdout(predicate::str::contains(
+            "export HOOK_URL='https://hooks.local/?token=tok-123'\n",
+        ))
+        .stdout(predicate::str::contains("export DB_PASSWORD='hunter2'\n"));
+
+    // Re-setting the same secret is not a change; setting it in the clear is.
+    run_envctl(&tmp, &["set", "API_TOKEN=tok-123", "--secret"]).success();
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 4"));
+    run_envctl(&tmp, &["set", "API_TOKEN=tok-123"]).success();
+    run_envctl(&tmp, &["get", "API_TOKEN"])
+        .success()
+        .stdout(predicate::str::diff("tok-123\n"));
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 5"))
+        .stdout(predicate::str::contains("secrets: 1"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn keys_set_with_ttl_expire() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "AWS_SESSION_TOKEN=short", "--ttl", "1s"]).success();
+    run_envctl(&tmp, &["set", "KEPT=1", "--ttl", "1"]).success();
+    run_envctl(&tmp, &["set", "KEPT=1"]).success();
+    run_envctl(&tmp, &["set", "LATER=1", "--ttl", "1h"]).success();
+    run_envctl(&tmp, &["set", "ZERO=1", "--ttl", "0"]).failure();
+    run_envctl(&tmp, &["list"])
+        .success()
+        .stdout(predicate::str::contains(
+            "AWS_SESSION_TOKEN=***** (expires in 1s)\n",
+        ))
+        .stdout(predicate::str::contains("KEPT=*\n"))
+        .stdout(predicate::str::contains("LATER=* (expires in 3600s)\n"));
+
+    // Expiry is a change like any other: the generation moves and shells unset the key.
+    thread::sleep(Duration::from_millis(1500));
+    run_envctl(&tmp, &["get", "AWS_SESSION_TOKEN"])
+        .success()
+        .stdout(predicate::str::is_empty());
+    run_envctl(&tmp, &["get", "KEPT"])
+        .success()
+        .stdout(predicate::str::diff("1\n"));
+    run_envctl(&tmp, &["export", "bash", "--since", "3"])
+        .success()
+        .stdout(predicate::str::diff(
+            "unset -v AWS_SESSION_TOKEN\nexport ENVCTL_GEN=4\n",
+        ));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn dump_and_import_round_trip_scopes_and_secrets() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let project = tmp.path().join("project");
+    std::fs::create_dir_all(&project).unwrap();
+    let project_dir = project.to_str().unwrap();
+
+    run_envctl(&tmp, &["set", "FOO=bar"]).success();
+    run_envctl(&tmp, &["set", "URL=$FOO/x \"quoted\""]).success();
+    run_envctl(&tmp, &["set", "TOKEN=tok-123", "--secret"]).success();
+    run_envctl(&tmp, &["set", "FOO=baz", "--dir", project_dir]).success();
+
+    // Scrubbed dumps name secrets without their values.
+    run_envctl(&tmp, &["dump", "--format", "dotenv"])
+        .success()
+        .stdout(predicate::str::diff(
+            "FOO=\"bar\"\n# TOKEN: secret value omitted\nURL=\"$FOO/x \\\"quoted\\\"\"\n",
+        ));
+    run_envctl(&tmp, &["dump", "--format", "yaml"])
+        .success()
+        .stdout(predicate::str::contains("secret: true"))
+        .stdout(predicate::str::contains("tok-123").not());
+
+    let backup = run_envctl(&tmp, &["dump", "--reveal-secrets"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let backup_path = tmp.path().join("backup.json");
+    fs::write(&backup_path, &backup).unwrap();
+
+    // A fresh daemon hydrated from the backup has the same state.
+    let fresh = TempDir::new().unwrap();
+    let mut fresh_child = start_envd_with_runtime(&fresh);
+    run_envctl(&fresh, &["import", backup_path.to_str().unwrap()]).success();
+    run_envctl(&fresh, &["dump", "--reveal-secrets"])
+        .success()
+        .stdout(predicate::eq(backup.as_slice()));
+    run_envctl(&fresh, &["get", "TOKEN"])
+        .success()
+        .stdout(predicate::str::diff("********\n"));
+    run_envctl(&fresh, &["get", "FOO", "--pwd", project_dir])
+        .success()
+        .stdout(predicate::str::diff("baz\n"));
+
+    // Importing a scrubbed dump keeps stored secrets; bad input changes nothing.
+    let scrubbed = tmp.path().join("scrubbed.yaml");
+    let yaml = run_envctl(&tmp, &["dump", "--format", "yml", "--global"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    fs::write(&scrubbed, yaml).unwrap();
+    run_envctl(
+        &fresh,
+        &["import", scrubbed.to_str().unwrap(), "--format", "yaml"],
+    )
+    .success();
+    run_envctl(&fresh, &["export", "bash", "--since", "0"])
+        .success()
+        .stdout(predicate::str::contains("export TOKEN='tok-123'\n"));
+    let bad = tmp.path().join("bad.json");
+    fs::write(&bad, r#"{"globals": {"NEW": "1", "9BAD": "x"}}"#).unwrap();
+    run_envctl(&fresh, &["import", bad.to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("invalid key: 9BAD"));
+    run_envctl(&fresh, &["get", "NEW"])
+        .success()
+        .stdout(predicate::str::is_empty());
+
+    let _ = fresh_child.kill();
+    let _ = fresh_child.wait();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn describe_shows_where_values_came_from() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let repo = tmp.path().join("repo");
+    let package = repo.join("pkg");
+    std::fs::create_dir_all(&package).unwrap();
+    let repo = repo.canonicalize().unwrap();
+    let env_file = repo.join(".env");
+    fs::write(
+        &env_file,
+        "DATABASE_URL=postgres://$DB_HOST/app\nDB_HOST=db\n",
+    )
+    .unwrap();
+    // envctl attributes writes to the process that ran it.
+    let pid = std::process::id();
+
+    run_envctl(&tmp, &["set", "DATABASE_URL=postgres://localhost/app"]).success();
+    run_envctl(
+        &tmp,
+        &[
+            "load",
+            env_file.to_str().unwrap(),
+            "--dir",
+            repo.to_str().unwrap(),
+        ],
+    )
+    .success();
+
+    run_envctl(
+        &tmp,
+        &[
+            "describe",
+            "DATABASE_URL",
+            "--pwd",
+            package.to_str().unwrap(),
+        ],
+    )
+    .success()
+    .stdout(predicate::str::starts_with(
+        "DATABASE_URL=postgres://db/app\n",
+    ))
+    .stdout(predicate::str::contains(format!(
+        "  global: set by pid {} ",
+        pid
+    )))
+    .stdout(predicate::str::contains(
+        "(generation 1) = postgres://localhost/app\n",
+    ))
+    .stdout(predicate::str::contains(format!(
+        "* {}: loaded from {} by pid {} ",
+        repo.display(),
+        env_file.display(),
+        pid
+    )))
+    .stdout(predicate::str::contains(
+        "(generation 2) = postgres://$DB_HOST/app\n",
+    ));
+    run_envctl(
+        &tmp,
+        &["list", "--annotate", "--pwd", package.to_str().unwrap()],
+    )
+    .success()
+    .stdout(predicate::str::contains(format!(
+        "DB_HOST=** [{}: loaded from {}",
+        repo.display(),
+        env_file.display()
+    )));
+    run_envctl(
+        &tmp,
+        &["describe", "NOPE", "--pwd", package.to_str().unwrap()],
+    )
+    .success()
+    .stdout(predicate::str::starts_with("NOPE is not set at "));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 #[test]
 fn dir_scoped_overlay() {
     let tmp = TempDir::new().unwrap();
@@ -169,6 +545,53 @@ fn dir_scoped_overlay() {
     let _ = child.wait();
 }
 
+#[test]
+fn nested_dir_scopes_merge_from_root_to_pwd() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    let repo = tmp.path().join("repo");
+    let package = repo.join("packages").join("web");
+    std::fs::create_dir_all(&package).unwrap();
+    let repo_dir = repo.to_str().unwrap();
+    let package_dir = package.to_str().unwrap();
+
+    run_envctl(&tmp, &["set", "REGION=global"]).success();
+    run_envctl(&tmp, &["set", "API_URL=http://repo", "--dir", repo_dir]).success();
+    run_envctl(&tmp, &["set", "REGION=repo", "--dir", repo_dir]).success();
+    run_envctl(&tmp, &["set", "BIN=/repo/bin", "--dir", repo_dir]).success();
+    run_envctl(&tmp, &["set", "REGION=web", "--dir", package_dir]).success();
+    run_envctl(&tmp, &["set", "BIN=$BIN:/web/bin", "--dir", package_dir]).success();
+
+    // The repo root scope still applies inside the package; the deepest scope wins per key.
+    for (key, expected) in [
+        ("API_URL", "http://repo\n"),
+        ("REGION", "web\n"),
+        ("BIN", "/repo/bin:/web/bin\n"),
+    ] {
+        run_envctl(&tmp, &["get", key, "--pwd", package_dir])
+            .success()
+            .stdout(predicate::str::diff(expected));
+    }
+    run_envctl(&tmp, &["get", "REGION", "--pwd", repo_dir])
+        .success()
+        .stdout(predicate::str::diff("repo\n"));
+
+    // Unsetting the package value falls back to the next scope up, not straight to globals.
+    run_envctl(&tmp, &["unset", "REGION", "--dir", package_dir]).success();
+    run_envctl(
+        &tmp,
+        &["export", "bash", "--since", "6", "--pwd", package_dir],
+    )
+    .success()
+    .stdout(predicate::str::diff(
+        "export REGION='repo'\nexport ENVCTL_GEN=7\n",
+    ));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 #[test]
 fn get_and_list_default_to_client_pwd() {
     let tmp = TempDir::new().unwrap();
@@ -678,7 +1101,7 @@ fn hook_text_bash() -> String {
     // Replicate the bash hook emitted by envctl hook bash
     r#"__envctl_apply() {
   local out
-  out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD")" || return
+  out="$(envctl export bash --since "${ENVCTL_GEN:-0}" --pwd "$PWD" --session "$$")" || return
   eval "$out"
 }
 __envctl_capture_debug_trap() {
@@ -815,3 +1238,994 @@ fn load_from_base64_invalid_payload_fails() {
     let _ = child.kill();
     let _ = child.wait();
 }
+
+#[test]
+fn if_generation_rejects_stale_writers() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+
+    run_envctl(&tmp, &["set", "FOO=one"]).success();
+    // Generation is now 1; a writer that observed 1 may proceed.
+    run_envctl(&tmp, &["set", "FOO=two", "--if-generation", "1"]).success();
+    // A writer still holding generation 1 is refused and nothing is written.
+    run_envctl(&tmp, &["set", "FOO=stale", "--if-generation", "1"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "generation conflict: expected 1, daemon is at 2",
+        ));
+    run_envctl(&tmp, &["unset", "FOO", "--if-generation", "0"]).failure();
+    run_envctl(&tmp, &["get", "FOO"])
+        .success()
+        .stdout(predicate::str::diff("two\n"));
+
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.args(["load", "-", "--if-generation", "1"]);
+    cmd.stdin(Stdio::piped());
+    let mut ch = cmd.spawn().unwrap();
+    use std::io::Write;
+    ch.stdin.as_mut().unwrap().write_all(b"A=1\nB=2\n").unwrap();
+    let out = ch.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    run_envctl(&tmp, &["get", "A"])
+        .success()
+        .stdout(predicate::str::is_empty());
+    run_envctl(&tmp, &["unset", "FOO", "--if-generation", "2"]).success();
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn watch_streams_changes_visible_from_pwd() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let project = tmp.path().join("project");
+    let other = tmp.path().join("other");
+    fs::create_dir_all(&project).unwrap();
+    fs::create_dir_all(&other).unwrap();
+
+    // One connection now carries several requests.
+    let mut stream = UnixStream::connect(tmp.path().join("cmux-envd/envd.sock")).unwrap();
+    stream
+        .write_all(b"{\"type\":\"Ping\"}\n{\"type\":\"Status\"}\n")
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains("Pong"), "{}", line);
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains("\"generation\":0"), "{}", line);
+    drop(stream);
+
+    run_envctl(&tmp, &["set", "FOO=1"]).success();
+
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.args(["watch", "--since", "0", "--pwd"]);
+    cmd.arg(&project);
+    cmd.stdout(Stdio::piped());
+    let mut watcher = cmd.spawn().expect("start watch");
+    let stdout = watcher.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let next_event = || -> serde_json::Value {
+        let line = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("watch event");
+        serde_json::from_str(&line).unwrap()
+    };
+
+    // Changes from before the watch are replayed from --since.
+    let event = next_event();
+    assert_eq!(event["key"], "FOO");
+    assert_eq!(event["generation"], 1);
+    assert_eq!(event["scope"]["type"], "Global");
+
+    // Changes scoped elsewhere are not visible from the project directory.
+    let other_dir = other.to_str().unwrap();
+    let project_dir = project.to_str().unwrap();
+    run_envctl(&tmp, &["set", "HIDDEN=1", "--dir", other_dir]).success();
+    run_envctl(&tmp, &["set", "BAR=2", "--dir", project_dir]).success();
+    run_envctl(&tmp, &["unset", "FOO"]).success();
+
+    let event = next_event();
+    assert_eq!(event["key"], "BAR");
+    assert_eq!(event["generation"], 3);
+    assert_eq!(event["scope"]["type"], "Dir");
+    let event = next_event();
+    assert_eq!(event["key"], "FOO");
+    assert_eq!(event["generation"], 4);
+
+    let _ = watcher.kill();
+    let _ = watcher.wait();
+    // The daemon drops the watch once the client is gone and keeps serving.
+    run_envctl(&tmp, &["set", "BAZ=3"]).success();
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 5"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn history_lists_changes_and_undo_reverts_them() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let repo = tmp.path().join("repo");
+    std::fs::create_dir_all(&repo).unwrap();
+    let repo = repo.canonicalize().unwrap();
+
+    run_envctl(&tmp, &["set", "PORT=3000"]).success();
+    run_envctl(&tmp, &["set", "PORT=4000"]).success();
+    run_envctl(&tmp, &["set", "--secret", "TOKEN=hunter2"]).success();
+    run_envctl(&tmp, &["set", "--dir", repo.to_str().unwrap(), "DEBUG=1"]).success();
+    run_envctl(&tmp, &["unset", "PORT"]).success();
+
+    run_envctl(&tmp, &["history", "--key", "P*"])
+        .success()
+        .stdout(predicate::str::contains("global  PORT: (unset) -> 3000\n"))
+        .stdout(predicate::str::contains("global  PORT: 3000 -> 4000\n"))
+        .stdout(predicate::str::contains("global  PORT: 4000 -> (unset)\n"))
+        .stdout(predicate::str::contains("TOKEN").not())
+        .stdout(predicate::str::contains("DEBUG").not());
+    run_envctl(&tmp, &["history", "--since", "2", "--global"])
+        .success()
+        .stdout(predicate::str::starts_with("3  "))
+        .stdout(predicate::str::contains("TOKEN: (unset) -> ********\n"))
+        .stdout(predicate::str::contains("hunter2").not())
+        .stdout(predicate::str::contains("DEBUG").not());
+    run_envctl(&tmp, &["history", "--dir", repo.to_str().unwrap()])
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{}  DEBUG: (unset) -> 1\n",
+            repo.display()
+        )))
+        .stdout(predicate::str::contains("PORT").not());
+
+    // Undoing the unset brings back the value it removed.
+    run_envctl(&tmp, &["undo"])
+        .success()
+        .stdout(predicate::str::starts_with("6  "))
+        .stdout(predicate::str::contains("PORT: (unset) -> 4000\n"));
+    run_envctl(&tmp, &["get", "PORT"])
+        .success()
+        .stdout("4000\n");
+    run_envctl(&tmp, &["undo", "--generation", "5"])
+        .failure()
+        .stderr(predicate::str::contains("already has its old value"));
+
+    // Secrets come back sealed and still reach exports.
+    run_envctl(&tmp, &["undo", "--generation", "3"]).success();
+    run_envctl(&tmp, &["get", "TOKEN"]).success().stdout("");
+    run_envctl(&tmp, &["undo"]).success();
+    run_envctl(&tmp, &["export", "bash", "--since", "0", "--pwd", "/"])
+        .success()
+        .stdout(predicate::str::contains("hunter2"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn batch_applies_every_op_under_one_generation() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let repo = tmp.path().join("repo");
+    std::fs::create_dir_all(&repo).unwrap();
+    let repo = repo.canonicalize().unwrap();
+    let batch_file = tmp.path().join("batch.json");
+    let ops = serde_json::json!([
+        { "type": "Set", "key": "DB_HOST", "value": "db", "scope": { "type": "Global" } },
+        {
+            "type": "Set",
+            "key": "DB_PORT",
+            "value": "5432",
+            "scope": { "type": "Dir", "path": repo },
+        },
+        {
+            "type": "Load",
+            "entries": [["DB_USER", "app"], ["DB_PASS", "pw"]],
+            "scope": { "type": "Global" },
+            "secret": true,
+        },
+        { "type": "Unset", "key": "DB_OLD", "scope": { "type": "Global" } },
+    ]);
+    fs::write(&batch_file, ops.to_string()).unwrap();
+
+    run_envctl(&tmp, &["set", "DB_OLD=1"]).success();
+    run_envctl(&tmp, &["batch", batch_file.to_str().unwrap()]).success();
+
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 2\n"))
+        .stdout(predicate::str::contains("secrets: 2\n"));
+    let history = run_envctl(&tmp, &["history", "--since", "1"]).success();
+    let history = String::from_utf8(history.get_output().stdout.clone()).unwrap();
+    assert_eq!(history.lines().count(), 5, "{}", history);
+    assert!(
+        history.lines().all(|line| line.starts_with("2  ")),
+        "{}",
+        history
+    );
+    run_envctl(&tmp, &["get", "DB_PORT", "--pwd", repo.to_str().unwrap()])
+        .success()
+        .stdout("5432\n");
+
+    // Undo takes back the whole batch.
+    run_envctl(&tmp, &["undo"]).success();
+    run_envctl(&tmp, &["get", "DB_HOST"]).success().stdout("");
+    run_envctl(&tmp, &["get", "DB_OLD"]).success().stdout("1\n");
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 3\n"))
+        .stdout(predicate::str::contains("secrets: 0\n"));
+
+    fs::write(&batch_file, "[{\"type\": \"Rename\"}]").unwrap();
+    run_envctl(&tmp, &["batch", batch_file.to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("parse batch"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn tracked_dotenv_files_reload_when_edited() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let repo = tmp.path().join("repo");
+    std::fs::create_dir_all(&repo).unwrap();
+    let repo = repo.canonicalize().unwrap();
+    let env_file = repo.join(".env.local");
+    fs::write(&env_file, "API_URL=http://localhost:3000\nDEBUG=1\n").unwrap();
+    let pwd = repo.to_str().unwrap();
+    let get = |key: &str| {
+        let out = run_envctl(&tmp, &["get", key, "--pwd", pwd]).success();
+        String::from_utf8(out.get_output().stdout.clone()).unwrap()
+    };
+    let wait_for = |key: &str, expected: &str| {
+        let start = Instant::now();
+        while get(key) != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{} never became {:?}",
+                key,
+                expected
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    run_envctl(&tmp, &["track", env_file.to_str().unwrap(), "--dir", pwd]).success();
+    assert_eq!(get("API_URL"), "http://localhost:3000\n");
+
+    // Edited in place.
+    fs::write(&env_file, "API_URL=http://localhost:4000\nDEBUG=1\n").unwrap();
+    wait_for("API_URL", "http://localhost:4000\n");
+
+    // Saved by renaming over the file, the way many editors do; DEBUG was removed.
+    let replacement = repo.join(".env.local.tmp");
+    fs::write(&replacement, "API_URL=http://localhost:5000\nTRACE=1\n").unwrap();
+    fs::rename(&replacement, &env_file).unwrap();
+    wait_for("TRACE", "1\n");
+    assert_eq!(get("API_URL"), "http://localhost:5000\n");
+    assert_eq!(get("DEBUG"), "");
+    run_envctl(&tmp, &["describe", "TRACE", "--pwd", pwd])
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "loaded from {}",
+            env_file.display()
+        )));
+
+    // Untracked files keep their values but no longer reload.
+    run_envctl(&tmp, &["untrack", env_file.to_str().unwrap()]).success();
+    fs::write(&env_file, "API_URL=http://localhost:6000\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(get("API_URL"), "http://localhost:5000\n");
+    run_envctl(&tmp, &["untrack", env_file.to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("is not tracked"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn allowed_envrc_exports_load_into_its_directory() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let repo = tmp.path().join("repo");
+    let package = repo.join("pkg");
+    std::fs::create_dir_all(&package).unwrap();
+    let repo = repo.canonicalize().unwrap();
+    let envrc = repo.join(".envrc");
+    fs::write(
+        &envrc,
+        "export APP_ENV=dev\nexport BUILD=$(echo computed)\nNOT_EXPORTED=1\n",
+    )
+    .unwrap();
+    let inside = package.to_str().unwrap();
+    let outside = tmp.path().to_str().unwrap();
+
+    // Nothing runs until the file is allowed.
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("");
+    run_envctl(&tmp, &["allow", repo.to_str().unwrap()]).success();
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("dev\n");
+    run_envctl(&tmp, &["get", "BUILD", "--pwd", inside])
+        .success()
+        .stdout("computed\n");
+    run_envctl(&tmp, &["get", "NOT_EXPORTED", "--pwd", inside])
+        .success()
+        .stdout("");
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", outside])
+        .success()
+        .stdout("");
+
+    // Rewriting the same content is not an edit.
+    let content = fs::read(&envrc).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    fs::write(&envrc, &content).unwrap();
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("dev\n");
+    run_envctl(&tmp, &["get", "BUILD", "--pwd", inside])
+        .success()
+        .stdout("computed\n");
+
+    // Edits block the file until it is allowed again.
+    fs::write(&envrc, "export APP_ENV=prod\n").unwrap();
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("");
+    run_envctl(&tmp, &["allow", envrc.to_str().unwrap()]).success();
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("prod\n");
+    run_envctl(&tmp, &["get", "BUILD", "--pwd", inside])
+        .success()
+        .stdout("");
+
+    run_envctl(&tmp, &["deny", repo.to_str().unwrap()]).success();
+    run_envctl(&tmp, &["get", "APP_ENV", "--pwd", inside])
+        .success()
+        .stdout("");
+
+    fs::write(&envrc, "echo broken >&2\nreturn 1\n").unwrap();
+    run_envctl(&tmp, &["allow", repo.to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("broken"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn framed_connections_carry_pipelined_requests() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    fn frame(value: serde_json::Value) -> Vec<u8> {
+        let data = value.to_string().into_bytes();
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend(data);
+        out
+    }
+    fn read_frame(stream: &mut UnixStream) -> serde_json::Value {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).unwrap();
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let sock = tmp.path().join("cmux-envd/envd.sock");
+
+    let mut stream = UnixStream::connect(&sock).unwrap();
+    let mut out = cmux_env::FRAMED_PREAMBLE.to_vec();
+    out.extend(frame(serde_json::json!({
+        "type": "Set",
+        "key": "CERT",
+        "value": "line one\nline two\n",
+        "scope": { "type": "Global" },
+    })));
+    out.extend(frame(
+        serde_json::json!({ "type": "Get", "key": "CERT", "pwd": "/" }),
+    ));
+    out.extend(frame(serde_json::json!({ "type": "Status" })));
+    stream.write_all(&out).unwrap();
+
+    assert_eq!(read_frame(&mut stream)["type"], "Ok");
+    let value = read_frame(&mut stream);
+    assert_eq!(value["type"], "Value");
+    assert_eq!(value["value"], "line one\nline two\n");
+    let status = read_frame(&mut stream);
+    assert_eq!(status["type"], "Status");
+    assert_eq!(status["generation"], 1);
+
+    // Oversized frames are refused before anything is read into memory.
+    let mut stream = UnixStream::connect(&sock).unwrap();
+    stream.write_all(cmux_env::FRAMED_PREAMBLE).unwrap();
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let error = read_frame(&mut stream);
+    assert_eq!(error["type"], "Error");
+    assert!(
+        error["message"].as_str().unwrap().contains("too large"),
+        "{}",
+        error
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn sigterm_removes_socket_and_pid_file() {
+    use wait_timeout::ChildExt;
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let sock = tmp.path().join("cmux-envd/envd.sock");
+    let pid_file = tmp.path().join("cmux-envd/envd.pid");
+    run_envctl(&tmp, &["set", "FOO=1"]).success();
+    assert!(pid_file.exists());
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let status = child
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap()
+        .expect("envd exits on SIGTERM");
+    assert!(status.success(), "{}", status);
+    assert!(!sock.exists());
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn replication_syncs_scopes_between_daemons() {
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let cert = fixtures.join("localhost.crt");
+    let key = fixtures.join("localhost.key");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = format!("127.0.0.1:{}", port);
+
+    let remote = TempDir::new().unwrap();
+    let token = remote.path().join("token");
+    fs::write(&token, "s3cret-token\n").unwrap();
+    let remote_proj = remote.path().join("workspace");
+    fs::create_dir_all(&remote_proj).unwrap();
+    let remote_proj = remote_proj.canonicalize().unwrap();
+    let mut remote_child = start_envd_with_args(
+        &remote,
+        &[
+            "--sync-listen",
+            &addr,
+            "--tls-cert",
+            cert.to_str().unwrap(),
+            "--tls-key",
+            key.to_str().unwrap(),
+            "--sync-token-file",
+            token.to_str().unwrap(),
+        ],
+    );
+
+    let local = TempDir::new().unwrap();
+    let local_proj = local.path().join("proj");
+    fs::create_dir_all(&local_proj).unwrap();
+    let local_proj = local_proj.canonicalize().unwrap();
+    let sync_dir = format!("{}={}", local_proj.display(), remote_proj.display());
+    let mut local_child = start_envd_with_args(
+        &local,
+        &[
+            "--sync-to",
+            &addr,
+            "--tls-ca",
+            cert.to_str().unwrap(),
+            "--sync-token-file",
+            token.to_str().unwrap(),
+            "--sync-global",
+            "--sync-dir",
+            &sync_dir,
+        ],
+    );
+
+    let get = |tmp: &TempDir, key: &str, pwd: &std::path::Path| {
+        let out = run_envctl(tmp, &["get", key, "--pwd", pwd.to_str().unwrap()]).success();
+        String::from_utf8(out.get_output().stdout.clone()).unwrap()
+    };
+    let wait_for = |tmp: &TempDir, key: &str, pwd: &std::path::Path, expected: &str| {
+        let start = Instant::now();
+        while get(tmp, key, pwd) != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{} never became {:?}",
+                key,
+                expected
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+    };
+
+    run_envctl(&local, &["set", "EARLY=1"]).success();
+    wait_for(&remote, "EARLY", &remote_proj, "1\n");
+    run_envctl(
+        &local,
+        &[
+            "set",
+            "DB=postgres://local",
+            "--dir",
+            local_proj.to_str().unwrap(),
+        ],
+    )
+    .success();
+    wait_for(&remote, "DB", &remote_proj, "postgres://local\n");
+    run_envctl(&remote, &["set", "FROM_REMOTE=yes"]).success();
+    wait_for(&local, "FROM_REMOTE", &local_proj, "yes\n");
+    run_envctl(&remote, &["unset", "EARLY"]).success();
+    wait_for(&local, "EARLY", &local_proj, "");
+
+    // Directories outside the synced ones stay local.
+    run_envctl(
+        &local,
+        &["set", "PRIVATE=1", "--dir", local.path().to_str().unwrap()],
+    )
+    .success();
+    run_envctl(&local, &["set", "MARKER=1"]).success();
+    wait_for(&remote, "MARKER", remote.path(), "1\n");
+    assert_eq!(get(&remote, "PRIVATE", local.path()), "");
+
+    let _ = local_child.kill();
+    let _ = local_child.wait();
+    let _ = remote_child.kill();
+    let _ = remote_child.wait();
+}
+
+#[test]
+fn scopes_lists_directories_and_clear_wipes_one() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let app = tmp.path().join("app");
+    let api = tmp.path().join("api");
+    fs::create_dir_all(&app).unwrap();
+    fs::create_dir_all(&api).unwrap();
+    let app = app.canonicalize().unwrap();
+    let api = api.canonicalize().unwrap();
+    let (app_dir, api_dir) = (app.to_str().unwrap(), api.to_str().unwrap());
+
+    run_envctl(&tmp, &["set", "PORT=3000", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["set", "HOST=app.local", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["set", "PORT=4000", "--dir", api_dir]).success();
+    run_envctl(&tmp, &["set", "GLOBAL=1"]).success();
+    run_envctl(&tmp, &["scopes"]).success().stdout(format!(
+        "{}  1 vars  generation 3\n{}  2 vars  generation 2\n",
+        api_dir, app_dir
+    ));
+
+    run_envctl(&tmp, &["clear", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["scopes"])
+        .success()
+        .stdout(format!("{}  1 vars  generation 3\n", api_dir));
+    // Both keys went in one change.
+    run_envctl(&tmp, &["history", "--since", "4"])
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.lines().count() == 2 && out.lines().all(|line| line.starts_with("5  "))
+        }));
+    run_envctl(&tmp, &["get", "GLOBAL", "--pwd", app_dir])
+        .success()
+        .stdout("1\n");
+
+    run_envctl(&tmp, &["clear", "--global", "--if-generation", "4"])
+        .failure()
+        .stderr(predicate::str::contains("generation conflict"));
+    run_envctl(&tmp, &["clear", "--global"]).success();
+    run_envctl(&tmp, &["get", "GLOBAL", "--pwd", app_dir])
+        .success()
+        .stdout("");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_session_covers_directory_switches() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let app = tmp.path().join("app");
+    let api = tmp.path().join("api");
+    fs::create_dir_all(&app).unwrap();
+    fs::create_dir_all(&api).unwrap();
+    let (app_dir, api_dir) = (app.to_str().unwrap(), api.to_str().unwrap());
+
+    run_envctl(&tmp, &["set", "PORT=3000", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["set", "APP_ONLY=1", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["set", "PORT=4000", "--dir", api_dir]).success();
+    run_envctl(&tmp, &["set", "SHARED=1"]).success();
+    let export = |pwd: &str, since: &str, session: Option<&str>| {
+        let mut args = vec!["export", "bash", "--since", since, "--pwd", pwd];
+        if let Some(session) = session {
+            args.extend(["--session", session]);
+        }
+        let out = run_envctl(&tmp, &args).success();
+        String::from_utf8(out.get_output().stdout.clone()).unwrap()
+    };
+
+    let first = export(app_dir, "0", Some("42"));
+    assert!(first.contains("export PORT='3000'"));
+    assert!(first.contains("export ENVCTL_GEN=4"));
+    // Nothing changed since generation 4, but the shell moved to another directory.
+    let moved = export(api_dir, "4", Some("42"));
+    assert_eq!(
+        moved,
+        "unset -v APP_ONLY\nexport PORT='4000'\nexport ENVCTL_GEN=4\n"
+    );
+    assert_eq!(export(api_dir, "4", Some("42")), "export ENVCTL_GEN=4\n");
+    // Without a session only changes since `--since` are known.
+    assert_eq!(export(app_dir, "4", None), "export ENVCTL_GEN=4\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn unset_pattern_and_list_prefix() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "AWS_ACCESS_KEY_ID=AKIA"]).success();
+    run_envctl(&tmp, &["set", "AWS_SECRET_ACCESS_KEY=s3cret", "--secret"]).success();
+    run_envctl(&tmp, &["set", "AWS_REGION=us-east-1"]).success();
+    run_envctl(&tmp, &["set", "APP_AWS_BUCKET=assets"]).success();
+
+    run_envctl(&tmp, &["list", "--prefix", "AWS_", "--pwd", "/"])
+        .success()
+        .stdout(predicate::str::contains(
+            "Active environment variables (3):",
+        ))
+        .stdout(predicate::str::contains("APP_AWS_BUCKET").not());
+
+    run_envctl(&tmp, &["unset", "AWS_*"]).success();
+    run_envctl(&tmp, &["list", "--prefix", "AWS_", "--pwd", "/"])
+        .success()
+        .stdout("No environment variables found.\n");
+    run_envctl(&tmp, &["get", "APP_AWS_BUCKET", "--pwd", "/"])
+        .success()
+        .stdout("assets\n");
+    // All three went in one change.
+    run_envctl(&tmp, &["history", "--since", "4"])
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.lines().count() == 3 && out.lines().all(|line| line.starts_with("5  "))
+        }));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn effective_reports_merged_values_with_their_scope() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let app = tmp.path().join("app");
+    fs::create_dir_all(&app).unwrap();
+    let app = app.canonicalize().unwrap();
+    let app_dir = app.to_str().unwrap();
+    run_envctl(&tmp, &["set", "FOO=bar"]).success();
+    run_envctl(&tmp, &["set", "FOO=baz", "--dir", app_dir]).success();
+    run_envctl(&tmp, &["set", "HOST=localhost"]).success();
+    run_envctl(&tmp, &["set", "TOKEN=s3cret", "--secret"]).success();
+
+    let out = run_envctl(&tmp, &["effective", "--pwd", app_dir]).success();
+    let env: serde_json::Value = serde_json::from_slice(&out.get_output().stdout).unwrap();
+    assert_eq!(env["pwd"], app_dir);
+    assert_eq!(env["generation"], 4);
+    assert_eq!(env["vars"]["FOO"]["value"], "baz");
+    assert_eq!(env["vars"]["FOO"]["definition"]["scope"]["type"], "Dir");
+    assert_eq!(env["vars"]["FOO"]["definition"]["scope"]["path"], app_dir);
+    assert_eq!(env["vars"]["HOST"]["definition"]["scope"]["type"], "Global");
+    assert_eq!(env["vars"]["TOKEN"]["value"], "********");
+    assert_eq!(env["vars"]["TOKEN"]["secret"], true);
+
+    run_envctl(&tmp, &["effective", "--pwd", app_dir, "--format", "dotenv"])
+        .success()
+        .stdout("FOO=\"baz\"\nHOST=\"localhost\"\n# TOKEN: secret value omitted\n");
+    run_envctl(
+        &tmp,
+        &[
+            "effective",
+            "--pwd",
+            app_dir,
+            "--format",
+            "dotenv",
+            "--reveal-secrets",
+        ],
+    )
+    .success()
+    .stdout(predicate::str::contains("TOKEN=\"s3cret\"\n"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn evaluated_bash_hook_applies_changes_before_each_command() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "FOO=1"]).success();
+    let envctl_path = cargo_bin("envctl");
+    let envctl_dir = envctl_path.parent().expect("envctl dir");
+
+    let out = Command::new("bash")
+        .env("XDG_RUNTIME_DIR", tmp.path())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                envctl_dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .env_remove("ENVCTL_GEN")
+        .arg("-c")
+        .arg(
+            r#"eval "$(envctl hook bash)"
+printf '%s\n' "$FOO"
+envctl set FOO=2
+printf '%s\n' "$FOO""#,
+        )
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "1\n2\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn concurrent_clients_are_all_answered() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let sock = tmp.path().join("cmux-envd/envd.sock");
+
+    // An idle watcher holds its connection open without holding up anyone else.
+    let mut watcher = UnixStream::connect(&sock).unwrap();
+    watcher
+        .write_all(b"{\"type\":\"Watch\",\"pwd\":null}\n")
+        .unwrap();
+
+    let clients: Vec<_> = (0..16)
+        .map(|client| {
+            let sock = sock.clone();
+            thread::spawn(move || {
+                let mut stream = UnixStream::connect(&sock).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                for i in 0..20 {
+                    let key = format!("K_{}_{}", client, i);
+                    let requests = format!(
+                        "{}\n{}\n",
+                        serde_json::json!({"type": "Set", "key": key, "value": i.to_string(), "scope": {"type": "Global"}}),
+                        serde_json::json!({"type": "Get", "key": key, "pwd": "/"}),
+                    );
+                    stream.write_all(requests.as_bytes()).unwrap();
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    assert!(line.contains("\"Ok\""), "{}", line);
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let expected = format!("\"value\":\"{}\"", i);
+                    assert!(line.contains(&expected), "{}", line);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    run_envctl(&tmp, &["status"])
+        .success()
+        .stdout(predicate::str::contains("generation: 320"));
+
+    drop(watcher);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn watchers_do_not_hold_up_requests() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    let sock = tmp.path().join("cmux-envd/envd.sock");
+
+    // More watchers than envd serves request connections at once.
+    let watchers: Vec<_> = (0..300)
+        .map(|_| {
+            let mut stream = UnixStream::connect(&sock).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream
+                .write_all(b"{\"type\":\"Watch\",\"pwd\":null}\n")
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut line)
+                .expect("watch started");
+            assert!(line.contains("Watching"), "{}", line);
+            stream
+        })
+        .collect();
+
+    run_envctl(&tmp, &["set", "AFTER_WATCHERS=1"]).success();
+    run_envctl(&tmp, &["get", "AFTER_WATCHERS"])
+        .success()
+        .stdout(predicate::str::contains("1"));
+
+    drop(watchers);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn export_policy_keeps_denied_keys_out_of_shells() {
+    let tmp = TempDir::new().unwrap();
+    let policy = tmp.path().join("config/cmux-env/policy.yaml");
+    fs::create_dir_all(policy.parent().unwrap()).unwrap();
+    fs::write(
+        &policy,
+        "export:\n  deny: [\"DEPLOY_*\"]\n  allow: [\"DEPLOY_ENV\"]\n",
+    )
+    .unwrap();
+    let mut child = start_envd_with_runtime(&tmp);
+    run_envctl(&tmp, &["set", "DEPLOY_KEY=k3y"]).success();
+    run_envctl(&tmp, &["set", "DEPLOY_ENV=prod"]).success();
+    run_envctl(&tmp, &["set", "PUSH_URL=https://ci?key=${DEPLOY_KEY}"]).success();
+    run_envctl(&tmp, &["set", "PLAIN=1"]).success();
+
+    run_envctl(&tmp, &["export", "bash", "--since", "0", "--pwd", "/"])
+        .success()
+        .stdout("export DEPLOY_ENV='prod'\nexport PLAIN='1'\nexport ENVCTL_GEN=4\n");
+    run_envctl(&tmp, &["get", "DEPLOY_KEY", "--pwd", "/"])
+        .success()
+        .stdout("k3y\n");
+    run_envctl(
+        &tmp,
+        &[
+            "exec",
+            "--pwd",
+            "/",
+            "sh",
+            "-c",
+            "printf '%s %s' \"$DEPLOY_KEY\" \"$PUSH_URL\"",
+        ],
+    )
+    .success()
+    .stdout("k3y https://ci?key=k3y");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn compacted_history_keeps_exports_complete() {
+    let tmp = TempDir::new().unwrap();
+    let mut child = start_envd_with_args(&tmp, &["--history-limit", "10"]);
+    for i in 0..30 {
+        run_envctl(&tmp, &["set", &format!("KEY_{}=v{}", i, i)]).success();
+    }
+    // The sweeper compacts within a poll interval.
+    let start = Instant::now();
+    loop {
+        let out = run_envctl(&tmp, &["status"]).get_output().stdout.clone();
+        if String::from_utf8_lossy(&out).contains("history compacted through: ") {
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "history was not compacted"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    run_envctl(&tmp, &["history"])
+        .success()
+        .stdout(predicate::str::contains("KEY_0:").not())
+        .stdout(predicate::str::contains("KEY_29:"));
+
+    // A shell that exported early still gets every key set after it.
+    run_envctl(&tmp, &["export", "bash", "--since", "1", "--pwd", "/"])
+        .success()
+        .stdout(predicate::str::contains("export KEY_0=").not())
+        .stdout(predicate::str::contains("export KEY_1='v1'"))
+        .stdout(predicate::str::contains("export KEY_29='v29'"));
+    // A generation from an earlier daemon gets a full export.
+    run_envctl(&tmp, &["export", "bash", "--since", "999", "--pwd", "/"])
+        .success()
+        .stdout(predicate::str::contains("export KEY_0='v0'"));
+    run_envctl(&tmp, &["undo", "--generation", "1"])
+        .failure()
+        .stderr(predicate::str::contains("was compacted"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn instances_and_socket_overrides_run_side_by_side() {
+    let tmp = TempDir::new().unwrap();
+    let mut default = start_envd_with_runtime(&tmp);
+    let mut alpha = start_envd_at(
+        &tmp,
+        &["--instance", "alpha"],
+        &tmp.path().join("cmux-envd/envd-alpha.sock"),
+    );
+    assert!(tmp.path().join("cmux-envd/envd-alpha.pid").exists());
+
+    run_envctl(&tmp, &["set", "WHO=default"]).success();
+    run_envctl(&tmp, &["--instance", "alpha", "set", "WHO=alpha"]).success();
+    run_envctl(&tmp, &["get", "WHO"])
+        .success()
+        .stdout("default\n");
+    run_envctl(&tmp, &["get", "WHO", "--instance", "alpha"])
+        .success()
+        .stdout("alpha\n");
+    let mut cmd = Command::cargo_bin("envctl").unwrap();
+    cmd.env("XDG_RUNTIME_DIR", tmp.path());
+    cmd.env("CMUX_ENVD_INSTANCE", "alpha");
+    cmd.args(["get", "WHO"]);
+    cmd.assert().success().stdout("alpha\n");
+
+    // A daemon autostarted for an overridden socket listens there.
+    let custom = tmp.path().join("project/envd.sock");
+    let custom_arg = custom.to_str().unwrap();
+    run_envctl(&tmp, &["--socket", custom_arg, "set", "WHO=project"]).success();
+    assert!(custom.exists());
+    run_envctl(&tmp, &["--socket", custom_arg, "get", "WHO"])
+        .success()
+        .stdout("project\n");
+    run_envctl(&tmp, &["get", "WHO"])
+        .success()
+        .stdout("default\n");
+
+    run_envctl(&tmp, &["--instance", "../escape", "ping"])
+        .failure()
+        .stderr(predicate::str::contains("invalid instance name"));
+
+    kill_envd_by_pid_file(&tmp.path().join("project/envd.pid"));
+    for child in [&mut default, &mut alpha] {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
@@ -0,0 +1,491 @@
+//! Optional admin listener for inspecting a running proxy: open tunnels, the effective
+//! configuration, and the current log filter. The log filter and the reloadable parts of the
+//! config can be changed without a restart.
+//!
+//! The admin API has no authentication; bind it to loopback.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::{
+    parse_port_range, response_with, AffinityMode, HeaderRule, IpCidr, MirrorRule, ProxyConfig,
+    ProxyState, ReloadHandle, TimeoutRule, WorkspaceNetwork,
+};
+
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    /// Backs `GET`/`PUT /log-level`; without it those endpoints return 404.
+    pub log_filter: Option<LogFilterHandle>,
+    /// Backs `POST /reload`; without it that endpoint returns 404.
+    pub reload: Option<ReloadHandle>,
+}
+
+type ApplyFilter = dyn Fn(&str) -> Result<(), String> + Send + Sync;
+
+/// Bridge to whatever `tracing` subscriber the binary installed, so the library does not need
+/// to know how filters are reloaded.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    current: Arc<Mutex<String>>,
+    apply: Arc<ApplyFilter>,
+}
+
+impl LogFilterHandle {
+    pub fn new<F>(initial: impl Into<String>, apply: F) -> Self
+    where
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        Self {
+            current: Arc::new(Mutex::new(initial.into())),
+            apply: Arc::new(apply),
+        }
+    }
+
+    pub fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, directive: &str) -> Result<(), String> {
+        (self.apply)(directive)?;
+        *self.current.lock().unwrap() = directive.to_string();
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LogFilterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilterHandle")
+            .field("current", &self.current())
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn spawn_admin<S>(
+    admin: AdminConfig,
+    state: Arc<ProxyState>,
+    shutdown: S,
+) -> JoinHandle<()>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let admin = Arc::new(admin);
+    let listen = admin.listen;
+    let make_svc = make_service_fn(move |_conn| {
+        let admin = admin.clone();
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_admin(admin.clone(), state.clone(), req)
+            }))
+        }
+    });
+
+    let builder = hyper::Server::bind(&listen)
+        .http1_only(true)
+        .serve(make_svc);
+    info!(admin = %builder.local_addr(), "admin listener started");
+    let server = builder.with_graceful_shutdown(shutdown);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!(%err, "admin server error");
+        }
+    })
+}
+
+async fn handle_admin(
+    admin: Arc<AdminConfig>,
+    state: Arc<ProxyState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/tunnels") => json_response(tunnels_json(&state)),
+        (&Method::GET, "/metrics") => metrics_response(&state),
+        (&Method::GET, "/config") => json_response(config_json(&state, &state.config.load())),
+        (&Method::PUT, "/config") | (&Method::PATCH, "/config") => patch_config(&state, req).await,
+        (&Method::POST, "/reload") => match &admin.reload {
+            Some(reload) => match reload.reload(&state.config) {
+                Ok(()) => {
+                    info!("config reloaded via admin API");
+                    json_response(config_json(&state, &state.config.load()))
+                }
+                Err(e) => response_with(StatusCode::BAD_REQUEST, format!("reload failed: {}", e)),
+            },
+            None => response_with(StatusCode::NOT_FOUND, "reload not enabled".into()),
+        },
+        (&Method::GET, "/log-level") => match &admin.log_filter {
+            Some(filter) => response_with(StatusCode::OK, filter.current()),
+            None => response_with(
+                StatusCode::NOT_FOUND,
+                "log level control not enabled".into(),
+            ),
+        },
+        (&Method::PUT, "/log-level") | (&Method::POST, "/log-level") => {
+            set_log_level(&admin, req).await
+        }
+        _ => response_with(StatusCode::NOT_FOUND, "not found".into()),
+    };
+    Ok(resp)
+}
+
+async fn set_log_level(admin: &AdminConfig, req: Request<Body>) -> Response<Body> {
+    let Some(filter) = &admin.log_filter else {
+        return response_with(
+            StatusCode::NOT_FOUND,
+            "log level control not enabled".into(),
+        );
+    };
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(e) => return response_with(StatusCode::BAD_REQUEST, format!("read error: {}", e)),
+    };
+    let directive = String::from_utf8_lossy(&body).trim().to_string();
+    if directive.is_empty() {
+        return response_with(StatusCode::BAD_REQUEST, "empty log filter".into());
+    }
+    match filter.set(&directive) {
+        Ok(()) => {
+            info!(filter = %directive, "log filter updated via admin API");
+            response_with(StatusCode::OK, directive)
+        }
+        Err(e) => response_with(
+            StatusCode::BAD_REQUEST,
+            format!("invalid log filter: {}", e),
+        ),
+    }
+}
+
+async fn patch_config(state: &ProxyState, req: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(e) => return response_with(StatusCode::BAD_REQUEST, format!("read error: {}", e)),
+    };
+    let patch: serde_json::Map<String, Value> = match serde_json::from_slice(&body) {
+        Ok(Value::Object(m)) => m,
+        Ok(_) => return response_with(StatusCode::BAD_REQUEST, "expected a JSON object".into()),
+        Err(e) => return response_with(StatusCode::BAD_REQUEST, format!("invalid JSON: {}", e)),
+    };
+    match state.config.update(|cfg| apply_patch(cfg, &patch)) {
+        Ok(()) => {
+            info!(keys = ?patch.keys().collect::<Vec<_>>(), "config updated via admin API");
+            json_response(config_json(state, &state.config.load()))
+        }
+        Err(e) => response_with(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Apply a partial config using the same keys `GET /config` reports. `null` clears optional
+/// settings. Either every key applies or none does.
+fn apply_patch(
+    cfg: &mut ProxyConfig,
+    patch: &serde_json::Map<String, Value>,
+) -> Result<(), String> {
+    for (key, value) in patch {
+        match key.as_str() {
+            "upstream_host" => cfg.upstream_host = as_str(key, value)?.to_string(),
+            "allow_default_upstream" => {
+                cfg.allow_default_upstream = value
+                    .as_bool()
+                    .ok_or_else(|| format!("{} must be a boolean", key))?
+            }
+            "header_rules" => {
+                cfg.header_rules = value
+                    .as_array()
+                    .ok_or_else(|| format!("{} must be an array of strings", key))?
+                    .iter()
+                    .map(|r| as_str(key, r)?.parse::<HeaderRule>())
+                    .collect::<Result<_, _>>()?
+            }
+            "mirror_rules" => {
+                cfg.mirror_rules = value
+                    .as_array()
+                    .ok_or_else(|| format!("{} must be an array of strings", key))?
+                    .iter()
+                    .map(|r| as_str(key, r)?.parse::<MirrorRule>())
+                    .collect::<Result<_, _>>()?
+            }
+            "allowed_ports" => {
+                cfg.allowed_ports = match value {
+                    Value::Null => None,
+                    v => Some(parse_port_range(as_str(key, v)?)?),
+                }
+            }
+            "auth_token" => {
+                cfg.auth_token = match value {
+                    Value::Null => None,
+                    v => Some(as_str(key, v)?.to_string()),
+                }
+            }
+            "connect_auth_token" => {
+                cfg.connect_auth_token = match value {
+                    Value::Null => None,
+                    v => Some(as_str(key, v)?.to_string()),
+                }
+            }
+            "connect_registered_only" => {
+                cfg.connect_registered_only = value
+                    .as_bool()
+                    .ok_or_else(|| format!("{} must be a boolean", key))?
+            }
+            "replicas" => {
+                let map = value
+                    .as_object()
+                    .ok_or_else(|| format!("{} must be an object of workspace -> [hosts]", key))?;
+                let mut replicas = HashMap::new();
+                for (name, hosts) in map {
+                    let hosts = hosts
+                        .as_array()
+                        .ok_or_else(|| format!("{}.{} must be an array of hosts", key, name))?
+                        .iter()
+                        .map(|h| as_str(key, h).map(str::to_string))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if hosts.is_empty() {
+                        return Err(format!("{}.{} has no hosts", key, name));
+                    }
+                    replicas.insert(name.clone(), hosts);
+                }
+                cfg.replicas = replicas;
+            }
+            "affinity" => cfg.affinity = as_str(key, value)?.parse::<AffinityMode>()?,
+            "affinity_ttl_ms" => {
+                cfg.affinity_ttl = Duration::from_millis(
+                    as_opt_u64(key, value)?.ok_or_else(|| format!("{} cannot be null", key))?,
+                )
+            }
+            "compress" => {
+                cfg.compress = value
+                    .as_bool()
+                    .ok_or_else(|| format!("{} must be a boolean", key))?
+            }
+            "compress_min_size" => {
+                cfg.compress_min_size =
+                    as_opt_u64(key, value)?.ok_or_else(|| format!("{} cannot be null", key))?
+            }
+            "max_request_body" => cfg.max_request_body = as_opt_u64(key, value)?,
+            "max_response_body" => cfg.max_response_body = as_opt_u64(key, value)?,
+            "max_upstream_connections" => {
+                cfg.max_upstream_connections = as_opt_u64(key, value)?
+                    .map(|n| usize::try_from(n).map_err(|_| format!("{} is too large", key)))
+                    .transpose()?
+                    .filter(|n| *n > 0)
+            }
+            "upstream_queue_timeout_ms" => {
+                cfg.upstream_queue_timeout = Duration::from_millis(
+                    as_opt_u64(key, value)?.ok_or_else(|| format!("{} cannot be null", key))?,
+                )
+            }
+            "ip_allowlist" => cfg.ip_allowlist = cidr_list(key, value)?,
+            "ip_denylist" => cfg.ip_denylist = cidr_list(key, value)?,
+            "timeout_rules" => {
+                cfg.timeout_rules = value
+                    .as_array()
+                    .ok_or_else(|| format!("{} must be an array of strings", key))?
+                    .iter()
+                    .map(|r| as_str(key, r)?.parse::<TimeoutRule>())
+                    .collect::<Result<_, _>>()?
+            }
+            "connect_timeout_ms" => {
+                cfg.connect_timeout = Duration::from_millis(
+                    as_opt_u64(key, value)?
+                        .filter(|ms| *ms > 0)
+                        .ok_or_else(|| format!("{} must be a positive integer", key))?,
+                )
+            }
+            "first_byte_timeout_ms" => {
+                cfg.first_byte_timeout = as_opt_u64(key, value)?.map(Duration::from_millis)
+            }
+            "response_timeout_ms" => {
+                cfg.response_timeout = as_opt_u64(key, value)?.map(Duration::from_millis)
+            }
+            "tunnel_idle_timeout_ms" => {
+                cfg.tunnel_idle_timeout = as_opt_u64(key, value)?.map(Duration::from_millis)
+            }
+            "ws_frame_debug" => {
+                cfg.ws_frame_debug = value
+                    .as_bool()
+                    .ok_or_else(|| format!("{} must be a boolean", key))?
+            }
+            "ws_keepalive_ms" => {
+                cfg.ws_keepalive = as_opt_u64(key, value)?.map(Duration::from_millis)
+            }
+            "ws_idle_timeout_ms" => {
+                cfg.ws_idle_timeout = as_opt_u64(key, value)?.map(Duration::from_millis)
+            }
+            "drain_timeout_ms" => {
+                cfg.drain_timeout = Duration::from_millis(
+                    as_opt_u64(key, value)?.ok_or_else(|| format!("{} cannot be null", key))?,
+                )
+            }
+            "workspace_cidr" => {
+                let mut net: WorkspaceNetwork = as_str(key, value)?.parse()?;
+                for (name, ip) in cfg.workspace_network.overrides() {
+                    net = net.with_override(name.clone(), *ip);
+                }
+                cfg.workspace_network = net;
+            }
+            "workspace_overrides" => {
+                let map = value
+                    .as_object()
+                    .ok_or_else(|| format!("{} must be an object of name -> IP", key))?;
+                let mut net: WorkspaceNetwork = cfg.workspace_network.to_string().parse()?;
+                for (name, ip) in map {
+                    let ip = as_str(key, ip)?
+                        .parse()
+                        .map_err(|_| format!("invalid IPv4 address for {}", name))?;
+                    net = net.with_override(name.clone(), ip);
+                }
+                cfg.workspace_network = net;
+            }
+            "listen" | "admin_listen" => {
+                return Err(format!("{} cannot be changed without a restart", key))
+            }
+            other => return Err(format!("unknown or read-only config key: {}", other)),
+        }
+    }
+    Ok(())
+}
+
+fn as_str<'a>(key: &str, value: &'a Value) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("{} must be a string", key))
+}
+
+fn cidr_list(key: &str, value: &Value) -> Result<Vec<IpCidr>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("{} must be an array of CIDR strings", key))?
+        .iter()
+        .map(|c| as_str(key, c)?.parse::<IpCidr>())
+        .collect()
+}
+
+fn as_opt_u64(key: &str, value: &Value) -> Result<Option<u64>, String> {
+    match value {
+        Value::Null => Ok(None),
+        v => v
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a non-negative integer or null", key)),
+    }
+}
+
+/// Counters in the Prometheus text exposition format.
+fn metrics_response(state: &ProxyState) -> Response<Body> {
+    let body = format!(
+        "# HELP cmux_proxy_rejected_connections_total Connections refused at accept time by the IP allow/deny lists.\n\
+         # TYPE cmux_proxy_rejected_connections_total counter\n\
+         cmux_proxy_rejected_connections_total {}\n",
+        state.rejected_connections.load(Ordering::Relaxed)
+    );
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn tunnels_json(state: &ProxyState) -> Value {
+    let tunnels: Vec<Value> = state
+        .tunnel_registry
+        .snapshot()
+        .iter()
+        .map(|t| {
+            let age_ms = t.started.elapsed().as_millis() as u64;
+            let mut tunnel = json!({
+                "id": t.id,
+                "kind": t.kind.as_str(),
+                "client": t.client.to_string(),
+                "target": t.target,
+                "bytes_to_upstream": t.to_upstream.load(Ordering::Relaxed),
+                "bytes_to_client": t.to_client.load(Ordering::Relaxed),
+                "age_ms": age_ms,
+            });
+            if t.frames_tracked.load(Ordering::Relaxed) {
+                let to_upstream = t.frames_to_upstream.load(Ordering::Relaxed);
+                let to_client = t.frames_to_client.load(Ordering::Relaxed);
+                tunnel["frames_to_upstream"] = json!(to_upstream);
+                tunnel["frames_to_client"] = json!(to_client);
+                tunnel["last_frame_age_ms"] = json!((to_upstream + to_client > 0)
+                    .then(|| age_ms.saturating_sub(t.last_frame_ms.load(Ordering::Relaxed))));
+            }
+            tunnel
+        })
+        .collect();
+    Value::Array(tunnels)
+}
+
+fn config_json(state: &ProxyState, cfg: &ProxyConfig) -> Value {
+    let listen: Vec<String> = state
+        .self_addrs
+        .read()
+        .unwrap()
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    json!({
+        "listen": listen,
+        "upstream_host": cfg.upstream_host,
+        "allow_default_upstream": cfg.allow_default_upstream,
+        "ws_keepalive_ms": cfg.ws_keepalive.map(duration_ms),
+        "ws_idle_timeout_ms": cfg.ws_idle_timeout.map(duration_ms),
+        "ws_frame_debug": cfg.ws_frame_debug,
+        "drain_timeout_ms": duration_ms(cfg.drain_timeout),
+        "connect_timeout_ms": duration_ms(cfg.connect_timeout),
+        "first_byte_timeout_ms": cfg.first_byte_timeout.map(duration_ms),
+        "response_timeout_ms": cfg.response_timeout.map(duration_ms),
+        "tunnel_idle_timeout_ms": cfg.tunnel_idle_timeout.map(duration_ms),
+        "timeout_rules": cfg.timeout_rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+        "admin_listen": cfg.admin.as_ref().map(|a| a.listen.to_string()),
+        "header_rules": cfg.header_rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+        "mirror_rules": cfg.mirror_rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+        "allowed_ports": cfg
+            .allowed_ports
+            .as_ref()
+            .map(|r| format!("{}-{}", r.start(), r.end())),
+        "auth_required": cfg.auth_token.is_some(),
+        "ip_allowlist": cfg.ip_allowlist.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+        "ip_denylist": cfg.ip_denylist.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+        "connect_auth_required": cfg.connect_auth_token.is_some(),
+        "connect_registered_only": cfg.connect_registered_only,
+        "max_request_body": cfg.max_request_body,
+        "max_response_body": cfg.max_response_body,
+        "compress": cfg.compress,
+        "replicas": cfg.replicas,
+        "affinity": cfg.affinity.to_string(),
+        "affinity_ttl_ms": duration_ms(cfg.affinity_ttl),
+        "compress_min_size": cfg.compress_min_size,
+        "max_upstream_connections": cfg.max_upstream_connections,
+        "upstream_queue_timeout_ms": duration_ms(cfg.upstream_queue_timeout),
+        "workspace_cidr": cfg.workspace_network.to_string(),
+        "workspace_overrides": cfg
+            .workspace_network
+            .overrides()
+            .iter()
+            .map(|(name, ip)| (name.clone(), Value::String(ip.to_string())))
+            .collect::<serde_json::Map<_, _>>(),
+    })
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+fn json_response(v: Value) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(Body::from(v.to_string()))
+        .unwrap()
+}
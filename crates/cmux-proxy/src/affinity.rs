@@ -0,0 +1,182 @@
+//! Replica selection with optional sticky sessions.
+//!
+//! A workspace can be served by several upstream replicas (`--replica name=host,host`).
+//! Without affinity requests are spread round-robin. With `cookie` affinity the proxy hands
+//! out a `cmux-affinity` session cookie; with `ip` affinity the client IP is the key. Either
+//! way the chosen replica is remembered in a small table whose entries expire after the
+//! affinity TTL, so WebSocket reconnects and session-stateful dev servers keep landing on the
+//! same replica while it stays in the set.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::COOKIE;
+use hyper::HeaderMap;
+
+pub(crate) const AFFINITY_COOKIE: &str = "cmux-affinity";
+
+/// Purge expired entries once the table grows past this many.
+const PURGE_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AffinityMode {
+    /// Round-robin across replicas.
+    #[default]
+    None,
+    /// Pin by a session cookie the proxy sets.
+    Cookie,
+    /// Pin by client IP.
+    Ip,
+}
+
+impl FromStr for AffinityMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" | "off" => Ok(AffinityMode::None),
+            "cookie" => Ok(AffinityMode::Cookie),
+            "ip" | "ip-hash" => Ok(AffinityMode::Ip),
+            other => Err(format!(
+                "unknown affinity mode: {} (expected none, cookie or ip)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AffinityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AffinityMode::None => "none",
+            AffinityMode::Cookie => "cookie",
+            AffinityMode::Ip => "ip",
+        })
+    }
+}
+
+/// Parse `workspace=host[,host...]` as used by `--replica`.
+pub fn parse_replica_set(s: &str) -> Result<(String, Vec<String>), String> {
+    let (name, hosts) = s
+        .split_once('=')
+        .ok_or_else(|| format!("replica set must be workspace=host[,host...]: {}", s))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("replica set is missing a workspace name: {}", s));
+    }
+    let hosts: Vec<String> = hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(str::to_string)
+        .collect();
+    if hosts.is_empty() {
+        return Err(format!("replica set for {} has no hosts", name));
+    }
+    Ok((name.to_string(), hosts))
+}
+
+/// The replica chosen for a request and, for cookie affinity, a session cookie to set.
+pub(crate) struct Pick {
+    pub(crate) host: String,
+    pub(crate) set_cookie: Option<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct AffinityTable {
+    /// (workspace, session key) -> (replica, last used).
+    entries: Mutex<HashMap<(String, String), (String, Instant)>>,
+    next: AtomicUsize,
+    sessions: AtomicUsize,
+    hasher: RandomState,
+}
+
+impl AffinityTable {
+    pub(crate) fn pick(
+        &self,
+        workspace: &str,
+        replicas: &[String],
+        mode: AffinityMode,
+        ttl: Duration,
+        client: IpAddr,
+        headers: &HeaderMap,
+    ) -> Pick {
+        let round_robin =
+            || replicas[self.next.fetch_add(1, Ordering::Relaxed) % replicas.len()].clone();
+        let (key, set_cookie) = match mode {
+            AffinityMode::None => {
+                return Pick {
+                    host: round_robin(),
+                    set_cookie: None,
+                }
+            }
+            AffinityMode::Ip => (client.to_string(), None),
+            AffinityMode::Cookie => match session_cookie(headers) {
+                Some(session) => (session, None),
+                None => {
+                    let session = self.new_session(client);
+                    (session.clone(), Some(session))
+                }
+            },
+        };
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > PURGE_THRESHOLD {
+            entries.retain(|_, (_, seen)| now.duration_since(*seen) < ttl);
+        }
+        let slot = (workspace.to_string(), key);
+        let host = match entries.get(&slot) {
+            Some((host, seen)) if now.duration_since(*seen) < ttl && replicas.contains(host) => {
+                host.clone()
+            }
+            // New or expired session, or its replica left the set: start from a stable
+            // per-key choice for IP affinity and round-robin for cookies.
+            _ => match mode {
+                AffinityMode::Ip => {
+                    replicas[(self.hasher.hash_one(&slot.1) as usize) % replicas.len()].clone()
+                }
+                _ => round_robin(),
+            },
+        };
+        entries.insert(slot, (host.clone(), now));
+        Pick {
+            host,
+            set_cookie: set_cookie.map(|session| {
+                format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                    AFFINITY_COOKIE,
+                    session,
+                    ttl.as_secs()
+                )
+            }),
+        }
+    }
+
+    fn new_session(&self, client: IpAddr) -> String {
+        let seq = self.sessions.fetch_add(1, Ordering::Relaxed);
+        format!(
+            "{:016x}",
+            self.hasher.hash_one((client, seq, Instant::now()))
+        )
+    }
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == AFFINITY_COOKIE)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|v| !v.is_empty() && v.len() <= 64 && v.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
@@ -0,0 +1,259 @@
+//! On-the-fly Brotli or gzip of upstream responses.
+//!
+//! Dev servers rarely compress, which makes preview loads slow over a WAN. When the client
+//! accepts br or gzip and the upstream sent an uncompressed, compressible body, the body is
+//! compressed as it streams: each upstream chunk is flushed, so long-polling and incremental
+//! responses keep flowing instead of waiting for the encoder to fill up. Brotli is preferred
+//! when the client accepts both.
+
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, HeaderMap, Method, StatusCode};
+
+// Brotli quality and window: fast enough to keep up with a stream, still ahead of gzip.
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_LGWIN: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+
+/// A content coding the proxy can apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// How a response should be compressed on its way to the client, if at all. Bodies that
+/// declare a length under `min_size` are left alone; bodies of unknown length are compressed.
+pub(crate) fn should_compress(
+    method: &Method,
+    req_headers: &HeaderMap,
+    status: StatusCode,
+    resp_headers: &HeaderMap,
+    min_size: u64,
+) -> Option<Encoding> {
+    if method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+        || resp_headers.contains_key(CONTENT_ENCODING)
+        || resp_headers.contains_key(CONTENT_RANGE)
+    {
+        return None;
+    }
+    let encoding = negotiate(req_headers)?;
+    if !is_compressible(resp_headers) {
+        return None;
+    }
+    if resp_headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("no-transform"))
+    {
+        return None;
+    }
+    let declared = resp_headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    declared
+        .is_none_or(|len| len >= min_size)
+        .then_some(encoding)
+}
+
+/// Rewrite headers for a compressed body: the length is no longer known up front, caches must
+/// key on `Accept-Encoding`, and a strong ETag no longer matches the bytes sent.
+pub(crate) fn mark_compressed(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("accept-encoding") || v.trim() == "*");
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(ETAG, weak);
+            }
+        }
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::fast())),
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_LGWIN,
+            ))),
+        }
+    }
+
+    // Compresses and flushes `chunk`, returning what the encoder produced for it.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Encoder::Gzip(enc) => {
+                enc.write_all(chunk)?;
+                enc.flush()?;
+                enc.get_mut()
+            }
+            Encoder::Brotli(enc) => {
+                enc.write_all(chunk)?;
+                enc.flush()?;
+                enc.get_mut()
+            }
+        };
+        Ok(std::mem::take(out))
+    }
+
+    // Ends the stream, returning its last bytes.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(enc) => enc.finish(),
+            Encoder::Brotli(enc) => Ok(enc.into_inner()),
+        }
+    }
+}
+
+/// Compress `body` as it streams. Trailers are forwarded after the end of the stream.
+pub(crate) fn compress_body(mut body: Body, encoding: Encoding) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut encoder = Encoder::new(encoding);
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                tx.abort();
+                return;
+            };
+            let Ok(out) = encoder.encode(&chunk) else {
+                tx.abort();
+                return;
+            };
+            if !out.is_empty() && tx.send_data(out.into()).await.is_err() {
+                return;
+            }
+        }
+        match encoder.finish() {
+            Ok(out) if !out.is_empty() => {
+                if tx.send_data(out.into()).await.is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => {
+                tx.abort();
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => tx.abort(),
+        }
+    });
+    rx
+}
+
+// Brotli when the client accepts it, else gzip. A coding named explicitly overrides `*`.
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut named: Vec<(String, bool)> = Vec::new();
+    for coding in headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let refused = parts.any(|p| {
+            p.trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        if name == "*" {
+            wildcard = Some(!refused);
+        } else {
+            named.push((name, !refused));
+        }
+    }
+    let accepts = |name: &str| {
+        named
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, ok)| *ok)
+            .or(wildcard)
+            .unwrap_or(false)
+    };
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|e| accepts(e.as_str()))
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    // Event streams are read incrementally by the browser; leave them untouched.
+    if mime == "text/event-stream" {
+        return false;
+    }
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/x-javascript"
+                | "application/ecmascript"
+                | "application/xml"
+                | "application/wasm"
+                | "application/manifest+json"
+                | "application/x-ndjson"
+                | "image/svg+xml"
+        )
+}
@@ -0,0 +1,108 @@
+//! Error responses generated by the proxy itself.
+//!
+//! Errors carry a stable machine-readable code next to the human message. Clients that ask
+//! for JSON (`Accept: application/json`) get
+//! `{"error": {"code": "...", "message": "...", "requestId": "..."}}`; everyone else (curl,
+//! browsers) gets the message as plain text. Either way the request id is echoed in
+//! `X-Cmux-Request-Id` so a report can be matched to the proxy logs.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde_json::json;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-cmux-request-id";
+
+/// Attached to the extensions of every proxy-generated error response.
+#[derive(Clone, Debug)]
+pub(crate) struct ProxyError {
+    pub(crate) code: &'static str,
+    pub(crate) message: String,
+}
+
+/// Default code for an error status when the call site doesn't pick a more specific one.
+pub(crate) fn code_for_status(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED => "proxy_auth_required",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::BAD_GATEWAY => "bad_gateway",
+        StatusCode::SERVICE_UNAVAILABLE => "upstream_busy",
+        StatusCode::GATEWAY_TIMEOUT => "upstream_timeout",
+        StatusCode::LOOP_DETECTED => "loop_detected",
+        s if s.is_client_error() => "client_error",
+        _ => "internal_error",
+    }
+}
+
+pub(crate) fn error_response(
+    status: StatusCode,
+    code: &'static str,
+    msg: String,
+) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(msg.clone()))
+        .unwrap();
+    resp.extensions_mut()
+        .insert(ProxyError { code, message: msg });
+    resp
+}
+
+/// The caller's `X-Request-Id` if it sent a usable one, otherwise a fresh id.
+pub(crate) fn request_id(headers: &HeaderMap) -> String {
+    if let Some(id) = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+    {
+        return id.to_string();
+    }
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let seq = NEXT.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    format!("{:x}-{:x}-{:x}", now, std::process::id(), seq)
+}
+
+/// True when the client prefers JSON, e.g. `Accept: application/json` or `*/*+json` types.
+pub(crate) fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.split(';').next().unwrap_or("").trim())
+        .any(|t| t.eq_ignore_ascii_case("application/json") || t.ends_with("+json"))
+}
+
+/// Stamp a proxy-generated error with the request id, re-rendering it as JSON if asked.
+/// Responses that came from an upstream are left alone.
+pub(crate) fn finish_error(resp: &mut Response<Body>, request_id: &str, json: bool) {
+    let Some(err) = resp.extensions_mut().remove::<ProxyError>() else {
+        return;
+    };
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    if json {
+        let body = json!({
+            "error": {
+                "code": err.code,
+                "message": err.message,
+                "requestId": request_id,
+            }
+        });
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        *resp.body_mut() = Body::from(body.to_string());
+    }
+}
@@ -1,65 +1,277 @@
-use std::{convert::Infallible, future::Future, net::SocketAddr, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
 
 use futures_util::future;
-use hyper::client::HttpConnector;
-use hyper::header::{CONNECTION, UPGRADE};
-use hyper::server::conn::AddrStream;
+use hyper::header::{
+    CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, SET_COOKIE, TE, UPGRADE,
+};
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{
     body::Body,
     client::Client,
-    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri},
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version},
 };
-use std::sync::Arc;
-use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::sync::Notify;
 use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
 use tracing::{error, info, warn};
 
+mod admin;
+mod affinity;
+mod compress;
+mod error;
+mod ipfilter;
+mod limit;
+mod listener;
+mod mirror;
+mod reload;
+mod rewrite;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upstream;
+mod workspace;
+mod ws;
+
+pub use admin::{AdminConfig, LogFilterHandle};
+use affinity::AffinityTable;
+pub use affinity::{parse_replica_set, AffinityMode};
+use error::error_response;
+use ipfilter::FilteredIncoming;
+pub use ipfilter::IpCidr;
+use listener::ListenerView;
+pub use listener::{parse_port_range, ListenerConfig};
+pub use mirror::MirrorRule;
+pub use reload::{ConfigHandle, ReloadHandle};
+pub use rewrite::{apply_header_rules, HeaderAction, HeaderRule, RuleDirection};
+use timeout::TimeoutConnector;
+pub use timeout::{TimeoutRule, Timeouts};
+pub use tls::TlsConfig;
+use tls::{ListenerIncoming, ProxyConn};
+use tunnel::{Counted, TunnelKind, TunnelRegistry, TunnelStats};
+use upstream::UpstreamLimiter;
+pub use workspace::WorkspaceNetwork;
+
 #[derive(Clone, Debug)]
 pub struct ProxyConfig {
     pub listen: SocketAddr,
     pub upstream_host: String,
     pub allow_default_upstream: bool,
+    /// Inject a WebSocket Ping toward the client at this interval on upgraded tunnels, so idle
+    /// connections are not dropped by intermediate NATs.
+    pub ws_keepalive: Option<Duration>,
+    /// Close upgraded WebSocket tunnels after no traffic in either direction for this long.
+    pub ws_idle_timeout: Option<Duration>,
+    /// Parse WebSocket tunnels frame by frame: log each frame's opcode and size to the
+    /// `cmux_proxy::ws_frames` target and count frames per tunnel for the admin API.
+    pub ws_frame_debug: bool,
+    /// After shutdown is signaled, how long open upgrade/CONNECT tunnels may keep running
+    /// before they are aborted.
+    pub drain_timeout: Duration,
+    /// Serve the admin/introspection API (open tunnels, effective config, log level).
+    pub admin: Option<AdminConfig>,
+    /// Header rewrites applied, in order, to proxied HTTP and upgrade traffic.
+    pub header_rules: Vec<HeaderRule>,
+    /// Network and explicit pins used to turn workspace names into upstream IPs.
+    pub workspace_network: WorkspaceNetwork,
+    /// Only proxy to target ports in this range; others get `403`.
+    pub allowed_ports: Option<RangeInclusive<u16>>,
+    /// Require `Proxy-Authorization: Bearer <token>`; requests without it get `407`.
+    pub auth_token: Option<String>,
+    /// Reject request bodies larger than this with `413`; streamed bodies are cut off once they
+    /// cross it.
+    pub max_request_body: Option<u64>,
+    /// Fail upstream responses larger than this: `502` if declared up front, otherwise the
+    /// stream is aborted mid-transfer.
+    pub max_response_body: Option<u64>,
+    /// Duplicate matching HTTP requests to a second upstream, discarding its responses.
+    pub mirror_rules: Vec<MirrorRule>,
+    /// Cap on concurrent connections to any single upstream `host:port`; requests over it queue.
+    pub max_upstream_connections: Option<usize>,
+    /// How long a request may queue for an upstream slot before it gets `503`.
+    pub upstream_queue_timeout: Duration,
+    /// Additionally require `Proxy-Authorization: Bearer <token>` on CONNECT requests.
+    pub connect_auth_token: Option<String>,
+    /// Only tunnel CONNECT to workspaces pinned in the workspace map; default-upstream and
+    /// computed (unregistered) names are refused.
+    pub connect_registered_only: bool,
+    /// Brotli- or gzip-compress uncompressed, compressible upstream responses for clients that
+    /// accept it.
+    pub compress: bool,
+    /// Responses declaring a smaller `Content-Length` than this are sent uncompressed.
+    pub compress_min_size: u64,
+    /// Upstream replicas per workspace name; requests for the workspace are spread across them
+    /// instead of going to its mapped IP.
+    pub replicas: HashMap<String, Vec<String>>,
+    /// How requests stick to a replica across requests and reconnects.
+    pub affinity: AffinityMode,
+    /// How long an idle client keeps its replica.
+    pub affinity_ttl: Duration,
+    /// Give up connecting to an upstream after this long (`504`).
+    pub connect_timeout: Duration,
+    /// Give up on an upstream that hasn't sent its response head this long after the request
+    /// went out, connect included (`504`).
+    pub first_byte_timeout: Option<Duration>,
+    /// Bound on a whole HTTP exchange, from receiving the request until the response body is
+    /// sent: `504` before the response head, otherwise the body is cut off.
+    pub response_timeout: Option<Duration>,
+    /// Close upgrade and CONNECT tunnels after no traffic in either direction for this long.
+    /// `ws_idle_timeout` takes precedence for WebSocket tunnels.
+    pub tunnel_idle_timeout: Option<Duration>,
+    /// Per-route overrides of the timeouts above; the first matching rule wins.
+    pub timeout_rules: Vec<TimeoutRule>,
+    /// Only accept connections from these networks (all when empty).
+    pub ip_allowlist: Vec<IpCidr>,
+    /// Never accept connections from these networks; checked before the allowlist.
+    pub ip_denylist: Vec<IpCidr>,
 }
 
-pub fn spawn_proxy<S>(cfg: ProxyConfig, shutdown: S) -> (SocketAddr, JoinHandle<()>)
-where
-    S: Future<Output = ()> + Send + 'static,
-{
-    // Hyper client for proxying HTTP/1.1
-    let mut connector = HttpConnector::new();
-    connector.set_connect_timeout(Some(Duration::from_secs(5)));
-    let client: Client<HttpConnector, Body> =
-        Client::builder().pool_max_idle_per_host(8).build(connector);
-
-    let listen = cfg.listen;
-    let make_cfg = cfg;
-    let make_svc = make_service_fn(move |conn: &AddrStream| {
-        let remote_addr = conn.remote_addr();
-        let client = client.clone();
-        let cfg = make_cfg.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                handle(client.to_owned(), cfg.to_owned(), remote_addr, req)
-            }))
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            upstream_host: "127.0.0.1".to_string(),
+            allow_default_upstream: false,
+            ws_keepalive: None,
+            ws_idle_timeout: None,
+            ws_frame_debug: false,
+            drain_timeout: Duration::from_secs(5),
+            admin: None,
+            header_rules: Vec::new(),
+            workspace_network: WorkspaceNetwork::default(),
+            allowed_ports: None,
+            auth_token: None,
+            max_request_body: None,
+            max_response_body: None,
+            mirror_rules: Vec::new(),
+            max_upstream_connections: None,
+            upstream_queue_timeout: Duration::from_secs(10),
+            connect_auth_token: None,
+            connect_registered_only: false,
+            compress: false,
+            compress_min_size: 1024,
+            replicas: HashMap::new(),
+            affinity: AffinityMode::None,
+            affinity_ttl: Duration::from_secs(3600),
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: None,
+            response_timeout: None,
+            tunnel_idle_timeout: None,
+            timeout_rules: Vec::new(),
+            ip_allowlist: Vec::new(),
+            ip_denylist: Vec::new(),
         }
-    });
+    }
+}
 
-    let builder = hyper::Server::bind(&listen)
-        .http1_only(true)
-        .serve(make_svc);
-    let listen_addr = builder.local_addr();
-    let server = builder.with_graceful_shutdown(shutdown);
+/// `tracing` target for the CONNECT audit trail, so it can be routed or filtered separately.
+const AUDIT: &str = "cmux_proxy::audit";
+
+/// State shared by every connection served by one proxy instance.
+struct ProxyState {
+    /// Live config template; listeners overlay their own overrides on it per request.
+    config: ConfigHandle,
+    client: Client<TimeoutConnector, Body>,
+    /// Prior-knowledge HTTP/2 (h2c) client, used when the inbound request is HTTP/2 so gRPC
+    /// and other h2-only backends can be reached.
+    h2_client: Client<TimeoutConnector, Body>,
+    /// Addresses the proxy is actually bound to; used to refuse requests that would loop back
+    /// into the proxy itself.
+    self_addrs: RwLock<Vec<SocketAddr>>,
+    /// Upgrade and CONNECT tunnels outlive the request that created them; they are tracked
+    /// here so shutdown can drain them instead of orphaning the tasks.
+    tunnels: Mutex<JoinSet<()>>,
+    /// Per-tunnel metadata and byte counters, reported by the admin API.
+    tunnel_registry: TunnelRegistry,
+    /// Connection slots per upstream, enforcing `max_upstream_connections`.
+    upstream_limiter: UpstreamLimiter,
+    /// Remembered replica choices for sticky sessions.
+    affinity: AffinityTable,
+    /// Connections closed at accept time by the IP allow/deny lists.
+    rejected_connections: AtomicU64,
+}
 
-    let handle = tokio::spawn(async move {
-        if let Err(err) = server.await {
-            error!(%err, "server error");
+impl ProxyState {
+    fn new(config: ConfigHandle) -> Self {
+        // Hyper client for proxying HTTP/1.1. The connect timeout is applied per request.
+        let connector = TimeoutConnector::new();
+        let client: Client<TimeoutConnector, Body> = Client::builder()
+            .pool_max_idle_per_host(8)
+            .build(connector.clone());
+        let h2_client: Client<TimeoutConnector, Body> =
+            Client::builder().http2_only(true).build(connector);
+        Self {
+            config,
+            client,
+            h2_client,
+            self_addrs: RwLock::new(Vec::new()),
+            tunnels: Mutex::new(JoinSet::new()),
+            tunnel_registry: TunnelRegistry::default(),
+            upstream_limiter: UpstreamLimiter::default(),
+            affinity: AffinityTable::default(),
+            rejected_connections: AtomicU64::new(0),
+        }
+    }
+
+    fn spawn_tunnel<F, Fut>(&self, kind: TunnelKind, client: SocketAddr, target: String, f: F)
+    where
+        F: FnOnce(Arc<TunnelStats>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (stats, guard) = self.tunnel_registry.register(kind, client, target);
+        let fut = f(stats);
+        let mut tunnels = self.tunnels.lock().unwrap();
+        // Reap finished tunnels so the set only holds live ones.
+        while tunnels.try_join_next().is_some() {}
+        tunnels.spawn(async move {
+            let _guard = guard;
+            fut.await;
+        });
+    }
+
+    /// Wait until `deadline` for open tunnels to finish on their own, then abort the rest.
+    async fn drain_tunnels(&self, deadline: Instant) {
+        let mut tunnels = std::mem::take(&mut *self.tunnels.lock().unwrap());
+        while tunnels.try_join_next().is_some() {}
+        if tunnels.is_empty() {
+            return;
         }
-    });
 
-    (listen_addr, handle)
+        info!(open = tunnels.len(), "draining tunnels");
+        let drained = tokio::time::timeout_at(deadline, async {
+            while tunnels.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                remaining = tunnels.len(),
+                "drain deadline reached; aborting tunnels"
+            );
+            tunnels.shutdown().await;
+        }
+    }
+}
+
+pub fn spawn_proxy<S>(cfg: ProxyConfig, shutdown: S) -> (SocketAddr, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let listener = ListenerConfig::new(cfg.listen);
+    let (bound, handle) = spawn_proxy_with_handle(vec![listener], ConfigHandle::new(cfg), shutdown);
+    (bound[0], handle)
 }
 
 /// Start the proxy on multiple addresses. Returns the bound addresses actually used and a handle
@@ -73,88 +285,301 @@ pub fn spawn_proxy_multi<S>(
 where
     S: Future<Output = ()> + Send + 'static,
 {
-    // Prepare shared client and shutdown notifier
-    let mut connector = HttpConnector::new();
-    connector.set_connect_timeout(Some(Duration::from_secs(5)));
-    let client: Client<HttpConnector, Body> =
-        Client::builder().pool_max_idle_per_host(8).build(connector);
+    let cfg = ProxyConfig {
+        upstream_host,
+        allow_default_upstream,
+        ..ProxyConfig::default()
+    };
+    spawn_proxy_multi_with_config(listens, cfg, shutdown)
+}
 
-    let notify = Arc::new(Notify::new());
-    let notify_clone = notify.clone();
-    tokio::spawn(async move {
-        shutdown.await;
-        notify_clone.notify_waiters();
-    });
+/// Like [`spawn_proxy_multi`], but takes a full config template. `cfg.listen` is ignored and
+/// replaced by each address in `listens`.
+pub fn spawn_proxy_multi_with_config<S>(
+    listens: Vec<SocketAddr>,
+    cfg: ProxyConfig,
+    shutdown: S,
+) -> (Vec<SocketAddr>, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let listeners = listens.into_iter().map(ListenerConfig::new).collect();
+    spawn_proxy_listeners(listeners, cfg, shutdown)
+}
 
+/// Start the proxy on several listeners, each overlaying its own upstream host, allowed port
+/// range and auth requirement on the shared `cfg`. All listeners share one tunnel registry,
+/// admin API and shutdown.
+pub fn spawn_proxy_listeners<S>(
+    listeners: Vec<ListenerConfig>,
+    cfg: ProxyConfig,
+    shutdown: S,
+) -> (Vec<SocketAddr>, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    spawn_proxy_with_handle(listeners, ConfigHandle::new(cfg), shutdown)
+}
+
+/// Like [`spawn_proxy_listeners`], but serves from a [`ConfigHandle`] the caller keeps, so the
+/// config can be swapped while the proxy runs.
+pub fn spawn_proxy_with_handle<S>(
+    listeners: Vec<ListenerConfig>,
+    config: ConfigHandle,
+    shutdown: S,
+) -> (Vec<SocketAddr>, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    // Prepare shared state and shutdown notifier
+    let state = Arc::new(ProxyState::new(config));
+
+    let notify = Arc::new(Notify::new());
     let mut join_set: JoinSet<()> = JoinSet::new();
     let mut bound_addrs = Vec::new();
+    let mut server_aborts = Vec::new();
 
-    for addr in listens {
-        let client = client.clone();
-        let upstream = upstream_host.clone();
+    for listener in listeners {
+        let incoming_state = state.clone();
+        let state = state.clone();
         let notify = notify.clone();
-        let allow_default = allow_default_upstream;
-        let listen_addr = addr;
+        let listen_addr = listener.listen;
+        let acceptor = listener.tls.as_ref().map(|tls| {
+            tls.load()
+                .unwrap_or_else(|e| panic!("TLS setup for {} failed: {}", listen_addr, e))
+        });
+        let listener = Arc::new(ListenerView::new(listener));
 
-        let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let make_svc = make_service_fn(move |conn: &ProxyConn| {
             let remote_addr = conn.remote_addr();
-            let client = client.clone();
-            let upstream = upstream.clone();
-            let allow_default = allow_default;
+            let state = state.clone();
+            let listener = listener.clone();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    let cfg = ProxyConfig {
-                        listen: listen_addr,
-                        upstream_host: upstream.clone(),
-                        allow_default_upstream: allow_default,
-                    };
-                    handle(client.to_owned(), cfg, remote_addr, req)
+                    // Resolved per request so reloads reach existing keep-alive connections.
+                    let cfg = listener.config(state.config.load());
+                    handle(state.clone(), cfg, remote_addr, req)
                 }))
             }
         });
 
-        let builder = hyper::Server::bind(&listen_addr)
-            .http1_only(true)
-            .serve(make_svc);
-        let local = builder.local_addr();
+        let incoming = AddrIncoming::bind(&listen_addr)
+            .unwrap_or_else(|e| panic!("error binding to {}: {}", listen_addr, e));
+        let local = incoming.local_addr();
+        let incoming =
+            ListenerIncoming::new(FilteredIncoming::new(incoming, incoming_state), acceptor);
+        let builder = hyper::Server::builder(incoming).serve(make_svc);
         bound_addrs.push(local);
         let server = builder.with_graceful_shutdown(async move {
             notify.notified().await;
         });
 
-        join_set.spawn(async move {
+        server_aborts.push(join_set.spawn(async move {
             if let Err(err) = server.await {
                 error!(%err, "server error");
             }
-        });
+        }));
     }
 
-    let handle = tokio::spawn(async move { while let Some(_res) = join_set.join_next().await {} });
+    *state.self_addrs.write().unwrap() = bound_addrs.clone();
+
+    let admin = spawn_admin_for(&state, &notify);
+    if let Some(admin) = &admin {
+        server_aborts.push(admin.abort_handle());
+    }
+
+    // One drain window covers both the servers and the tunnels they leave behind.
+    let drain_deadline = Arc::new(OnceLock::new());
+    let shutdown_state = state.clone();
+    let shutdown_deadline = drain_deadline.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        let deadline = *shutdown_deadline
+            .get_or_init(|| Instant::now() + shutdown_state.config.load().drain_timeout);
+        notify.notify_waiters();
+        // A graceful shutdown waits on connections that never send a request (e.g. a client's
+        // spare pooled connection); stop waiting for them once the drain window has passed.
+        tokio::time::sleep_until(deadline).await;
+        for abort in server_aborts {
+            abort.abort();
+        }
+    });
+    let handle = tokio::spawn(async move {
+        while let Some(_res) = join_set.join_next().await {}
+        if let Some(admin) = admin {
+            let _ = admin.await;
+        }
+        let deadline =
+            *drain_deadline.get_or_init(|| Instant::now() + state.config.load().drain_timeout);
+        state.drain_tunnels(deadline).await;
+    });
 
     (bound_addrs, handle)
 }
 
-fn get_port_from_header(headers: &HeaderMap) -> Result<u16, Response<Body>> {
+/// Start the admin listener if configured; it stops on the same shutdown signal as the proxy.
+fn spawn_admin_for(state: &Arc<ProxyState>, notify: &Arc<Notify>) -> Option<JoinHandle<()>> {
+    let admin = state.config.load().admin.clone()?;
+    let notify = notify.clone();
+    Some(admin::spawn_admin(admin, state.clone(), async move {
+        notify.notified().await;
+    }))
+}
+
+/// Swap the resolved upstream for one of the workspace's replicas, if it has any. Returns the
+/// host to use and, for cookie affinity, a `Set-Cookie` value for the response.
+fn choose_replica(
+    state: &ProxyState,
+    cfg: &ProxyConfig,
+    workspace: Option<&str>,
+    upstream_host: String,
+    remote_addr: SocketAddr,
+    headers: &HeaderMap,
+) -> (String, Option<String>) {
+    let Some((workspace, replicas)) = workspace
+        .and_then(|ws| cfg.replicas.get_key_value(ws))
+        .filter(|(_, r)| !r.is_empty())
+    else {
+        return (upstream_host, None);
+    };
+    let pick = state.affinity.pick(
+        workspace,
+        replicas,
+        cfg.affinity,
+        cfg.affinity_ttl,
+        remote_addr.ip(),
+        headers,
+    );
+    (pick.host, pick.set_cookie)
+}
+
+fn append_set_cookie(headers: &mut HeaderMap, cookie: Option<String>) {
+    if let Some(value) = cookie.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        headers.append(SET_COOKIE, value);
+    }
+}
+
+/// A response that ends a request before it reaches upstream. Boxed so the helpers returning it
+/// keep a small `Result`; `?` unboxes it in handlers that return `Response<Body>` errors.
+struct Rejection(Box<Response<Body>>);
+
+impl From<Response<Body>> for Rejection {
+    fn from(resp: Response<Body>) -> Self {
+        Rejection(Box::new(resp))
+    }
+}
+
+impl From<Rejection> for Response<Body> {
+    fn from(r: Rejection) -> Self {
+        *r.0
+    }
+}
+
+fn check_port_allowed(cfg: &ProxyConfig, port: u16) -> Result<(), Rejection> {
+    match &cfg.allowed_ports {
+        Some(range) if !range.contains(&port) => Err(error_response(
+            StatusCode::FORBIDDEN,
+            "port_not_allowed",
+            format!(
+                "port {} is not allowed on this listener ({}-{})",
+                port,
+                range.start(),
+                range.end()
+            ),
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn check_proxy_auth(cfg: &ProxyConfig, headers: &HeaderMap) -> Result<(), Rejection> {
+    check_bearer(cfg.auth_token.as_deref(), headers)
+}
+
+fn check_bearer(expected: Option<&str>, headers: &HeaderMap) -> Result<(), Rejection> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let presented = headers
+        .get(PROXY_AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("Bearer "))
+        .map(str::trim);
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => {
+            let mut resp = response_with(
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                "proxy authentication required".to_string(),
+            );
+            resp.headers_mut().insert(
+                PROXY_AUTHENTICATE,
+                HeaderValue::from_static("Bearer realm=\"cmux-proxy\""),
+            );
+            Err(resp.into())
+        }
+    }
+}
+
+/// Refuse CONNECT targets outside the workspace network. The resolver only ever produces
+/// workspace IPs or the configured default host, so this guards against pins or listener
+/// overrides pointing somewhere unexpected, and enforces `connect_registered_only`.
+fn check_connect_target(
+    cfg: &ProxyConfig,
+    workspace: Option<&str>,
+    upstream_host: &str,
+) -> Result<(), Rejection> {
+    let in_network = upstream_host
+        .parse::<std::net::Ipv4Addr>()
+        .is_ok_and(|ip| cfg.workspace_network.contains(ip));
+    let allowed = if cfg.connect_registered_only {
+        in_network && workspace.is_some_and(|ws| cfg.workspace_network.is_registered(ws))
+    } else {
+        in_network || upstream_host == cfg.upstream_host
+    };
+    if allowed {
+        return Ok(());
+    }
+    Err(error_response(
+        StatusCode::FORBIDDEN,
+        "connect_forbidden",
+        match workspace {
+            Some(ws) => format!("CONNECT to workspace {} is not allowed", ws),
+            None => "CONNECT requires a registered workspace".to_string(),
+        },
+    )
+    .into())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn get_port_from_header(headers: &HeaderMap) -> Result<u16, Rejection> {
     const HDR: &str = "X-Cmux-Port-Internal";
     if let Some(val) = headers.get(HDR) {
         let s = val.to_str().map_err(|_| {
-            response_with(
+            error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_port",
                 "invalid header value (not UTF-8)".to_string(),
             )
         })?;
 
         let s = s.trim();
         if s.is_empty() {
-            return Err(response_with(
+            return Err(error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_port",
                 "header value cannot be empty".to_string(),
-            ));
+            )
+            .into());
         }
 
         let port: u16 = s.parse().map_err(|_| {
-            response_with(
+            error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_port",
                 "invalid port in X-Cmux-Port-Internal".to_string(),
             )
         })?;
@@ -166,69 +591,50 @@ fn get_port_from_header(headers: &HeaderMap) -> Result<u16, Response<Body>> {
         return Ok(port);
     }
 
-    Err(response_with(
+    Err(error_response(
         StatusCode::BAD_REQUEST,
+        "missing_port",
         format!("missing required header: {}", HDR),
-    ))
+    )
+    .into())
 }
 
 /// Public helper: compute a per-workspace IPv4 address in 127/8 based on a workspace name
-/// of the form `workspace-N` (N >= 1). If input contains path separators, the last component
-/// is used. Returns None if no trailing digits are found.
+/// of the form `workspace-N` (N >= 1), using the default `127.18.0.0/16` network. If input
+/// contains path separators, the last component is used. Names without trailing digits are
+/// hashed into the same space. See [`WorkspaceNetwork`] for a configurable network.
 pub fn workspace_ip_from_name(name: &str) -> Option<std::net::Ipv4Addr> {
-    use std::net::Ipv4Addr;
-
-    let base = name.rsplit('/').next().unwrap_or(name);
-    // Extract trailing digits
-    let digits: String = base
-        .chars()
-        .rev()
-        .take_while(|c| c.is_ascii_digit())
-        .collect::<String>()
-        .chars()
-        .rev()
-        .collect();
-
-    let n: u32 = if !digits.is_empty() {
-        digits.parse().ok()?
-    } else {
-        // Stable 32-bit FNV-1a hash of lowercase name; map to 16-bit space
-        let mut h: u32 = 0x811C9DC5;
-        for b in base.to_ascii_lowercase().as_bytes() {
-            h ^= *b as u32;
-            h = h.wrapping_mul(0x01000193);
-        }
-        h & 0xFFFF
-    };
-
-    let b2 = ((n >> 8) & 0xFF) as u8;
-    let b3 = (n & 0xFF) as u8;
-    Some(Ipv4Addr::new(127, 18, b2, b3))
+    WorkspaceNetwork::default().ip_for(name)
 }
 
 fn upstream_host_from_headers(
     headers: &HeaderMap,
+    network: &WorkspaceNetwork,
     default_host: &str,
     allow_default_without_workspace: bool,
-) -> Result<String, Response<Body>> {
+) -> Result<String, Rejection> {
     const HDR_WS: &str = "X-Cmux-Workspace-Internal";
     if let Some(val) = headers.get(HDR_WS) {
         let v = val.to_str().map_err(|_| {
-            response_with(
+            error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_workspace",
                 format!("invalid header value (not UTF-8): {}", HDR_WS),
             )
         })?;
         let ws = v.trim();
         if ws.is_empty() {
-            return Err(response_with(
+            return Err(error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_workspace",
                 format!("{} cannot be empty", HDR_WS),
-            ));
+            )
+            .into());
         }
-        let ip = workspace_ip_from_name(ws).ok_or_else(|| {
-            response_with(
+        let ip = network.ip_for(ws).ok_or_else(|| {
+            error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_workspace",
                 format!("invalid workspace name: {}", ws),
             )
         })?;
@@ -241,19 +647,115 @@ fn upstream_host_from_headers(
 
     // Fallback: try parsing from subdomain pattern if present
     if let Some((ws, _port)) = parse_workspace_port_from_host(headers) {
-        if let Some(ip) = workspace_ip_from_name(&ws) {
+        if let Some(ip) = network.ip_for(&ws) {
             return Ok(ip.to_string());
         } else {
-            return Err(response_with(
+            return Err(error_response(
                 StatusCode::BAD_REQUEST,
+                "invalid_workspace",
                 format!("invalid workspace name: {}", ws),
-            ));
+            )
+            .into());
         }
     }
 
     Ok(default_host.to_string())
 }
 
+/// Workspace a request is addressed to, from the workspace header or the Host subdomain.
+fn workspace_from_headers(headers: &HeaderMap) -> Option<String> {
+    if let Some(ws) = headers
+        .get("X-Cmux-Workspace-Internal")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        return Some(ws.to_string());
+    }
+    parse_workspace_port_from_host(headers).map(|(ws, _port)| ws)
+}
+
+/// Returns true if connecting to `ip:port` would land on one of the `bound` listener addresses.
+/// A wildcard listener accepts on every local address, so any loopback target on its port
+/// (including the per-workspace 127.18.x.y range) or any of the machine's interface addresses
+/// (`local_ips`) counts as a match.
+pub fn is_self_target(ip: IpAddr, port: u16, bound: &[SocketAddr], local_ips: &[IpAddr]) -> bool {
+    bound.iter().any(|addr| {
+        if addr.port() != port {
+            return false;
+        }
+        if addr.ip().is_unspecified() {
+            ip.is_loopback() || ip.is_unspecified() || local_ips.contains(&ip)
+        } else {
+            addr.ip() == ip
+        }
+    })
+}
+
+/// A request whose upstream resolves to one of this proxy's own listen addresses.
+struct SelfTarget {
+    upstream_host: String,
+    port: u16,
+}
+
+impl From<SelfTarget> for Response<Body> {
+    fn from(e: SelfTarget) -> Self {
+        response_with(
+            StatusCode::LOOP_DETECTED,
+            format!(
+                "refusing to proxy to {}:{}: target is this proxy's own listen address",
+                e.upstream_host, e.port
+            ),
+        )
+    }
+}
+
+async fn reject_self_target(
+    state: &ProxyState,
+    upstream_host: &str,
+    port: u16,
+) -> Result<(), SelfTarget> {
+    let bound = state.self_addrs.read().unwrap().clone();
+    // Cheap check first so the common case never resolves hostnames.
+    if !bound.iter().any(|a| a.port() == port) {
+        return Ok(());
+    }
+
+    let targets: Vec<IpAddr> = match upstream_host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => match tokio::net::lookup_host((upstream_host, port)).await {
+            Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+            // Let the actual connect attempt report resolution failures.
+            Err(_) => Vec::new(),
+        },
+    };
+
+    // Interface addresses only matter for wildcard listeners; they are read per check since
+    // they can change while the proxy runs.
+    let local_ips: Vec<IpAddr> = if bound
+        .iter()
+        .any(|a| a.port() == port && a.ip().is_unspecified())
+    {
+        if_addrs::get_if_addrs()
+            .map(|ifaces| ifaces.iter().map(|i| i.ip()).collect())
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    if targets
+        .iter()
+        .any(|ip| is_self_target(*ip, port, &bound, &local_ips))
+    {
+        warn!(upstream = %upstream_host, port, "refusing to proxy to own listen address");
+        return Err(SelfTarget {
+            upstream_host: upstream_host.to_string(),
+            port,
+        });
+    }
+    Ok(())
+}
+
 fn is_upgrade_request(req: &Request<Body>) -> bool {
     if req.method() == Method::CONNECT {
         return true;
@@ -303,11 +805,19 @@ fn strip_hop_by_hop_headers(h: &mut HeaderMap) {
     }
 }
 
-fn build_upstream_uri(upstream_host: &str, port: u16, orig: &Uri) -> Result<Uri, Response<Body>> {
+fn wants_trailers(h: &HeaderMap) -> bool {
+    h.get_all(TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+}
+
+fn build_upstream_uri(upstream_host: &str, port: u16, orig: &Uri) -> Result<Uri, Rejection> {
     let path_and_query = orig.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
     let uri_str = format!("http://{}:{}{}", upstream_host, port, path_and_query);
     Uri::from_str(&uri_str)
-        .map_err(|_| response_with(StatusCode::BAD_GATEWAY, "invalid upstream uri".into()))
+        .map_err(|_| response_with(StatusCode::BAD_GATEWAY, "invalid upstream uri".into()).into())
 }
 
 // Attempt to parse a pattern like: <workspace>-<port>.localhost[:...]
@@ -347,60 +857,156 @@ fn parse_workspace_port_from_host(headers: &HeaderMap) -> Option<(String, u16)>
     Some((ws_part.to_string(), port))
 }
 
+fn payload_too_large(limit: u64) -> Response<Body> {
+    response_with(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("request body exceeds {} byte limit", limit),
+    )
+}
+
 fn response_with(status: StatusCode, msg: String) -> Response<Body> {
-    Response::builder()
-        .status(status)
-        .header("content-type", "text/plain; charset=utf-8")
-        .body(Body::from(msg))
-        .unwrap()
+    error_response(status, error::code_for_status(status), msg)
+}
+
+/// A failed upstream request. Connection failures get their own code so clients can tell a
+/// sleeping or stopped workspace apart from an upstream that answered badly.
+fn upstream_error(e: &hyper::Error, context: &str) -> Response<Body> {
+    if let Some(after) = timeout::connect_timed_out(e) {
+        return upstream_timeout(format!(
+            "timed out connecting to upstream after {}",
+            timeout::format_duration(after)
+        ));
+    }
+    let code = if e.is_connect() {
+        "upstream_unreachable"
+    } else {
+        "upstream_error"
+    };
+    error_response(StatusCode::BAD_GATEWAY, code, format!("{}: {}", context, e))
+}
+
+fn upstream_timeout(msg: String) -> Response<Body> {
+    error_response(StatusCode::GATEWAY_TIMEOUT, "upstream_timeout", msg)
+}
+
+/// Send `req` upstream under the connect, first-byte and total deadlines in `timeouts`. The
+/// total deadline counts from `started`, when the proxy received the request.
+async fn request_with_timeouts(
+    client: &Client<TimeoutConnector, Body>,
+    req: Request<Body>,
+    timeouts: &Timeouts,
+    started: Instant,
+    on_error: impl FnOnce(hyper::Error) -> Response<Body>,
+) -> Result<Response<Body>, Response<Body>> {
+    let sending = timeout::with_connect_timeout(
+        timeouts.connect.unwrap_or(Duration::from_secs(5)),
+        client.request(req),
+    );
+    let first_byte = timeouts
+        .first_byte
+        .map(|d| (Instant::now() + d, "upstream did not respond within", d));
+    let total = timeouts
+        .total
+        .map(|d| (started + d, "upstream response did not complete within", d));
+    let earliest = match (first_byte, total) {
+        (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+        (a, b) => a.or(b),
+    };
+    let Some((deadline, what, after)) = earliest else {
+        return sending.await.map_err(on_error);
+    };
+    match tokio::time::timeout_at(deadline, sending).await {
+        Ok(res) => res.map_err(on_error),
+        Err(_) => Err(upstream_timeout(format!(
+            "{} {}",
+            what,
+            timeout::format_duration(after)
+        ))),
+    }
 }
 
 async fn handle(
-    client: Client<HttpConnector, Body>,
-    cfg: ProxyConfig,
+    state: Arc<ProxyState>,
+    cfg: Arc<ProxyConfig>,
     remote_addr: SocketAddr,
     mut req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
     let method = req.method().clone();
     let is_upgrade = is_upgrade_request(&req);
 
-    match method {
-        Method::CONNECT => match handle_connect(req, &cfg, remote_addr).await {
-            Ok(resp) => Ok(resp),
-            Err(resp) => Ok(resp),
-        },
-        _ => {
-            if is_upgrade {
-                match handle_upgrade(client, cfg, remote_addr, req).await {
-                    Ok(resp) => Ok(resp),
-                    Err(resp) => Ok(resp),
-                }
-            } else {
-                match handle_http(client, &cfg, remote_addr, &mut req).await {
-                    Ok(resp) => Ok(resp),
-                    Err(resp) => Ok(resp),
-                }
-            }
+    // HTTP/2 carries the host in the `:authority` pseudo-header; surface it as `Host` so
+    // subdomain routing works the same for both protocols.
+    if req.version() == Version::HTTP_2 && !req.headers().contains_key(HOST) {
+        if let Some(value) = req
+            .uri()
+            .authority()
+            .and_then(|a| HeaderValue::from_str(a.as_str()).ok())
+        {
+            req.headers_mut().insert(HOST, value);
         }
     }
+
+    let request_id = error::request_id(req.headers());
+    let json_errors = error::wants_json(req.headers());
+
+    let result = if let Err(resp) = check_proxy_auth(&cfg, req.headers()) {
+        Err(resp.into())
+    } else if method == Method::CONNECT {
+        handle_connect(&state, req, &cfg, remote_addr).await
+    } else if is_upgrade {
+        handle_upgrade(&state, cfg, remote_addr, req).await
+    } else {
+        handle_http(&state, &cfg, remote_addr, &mut req).await
+    };
+    let mut resp = result.unwrap_or_else(|resp| resp);
+    error::finish_error(&mut resp, &request_id, json_errors);
+    Ok(resp)
 }
 
 async fn handle_http(
-    client: Client<HttpConnector, Body>,
+    state: &Arc<ProxyState>,
     cfg: &ProxyConfig,
     remote_addr: SocketAddr,
     req: &mut Request<Body>,
 ) -> Result<Response<Body>, Response<Body>> {
+    let started = Instant::now();
     let port = get_port_from_header(req.headers())?;
+    check_port_allowed(cfg, port)?;
     let upstream_host = upstream_host_from_headers(
         req.headers(),
+        &cfg.workspace_network,
         &cfg.upstream_host,
         cfg.allow_default_upstream,
     )?;
+    let workspace = workspace_from_headers(req.headers());
+    let (upstream_host, affinity_cookie) = choose_replica(
+        state,
+        cfg,
+        workspace.as_deref(),
+        upstream_host,
+        remote_addr,
+        req.headers(),
+    );
+    reject_self_target(state, &upstream_host, port).await?;
     let uri = build_upstream_uri(&upstream_host, port, req.uri())?;
 
     // Build proxied request
-    let body = std::mem::replace(req.body_mut(), Body::empty());
+    let mut body = std::mem::replace(req.body_mut(), Body::empty());
+    let request_too_large = Arc::new(AtomicBool::new(false));
+    if let Some(limit) = cfg.max_request_body {
+        if limit::declared_length_exceeds(req.headers(), limit) {
+            return Err(payload_too_large(limit));
+        }
+        body = limit::limit_body(body, limit, "request", request_too_large.clone());
+    }
+    let mut mirror = None;
+    if let Some(rule) =
+        mirror::find_mirror(&cfg.mirror_rules, req.uri().path(), workspace.as_deref())
+    {
+        let (primary, copy) = mirror::tee_body(body, mirror::MIRROR_BODY_LIMIT);
+        body = primary;
+        mirror = Some((rule, copy));
+    }
     let mut new_req = Request::builder()
         .method(req.method())
         .uri(uri)
@@ -428,6 +1034,21 @@ async fn handle_http(
     // Strip hop-by-hop headers on the proxied request
     strip_hop_by_hop_headers(new_req.headers_mut());
 
+    // `TE: trailers` is the one TE value HTTP/2 allows, and gRPC servers require it.
+    let is_h2 = req.version() == Version::HTTP_2;
+    if is_h2 && wants_trailers(req.headers()) {
+        new_req
+            .headers_mut()
+            .insert(TE, HeaderValue::from_static("trailers"));
+    }
+    apply_header_rules(
+        &cfg.header_rules,
+        RuleDirection::Request,
+        req.uri().path(),
+        workspace.as_deref(),
+        new_req.headers_mut(),
+    );
+
     info!(
         client = %remote_addr,
         method = %new_req.method(),
@@ -437,12 +1058,43 @@ async fn handle_http(
         "proxy http"
     );
 
-    let upstream_resp = client.request(new_req).await.map_err(|e| {
-        response_with(
-            StatusCode::BAD_GATEWAY,
-            format!("upstream request error: {}", e),
+    if let Some((rule, copy)) = mirror {
+        spawn_mirror(state.clone(), rule, port, req.uri(), &new_req, copy, is_h2);
+    }
+
+    let permit = state
+        .upstream_limiter
+        .acquire(
+            &upstream_host,
+            port,
+            cfg.max_upstream_connections,
+            cfg.upstream_queue_timeout,
         )
-    })?;
+        .await?;
+
+    // Bodies are streamed through untouched, so request and response trailers are forwarded.
+    let client = if is_h2 {
+        &state.h2_client
+    } else {
+        &state.client
+    };
+    let timeouts = timeout::for_route(cfg, Some(req.uri().path()), workspace.as_deref(), port);
+    let upstream_resp = request_with_timeouts(client, new_req, &timeouts, started, |e| {
+        if request_too_large.load(Ordering::SeqCst) {
+            return payload_too_large(cfg.max_request_body.unwrap_or_default());
+        }
+        upstream_error(&e, "upstream request error")
+    })
+    .await?;
+    if let Some(limit) = cfg.max_response_body {
+        if limit::declared_length_exceeds(upstream_resp.headers(), limit) {
+            return Err(error_response(
+                StatusCode::BAD_GATEWAY,
+                "upstream_response_too_large",
+                format!("upstream response exceeds {} byte limit", limit),
+            ));
+        }
+    }
 
     // Map upstream response back to client, stripping hop-by-hop headers
     let mut client_resp_builder = Response::builder().status(upstream_resp.status());
@@ -454,8 +1106,44 @@ async fn handle_http(
         headers.insert(name, value.clone());
     }
     strip_hop_by_hop_headers(headers);
+    apply_header_rules(
+        &cfg.header_rules,
+        RuleDirection::Response,
+        req.uri().path(),
+        workspace.as_deref(),
+        headers,
+    );
+    append_set_cookie(headers, affinity_cookie);
+
+    let encoding = cfg
+        .compress
+        .then(|| {
+            compress::should_compress(
+                req.method(),
+                req.headers(),
+                upstream_resp.status(),
+                headers,
+                cfg.compress_min_size,
+            )
+        })
+        .flatten();
+    if let Some(encoding) = encoding {
+        compress::mark_compressed(headers, encoding);
+    }
 
-    let body = upstream_resp.into_body();
+    let mut body = upstream_resp.into_body();
+    if let Some(limit) = cfg.max_response_body {
+        body = limit::limit_body(body, limit, "response", Arc::new(AtomicBool::new(false)));
+    }
+    if let Some(encoding) = encoding {
+        body = compress::compress_body(body, encoding);
+    }
+    if let Some(total) = timeouts.total {
+        body = timeout::deadline_body(body, started + total);
+    }
+    if let Some(permit) = permit {
+        body = upstream::hold_until_done(body, permit);
+    }
     let resp = client_resp_builder.body(body).map_err(|_| {
         response_with(
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -465,21 +1153,82 @@ async fn handle_http(
     Ok(resp)
 }
 
+/// Send a copy of `primary` to the mirror described by `rule` without waiting for it.
+fn spawn_mirror(
+    state: Arc<ProxyState>,
+    rule: &MirrorRule,
+    port: u16,
+    orig: &Uri,
+    primary: &Request<Body>,
+    body: Body,
+    is_h2: bool,
+) {
+    let port = rule.port.unwrap_or(port);
+    let Ok(uri) = build_upstream_uri(&rule.host, port, orig) else {
+        warn!(mirror = %rule, "invalid mirror target; not mirroring");
+        return;
+    };
+    let mut mirror_req = Request::new(body);
+    *mirror_req.method_mut() = primary.method().clone();
+    *mirror_req.uri_mut() = uri;
+    *mirror_req.version_mut() = primary.version();
+    *mirror_req.headers_mut() = primary.headers().clone();
+    mirror_req
+        .headers_mut()
+        .insert("x-cmux-mirror", HeaderValue::from_static("1"));
+    let host = rule.host.clone();
+    tokio::spawn(async move {
+        if reject_self_target(&state, &host, port).await.is_err() {
+            return;
+        }
+        let client = if is_h2 {
+            &state.h2_client
+        } else {
+            &state.client
+        };
+        match tokio::time::timeout(MIRROR_TIMEOUT, client.request(mirror_req)).await {
+            // Read the body to the end so the pooled connection can be reused.
+            Ok(Ok(resp)) => {
+                let _ = tokio::time::timeout(MIRROR_TIMEOUT, hyper::body::to_bytes(resp)).await;
+            }
+            Ok(Err(e)) => warn!(mirror = %host, port, error = %e, "mirror request failed"),
+            Err(_) => warn!(mirror = %host, port, "mirror request timed out"),
+        }
+    });
+}
+
+/// Upper bound on each phase of a mirrored request, so a hung mirror cannot pile up tasks.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(30);
+
 async fn handle_upgrade(
-    client: Client<HttpConnector, Body>,
-    cfg: ProxyConfig,
+    state: &ProxyState,
+    cfg: Arc<ProxyConfig>,
     remote_addr: SocketAddr,
     mut req: Request<Body>,
 ) -> Result<Response<Body>, Response<Body>> {
+    let started = Instant::now();
     // Treat as reverse-proxied upgrade (e.g., WebSocket). We forward the request to upstream,
     // then mirror the 101 response headers to the client and tunnel bytes between both upgrades.
     let port = get_port_from_header(req.headers())?;
+    check_port_allowed(&cfg, port)?;
     let upstream_host = upstream_host_from_headers(
         req.headers(),
+        &cfg.workspace_network,
         &cfg.upstream_host,
         cfg.allow_default_upstream,
     )?;
+    let workspace = workspace_from_headers(req.headers());
+    let (upstream_host, affinity_cookie) = choose_replica(
+        state,
+        &cfg,
+        workspace.as_deref(),
+        upstream_host,
+        remote_addr,
+        req.headers(),
+    );
+    reject_self_target(state, &upstream_host, port).await?;
     let upstream_uri = build_upstream_uri(&upstream_host, port, req.uri())?;
+    let path = req.uri().path().to_string();
 
     // Build proxied request for upstream
     let body = std::mem::replace(req.body_mut(), Body::empty());
@@ -512,16 +1261,47 @@ async fn handle_upgrade(
     proxied_req.headers_mut().remove("te");
     proxied_req.headers_mut().remove("transfer-encoding");
     proxied_req.headers_mut().remove("trailers");
+    apply_header_rules(
+        &cfg.header_rules,
+        RuleDirection::Request,
+        &path,
+        workspace.as_deref(),
+        proxied_req.headers_mut(),
+    );
 
     info!(client = %remote_addr, port = port, upstream = %upstream_host, "proxy upgrade (e.g. websocket)");
 
+    let timeouts = timeout::for_route(&cfg, Some(&path), workspace.as_deref(), port);
+    let is_websocket = ws::is_websocket_upgrade(req.headers());
+    let idle_timeout = if is_websocket {
+        timeouts
+            .idle
+            .or(cfg.ws_idle_timeout)
+            .or(cfg.tunnel_idle_timeout)
+    } else {
+        timeouts.idle.or(cfg.tunnel_idle_timeout)
+    };
+    let keepalive_tunnel = is_websocket
+        && (cfg.ws_keepalive.is_some() || idle_timeout.is_some() || cfg.ws_frame_debug);
+    let frame_debug = cfg.ws_frame_debug;
+
     // Send to upstream and get its response (should be 101)
-    let upstream_resp = client.request(proxied_req).await.map_err(|e| {
-        response_with(
-            StatusCode::BAD_GATEWAY,
-            format!("upstream upgrade error: {}", e),
+    let permit = state
+        .upstream_limiter
+        .acquire(
+            &upstream_host,
+            port,
+            cfg.max_upstream_connections,
+            cfg.upstream_queue_timeout,
         )
-    })?;
+        .await?;
+    // The total timeout bounds the handshake; the tunnel itself is governed by the idle one.
+    let upstream_resp =
+        request_with_timeouts(&state.client, proxied_req, &timeouts, started, |e| {
+            upstream_error(&e, "upstream upgrade error")
+        })
+        .await?;
+    drop(permit);
 
     if upstream_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
         // Return upstream status (probably 4xx/5xx) to client with body
@@ -531,6 +1311,13 @@ async fn handle_upgrade(
         for (k, v) in upstream_resp.headers() {
             headers.insert(k, v.clone());
         }
+        apply_header_rules(
+            &cfg.header_rules,
+            RuleDirection::Response,
+            &path,
+            workspace.as_deref(),
+            headers,
+        );
         let body = upstream_resp.into_body();
         return builder.body(body).map_err(|_| {
             response_with(
@@ -548,6 +1335,14 @@ async fn handle_upgrade(
     for (k, v) in upstream_resp.headers().iter() {
         out_headers.insert(k, v.clone());
     }
+    apply_header_rules(
+        &cfg.header_rules,
+        RuleDirection::Response,
+        &path,
+        workspace.as_deref(),
+        out_headers,
+    );
+    append_set_cookie(out_headers, affinity_cookie);
     // Ensure Connection: upgrade and Upgrade headers are present
     out_headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
 
@@ -559,17 +1354,43 @@ async fn handle_upgrade(
         )
     })?;
 
+    let kind = if is_websocket {
+        TunnelKind::WebSocket
+    } else {
+        TunnelKind::Upgrade
+    };
+    let target = format!("{}:{}", upstream_host, port);
+
     // Spawn tunnel after returning the 101 to the client
-    tokio::spawn(async move {
+    state.spawn_tunnel(kind, remote_addr, target, |stats| async move {
         match future::try_join(
             hyper::upgrade::on(&mut req),
             hyper::upgrade::on(upstream_resp),
         )
         .await
         {
-            Ok((mut client_upgraded, mut upstream_upgraded)) => {
-                if let Err(e) =
-                    copy_bidirectional(&mut client_upgraded, &mut upstream_upgraded).await
+            Ok((client_upgraded, upstream_upgraded)) if keepalive_tunnel => {
+                let frame_log = frame_debug.then(|| stats.clone());
+                if let Err(e) = ws::tunnel_with_keepalive(
+                    Counted::new(client_upgraded, stats),
+                    upstream_upgraded,
+                    cfg.ws_keepalive,
+                    idle_timeout,
+                    frame_log,
+                )
+                .await
+                {
+                    warn!(%e, "websocket tunnel error");
+                }
+            }
+            Ok((client_upgraded, mut upstream_upgraded)) => {
+                let mut client_upgraded = Counted::new(client_upgraded, stats);
+                if let Err(e) = tunnel::copy_until_idle(
+                    &mut client_upgraded,
+                    &mut upstream_upgraded,
+                    idle_timeout,
+                )
+                .await
                 {
                     warn!(%e, "upgrade tunnel error");
                 }
@@ -587,18 +1408,57 @@ async fn handle_upgrade(
 }
 
 async fn handle_connect(
+    state: &ProxyState,
     mut req: Request<Body>,
     cfg: &ProxyConfig,
     remote_addr: SocketAddr,
 ) -> Result<Response<Body>, Response<Body>> {
-    let port = get_port_from_header(req.headers())?;
-    let upstream_host = upstream_host_from_headers(
-        req.headers(),
-        &cfg.upstream_host,
-        cfg.allow_default_upstream,
-    )?;
-    let target = format!("{}:{}", upstream_host, port);
-    info!(client = %remote_addr, %target, "tcp tunnel via CONNECT");
+    let workspace = workspace_from_headers(req.headers());
+    let resolved = async {
+        check_bearer(cfg.connect_auth_token.as_deref(), req.headers())?;
+        let port = get_port_from_header(req.headers())?;
+        check_port_allowed(cfg, port)?;
+        let upstream_host = upstream_host_from_headers(
+            req.headers(),
+            &cfg.workspace_network,
+            &cfg.upstream_host,
+            cfg.allow_default_upstream,
+        )?;
+        check_connect_target(cfg, workspace.as_deref(), &upstream_host)?;
+        reject_self_target(state, &upstream_host, port).await?;
+        let timeouts = timeout::for_route(cfg, None, workspace.as_deref(), port);
+        let permit = state
+            .upstream_limiter
+            .acquire(
+                &upstream_host,
+                port,
+                cfg.max_upstream_connections,
+                cfg.upstream_queue_timeout,
+            )
+            .await?;
+        Ok::<_, Response<Body>>((format!("{}:{}", upstream_host, port), permit, timeouts))
+    }
+    .await;
+    let (target, permit, timeouts) = match resolved {
+        Ok(resolved) => resolved,
+        Err(resp) => {
+            info!(
+                target: AUDIT,
+                client = %remote_addr,
+                workspace = workspace.as_deref().unwrap_or("-"),
+                status = resp.status().as_u16(),
+                "connect rejected"
+            );
+            return Err(resp);
+        }
+    };
+    info!(
+        target: AUDIT,
+        client = %remote_addr,
+        workspace = workspace.as_deref().unwrap_or("-"),
+        %target,
+        "connect accepted"
+    );
 
     // Respond that the connection is established; then upgrade to a raw tunnel
     let resp = Response::builder()
@@ -612,27 +1472,64 @@ async fn handle_connect(
             )
         })?;
 
-    tokio::spawn(async move {
-        match hyper::upgrade::on(&mut req).await {
-            Ok(mut upgraded) => match TcpStream::connect(&target).await {
-                Ok(mut upstream) => {
-                    if let Err(e) = copy_bidirectional(&mut upgraded, &mut upstream).await {
-                        warn!(%e, "tcp tunnel error");
+    let connect_timeout = timeouts.connect.unwrap_or(cfg.connect_timeout);
+    let idle_timeout = timeouts.idle.or(cfg.tunnel_idle_timeout);
+    let tunnel_target = target.clone();
+    state.spawn_tunnel(
+        TunnelKind::Connect,
+        remote_addr,
+        tunnel_target,
+        |stats| async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(mut upgraded) => {
+                    let connected =
+                        tokio::time::timeout(connect_timeout, TcpStream::connect(&target)).await;
+                    // Only the connect counts against the upstream cap, not the tunnel.
+                    drop(permit);
+                    match connected {
+                        Ok(Ok(mut upstream)) => {
+                            let mut upgraded = Counted::new(upgraded, stats.clone());
+                            if let Err(e) =
+                                tunnel::copy_until_idle(&mut upgraded, &mut upstream, idle_timeout)
+                                    .await
+                            {
+                                warn!(%e, "tcp tunnel error");
+                            }
+                            let _ = upgraded.shutdown().await;
+                            let _ = upstream.shutdown().await;
+                            info!(
+                                target: AUDIT,
+                                id = stats.id,
+                                client = %stats.client,
+                                target = %stats.target,
+                                bytes_to_upstream = stats.to_upstream.load(Ordering::Relaxed),
+                                bytes_to_client = stats.to_client.load(Ordering::Relaxed),
+                                duration_ms = stats.started.elapsed().as_millis() as u64,
+                                "connect closed"
+                            );
+                        }
+                        Ok(Err(e)) => {
+                            warn!(%e, "failed to connect to upstream for CONNECT");
+                            let _ = upgraded
+                                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                                .await;
+                            let _ = upgraded.shutdown().await;
+                        }
+                        Err(_) => {
+                            warn!(%target, "timed out connecting to upstream for CONNECT");
+                            let _ = upgraded
+                                .write_all(
+                                    b"HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\n\r\n",
+                                )
+                                .await;
+                            let _ = upgraded.shutdown().await;
+                        }
                     }
-                    let _ = upgraded.shutdown().await;
-                    let _ = upstream.shutdown().await;
                 }
-                Err(e) => {
-                    warn!(%e, "failed to connect to upstream for CONNECT");
-                    let _ = upgraded
-                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
-                        .await;
-                    let _ = upgraded.shutdown().await;
-                }
-            },
-            Err(e) => warn!("CONNECT upgrade error: {:?}", e),
-        }
-    });
+                Err(e) => warn!("CONNECT upgrade error: {:?}", e),
+            }
+        },
+    );
 
     Ok(resp)
 }
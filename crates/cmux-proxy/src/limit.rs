@@ -0,0 +1,63 @@
+//! Streaming body size limits.
+//!
+//! Bodies are pumped through a channel instead of buffered, so a multi-GB artifact never sits in
+//! memory and the stream is aborted as soon as the limit is crossed. Trailers are forwarded,
+//! which keeps gRPC working under a limit.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, HeaderMap};
+use tracing::warn;
+
+/// True when a declared `Content-Length` is already over `limit`.
+pub(crate) fn declared_length_exceeds(headers: &HeaderMap, limit: u64) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .is_some_and(|len| len > limit)
+}
+
+/// Wrap `body` so it errors once more than `limit` bytes have passed. `exceeded` is set when
+/// that happens, so the caller can tell a limit abort from other stream errors.
+pub(crate) fn limit_body(
+    mut body: Body,
+    limit: u64,
+    what: &'static str,
+    exceeded: Arc<AtomicBool>,
+) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut total: u64 = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            };
+            total += chunk.len() as u64;
+            if total > limit {
+                warn!(limit, body = what, "body exceeded size limit; aborting");
+                exceeded.store(true, Ordering::SeqCst);
+                tx.abort();
+                return;
+            }
+            if tx.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => tx.abort(),
+        }
+    });
+    rx
+}
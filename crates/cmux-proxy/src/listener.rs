@@ -0,0 +1,182 @@
+//! Per-listener overrides for [`spawn_proxy_listeners`](crate::spawn_proxy_listeners).
+//!
+//! Different loopback listeners front different workspaces, so each may carry its own default
+//! upstream host, allowed target ports and auth requirement, and may terminate TLS (e.g. the
+//! wireguard-facing interface next to a plain loopback one). Unset fields fall back to the
+//! shared [`ProxyConfig`].
+//!
+//! String form (used by `--listener`):
+//!
+//! ```text
+//! <addr>[;upstream=<host>][;ports=<lo>-<hi>][;token=<secret>][;tls-cert=<pem>;tls-key=<pem>]
+//! ```
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+
+use crate::tls::TlsConfig;
+use crate::ProxyConfig;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub listen: SocketAddr,
+    /// Default upstream host for requests on this listener.
+    pub upstream_host: Option<String>,
+    /// Target ports requests on this listener may reach.
+    pub allowed_ports: Option<RangeInclusive<u16>>,
+    /// Require `Proxy-Authorization: Bearer <token>` on this listener.
+    pub auth_token: Option<String>,
+    /// Terminate TLS on this listener.
+    pub tls: Option<TlsConfig>,
+}
+
+impl ListenerConfig {
+    pub fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            upstream_host: None,
+            allowed_ports: None,
+            auth_token: None,
+            tls: None,
+        }
+    }
+
+    /// The effective config for connections accepted on this listener.
+    pub(crate) fn apply(&self, base: &ProxyConfig) -> ProxyConfig {
+        let mut cfg = base.clone();
+        cfg.listen = self.listen;
+        if let Some(host) = &self.upstream_host {
+            cfg.upstream_host = host.clone();
+        }
+        if let Some(ports) = &self.allowed_ports {
+            cfg.allowed_ports = Some(ports.clone());
+        }
+        if let Some(token) = &self.auth_token {
+            cfg.auth_token = Some(token.clone());
+        }
+        cfg
+    }
+}
+
+/// A listener's effective config, merged once per config snapshot so requests share it
+/// instead of cloning the whole [`ProxyConfig`] each time.
+pub(crate) struct ListenerView {
+    listener: ListenerConfig,
+    /// The snapshot last merged, with its merged result.
+    merged: ArcSwapOption<(Arc<ProxyConfig>, Arc<ProxyConfig>)>,
+}
+
+impl ListenerView {
+    pub(crate) fn new(listener: ListenerConfig) -> Self {
+        Self {
+            listener,
+            merged: ArcSwapOption::empty(),
+        }
+    }
+
+    /// The effective config under `base`, reusing the previous merge while `base` is unchanged.
+    pub(crate) fn config(&self, base: Arc<ProxyConfig>) -> Arc<ProxyConfig> {
+        if let Some(cached) = self.merged.load().as_ref() {
+            if Arc::ptr_eq(&cached.0, &base) {
+                return cached.1.clone();
+            }
+        }
+        let cfg = Arc::new(self.listener.apply(&base));
+        self.merged.store(Some(Arc::new((base, cfg.clone()))));
+        cfg
+    }
+}
+
+impl From<SocketAddr> for ListenerConfig {
+    fn from(listen: SocketAddr) -> Self {
+        Self::new(listen)
+    }
+}
+
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split(';');
+        let addr = parts.next().unwrap_or("").trim();
+        let listen: SocketAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid listener address: {}", addr))?;
+        let mut out = Self::new(listen);
+        let (mut tls_cert, mut tls_key) = (None, None);
+        for part in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value in listener option: {}", part))?;
+            match key.trim() {
+                "upstream" => out.upstream_host = Some(value.trim().to_string()),
+                "ports" => out.allowed_ports = Some(parse_port_range(value.trim())?),
+                "token" => out.auth_token = Some(value.trim().to_string()),
+                "tls-cert" => tls_cert = Some(value.trim().to_string()),
+                "tls-key" => tls_key = Some(value.trim().to_string()),
+                other => return Err(format!("unknown listener option: {}", other)),
+            }
+        }
+        out.tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "listener needs both tls-cert and tls-key: {}",
+                    addr
+                ))
+            }
+        };
+        Ok(out)
+    }
+}
+
+impl fmt::Display for ListenerConfig {
+    /// Same syntax as `FromStr`, with the token redacted.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.listen)?;
+        if let Some(host) = &self.upstream_host {
+            write!(f, ";upstream={}", host)?;
+        }
+        if let Some(ports) = &self.allowed_ports {
+            write!(f, ";ports={}-{}", ports.start(), ports.end())?;
+        }
+        if self.auth_token.is_some() {
+            write!(f, ";token=<redacted>")?;
+        }
+        if let Some(tls) = &self.tls {
+            write!(
+                f,
+                ";tls-cert={};tls-key={}",
+                tls.cert_path.display(),
+                tls.key_path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `lo-hi` or a single port.
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port in range: {}", p))
+    };
+    let (lo, hi) = match s.split_once('-') {
+        Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+        None => {
+            let p = parse(s)?;
+            (p, p)
+        }
+    };
+    if lo > hi {
+        return Err(format!("port range start exceeds end: {}", s));
+    }
+    Ok(lo..=hi)
+}
@@ -1,7 +1,9 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::Parser;
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -23,20 +25,166 @@ struct Args {
     /// Allow requests without workspace headers to route to the default upstream host.
     #[arg(long, env = "CMUX_ALLOW_DEFAULT_UPSTREAM", default_value_t = true)]
     allow_default_upstream: bool,
+
+    /// Send a WebSocket Ping to clients of upgraded tunnels every N seconds (0 disables).
+    #[arg(long, env = "CMUX_WS_KEEPALIVE_SECS", default_value_t = 0)]
+    ws_keepalive_secs: u64,
+
+    /// Close upgraded WebSocket tunnels idle for N seconds in both directions (0 disables).
+    #[arg(long, env = "CMUX_WS_IDLE_TIMEOUT_SECS", default_value_t = 0)]
+    ws_idle_timeout_secs: u64,
+
+    /// Close upgrade/CONNECT tunnels idle for N seconds in both directions (0 disables). WebSockets
+    /// use `--ws-idle-timeout-secs` when that is set.
+    #[arg(long, env = "CMUX_TUNNEL_IDLE_TIMEOUT_SECS", default_value_t = 0)]
+    tunnel_idle_timeout_secs: u64,
+
+    /// Give up connecting to an upstream after N milliseconds (504).
+    #[arg(long, env = "CMUX_CONNECT_TIMEOUT_MS", default_value_t = 5000)]
+    connect_timeout_ms: u64,
+
+    /// Give up on upstreams that haven't sent response headers N seconds after the request went out (0 disables).
+    #[arg(long, env = "CMUX_FIRST_BYTE_TIMEOUT_SECS", default_value_t = 0)]
+    first_byte_timeout_secs: u64,
+
+    /// Bound whole HTTP exchanges to N seconds: 504 before headers, otherwise the body is cut (0 disables).
+    #[arg(long, env = "CMUX_RESPONSE_TIMEOUT_SECS", default_value_t = 0)]
+    response_timeout_secs: u64,
+
+    /// Timeout rule, repeatable: `[path=/prefix] [workspace=name] [port=n] [connect=5s] [ttfb=30s] [total=2m] [idle=10m]`.
+    /// Overrides the timeouts above for matching requests; the first matching rule wins.
+    #[arg(
+        long = "timeout-rule",
+        env = "CMUX_TIMEOUT_RULES",
+        value_delimiter = '\n'
+    )]
+    timeout_rules: Vec<cmux_proxy::TimeoutRule>,
+
+    /// Log every WebSocket frame's opcode and size (target `cmux_proxy::ws_frames`) and count
+    /// frames per tunnel in the admin API. For debugging stuck sessions; costs a parse per frame.
+    #[arg(long, env = "CMUX_WS_FRAME_DEBUG", default_value_t = false)]
+    ws_frame_debug: bool,
+
+    /// On shutdown, let open WebSocket/CONNECT tunnels run for up to N seconds before aborting them.
+    #[arg(long, env = "CMUX_DRAIN_TIMEOUT_SECS", default_value_t = 5)]
+    drain_timeout_secs: u64,
+
+    /// Serve the admin API (tunnels, config, log level) on this address. Disabled when unset;
+    /// it is unauthenticated, so keep it on loopback.
+    #[arg(long, env = "CMUX_ADMIN_LISTEN")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Header rewrite rule, repeatable: `[path=/prefix] [workspace=name] request|response:add|set|remove:Name[=value]`.
+    /// Passing any rule replaces the default, which strips internal `X-Cmux-*` headers before they reach upstreams.
+    #[arg(long = "header-rule", env = "CMUX_HEADER_RULES", value_delimiter = '\n', default_values = ["request:remove:X-Cmux-*"])]
+    header_rules: Vec<cmux_proxy::HeaderRule>,
+
+    /// Replica set for a workspace, repeatable: `workspace=host[,host...]`. Requests for that workspace
+    /// are spread across the hosts instead of its mapped IP.
+    #[arg(long = "replica", env = "CMUX_REPLICAS", value_delimiter = '\n', value_parser = cmux_proxy::parse_replica_set)]
+    replicas: Vec<(String, Vec<String>)>,
+
+    /// Replica affinity: `none` (round-robin), `cookie` (session cookie) or `ip` (client IP).
+    #[arg(long, env = "CMUX_AFFINITY", default_value = "none")]
+    affinity: cmux_proxy::AffinityMode,
+
+    /// Forget a client's replica after N seconds without requests.
+    #[arg(long, env = "CMUX_AFFINITY_TTL_SECS", default_value_t = 3600)]
+    affinity_ttl_secs: u64,
+
+    /// Compress uncompressed text/JSON/JS/etc. upstream responses with br or gzip, as the client accepts.
+    #[arg(long, env = "CMUX_COMPRESS", default_value_t = false)]
+    compress: bool,
+
+    /// Leave responses declaring fewer bytes than this uncompressed.
+    #[arg(long, env = "CMUX_COMPRESS_MIN_BYTES", default_value_t = 1024)]
+    compress_min_bytes: u64,
+
+    /// Cap concurrent connections to any single upstream `host:port`; extra requests queue (0 disables).
+    #[arg(long, env = "CMUX_MAX_UPSTREAM_CONNECTIONS", default_value_t = 0)]
+    max_upstream_connections: usize,
+
+    /// How long a request may wait for an upstream connection slot before getting 503.
+    #[arg(long, env = "CMUX_UPSTREAM_QUEUE_TIMEOUT_SECS", default_value_t = 10)]
+    upstream_queue_timeout_secs: u64,
+
+    /// Mirror rule, repeatable: `[path=/prefix] [workspace=name] host[:port]`. Matching HTTP requests are
+    /// also sent to the mirror target; its responses are discarded.
+    #[arg(long = "mirror", env = "CMUX_MIRROR_RULES", value_delimiter = '\n')]
+    mirror_rules: Vec<cmux_proxy::MirrorRule>,
+
+    /// Network that `workspace-N` names are mapped into (N's low bits become the host part).
+    #[arg(long, env = "CMUX_WORKSPACE_CIDR", default_value = "127.18.0.0/16")]
+    workspace_cidr: cmux_proxy::WorkspaceNetwork,
+
+    /// Optional file of `name: ip` lines pinning workspaces to explicit addresses.
+    #[arg(long, env = "CMUX_WORKSPACE_MAP")]
+    workspace_map: Option<PathBuf>,
+
+    /// Optional file of header rules (one per line, `#` comments), applied after `--header-rule`.
+    /// Re-read together with `--workspace-map` on SIGHUP or `POST /reload`.
+    #[arg(long, env = "CMUX_HEADER_RULES_FILE")]
+    header_rules_file: Option<PathBuf>,
+
+    /// Listener with its own overrides, repeatable:
+    /// `addr[;upstream=host][;ports=lo-hi][;token=secret][;tls-cert=cert.pem;tls-key=key.pem]`.
+    /// Replaces a `--listen` entry with the same address, otherwise is added alongside them.
+    #[arg(long = "listener", env = "CMUX_LISTENERS", value_delimiter = '\n')]
+    listeners: Vec<cmux_proxy::ListenerConfig>,
+
+    /// Only proxy to target ports in this range (`lo-hi`) unless a listener overrides it.
+    #[arg(long, env = "CMUX_ALLOWED_PORTS", value_parser = cmux_proxy::parse_port_range)]
+    allowed_ports: Option<std::ops::RangeInclusive<u16>>,
+
+    /// Only accept connections from these networks (comma-separated CIDRs or addresses; all when unset).
+    #[arg(long = "allow-cidr", env = "CMUX_ALLOW_CIDRS", value_delimiter = ',')]
+    allow_cidrs: Vec<cmux_proxy::IpCidr>,
+
+    /// Refuse connections from these networks, even if allowed above (comma-separated).
+    #[arg(long = "deny-cidr", env = "CMUX_DENY_CIDRS", value_delimiter = ',')]
+    deny_cidrs: Vec<cmux_proxy::IpCidr>,
+
+    /// Require `Proxy-Authorization: Bearer <token>` on every listener unless one overrides it.
+    #[arg(long, env = "CMUX_PROXY_TOKEN")]
+    proxy_token: Option<String>,
+
+    /// Additionally require `Proxy-Authorization: Bearer <token>` on CONNECT tunnels.
+    #[arg(long, env = "CMUX_CONNECT_TOKEN")]
+    connect_token: Option<String>,
+
+    /// Only allow CONNECT to workspaces pinned in `--workspace-map`.
+    #[arg(long, env = "CMUX_CONNECT_REGISTERED_ONLY", default_value_t = false)]
+    connect_registered_only: bool,
+
+    /// Reject request bodies over N bytes with 413 (0 disables).
+    #[arg(long, env = "CMUX_MAX_REQUEST_BODY_BYTES", default_value_t = 0)]
+    max_request_body_bytes: u64,
+
+    /// Fail upstream response bodies over N bytes: 502 when declared, otherwise the stream is cut (0 disables).
+    #[arg(long, env = "CMUX_MAX_RESPONSE_BODY_BYTES", default_value_t = 0)]
+    max_response_body_bytes: u64,
 }
 
+/// Frame logs are only emitted with `--ws-frame-debug`, so their target can default to debug.
+const DEFAULT_LOG_FILTER: &str =
+    "cmux-proxy=info,cmux_proxy::audit=info,cmux_proxy::ws_frames=debug,hyper=warn";
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    // Init logging
-    tracing_subscriber::fmt()
+    // Init logging. The filter is reloadable so the admin API can change it at runtime.
+    let initial_filter =
+        std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string());
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "cmux-proxy=info,hyper=warn".into()),
+            tracing_subscriber::EnvFilter::try_new(&initial_filter)
+                .unwrap_or_else(|_| DEFAULT_LOG_FILTER.into()),
         )
         .compact()
-        .init();
+        .with_filter_reloading();
+    let filter_reload = subscriber.reload_handle();
+    subscriber.init();
 
     info!(
         "listen" = ?args.listen,
@@ -54,19 +202,158 @@ async fn main() {
     });
     listens.dedup();
     let listens = dedupe_wildcard_v4(listens);
+    let mut listeners: Vec<cmux_proxy::ListenerConfig> = listens
+        .into_iter()
+        .filter(|addr| !args.listeners.iter().any(|l| l.listen == *addr))
+        .map(cmux_proxy::ListenerConfig::new)
+        .collect();
+    listeners.extend(args.listeners);
+    for listener in &listeners {
+        if let Some(tls) = &listener.tls {
+            if let Err(e) = tls.load() {
+                eprintln!("invalid TLS configuration for {}: {}", listener.listen, e);
+                std::process::exit(2);
+            }
+        }
+    }
 
-    let upstream_host = args.upstream_host;
-    let allow_default_upstream = args.allow_default_upstream;
+    // File-backed settings, re-read on SIGHUP and `POST /reload`. Flag-only settings such as the
+    // source allow/deny lists cannot change without a restart or `PUT /config`.
+    let sources = ReloadSources {
+        workspace_cidr: args.workspace_cidr,
+        workspace_map: args.workspace_map,
+        header_rules: args.header_rules,
+        header_rules_file: args.header_rules_file,
+    };
+    let (workspace_network, header_rules) = match sources.load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let reload_handle = cmux_proxy::ReloadHandle::new(move |config| {
+        let (workspace_network, header_rules) = sources.load()?;
+        config.update(|cfg| {
+            cfg.workspace_network = workspace_network.clone();
+            cfg.header_rules = header_rules.clone();
+            Ok(())
+        })
+    });
 
-    let (bound, handle) =
-        cmux_proxy::spawn_proxy_multi(listens, upstream_host, allow_default_upstream, async {
-            let _ = tokio::signal::ctrl_c().await;
-        });
+    let cfg = cmux_proxy::ProxyConfig {
+        upstream_host: args.upstream_host,
+        allow_default_upstream: args.allow_default_upstream,
+        ws_keepalive: secs_to_duration(args.ws_keepalive_secs),
+        ws_idle_timeout: secs_to_duration(args.ws_idle_timeout_secs),
+        ws_frame_debug: args.ws_frame_debug,
+        tunnel_idle_timeout: secs_to_duration(args.tunnel_idle_timeout_secs),
+        connect_timeout: Duration::from_millis(args.connect_timeout_ms.max(1)),
+        first_byte_timeout: secs_to_duration(args.first_byte_timeout_secs),
+        response_timeout: secs_to_duration(args.response_timeout_secs),
+        timeout_rules: args.timeout_rules,
+        drain_timeout: Duration::from_secs(args.drain_timeout_secs),
+        admin: args.admin_listen.map(|listen| cmux_proxy::AdminConfig {
+            listen,
+            log_filter: Some(cmux_proxy::LogFilterHandle::new(
+                initial_filter,
+                move |directive| {
+                    let filter = tracing_subscriber::EnvFilter::try_new(directive)
+                        .map_err(|e| e.to_string())?;
+                    filter_reload.reload(filter).map_err(|e| e.to_string())
+                },
+            )),
+            reload: Some(reload_handle.clone()),
+        }),
+        header_rules,
+        workspace_network,
+        allowed_ports: args.allowed_ports,
+        ip_allowlist: args.allow_cidrs,
+        ip_denylist: args.deny_cidrs,
+        auth_token: args.proxy_token,
+        connect_auth_token: args.connect_token,
+        connect_registered_only: args.connect_registered_only,
+        max_request_body: (args.max_request_body_bytes > 0).then_some(args.max_request_body_bytes),
+        max_response_body: (args.max_response_body_bytes > 0)
+            .then_some(args.max_response_body_bytes),
+        mirror_rules: args.mirror_rules,
+        compress: args.compress,
+        replicas: args.replicas.into_iter().collect(),
+        affinity: args.affinity,
+        affinity_ttl: Duration::from_secs(args.affinity_ttl_secs),
+        compress_min_size: args.compress_min_bytes,
+        max_upstream_connections: (args.max_upstream_connections > 0)
+            .then_some(args.max_upstream_connections),
+        upstream_queue_timeout: Duration::from_secs(args.upstream_queue_timeout_secs),
+        ..Default::default()
+    };
+
+    let config = cmux_proxy::ConfigHandle::new(cfg);
+    spawn_sighup_reload(reload_handle, config.clone());
+    let (bound, handle) = cmux_proxy::spawn_proxy_with_handle(listeners, config, async {
+        let _ = tokio::signal::ctrl_c().await;
+    });
     info!("bound_addrs" = ?bound, "proxy started");
     let _ = handle.await;
 }
 // server logic moved to library
 
+struct ReloadSources {
+    workspace_cidr: cmux_proxy::WorkspaceNetwork,
+    workspace_map: Option<PathBuf>,
+    header_rules: Vec<cmux_proxy::HeaderRule>,
+    header_rules_file: Option<PathBuf>,
+}
+
+impl ReloadSources {
+    fn load(&self) -> Result<(cmux_proxy::WorkspaceNetwork, Vec<cmux_proxy::HeaderRule>), String> {
+        let network = match &self.workspace_map {
+            Some(path) => self
+                .workspace_cidr
+                .clone()
+                .with_mapping_file(path)
+                .map_err(|e| format!("workspace map: {}", e))?,
+            None => self.workspace_cidr.clone(),
+        };
+        let mut rules = self.header_rules.clone();
+        if let Some(path) = &self.header_rules_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("read {}: {}", path.display(), e))?;
+            for line in text.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                rules.push(line.parse()?);
+            }
+        }
+        Ok((network, rules))
+    }
+}
+
+#[cfg(unix)]
+fn spawn_sighup_reload(reload: cmux_proxy::ReloadHandle, config: cmux_proxy::ConfigHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut hup) = signal(SignalKind::hangup()) else {
+        warn!("failed to install SIGHUP handler; reload only via admin API");
+        return;
+    };
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            match reload.reload(&config) {
+                Ok(()) => info!("config reloaded on SIGHUP"),
+                Err(e) => warn!(error = %e, "config reload failed; keeping previous config"),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_sighup_reload(_reload: cmux_proxy::ReloadHandle, _config: cmux_proxy::ConfigHandle) {}
+
+fn secs_to_duration(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
 fn dedupe_wildcard_v4(listens: Vec<SocketAddr>) -> Vec<SocketAddr> {
     let mut result = Vec::new();
     for addr in listens.into_iter() {
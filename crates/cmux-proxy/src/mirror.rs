@@ -0,0 +1,193 @@
+//! Traffic mirroring: duplicate proxied HTTP requests to a second upstream.
+//!
+//! Mirrored requests are fire-and-forget; their responses are discarded and failures are only
+//! logged, so a slow or broken mirror never affects the primary request. Upgrade and CONNECT
+//! tunnels are not mirrored.
+//!
+//! Rule syntax (one rule per string; the first matching rule wins):
+//!
+//! ```text
+//! [path=<prefix>] [workspace=<name>] <host>[:<port>]
+//! ```
+//!
+//! Without a port the mirror receives the request on the same port as the primary upstream.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::Body;
+use tokio::sync::mpsc;
+
+/// Request bodies larger than this are not mirrored; the mirror copy is aborted instead of
+/// buffering an unbounded amount while the primary upstream streams.
+pub(crate) const MIRROR_BODY_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MirrorRule {
+    /// Host (IP or DNS name) that receives the copy.
+    pub host: String,
+    /// Target port; the primary request's port when unset.
+    pub port: Option<u16>,
+    /// Only mirror when the request path starts with this prefix.
+    pub path_prefix: Option<String>,
+    /// Only mirror when the request targets this workspace.
+    pub workspace: Option<String>,
+}
+
+impl MirrorRule {
+    fn matches(&self, path: &str, workspace: Option<&str>) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ws) = &self.workspace {
+            if workspace != Some(ws.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The first rule matching `path` and `workspace`, if any.
+pub(crate) fn find_mirror<'a>(
+    rules: &'a [MirrorRule],
+    path: &str,
+    workspace: Option<&str>,
+) -> Option<&'a MirrorRule> {
+    rules.iter().find(|r| r.matches(path, workspace))
+}
+
+/// Split `body` into the primary body and a copy for the mirror. The primary side sees the
+/// original stream, trailers included, and is never held back by the mirror; the copy is
+/// aborted once more than `limit` bytes have been seen or the original stream fails.
+pub(crate) fn tee_body(mut body: Body, limit: u64) -> (Body, Body) {
+    let (mut tx, primary) = Body::channel();
+    let (mirror_tx, mut mirror_rx) = mpsc::unbounded_channel::<io::Result<Bytes>>();
+    let mirror = Body::wrap_stream(futures_util::stream::poll_fn(move |cx| {
+        mirror_rx.poll_recv(cx)
+    }));
+    tokio::spawn(async move {
+        let mut mirror_tx = Some(mirror_tx);
+        let mut total: u64 = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(_) => {
+                    if let Some(m) = mirror_tx.take() {
+                        let _ = m.send(Err(io::Error::other("request body failed")));
+                    }
+                    tx.abort();
+                    return;
+                }
+            };
+            total += chunk.len() as u64;
+            if let Some(m) = &mirror_tx {
+                let sent = if total > limit {
+                    let _ = m.send(Err(io::Error::other("request body too large to mirror")));
+                    false
+                } else {
+                    m.send(Ok(chunk.clone())).is_ok()
+                };
+                if !sent {
+                    mirror_tx = None;
+                }
+            }
+            if tx.send_data(chunk).await.is_err() {
+                // Don't let the mirror treat a truncated body as complete.
+                if let Some(m) = mirror_tx.take() {
+                    let _ = m.send(Err(io::Error::other("primary request aborted")));
+                }
+                return;
+            }
+        }
+        drop(mirror_tx);
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => tx.abort(),
+        }
+    });
+    (primary, mirror)
+}
+
+impl FromStr for MirrorRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s.trim();
+        let mut path_prefix = None;
+        let mut workspace = None;
+        loop {
+            if let Some(r) = rest.strip_prefix("path=") {
+                let (v, tail) = split_token(r);
+                path_prefix = Some(v.to_string());
+                rest = tail;
+            } else if let Some(r) = rest.strip_prefix("workspace=") {
+                let (v, tail) = split_token(r);
+                workspace = Some(v.to_string());
+                rest = tail;
+            } else {
+                break;
+            }
+        }
+
+        if rest.is_empty() || rest.contains(char::is_whitespace) {
+            return Err(format!(
+                "mirror rule needs a single <host>[:<port>] target ({})",
+                s
+            ));
+        }
+        let (host, port) = match rest.rsplit_once(':') {
+            // A bracketed IPv6 literal without a port, e.g. `[::1]`.
+            Some((_, p)) if p.ends_with(']') => (rest, None),
+            Some((h, p)) => {
+                let port = p
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|p| *p != 0)
+                    .ok_or_else(|| format!("invalid mirror port: {}", p))?;
+                (h, Some(port))
+            }
+            None => (rest, None),
+        };
+        if host.is_empty() {
+            return Err(format!("mirror rule is missing a host ({})", s));
+        }
+
+        Ok(MirrorRule {
+            host: host.to_string(),
+            port,
+            path_prefix,
+            workspace,
+        })
+    }
+}
+
+impl fmt::Display for MirrorRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(p) = &self.path_prefix {
+            write!(f, "path={} ", p)?;
+        }
+        if let Some(w) = &self.workspace {
+            write!(f, "workspace={} ", w)?;
+        }
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+fn split_token(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
@@ -0,0 +1,94 @@
+//! Live configuration that can be swapped without dropping connections.
+//!
+//! Listeners consult the current snapshot on every request, so a reload takes effect on the
+//! next request of an existing keep-alive connection; open WebSocket/CONNECT tunnels keep the
+//! config they started with. `listen` and `admin` are fixed at startup.
+
+use std::fmt;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::ProxyConfig;
+
+/// Shared, swappable [`ProxyConfig`]. Clones refer to the same config.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    inner: Arc<ArcSwap<ProxyConfig>>,
+}
+
+impl ConfigHandle {
+    pub fn new(cfg: ProxyConfig) -> Self {
+        Self {
+            inner: Arc::new(ArcSwap::from_pointee(cfg)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<ProxyConfig> {
+        self.inner.load_full()
+    }
+
+    /// Replace the reloadable parts of the config. Bound addresses and the admin listener
+    /// cannot change at runtime, so they are carried over from the current config.
+    pub fn store(&self, mut cfg: ProxyConfig) {
+        let current = self.inner.load();
+        cfg.listen = current.listen;
+        cfg.admin = current.admin.clone();
+        self.inner.store(Arc::new(cfg));
+    }
+
+    /// Edit a copy of the current config and store it if `f` succeeds. The swap is atomic: if
+    /// another update lands first, `f` runs again on the newer config, so it may run more than
+    /// once and must not depend on being called only once.
+    pub fn update<F>(&self, mut f: F) -> Result<(), String>
+    where
+        F: FnMut(&mut ProxyConfig) -> Result<(), String>,
+    {
+        let mut result = Ok(());
+        self.inner.rcu(|current| {
+            let mut cfg = ProxyConfig::clone(current);
+            result = f(&mut cfg);
+            match result {
+                Ok(()) => Arc::new(cfg),
+                Err(_) => Arc::clone(current),
+            }
+        });
+        result
+    }
+}
+
+impl fmt::Debug for ConfigHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ConfigHandle").field(&self.load()).finish()
+    }
+}
+
+type ReloadFn = dyn Fn(&ConfigHandle) -> Result<(), String> + Send + Sync;
+
+/// Re-reads whatever sources the binary built its config from (mapping files, rule files) and
+/// stores the result. Triggered by SIGHUP in the binary and by `POST /reload` on the admin API.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    reload: Arc<ReloadFn>,
+}
+
+impl ReloadHandle {
+    pub fn new<F>(reload: F) -> Self
+    where
+        F: Fn(&ConfigHandle) -> Result<(), String> + Send + Sync + 'static,
+    {
+        Self {
+            reload: Arc::new(reload),
+        }
+    }
+
+    pub fn reload(&self, config: &ConfigHandle) -> Result<(), String> {
+        (self.reload)(config)
+    }
+}
+
+impl fmt::Debug for ReloadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadHandle").finish_non_exhaustive()
+    }
+}
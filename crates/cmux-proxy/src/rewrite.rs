@@ -0,0 +1,204 @@
+//! Configurable header rewrite rules applied to proxied HTTP and upgrade requests/responses.
+//!
+//! Rule syntax (one rule per string):
+//!
+//! ```text
+//! [path=<prefix>] [workspace=<name>] <request|response>:<add|set|remove>:<Header>[=<value>]
+//! ```
+//!
+//! `remove` accepts a trailing `*` to drop every header with that prefix (`X-Cmux-*`).
+//! Everything after the first `=` of the header part is the value, so values may contain
+//! spaces, `;` and `=` (useful for CSP).
+
+use std::fmt;
+use std::str::FromStr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleDirection {
+    Request,
+    Response,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderAction {
+    /// Append a value, keeping existing ones.
+    Add(HeaderName, HeaderValue),
+    /// Replace all existing values.
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+    /// Remove every header whose (lowercase) name starts with the prefix.
+    RemovePrefix(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub direction: RuleDirection,
+    pub action: HeaderAction,
+    /// Only apply when the request path starts with this prefix.
+    pub path_prefix: Option<String>,
+    /// Only apply when the request targets this workspace.
+    pub workspace: Option<String>,
+}
+
+impl HeaderRule {
+    fn matches(&self, path: &str, workspace: Option<&str>) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ws) = &self.workspace {
+            if workspace != Some(ws.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        match &self.action {
+            HeaderAction::Add(name, value) => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderAction::Set(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderAction::Remove(name) => {
+                headers.remove(name);
+            }
+            HeaderAction::RemovePrefix(prefix) => {
+                let doomed: Vec<HeaderName> = headers
+                    .keys()
+                    .filter(|k| k.as_str().starts_with(prefix.as_str()))
+                    .cloned()
+                    .collect();
+                for name in doomed {
+                    headers.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+/// Apply every rule for `direction` whose conditions match, in order.
+pub fn apply_header_rules(
+    rules: &[HeaderRule],
+    direction: RuleDirection,
+    path: &str,
+    workspace: Option<&str>,
+    headers: &mut HeaderMap,
+) {
+    for rule in rules
+        .iter()
+        .filter(|r| r.direction == direction && r.matches(path, workspace))
+    {
+        rule.apply(headers);
+    }
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s.trim();
+        let mut path_prefix = None;
+        let mut workspace = None;
+        loop {
+            if let Some(r) = rest.strip_prefix("path=") {
+                let (v, tail) = split_token(r);
+                path_prefix = Some(v.to_string());
+                rest = tail;
+            } else if let Some(r) = rest.strip_prefix("workspace=") {
+                let (v, tail) = split_token(r);
+                workspace = Some(v.to_string());
+                rest = tail;
+            } else {
+                break;
+            }
+        }
+
+        let mut parts = rest.splitn(3, ':');
+        let direction = match parts
+            .next()
+            .map(|p| p.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("request") | Some("req") => RuleDirection::Request,
+            Some("response") | Some("resp") => RuleDirection::Response,
+            _ => {
+                return Err(format!(
+                    "rule must start with request: or response: ({})",
+                    s
+                ))
+            }
+        };
+        let op = parts.next().map(|p| p.trim().to_ascii_lowercase());
+        let header = parts
+            .next()
+            .ok_or_else(|| format!("missing header in rule ({})", s))?;
+        let (name, value) = match header.split_once('=') {
+            Some((n, v)) => (n.trim(), Some(v.trim())),
+            None => (header.trim(), None),
+        };
+
+        let action = match (op.as_deref(), value) {
+            (Some("add"), Some(v)) => HeaderAction::Add(parse_name(name)?, parse_value(v)?),
+            (Some("set"), Some(v)) => HeaderAction::Set(parse_name(name)?, parse_value(v)?),
+            (Some("add" | "set"), None) => {
+                return Err(format!("{} rule needs a value ({})", op.unwrap(), s))
+            }
+            (Some("remove"), None) => match name.strip_suffix('*') {
+                Some(prefix) => HeaderAction::RemovePrefix(prefix.to_ascii_lowercase()),
+                None => HeaderAction::Remove(parse_name(name)?),
+            },
+            (Some("remove"), Some(_)) => return Err(format!("remove rule takes no value ({})", s)),
+            _ => return Err(format!("action must be add, set or remove ({})", s)),
+        };
+
+        Ok(HeaderRule {
+            direction,
+            action,
+            path_prefix,
+            workspace,
+        })
+    }
+}
+
+impl fmt::Display for HeaderRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(p) = &self.path_prefix {
+            write!(f, "path={} ", p)?;
+        }
+        if let Some(w) = &self.workspace {
+            write!(f, "workspace={} ", w)?;
+        }
+        let dir = match self.direction {
+            RuleDirection::Request => "request",
+            RuleDirection::Response => "response",
+        };
+        match &self.action {
+            HeaderAction::Add(n, v) => write!(f, "{}:add:{}={}", dir, n, v.to_str().unwrap_or("")),
+            HeaderAction::Set(n, v) => write!(f, "{}:set:{}={}", dir, n, v.to_str().unwrap_or("")),
+            HeaderAction::Remove(n) => write!(f, "{}:remove:{}", dir, n),
+            HeaderAction::RemovePrefix(p) => write!(f, "{}:remove:{}*", dir, p),
+        }
+    }
+}
+
+fn split_token(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name: {}", name))
+}
+
+fn parse_value(value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(value).map_err(|_| format!("invalid header value: {}", value))
+}
@@ -0,0 +1,308 @@
+//! Upstream timeouts: connect, first byte, total response, and tunnel idle.
+//!
+//! Global values come from the config; timeout rules override them per route. Rule syntax
+//! (one rule per string; the first matching rule wins, unset keys fall back to the globals):
+//!
+//! ```text
+//! [path=<prefix>] [workspace=<name>] [port=<n>] [connect=<d>] [ttfb=<d>] [total=<d>] [idle=<d>]
+//! ```
+//!
+//! Durations take an `ms`, `s`, `m` or `h` suffix; a bare number is seconds. HTTP requests that
+//! miss their connect, first-byte or total deadline before the response head arrives get `504`;
+//! a body still streaming at the total deadline is cut off. `idle` closes upgrade and CONNECT
+//! tunnels with no traffic in either direction.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Uri};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::ProxyConfig;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establishing the TCP connection to the upstream.
+    pub connect: Option<Duration>,
+    /// From sending the request (connect included) until the response head arrives.
+    pub first_byte: Option<Duration>,
+    /// From receiving the request until the response body has been fully sent.
+    pub total: Option<Duration>,
+    /// Close upgraded and CONNECT tunnels idle in both directions for this long.
+    pub idle: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeoutRule {
+    /// Only apply when the request path starts with this prefix. CONNECT tunnels have no path
+    /// and never match a rule that sets one.
+    pub path_prefix: Option<String>,
+    /// Only apply when the request targets this workspace.
+    pub workspace: Option<String>,
+    /// Only apply when the request targets this upstream port.
+    pub port: Option<u16>,
+    pub timeouts: Timeouts,
+}
+
+impl TimeoutRule {
+    fn matches(&self, path: Option<&str>, workspace: Option<&str>, port: u16) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !path.is_some_and(|p| p.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+        if let Some(ws) = &self.workspace {
+            if workspace != Some(ws.as_str()) {
+                return false;
+            }
+        }
+        self.port.is_none_or(|p| p == port)
+    }
+}
+
+/// Effective timeouts for a request: the first matching rule layered over the globals.
+/// `idle` is only set by rules; callers fall back to the tunnel-kind specific default.
+pub(crate) fn for_route(
+    cfg: &ProxyConfig,
+    path: Option<&str>,
+    workspace: Option<&str>,
+    port: u16,
+) -> Timeouts {
+    let route = cfg
+        .timeout_rules
+        .iter()
+        .find(|r| r.matches(path, workspace, port))
+        .map(|r| r.timeouts)
+        .unwrap_or_default();
+    Timeouts {
+        connect: route.connect.or(Some(cfg.connect_timeout)),
+        first_byte: route.first_byte.or(cfg.first_byte_timeout),
+        total: route.total.or(cfg.response_timeout),
+        idle: route.idle,
+    }
+}
+
+tokio::task_local! {
+    /// Connect timeout for upstream connections opened while polling the current request.
+    static CONNECT_TIMEOUT: Duration;
+}
+
+/// Fallback for connections opened outside [`with_connect_timeout`] (e.g. mirrors).
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run `fut` (a client request) with `timeout` applied to any new upstream connection it opens.
+pub(crate) async fn with_connect_timeout<F: Future>(timeout: Duration, fut: F) -> F::Output {
+    CONNECT_TIMEOUT.scope(timeout, fut).await
+}
+
+/// The connect step took longer than its timeout.
+#[derive(Debug)]
+pub(crate) struct ConnectTimedOut(Duration);
+
+impl fmt::Display for ConnectTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connect timed out after {}", format_duration(self.0))
+    }
+}
+
+impl StdError for ConnectTimedOut {}
+
+/// If `err` was caused by a connect timeout, how long the connect was given.
+pub(crate) fn connect_timed_out(err: &hyper::Error) -> Option<Duration> {
+    let mut source = err.source();
+    while let Some(e) = source {
+        if let Some(t) = e.downcast_ref::<ConnectTimedOut>() {
+            return Some(t.0);
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// `HttpConnector` whose connect timeout is chosen per request instead of per client. The
+/// connector is called while the request future is polled, so the timeout set by
+/// [`with_connect_timeout`] for that request is in scope.
+#[derive(Clone)]
+pub(crate) struct TimeoutConnector {
+    inner: HttpConnector,
+}
+
+impl TimeoutConnector {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: HttpConnector::new(),
+        }
+    }
+}
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+impl Service<Uri> for TimeoutConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let timeout = CONNECT_TIMEOUT
+            .try_with(|t| *t)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, connecting).await {
+                Ok(res) => res.map_err(Into::into),
+                Err(_) => Err(ConnectTimedOut(timeout).into()),
+            }
+        })
+    }
+}
+
+/// Cut `body` off once `deadline` passes. Trailers are forwarded.
+pub(crate) fn deadline_body(mut body: Body, deadline: Instant) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let forward = async {
+            while let Some(chunk) = body.data().await {
+                let Ok(chunk) = chunk else {
+                    return false;
+                };
+                if tx.send_data(chunk).await.is_err() {
+                    return true;
+                }
+            }
+            match body.trailers().await {
+                Ok(Some(trailers)) => {
+                    let _ = tx.send_trailers(trailers).await;
+                    true
+                }
+                Ok(None) => true,
+                Err(_) => false,
+            }
+        };
+        match tokio::time::timeout_at(deadline, forward).await {
+            Ok(true) => {}
+            Ok(false) => tx.abort(),
+            Err(_) => {
+                tracing::warn!("upstream response exceeded its total timeout; aborting body");
+                tx.abort();
+            }
+        }
+    });
+    rx
+}
+
+pub(crate) fn format_duration(d: Duration) -> String {
+    let ms = d.as_millis();
+    if !ms.is_multiple_of(1000) {
+        format!("{}ms", ms)
+    } else if ms != 0 && ms.is_multiple_of(3_600_000) {
+        format!("{}h", ms / 3_600_000)
+    } else if ms != 0 && ms.is_multiple_of(60_000) {
+        format!("{}m", ms / 60_000)
+    } else {
+        format!("{}s", ms / 1000)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (digits, unit_ms) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 1)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1000)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60_000)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3_600_000)
+    } else {
+        (s, 1000)
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(unit_ms))
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("invalid duration: {}", s))
+}
+
+impl FromStr for TimeoutRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = TimeoutRule {
+            path_prefix: None,
+            workspace: None,
+            port: None,
+            timeouts: Timeouts::default(),
+        };
+        for token in s.split_whitespace() {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| format!("timeout rule tokens must be key=value ({})", s))?;
+            match key {
+                "path" => rule.path_prefix = Some(value.to_string()),
+                "workspace" => rule.workspace = Some(value.to_string()),
+                "port" => {
+                    rule.port = Some(
+                        value
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|p| *p != 0)
+                            .ok_or_else(|| format!("invalid timeout rule port: {}", value))?,
+                    )
+                }
+                "connect" => rule.timeouts.connect = Some(parse_duration(value)?),
+                "ttfb" | "first-byte" => rule.timeouts.first_byte = Some(parse_duration(value)?),
+                "total" => rule.timeouts.total = Some(parse_duration(value)?),
+                "idle" => rule.timeouts.idle = Some(parse_duration(value)?),
+                other => return Err(format!("unknown timeout rule key: {}", other)),
+            }
+        }
+        if rule.timeouts == Timeouts::default() {
+            return Err(format!(
+                "timeout rule sets no timeout (connect, ttfb, total or idle): {}",
+                s
+            ));
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for TimeoutRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(p) = &self.path_prefix {
+            parts.push(format!("path={}", p));
+        }
+        if let Some(w) = &self.workspace {
+            parts.push(format!("workspace={}", w));
+        }
+        if let Some(p) = self.port {
+            parts.push(format!("port={}", p));
+        }
+        let t = &self.timeouts;
+        for (key, value) in [
+            ("connect", t.connect),
+            ("ttfb", t.first_byte),
+            ("total", t.total),
+            ("idle", t.idle),
+        ] {
+            if let Some(d) = value {
+                parts.push(format!("{}={}", key, format_duration(d)));
+            }
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
@@ -0,0 +1,216 @@
+//! Optional TLS termination per listener.
+//!
+//! A listener with a certificate and key accepts TLS only; the proxied traffic behind it is
+//! unchanged (upstreams are still reached over plain HTTP). ALPN offers `h2` and `http/1.1`.
+//! Handshakes run concurrently with accepting, so a slow or stalled client cannot hold up the
+//! listener, and each one is bounded by [`HANDSHAKE_TIMEOUT`].
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, Stream};
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::ipfilter::FilteredIncoming;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM certificate chain and private key for a TLS listener.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    /// Read the certificate and key and build an acceptor. Call this at startup to report bad
+    /// paths or PEM before binding.
+    pub fn load(&self) -> Result<TlsAcceptor, String> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = read_key(&self.key_path)?;
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("invalid TLS certificate/key: {}", e))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to open {}: {}", path.display(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("failed to read certificates from {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, String> {
+    let items = rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|e| format!("failed to read private key from {}: {}", path.display(), e))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key found in {}", path.display()))
+}
+
+/// A connection accepted by a listener, plain or TLS.
+pub(crate) enum ProxyConn {
+    Plain(AddrStream),
+    Tls(Box<TlsStream<AddrStream>>),
+}
+
+impl ProxyConn {
+    pub(crate) fn remote_addr(&self) -> SocketAddr {
+        match self {
+            ProxyConn::Plain(s) => s.remote_addr(),
+            ProxyConn::Tls(s) => s.get_ref().0.remote_addr(),
+        }
+    }
+}
+
+impl AsyncRead for ProxyConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyConn::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ProxyConn::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ProxyConn::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ProxyConn::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyConn::Plain(s) => Pin::new(s).poll_flush(cx),
+            ProxyConn::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyConn::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ProxyConn::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Hands out accepted connections, terminating TLS first when the listener has an acceptor.
+pub(crate) struct ListenerIncoming {
+    inner: FilteredIncoming,
+    acceptor: Option<TlsAcceptor>,
+    handshakes: FuturesUnordered<BoxFuture<'static, Option<ProxyConn>>>,
+}
+
+impl ListenerIncoming {
+    pub(crate) fn new(inner: FilteredIncoming, acceptor: Option<TlsAcceptor>) -> Self {
+        Self {
+            inner,
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for ListenerIncoming {
+    type Conn = ProxyConn;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        let Some(acceptor) = &this.acceptor else {
+            return Pin::new(&mut this.inner)
+                .poll_accept(cx)
+                .map(|conn| conn.map(|c| c.map(ProxyConn::Plain)));
+        };
+
+        // Start handshakes for everything the listener has ready.
+        loop {
+            match Pin::new(&mut this.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    let acceptor = acceptor.clone();
+                    this.handshakes.push(Box::pin(async move {
+                        let client = conn.remote_addr();
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                            Ok(Ok(tls)) => Some(ProxyConn::Tls(Box::new(tls))),
+                            Ok(Err(e)) => {
+                                debug!(%client, error = %e, "TLS handshake failed");
+                                None
+                            }
+                            Err(_) => {
+                                debug!(%client, "TLS handshake timed out");
+                                None
+                            }
+                        }
+                    }));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    if this.handshakes.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        // Hand out the first connection whose handshake completed.
+        while let Poll::Ready(Some(done)) = Pin::new(&mut this.handshakes).poll_next(cx) {
+            if let Some(conn) = done {
+                return Poll::Ready(Some(Ok(conn)));
+            }
+        }
+        Poll::Pending
+    }
+}
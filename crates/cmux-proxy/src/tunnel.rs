@@ -0,0 +1,212 @@
+//! Bookkeeping for long-lived upgrade/CONNECT tunnels so they can be listed by the admin API.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TunnelKind {
+    WebSocket,
+    Upgrade,
+    Connect,
+}
+
+impl TunnelKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            TunnelKind::WebSocket => "websocket",
+            TunnelKind::Upgrade => "upgrade",
+            TunnelKind::Connect => "connect",
+        }
+    }
+}
+
+/// Live view of one tunnel. Byte counters are updated as data flows.
+#[derive(Debug)]
+pub(crate) struct TunnelStats {
+    pub(crate) id: u64,
+    pub(crate) kind: TunnelKind,
+    pub(crate) client: SocketAddr,
+    pub(crate) target: String,
+    pub(crate) started: Instant,
+    pub(crate) to_upstream: AtomicU64,
+    pub(crate) to_client: AtomicU64,
+    /// Set when the tunnel parses WebSocket frames (frame-debug mode); the frame counters
+    /// below are only meaningful then.
+    pub(crate) frames_tracked: AtomicBool,
+    pub(crate) frames_to_upstream: AtomicU64,
+    pub(crate) frames_to_client: AtomicU64,
+    /// Milliseconds after `started` at which the last frame was seen.
+    pub(crate) last_frame_ms: AtomicU64,
+}
+
+impl TunnelStats {
+    pub(crate) fn record_frame(&self, to_upstream: bool) {
+        let counter = if to_upstream {
+            &self.frames_to_upstream
+        } else {
+            &self.frames_to_client
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.last_frame_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct TunnelRegistry {
+    next_id: Arc<AtomicU64>,
+    open: Arc<Mutex<HashMap<u64, Arc<TunnelStats>>>>,
+}
+
+impl TunnelRegistry {
+    /// Records a new tunnel. It stays listed until the returned guard is dropped, which also
+    /// covers tunnels aborted at shutdown.
+    pub(crate) fn register(
+        &self,
+        kind: TunnelKind,
+        client: SocketAddr,
+        target: String,
+    ) -> (Arc<TunnelStats>, TunnelGuard) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let stats = Arc::new(TunnelStats {
+            id,
+            kind,
+            client,
+            target,
+            started: Instant::now(),
+            to_upstream: AtomicU64::new(0),
+            to_client: AtomicU64::new(0),
+            frames_tracked: AtomicBool::new(false),
+            frames_to_upstream: AtomicU64::new(0),
+            frames_to_client: AtomicU64::new(0),
+            last_frame_ms: AtomicU64::new(0),
+        });
+        self.open.lock().unwrap().insert(id, stats.clone());
+        let guard = TunnelGuard {
+            id,
+            registry: self.clone(),
+        };
+        (stats, guard)
+    }
+
+    /// Open tunnels, oldest first.
+    pub(crate) fn snapshot(&self) -> Vec<Arc<TunnelStats>> {
+        let mut open: Vec<_> = self.open.lock().unwrap().values().cloned().collect();
+        open.sort_by_key(|t| t.id);
+        open
+    }
+}
+
+pub(crate) struct TunnelGuard {
+    id: u64,
+    registry: TunnelRegistry,
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.registry.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Wraps the client side of a tunnel: bytes read from it are headed upstream, bytes written to
+/// it are headed to the client.
+pub(crate) struct Counted<S> {
+    inner: S,
+    stats: Arc<TunnelStats>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, stats: Arc<TunnelStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.stats
+            .to_upstream
+            .fetch_add(n as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.stats.to_client.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy between both ends of a tunnel until either side closes or, with `idle` set, no bytes
+/// have moved in either direction for that long.
+pub(crate) async fn copy_until_idle<C, U>(
+    client: &mut Counted<C>,
+    upstream: &mut U,
+    idle: Option<Duration>,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(idle) = idle else {
+        return copy_bidirectional(client, upstream).await.map(|_| ());
+    };
+    let stats = client.stats.clone();
+    tokio::select! {
+        res = copy_bidirectional(client, upstream) => res.map(|_| ()),
+        _ = idle_for(&stats, idle) => {
+            tracing::info!(id = stats.id, target = %stats.target, "closing idle tunnel");
+            Ok(())
+        }
+    }
+}
+
+/// Resolves once the tunnel's byte counters have not moved for `idle`.
+async fn idle_for(stats: &TunnelStats, idle: Duration) {
+    let moved =
+        || stats.to_upstream.load(Ordering::Relaxed) + stats.to_client.load(Ordering::Relaxed);
+    // Check a few times per period so the tunnel closes soon after it goes quiet.
+    let tick = (idle / 4).max(Duration::from_millis(10));
+    let mut last = moved();
+    let mut quiet_since = tokio::time::Instant::now();
+    loop {
+        tokio::time::sleep(tick).await;
+        let now = moved();
+        if now != last {
+            last = now;
+            quiet_since = tokio::time::Instant::now();
+        } else if quiet_since.elapsed() >= idle {
+            return;
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Per-upstream connection caps.
+//!
+//! Each workspace `host:port` gets its own semaphore. Requests over the cap queue for a slot
+//! until `upstream_queue_timeout`, then get `503`, so a thundering herd against a cold dev
+//! server waits its turn instead of opening a socket per request. HTTP requests hold their
+//! slot until the response body finishes; upgrade and CONNECT tunnels only while the upstream
+//! connection is being established, so long-lived WebSockets never starve page loads.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::response_with;
+
+#[derive(Default)]
+pub(crate) struct UpstreamLimiter {
+    /// Semaphore per `host:port`, tagged with the cap it was created for so a reload that
+    /// changes the cap starts fresh.
+    slots: Mutex<HashMap<String, (usize, Arc<Semaphore>)>>,
+}
+
+impl UpstreamLimiter {
+    /// Wait up to `wait` for a slot to `host:port`. `Ok(None)` means no cap is configured.
+    pub(crate) async fn acquire(
+        &self,
+        host: &str,
+        port: u16,
+        cap: Option<usize>,
+        wait: Duration,
+    ) -> Result<Option<OwnedSemaphorePermit>, Response<Body>> {
+        let Some(cap) = cap else {
+            return Ok(None);
+        };
+        let target = format!("{}:{}", host, port);
+        let semaphore = {
+            let mut slots = self.slots.lock().unwrap();
+            // Forget upstreams nobody holds or waits on (permits keep the semaphore alive), so
+            // the map doesn't grow with every target ever seen.
+            slots.retain(|_, (_, s)| Arc::strong_count(s) > 1);
+            match slots.get(&target) {
+                Some((c, s)) if *c == cap => s.clone(),
+                _ => {
+                    let s = Arc::new(Semaphore::new(cap));
+                    slots.insert(target.clone(), (cap, s.clone()));
+                    s
+                }
+            }
+        };
+        match tokio::time::timeout(wait, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                warn!(upstream = %target, cap, "timed out waiting for an upstream connection slot");
+                let mut resp = response_with(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "upstream {} is at its limit of {} concurrent connections",
+                        target, cap
+                    ),
+                );
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                Err(resp)
+            }
+        }
+    }
+}
+
+/// Keep `permit` until `body` has been fully sent (or dropped). Trailers are forwarded.
+pub(crate) fn hold_until_done(mut body: Body, permit: OwnedSemaphorePermit) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let _permit = permit;
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                tx.abort();
+                return;
+            };
+            if tx.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => tx.abort(),
+        }
+    });
+    rx
+}
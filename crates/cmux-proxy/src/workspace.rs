@@ -0,0 +1,227 @@
+//! Workspace name -> loopback IP mapping.
+//!
+//! By default `workspace-N` maps into `127.18.0.0/16` as `127.18.(N>>8).(N&255)` and names
+//! without trailing digits are hashed into the same space. The base network is configurable
+//! (`--workspace-cidr`) and individual names can be pinned with a mapping file of
+//! `name: ip` lines (a flat YAML map):
+//!
+//! ```text
+//! # workspace -> IP
+//! workspace-1: 127.18.0.1
+//! frontend: 127.18.200.7
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkspaceNetwork {
+    base: Ipv4Addr,
+    prefix_len: u8,
+    /// Explicit name -> IP pins, consulted before the computed scheme.
+    overrides: HashMap<String, Ipv4Addr>,
+}
+
+impl Default for WorkspaceNetwork {
+    fn default() -> Self {
+        Self {
+            base: Ipv4Addr::new(127, 18, 0, 0),
+            prefix_len: 16,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl WorkspaceNetwork {
+    /// `base` must be the network address of the block (no host bits set).
+    pub fn new(base: Ipv4Addr, prefix_len: u8) -> Result<Self, String> {
+        if !(8..=30).contains(&prefix_len) {
+            return Err(format!(
+                "workspace CIDR prefix must be between /8 and /30, got /{}",
+                prefix_len
+            ));
+        }
+        let net = Self {
+            base,
+            prefix_len,
+            overrides: HashMap::new(),
+        };
+        if u32::from(base) & net.host_mask() != 0 {
+            return Err(format!(
+                "{}/{} has host bits set; use the network address",
+                base, prefix_len
+            ));
+        }
+        Ok(net)
+    }
+
+    pub fn base(&self) -> Ipv4Addr {
+        self.base
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn overrides(&self) -> &HashMap<String, Ipv4Addr> {
+        &self.overrides
+    }
+
+    /// Pin `name` to `ip`, bypassing the computed scheme.
+    pub fn with_override(mut self, name: impl Into<String>, ip: Ipv4Addr) -> Self {
+        self.overrides.insert(name.into(), ip);
+        self
+    }
+
+    /// Parse a mapping file body (`name: ip` per line, `#` comments) and add its entries as
+    /// overrides. Entries must not repeat names or IPs.
+    pub fn with_mapping_str(mut self, text: &str) -> Result<Self, String> {
+        for (lineno, raw) in text.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            if line.is_empty() || line == "---" {
+                continue;
+            }
+            let (name, ip) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected `name: ip`", lineno + 1))?;
+            let name = unquote(name.trim());
+            let ip_str = unquote(ip.trim());
+            if name.is_empty() {
+                return Err(format!("line {}: empty workspace name", lineno + 1));
+            }
+            let ip: Ipv4Addr = ip_str
+                .parse()
+                .map_err(|_| format!("line {}: invalid IPv4 address: {}", lineno + 1, ip_str))?;
+            if self.overrides.insert(name.to_string(), ip).is_some() {
+                return Err(format!("line {}: duplicate workspace {}", lineno + 1, name));
+            }
+        }
+        let mut seen: HashMap<Ipv4Addr, &str> = HashMap::new();
+        for (name, ip) in &self.overrides {
+            if let Some(other) = seen.insert(*ip, name) {
+                return Err(format!("{} and {} both map to {}", other, name, ip));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_mapping_file(self, path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        self.with_mapping_str(&text)
+    }
+
+    fn host_mask(&self) -> u32 {
+        u32::MAX >> self.prefix_len
+    }
+
+    /// Resolve a workspace name. If input contains path separators, the last component is
+    /// used. Overrides win; otherwise trailing digits (or a stable hash of the name) are
+    /// folded into the host bits of the network. Returns None if the digits overflow a u32.
+    pub fn ip_for(&self, name: &str) -> Option<Ipv4Addr> {
+        let base = name.rsplit('/').next().unwrap_or(name);
+        if let Some(ip) = self
+            .overrides
+            .get(base)
+            .or_else(|| self.overrides.get(name))
+        {
+            return Some(*ip);
+        }
+        let index = workspace_index(base)?;
+        Some(Ipv4Addr::from(
+            u32::from(self.base) | (index & self.host_mask()),
+        ))
+    }
+
+    /// True if `ip` lies inside the workspace block or is one of the pinned addresses.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & !self.host_mask() == u32::from(self.base)
+            || self.overrides.values().any(|pinned| *pinned == ip)
+    }
+
+    /// True if `name` is pinned explicitly (via an override or the mapping file), as opposed
+    /// to resolving through the computed scheme that accepts any name.
+    pub fn is_registered(&self, name: &str) -> bool {
+        let base = name.rsplit('/').next().unwrap_or(name);
+        self.overrides.contains_key(base) || self.overrides.contains_key(name)
+    }
+
+    /// Compute the mapping for `names` and fail if two of them land on the same IP, so callers
+    /// can reject a workspace set before traffic is routed to the wrong place.
+    pub fn validate<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<(String, Ipv4Addr)>, String> {
+        let mut by_ip: HashMap<Ipv4Addr, String> = HashMap::new();
+        let mut out = Vec::new();
+        for name in names {
+            let ip = self
+                .ip_for(name)
+                .ok_or_else(|| format!("invalid workspace name: {}", name))?;
+            if let Some(other) = by_ip.insert(ip, name.to_string()) {
+                if other != name {
+                    return Err(format!("{} and {} both map to {}", other, name, ip));
+                }
+            }
+            out.push((name.to_string(), ip));
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for WorkspaceNetwork {
+    type Err = String;
+
+    /// Parse `a.b.c.d/len`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, len) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("expected CIDR like 127.18.0.0/16, got {}", s))?;
+        let base: Ipv4Addr = ip
+            .parse()
+            .map_err(|_| format!("invalid IPv4 address in CIDR: {}", ip))?;
+        let prefix_len: u8 = len
+            .parse()
+            .map_err(|_| format!("invalid prefix length in CIDR: {}", len))?;
+        Self::new(base, prefix_len)
+    }
+}
+
+impl fmt::Display for WorkspaceNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.prefix_len)
+    }
+}
+
+/// Trailing digits of the name, or a stable 32-bit FNV-1a hash of the lowercase name folded
+/// to 16 bits when there are none.
+fn workspace_index(name: &str) -> Option<u32> {
+    let digits: String = name
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .chars()
+        .rev()
+        .collect();
+    if !digits.is_empty() {
+        return digits.parse().ok();
+    }
+    let mut h: u32 = 0x811C9DC5;
+    for b in name.to_ascii_lowercase().as_bytes() {
+        h ^= *b as u32;
+        h = h.wrapping_mul(0x01000193);
+    }
+    Some(h & 0xFFFF)
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')))
+        .unwrap_or(s)
+}
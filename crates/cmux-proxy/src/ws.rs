@@ -0,0 +1,253 @@
+//! WebSocket-aware tunnelling used for upgrade requests when keepalive, idle detection or frame
+//! debugging is enabled. Bytes are forwarded unchanged; frames are only parsed far enough to
+//! know where their boundaries are, so that Ping frames can be injected between them and, in
+//! frame-debug mode, each frame's opcode and size can be logged and counted.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{interval_at, sleep_until, Instant};
+
+use crate::tunnel::TunnelStats;
+
+/// `tracing` target for per-frame logs in frame-debug mode.
+const WS_FRAMES: &str = "cmux_proxy::ws_frames";
+
+/// Unmasked Ping with an empty payload (server-to-client frames are never masked).
+const PING_FRAME: [u8; 2] = [0x89, 0x00];
+
+/// How long the open direction keeps relaying after the other side half-closes.
+const HALF_CLOSE_GRACE: Duration = Duration::from_secs(30);
+
+/// Tracks frame boundaries in a WebSocket byte stream.
+#[derive(Default, Debug)]
+pub(crate) struct FrameTracker {
+    header: Vec<u8>,
+    remaining: u64,
+}
+
+/// A parsed WebSocket frame header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    pub(crate) fin: bool,
+    pub(crate) opcode: u8,
+    pub(crate) masked: bool,
+    pub(crate) payload_len: u64,
+}
+
+impl FrameHeader {
+    pub(crate) fn opcode_name(&self) -> &'static str {
+        match self.opcode {
+            0x0 => "continuation",
+            0x1 => "text",
+            0x2 => "binary",
+            0x8 => "close",
+            0x9 => "ping",
+            0xA => "pong",
+            _ => "reserved",
+        }
+    }
+}
+
+impl FrameTracker {
+    pub(crate) fn feed(&mut self, buf: &[u8]) {
+        self.feed_with(buf, |_| {});
+    }
+
+    /// Like [`feed`](Self::feed), calling `on_frame` for every frame header completed in `buf`.
+    pub(crate) fn feed_with(&mut self, mut buf: &[u8], mut on_frame: impl FnMut(FrameHeader)) {
+        while !buf.is_empty() {
+            if self.remaining > 0 {
+                let n = (self.remaining.min(buf.len() as u64)) as usize;
+                self.remaining -= n as u64;
+                buf = &buf[n..];
+                continue;
+            }
+            self.header.push(buf[0]);
+            buf = &buf[1..];
+            if let Some(frame) = parse_frame_header(&self.header) {
+                self.remaining = frame.payload_len;
+                self.header.clear();
+                on_frame(frame);
+            }
+        }
+    }
+
+    /// True when the stream is between frames, i.e. it is safe to inject a control frame.
+    pub(crate) fn at_boundary(&self) -> bool {
+        self.remaining == 0 && self.header.is_empty()
+    }
+}
+
+/// Returns the header once `h` holds a complete one.
+fn parse_frame_header(h: &[u8]) -> Option<FrameHeader> {
+    if h.len() < 2 {
+        return None;
+    }
+    let masked = h[1] & 0x80 != 0;
+    let len7 = h[1] & 0x7f;
+    let ext = match len7 {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let header_len = 2 + ext + if masked { 4 } else { 0 };
+    if h.len() < header_len {
+        return None;
+    }
+    let payload_len = match len7 {
+        126 => u16::from_be_bytes([h[2], h[3]]) as u64,
+        127 => u64::from_be_bytes(h[2..10].try_into().expect("8 length bytes")),
+        n => n as u64,
+    };
+    Some(FrameHeader {
+        fin: h[0] & 0x80 != 0,
+        opcode: h[0] & 0x0f,
+        masked,
+        payload_len,
+    })
+}
+
+/// Counts and logs frames seen in one direction of a tunnel (frame-debug mode).
+struct FrameLog {
+    stats: Arc<TunnelStats>,
+    tracker: FrameTracker,
+    to_upstream: bool,
+}
+
+impl FrameLog {
+    fn new(stats: Arc<TunnelStats>, to_upstream: bool) -> Self {
+        stats.frames_tracked.store(true, Ordering::Relaxed);
+        Self {
+            stats,
+            tracker: FrameTracker::default(),
+            to_upstream,
+        }
+    }
+
+    fn feed(&mut self, buf: &[u8]) {
+        let (stats, to_upstream) = (&self.stats, self.to_upstream);
+        self.tracker.feed_with(buf, |frame| {
+            stats.record_frame(to_upstream);
+            tracing::debug!(
+                target: WS_FRAMES,
+                tunnel = stats.id,
+                direction = if to_upstream { "to_upstream" } else { "to_client" },
+                opcode = frame.opcode_name(),
+                fin = frame.fin,
+                masked = frame.masked,
+                len = frame.payload_len,
+                "ws frame"
+            );
+        });
+    }
+}
+
+/// Tunnel bytes between a client and an upstream WebSocket connection.
+///
+/// When `ping_every` is set, a Ping is written to the client at that interval (at the next frame
+/// boundary). When `idle_timeout` is set, the tunnel is closed once no bytes have moved in either
+/// direction for that long. With `frame_log` set, every frame in both directions is counted on
+/// those stats and logged to the `cmux_proxy::ws_frames` target. When one side reaches EOF only
+/// that direction is shut down; the other keeps relaying for up to [`HALF_CLOSE_GRACE`]. Returns
+/// the bytes copied (client->upstream, upstream->client).
+pub(crate) async fn tunnel_with_keepalive<C, U>(
+    client: C,
+    upstream: U,
+    ping_every: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    frame_log: Option<Arc<TunnelStats>>,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_rd, mut client_wr) = tokio::io::split(client);
+    let (mut upstream_rd, mut upstream_wr) = tokio::io::split(upstream);
+
+    let mut tracker = FrameTracker::default();
+    let mut client_frames = frame_log.clone().map(|s| FrameLog::new(s, true));
+    let mut upstream_frames = frame_log.map(|s| FrameLog::new(s, false));
+    let mut client_buf = vec![0u8; 16 * 1024];
+    let mut upstream_buf = vec![0u8; 16 * 1024];
+    let (mut to_upstream, mut to_client) = (0u64, 0u64);
+
+    // A far-future period stands in for "disabled" so the select below stays uniform.
+    let far = Duration::from_secs(60 * 60 * 24 * 365);
+    let ping_period = ping_every.unwrap_or(far);
+    let mut ping = interval_at(Instant::now() + ping_period, ping_period);
+    let mut ping_pending = false;
+    let mut last_activity = Instant::now();
+    let (mut client_open, mut upstream_open) = (true, true);
+    let mut half_closed_at = None;
+
+    while client_open || upstream_open {
+        let idle_deadline = last_activity + idle_timeout.unwrap_or(far);
+        let grace_deadline = half_closed_at.unwrap_or(last_activity) + HALF_CLOSE_GRACE;
+        tokio::select! {
+            n = client_rd.read(&mut client_buf), if client_open => {
+                let n = n?;
+                if n == 0 {
+                    client_open = false;
+                    half_closed_at.get_or_insert_with(Instant::now);
+                    let _ = upstream_wr.shutdown().await;
+                    continue;
+                }
+                if let Some(log) = client_frames.as_mut() {
+                    log.feed(&client_buf[..n]);
+                }
+                upstream_wr.write_all(&client_buf[..n]).await?;
+                to_upstream += n as u64;
+                last_activity = Instant::now();
+            }
+            n = upstream_rd.read(&mut upstream_buf), if upstream_open => {
+                let n = n?;
+                if n == 0 {
+                    upstream_open = false;
+                    half_closed_at.get_or_insert_with(Instant::now);
+                    let _ = client_wr.shutdown().await;
+                    continue;
+                }
+                tracker.feed(&upstream_buf[..n]);
+                if let Some(log) = upstream_frames.as_mut() {
+                    log.feed(&upstream_buf[..n]);
+                }
+                client_wr.write_all(&upstream_buf[..n]).await?;
+                to_client += n as u64;
+                last_activity = Instant::now();
+            }
+            _ = ping.tick(), if ping_every.is_some() && upstream_open => {
+                ping_pending = true;
+            }
+            _ = sleep_until(idle_deadline), if idle_timeout.is_some() => {
+                tracing::info!(to_upstream, to_client, "closing idle websocket tunnel");
+                break;
+            }
+            _ = sleep_until(grace_deadline), if half_closed_at.is_some() => {
+                tracing::info!(to_upstream, to_client, "closing half-closed websocket tunnel");
+                break;
+            }
+        }
+
+        if ping_pending && upstream_open && tracker.at_boundary() {
+            client_wr.write_all(&PING_FRAME).await?;
+            ping_pending = false;
+        }
+    }
+
+    let _ = client_wr.shutdown().await;
+    let _ = upstream_wr.shutdown().await;
+    Ok((to_upstream, to_client))
+}
+
+/// Returns true if the request asks for a WebSocket upgrade specifically.
+pub(crate) fn is_websocket_upgrade(headers: &hyper::HeaderMap) -> bool {
+    headers
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
@@ -14,6 +14,7 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::oneshot;
 use tokio::time::timeout;
 
+#[allow(clippy::single_match)]
 async fn start_upstream_real_ws_echo() -> (SocketAddr, tokio::task::JoinHandle<()>) {
     use tokio_tungstenite::accept_async;
 
@@ -56,6 +57,7 @@ async fn start_upstream_real_ws_echo() -> (SocketAddr, tokio::task::JoinHandle<(
     (local, handle)
 }
 
+#[allow(clippy::single_match)]
 async fn start_upstream_real_ws_echo_multi() -> (SocketAddr, tokio::task::JoinHandle<()>) {
     use tokio_tungstenite::accept_async;
 
@@ -114,6 +116,7 @@ async fn start_upstream_http() -> SocketAddr {
     local
 }
 
+#[allow(clippy::single_match)]
 async fn start_upstream_ws_like_upgrade_echo() -> SocketAddr {
     use hyper::header::{CONNECTION, UPGRADE};
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -212,6 +215,7 @@ async fn start_proxy(
         listen,
         upstream_host: upstream_host.to_string(),
         allow_default_upstream,
+        ..ProxyConfig::default()
     };
     let (tx, rx) = oneshot::channel::<()>();
     let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move {
@@ -516,7 +520,6 @@ async fn test_concurrent_websocket_connections() {
     let n = 16usize;
     let mut tasks = Vec::new();
     for i in 0..n {
-        let proxy_addr = proxy_addr.clone();
         let ws_port = ws_addr.port();
         tasks.push(tokio::spawn(async move {
             let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
@@ -556,3 +559,1898 @@ async fn test_concurrent_websocket_connections() {
     let _ = shutdown.send(());
     let _ = handle.await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_rejects_requests_targeting_proxy_itself() {
+    let (proxy_addr, shutdown, handle) = start_proxy(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        "127.0.0.1",
+        false,
+    )
+    .await;
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let url = format!("http://{}:{}/loop", proxy_addr.ip(), proxy_addr.port());
+    let req = Request::builder()
+        .method("GET")
+        .uri(url)
+        .header("X-Cmux-Port-Internal", proxy_addr.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = timeout(Duration::from_secs(5), client.request(req))
+        .await
+        .expect("resp timeout")
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::LOOP_DETECTED);
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_is_self_target_matches_wildcard_and_exact_binds() {
+    let wildcard = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 39379));
+    let exact = SocketAddr::from((Ipv4Addr::LOCALHOST, 4000));
+    let bound = [wildcard, exact];
+
+    assert!(cmux_proxy::is_self_target(
+        IpAddr::V4(Ipv4Addr::new(127, 18, 0, 1)),
+        39379,
+        &bound,
+        &[]
+    ));
+    assert!(cmux_proxy::is_self_target(
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        4000,
+        &bound,
+        &[]
+    ));
+    assert!(!cmux_proxy::is_self_target(
+        IpAddr::V4(Ipv4Addr::new(127, 18, 0, 1)),
+        4000,
+        &bound,
+        &[]
+    ));
+    assert!(!cmux_proxy::is_self_target(
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        3000,
+        &bound,
+        &[]
+    ));
+
+    // A wildcard listener also answers on the machine's interface addresses.
+    let lan = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    assert!(cmux_proxy::is_self_target(lan, 39379, &bound, &[lan]));
+    assert!(!cmux_proxy::is_self_target(lan, 39379, &bound, &[]));
+    assert!(!cmux_proxy::is_self_target(lan, 4000, &bound, &[lan]));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_rejects_wildcard_listener_targeting_its_interface_address() {
+    let Some(local_ip) = if_addrs::get_if_addrs()
+        .unwrap()
+        .into_iter()
+        .map(|iface| iface.ip())
+        .find(|ip| ip.is_ipv4() && !ip.is_loopback())
+    else {
+        eprintln!("no non-loopback IPv4 interface; skipping");
+        return;
+    };
+
+    let (proxy_addr, shutdown, handle) = start_proxy(
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        &local_ip.to_string(),
+        true,
+    )
+    .await;
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let url = format!("http://127.0.0.1:{}/loop", proxy_addr.port());
+    let req = Request::builder()
+        .method("GET")
+        .uri(url)
+        .header("X-Cmux-Port-Internal", proxy_addr.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = timeout(Duration::from_secs(5), client.request(req))
+        .await
+        .expect("resp timeout")
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::LOOP_DETECTED);
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+async fn start_proxy_with_config(
+    cfg: ProxyConfig,
+) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+    let (tx, rx) = oneshot::channel::<()>();
+    let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move {
+        let _ = rx.await;
+    });
+    (bound, tx, handle)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_websocket_keepalive_injects_pings() {
+    use tokio_tungstenite::connect_async;
+    use tungstenite::client::IntoClientRequest;
+
+    let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        ws_keepalive: Some(Duration::from_millis(100)),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
+    let mut req = url.into_client_request().unwrap();
+    req.headers_mut().insert(
+        "X-Cmux-Port-Internal",
+        ws_addr.port().to_string().parse().unwrap(),
+    );
+    let (mut ws, _resp) = timeout(Duration::from_secs(5), connect_async(req))
+        .await
+        .expect("ws connect timeout")
+        .expect("ws connect failed");
+
+    // Traffic still flows normally alongside the injected pings
+    ws.send(tungstenite::Message::Text("hello-ws".into()))
+        .await
+        .unwrap();
+    let mut saw_ping = false;
+    let mut saw_echo = false;
+    while !(saw_ping && saw_echo) {
+        let msg = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("ws recv timeout")
+            .unwrap()
+            .unwrap();
+        match msg {
+            tungstenite::Message::Ping(_) => saw_ping = true,
+            tungstenite::Message::Text(t) => {
+                assert_eq!(t, "hello-ws");
+                saw_echo = true;
+            }
+            _ => {}
+        }
+    }
+
+    let _ = ws.close(None).await;
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_websocket_idle_timeout_closes_tunnel() {
+    use tokio_tungstenite::connect_async;
+    use tungstenite::client::IntoClientRequest;
+
+    let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        ws_idle_timeout: Some(Duration::from_millis(200)),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
+    let mut req = url.into_client_request().unwrap();
+    req.headers_mut().insert(
+        "X-Cmux-Port-Internal",
+        ws_addr.port().to_string().parse().unwrap(),
+    );
+    let (mut ws, _resp) = timeout(Duration::from_secs(5), connect_async(req))
+        .await
+        .expect("ws connect timeout")
+        .expect("ws connect failed");
+
+    // Stay silent; the proxy should tear the tunnel down
+    let next = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("tunnel was not closed");
+    assert!(
+        matches!(next, None | Some(Err(_))),
+        "unexpected: {:?}",
+        next
+    );
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_websocket_tunnel_relays_reply_after_client_half_close() {
+    // Upstream accepts the upgrade, reads until the client stops sending, then replies.
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .unwrap();
+    let upstream_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut sock, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            sock.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        sock.write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut got = Vec::new();
+        sock.read_to_end(&mut got).await.unwrap();
+        sock.write_all(&[b"got:".as_slice(), &got].concat())
+            .await
+            .unwrap();
+    });
+
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        ws_idle_timeout: Some(Duration::from_secs(5)),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let req = format!(
+        "GET /ws HTTP/1.1\r\nHost: foo\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nX-Cmux-Port-Internal: {}\r\n\r\n",
+        upstream_addr.port()
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        timeout(Duration::from_secs(5), stream.read_exact(&mut byte))
+            .await
+            .expect("upgrade response timeout")
+            .unwrap();
+        head.push(byte[0]);
+    }
+    assert!(
+        head.starts_with(b"HTTP/1.1 101"),
+        "{:?}",
+        String::from_utf8_lossy(&head)
+    );
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut reply))
+        .await
+        .expect("reply timeout")
+        .unwrap();
+    assert_eq!(reply, b"got:hello");
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_shutdown_aborts_tunnels_after_drain_timeout() {
+    let (echo_addr, _echo_handle) = start_upstream_tcp_echo().await;
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        drain_timeout: Duration::from_millis(1000),
+        ..ProxyConfig::default()
+    })
+    .await;
+    // A connection that never sends a request holds the server open for the whole window too.
+    let _idle = TcpStream::connect(proxy_addr).await.unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let req = format!(
+        "CONNECT foo HTTP/1.1\r\nHost: foo\r\nX-Cmux-Port-Internal: {}\r\n\r\n",
+        echo_addr.port()
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp_buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    while !resp_buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(Duration::from_secs(5), stream.read(&mut tmp))
+            .await
+            .expect("read timeout")
+            .unwrap();
+        assert!(n > 0);
+        resp_buf.extend_from_slice(&tmp[..n]);
+    }
+    stream.write_all(b"ping\n").await.unwrap();
+    let mut recv = [0u8; 5];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut recv))
+        .await
+        .expect("echo timeout")
+        .unwrap();
+
+    // The tunnel is idle but open; shutdown must not wait on it past the drain deadline, and
+    // the servers and tunnels share that one deadline rather than waiting for it in turn.
+    let _ = shutdown.send(());
+    timeout(Duration::from_millis(1800), handle)
+        .await
+        .expect("proxy did not stop after drain timeout")
+        .unwrap();
+
+    let n = timeout(Duration::from_secs(3), stream.read(&mut tmp))
+        .await
+        .expect("tunnel still open after abort")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+}
+
+fn free_local_addr() -> SocketAddr {
+    let l = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    l.local_addr().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_admin_endpoint_reports_tunnels_config_and_log_level() {
+    use std::sync::{Arc, Mutex};
+    use tokio_tungstenite::connect_async;
+    use tungstenite::client::IntoClientRequest;
+
+    let applied = Arc::new(Mutex::new(Vec::<String>::new()));
+    let applied_clone = applied.clone();
+    let admin_addr = free_local_addr();
+    let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        admin: Some(cmux_proxy::AdminConfig {
+            listen: admin_addr,
+            log_filter: Some(cmux_proxy::LogFilterHandle::new("info", move |d| {
+                if d == "bogus[" {
+                    return Err("bad directive".into());
+                }
+                applied_clone.lock().unwrap().push(d.to_string());
+                Ok(())
+            })),
+            reload: None,
+        }),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
+    let mut req = url.into_client_request().unwrap();
+    req.headers_mut().insert(
+        "X-Cmux-Port-Internal",
+        ws_addr.port().to_string().parse().unwrap(),
+    );
+    let (mut ws, _resp) = timeout(Duration::from_secs(5), connect_async(req))
+        .await
+        .expect("ws connect timeout")
+        .expect("ws connect failed");
+    ws.send(tungstenite::Message::Text("hello-admin".into()))
+        .await
+        .unwrap();
+    let _ = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let get_json = |path: &str| {
+        let client = client.clone();
+        let uri: hyper::Uri = format!("http://{}{}", admin_addr, path).parse().unwrap();
+        async move {
+            let resp = client.get(uri).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        }
+    };
+
+    let tunnels = get_json("/tunnels").await;
+    let tunnels = tunnels.as_array().unwrap();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0]["kind"], "websocket");
+    assert_eq!(
+        tunnels[0]["target"],
+        format!("127.0.0.1:{}", ws_addr.port())
+    );
+    assert!(tunnels[0]["bytes_to_upstream"].as_u64().unwrap() > 0);
+    assert!(tunnels[0]["bytes_to_client"].as_u64().unwrap() > 0);
+
+    let config = get_json("/config").await;
+    assert_eq!(config["upstream_host"], "127.0.0.1");
+    assert_eq!(config["listen"][0], proxy_addr.to_string());
+    assert_eq!(config["admin_listen"], admin_addr.to_string());
+
+    let put = |body: &'static str| {
+        let client = client.clone();
+        let req = Request::put(format!("http://{}/log-level", admin_addr))
+            .body(Body::from(body))
+            .unwrap();
+        async move { client.request(req).await.unwrap().status() }
+    };
+    assert_eq!(put("debug").await, StatusCode::OK);
+    assert_eq!(put("bogus[").await, StatusCode::BAD_REQUEST);
+    assert_eq!(*applied.lock().unwrap(), vec!["debug".to_string()]);
+    let resp = client
+        .get(format!("http://{}/log-level", admin_addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(&to_bytes(resp.into_body()).await.unwrap()[..], b"debug");
+
+    // Closed tunnels drop out of the listing.
+    let _ = ws.close(None).await;
+    drop(ws);
+    let mut remaining = usize::MAX;
+    for _ in 0..50 {
+        remaining = get_json("/tunnels").await.as_array().unwrap().len();
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(remaining, 0);
+
+    // Idle pooled connections would otherwise hold up the admin listener's graceful shutdown.
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+/// h2c-only upstream that mimics a unary gRPC handler: echoes the request body and finishes
+/// with `grpc-status` trailers.
+async fn start_upstream_h2_grpc_like() -> SocketAddr {
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            assert_eq!(req.version(), hyper::Version::HTTP_2);
+            let te = req
+                .headers()
+                .get("te")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body = to_bytes(req.into_body()).await.unwrap();
+            let (mut tx, resp_body) = Body::channel();
+            tokio::spawn(async move {
+                tx.send_data(body).await.unwrap();
+                let mut trailers = hyper::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                trailers.insert("grpc-message", "ok".parse().unwrap());
+                tx.send_trailers(trailers).await.unwrap();
+            });
+            let resp = Response::builder()
+                .header("content-type", "application/grpc")
+                .header("x-upstream-te", te.unwrap_or_default())
+                .body(resp_body)
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .http2_only(true)
+        .serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_http2_grpc_passthrough_forwards_trailers() {
+    use hyper::body::HttpBody;
+
+    let upstream_addr = start_upstream_h2_grpc_like().await;
+    let (proxy_addr, shutdown, handle) = start_proxy(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        "127.0.0.1",
+        false,
+    )
+    .await;
+
+    let client: Client<HttpConnector, Body> = Client::builder().http2_only(true).build_http();
+    let req = Request::post(format!("http://{}/helloworld.Greeter/SayHello", proxy_addr))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("X-Cmux-Port-Internal", upstream_addr.port().to_string())
+        .body(Body::from("grpc-frame"))
+        .unwrap();
+
+    let resp = timeout(Duration::from_secs(5), client.request(req))
+        .await
+        .expect("h2 request timeout")
+        .expect("h2 request failed");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.version(), hyper::Version::HTTP_2);
+    assert_eq!(resp.headers()["x-upstream-te"], "trailers");
+
+    let mut body = resp.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(data, b"grpc-frame");
+    let trailers = body.trailers().await.unwrap().expect("trailers forwarded");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["grpc-message"], "ok");
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+/// Upstream that echoes the request headers it received, one `name: value` per line.
+async fn start_upstream_header_echo() -> SocketAddr {
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let mut lines: Vec<String> = req
+                .headers()
+                .iter()
+                .map(|(k, v)| format!("{}: {}", k, v.to_str().unwrap_or("")))
+                .collect();
+            lines.sort();
+            let resp = Response::builder()
+                .header("server", "echo")
+                .body(Body::from(lines.join("\n")))
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let local = server.local_addr();
+    tokio::spawn(server);
+    local
+}
+
+#[test]
+fn test_header_rule_parsing_and_conditions() {
+    use cmux_proxy::{apply_header_rules, HeaderAction, HeaderRule, RuleDirection};
+    use hyper::HeaderMap;
+
+    let csp: HeaderRule =
+        "path=/app response:set:Content-Security-Policy=default-src 'self'; frame-ancestors *"
+            .parse()
+            .unwrap();
+    assert_eq!(csp.direction, RuleDirection::Response);
+    assert_eq!(csp.path_prefix.as_deref(), Some("/app"));
+    match &csp.action {
+        HeaderAction::Set(name, value) => {
+            assert_eq!(name, "content-security-policy");
+            assert_eq!(value, "default-src 'self'; frame-ancestors *");
+        }
+        other => panic!("unexpected action: {:?}", other),
+    }
+    assert_eq!(
+        csp.to_string(),
+        "path=/app response:set:content-security-policy=default-src 'self'; frame-ancestors *"
+    );
+
+    let strip: HeaderRule = "request:remove:X-Cmux-*".parse().unwrap();
+    assert_eq!(strip.action, HeaderAction::RemovePrefix("x-cmux-".into()));
+    let tag: HeaderRule = "workspace=workspace-2 request:add:X-Team=blue"
+        .parse()
+        .unwrap();
+
+    assert!("request:set:X-Foo".parse::<HeaderRule>().is_err());
+    assert!("request:remove:X-Foo=bar".parse::<HeaderRule>().is_err());
+    assert!("sideways:set:X-Foo=bar".parse::<HeaderRule>().is_err());
+    assert!("request:upsert:X-Foo=bar".parse::<HeaderRule>().is_err());
+
+    let rules = vec![csp, strip, tag];
+    let mut h = HeaderMap::new();
+    h.insert("x-cmux-token", "secret".parse().unwrap());
+    h.insert("accept", "*/*".parse().unwrap());
+    apply_header_rules(
+        &rules,
+        RuleDirection::Request,
+        "/",
+        Some("workspace-1"),
+        &mut h,
+    );
+    assert!(!h.contains_key("x-cmux-token"));
+    assert!(!h.contains_key("x-team"));
+    assert!(h.contains_key("accept"));
+    apply_header_rules(
+        &rules,
+        RuleDirection::Request,
+        "/",
+        Some("workspace-2"),
+        &mut h,
+    );
+    assert_eq!(h["x-team"], "blue");
+
+    let mut resp = HeaderMap::new();
+    apply_header_rules(&rules, RuleDirection::Response, "/other", None, &mut resp);
+    assert!(resp.is_empty());
+    apply_header_rules(&rules, RuleDirection::Response, "/app/x", None, &mut resp);
+    assert!(resp.contains_key("content-security-policy"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_header_rules_rewrite_proxied_http() {
+    let upstream_addr = start_upstream_header_echo().await;
+    let rules = [
+        "request:remove:X-Cmux-*",
+        "request:set:X-Forwarded-By=cmux-proxy",
+        "response:remove:Server",
+        "path=/app response:add:Content-Security-Policy=frame-ancestors 'self'",
+    ]
+    .iter()
+    .map(|r| r.parse().unwrap())
+    .collect();
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        header_rules: rules,
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    for (path, expect_csp) in [("/app/index.html", true), ("/api", false)] {
+        let req = Request::get(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Port-Internal", upstream_addr.port().to_string())
+            .header("X-Cmux-Session", "internal-secret")
+            .header("X-Other", "kept")
+            .body(Body::empty())
+            .unwrap();
+        let resp = timeout(Duration::from_secs(5), client.request(req))
+            .await
+            .expect("resp timeout")
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("server"));
+        assert_eq!(
+            resp.headers().contains_key("content-security-policy"),
+            expect_csp,
+            "csp on {}",
+            path
+        );
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let seen = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!seen.contains("x-cmux-"), "leaked headers: {}", seen);
+        assert!(seen.contains("x-forwarded-by: cmux-proxy"), "{}", seen);
+        assert!(seen.contains("x-other: kept"), "{}", seen);
+    }
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_listener_config_parsing() {
+    let l: cmux_proxy::ListenerConfig =
+        "127.0.0.2:39379;upstream=127.18.0.2;ports=3000-3999;token=s3cret"
+            .parse()
+            .expect("parse listener");
+    assert_eq!(l.listen, "127.0.0.2:39379".parse().unwrap());
+    assert_eq!(l.upstream_host.as_deref(), Some("127.18.0.2"));
+    assert_eq!(l.allowed_ports, Some(3000..=3999));
+    assert_eq!(l.auth_token.as_deref(), Some("s3cret"));
+    assert_eq!(
+        l.to_string(),
+        "127.0.0.2:39379;upstream=127.18.0.2;ports=3000-3999;token=<redacted>"
+    );
+
+    let bare: cmux_proxy::ListenerConfig = "127.0.0.1:0".parse().unwrap();
+    assert_eq!(
+        bare,
+        cmux_proxy::ListenerConfig::new("127.0.0.1:0".parse().unwrap())
+    );
+
+    assert_eq!(cmux_proxy::parse_port_range("8080"), Ok(8080..=8080));
+    assert!(cmux_proxy::parse_port_range("9000-8000").is_err());
+    assert!("127.0.0.1:0;ports=abc"
+        .parse::<cmux_proxy::ListenerConfig>()
+        .is_err());
+    assert!("127.0.0.1:0;bogus=1"
+        .parse::<cmux_proxy::ListenerConfig>()
+        .is_err());
+    assert!("not-an-addr".parse::<cmux_proxy::ListenerConfig>().is_err());
+
+    let tls: cmux_proxy::ListenerConfig =
+        "10.8.0.1:443;tls-cert=/etc/cmux/cert.pem;tls-key=/etc/cmux/key.pem"
+            .parse()
+            .unwrap();
+    assert_eq!(
+        tls.tls,
+        Some(cmux_proxy::TlsConfig::new(
+            "/etc/cmux/cert.pem",
+            "/etc/cmux/key.pem"
+        ))
+    );
+    assert_eq!(
+        tls.to_string(),
+        "10.8.0.1:443;tls-cert=/etc/cmux/cert.pem;tls-key=/etc/cmux/key.pem"
+    );
+    assert!("10.8.0.1:443;tls-cert=/etc/cmux/cert.pem"
+        .parse::<cmux_proxy::ListenerConfig>()
+        .is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_per_listener_upstream_ports_and_auth() {
+    let upstream_default = start_upstream_http().await;
+    let alt_ip = Ipv4Addr::new(127, 0, 0, 3);
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::new(Body::from("alt")))
+        }))
+    });
+    let alt_server = Server::bind(&SocketAddr::from((alt_ip, 0))).serve(make_svc);
+    let upstream_alt = alt_server.local_addr();
+    tokio::spawn(alt_server);
+
+    let mut restricted =
+        cmux_proxy::ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    restricted.upstream_host = Some(alt_ip.to_string());
+    restricted.allowed_ports = Some(upstream_alt.port()..=upstream_alt.port());
+    restricted.auth_token = Some("s3cret".to_string());
+    let listeners = vec![
+        cmux_proxy::ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        restricted,
+    ];
+    let cfg = ProxyConfig {
+        allow_default_upstream: true,
+        ..ProxyConfig::default()
+    };
+    let (tx, rx) = oneshot::channel::<()>();
+    let (bound, handle) = cmux_proxy::spawn_proxy_listeners(listeners, cfg, async move {
+        let _ = rx.await;
+    });
+    let (open_addr, locked_addr) = (bound[0], bound[1]);
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let send = |addr: SocketAddr, port: u16, token: Option<&str>| {
+        let mut req = Request::builder()
+            .uri(format!("http://{}/x", addr))
+            .header("X-Cmux-Port-Internal", port.to_string());
+        if let Some(token) = token {
+            req = req.header("Proxy-Authorization", format!("Bearer {}", token));
+        }
+        let fut = client.request(req.body(Body::empty()).unwrap());
+        async move {
+            let resp = timeout(Duration::from_secs(5), fut)
+                .await
+                .expect("resp timeout")
+                .unwrap();
+            let status = resp.status();
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            (status, String::from_utf8_lossy(&body).to_string())
+        }
+    };
+
+    // The shared config applies to the open listener.
+    let (status, body) = send(open_addr, upstream_default.port(), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "ok:GET:/x");
+
+    // The restricted listener requires its token...
+    let (status, _) = send(locked_addr, upstream_alt.port(), None).await;
+    assert_eq!(status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    let (status, _) = send(locked_addr, upstream_alt.port(), Some("wrong")).await;
+    assert_eq!(status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+
+    // ...routes to its own default upstream host...
+    let (status, body) = send(locked_addr, upstream_alt.port(), Some("s3cret")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "alt");
+
+    // ...and refuses ports outside its range.
+    let (status, _) = send(locked_addr, upstream_default.port(), Some("s3cret")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let _ = tx.send(());
+    let _ = handle.await;
+}
+
+async fn start_upstream_sized_bodies() -> SocketAddr {
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let resp = match req.uri().path() {
+                "/declared" => Response::new(Body::from(vec![b'x'; 4096])),
+                "/streamed" => {
+                    let chunks = (0..8).map(|_| Ok::<_, Infallible>(vec![b'y'; 512]));
+                    Response::new(Body::wrap_stream(futures_util::stream::iter(chunks)))
+                }
+                // The proxy aborts oversize request bodies, so the read may fail here.
+                _ => match to_bytes(req.into_body()).await {
+                    Ok(body) => Response::new(Body::from(format!("len:{}", body.len()))),
+                    Err(_) => Response::new(Body::from("aborted")),
+                },
+            };
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let local = server.local_addr();
+    tokio::spawn(server);
+    local
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_body_size_limits() {
+    let upstream = start_upstream_sized_bodies().await;
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        max_request_body: Some(1024),
+        max_response_body: Some(1024),
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let client: Client<HttpConnector, Body> = Client::new();
+    let request = |path: &str, body: Body| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Port-Internal", upstream.port().to_string())
+            .body(body)
+            .unwrap()
+    };
+
+    // Under the limit passes through.
+    let resp = client
+        .request(request("/echo", Body::from(vec![b'a'; 512])))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "len:512");
+
+    // Declared oversize request is rejected before contacting the upstream.
+    let resp = client
+        .request(request("/echo", Body::from(vec![b'a'; 2048])))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Chunked oversize request is cut off once it crosses the limit.
+    let chunks = (0..4).map(|_| Ok::<_, Infallible>(vec![b'b'; 512]));
+    let resp = timeout(
+        Duration::from_secs(5),
+        client.request(request(
+            "/echo",
+            Body::wrap_stream(futures_util::stream::iter(chunks)),
+        )),
+    )
+    .await
+    .expect("resp timeout")
+    .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Declared oversize response becomes a 502.
+    let resp = client
+        .request(request("/declared", Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+    // Streamed oversize response is aborted mid-body.
+    let resp = client
+        .request(request("/streamed", Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = timeout(Duration::from_secs(5), to_bytes(resp.into_body()))
+        .await
+        .expect("body timeout");
+    assert!(body.is_err(), "expected truncated body, got {:?}", body);
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_concurrent_config_updates_are_not_lost() {
+    let config = cmux_proxy::ConfigHandle::new(ProxyConfig::default());
+    let writers: Vec<_> = (0..4)
+        .map(|_| {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    config
+                        .update(|cfg| {
+                            cfg.ip_denylist.push("192.0.2.1".parse().unwrap());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(config.load().ip_denylist.len(), 800);
+
+    // A failed update leaves the config as it was.
+    let err = config.update(|cfg| {
+        cfg.ip_denylist.clear();
+        Err("nope".to_string())
+    });
+    assert_eq!(err, Err("nope".to_string()));
+    assert_eq!(config.load().ip_denylist.len(), 800);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_config_reload_applies_to_existing_connections() {
+    let upstream = start_upstream_http().await;
+    let admin_addr = free_local_addr();
+    let reloads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let reloads_clone = reloads.clone();
+    let config = cmux_proxy::ConfigHandle::new(ProxyConfig {
+        allow_default_upstream: true,
+        admin: Some(cmux_proxy::AdminConfig {
+            listen: admin_addr,
+            log_filter: None,
+            reload: Some(cmux_proxy::ReloadHandle::new(move |config| {
+                reloads_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                config.update(|cfg| {
+                    cfg.allowed_ports = None;
+                    Ok(())
+                })
+            })),
+        }),
+        ..ProxyConfig::default()
+    });
+    let (tx, rx) = oneshot::channel::<()>();
+    let listener = cmux_proxy::ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    let (bound, handle) =
+        cmux_proxy::spawn_proxy_with_handle(vec![listener], config.clone(), async move {
+            let _ = rx.await;
+        });
+    let proxy_addr = bound[0];
+
+    // One client, so requests reuse the same keep-alive connection to the proxy.
+    let client: Client<HttpConnector, Body> = Client::new();
+    let get = |port: u16| {
+        let req = Request::get(format!("http://{}/r", proxy_addr))
+            .header("X-Cmux-Port-Internal", port.to_string())
+            .body(Body::empty())
+            .unwrap();
+        let fut = client.request(req);
+        async move { fut.await.unwrap().status() }
+    };
+    let admin = |method: &str, path: &str, body: &'static str| {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", admin_addr, path))
+            .body(Body::from(body))
+            .unwrap();
+        let fut = client.request(req);
+        async move {
+            let resp = fut.await.unwrap();
+            let status = resp.status();
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            (status, String::from_utf8_lossy(&body).to_string())
+        }
+    };
+
+    assert_eq!(get(upstream.port()).await, StatusCode::OK);
+
+    // Swapping the config directly takes effect on the next request.
+    config
+        .update(|cfg| {
+            cfg.allowed_ports = Some(1..=1);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(get(upstream.port()).await, StatusCode::FORBIDDEN);
+
+    // The reload hook runs on POST /reload.
+    let (status, _) = admin("POST", "/reload", "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(reloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(get(upstream.port()).await, StatusCode::OK);
+
+    // PUT /config patches reloadable keys and reports the result.
+    let (status, body) = admin(
+        "PUT",
+        "/config",
+        r#"{"auth_token": "s3cret", "header_rules": ["response:set:X-Reloaded=1"]}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let cfg: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(cfg["auth_required"], true);
+    assert_eq!(cfg["header_rules"][0], "response:set:x-reloaded=1");
+    assert_eq!(
+        get(upstream.port()).await,
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED
+    );
+
+    // Invalid patches are rejected atomically.
+    let (status, _) = admin(
+        "PUT",
+        "/config",
+        r#"{"auth_token": null, "allowed_ports": "9-1"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(config.load().auth_token.is_some());
+    let (status, _) = admin("PUT", "/config", r#"{"listen": "127.0.0.1:1"}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    drop(client);
+    let _ = tx.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_mirror_rule_parsing() {
+    use cmux_proxy::MirrorRule;
+
+    let rule: MirrorRule = "path=/api workspace=workspace-2 127.0.0.1:4000"
+        .parse()
+        .unwrap();
+    assert_eq!(rule.host, "127.0.0.1");
+    assert_eq!(rule.port, Some(4000));
+    assert_eq!(rule.path_prefix.as_deref(), Some("/api"));
+    assert_eq!(rule.workspace.as_deref(), Some("workspace-2"));
+    assert_eq!(
+        rule.to_string(),
+        "path=/api workspace=workspace-2 127.0.0.1:4000"
+    );
+
+    let rule: MirrorRule = "canary.internal".parse().unwrap();
+    assert_eq!(rule.host, "canary.internal");
+    assert_eq!(rule.port, None);
+    let rule: MirrorRule = "[::1]".parse().unwrap();
+    assert_eq!(rule.host, "[::1]");
+    assert_eq!(rule.port, None);
+
+    assert!("".parse::<MirrorRule>().is_err());
+    assert!("path=/api".parse::<MirrorRule>().is_err());
+    assert!("host:notaport".parse::<MirrorRule>().is_err());
+    assert!("a b".parse::<MirrorRule>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mirror_duplicates_matching_requests() {
+    let upstream = start_upstream_sized_bodies().await;
+
+    // Mirror upstream reports what it received and answers with an error the proxy must ignore.
+    let (seen_tx, mut seen_rx) = tokio::sync::mpsc::unbounded_channel::<(String, bool, Vec<u8>)>();
+    let make_svc = make_service_fn(move |_conn| {
+        let seen_tx = seen_tx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let seen_tx = seen_tx.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let marked = req.headers().contains_key("x-cmux-mirror");
+                    let body = to_bytes(req.into_body()).await.unwrap_or_default();
+                    let _ = seen_tx.send((path, marked, body.to_vec()));
+                    let mut resp = Response::new(Body::from("mirror"));
+                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+    let mirror_server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let mirror_addr = mirror_server.local_addr();
+    tokio::spawn(mirror_server);
+
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        mirror_rules: vec![format!("path=/api {}", mirror_addr).parse().unwrap()],
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let client: Client<HttpConnector, Body> = Client::new();
+    let request = |path: &str, body: &'static str| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Port-Internal", upstream.port().to_string())
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    // The primary response is returned unchanged; the mirror gets the same request.
+    let resp = client
+        .request(request("/api/items", "hello"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "len:5");
+    let (path, marked, body) = timeout(Duration::from_secs(5), seen_rx.recv())
+        .await
+        .expect("mirror timeout")
+        .expect("mirror channel closed");
+    assert_eq!(path, "/api/items");
+    assert!(marked, "mirrored request should carry X-Cmux-Mirror");
+    assert_eq!(body, b"hello");
+
+    // Requests outside the rule are not mirrored.
+    let resp = client.request(request("/other", "skip")).await.unwrap();
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "len:4");
+    assert!(timeout(Duration::from_millis(300), seen_rx.recv())
+        .await
+        .is_err());
+
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_upstream_connection_cap_queues_then_rejects() {
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(Response::new(Body::from("slow")))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let upstream = server.local_addr();
+    tokio::spawn(server);
+
+    async fn concurrent_statuses(proxy_addr: SocketAddr, upstream: SocketAddr) -> Vec<StatusCode> {
+        let client: Client<HttpConnector, Body> = Client::new();
+        let send = || {
+            let req = Request::builder()
+                .uri(format!("http://{}/", proxy_addr))
+                .header("X-Cmux-Port-Internal", upstream.port().to_string())
+                .body(Body::empty())
+                .unwrap();
+            client.request(req)
+        };
+        let (a, b) = tokio::join!(send(), send());
+        let mut statuses = Vec::new();
+        for resp in [a.unwrap(), b.unwrap()] {
+            statuses.push(resp.status());
+            if resp.status() == StatusCode::SERVICE_UNAVAILABLE {
+                assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+            }
+            let _ = to_bytes(resp.into_body()).await;
+        }
+        statuses.sort();
+        statuses
+    }
+
+    // A queue timeout shorter than the upstream's response time turns the second request away.
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        max_upstream_connections: Some(1),
+        upstream_queue_timeout: Duration::from_millis(100),
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    assert_eq!(
+        concurrent_statuses(proxy_addr, upstream).await,
+        vec![StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]
+    );
+    let _ = shutdown.send(());
+    let _ = handle.await;
+
+    // With enough patience the second request waits for the slot and succeeds.
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        max_upstream_connections: Some(1),
+        upstream_queue_timeout: Duration::from_secs(5),
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    assert_eq!(
+        concurrent_statuses(proxy_addr, upstream).await,
+        vec![StatusCode::OK, StatusCode::OK]
+    );
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+/// Send a CONNECT with `headers` and return the response status line.
+async fn connect_status(proxy_addr: SocketAddr, headers: &str) -> (String, TcpStream) {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let req = format!("CONNECT foo HTTP/1.1\r\nHost: foo\r\n{}\r\n", headers);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(Duration::from_secs(5), stream.read(&mut tmp))
+            .await
+            .expect("read timeout")
+            .unwrap();
+        assert!(n > 0, "connection closed before response");
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    let text = String::from_utf8_lossy(&buf);
+    (text.lines().next().unwrap_or("").to_string(), stream)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_connect_requires_registered_workspace_and_token() {
+    let (echo_addr, _echo_handle) = start_upstream_tcp_echo().await;
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        workspace_network: cmux_proxy::WorkspaceNetwork::default()
+            .with_override("dev", Ipv4Addr::LOCALHOST),
+        connect_auth_token: Some("s3cret".to_string()),
+        connect_registered_only: true,
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let port = format!("X-Cmux-Port-Internal: {}\r\n", echo_addr.port());
+    let auth = "Proxy-Authorization: Bearer s3cret\r\n";
+
+    let (status, _) = connect_status(
+        proxy_addr,
+        &format!("{}X-Cmux-Workspace-Internal: dev\r\n", port),
+    )
+    .await;
+    assert!(status.contains(" 407 "), "{}", status);
+
+    // Computed names resolve, but aren't registered.
+    let (status, _) = connect_status(
+        proxy_addr,
+        &format!("{}{}X-Cmux-Workspace-Internal: workspace-3\r\n", port, auth),
+    )
+    .await;
+    assert!(status.contains(" 403 "), "{}", status);
+
+    // Without a workspace the default upstream is not a registered target either.
+    let (status, _) = connect_status(proxy_addr, &format!("{}{}", port, auth)).await;
+    assert!(status.contains(" 403 "), "{}", status);
+
+    let (status, mut stream) = connect_status(
+        proxy_addr,
+        &format!("{}{}X-Cmux-Workspace-Internal: dev\r\n", port, auth),
+    )
+    .await;
+    assert!(status.contains(" 200"), "{}", status);
+    stream.write_all(b"audit-me\n").await.unwrap();
+    let mut recv = [0u8; 9];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut recv))
+        .await
+        .expect("echo timeout")
+        .unwrap();
+    assert_eq!(&recv, b"audit-me\n");
+    drop(stream);
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_error_responses_render_json_when_requested() {
+    // Grab a port with nothing listening, standing in for a sleeping workspace.
+    let closed_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let client: Client<HttpConnector, Body> = Client::new();
+    let request = |port: Option<u16>, accept: &str| {
+        let mut builder = Request::builder()
+            .uri(format!("http://{}/", proxy_addr))
+            .header("Accept", accept);
+        if let Some(port) = port {
+            builder = builder.header("X-Cmux-Port-Internal", port.to_string());
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    let mut req = request(Some(closed_port), "application/json, text/plain;q=0.5");
+    req.headers_mut()
+        .insert("X-Request-Id", "req-123".parse().unwrap());
+    let resp = client.request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    assert_eq!(resp.headers()["x-cmux-request-id"], "req-123");
+    let body: serde_json::Value =
+        serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "upstream_unreachable");
+    assert_eq!(body["error"]["requestId"], "req-123");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("upstream request error"));
+
+    let resp = client
+        .request(request(None, "application/json"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let request_id = resp.headers()["x-cmux-request-id"].clone();
+    let body: serde_json::Value =
+        serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"]["code"], "missing_port");
+    assert_eq!(body["error"]["requestId"], request_id.to_str().unwrap());
+
+    // curl's default Accept keeps the plain-text body.
+    let resp = client.request(request(None, "*/*")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(resp.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert!(resp.headers().contains_key("x-cmux-request-id"));
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("missing required header"));
+
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_compresses_eligible_responses_with_br_or_gzip() {
+    use std::io::Read;
+
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let (content_type, body, encoding) = match req.uri().path() {
+                "/page" => ("text/html; charset=utf-8", "<p>hello</p>".repeat(400), None),
+                "/small" => ("application/json", "{\"ok\":true}".to_string(), None),
+                "/image" => ("image/png", "x".repeat(4096), None),
+                _ => ("text/plain", "already".repeat(400), Some("br")),
+            };
+            let mut resp = Response::builder().header("content-type", content_type);
+            if let Some(encoding) = encoding {
+                resp = resp.header("content-encoding", encoding);
+            }
+            Ok::<_, Infallible>(resp.body(Body::from(body)).unwrap())
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let upstream = server.local_addr();
+    tokio::spawn(server);
+
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        compress: true,
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let client: Client<HttpConnector, Body> = Client::new();
+    let get = |path: &str, accept_encoding: &str| {
+        let req = Request::builder()
+            .uri(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Port-Internal", upstream.port().to_string())
+            .header("Accept-Encoding", accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        client.request(req)
+    };
+
+    let resp = get("/page", "br;q=0, gzip, deflate").await.unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+    assert!(!resp.headers().contains_key("content-length"));
+    let compressed = to_bytes(resp.into_body()).await.unwrap();
+    assert!(compressed.len() < 4800 / 4, "{} bytes", compressed.len());
+    let mut html = String::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_string(&mut html)
+        .unwrap();
+    assert_eq!(html, "<p>hello</p>".repeat(400));
+
+    // Brotli wins when both are accepted.
+    for accept in ["gzip, deflate, br", "*"] {
+        let resp = get("/page", accept).await.unwrap();
+        assert_eq!(resp.headers()["content-encoding"], "br", "{}", accept);
+        let compressed = to_bytes(resp.into_body()).await.unwrap();
+        assert!(compressed.len() < 4800 / 4, "{} bytes", compressed.len());
+        let mut html = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut html)
+            .unwrap();
+        assert_eq!(html, "<p>hello</p>".repeat(400));
+    }
+
+    // Not accepted, too small, not compressible, or already encoded: passed through.
+    for (path, accept) in [
+        ("/page", "gzip;q=0"),
+        ("/page", "*, br;q=0, gzip;q=0"),
+        ("/small", "gzip"),
+        ("/image", "gzip"),
+        ("/encoded", "gzip"),
+    ] {
+        let resp = get(path, accept).await.unwrap();
+        let encoding = resp
+            .headers()
+            .get("content-encoding")
+            .map(|v| v.to_str().unwrap().to_string());
+        // The upstream's own encoding is left as it was.
+        let expected = (path == "/encoded").then_some("br");
+        assert_eq!(encoding.as_deref(), expected, "{} {}", path, accept);
+        let _ = to_bytes(resp.into_body()).await;
+    }
+
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_replica_set_and_affinity_parsing() {
+    let (name, hosts) = cmux_proxy::parse_replica_set("workspace-1= 10.0.0.2, 10.0.0.3 ,").unwrap();
+    assert_eq!(name, "workspace-1");
+    assert_eq!(hosts, vec!["10.0.0.2", "10.0.0.3"]);
+    assert!(cmux_proxy::parse_replica_set("workspace-1").is_err());
+    assert!(cmux_proxy::parse_replica_set("=10.0.0.2").is_err());
+    assert!(cmux_proxy::parse_replica_set("workspace-1=,").is_err());
+
+    use cmux_proxy::AffinityMode;
+    assert_eq!(
+        "cookie".parse::<AffinityMode>().unwrap(),
+        AffinityMode::Cookie
+    );
+    assert_eq!("IP".parse::<AffinityMode>().unwrap(), AffinityMode::Ip);
+    assert_eq!("off".parse::<AffinityMode>().unwrap(), AffinityMode::None);
+    assert!("sticky".parse::<AffinityMode>().is_err());
+    assert_eq!(AffinityMode::Ip.to_string(), "ip");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_replica_affinity_round_robin_and_cookie() {
+    let serve = |ip: Ipv4Addr, port: u16| {
+        let make_svc = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(ip.to_string())))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from((ip, port))).serve(make_svc);
+        let local = server.local_addr();
+        tokio::spawn(server);
+        local
+    };
+    let first = serve(Ipv4Addr::LOCALHOST, 0);
+    let second = serve(Ipv4Addr::new(127, 0, 0, 3), first.port());
+    let port = first.port();
+
+    let start = |affinity: cmux_proxy::AffinityMode| {
+        let mut replicas = std::collections::HashMap::new();
+        replicas.insert(
+            "workspace-1".to_string(),
+            vec![first.ip().to_string(), second.ip().to_string()],
+        );
+        start_proxy_with_config(ProxyConfig {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            replicas,
+            affinity,
+            ..ProxyConfig::default()
+        })
+    };
+    let client: Client<HttpConnector, Body> = Client::new();
+    let get = |proxy: SocketAddr, cookie: Option<String>| {
+        let mut req = Request::builder()
+            .uri(format!("http://{}/", proxy))
+            .header("X-Cmux-Workspace-Internal", "workspace-1")
+            .header("X-Cmux-Port-Internal", port.to_string());
+        if let Some(cookie) = cookie {
+            req = req.header("Cookie", cookie);
+        }
+        let fut = client.request(req.body(Body::empty()).unwrap());
+        async move {
+            let resp = timeout(Duration::from_secs(5), fut)
+                .await
+                .expect("resp timeout")
+                .unwrap();
+            let set_cookie = resp
+                .headers()
+                .get("set-cookie")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            (String::from_utf8_lossy(&body).to_string(), set_cookie)
+        }
+    };
+
+    // Without affinity requests alternate between replicas.
+    let (proxy, shutdown, handle) = start(cmux_proxy::AffinityMode::None).await;
+    let (a, cookie) = get(proxy, None).await;
+    let (b, _) = get(proxy, None).await;
+    assert_ne!(a, b);
+    assert!(cookie.is_none());
+    let _ = shutdown.send(());
+    let _ = handle.await;
+
+    // With cookie affinity the first response sets a session cookie and requests carrying it
+    // keep landing on the same replica.
+    let (proxy, shutdown, handle) = start(cmux_proxy::AffinityMode::Cookie).await;
+    let (pinned, set_cookie) = get(proxy, None).await;
+    let set_cookie = set_cookie.expect("affinity cookie");
+    assert!(set_cookie.starts_with("cmux-affinity="), "{}", set_cookie);
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..4 {
+        let (body, again) = get(proxy, Some(format!("theme=dark; {}", cookie))).await;
+        assert_eq!(body, pinned);
+        assert!(again.is_none());
+    }
+    // A client without the cookie gets a fresh session on the other replica.
+    let (other, fresh) = get(proxy, None).await;
+    assert_ne!(other, pinned);
+    assert!(fresh.is_some());
+
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_timeout_rule_parsing() {
+    let rule: cmux_proxy::TimeoutRule =
+        "path=/api workspace=workspace-2 port=3000 connect=500ms ttfb=30 total=2m idle=1h"
+            .parse()
+            .unwrap();
+    assert_eq!(rule.path_prefix.as_deref(), Some("/api"));
+    assert_eq!(rule.workspace.as_deref(), Some("workspace-2"));
+    assert_eq!(rule.port, Some(3000));
+    assert_eq!(
+        rule.timeouts,
+        cmux_proxy::Timeouts {
+            connect: Some(Duration::from_millis(500)),
+            first_byte: Some(Duration::from_secs(30)),
+            total: Some(Duration::from_secs(120)),
+            idle: Some(Duration::from_secs(3600)),
+        }
+    );
+    assert_eq!(
+        rule.to_string(),
+        "path=/api workspace=workspace-2 port=3000 connect=500ms ttfb=30s total=2m idle=1h"
+    );
+    assert_eq!(
+        rule.to_string().parse::<cmux_proxy::TimeoutRule>().unwrap(),
+        rule
+    );
+
+    assert!("path=/api".parse::<cmux_proxy::TimeoutRule>().is_err());
+    assert!("ttfb=0".parse::<cmux_proxy::TimeoutRule>().is_err());
+    assert!("ttfb=fast".parse::<cmux_proxy::TimeoutRule>().is_err());
+    assert!("port=0 total=1s"
+        .parse::<cmux_proxy::TimeoutRule>()
+        .is_err());
+    assert!("deadline=1s".parse::<cmux_proxy::TimeoutRule>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_upstream_timeouts_return_504_and_close_idle_tunnels() {
+    let make_svc = make_service_fn(|_conn| async move {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            match req.uri().path() {
+                "/slow-head" => {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    Ok::<_, Infallible>(Response::new(Body::from("late")))
+                }
+                "/slow-body" => {
+                    let (mut tx, body) = Body::channel();
+                    tokio::spawn(async move {
+                        let _ = tx.send_data("first".into()).await;
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                        let _ = tx.send_data("second".into()).await;
+                    });
+                    Ok(Response::new(body))
+                }
+                _ => Ok(Response::new(Body::from("fast"))),
+            }
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_svc);
+    let upstream = server.local_addr();
+    tokio::spawn(server);
+    let (echo_addr, _echo_handle) = start_upstream_tcp_echo().await;
+
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        allow_default_upstream: true,
+        first_byte_timeout: Some(Duration::from_millis(300)),
+        timeout_rules: vec![
+            "path=/slow-body total=500ms".parse().unwrap(),
+            format!("port={} idle=300ms", echo_addr.port())
+                .parse()
+                .unwrap(),
+        ],
+        ..ProxyConfig::default()
+    };
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(cfg).await;
+    let client: Client<HttpConnector, Body> = Client::new();
+    let get = |path: &str| {
+        let req = Request::builder()
+            .uri(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Port-Internal", upstream.port().to_string())
+            .body(Body::empty())
+            .unwrap();
+        timeout(Duration::from_secs(5), client.request(req))
+    };
+
+    let resp = get("/fast").await.expect("resp timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let _ = to_bytes(resp.into_body()).await;
+
+    // No response head within the first-byte timeout.
+    let resp = get("/slow-head").await.expect("resp timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert!(
+        String::from_utf8_lossy(&body).contains("did not respond within 300ms"),
+        "{:?}",
+        body
+    );
+
+    // The head arrived in time, so the body is cut off at the route's total deadline.
+    let resp = get("/slow-body").await.expect("resp timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = timeout(Duration::from_secs(5), to_bytes(resp.into_body()))
+        .await
+        .expect("body timeout");
+    assert!(body.is_err(), "{:?}", body);
+
+    // A quiet CONNECT tunnel is closed once its route's idle timeout passes.
+    let (status, mut stream) = connect_status(
+        proxy_addr,
+        &format!("X-Cmux-Port-Internal: {}\r\n", echo_addr.port()),
+    )
+    .await;
+    assert!(status.contains(" 200"), "{}", status);
+    stream.write_all(b"ping").await.unwrap();
+    let mut recv = [0u8; 4];
+    stream.read_exact(&mut recv).await.unwrap();
+    let mut rest = Vec::new();
+    let closed = timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+    assert!(closed.is_ok(), "idle tunnel was not closed");
+
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[test]
+fn test_ip_cidr_parsing_and_matching() {
+    use cmux_proxy::IpCidr;
+
+    let net: IpCidr = "10.1.2.3/16".parse().unwrap();
+    assert_eq!(net.to_string(), "10.1.0.0/16");
+    assert!(net.contains("10.1.200.7".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    // IPv4-mapped peers from dual-stack sockets match the IPv4 network.
+    assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+    let host: IpCidr = "::1".parse().unwrap();
+    assert_eq!(host.to_string(), "::1/128");
+    assert!(host.contains("::1".parse().unwrap()));
+    assert!(!host.contains("127.0.0.1".parse().unwrap()));
+
+    let all: IpCidr = "0.0.0.0/0".parse().unwrap();
+    assert!(all.contains("192.0.2.1".parse().unwrap()));
+    assert!(!all.contains("2001:db8::1".parse().unwrap()));
+
+    assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+    assert!("fe80::/129".parse::<IpCidr>().is_err());
+    assert!("10.0.0/8".parse::<IpCidr>().is_err());
+    assert!("10.0.0.0/x".parse::<IpCidr>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_ip_filter_refuses_connections_at_accept() {
+    let upstream = start_upstream_http().await;
+    let admin_addr = free_local_addr();
+    let config = cmux_proxy::ConfigHandle::new(ProxyConfig {
+        allow_default_upstream: true,
+        ip_allowlist: vec!["10.0.0.0/8".parse().unwrap()],
+        admin: Some(cmux_proxy::AdminConfig {
+            listen: admin_addr,
+            log_filter: None,
+            reload: None,
+        }),
+        ..ProxyConfig::default()
+    });
+    let (tx, rx) = oneshot::channel::<()>();
+    let (bound, handle) = cmux_proxy::spawn_proxy_with_handle(
+        vec![cmux_proxy::ListenerConfig::new(SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            0,
+        )))],
+        config.clone(),
+        async move {
+            let _ = rx.await;
+        },
+    );
+    let proxy_addr = bound[0];
+
+    // No pooling, so every request is a fresh connection that goes through the filter.
+    let client: Client<HttpConnector, Body> =
+        Client::builder().pool_max_idle_per_host(0).build_http();
+    let get = || {
+        let req = Request::builder()
+            .uri(format!("http://{}/x", proxy_addr))
+            .header("X-Cmux-Port-Internal", upstream.port().to_string())
+            .body(Body::empty())
+            .unwrap();
+        timeout(Duration::from_secs(5), client.request(req))
+    };
+
+    // Loopback isn't on the allowlist: the connection is closed without a response.
+    assert!(get().await.expect("resp timeout").is_err());
+
+    config
+        .update(|cfg| {
+            cfg.ip_allowlist = vec!["127.0.0.0/8".parse().unwrap()];
+            Ok(())
+        })
+        .unwrap();
+    let resp = get().await.expect("resp timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let _ = to_bytes(resp.into_body()).await;
+
+    // The denylist wins over the allowlist.
+    config
+        .update(|cfg| {
+            cfg.ip_denylist = vec!["127.0.0.1".parse().unwrap()];
+            Ok(())
+        })
+        .unwrap();
+    assert!(get().await.expect("resp timeout").is_err());
+
+    let resp = client
+        .get(format!("http://{}/metrics", admin_addr).parse().unwrap())
+        .await
+        .unwrap();
+    let metrics = String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(
+        metrics.contains("\ncmux_proxy_rejected_connections_total 2\n"),
+        "{}",
+        metrics
+    );
+
+    drop(client);
+    let _ = tx.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_ws_frame_debug_counts_frames_per_tunnel() {
+    use tokio_tungstenite::connect_async;
+    use tungstenite::client::IntoClientRequest;
+
+    let admin_addr = free_local_addr();
+    let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
+    let (proxy_addr, shutdown, handle) = start_proxy_with_config(ProxyConfig {
+        ws_frame_debug: true,
+        admin: Some(cmux_proxy::AdminConfig {
+            listen: admin_addr,
+            log_filter: None,
+            reload: None,
+        }),
+        ..ProxyConfig::default()
+    })
+    .await;
+
+    let url = format!("ws://{}/ws", proxy_addr);
+    let mut req = url.into_client_request().unwrap();
+    req.headers_mut().insert(
+        "X-Cmux-Port-Internal",
+        ws_addr.port().to_string().parse().unwrap(),
+    );
+    let (mut ws, _resp) = timeout(Duration::from_secs(5), connect_async(req))
+        .await
+        .expect("ws connect timeout")
+        .expect("ws connect failed");
+    for msg in ["one", "two", "three"] {
+        ws.send(tungstenite::Message::Text(msg.into()))
+            .await
+            .unwrap();
+        let echoed = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("echo timeout")
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, tungstenite::Message::Text(msg.into()));
+    }
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let resp = client
+        .get(format!("http://{}/tunnels", admin_addr).parse().unwrap())
+        .await
+        .unwrap();
+    let tunnels: serde_json::Value =
+        serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    let tunnel = &tunnels[0];
+    assert_eq!(tunnel["kind"], "websocket");
+    assert_eq!(tunnel["frames_to_upstream"], 3);
+    assert_eq!(tunnel["frames_to_client"], 3);
+    assert!(tunnel["last_frame_age_ms"].as_u64().is_some());
+    // Frames are parsed without changing the byte stream: each masked 3-5 byte text frame is
+    // 6 bytes of header plus payload.
+    assert_eq!(tunnel["bytes_to_upstream"], 3 * 6 + 3 + 3 + 5);
+
+    let _ = ws.close(None).await;
+    drop(ws);
+    drop(client);
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tls_listener_alongside_plain_listener() {
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{self, Certificate, RootCertStore, ServerName};
+
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let cert_path = fixtures.join("localhost.crt");
+    let key_path = fixtures.join("localhost.key");
+    assert!(
+        cmux_proxy::TlsConfig::new(&cert_path, fixtures.join("missing.key"))
+            .load()
+            .is_err()
+    );
+
+    let upstream = start_upstream_http().await;
+    let plain = cmux_proxy::ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    let mut tls = cmux_proxy::ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    tls.tls = Some(cmux_proxy::TlsConfig::new(&cert_path, &key_path));
+    tls.auth_token = Some("s3cret".to_string());
+    let cfg = ProxyConfig {
+        allow_default_upstream: true,
+        ..ProxyConfig::default()
+    };
+    let (tx, rx) = oneshot::channel::<()>();
+    let (bound, handle) = cmux_proxy::spawn_proxy_listeners(vec![plain, tls], cfg, async move {
+        let _ = rx.await;
+    });
+    let (plain_addr, tls_addr) = (bound[0], bound[1]);
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}/plain", plain_addr))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = client.request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let _ = to_bytes(resp.into_body()).await;
+
+    // Plain HTTP on the TLS listener never gets a response.
+    let req = Request::builder()
+        .uri(format!("http://{}/x", tls_addr))
+        .body(Body::empty())
+        .unwrap();
+    let plain_on_tls = timeout(Duration::from_secs(5), client.request(req))
+        .await
+        .expect("resp timeout");
+    assert!(plain_on_tls.is_err());
+
+    let mut roots = RootCertStore::empty();
+    let pem = std::fs::read(&cert_path).unwrap();
+    for der in rustls_pemfile::certs(&mut &pem[..]).unwrap() {
+        roots.add(&Certificate(der)).unwrap();
+    }
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+    let send = |token: Option<&'static str>| {
+        let connector = connector.clone();
+        let port = upstream.port();
+        async move {
+            let tcp = TcpStream::connect(tls_addr).await.unwrap();
+            let stream = connector
+                .connect(ServerName::try_from("localhost").unwrap(), tcp)
+                .await
+                .expect("TLS handshake");
+            let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+            tokio::spawn(conn);
+            let mut req = Request::builder()
+                .uri("/secure")
+                .header("Host", "localhost")
+                .header("X-Cmux-Port-Internal", port.to_string());
+            if let Some(token) = token {
+                req = req.header("Proxy-Authorization", format!("Bearer {}", token));
+            }
+            let resp = sender
+                .send_request(req.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = resp.status();
+            (status, to_bytes(resp.into_body()).await.unwrap_or_default())
+        }
+    };
+
+    // The TLS listener keeps its own overrides (here: auth).
+    let (status, _) = send(None).await;
+    assert_eq!(status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    let (status, body) = send(Some("s3cret")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"ok:GET:/secure");
+
+    drop(client);
+    let _ = tx.send(());
+    let _ = handle.await;
+}
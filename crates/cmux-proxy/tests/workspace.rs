@@ -4,7 +4,7 @@ use std::convert::Infallible;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
-use cmux_proxy::{workspace_ip_from_name, ProxyConfig};
+use cmux_proxy::{workspace_ip_from_name, ProxyConfig, WorkspaceNetwork};
 use hyper::body::to_bytes;
 use hyper::client::HttpConnector;
 use hyper::service::{make_service_fn, service_fn};
@@ -51,6 +51,7 @@ async fn start_proxy(
         listen,
         upstream_host: upstream_host.to_string(),
         allow_default_upstream,
+        ..ProxyConfig::default()
     };
     let (tx, rx) = oneshot::channel::<()>();
     let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move {
@@ -365,3 +366,120 @@ async fn test_same_port_isolation_across_workspaces() {
     let _ = shutdown.send(());
     let _ = handle.await;
 }
+
+#[test]
+fn test_workspace_network_mapping_and_validation() {
+    // Default network matches the legacy helper.
+    let default = WorkspaceNetwork::default();
+    assert_eq!(default.to_string(), "127.18.0.0/16");
+    assert_eq!(
+        default.ip_for("workspace-258"),
+        Some(Ipv4Addr::new(127, 18, 1, 2))
+    );
+    for name in ["workspace-1", "workspace-a", "/root/workspace-7"] {
+        assert_eq!(default.ip_for(name), workspace_ip_from_name(name));
+    }
+
+    // Custom CIDR: the index is folded into the host bits.
+    let net: WorkspaceNetwork = "127.42.7.0/24".parse().expect("cidr");
+    assert_eq!(
+        net.ip_for("workspace-5"),
+        Some(Ipv4Addr::new(127, 42, 7, 5))
+    );
+    assert_eq!(
+        net.ip_for("workspace-261"),
+        Some(Ipv4Addr::new(127, 42, 7, 5))
+    );
+    assert!("127.42.7.1/24".parse::<WorkspaceNetwork>().is_err());
+    assert!("127.42.7.0".parse::<WorkspaceNetwork>().is_err());
+    assert!("127.42.7.0/31".parse::<WorkspaceNetwork>().is_err());
+
+    // Collisions are reported by validate().
+    let err = net
+        .validate(["workspace-5", "workspace-261"])
+        .expect_err("collision");
+    assert!(err.contains("127.42.7.5"), "{}", err);
+
+    // Mapping file entries win over the computed scheme.
+    let mapped = net
+        .clone()
+        .with_mapping_str("# pins\nworkspace-261: 127.42.7.200\n\"frontend\": '127.42.7.9'\n")
+        .expect("mapping");
+    assert_eq!(
+        mapped.ip_for("workspace-261"),
+        Some(Ipv4Addr::new(127, 42, 7, 200))
+    );
+    assert_eq!(
+        mapped.ip_for("frontend"),
+        Some(Ipv4Addr::new(127, 42, 7, 9))
+    );
+    let ok = mapped
+        .validate(["workspace-5", "workspace-261", "frontend"])
+        .expect("no collisions");
+    assert_eq!(ok.len(), 3);
+
+    // Only pinned names count as registered; pinned IPs are part of the network.
+    assert!(mapped.is_registered("frontend"));
+    assert!(!mapped.is_registered("workspace-5"));
+    assert!(mapped.contains(Ipv4Addr::new(127, 42, 7, 5)));
+    assert!(!mapped.contains(Ipv4Addr::new(127, 42, 8, 5)));
+    let pinned_outside = mapped
+        .clone()
+        .with_override("edge", Ipv4Addr::new(10, 0, 0, 1));
+    assert!(pinned_outside.contains(Ipv4Addr::new(10, 0, 0, 1)));
+
+    assert!(net
+        .clone()
+        .with_mapping_str("a: 127.0.0.2\nb: 127.0.0.2\n")
+        .is_err());
+    assert!(net
+        .clone()
+        .with_mapping_str("a: 127.0.0.2\na: 127.0.0.3\n")
+        .is_err());
+    assert!(net.clone().with_mapping_str("a 127.0.0.2\n").is_err());
+    assert!(net.with_mapping_str("a: not-an-ip\n").is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_http_proxy_routes_with_custom_workspace_network() {
+    let network: WorkspaceNetwork = "127.43.0.0/16".parse().expect("cidr");
+    let network = network.with_override("frontend", Ipv4Addr::new(127, 43, 9, 9));
+    let upstream_computed = start_upstream_http_on(Ipv4Addr::new(127, 43, 0, 3)).await;
+    let upstream_pinned = start_upstream_http_on(Ipv4Addr::new(127, 43, 9, 9)).await;
+
+    let cfg = ProxyConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        workspace_network: network,
+        ..ProxyConfig::default()
+    };
+    let (tx, rx) = oneshot::channel::<()>();
+    let (proxy_addr, handle) = cmux_proxy::spawn_proxy(cfg, async move {
+        let _ = rx.await;
+    });
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    for (ws, port, path) in [
+        ("workspace-3", upstream_computed.port(), "/computed"),
+        ("frontend", upstream_pinned.port(), "/pinned"),
+    ] {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("http://{}{}", proxy_addr, path))
+            .header("X-Cmux-Workspace-Internal", ws)
+            .header("X-Cmux-Port-Internal", port.to_string())
+            .body(Body::empty())
+            .unwrap();
+        let resp = timeout(Duration::from_secs(5), client.request(req))
+            .await
+            .expect("resp timeout")
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK, "workspace {}", ws);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let s = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(s, format!("ok:GET:{}", path));
+    }
+
+    let _ = tx.send(());
+    let _ = handle.await;
+}
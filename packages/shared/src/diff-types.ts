@@ -1,4 +1,31 @@
-export type DiffStatus = "added" | "modified" | "deleted" | "renamed";
+export type DiffStatus =
+  | "added"
+  | "modified"
+  | "deleted"
+  | "renamed"
+  | "copied"
+  | "submodule";
+
+export interface DiffLine {
+  tag: "context" | "add" | "delete";
+  /** The line without its newline. */
+  content: string;
+  oldLineNumber?: number;
+  newLineNumber?: number;
+  noNewlineAtEnd?: boolean;
+  /** Changed stretches of `content` as UTF-16 offsets, under `includeIntraline`. */
+  spans?: Array<{ start: number; end: number }>;
+}
+
+export interface DiffHunk {
+  /** `@@ -oldStart,oldLines +newStart,newLines @@` */
+  header: string;
+  oldStart: number;
+  oldLines: number;
+  newStart: number;
+  newLines: number;
+  lines: DiffLine[];
+}
 
 export interface ReplaceDiffEntry {
   filePath: string;
@@ -14,5 +41,21 @@ export interface ReplaceDiffEntry {
   oldSize?: number;
   newSize?: number;
   patchSize?: number;
+  collapsed?: boolean;
+  stale?: boolean;
+  /** Present when the diff was requested with `includeHunks`. */
+  hunks?: DiffHunk[];
+  /** Percent of lines shared with `oldPath`, for renames and copies paired by content. */
+  similarity?: number;
+  /** Git mode before the change, e.g. `100755` or `120000` for a symlink; absent when added. */
+  oldMode?: string;
+  /** Git mode after the change; absent when deleted. */
+  newMode?: string;
+  /** A regular file became a symlink or the other way around. */
+  typeChanged?: boolean;
+  /** Commit a `submodule` entry pinned before; absent when it was added. */
+  oldSubmoduleSha?: string;
+  /** Commit a `submodule` entry pins now; absent when it was removed. */
+  newSubmoduleSha?: string;
 }
 